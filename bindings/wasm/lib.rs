@@ -269,6 +269,11 @@ impl limbo_core::File for File {
     fn size(&self) -> Result<u64> {
         Ok(self.vfs.size(self.fd))
     }
+
+    fn truncate(&self, _len: usize) -> Result<()> {
+        // TODO
+        Ok(())
+    }
 }
 
 pub struct PlatformIO {
@@ -361,6 +366,10 @@ impl limbo_core::DatabaseStorage for DatabaseStorage {
     fn sync(&self, _c: limbo_core::Completion) -> Result<()> {
         todo!()
     }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.file.truncate(len)
+    }
 }
 
 #[cfg(all(feature = "web", feature = "nodejs"))]
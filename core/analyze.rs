@@ -0,0 +1,262 @@
+//! ANALYZE.
+//!
+//! ANALYZE reads the b-tree of each index in order to count its entries and how many distinct keys
+//! are made of its first column, its first two columns and so on. It stores the number of rows of
+//! the table and the average number of rows per key in the sqlite_stat1 table of the database, in
+//! the format of SQLite, and keeps them in the schema, where the optimizer uses them to estimate
+//! how many rows a scan or a search of a table returns.
+
+use crate::schema::{quote_ident, BTreeTable, Index, TableStats, MAIN_DB};
+use crate::storage::btree::BTreeCursor;
+use crate::storage::pager::Pager;
+use crate::types::OwnedValue;
+use crate::util::normalize_ident;
+use crate::vacuum::run_io;
+use crate::{AuxDatabase, Connection, LimboError, Result, StepResult};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+impl Connection {
+    /// Gather the statistics of the table named `table_name` of the database `db`, or of all of
+    /// its tables, and store them in place of the ones sqlite_stat1 has for them.
+    pub(crate) fn analyze(
+        self: &Rc<Connection>,
+        db: usize,
+        table_name: Option<&str>,
+    ) -> Result<()> {
+        let database = match db {
+            MAIN_DB => None,
+            db => Some(self.aux_database(db)?),
+        };
+        let database = database.as_deref();
+        // The statistics are written by statements of their own, which must not each commit.
+        let auto_commit = *self.auto_commit.borrow();
+        if auto_commit {
+            self.run_statement("BEGIN IMMEDIATE")?;
+        }
+        let result = self.store_stats(database, table_name);
+        if auto_commit {
+            self.run_statement(if result.is_ok() { "COMMIT" } else { "ROLLBACK" })?;
+        }
+        result
+    }
+
+    /// Replace the rows of sqlite_stat1 for the tables ANALYZE gathers the statistics of, and the
+    /// statistics in the schema with the ones in sqlite_stat1.
+    fn store_stats(
+        self: &Rc<Connection>,
+        database: Option<&AuxDatabase>,
+        table_name: Option<&str>,
+    ) -> Result<()> {
+        let name = database.map_or("main", |database| &database.name);
+        let db_name = quote_ident(name);
+        self.run_statement(format!(
+            "CREATE TABLE IF NOT EXISTS {}.sqlite_stat1(tbl,idx,stat)",
+            db_name
+        ))?;
+        match table_name {
+            Some(table_name) => self.run_statement(format!(
+                "DELETE FROM {}.sqlite_stat1 WHERE tbl = {}",
+                db_name,
+                quote_literal(table_name)
+            ))?,
+            None => self.run_statement(format!("DELETE FROM {}.sqlite_stat1", db_name))?,
+        }
+        let pager = database.map_or(&self.pager, |database| &database.pager);
+        for (table, indexes) in self.tables_to_analyze(database, table_name) {
+            for (index, stat) in gather_stats(pager, &table, &indexes)? {
+                self.run_statement(format!(
+                    "INSERT INTO {}.sqlite_stat1 VALUES ({}, {}, {})",
+                    db_name,
+                    quote_literal(&table.name),
+                    index.as_deref().map_or("NULL".to_string(), quote_literal),
+                    quote_literal(&stat)
+                ))?;
+            }
+        }
+        let stats = self.read_stats(name)?;
+        match database {
+            Some(database) => {
+                database.save_committed_schema();
+                database.schema.borrow_mut().stats = stats;
+            }
+            None => {
+                self.save_committed_schema();
+                self.schema.borrow_mut().stats = stats;
+            }
+        }
+        Ok(())
+    }
+
+    /// The tables of `database` ANALYZE gathers the statistics of, by name, along with their
+    /// indexes: the index that holds the rows of a WITHOUT ROWID table comes first.
+    fn tables_to_analyze(
+        &self,
+        database: Option<&AuxDatabase>,
+        table_name: Option<&str>,
+    ) -> Vec<(Rc<BTreeTable>, Vec<Rc<Index>>)> {
+        let schema = match database {
+            Some(database) => database.schema.borrow(),
+            None => self.schema.borrow(),
+        };
+        let table_name = table_name.map(normalize_ident);
+        let mut tables: Vec<_> = schema
+            .tables
+            .iter()
+            .filter(|(name, _)| {
+                table_name
+                    .as_ref()
+                    .map_or(true, |table_name| table_name == *name)
+            })
+            .filter_map(|(_, table)| table.btree())
+            .filter(|table| !table.name.starts_with("sqlite_"))
+            .map(|table| {
+                let indexes = table
+                    .primary_key_index()
+                    .into_iter()
+                    .chain(schema.get_table_indices(&table).iter().cloned())
+                    .collect();
+                (table, indexes)
+            })
+            .collect();
+        tables.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        tables
+    }
+
+    /// Read the statistics in the sqlite_stat1 table of the database named `db_name`, if it has one.
+    pub(crate) fn read_stats(
+        self: &Rc<Connection>,
+        db_name: &str,
+    ) -> Result<HashMap<String, TableStats>> {
+        let mut stats = HashMap::new();
+        let has_stat_table = self.with_schema(|schema| {
+            schema
+                .database_index(db_name)
+                .and_then(|db| schema.get_table_in(db, "sqlite_stat1"))
+                .is_some()
+        });
+        if !has_stat_table {
+            return Ok(stats);
+        }
+        let Some(mut rows) = self.query(format!(
+            "SELECT tbl, idx, stat FROM {}.sqlite_stat1",
+            quote_ident(db_name)
+        ))?
+        else {
+            return Ok(stats);
+        };
+        loop {
+            match rows.step()? {
+                StepResult::Row => {
+                    let row = rows.row().unwrap();
+                    let (Ok(table), Ok(stat)) = (row.get::<&str>(0), row.get::<&str>(2)) else {
+                        continue;
+                    };
+                    stats
+                        .entry(normalize_ident(table))
+                        .or_insert_with(TableStats::default)
+                        .add_stat(row.get::<&str>(1).ok(), stat);
+                }
+                StepResult::IO => self.pager.io.run_once()?,
+                StepResult::Interrupt | StepResult::Done | StepResult::Busy => break,
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Run a statement that returns no rows to completion.
+    fn run_statement(self: &Rc<Connection>, sql: impl AsRef<str>) -> Result<()> {
+        let Some(mut stmt) = self.query(sql)? else {
+            return Ok(());
+        };
+        loop {
+            match stmt.step()? {
+                StepResult::Row => {}
+                StepResult::IO => self.pager.io.run_once()?,
+                StepResult::Interrupt | StepResult::Done => return Ok(()),
+                StepResult::Busy => {
+                    return Err(LimboError::LockingError("database is locked".to_string()))
+                }
+            }
+        }
+    }
+}
+
+/// The rows of sqlite_stat1 for `table`, whose indexes are `indexes`, as pairs of index name and
+/// `stat` column. A table without indexes has a single row without an index name, and an empty
+/// table has none, as in SQLite.
+fn gather_stats(
+    pager: &Rc<Pager>,
+    table: &BTreeTable,
+    indexes: &[Rc<Index>],
+) -> Result<Vec<(Option<String>, String)>> {
+    if indexes.is_empty() {
+        let mut cursor = BTreeCursor::new(pager.clone(), table.root_page);
+        let mut row_count = 0;
+        run_io(pager, || cursor.rewind())?;
+        while !cursor.is_empty() {
+            row_count += 1;
+            run_io(pager, || cursor.next())?;
+        }
+        if row_count == 0 {
+            return Ok(vec![]);
+        }
+        return Ok(vec![(None, row_count.to_string())]);
+    }
+    let mut rows = Vec::with_capacity(indexes.len());
+    for index in indexes {
+        let (row_count, num_distinct_keys) = count_distinct_keys(pager, index)?;
+        if row_count == 0 {
+            return Ok(vec![]);
+        }
+        let mut stat = row_count.to_string();
+        for num_keys in num_distinct_keys {
+            stat.push_str(&format!(" {}", row_count.div_ceil(num_keys)));
+        }
+        let name = if table.is_primary_key_index(index) {
+            table.name.clone()
+        } else {
+            index.name.clone()
+        };
+        rows.push((Some(name), stat));
+    }
+    Ok(rows)
+}
+
+/// Count the entries of `index`, and the distinct keys made of its first column, of its first two
+/// columns and so on. NULLs are counted as equal to each other.
+fn count_distinct_keys(pager: &Rc<Pager>, index: &Index) -> Result<(u64, Vec<u64>)> {
+    let num_columns = index.columns.len();
+    let mut cursor = BTreeCursor::new_index(pager.clone(), index.root_page, index);
+    let mut row_count = 0;
+    let mut num_distinct_keys = vec![0; num_columns];
+    let mut previous_key: Option<Vec<OwnedValue>> = None;
+    run_io(pager, || cursor.rewind())?;
+    while !cursor.is_empty() {
+        {
+            let record = cursor.record()?;
+            let key = &record.as_ref().unwrap().get_values()[..num_columns];
+            // The first column that differs from the previous key starts a new key of its prefix
+            // and of all the longer ones.
+            let first_different_column = previous_key.as_ref().map_or(0, |previous_key| {
+                previous_key
+                    .iter()
+                    .zip(key)
+                    .position(|(a, b)| a != b)
+                    .unwrap_or(num_columns)
+            });
+            for num_keys in num_distinct_keys[first_different_column..].iter_mut() {
+                *num_keys += 1;
+            }
+            previous_key = Some(key.to_vec());
+        }
+        row_count += 1;
+        run_io(pager, || cursor.next())?;
+    }
+    Ok((row_count, num_distinct_keys))
+}
+
+/// A string as an SQL string literal.
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
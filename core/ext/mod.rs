@@ -1,6 +1,7 @@
 use crate::{function::ExternalFunc, Database};
 use limbo_ext::{
-    ExtensionApi, InitAggFunction, ResultCode, ScalarFunction, VTabKind, VTabModuleImpl,
+    CollationFunction, ExtensionApi, InitAggFunction, ResultCode, ScalarFunction, VTabKind,
+    VTabModuleImpl,
 };
 pub use limbo_ext::{FinalizeFunction, StepFunction, Value as ExtValue, ValueType as ExtValueType};
 use std::{
@@ -74,6 +75,23 @@ unsafe extern "C" fn register_module(
     db.register_module_impl(&name_str, module, kind)
 }
 
+unsafe extern "C" fn register_collation(
+    ctx: *mut c_void,
+    name: *const c_char,
+    func: CollationFunction,
+) -> ResultCode {
+    let c_str = unsafe { CStr::from_ptr(name) };
+    let name_str = match c_str.to_str() {
+        Ok(s) => s.to_string(),
+        Err(_) => return ResultCode::InvalidArgs,
+    };
+    if ctx.is_null() {
+        return ResultCode::Error;
+    }
+    let db = unsafe { &*(ctx as *const Database) };
+    db.register_collation_impl(&name_str, func)
+}
+
 impl Database {
     fn register_scalar_function_impl(&self, name: &str, func: ScalarFunction) -> ResultCode {
         self.syms.borrow_mut().functions.insert(
@@ -114,12 +132,31 @@ impl Database {
         ResultCode::OK
     }
 
+    fn register_collation_impl(&self, name: &str, func: CollationFunction) -> ResultCode {
+        let compare = move |lhs: &str, rhs: &str| {
+            let cmp = unsafe {
+                func(
+                    lhs.as_ptr() as *const c_char,
+                    lhs.len(),
+                    rhs.as_ptr() as *const c_char,
+                    rhs.len(),
+                )
+            };
+            cmp.cmp(&0)
+        };
+        match self.create_collation(name, compare) {
+            Ok(()) => ResultCode::OK,
+            Err(_) => ResultCode::InvalidArgs,
+        }
+    }
+
     pub fn build_limbo_ext(&self) -> ExtensionApi {
         ExtensionApi {
             ctx: self as *const _ as *mut c_void,
             register_scalar_function,
             register_aggregate_function,
             register_module,
+            register_collation,
         }
     }
 
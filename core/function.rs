@@ -199,6 +199,78 @@ impl AggFunc {
     }
 }
 
+/// A function that is computed over a window of rows, e.g. `rank() OVER (ORDER BY x)`.
+/// Any aggregate function can also be used as a window function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WindowFunc {
+    RowNumber,
+    Rank,
+    DenseRank,
+    PercentRank,
+    CumeDist,
+    Ntile,
+    Lag,
+    Lead,
+    FirstValue,
+    LastValue,
+    NthValue,
+    Agg(AggFunc),
+}
+
+impl WindowFunc {
+    pub fn resolve_function(name: &str, arg_count: usize) -> Result<Self, LimboError> {
+        let (func, arg_count_ok) = match name {
+            "row_number" => (Self::RowNumber, arg_count == 0),
+            "rank" => (Self::Rank, arg_count == 0),
+            "dense_rank" => (Self::DenseRank, arg_count == 0),
+            "percent_rank" => (Self::PercentRank, arg_count == 0),
+            "cume_dist" => (Self::CumeDist, arg_count == 0),
+            "ntile" => (Self::Ntile, arg_count == 1),
+            "lag" => (Self::Lag, (1..=3).contains(&arg_count)),
+            "lead" => (Self::Lead, (1..=3).contains(&arg_count)),
+            "first_value" => (Self::FirstValue, arg_count == 1),
+            "last_value" => (Self::LastValue, arg_count == 1),
+            "nth_value" => (Self::NthValue, arg_count == 2),
+            _ => match Func::resolve_function(name, arg_count) {
+                Ok(Func::Agg(agg_func)) => return Ok(Self::Agg(agg_func)),
+                Ok(_) => {
+                    crate::bail_parse_error!("{}() may not be used as a window function", name)
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        if !arg_count_ok {
+            crate::bail_parse_error!("wrong number of arguments to function {}()", name)
+        }
+        Ok(func)
+    }
+
+    /// Whether the function is computed over the frame of the window, rather than the whole partition
+    pub fn uses_frame(&self) -> bool {
+        matches!(
+            self,
+            Self::FirstValue | Self::LastValue | Self::NthValue | Self::Agg(_)
+        )
+    }
+
+    pub fn to_string(&self) -> &str {
+        match self {
+            Self::RowNumber => "row_number",
+            Self::Rank => "rank",
+            Self::DenseRank => "dense_rank",
+            Self::PercentRank => "percent_rank",
+            Self::CumeDist => "cume_dist",
+            Self::Ntile => "ntile",
+            Self::Lag => "lag",
+            Self::Lead => "lead",
+            Self::FirstValue => "first_value",
+            Self::LastValue => "last_value",
+            Self::NthValue => "nth_value",
+            Self::Agg(agg_func) => agg_func.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ScalarFunc {
     Cast,
@@ -105,6 +105,38 @@ impl WrappedIOUring {
         Ok(())
     }
 
+    /// Run the callbacks of the operations that completed.
+    fn process_completions(&mut self) -> Result<()> {
+        while let Some(cqe) = self.get_completion() {
+            let result = cqe.result();
+            if result < 0 {
+                return Err(LimboError::UringIOError(format!(
+                    "{} cqe: {:?}",
+                    UringIOError::IOUringCQError(result),
+                    cqe
+                )));
+            }
+            {
+                if let Some(c) = self.pending[cqe.user_data() as usize].as_ref() {
+                    c.complete(cqe.result());
+                }
+            }
+            self.pending[cqe.user_data() as usize] = None;
+        }
+        Ok(())
+    }
+
+    /// Wait for pending operations to complete until there is room for one more. The keys and the
+    /// iovecs of operations are reused after MAX_IOVECS of them, so no more can be pending, as
+    /// when a transaction writes many pages to the WAL at once.
+    fn make_room(&mut self) -> Result<()> {
+        while self.pending_ops >= MAX_IOVECS as usize {
+            self.wait_for_completion()?;
+            self.process_completions()?;
+        }
+        Ok(())
+    }
+
     fn get_completion(&mut self) -> Option<io_uring::cqueue::Entry> {
         // NOTE: This works because CompletionQueue's next function pops the head of the queue. This is not normal behaviour of iterators
         let entry = self.ring.completion().next();
@@ -168,23 +200,7 @@ impl IO for UringIO {
         }
 
         ring.wait_for_completion()?;
-        while let Some(cqe) = ring.get_completion() {
-            let result = cqe.result();
-            if result < 0 {
-                return Err(LimboError::UringIOError(format!(
-                    "{} cqe: {:?}",
-                    UringIOError::IOUringCQError(result),
-                    cqe
-                )));
-            }
-            {
-                if let Some(c) = ring.pending[cqe.user_data() as usize].as_ref() {
-                    c.complete(cqe.result());
-                }
-            }
-            ring.pending[cqe.user_data() as usize] = None;
-        }
-        Ok(())
+        ring.process_completions()
     }
 
     fn generate_random_number(&self) -> i64 {
@@ -246,6 +262,7 @@ impl File for UringFile {
         trace!("pread(pos = {}, length = {})", pos, r.buf().len());
         let fd = io_uring::types::Fd(self.file.as_raw_fd());
         let mut io = self.io.borrow_mut();
+        io.ring.make_room()?;
         let read_e = {
             let mut buf = r.buf_mut();
             let len = buf.len();
@@ -262,6 +279,7 @@ impl File for UringFile {
 
     fn pwrite(&self, pos: usize, buffer: Rc<RefCell<crate::Buffer>>, c: Completion) -> Result<()> {
         let mut io = self.io.borrow_mut();
+        io.ring.make_room()?;
         let fd = io_uring::types::Fd(self.file.as_raw_fd());
         let write = {
             let buf = buffer.borrow();
@@ -279,6 +297,7 @@ impl File for UringFile {
     fn sync(&self, c: Completion) -> Result<()> {
         let fd = io_uring::types::Fd(self.file.as_raw_fd());
         let mut io = self.io.borrow_mut();
+        io.ring.make_room()?;
         trace!("sync()");
         let sync = io_uring::opcode::Fsync::new(fd)
             .build()
@@ -290,6 +309,11 @@ impl File for UringFile {
     fn size(&self) -> Result<u64> {
         Ok(self.file.metadata()?.len())
     }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.file.set_len(len as u64)?;
+        Ok(())
+    }
 }
 
 impl Drop for UringFile {
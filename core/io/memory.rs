@@ -3,15 +3,15 @@ use crate::Result;
 
 use std::{
     cell::{Cell, RefCell, UnsafeCell},
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     rc::Rc,
     sync::Arc,
 };
 use tracing::debug;
 
 pub struct MemoryIO {
-    pages: UnsafeCell<BTreeMap<usize, MemPage>>,
-    size: Cell<usize>,
+    /// The files opened so far by path, so that opening a path again opens the same file.
+    files: RefCell<HashMap<String, Rc<MemoryFile>>>,
 }
 
 // TODO: page size flag
@@ -23,31 +23,25 @@ impl MemoryIO {
     pub fn new() -> Result<Arc<Self>> {
         debug!("Using IO backend 'memory'");
         Ok(Arc::new(Self {
-            pages: BTreeMap::new().into(),
-            size: 0.into(),
+            files: RefCell::new(HashMap::new()),
         }))
     }
-
-    #[allow(clippy::mut_from_ref)]
-    fn get_or_allocate_page(&self, page_no: usize) -> &mut MemPage {
-        unsafe {
-            let pages = &mut *self.pages.get();
-            pages
-                .entry(page_no)
-                .or_insert_with(|| Box::new([0; PAGE_SIZE]))
-        }
-    }
-
-    fn get_page(&self, page_no: usize) -> Option<&MemPage> {
-        unsafe { (*self.pages.get()).get(&page_no) }
-    }
 }
 
 impl IO for Arc<MemoryIO> {
-    fn open_file(&self, _path: &str, _flags: OpenFlags, _direct: bool) -> Result<Rc<dyn File>> {
-        Ok(Rc::new(MemoryFile {
-            io: Arc::clone(self),
-        }))
+    fn open_file(&self, path: &str, _flags: OpenFlags, _direct: bool) -> Result<Rc<dyn File>> {
+        let file = self
+            .files
+            .borrow_mut()
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                Rc::new(MemoryFile {
+                    pages: BTreeMap::new().into(),
+                    size: 0.into(),
+                })
+            })
+            .clone();
+        Ok(file)
     }
 
     fn run_once(&self) -> Result<()> {
@@ -67,7 +61,24 @@ impl IO for Arc<MemoryIO> {
 }
 
 pub struct MemoryFile {
-    io: Arc<MemoryIO>,
+    pages: UnsafeCell<BTreeMap<usize, MemPage>>,
+    size: Cell<usize>,
+}
+
+impl MemoryFile {
+    #[allow(clippy::mut_from_ref)]
+    fn get_or_allocate_page(&self, page_no: usize) -> &mut MemPage {
+        unsafe {
+            let pages = &mut *self.pages.get();
+            pages
+                .entry(page_no)
+                .or_insert_with(|| Box::new([0; PAGE_SIZE]))
+        }
+    }
+
+    fn get_page(&self, page_no: usize) -> Option<&MemPage> {
+        unsafe { (*self.pages.get()).get(&page_no) }
+    }
 }
 
 impl File for MemoryFile {
@@ -86,7 +97,7 @@ impl File for MemoryFile {
             return Ok(());
         }
 
-        let file_size = self.io.size.get();
+        let file_size = self.size.get();
         if pos >= file_size {
             c.complete(0);
             return Ok(());
@@ -103,7 +114,7 @@ impl File for MemoryFile {
                 let page_no = offset / PAGE_SIZE;
                 let page_offset = offset % PAGE_SIZE;
                 let bytes_to_read = remaining.min(PAGE_SIZE - page_offset);
-                if let Some(page) = self.io.get_page(page_no) {
+                if let Some(page) = self.get_page(page_no) {
                     read_buf.as_mut_slice()[buf_offset..buf_offset + bytes_to_read]
                         .copy_from_slice(&page[page_offset..page_offset + bytes_to_read]);
                 } else {
@@ -138,7 +149,7 @@ impl File for MemoryFile {
             let bytes_to_write = remaining.min(PAGE_SIZE - page_offset);
 
             {
-                let page = self.io.get_or_allocate_page(page_no);
+                let page = self.get_or_allocate_page(page_no);
                 page[page_offset..page_offset + bytes_to_write]
                     .copy_from_slice(&data[buf_offset..buf_offset + bytes_to_write]);
             }
@@ -148,9 +159,8 @@ impl File for MemoryFile {
             remaining -= bytes_to_write;
         }
 
-        self.io
-            .size
-            .set(core::cmp::max(pos + buf_len, self.io.size.get()));
+        self.size
+            .set(core::cmp::max(pos + buf_len, self.size.get()));
 
         c.complete(buf_len as i32);
         Ok(())
@@ -163,7 +173,20 @@ impl File for MemoryFile {
     }
 
     fn size(&self) -> Result<u64> {
-        Ok(self.io.size.get() as u64)
+        Ok(self.size.get() as u64)
+    }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        if len >= self.size.get() {
+            return Ok(());
+        }
+        let pages = unsafe { &mut *self.pages.get() };
+        pages.retain(|page_no, _| page_no * PAGE_SIZE < len);
+        if let Some(page) = pages.get_mut(&(len / PAGE_SIZE)) {
+            page[len % PAGE_SIZE..].fill(0);
+        }
+        self.size.set(len);
+        Ok(())
     }
 }
 
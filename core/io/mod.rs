@@ -16,6 +16,7 @@ pub trait File {
     fn pwrite(&self, pos: usize, buffer: Rc<RefCell<Buffer>>, c: Completion) -> Result<()>;
     fn sync(&self, c: Completion) -> Result<()>;
     fn size(&self) -> Result<u64>;
+    fn truncate(&self, len: usize) -> Result<()>;
 }
 
 pub enum OpenFlags {
@@ -378,6 +378,12 @@ impl File for UnixFile<'_> {
         let file = self.file.borrow();
         Ok(file.metadata()?.len())
     }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        let file = self.file.borrow();
+        file.set_len(len as u64)?;
+        Ok(())
+    }
 }
 
 impl Drop for UnixFile<'_> {
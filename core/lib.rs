@@ -1,3 +1,4 @@
+mod analyze;
 mod error;
 mod ext;
 mod function;
@@ -16,6 +17,7 @@ mod translate;
 mod types;
 #[allow(dead_code)]
 mod util;
+mod vacuum;
 mod vdbe;
 mod vector;
 
@@ -31,7 +33,8 @@ use limbo_ext::{ExtensionApi, ExtensionEntryPoint};
 use limbo_ext::{ResultCode, VTabKind, VTabModuleImpl, Value as ExtValue};
 use limbo_sqlite3_parser::{ast, ast::Cmd, lexer::sql::Parser};
 use parking_lot::RwLock;
-use schema::{Column, Schema};
+use result::LimboResult;
+use schema::{Column, Schema, MAIN_DB, TEMP_DB};
 use std::borrow::Cow;
 use std::cell::Cell;
 use std::collections::HashMap;
@@ -39,7 +42,6 @@ use std::num::NonZero;
 use std::sync::{Arc, OnceLock};
 use std::{cell::RefCell, rc::Rc};
 use storage::btree::btree_init_page;
-#[cfg(feature = "fs")]
 use storage::database::FileStorage;
 use storage::page_cache::DumbLruPageCache;
 use storage::pager::allocate_page;
@@ -50,11 +52,12 @@ pub use storage::wal::WalFile;
 pub use storage::wal::WalFileShared;
 use types::OwnedValue;
 pub use types::Value;
-use util::{columns_from_create_table_body, parse_schema_rows};
+use util::{columns_from_create_table_body, normalize_ident, parse_schema_rows};
 use vdbe::builder::QueryMode;
 use vdbe::VTabOpaqueCursor;
 
 pub use error::LimboError;
+use translate::collate::{CollationFn, CollationSeq};
 use translate::select::prepare_select_plan;
 pub type Result<T, E = LimboError> = std::result::Result<T, E>;
 
@@ -84,6 +87,8 @@ enum TransactionState {
 }
 
 pub struct Database {
+    /// The path the database was opened at, empty if it wasn't opened from a path.
+    path: String,
     pager: Rc<Pager>,
     schema: Rc<RefCell<Schema>>,
     header: Rc<RefCell<DatabaseHeader>>,
@@ -114,16 +119,27 @@ impl Database {
             wal_shared.clone(),
             buffer_pool.clone(),
         )));
-        Self::open(io, page_io, wal, wal_shared, buffer_pool)
+        Self::open_at(path, io, page_io, wal, wal_shared, buffer_pool)
     }
 
-    #[allow(clippy::arc_with_non_send_sync)]
     pub fn open(
         io: Arc<dyn IO>,
         page_io: Rc<dyn DatabaseStorage>,
         wal: Rc<RefCell<dyn Wal>>,
         shared_wal: Arc<RwLock<WalFileShared>>,
         buffer_pool: Rc<BufferPool>,
+    ) -> Result<Arc<Database>> {
+        Self::open_at("", io, page_io, wal, shared_wal, buffer_pool)
+    }
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn open_at(
+        path: &str,
+        io: Arc<dyn IO>,
+        page_io: Rc<dyn DatabaseStorage>,
+        wal: Rc<RefCell<dyn Wal>>,
+        shared_wal: Arc<RwLock<WalFileShared>>,
+        buffer_pool: Rc<BufferPool>,
     ) -> Result<Arc<Database>> {
         let db_header = Pager::begin_open(page_io.clone())?;
         io.run_once()?;
@@ -144,6 +160,7 @@ impl Database {
         let schema = Rc::new(RefCell::new(Schema::new()));
         let syms = Rc::new(RefCell::new(SymbolTable::new()));
         let db = Database {
+            path: path.to_string(),
             pager: pager.clone(),
             schema: schema.clone(),
             header: header.clone(),
@@ -162,13 +179,22 @@ impl Database {
             header,
             auto_commit: RefCell::new(true),
             transaction_state: RefCell::new(TransactionState::None),
+            committed_schema: RefCell::new(None),
+            savepoints: RefCell::new(Vec::new()),
+            temp: RefCell::new(None),
+            attached: RefCell::new(Vec::new()),
             last_insert_rowid: Cell::new(0),
             last_change: Cell::new(0),
             total_changes: Cell::new(0),
+            foreign_keys: Cell::new(false),
+            defer_foreign_keys: Cell::new(false),
+            deferred_fk_violations: Cell::new(0),
+            deferred_imm_fk_violations: Cell::new(0),
         });
         let rows = conn.query("SELECT * FROM sqlite_schema")?;
-        let mut schema = schema.borrow_mut();
-        parse_schema_rows(rows, &mut schema, io, &syms.borrow())?;
+        parse_schema_rows(rows, &mut schema.borrow_mut(), io, &syms.borrow())?;
+        let stats = conn.read_stats("main")?;
+        schema.borrow_mut().stats = stats;
         Ok(db)
     }
 
@@ -181,11 +207,56 @@ impl Database {
             last_insert_rowid: Cell::new(0),
             auto_commit: RefCell::new(true),
             transaction_state: RefCell::new(TransactionState::None),
+            committed_schema: RefCell::new(None),
+            savepoints: RefCell::new(Vec::new()),
+            temp: RefCell::new(None),
+            attached: RefCell::new(Vec::new()),
             last_change: Cell::new(0),
             total_changes: Cell::new(0),
+            foreign_keys: Cell::new(false),
+            defer_foreign_keys: Cell::new(false),
+            deferred_fk_violations: Cell::new(0),
+            deferred_imm_fk_violations: Cell::new(0),
         })
     }
 
+    /// Registers a collation that COLLATE clauses can name, e.g. to sort text the way a locale does,
+    /// or replaces the function of the one with the same name. The function returns how the first
+    /// string sorts compared to the second one. The built-in collations can't be replaced, and
+    /// neither can a collation that an index orders its keys by, as the index would then have to
+    /// be rebuilt.
+    pub fn create_collation(
+        &self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> std::cmp::Ordering + 'static,
+    ) -> Result<()> {
+        if CollationSeq::builtin(name).is_some() {
+            return Err(LimboError::InvalidArgument(format!(
+                "cannot replace the built-in collation {}",
+                name
+            )));
+        }
+        let compare: CollationFn = Rc::new(compare);
+        let mut syms = self.syms.borrow_mut();
+        match syms
+            .collations
+            .iter_mut()
+            .find(|(registered, _)| registered.eq_ignore_ascii_case(name))
+        {
+            Some((_, function)) => {
+                if let Some(index) = self.schema.borrow().index_using_collation(name) {
+                    return Err(LimboError::InvalidArgument(format!(
+                        "cannot replace the collation {} that index {} is ordered by",
+                        name, index.name
+                    )));
+                }
+                *function = compare
+            }
+            None => syms.collations.push((name.into(), compare)),
+        }
+        Ok(())
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub fn load_extension<P: AsRef<std::ffi::OsStr>>(&self, path: P) -> Result<()> {
         let api = Box::new(self.build_limbo_ext());
@@ -213,52 +284,268 @@ impl Database {
 
 pub fn maybe_init_database_file(file: &Rc<dyn File>, io: &Arc<dyn IO>) -> Result<()> {
     if file.size()? == 0 {
-        // init db
-        let db_header = DatabaseHeader::default();
-        let page1 = allocate_page(
-            1,
-            &Rc::new(BufferPool::new(db_header.page_size as usize)),
+        init_database_file(file, io, &DatabaseHeader::default())?;
+    }
+    Ok(())
+}
+
+/// Write the first page of an empty database with the given header to `file`.
+fn init_database_file(
+    file: &Rc<dyn File>,
+    io: &Arc<dyn IO>,
+    db_header: &DatabaseHeader,
+) -> Result<()> {
+    let page1 = allocate_page(
+        1,
+        &Rc::new(BufferPool::new(db_header.page_size as usize)),
+        DATABASE_HEADER_SIZE,
+    );
+    {
+        // Create the sqlite_schema table, for this we just need to create the btree page
+        // for the first page of the database which is basically like any other btree page
+        // but with a 100 byte offset, so we just init the page so that sqlite understands
+        // this is a correct page.
+        btree_init_page(
+            &page1,
+            storage::sqlite3_ondisk::PageType::TableLeaf,
+            db_header,
             DATABASE_HEADER_SIZE,
         );
+
+        let contents = page1.get().contents.as_mut().unwrap();
+        contents.write_database_header(db_header);
+        // write the first page to disk synchronously
+        let flag_complete = Rc::new(RefCell::new(false));
         {
-            // Create the sqlite_schema table, for this we just need to create the btree page
-            // for the first page of the database which is basically like any other btree page
-            // but with a 100 byte offset, so we just init the page so that sqlite understands
-            // this is a correct page.
-            btree_init_page(
-                &page1,
-                storage::sqlite3_ondisk::PageType::TableLeaf,
-                &db_header,
-                DATABASE_HEADER_SIZE,
-            );
-
-            let contents = page1.get().contents.as_mut().unwrap();
-            contents.write_database_header(&db_header);
-            // write the first page to disk synchronously
-            let flag_complete = Rc::new(RefCell::new(false));
-            {
-                let flag_complete = flag_complete.clone();
-                let completion = Completion::Write(WriteCompletion::new(Box::new(move |_| {
-                    *flag_complete.borrow_mut() = true;
-                })));
-                file.pwrite(0, contents.buffer.clone(), completion)?;
+            let flag_complete = flag_complete.clone();
+            let completion = Completion::Write(WriteCompletion::new(Box::new(move |_| {
+                *flag_complete.borrow_mut() = true;
+            })));
+            file.pwrite(0, contents.buffer.clone(), completion)?;
+        }
+        let mut limit = 100;
+        loop {
+            io.run_once()?;
+            if *flag_complete.borrow() {
+                break;
             }
-            let mut limit = 100;
-            loop {
-                io.run_once()?;
-                if *flag_complete.borrow() {
-                    break;
-                }
-                limit -= 1;
-                if limit == 0 {
-                    panic!("Database file couldn't be initialized, io loop run for {} iterations and write didn't finish", limit);
-                }
+            limit -= 1;
+            if limit == 0 {
+                panic!("Database file couldn't be initialized, io loop run for {} iterations and write didn't finish", limit);
             }
         }
-    };
+    }
     Ok(())
 }
 
+/// An IO for files whose reads and writes complete right away. The statements of a connection only
+/// run the IO of its main database, so a database with another IO can't wait on it.
+#[cfg(all(feature = "fs", target_family = "unix"))]
+#[allow(clippy::arc_with_non_send_sync)]
+fn blocking_file_io() -> Result<Arc<dyn IO>> {
+    Ok(Arc::new(UnixIO::new()?))
+}
+
+#[cfg(all(feature = "fs", not(target_family = "unix")))]
+#[allow(clippy::arc_with_non_send_sync)]
+fn blocking_file_io() -> Result<Arc<dyn IO>> {
+    Ok(Arc::new(PlatformIO::new()?))
+}
+
+/// Open a database that lives in memory, named `name`, whose first page is initialized with the
+/// given header.
+#[allow(clippy::arc_with_non_send_sync)]
+fn open_memory_pager(name: &str, db_header: &DatabaseHeader) -> Result<Pager> {
+    let io: Arc<dyn IO> = Arc::new(MemoryIO::new()?);
+    let file = io.open_file(name, OpenFlags::Create, false)?;
+    init_database_file(&file, &io, db_header)?;
+    let page_io = Rc::new(FileStorage::new(file));
+    let db_header = Pager::begin_open(page_io.clone())?;
+    io.run_once()?;
+    let page_size = db_header.borrow().page_size;
+    let wal_path = format!("{}-wal", name);
+    let wal_shared = WalFileShared::open_shared(&io, &wal_path, page_size)?;
+    let buffer_pool = Rc::new(BufferPool::new(page_size as usize));
+    let wal = Rc::new(RefCell::new(WalFile::new(
+        io.clone(),
+        page_size as usize,
+        wal_shared,
+        buffer_pool.clone(),
+    )));
+    Pager::finish_open(
+        db_header,
+        page_io,
+        wal,
+        io,
+        Arc::new(RwLock::new(DumbLruPageCache::new(10))),
+        buffer_pool,
+    )
+}
+
+struct Savepoint {
+    /// The name of the savepoint, or none for the savepoint of the statement being run in a
+    /// transaction, which undoes the statement alone if it fails.
+    name: Option<String>,
+    /// The schema when the savepoint was opened.
+    schema: Schema,
+    /// Whether opening the savepoint started the transaction, which releasing it then commits.
+    starts_transaction: bool,
+    /// The deferred FOREIGN KEY constraint violations when the savepoint was opened.
+    fk_violations: (i64, i64),
+}
+
+/// A database of a connection besides its main database: the temp database, which holds the TEMP
+/// tables of the connection and goes away with it, or an attached database. Its transactions follow
+/// the one of the main database.
+struct AuxDatabase {
+    /// The index of the database, as in the `db` operand of instructions.
+    db: usize,
+    /// The name statements refer to the database by.
+    name: String,
+    /// The path of the database file, empty for a database that lives in memory.
+    path: String,
+    pager: Rc<Pager>,
+    schema: RefCell<Schema>,
+    transaction_state: RefCell<TransactionState>,
+    /// The schema as of the last commit, saved when the current transaction first changes it.
+    committed_schema: RefCell<Option<Schema>>,
+    /// The schema when each open savepoint of the transaction was opened, innermost last.
+    savepoint_schemas: RefCell<Vec<Schema>>,
+    /// The database that owns the pager of an attached database file.
+    database: Option<Arc<Database>>,
+}
+
+impl AuxDatabase {
+    /// Open a database that lives in memory, with the given empty schema.
+    fn open_memory(schema: Schema) -> Result<Self> {
+        let pager = open_memory_pager(&schema.name, &DatabaseHeader::default())?;
+        Ok(Self::new(schema, String::new(), Rc::new(pager), None))
+    }
+
+    /// Open the database file at `path`, to be attached as the database `db` named `name`.
+    #[cfg(feature = "fs")]
+    fn open_file(
+        io: Arc<dyn IO>,
+        path: &str,
+        db: usize,
+        name: &str,
+        syms: &SymbolTable,
+    ) -> Result<Self> {
+        let database = Database::open_file(io.clone(), path)?;
+        let mut schema = Schema::new_attached(db, name);
+        let conn = database.connect();
+        let rows = conn.query("SELECT * FROM sqlite_schema")?;
+        parse_schema_rows(rows, &mut schema, io, syms)?;
+        schema.stats = conn.read_stats("main")?;
+        let pager = database.pager.clone();
+        Ok(Self::new(schema, path.to_string(), pager, Some(database)))
+    }
+
+    fn new(
+        schema: Schema,
+        path: String,
+        pager: Rc<Pager>,
+        database: Option<Arc<Database>>,
+    ) -> Self {
+        Self {
+            db: schema.db,
+            name: schema.name.clone(),
+            path,
+            pager,
+            schema: RefCell::new(schema),
+            transaction_state: RefCell::new(TransactionState::None),
+            committed_schema: RefCell::new(None),
+            savepoint_schemas: RefCell::new(Vec::new()),
+            database,
+        }
+    }
+
+    /// Begin a read or write transaction, or upgrade the running read transaction to a write one.
+    fn begin(&self, write: bool) -> Result<LimboResult> {
+        let current_state = self.transaction_state.borrow().clone();
+        if current_state == TransactionState::None {
+            if let LimboResult::Busy = self.pager.begin_read_tx()? {
+                return Ok(LimboResult::Busy);
+            }
+            self.transaction_state.replace(TransactionState::Read);
+        }
+        if write && current_state != TransactionState::Write {
+            if let LimboResult::Busy = self.pager.begin_write_tx()? {
+                return Ok(LimboResult::Busy);
+            }
+            self.transaction_state.replace(TransactionState::Write);
+        }
+        Ok(LimboResult::Ok)
+    }
+
+    fn commit(&self) -> Result<()> {
+        match self.transaction_state.replace(TransactionState::None) {
+            TransactionState::None => return Ok(()),
+            TransactionState::Read => self.pager.end_read_tx()?,
+            TransactionState::Write => loop {
+                match self.pager.end_tx()? {
+                    CheckpointStatus::Done(_) => break,
+                    CheckpointStatus::IO => self.pager.io.run_once()?,
+                }
+            },
+        }
+        self.committed_schema.take();
+        self.savepoint_schemas.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        match self.transaction_state.replace(TransactionState::None) {
+            TransactionState::None => return Ok(()),
+            TransactionState::Read => self.pager.end_read_tx()?,
+            TransactionState::Write => self.pager.rollback()?,
+        }
+        if let Some(schema) = self.committed_schema.take() {
+            self.schema.replace(schema);
+        }
+        self.savepoint_schemas.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn open_savepoint(&self) {
+        self.pager.open_savepoint();
+        self.savepoint_schemas
+            .borrow_mut()
+            .push(self.schema.borrow().clone());
+    }
+
+    fn release_savepoint(&self, index: usize) {
+        self.pager.release_savepoint(index);
+        self.savepoint_schemas.borrow_mut().truncate(index);
+    }
+
+    fn rollback_to_savepoint(&self, index: usize) {
+        self.pager.rollback_to_savepoint(index);
+        let mut savepoint_schemas = self.savepoint_schemas.borrow_mut();
+        savepoint_schemas.truncate(index + 1);
+        self.schema.replace(savepoint_schemas[index].clone());
+    }
+
+    /// Saves the schema before the current transaction first changes it, so that it can be
+    /// restored if the transaction rolls back.
+    fn save_committed_schema(&self) {
+        let mut committed_schema = self.committed_schema.borrow_mut();
+        if committed_schema.is_none() {
+            *committed_schema = Some(self.schema.borrow().clone());
+        }
+    }
+
+    /// Write the changes in the WAL back to the database file.
+    fn checkpoint(&self) -> Result<()> {
+        loop {
+            match self.pager.checkpoint()? {
+                CheckpointStatus::Done(_) => return Ok(()),
+                CheckpointStatus::IO => self.pager.io.run_once()?,
+            }
+        }
+    }
+}
+
 pub struct Connection {
     db: Arc<Database>,
     pager: Rc<Pager>,
@@ -266,9 +553,26 @@ pub struct Connection {
     header: Rc<RefCell<DatabaseHeader>>,
     auto_commit: RefCell<bool>,
     transaction_state: RefCell<TransactionState>,
+    /// The schema as of the last commit, saved when the current transaction first changes it.
+    committed_schema: RefCell<Option<Schema>>,
+    /// The open savepoints of the current transaction, innermost last.
+    savepoints: RefCell<Vec<Savepoint>>,
+    /// The temp database, created the first time a statement uses it.
+    temp: RefCell<Option<Rc<AuxDatabase>>>,
+    /// The attached databases, in the order they were attached.
+    attached: RefCell<Vec<Rc<AuxDatabase>>>,
     last_insert_rowid: Cell<u64>,
     last_change: Cell<i64>,
     total_changes: Cell<i64>,
+    /// Whether FOREIGN KEY constraints are enforced, set by PRAGMA foreign_keys.
+    foreign_keys: Cell<bool>,
+    /// Whether the immediate FOREIGN KEY constraints of the current transaction are checked at
+    /// COMMIT, set by PRAGMA defer_foreign_keys.
+    defer_foreign_keys: Cell<bool>,
+    /// The violations of deferred FOREIGN KEY constraints by the current transaction.
+    deferred_fk_violations: Cell<i64>,
+    /// The violations of immediate FOREIGN KEY constraints deferred by PRAGMA defer_foreign_keys.
+    deferred_imm_fk_violations: Cell<i64>,
 }
 
 impl Connection {
@@ -281,15 +585,17 @@ impl Connection {
         if let Some(cmd) = cmd {
             match cmd {
                 Cmd::Stmt(stmt) => {
-                    let program = Rc::new(translate::translate(
-                        &self.schema.borrow(),
-                        stmt,
-                        self.header.clone(),
-                        self.pager.clone(),
-                        Rc::downgrade(self),
-                        &syms,
-                        QueryMode::Normal,
-                    )?);
+                    let program = Rc::new(self.with_schema(|schema| {
+                        translate::translate(
+                            schema,
+                            stmt,
+                            self.header.clone(),
+                            self.pager.clone(),
+                            Rc::downgrade(self),
+                            &syms,
+                            QueryMode::Normal,
+                        )
+                    })?);
                     Ok(Statement::new(program, self.pager.clone()))
                 }
                 Cmd::Explain(_stmt) => todo!(),
@@ -316,37 +622,43 @@ impl Connection {
         let syms = db.syms.borrow();
         match cmd {
             Cmd::Stmt(stmt) => {
-                let program = Rc::new(translate::translate(
-                    &self.schema.borrow(),
-                    stmt,
-                    self.header.clone(),
-                    self.pager.clone(),
-                    Rc::downgrade(self),
-                    &syms,
-                    QueryMode::Normal,
-                )?);
+                let program = Rc::new(self.with_schema(|schema| {
+                    translate::translate(
+                        schema,
+                        stmt,
+                        self.header.clone(),
+                        self.pager.clone(),
+                        Rc::downgrade(self),
+                        &syms,
+                        QueryMode::Normal,
+                    )
+                })?);
                 let stmt = Statement::new(program, self.pager.clone());
                 Ok(Some(stmt))
             }
             Cmd::Explain(stmt) => {
-                let program = translate::translate(
-                    &self.schema.borrow(),
-                    stmt,
-                    self.header.clone(),
-                    self.pager.clone(),
-                    Rc::downgrade(self),
-                    &syms,
-                    QueryMode::Explain,
-                )?;
+                let program = self.with_schema(|schema| {
+                    translate::translate(
+                        schema,
+                        stmt,
+                        self.header.clone(),
+                        self.pager.clone(),
+                        Rc::downgrade(self),
+                        &syms,
+                        QueryMode::Explain,
+                    )
+                })?;
                 program.explain();
                 Ok(None)
             }
             Cmd::ExplainQueryPlan(stmt) => {
                 match stmt {
                     ast::Stmt::Select(select) => {
-                        let mut plan =
-                            prepare_select_plan(&self.schema.borrow(), *select, &syms, None)?;
-                        optimize_plan(&mut plan, &self.schema.borrow())?;
+                        let plan = self.with_schema(|schema| {
+                            let mut plan = prepare_select_plan(schema, *select, &syms, None)?;
+                            optimize_plan(&mut plan, schema)?;
+                            Ok::<_, LimboError>(plan)
+                        })?;
                         println!("{}", plan);
                     }
                     _ => todo!(),
@@ -368,28 +680,32 @@ impl Connection {
         if let Some(cmd) = cmd {
             match cmd {
                 Cmd::Explain(stmt) => {
-                    let program = translate::translate(
-                        &self.schema.borrow(),
-                        stmt,
-                        self.header.clone(),
-                        self.pager.clone(),
-                        Rc::downgrade(self),
-                        &syms,
-                        QueryMode::Explain,
-                    )?;
+                    let program = self.with_schema(|schema| {
+                        translate::translate(
+                            schema,
+                            stmt,
+                            self.header.clone(),
+                            self.pager.clone(),
+                            Rc::downgrade(self),
+                            &syms,
+                            QueryMode::Explain,
+                        )
+                    })?;
                     program.explain();
                 }
                 Cmd::ExplainQueryPlan(_stmt) => todo!(),
                 Cmd::Stmt(stmt) => {
-                    let program = translate::translate(
-                        &self.schema.borrow(),
-                        stmt,
-                        self.header.clone(),
-                        self.pager.clone(),
-                        Rc::downgrade(self),
-                        &syms,
-                        QueryMode::Normal,
-                    )?;
+                    let program = self.with_schema(|schema| {
+                        translate::translate(
+                            schema,
+                            stmt,
+                            self.header.clone(),
+                            self.pager.clone(),
+                            Rc::downgrade(self),
+                            &syms,
+                            QueryMode::Normal,
+                        )
+                    })?;
 
                     let mut state =
                         vdbe::ProgramState::new(program.max_registers, program.cursor_ref.len());
@@ -400,6 +716,190 @@ impl Connection {
         Ok(())
     }
 
+    /// Saves the schema before the current transaction first changes it, so that it can be
+    /// restored if the transaction rolls back.
+    fn save_committed_schema(&self) {
+        let mut committed_schema = self.committed_schema.borrow_mut();
+        if committed_schema.is_none() {
+            *committed_schema = Some(self.schema.borrow().clone());
+        }
+    }
+
+    /// Run `f` with the schema statements are translated against: the schema of the main database,
+    /// along with the ones of the temp database and the attached databases of the connection.
+    fn with_schema<T>(&self, f: impl FnOnce(&Schema) -> T) -> T {
+        let temp = self.temp.borrow();
+        let attached = self.attached.borrow();
+        if temp.is_none() && attached.is_empty() {
+            return f(&self.schema.borrow());
+        }
+        let mut schema = self.schema.borrow().clone();
+        schema.temp = temp
+            .as_ref()
+            .map(|temp| Box::new(temp.schema.borrow().clone()));
+        schema.attached = attached
+            .iter()
+            .map(|database| database.schema.borrow().clone())
+            .collect();
+        drop((temp, attached));
+        f(&schema)
+    }
+
+    /// The temp database and the attached databases of the connection.
+    fn aux_databases(&self) -> Vec<Rc<AuxDatabase>> {
+        let temp = self.temp.borrow().clone();
+        temp.into_iter()
+            .chain(self.attached.borrow().iter().cloned())
+            .collect()
+    }
+
+    /// The database `db` of the connection, other than the main database.
+    fn aux_database(&self, db: usize) -> Result<Rc<AuxDatabase>> {
+        if db == TEMP_DB {
+            return self.temp_database();
+        }
+        self.attached
+            .borrow()
+            .iter()
+            .find(|database| database.db == db)
+            .cloned()
+            .ok_or_else(|| LimboError::InternalError(format!("no database with index {}", db)))
+    }
+
+    /// The temp database of the connection, which is created the first time a statement needs it
+    /// and joins the transaction of that statement, along with its savepoints.
+    fn temp_database(&self) -> Result<Rc<AuxDatabase>> {
+        if let Some(temp) = self.temp.borrow().as_ref() {
+            return Ok(temp.clone());
+        }
+        let temp = Rc::new(AuxDatabase::open_memory(Schema::new_temp())?);
+        temp.begin(true)?;
+        for _ in self.savepoints.borrow().iter() {
+            temp.open_savepoint();
+        }
+        self.temp.replace(Some(temp.clone()));
+        Ok(temp)
+    }
+
+    /// Attach the database file at `path`, or a new in-memory database for `:memory:`, as `name`.
+    pub(crate) fn attach(&self, path: &str, name: &str) -> Result<()> {
+        if !*self.auto_commit.borrow() {
+            return Err(LimboError::TxError(
+                "cannot ATTACH database within transaction".to_string(),
+            ));
+        }
+        let normalized_name = normalize_ident(name);
+        let in_use = matches!(normalized_name.as_str(), "main" | "temp")
+            || self
+                .attached
+                .borrow()
+                .iter()
+                .any(|database| database.name == normalized_name);
+        if in_use {
+            return Err(LimboError::InvalidArgument(format!(
+                "database {} is already in use",
+                name
+            )));
+        }
+        // The index of a detached database is reused, so that the others keep theirs
+        let db = (TEMP_DB + 1..)
+            .find(|db| self.attached.borrow().iter().all(|d| d.db != *db))
+            .unwrap();
+        let database = if path == ":memory:" || path.is_empty() {
+            AuxDatabase::open_memory(Schema::new_attached(db, &normalized_name))?
+        } else {
+            self.open_attached_file(path, db, &normalized_name)?
+        };
+        self.attached.borrow_mut().push(Rc::new(database));
+        Ok(())
+    }
+
+    #[cfg(feature = "fs")]
+    fn open_attached_file(&self, path: &str, db: usize, name: &str) -> Result<AuxDatabase> {
+        let syms = self.db.syms.borrow();
+        AuxDatabase::open_file(self.file_io()?, path, db, name, &syms)
+    }
+
+    /// The IO that opens the files of the connection other than its main database: the one of the
+    /// main database, unless it is in memory, as its IO can't reach the file system then.
+    #[cfg(feature = "fs")]
+    fn file_io(&self) -> Result<Arc<dyn IO>> {
+        match self.db.path.as_str() {
+            "" | ":memory:" => blocking_file_io(),
+            _ => Ok(self.pager.io.clone()),
+        }
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn file_io(&self) -> Result<Arc<dyn IO>> {
+        Ok(self.pager.io.clone())
+    }
+
+    #[cfg(not(feature = "fs"))]
+    fn open_attached_file(&self, path: &str, _db: usize, _name: &str) -> Result<AuxDatabase> {
+        Err(LimboError::InvalidArgument(format!(
+            "unable to open database: {}",
+            path
+        )))
+    }
+
+    /// Detach the database attached as `name`.
+    pub(crate) fn detach(&self, name: &str) -> Result<()> {
+        if !*self.auto_commit.borrow() {
+            return Err(LimboError::TxError(
+                "cannot DETACH database within transaction".to_string(),
+            ));
+        }
+        let normalized_name = normalize_ident(name);
+        if matches!(normalized_name.as_str(), "main" | "temp") {
+            return Err(LimboError::InvalidArgument(format!(
+                "cannot detach database {}",
+                name
+            )));
+        }
+        let mut attached = self.attached.borrow_mut();
+        let Some(position) = attached
+            .iter()
+            .position(|database| database.name == normalized_name)
+        else {
+            return Err(LimboError::InvalidArgument(format!(
+                "no such database: {}",
+                name
+            )));
+        };
+        let database = attached.remove(position);
+        if database.database.is_some() {
+            database.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// The sequence number, name and file path of every database of the connection, as listed by
+    /// `PRAGMA database_list`.
+    pub(crate) fn database_list(&self) -> Vec<(usize, String, String)> {
+        // The paths are listed in full, as SQLite does.
+        let full_path = |path: &str| match path {
+            "" | ":memory:" => String::new(),
+            path => std::fs::canonicalize(path)
+                .map(|path| path.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| path.to_string()),
+        };
+        let mut list = vec![(MAIN_DB, "main".to_string(), full_path(&self.db.path))];
+        if let Some(temp) = self.temp.borrow().as_ref() {
+            list.push((TEMP_DB, temp.name.clone(), String::new()));
+        }
+        // SQLite numbers the attached databases in the order they were attached, while the index
+        // of a detached database is reused here.
+        for (seq, database) in self.attached.borrow().iter().enumerate() {
+            list.push((
+                TEMP_DB + 1 + seq,
+                database.name.clone(),
+                full_path(&database.path),
+            ));
+        }
+        list
+    }
+
     pub fn cacheflush(&self) -> Result<CheckpointStatus> {
         self.pager.cacheflush()
     }
@@ -419,8 +919,15 @@ impl Connection {
         Database::load_extension(&self.db, path)
     }
 
-    /// Close a connection and checkpoint.
+    /// Close a connection and checkpoint. The temp database of the connection is dropped and the
+    /// attached databases are detached.
     pub fn close(&self) -> Result<()> {
+        self.temp.take();
+        for database in self.attached.take() {
+            if database.database.is_some() {
+                database.checkpoint()?;
+            }
+        }
         loop {
             // TODO: make this async?
             match self.pager.checkpoint()? {
@@ -451,6 +958,91 @@ impl Connection {
     pub fn total_changes(&self) -> i64 {
         self.total_changes.get()
     }
+
+    /// The violations of deferred FOREIGN KEY constraints that COMMIT would fail on.
+    fn deferred_fk_violations(&self) -> i64 {
+        self.deferred_fk_violations.get() + self.deferred_imm_fk_violations.get()
+    }
+
+    /// Forget the deferred FOREIGN KEY constraint violations, as the transaction ended.
+    fn reset_deferred_fk_violations(&self) {
+        self.deferred_fk_violations.set(0);
+        self.deferred_imm_fk_violations.set(0);
+        self.defer_foreign_keys.set(false);
+    }
+
+    /// Open a savepoint of the transaction, `starts_transaction` telling whether opening it starts
+    /// the transaction.
+    fn open_savepoint(&self, name: Option<String>, starts_transaction: bool) {
+        self.pager.open_savepoint();
+        for database in self.aux_databases() {
+            database.open_savepoint();
+        }
+        self.savepoints.borrow_mut().push(Savepoint {
+            name,
+            schema: self.schema.borrow().clone(),
+            starts_transaction,
+            fk_violations: (
+                self.deferred_fk_violations.get(),
+                self.deferred_imm_fk_violations.get(),
+            ),
+        });
+    }
+
+    /// Release the savepoint at `index` and the ones opened after it, keeping their changes.
+    fn release_savepoint(&self, index: usize) {
+        self.pager.release_savepoint(index);
+        for database in self.aux_databases() {
+            database.release_savepoint(index);
+        }
+        self.savepoints.borrow_mut().truncate(index);
+    }
+
+    /// Undo the changes made since the savepoint at `index` was opened, releasing the ones opened
+    /// after it. The savepoint itself stays open.
+    fn rollback_to_savepoint(&self, index: usize) {
+        let mut savepoints = self.savepoints.borrow_mut();
+        self.pager.rollback_to_savepoint(index);
+        for database in self.aux_databases() {
+            database.rollback_to_savepoint(index);
+        }
+        savepoints.truncate(index + 1);
+        self.schema.replace(savepoints[index].schema.clone());
+        let (deferred, deferred_imm) = savepoints[index].fk_violations;
+        self.deferred_fk_violations.set(deferred);
+        self.deferred_imm_fk_violations.set(deferred_imm);
+    }
+
+    /// The index of the savepoint of the statement being run, which is the innermost one.
+    fn statement_savepoint(&self) -> Option<usize> {
+        let savepoints = self.savepoints.borrow();
+        savepoints
+            .last()
+            .filter(|savepoint| savepoint.name.is_none())
+            .map(|_| savepoints.len() - 1)
+    }
+
+    /// Open the savepoint of a statement that writes in a transaction. The savepoint of a previous
+    /// statement that didn't run to completion is released first, keeping its changes.
+    fn open_statement_savepoint(&self) {
+        self.release_statement_savepoint();
+        self.open_savepoint(None, false);
+    }
+
+    /// Release the savepoint of the statement, which succeeded.
+    fn release_statement_savepoint(&self) {
+        if let Some(index) = self.statement_savepoint() {
+            self.release_savepoint(index);
+        }
+    }
+
+    /// Undo the changes of the statement, which failed, and release its savepoint.
+    fn rollback_statement(&self) {
+        if let Some(index) = self.statement_savepoint() {
+            self.rollback_to_savepoint(index);
+            self.release_savepoint(index);
+        }
+    }
 }
 
 pub struct Statement {
@@ -652,6 +1244,8 @@ pub(crate) struct SymbolTable {
     extensions: Vec<(Library, *const ExtensionApi)>,
     pub vtabs: HashMap<String, Rc<VirtualTable>>,
     pub vtab_modules: HashMap<String, Rc<crate::ext::VTabImpl>>,
+    /// The registered collations, in the order in which they were registered.
+    pub collations: Vec<(Rc<str>, CollationFn)>,
 }
 
 impl std::fmt::Debug for SymbolTable {
@@ -697,6 +1291,7 @@ impl SymbolTable {
             #[cfg(not(target_family = "wasm"))]
             extensions: Vec::new(),
             vtab_modules: HashMap::new(),
+            collations: Vec::new(),
         }
     }
 
@@ -707,6 +1302,13 @@ impl SymbolTable {
     ) -> Option<Rc<function::ExternalFunc>> {
         self.functions.get(name).cloned()
     }
+
+    pub fn resolve_collation(&self, name: &str) -> Option<CollationFn> {
+        self.collations
+            .iter()
+            .find(|(registered, _)| registered.eq_ignore_ascii_case(name))
+            .map(|(_, compare)| compare.clone())
+    }
 }
 
 pub struct QueryRunner<'a> {
@@ -1,31 +1,191 @@
+use crate::translate::collate::CollationSeq;
+use crate::util::{normalize_ident, PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX};
 use crate::VirtualTable;
-use crate::{util::normalize_ident, Result};
+use crate::{Result, SymbolTable};
 use core::fmt;
 use fallible_iterator::FallibleIterator;
-use limbo_sqlite3_parser::ast::{Expr, Literal, TableOptions};
+use limbo_sqlite3_parser::ast::{
+    self, ColumnDefinition, Expr, Literal, TableOptions, UnaryOperator,
+};
 use limbo_sqlite3_parser::{
     ast::{Cmd, CreateTableBody, QualifiedName, ResultColumn, Stmt},
+    dialect::keyword_token,
     lexer::sql::Parser,
 };
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::rc::Rc;
 use tracing::trace;
 
+/// The index of the main database of a connection, as in the `db` operand of instructions.
+pub const MAIN_DB: usize = 0;
+/// The index of the temp database of a connection, which holds its TEMP tables.
+pub const TEMP_DB: usize = 1;
+
+#[derive(Clone)]
 pub struct Schema {
+    /// The index of the database this schema describes: [MAIN_DB], [TEMP_DB] or the index of an
+    /// attached database.
+    pub db: usize,
+    /// The name statements refer to the database by: `main`, `temp` or the name it was attached as.
+    pub name: String,
     pub tables: HashMap<String, Rc<Table>>,
     // table_name to list of indexes for the table
     pub indexes: HashMap<String, Vec<Rc<Index>>>,
+    pub views: HashMap<String, Rc<View>>,
+    // table or view name to list of its triggers, the most recently created first
+    pub triggers: HashMap<String, Vec<Rc<Trigger>>>,
+    /// The schema of the temp database when statements are translated for a connection that has one.
+    /// Its objects are found before the ones of this schema with the same name.
+    pub temp: Option<Box<Schema>>,
+    /// The schemas of the databases attached to the connection statements are translated for.
+    /// Their objects are found after the ones of this schema, in the order they were attached.
+    pub attached: Vec<Schema>,
+    /// The statistics ANALYZE stored in the sqlite_stat1 table of the database, by table name.
+    pub stats: HashMap<String, TableStats>,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Schema {
     pub fn new() -> Self {
+        Self::with_schema_table(MAIN_DB, "main", sqlite_schema_table())
+    }
+
+    /// The schema of a temp database, whose schema table is sqlite_temp_schema.
+    pub fn new_temp() -> Self {
+        Self::with_schema_table(TEMP_DB, "temp", sqlite_temp_schema_table())
+    }
+
+    /// The schema of a database attached as `name`, which is the database `db` of the connection.
+    pub fn new_attached(db: usize, name: &str) -> Self {
+        let schema_table = BTreeTable {
+            db,
+            ..sqlite_schema_table()
+        };
+        Self::with_schema_table(db, name, schema_table)
+    }
+
+    fn with_schema_table(db: usize, name: &str, schema_table: BTreeTable) -> Self {
         let mut tables: HashMap<String, Rc<Table>> = HashMap::new();
         let indexes: HashMap<String, Vec<Rc<Index>>> = HashMap::new();
         tables.insert(
-            "sqlite_schema".to_string(),
-            Rc::new(Table::BTree(sqlite_schema_table().into())),
+            schema_table.name.clone(),
+            Rc::new(Table::BTree(schema_table.into())),
         );
-        Self { tables, indexes }
+        let views: HashMap<String, Rc<View>> = HashMap::new();
+        let triggers: HashMap<String, Vec<Rc<Trigger>>> = HashMap::new();
+        Self {
+            db,
+            name: name.to_string(),
+            tables,
+            indexes,
+            views,
+            triggers,
+            temp: None,
+            attached: Vec::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    /// The table a possibly schema-qualified name refers to. The schema qualifier names the database
+    /// the table is looked up in, while an unqualified name is looked up in every database: the temp
+    /// database first, then the main database, then the attached databases.
+    pub fn resolve_table(&self, name: &QualifiedName) -> Result<Option<Rc<Table>>> {
+        let table_name = &name.name.0;
+        match &name.db_name {
+            Some(db_name) => Ok(self.get_table_in(self.resolve_database(db_name)?, table_name)),
+            None => Ok(self.get_table(table_name)),
+        }
+    }
+
+    /// The index a possibly schema-qualified name refers to, looked up like [Schema::resolve_table].
+    pub fn resolve_index(&self, name: &QualifiedName) -> Result<Option<Rc<Index>>> {
+        match &name.db_name {
+            Some(db_name) => {
+                let db = self.resolve_database(db_name)?;
+                Ok(self
+                    .databases()
+                    .find(|schema| schema.db == db)
+                    .and_then(|schema| schema.get_index_in(&name.name.0)))
+            }
+            None => Ok(self.get_index(&name.name.0)),
+        }
+    }
+
+    /// The index of the database a schema qualifier names.
+    pub fn resolve_database(&self, db_name: &ast::Name) -> Result<usize> {
+        match self.database_index(&db_name.0) {
+            Some(db) => Ok(db),
+            None => crate::bail_parse_error!("unknown database {}", db_name.0),
+        }
+    }
+
+    /// The index of the database with the given name, if the connection has one. `temp` always
+    /// names the temp database, which is only created when it is first used.
+    pub fn database_index(&self, name: &str) -> Option<usize> {
+        let name = normalize_ident(name);
+        match name.as_str() {
+            "main" => Some(MAIN_DB),
+            "temp" => Some(TEMP_DB),
+            _ => self
+                .attached
+                .iter()
+                .find(|schema| schema.name == name)
+                .map(|schema| schema.db),
+        }
+    }
+
+    /// The name of the database `db`.
+    pub fn database_name(&self, db: usize) -> &str {
+        match db {
+            MAIN_DB => "main",
+            TEMP_DB => "temp",
+            _ => &self.database(db).name,
+        }
+    }
+
+    /// The table with the given name in the database `db`, ignoring the tables of other databases.
+    pub fn get_table_in(&self, db: usize, name: &str) -> Option<Rc<Table>> {
+        let name = normalize_ident(name);
+        let name = schema_table_name(Some(db), &name).unwrap_or(&name);
+        match self.databases().find(|schema| schema.db == db) {
+            Some(schema) => schema.tables.get(name).cloned(),
+            // The temp database is only created when it is first used, but its schema table can be
+            // read before
+            None if db == TEMP_DB && name == "sqlite_temp_schema" => {
+                Some(Rc::new(Table::BTree(sqlite_temp_schema_table().into())))
+            }
+            None => None,
+        }
+    }
+
+    /// The schemas of the databases in the order unqualified names are looked up in.
+    pub(crate) fn databases(&self) -> impl Iterator<Item = &Schema> {
+        self.temp
+            .as_deref()
+            .into_iter()
+            .chain(std::iter::once(self))
+            .chain(self.attached.iter())
+    }
+
+    /// The schema of the first database with a table or view with the given name, which hides the
+    /// objects of the other databases with that name. This schema if there is none.
+    fn schema_with(&self, name: &str) -> &Schema {
+        self.databases()
+            .find(|schema| schema.tables.contains_key(name) || schema.views.contains_key(name))
+            .unwrap_or(self)
+    }
+
+    /// The schema of the database `db`.
+    pub fn database(&self, db: usize) -> &Schema {
+        self.databases()
+            .find(|schema| schema.db == db)
+            .expect("the schema of the database is missing")
     }
 
     pub fn add_btree_table(&mut self, table: Rc<BTreeTable>) {
@@ -38,20 +198,35 @@ impl Schema {
         self.tables.insert(name, Table::Virtual(table).into());
     }
 
-    pub fn get_table(&self, name: &str) -> Option<Rc<Table>> {
+    /// Remove the table and all of its indexes and triggers.
+    pub fn remove_table(&mut self, name: &str) {
         let name = normalize_ident(name);
-        self.tables.get(&name).cloned()
+        self.tables.remove(&name);
+        self.indexes.remove(&name);
+        self.triggers.remove(&name);
+        self.stats.remove(&name);
     }
 
-    pub fn get_btree_table(&self, name: &str) -> Option<Rc<BTreeTable>> {
+    pub fn get_table(&self, name: &str) -> Option<Rc<Table>> {
         let name = normalize_ident(name);
-        if let Some(table) = self.tables.get(&name) {
-            table.btree()
-        } else {
-            None
+        match schema_table_name(None, &name) {
+            Some("sqlite_temp_schema") => self.get_table_in(TEMP_DB, "sqlite_temp_schema"),
+            Some(name) => self.get_table_in(MAIN_DB, name),
+            None => self.schema_with(&name).tables.get(&name).cloned(),
         }
     }
 
+    pub fn get_btree_table(&self, name: &str) -> Option<Rc<BTreeTable>> {
+        self.get_table(name).and_then(|table| table.btree())
+    }
+
+    /// The statistics of the table, if ANALYZE has stored any for it.
+    pub fn get_table_stats(&self, table: &BTreeTable) -> Option<&TableStats> {
+        self.database(table.db)
+            .stats
+            .get(&normalize_ident(&table.name))
+    }
+
     pub fn add_index(&mut self, index: Rc<Index>) {
         let table_name = normalize_ident(&index.table_name);
         self.indexes
@@ -59,6 +234,187 @@ impl Schema {
             .or_default()
             .push(index.clone())
     }
+
+    /// The indexes of the table, which belong to the same database as the table.
+    pub fn get_table_indices(&self, table: &BTreeTable) -> &[Rc<Index>] {
+        self.database(table.db)
+            .indexes
+            .get(&normalize_ident(&table.name))
+            .map_or(&[] as &[Rc<Index>], |indexes| indexes.as_slice())
+    }
+
+    /// The foreign keys that reference the table, as the child tables they belong to and their ids.
+    /// A foreign key references a table of its own database.
+    pub fn get_referencing_foreign_keys(&self, table: &BTreeTable) -> Vec<(Rc<BTreeTable>, usize)> {
+        let name = normalize_ident(&table.name);
+        let mut foreign_keys = vec![];
+        for child in self.database(table.db).tables.values() {
+            let Some(child) = child.btree() else {
+                continue;
+            };
+            for (id, foreign_key) in child.foreign_keys.iter().enumerate() {
+                if foreign_key.parent_table == name {
+                    foreign_keys.push((child.clone(), id));
+                }
+            }
+        }
+        foreign_keys.sort_by(|(a, a_id), (b, b_id)| a.name.cmp(&b.name).then(a_id.cmp(b_id)));
+        foreign_keys
+    }
+
+    /// The indexes of every table, from every database.
+    pub fn all_indices(&self) -> Cow<HashMap<String, Vec<Rc<Index>>>> {
+        if self.temp.is_none() && self.attached.is_empty() {
+            return Cow::Borrowed(&self.indexes);
+        }
+        let mut indexes = HashMap::new();
+        for schema in self.databases() {
+            for (table_name, table_indexes) in &schema.indexes {
+                indexes
+                    .entry(table_name.clone())
+                    .or_insert_with(Vec::new)
+                    .extend(table_indexes.iter().cloned());
+            }
+        }
+        Cow::Owned(indexes)
+    }
+
+    pub fn get_index(&self, name: &str) -> Option<Rc<Index>> {
+        let name = normalize_ident(name);
+        self.databases()
+            .find_map(|schema| schema.get_index_in(&name))
+    }
+
+    /// The index with the given name in the database of this schema, ignoring other databases.
+    pub fn get_index_in(&self, name: &str) -> Option<Rc<Index>> {
+        let name = normalize_ident(name);
+        self.indexes
+            .values()
+            .flatten()
+            .find(|index| index.name == name)
+            .cloned()
+    }
+
+    /// An index of any database whose keys are ordered by the collation with the given name,
+    /// including the primary key of a WITHOUT ROWID table.
+    pub fn index_using_collation(&self, name: &str) -> Option<Rc<Index>> {
+        let collation = CollationSeq::named(name);
+        self.databases().find_map(|schema| {
+            let primary_key_indexes = schema
+                .tables
+                .values()
+                .filter_map(|table| table.btree().and_then(|table| table.primary_key_index()));
+            schema
+                .indexes
+                .values()
+                .flatten()
+                .cloned()
+                .chain(primary_key_indexes)
+                .find(|index| {
+                    index
+                        .columns
+                        .iter()
+                        .any(|column| column.collation.as_ref() == Some(&collation))
+                })
+        })
+    }
+
+    pub fn remove_index(&mut self, index: &Index) {
+        let table_name = normalize_ident(&index.table_name);
+        if let Some(indexes) = self.indexes.get_mut(&table_name) {
+            indexes.retain(|i| i.name != index.name);
+        }
+        if let Some(stats) = self.stats.get_mut(&table_name) {
+            stats.rows_per_key.remove(&normalize_ident(&index.name));
+        }
+    }
+
+    pub fn add_view(&mut self, view: Rc<View>) {
+        let name = normalize_ident(&view.name);
+        self.views.insert(name, view);
+    }
+
+    pub fn get_view(&self, name: &str) -> Option<Rc<View>> {
+        let name = normalize_ident(name);
+        self.schema_with(&name).views.get(&name).cloned()
+    }
+
+    /// Remove the view and all of its triggers.
+    pub fn remove_view(&mut self, name: &str) {
+        let name = normalize_ident(name);
+        self.views.remove(&name);
+        self.triggers.remove(&name);
+    }
+
+    pub fn add_trigger(&mut self, trigger: Rc<Trigger>) {
+        // SQLite fires the triggers of a table in the reverse order of their creation
+        self.triggers
+            .entry(trigger.table_name.clone())
+            .or_default()
+            .insert(0, trigger);
+    }
+
+    pub fn get_trigger(&self, name: &str) -> Option<Rc<Trigger>> {
+        let name = normalize_ident(name);
+        self.triggers
+            .values()
+            .flatten()
+            .find(|trigger| trigger.name == name)
+            .cloned()
+    }
+
+    /// The triggers of the table or view that fire at `time` for `event`.
+    /// An UPDATE passes the names of the columns it sets, which decide whether UPDATE OF triggers fire.
+    pub fn get_triggers(
+        &self,
+        table_name: &str,
+        time: ast::TriggerTime,
+        event: ast::TriggerEvent,
+        updated_columns: &[String],
+    ) -> Vec<Rc<Trigger>> {
+        let name = normalize_ident(table_name);
+        self.schema_with(&name)
+            .own_triggers(&name, time, event, updated_columns)
+    }
+
+    /// The triggers of the table that fire at `time` for `event`, which belong to the same
+    /// database as the table.
+    pub fn get_table_triggers(
+        &self,
+        table: &BTreeTable,
+        time: ast::TriggerTime,
+        event: ast::TriggerEvent,
+        updated_columns: &[String],
+    ) -> Vec<Rc<Trigger>> {
+        self.database(table.db).own_triggers(
+            &normalize_ident(&table.name),
+            time,
+            event,
+            updated_columns,
+        )
+    }
+
+    fn own_triggers(
+        &self,
+        name: &str,
+        time: ast::TriggerTime,
+        event: ast::TriggerEvent,
+        updated_columns: &[String],
+    ) -> Vec<Rc<Trigger>> {
+        self.triggers
+            .get(name)
+            .map_or(&[] as &[Rc<Trigger>], |triggers| triggers.as_slice())
+            .iter()
+            .filter(|trigger| trigger.time == time && trigger.fires_for(&event, updated_columns))
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove_trigger(&mut self, trigger: &Trigger) {
+        if let Some(triggers) = self.triggers.get_mut(&trigger.table_name) {
+            triggers.retain(|t| t.name != trigger.name);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -128,19 +484,52 @@ impl PartialEq for Table {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BTreeTable {
+    /// The database the table is in, [MAIN_DB] or [TEMP_DB].
+    pub db: usize,
     pub root_page: usize,
     pub name: String,
-    pub primary_key_column_names: Vec<String>,
+    /// The columns of the primary key with their sort order, in declaration order.
+    pub primary_key_columns: Vec<(String, Order)>,
     pub columns: Vec<Column>,
     pub has_rowid: bool,
+    /// The FOREIGN KEY constraints of the table, the most recently declared first. Like in SQLite,
+    /// the position of a foreign key in this list is its id.
+    pub foreign_keys: Vec<ForeignKey>,
+    /// The PRIMARY KEY and UNIQUE constraints that have an automatic index, in the order SQLite
+    /// numbers them: the index of the constraint at position `i` is sqlite_autoindex_<table>_<i + 1>.
+    /// A primary key that is an alias of the rowid has no index, and constraints on the same columns
+    /// share one.
+    pub unique_sets: Vec<UniqueSet>,
+}
+
+/// The columns of a PRIMARY KEY or UNIQUE constraint, which are the columns of its automatic index.
+#[derive(Debug, Clone)]
+pub struct UniqueSet {
+    pub columns: Vec<IndexColumn>,
+    pub is_primary_key: bool,
+}
+
+impl UniqueSet {
+    /// Whether a constraint on `columns` is enforced by the index of this one, which is the case
+    /// when it has the same columns with the same collations, whatever their sort order.
+    fn covers(&self, columns: &[IndexColumn]) -> bool {
+        let collation =
+            |column: &IndexColumn| column.collation.clone().unwrap_or(CollationSeq::Binary);
+        self.columns.len() == columns.len()
+            && self
+                .columns
+                .iter()
+                .zip(columns)
+                .all(|(a, b)| a.name == b.name && collation(a) == collation(b))
+    }
 }
 
 impl BTreeTable {
     pub fn get_rowid_alias_column(&self) -> Option<(usize, &Column)> {
-        if self.primary_key_column_names.len() == 1 {
-            let (idx, col) = self.get_column(&self.primary_key_column_names[0]).unwrap();
+        if self.primary_key_columns.len() == 1 {
+            let (idx, col) = self.get_column(&self.primary_key_columns[0].0).unwrap();
             if self.column_is_rowid_alias(col) {
                 return Some((idx, col));
             }
@@ -162,6 +551,82 @@ impl BTreeTable {
         None
     }
 
+    /// The index of each column of the table, in the order their values are stored in a record.
+    /// The records of a WITHOUT ROWID table start with the primary key columns, followed by the
+    /// rest of the columns in table order.
+    pub fn columns_in_record_order(&self) -> Vec<usize> {
+        if self.has_rowid {
+            return (0..self.columns.len()).collect();
+        }
+        let mut order = Vec::with_capacity(self.columns.len());
+        let primary_key_columns = self
+            .primary_key_columns
+            .iter()
+            .filter_map(|(name, _)| self.get_column(name).map(|(idx, _)| idx));
+        for idx in primary_key_columns.chain(0..self.columns.len()) {
+            if !order.contains(&idx) {
+                order.push(idx);
+            }
+        }
+        order
+    }
+
+    /// The position of the value of the column at `column` in the records of the table.
+    pub fn column_record_position(&self, column: usize) -> usize {
+        if self.has_rowid {
+            return column;
+        }
+        self.columns_in_record_order()
+            .iter()
+            .position(|idx| *idx == column)
+            .expect("column not found in table")
+    }
+
+    /// The index whose b-tree holds the rows of a WITHOUT ROWID table, keyed by its primary key.
+    /// The b-tree of a rowid table is keyed by the rowid instead, so it has none.
+    pub fn primary_key_index(&self) -> Option<Rc<Index>> {
+        if self.has_rowid {
+            return None;
+        }
+        let position = self.unique_sets.iter().position(|set| set.is_primary_key)?;
+        let name = format!(
+            "{}{}_{}",
+            PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX,
+            self.name,
+            position + 1
+        );
+        Index::automatic_from_primary_key(self, &name, self.root_page)
+            .ok()
+            .map(Rc::new)
+    }
+
+    /// Whether `index` is the primary key index that holds the rows of this WITHOUT ROWID table.
+    pub fn is_primary_key_index(&self, index: &Index) -> bool {
+        !self.has_rowid && index.root_page == self.root_page
+    }
+
+    /// The columns that identify a row in the entries of `index`. For a rowid table this is the rowid
+    /// and the list is empty; for a WITHOUT ROWID table it is the primary key columns that the index
+    /// does not already contain.
+    pub fn primary_key_columns_not_in(&self, index: &Index) -> Vec<usize> {
+        if self.has_rowid {
+            return vec![];
+        }
+        let mut columns = vec![];
+        for (name, _) in self.primary_key_columns.iter() {
+            let name = normalize_ident(name);
+            if index.columns.iter().any(|column| column.name == name) {
+                continue;
+            }
+            if let Some((idx, _)) = self.get_column(&name) {
+                if !columns.contains(&idx) {
+                    columns.push(idx);
+                }
+            }
+        }
+        columns
+    }
+
     pub fn from_sql(sql: &str, root_page: usize) -> Result<BTreeTable> {
         let mut parser = Parser::new(sql.as_bytes());
         let cmd = parser.next()?;
@@ -173,23 +638,239 @@ impl BTreeTable {
         }
     }
 
-    #[cfg(test)]
+    /// The CREATE TABLE statement that describes the table, as stored in sqlite_schema.
     pub fn to_sql(&self) -> String {
-        let mut sql = format!("CREATE TABLE {} (\n", self.name);
+        let mut sql = format!("CREATE TABLE {} (", quote_ident(&self.name));
+        // The automatic indexes are named after the order of the PRIMARY KEY and UNIQUE constraints,
+        // column constraints first, so the first constraints are declared on their column as long as
+        // their columns come in order, and the others are declared as table constraints.
+        let mut column_constraints = vec![None; self.columns.len()];
+        let mut table_constraints = &self.unique_sets[..];
+        while let Some((set, rest)) = table_constraints.split_first() {
+            let [set_column] = set.columns.as_slice() else {
+                break;
+            };
+            let Some((idx, column)) = self.get_column(&set_column.name) else {
+                break;
+            };
+            if column_constraints[idx..].iter().any(Option::is_some)
+                || (!set.is_primary_key
+                    && (set_column.order == Order::Descending
+                        || !set.covers(&[IndexColumn {
+                            collation: column.collation.clone(),
+                            ..set_column.clone()
+                        }])))
+            {
+                break;
+            }
+            column_constraints[idx] = Some(set);
+            table_constraints = rest;
+        }
         for (i, column) in self.columns.iter().enumerate() {
             if i > 0 {
-                sql.push_str(",\n");
+                sql.push_str(", ");
+            }
+            sql.push_str(&quote_ident(
+                column.name.as_ref().expect("column name is None"),
+            ));
+            if !column.ty_str.is_empty() {
+                sql.push(' ');
+                sql.push_str(&column.ty_str);
+            }
+            if column.is_rowid_alias {
+                sql.push_str(" PRIMARY KEY");
+            }
+            match column_constraints[i] {
+                Some(set) if set.is_primary_key => {
+                    sql.push_str(" PRIMARY KEY");
+                    if self.primary_key_columns[0].1 == Order::Descending {
+                        sql.push_str(" DESC");
+                    }
+                }
+                Some(_) => sql.push_str(" UNIQUE"),
+                None => {}
+            }
+            if column.notnull {
+                sql.push_str(" NOT NULL");
+            }
+            if let Some(collation) = &column.collation {
+                sql.push_str(&format!(" COLLATE {}", collation));
+            }
+            if let Some(default) = &column.default {
+                match default {
+                    Expr::Literal(_) | Expr::Parenthesized(_) => {
+                        sql.push_str(&format!(" DEFAULT {}", default))
+                    }
+                    Expr::Unary(UnaryOperator::Negative, expr)
+                        if matches!(expr.as_ref(), Expr::Literal(_)) =>
+                    {
+                        sql.push_str(&format!(" DEFAULT -{}", expr))
+                    }
+                    _ => sql.push_str(&format!(" DEFAULT ({})", default)),
+                }
+            }
+        }
+        for set in table_constraints {
+            let columns = if set.is_primary_key {
+                self.primary_key_columns
+                    .iter()
+                    .map(|(name, order)| match order {
+                        Order::Ascending => quote_ident(&normalize_ident(name)),
+                        Order::Descending => {
+                            format!("{} DESC", quote_ident(&normalize_ident(name)))
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                set.columns
+                    .iter()
+                    .map(|set_column| {
+                        let mut sql = quote_ident(&set_column.name);
+                        let column_collation = self
+                            .get_column(&set_column.name)
+                            .and_then(|(_, column)| column.collation.as_ref());
+                        if let Some(collation) = &set_column.collation {
+                            if column_collation != Some(collation) {
+                                sql.push_str(&format!(" COLLATE {}", collation));
+                            }
+                        }
+                        if set_column.order == Order::Descending {
+                            sql.push_str(" DESC");
+                        }
+                        sql
+                    })
+                    .collect::<Vec<_>>()
+            };
+            sql.push_str(&format!(
+                ", {} ({})",
+                if set.is_primary_key {
+                    "PRIMARY KEY"
+                } else {
+                    "UNIQUE"
+                },
+                columns.join(", ")
+            ));
+        }
+        for foreign_key in self.foreign_keys.iter().rev() {
+            sql.push_str(", ");
+            sql.push_str(&foreign_key.to_sql());
+        }
+        sql.push(')');
+        if !self.has_rowid {
+            sql.push_str(" WITHOUT ROWID");
+        }
+        sql
+    }
+}
+
+/// A FOREIGN KEY constraint of a table, the child table, which requires the values of some of its
+/// columns to be those of a key of another table, the parent table.
+#[derive(Debug, Clone)]
+pub struct ForeignKey {
+    /// The columns of the child table, in the order of the columns of the parent key.
+    pub child_columns: Vec<String>,
+    pub parent_table: String,
+    /// The columns of the parent key, or none when the parent key is the primary key of the
+    /// parent table.
+    pub parent_columns: Vec<String>,
+    pub on_delete: ast::RefAct,
+    pub on_update: ast::RefAct,
+    /// Whether the constraint is DEFERRABLE INITIALLY DEFERRED, which checks it when the
+    /// transaction commits instead of at the end of each statement.
+    pub deferred: bool,
+}
+
+impl ForeignKey {
+    pub fn new(
+        child_columns: Vec<String>,
+        clause: &ast::ForeignKeyClause,
+        defer_clause: Option<&ast::DeferSubclause>,
+    ) -> Self {
+        let mut on_delete = ast::RefAct::NoAction;
+        let mut on_update = ast::RefAct::NoAction;
+        for arg in &clause.args {
+            match arg {
+                ast::RefArg::OnDelete(action) => on_delete = *action,
+                ast::RefArg::OnUpdate(action) => on_update = *action,
+                ast::RefArg::OnInsert(_) | ast::RefArg::Match(_) => {}
             }
-            sql.push_str("  ");
-            sql.push_str(column.name.as_ref().expect("column name is None"));
-            sql.push(' ');
-            sql.push_str(&column.ty.to_string());
         }
-        sql.push_str(");\n");
+        Self {
+            child_columns,
+            parent_table: normalize_ident(&clause.tbl_name.0),
+            parent_columns: clause.columns.as_ref().map_or(vec![], |columns| {
+                columns
+                    .iter()
+                    .map(|column| normalize_ident(&column.col_name.0))
+                    .collect()
+            }),
+            on_delete,
+            on_update,
+            deferred: defer_clause.is_some_and(Self::is_initially_deferred),
+        }
+    }
+
+    fn is_initially_deferred(defer_clause: &ast::DeferSubclause) -> bool {
+        defer_clause.deferrable
+            && defer_clause.init_deferred == Some(ast::InitDeferredPred::InitiallyDeferred)
+    }
+
+    /// The table constraint that declares the foreign key in a CREATE TABLE statement.
+    pub fn to_sql(&self) -> String {
+        let quote_columns = |columns: &[String]| {
+            columns
+                .iter()
+                .map(|column| quote_ident(column))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut sql = format!(
+            "FOREIGN KEY ({}) REFERENCES {}",
+            quote_columns(&self.child_columns),
+            quote_ident(&self.parent_table)
+        );
+        if !self.parent_columns.is_empty() {
+            sql.push_str(&format!(" ({})", quote_columns(&self.parent_columns)));
+        }
+        if self.on_delete != ast::RefAct::NoAction {
+            sql.push_str(&format!(" ON DELETE {}", ref_act_name(self.on_delete)));
+        }
+        if self.on_update != ast::RefAct::NoAction {
+            sql.push_str(&format!(" ON UPDATE {}", ref_act_name(self.on_update)));
+        }
+        if self.deferred {
+            sql.push_str(" DEFERRABLE INITIALLY DEFERRED");
+        }
         sql
     }
 }
 
+/// The name of a foreign key action, as PRAGMA foreign_key_list shows it.
+pub fn ref_act_name(action: ast::RefAct) -> &'static str {
+    match action {
+        ast::RefAct::SetNull => "SET NULL",
+        ast::RefAct::SetDefault => "SET DEFAULT",
+        ast::RefAct::Cascade => "CASCADE",
+        ast::RefAct::Restrict => "RESTRICT",
+        ast::RefAct::NoAction => "NO ACTION",
+    }
+}
+
+/// Quote an identifier for use in SQL text if it is not a plain identifier.
+pub fn quote_ident(name: &str) -> String {
+    let mut chars = name.chars();
+    let is_plain = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && keyword_token(name.as_bytes()).is_none();
+    if is_plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
 #[derive(Debug)]
 pub struct PseudoTable {
     pub columns: Vec<Column>,
@@ -213,6 +894,7 @@ impl PseudoTable {
             is_rowid_alias: false,
             notnull: false,
             default: None,
+            collation: None,
         });
     }
     pub fn get_column(&self, name: &str) -> Option<(usize, &Column)> {
@@ -240,117 +922,140 @@ fn create_table(
     let table_name = normalize_ident(&tbl_name.name.0);
     trace!("Creating table {}", table_name);
     let mut has_rowid = true;
-    let mut primary_key_column_names = vec![];
+    let mut primary_key_columns = vec![];
     let mut cols = vec![];
+    let mut foreign_keys = vec![];
+    // The columns of the PRIMARY KEY and UNIQUE constraints in the order they are declared, with the
+    // collations of their COLLATE clauses, and whether they are the primary key.
+    let mut constraint_columns = vec![];
     match body {
         CreateTableBody::ColumnsAndConstraints {
             columns,
             constraints,
             options,
         } => {
+            let mut table_foreign_keys = vec![];
+            let mut table_constraint_columns = vec![];
             if let Some(constraints) = constraints {
                 for c in constraints {
-                    if let limbo_sqlite3_parser::ast::TableConstraint::PrimaryKey {
-                        columns, ..
-                    } = c.constraint
-                    {
-                        for column in columns {
-                            primary_key_column_names.push(match column.expr {
-                                Expr::Id(id) => normalize_ident(&id.0),
-                                Expr::Literal(Literal::String(value)) => {
-                                    value.trim_matches('\'').to_owned()
-                                }
-                                _ => {
-                                    todo!("Unsupported primary key expression");
-                                }
-                            });
+                    match c.constraint {
+                        ast::TableConstraint::PrimaryKey { columns, .. } => {
+                            let mut set = vec![];
+                            for column in columns {
+                                let name = match column.expr {
+                                    Expr::Id(id) => normalize_ident(&id.0),
+                                    Expr::Literal(Literal::String(value)) => {
+                                        value.trim_matches('\'').to_owned()
+                                    }
+                                    _ => {
+                                        todo!("Unsupported primary key expression");
+                                    }
+                                };
+                                let order = Order::from(column.order);
+                                set.push((name.clone(), order.clone(), None));
+                                primary_key_columns.push((name, order));
+                            }
+                            table_constraint_columns.push((set, true));
                         }
+                        ast::TableConstraint::Unique { columns, .. } => {
+                            let set = columns
+                                .into_iter()
+                                .map(|column| {
+                                    let (expr, collation) = match column.expr {
+                                        Expr::Collate(expr, collation) => {
+                                            (*expr, Some(CollationSeq::named(&collation)))
+                                        }
+                                        expr => (expr, None),
+                                    };
+                                    let name = match expr {
+                                        Expr::Id(id) => normalize_ident(&id.0),
+                                        Expr::Literal(Literal::String(value)) => {
+                                            value.trim_matches('\'').to_owned()
+                                        }
+                                        _ => todo!("Unsupported unique expression"),
+                                    };
+                                    (name, Order::from(column.order), collation)
+                                })
+                                .collect();
+                            table_constraint_columns.push((set, false));
+                        }
+                        ast::TableConstraint::ForeignKey {
+                            columns,
+                            clause,
+                            deref_clause,
+                        } => {
+                            let child_columns = columns
+                                .iter()
+                                .map(|column| normalize_ident(&column.col_name.0))
+                                .collect();
+                            table_foreign_keys.push(ForeignKey::new(
+                                child_columns,
+                                &clause,
+                                deref_clause.as_ref(),
+                            ));
+                        }
+                        _ => {}
                     }
                 }
             }
             for (col_name, col_def) in columns {
                 let name = col_name.0.to_string();
-                // Regular sqlite tables have an integer rowid that uniquely identifies a row.
-                // Even if you create a table with a column e.g. 'id INT PRIMARY KEY', there will still
-                // be a separate hidden rowid, and the 'id' column will have a separate index built for it.
-                //
-                // However:
-                // A column defined as exactly INTEGER PRIMARY KEY is a rowid alias, meaning that the rowid
-                // and the value of this column are the same.
-                // https://www.sqlite.org/lang_createtable.html#rowids_and_the_integer_primary_key
-                let mut typename_exactly_integer = false;
-                let (ty, ty_str) = match col_def.col_type {
-                    Some(data_type) => {
-                        let s = data_type.name.as_str();
-                        let ty_str = if matches!(
-                            s.to_uppercase().as_str(),
-                            "TEXT" | "INT" | "INTEGER" | "BLOB" | "REAL"
-                        ) {
-                            s.to_uppercase().to_string()
-                        } else {
-                            s.to_string()
-                        };
-
-                        // https://www.sqlite.org/datatype3.html
-                        let type_name = ty_str.to_uppercase();
-                        if type_name.contains("INT") {
-                            typename_exactly_integer = type_name == "INTEGER";
-                            (Type::Integer, ty_str)
-                        } else if type_name.contains("CHAR")
-                            || type_name.contains("CLOB")
-                            || type_name.contains("TEXT")
-                        {
-                            (Type::Text, ty_str)
-                        } else if type_name.contains("BLOB") {
-                            (Type::Blob, ty_str)
-                        } else if type_name.is_empty() {
-                            (Type::Blob, "".to_string())
-                        } else if type_name.contains("REAL")
-                            || type_name.contains("FLOA")
-                            || type_name.contains("DOUB")
-                        {
-                            (Type::Real, ty_str)
-                        } else {
-                            (Type::Numeric, ty_str)
-                        }
-                    }
-                    None => (Type::Null, "".to_string()),
-                };
-
-                let mut default = None;
-                let mut primary_key = false;
-                let mut notnull = false;
-                for c_def in &col_def.constraints {
-                    match &c_def.constraint {
-                        limbo_sqlite3_parser::ast::ColumnConstraint::PrimaryKey { .. } => {
-                            primary_key = true;
-                        }
-                        limbo_sqlite3_parser::ast::ColumnConstraint::NotNull { .. } => {
-                            notnull = true;
+                let mut column = Column::from_definition(&col_def);
+                if column.primary_key {
+                    let order = col_def
+                        .constraints
+                        .iter()
+                        .find_map(|constraint| match constraint.constraint {
+                            ast::ColumnConstraint::PrimaryKey { order, .. } => Some(order),
+                            _ => None,
+                        });
+                    primary_key_columns.push((name.clone(), Order::from(order.flatten())));
+                } else if primary_key_columns
+                    .iter()
+                    .any(|(pk_name, _)| *pk_name == name)
+                {
+                    column.primary_key = true;
+                    column.is_rowid_alias = column.ty_str == "INTEGER";
+                }
+                let column_foreign_keys = foreign_keys.len();
+                for constraint in &col_def.constraints {
+                    match &constraint.constraint {
+                        ast::ColumnConstraint::PrimaryKey { order, .. } => {
+                            constraint_columns.push((
+                                vec![(normalize_ident(&name), Order::from(*order), None)],
+                                true,
+                            ))
                         }
-                        limbo_sqlite3_parser::ast::ColumnConstraint::Default(expr) => {
-                            default = Some(expr.clone())
+                        ast::ColumnConstraint::Unique(_) => constraint_columns.push((
+                            vec![(normalize_ident(&name), Order::Ascending, None)],
+                            false,
+                        )),
+                        ast::ColumnConstraint::ForeignKey {
+                            clause,
+                            deref_clause,
+                        } => foreign_keys.push(ForeignKey::new(
+                            vec![normalize_ident(&name)],
+                            clause,
+                            deref_clause.as_ref(),
+                        )),
+                        // The DEFERRABLE clause of a REFERENCES column constraint is parsed as
+                        // a constraint of its own.
+                        ast::ColumnConstraint::Defer(defer_clause)
+                            if foreign_keys.len() > column_foreign_keys =>
+                        {
+                            foreign_keys.last_mut().unwrap().deferred =
+                                ForeignKey::is_initially_deferred(defer_clause);
                         }
                         _ => {}
                     }
                 }
-
-                if primary_key {
-                    primary_key_column_names.push(name.clone());
-                } else if primary_key_column_names.contains(&name) {
-                    primary_key = true;
-                }
-
-                cols.push(Column {
-                    name: Some(normalize_ident(&name)),
-                    ty,
-                    ty_str,
-                    primary_key,
-                    is_rowid_alias: typename_exactly_integer && primary_key,
-                    notnull,
-                    default,
-                });
+                cols.push(column);
             }
+            // SQLite numbers the foreign keys from the last one declared, the table constraints
+            // being declared after the columns.
+            foreign_keys.extend(table_foreign_keys);
+            foreign_keys.reverse();
+            constraint_columns.extend(table_constraint_columns);
             if options.contains(TableOptions::WITHOUT_ROWID) {
                 has_rowid = false;
             }
@@ -359,17 +1064,48 @@ fn create_table(
     };
     // flip is_rowid_alias back to false if the table has multiple primary keys
     // or if the table has no rowid
-    if !has_rowid || primary_key_column_names.len() > 1 {
+    if !has_rowid || primary_key_columns.len() > 1 {
         for col in cols.iter_mut() {
             col.is_rowid_alias = false;
         }
     }
+    let has_rowid_alias = cols.iter().any(|col| col.is_rowid_alias);
+    let mut unique_sets: Vec<UniqueSet> = vec![];
+    for (columns, is_primary_key) in constraint_columns {
+        if is_primary_key && has_rowid_alias {
+            continue;
+        }
+        let columns = columns
+            .into_iter()
+            .map(|(name, order, collation)| IndexColumn {
+                collation: collation.or_else(|| {
+                    cols.iter()
+                        .find(|col| col.name.as_deref() == Some(name.as_str()))
+                        .and_then(|col| col.collation.clone())
+                }),
+                name,
+                order,
+            })
+            .collect::<Vec<_>>();
+        // A constraint on the columns of an earlier one is enforced by its index, which becomes
+        // the primary key index if the constraint is the primary key.
+        match unique_sets.iter_mut().find(|set| set.covers(&columns)) {
+            Some(set) => set.is_primary_key |= is_primary_key,
+            None => unique_sets.push(UniqueSet {
+                columns,
+                is_primary_key,
+            }),
+        }
+    }
     Ok(BTreeTable {
+        db: MAIN_DB,
         root_page,
         name: table_name,
         has_rowid,
-        primary_key_column_names,
+        primary_key_columns,
         columns: cols,
+        foreign_keys,
+        unique_sets,
     })
 }
 
@@ -401,12 +1137,101 @@ pub struct Column {
     pub is_rowid_alias: bool,
     pub notnull: bool,
     pub default: Option<Expr>,
+    /// The collation of a `COLLATE` clause, BINARY if there is none.
+    pub collation: Option<CollationSeq>,
 }
 
 impl Column {
     pub fn affinity(&self) -> Affinity {
         affinity(&self.ty_str)
     }
+
+    /// Build a column from its definition in a CREATE TABLE or ALTER TABLE ADD COLUMN statement.
+    /// Table constraints, such as a PRIMARY KEY over several columns, are not taken into account.
+    pub fn from_definition(col_def: &ColumnDefinition) -> Column {
+        // Regular sqlite tables have an integer rowid that uniquely identifies a row.
+        // Even if you create a table with a column e.g. 'id INT PRIMARY KEY', there will still
+        // be a separate hidden rowid, and the 'id' column will have a separate index built for it.
+        //
+        // However:
+        // A column defined as exactly INTEGER PRIMARY KEY is a rowid alias, meaning that the rowid
+        // and the value of this column are the same.
+        // https://www.sqlite.org/lang_createtable.html#rowids_and_the_integer_primary_key
+        let mut typename_exactly_integer = false;
+        let (ty, ty_str) = match &col_def.col_type {
+            Some(data_type) => {
+                let s = data_type.name.as_str();
+                let ty_str = if matches!(
+                    s.to_uppercase().as_str(),
+                    "TEXT" | "INT" | "INTEGER" | "BLOB" | "REAL"
+                ) {
+                    s.to_uppercase().to_string()
+                } else {
+                    s.to_string()
+                };
+
+                // https://www.sqlite.org/datatype3.html
+                let type_name = ty_str.to_uppercase();
+                if type_name.contains("INT") {
+                    typename_exactly_integer = type_name == "INTEGER";
+                    (Type::Integer, ty_str)
+                } else if type_name.contains("CHAR")
+                    || type_name.contains("CLOB")
+                    || type_name.contains("TEXT")
+                {
+                    (Type::Text, ty_str)
+                } else if type_name.contains("BLOB") {
+                    (Type::Blob, ty_str)
+                } else if type_name.is_empty() {
+                    (Type::Blob, "".to_string())
+                } else if type_name.contains("REAL")
+                    || type_name.contains("FLOA")
+                    || type_name.contains("DOUB")
+                {
+                    (Type::Real, ty_str)
+                } else {
+                    (Type::Numeric, ty_str)
+                }
+            }
+            None => (Type::Null, "".to_string()),
+        };
+
+        let mut default = None;
+        let mut primary_key = false;
+        let mut descending = false;
+        let mut notnull = false;
+        let mut collation = None;
+        for c_def in &col_def.constraints {
+            match &c_def.constraint {
+                limbo_sqlite3_parser::ast::ColumnConstraint::PrimaryKey { order, .. } => {
+                    primary_key = true;
+                    // A quirk of SQLite: INTEGER PRIMARY KEY DESC is not an alias of the rowid
+                    descending = *order == Some(ast::SortOrder::Desc);
+                }
+                limbo_sqlite3_parser::ast::ColumnConstraint::NotNull { .. } => {
+                    notnull = true;
+                }
+                limbo_sqlite3_parser::ast::ColumnConstraint::Default(expr) => {
+                    default = Some(expr.clone())
+                }
+                limbo_sqlite3_parser::ast::ColumnConstraint::Collate { collation_name } => {
+                    collation = Some(CollationSeq::named(&collation_name.0));
+                }
+                _ => {}
+            }
+        }
+
+        Column {
+            name: Some(normalize_ident(&col_def.col_name.0)),
+            ty,
+            ty_str,
+            primary_key,
+            is_rowid_alias: typename_exactly_integer && primary_key && !descending,
+            notnull,
+            default,
+            collation,
+        }
+    }
 }
 
 /// 3.1. Determination Of Column Affinity
@@ -527,12 +1352,39 @@ impl fmt::Display for Type {
     }
 }
 
+/// The name of the schema table that `name` refers to in the database `db`, or in the main or the
+/// temp database if the name is unqualified. Like in SQLite, the schema tables are also known as
+/// sqlite_master and sqlite_temp_master, and the temp database has the names of the schema table of
+/// the other databases as well.
+fn schema_table_name(db: Option<usize>, name: &str) -> Option<&'static str> {
+    match (db, name) {
+        (Some(TEMP_DB), "sqlite_schema" | "sqlite_master")
+        | (None | Some(TEMP_DB), "sqlite_temp_schema" | "sqlite_temp_master") => {
+            Some("sqlite_temp_schema")
+        }
+        (_, "sqlite_schema" | "sqlite_master") => Some("sqlite_schema"),
+        _ => None,
+    }
+}
+
+/// The schema table of a temp database, which has the same columns as sqlite_schema.
+pub fn sqlite_temp_schema_table() -> BTreeTable {
+    BTreeTable {
+        db: TEMP_DB,
+        name: "sqlite_temp_schema".to_string(),
+        ..sqlite_schema_table()
+    }
+}
+
 pub fn sqlite_schema_table() -> BTreeTable {
     BTreeTable {
+        db: MAIN_DB,
         root_page: 1,
         name: "sqlite_schema".to_string(),
         has_rowid: true,
-        primary_key_column_names: vec![],
+        primary_key_columns: vec![],
+        foreign_keys: vec![],
+        unique_sets: vec![],
         columns: vec![
             Column {
                 name: Some("type".to_string()),
@@ -542,6 +1394,7 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             },
             Column {
                 name: Some("name".to_string()),
@@ -551,6 +1404,7 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             },
             Column {
                 name: Some("tbl_name".to_string()),
@@ -560,6 +1414,7 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             },
             Column {
                 name: Some("rootpage".to_string()),
@@ -569,6 +1424,7 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             },
             Column {
                 name: Some("sql".to_string()),
@@ -578,14 +1434,115 @@ pub fn sqlite_schema_table() -> BTreeTable {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             },
         ],
     }
 }
 
+/// A view, which is a named SELECT statement that is expanded wherever the view is used.
+#[derive(Debug, Clone)]
+pub struct View {
+    pub name: String,
+    /// The column names given in the view definition, if any.
+    pub columns: Option<Vec<String>>,
+    pub select: ast::Select,
+}
+
+impl View {
+    pub fn from_sql(sql: &str) -> Result<View> {
+        let mut parser = Parser::new(sql.as_bytes());
+        let cmd = parser.next()?;
+        match cmd {
+            Some(Cmd::Stmt(Stmt::CreateView {
+                view_name,
+                columns,
+                select,
+                ..
+            })) => Ok(View {
+                name: normalize_ident(&view_name.name.0),
+                columns: columns.map(|columns| {
+                    columns
+                        .iter()
+                        .map(|column| normalize_ident(&column.col_name.0))
+                        .collect()
+                }),
+                select: *select,
+            }),
+            _ => crate::bail_corrupt_error!(
+                "malformed database schema: expected CREATE VIEW: {}",
+                sql
+            ),
+        }
+    }
+}
+
+/// A trigger, which runs its commands for each row of a table or view that is inserted, deleted or updated.
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub name: String,
+    /// The table or view the trigger is attached to.
+    pub table_name: String,
+    pub time: ast::TriggerTime,
+    pub event: ast::TriggerEvent,
+    pub for_each_row: bool,
+    pub when_clause: Option<ast::Expr>,
+    pub commands: Vec<ast::TriggerCmd>,
+}
+
+impl Trigger {
+    pub fn from_sql(sql: &str) -> Result<Trigger> {
+        let mut parser = Parser::new(sql.as_bytes());
+        let cmd = parser.next()?;
+        match cmd {
+            Some(Cmd::Stmt(Stmt::CreateTrigger(create_trigger))) => {
+                let ast::CreateTrigger {
+                    trigger_name,
+                    time,
+                    event,
+                    tbl_name,
+                    for_each_row,
+                    when_clause,
+                    commands,
+                    ..
+                } = *create_trigger;
+                Ok(Trigger {
+                    name: normalize_ident(&trigger_name.name.0),
+                    table_name: normalize_ident(&tbl_name.name.0),
+                    time: time.unwrap_or(ast::TriggerTime::Before),
+                    event,
+                    for_each_row,
+                    when_clause,
+                    commands,
+                })
+            }
+            _ => crate::bail_corrupt_error!(
+                "malformed database schema: expected CREATE TRIGGER: {}",
+                sql
+            ),
+        }
+    }
+
+    /// Whether the trigger fires for `event`. An UPDATE OF trigger only fires for an
+    /// UPDATE that sets one of its columns.
+    pub fn fires_for(&self, event: &ast::TriggerEvent, updated_columns: &[String]) -> bool {
+        match (&self.event, event) {
+            (ast::TriggerEvent::UpdateOf(columns), ast::TriggerEvent::Update) => {
+                columns.iter().any(|column| {
+                    let column = normalize_ident(&column.0);
+                    updated_columns.iter().any(|updated| *updated == column)
+                })
+            }
+            (trigger_event, event) => trigger_event == event,
+        }
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Index {
+    /// The database the index is in, which is the one of its table.
+    pub db: usize,
     pub name: String,
     pub table_name: String,
     pub root_page: usize,
@@ -593,11 +1550,57 @@ pub struct Index {
     pub unique: bool,
 }
 
+/// The statistics of a table and its indexes, as stored by ANALYZE in a row of sqlite_stat1 for
+/// each index of the table, or a single row for a table without indexes.
+#[derive(Debug, Clone, Default)]
+pub struct TableStats {
+    /// The number of rows in the table.
+    pub row_count: u64,
+    /// For each index by name, the average number of rows that have the same values in the first
+    /// column of the index, in the first two columns, and so on. The index that holds the rows of a
+    /// WITHOUT ROWID table goes by the name of the table, as in SQLite.
+    pub rows_per_key: HashMap<String, Vec<u64>>,
+}
+
+impl TableStats {
+    /// Add the statistics of a row of sqlite_stat1, whose `stat` column starts with the number of
+    /// rows of the table, followed by the average number of rows per key of `index` if there is one.
+    /// The other fields of `stat`, such as `unordered`, are ignored.
+    pub fn add_stat(&mut self, index: Option<&str>, stat: &str) {
+        let mut values = stat
+            .split_whitespace()
+            .map_while(|value| value.parse::<u64>().ok());
+        let Some(row_count) = values.next() else {
+            return;
+        };
+        self.row_count = row_count;
+        if let Some(index) = index {
+            self.rows_per_key
+                .insert(normalize_ident(index), values.collect());
+        }
+    }
+
+    /// The average number of rows per key of the index, for keys of one column, two columns and so on.
+    pub fn index_rows_per_key(&self, table: &BTreeTable, index: &Index) -> Option<&[u64]> {
+        let name = if table.is_primary_key_index(index) {
+            &table.name
+        } else {
+            &index.name
+        };
+        self.rows_per_key
+            .get(&normalize_ident(name))
+            .map(|rows_per_key| rows_per_key.as_slice())
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct IndexColumn {
     pub name: String,
     pub order: Order,
+    /// The collation the keys are ordered by: the one of a `COLLATE` clause in the index
+    /// definition, or else the one of the table column.
+    pub collation: Option<CollationSeq>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -606,8 +1609,17 @@ pub enum Order {
     Descending,
 }
 
+impl From<Option<ast::SortOrder>> for Order {
+    fn from(order: Option<ast::SortOrder>) -> Self {
+        match order {
+            Some(ast::SortOrder::Desc) => Order::Descending,
+            Some(ast::SortOrder::Asc) | None => Order::Ascending,
+        }
+    }
+}
+
 impl Index {
-    pub fn from_sql(sql: &str, root_page: usize) -> Result<Index> {
+    pub fn from_sql(sql: &str, root_page: usize, table: &BTreeTable) -> Result<Index> {
         let mut parser = Parser::new(sql.as_bytes());
         let cmd = parser.next()?;
         match cmd {
@@ -621,16 +1633,28 @@ impl Index {
                 let index_name = normalize_ident(&idx_name.name.0);
                 let index_columns = columns
                     .into_iter()
-                    .map(|col| IndexColumn {
-                        name: normalize_ident(&col.expr.to_string()),
-                        order: match col.order {
-                            Some(limbo_sqlite3_parser::ast::SortOrder::Asc) => Order::Ascending,
-                            Some(limbo_sqlite3_parser::ast::SortOrder::Desc) => Order::Descending,
-                            None => Order::Ascending,
-                        },
+                    .map(|col| {
+                        let (expr, collation) = match col.expr {
+                            Expr::Collate(expr, collation) => {
+                                (*expr, Some(CollationSeq::named(&collation)))
+                            }
+                            expr => (expr, None),
+                        };
+                        let name = normalize_ident(&expr.to_string());
+                        let collation = collation.or_else(|| {
+                            table
+                                .get_column(&name)
+                                .and_then(|(_, column)| column.collation.clone())
+                        });
+                        Ok(IndexColumn {
+                            name,
+                            order: Order::from(col.order),
+                            collation,
+                        })
                     })
-                    .collect();
+                    .collect::<Result<Vec<_>>>()?;
                 Ok(Index {
+                    db: MAIN_DB,
                     name: index_name,
                     table_name: normalize_ident(&tbl_name.0),
                     root_page,
@@ -642,21 +1666,47 @@ impl Index {
         }
     }
 
+    /// The CREATE INDEX statement that describes the index, as stored in sqlite_schema.
+    pub fn to_sql(&self) -> String {
+        let columns = self
+            .columns
+            .iter()
+            .map(|column| {
+                let mut sql = quote_ident(&column.name);
+                if let Some(collation) = &column.collation {
+                    sql.push_str(&format!(" COLLATE {}", collation));
+                }
+                if column.order == Order::Descending {
+                    sql.push_str(" DESC");
+                }
+                sql
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "CREATE {}INDEX {} ON {} ({})",
+            if self.unique { "UNIQUE " } else { "" },
+            quote_ident(&self.name),
+            quote_ident(&self.table_name),
+            columns
+        )
+    }
+
     pub fn automatic_from_primary_key(
         table: &BTreeTable,
         index_name: &str,
         root_page: usize,
     ) -> Result<Index> {
-        if table.primary_key_column_names.is_empty() {
+        if table.primary_key_columns.is_empty() {
             return Err(crate::LimboError::InternalError(
                 "Cannot create automatic index for table without primary key".to_string(),
             ));
         }
 
         let index_columns = table
-            .primary_key_column_names
+            .primary_key_columns
             .iter()
-            .map(|col_name| {
+            .map(|(col_name, order)| {
                 // Verify that each primary key column exists in the table
                 if table.get_column(col_name).is_none() {
                     return Err(crate::LimboError::InternalError(format!(
@@ -666,12 +1716,14 @@ impl Index {
                 }
                 Ok(IndexColumn {
                     name: normalize_ident(col_name),
-                    order: Order::Ascending, // Primary key indexes are always ascending
+                    order: order.clone(),
+                    collation: table.get_column(col_name).unwrap().1.collation.clone(),
                 })
             })
             .collect::<Result<Vec<_>>>()?;
 
         Ok(Index {
+            db: table.db,
             name: normalize_ident(index_name),
             table_name: table.name.clone(),
             root_page,
@@ -679,6 +1731,54 @@ impl Index {
             unique: true, // Primary key indexes are always unique
         })
     }
+
+    /// The automatic index of a PRIMARY KEY or UNIQUE constraint, which SQLite names
+    /// sqlite_autoindex_<table>_<N> after the position N of the constraint in [BTreeTable::unique_sets].
+    pub fn automatic(table: &BTreeTable, index_name: &str, root_page: usize) -> Result<Index> {
+        let set = index_name
+            .rsplit_once('_')
+            .and_then(|(_, n)| n.parse::<usize>().ok())
+            .and_then(|n| table.unique_sets.get(n.checked_sub(1)?))
+            .ok_or_else(|| {
+                crate::LimboError::Corrupt(format!(
+                    "no PRIMARY KEY or UNIQUE constraint for automatic index {}",
+                    index_name
+                ))
+            })?;
+        if set.is_primary_key {
+            return Self::automatic_from_primary_key(table, index_name, root_page);
+        }
+        Ok(Index {
+            db: table.db,
+            name: normalize_ident(index_name),
+            table_name: table.name.clone(),
+            root_page,
+            columns: set.columns.clone(),
+            unique: true,
+        })
+    }
+
+    /// The index with the functions of its registered collations looked up in `syms`, which the
+    /// comparisons of its keys need.
+    pub(crate) fn with_resolved_collations(
+        self: &Rc<Self>,
+        syms: &SymbolTable,
+    ) -> Result<Rc<Self>> {
+        let is_custom =
+            |column: &IndexColumn| matches!(column.collation, Some(CollationSeq::Custom(_)));
+        if !self.columns.iter().any(is_custom) {
+            return Ok(self.clone());
+        }
+        let mut index = self.as_ref().clone();
+        for collation in index
+            .columns
+            .iter_mut()
+            .filter_map(|c| c.collation.as_mut())
+        {
+            collation.resolve(syms)?;
+        }
+        Ok(Rc::new(index))
+    }
 }
 
 #[cfg(test)]
@@ -799,8 +1899,8 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a"],
-            table.primary_key_column_names,
+            vec![("a".to_string(), Order::Ascending)],
+            table.primary_key_columns,
             "primary key column names should be ['a']"
         );
         Ok(())
@@ -817,8 +1917,11 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a", "b"],
-            table.primary_key_column_names,
+            vec![
+                ("a".to_string(), Order::Ascending),
+                ("b".to_string(), Order::Ascending)
+            ],
+            table.primary_key_columns,
             "primary key column names should be ['a', 'b']"
         );
         Ok(())
@@ -835,8 +1938,8 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a"],
-            table.primary_key_column_names,
+            vec![("a".to_string(), Order::Ascending)],
+            table.primary_key_columns,
             "primary key column names should be ['a']"
         );
         Ok(())
@@ -853,8 +1956,11 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a", "b"],
-            table.primary_key_column_names,
+            vec![
+                ("a".to_string(), Order::Ascending),
+                ("b".to_string(), Order::Ascending)
+            ],
+            table.primary_key_columns,
             "primary key column names should be ['a', 'b']"
         );
         Ok(())
@@ -871,8 +1977,8 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a"],
-            table.primary_key_column_names,
+            vec![("a".to_string(), Order::Ascending)],
+            table.primary_key_columns,
             "primary key column names should be ['a']"
         );
         Ok(())
@@ -888,8 +1994,8 @@ mod tests {
         let column = table.get_column("c").unwrap().1;
         assert!(!column.primary_key, "column 'c' shouldn't be a primary key");
         assert_eq!(
-            vec!["a"],
-            table.primary_key_column_names,
+            vec![("a".to_string(), Order::Ascending)],
+            table.primary_key_columns,
             "primary key column names should be ['a']"
         );
         Ok(())
@@ -970,13 +2076,7 @@ mod tests {
 
     #[test]
     pub fn test_sqlite_schema() {
-        let expected = r#"CREATE TABLE sqlite_schema (
-  type TEXT,
-  name TEXT,
-  tbl_name TEXT,
-  rootpage INTEGER,
-  sql TEXT);
-"#;
+        let expected = "CREATE TABLE sqlite_schema (type TEXT, name TEXT, tbl_name TEXT, rootpage INT, sql TEXT)";
         let actual = sqlite_schema_table().to_sql();
         assert_eq!(expected, actual);
     }
@@ -1015,6 +2115,51 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_automatic_index_descending_key() -> Result<()> {
+        let sql = r#"CREATE TABLE t1 (a INTEGER, b TEXT, PRIMARY KEY(b DESC, a)) WITHOUT ROWID;"#;
+        let table = BTreeTable::from_sql(sql, 0)?;
+        let index = Index::automatic_from_primary_key(&table, "sqlite_autoindex_t1_1", 2)?;
+
+        assert_eq!(index.columns.len(), 2);
+        assert_eq!(index.columns[0].name, "b");
+        assert_eq!(index.columns[1].name, "a");
+        assert!(matches!(index.columns[0].order, Order::Descending));
+        assert!(matches!(index.columns[1].order, Order::Ascending));
+
+        let sql = r#"CREATE TABLE t2 (a TEXT PRIMARY KEY DESC, b);"#;
+        let table = BTreeTable::from_sql(sql, 0)?;
+        let index = Index::automatic_from_primary_key(&table, "sqlite_autoindex_t2_1", 2)?;
+        assert!(matches!(index.columns[0].order, Order::Descending));
+        Ok(())
+    }
+
+    #[test]
+    fn test_automatic_index_unique() -> Result<()> {
+        let sql = r#"CREATE TABLE t1 (a UNIQUE, b TEXT PRIMARY KEY UNIQUE, c, UNIQUE(c DESC, a), UNIQUE(b COLLATE NOCASE));"#;
+        let table = BTreeTable::from_sql(sql, 0)?;
+        assert_eq!(table.unique_sets.len(), 4);
+        assert!(table.unique_sets[1].is_primary_key);
+
+        let index = Index::automatic(&table, "sqlite_autoindex_t1_3", 3)?;
+        assert!(index.unique);
+        assert_eq!(index.columns.len(), 2);
+        assert_eq!(index.columns[0].name, "c");
+        assert!(matches!(index.columns[0].order, Order::Descending));
+        assert_eq!(index.columns[1].name, "a");
+
+        let index = Index::automatic(&table, "sqlite_autoindex_t1_4", 4)?;
+        assert_eq!(index.columns[0].collation, Some(CollationSeq::NoCase));
+
+        // An INTEGER PRIMARY KEY is an alias of the rowid and has no index
+        let sql = r#"CREATE TABLE t2 (id INTEGER PRIMARY KEY, x UNIQUE);"#;
+        let table = BTreeTable::from_sql(sql, 0)?;
+        assert_eq!(table.unique_sets.len(), 1);
+        assert!(!table.unique_sets[0].is_primary_key);
+        assert!(Index::automatic(&table, "sqlite_autoindex_t2_2", 3).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_automatic_index_no_primary_key() -> Result<()> {
         let sql = r#"CREATE TABLE t1 (a INTEGER, b TEXT);"#;
@@ -1033,10 +2178,13 @@ mod tests {
     fn test_automatic_index_nonexistent_column() -> Result<()> {
         // Create a table with a primary key column that doesn't exist in the table
         let table = BTreeTable {
+            db: MAIN_DB,
             root_page: 0,
             name: "t1".to_string(),
             has_rowid: true,
-            primary_key_column_names: vec!["nonexistent".to_string()],
+            primary_key_columns: vec![("nonexistent".to_string(), Order::Ascending)],
+            foreign_keys: vec![],
+            unique_sets: vec![],
             columns: vec![Column {
                 name: Some("a".to_string()),
                 ty: Type::Integer,
@@ -1045,6 +2193,7 @@ mod tests {
                 is_rowid_alias: false,
                 notnull: false,
                 default: None,
+                collation: None,
             }],
         };
 
@@ -1057,4 +2206,16 @@ mod tests {
         ));
         Ok(())
     }
+
+    #[test]
+    fn test_view_from_sql_not_create_view() {
+        let result = View::from_sql("CREATE TABLE t (a)");
+        assert!(matches!(result, Err(LimboError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_trigger_from_sql_not_create_trigger() {
+        let result = Trigger::from_sql("CREATE VIEW v AS SELECT 1");
+        assert!(matches!(result, Err(LimboError::Corrupt(_))));
+    }
 }
@@ -2,11 +2,14 @@ use tracing::debug;
 
 use crate::storage::pager::Pager;
 use crate::storage::sqlite3_ondisk::{
-    read_btree_cell, read_varint, BTreeCell, DatabaseHeader, PageContent, PageType,
-    TableInteriorCell, TableLeafCell,
+    read_btree_cell, BTreeCell, DatabaseHeader, PageContent, PageType, TableInteriorCell,
+    TableLeafCell,
 };
 
-use crate::types::{CursorResult, OwnedValue, Record, SeekKey, SeekOp};
+use crate::schema::Index;
+use crate::types::{
+    compare_index_keys, CursorResult, IndexKeyInfo, OwnedValue, Record, SeekKey, SeekOp,
+};
 use crate::{LimboError, Result};
 
 use std::cell::{Ref, RefCell};
@@ -15,8 +18,8 @@ use std::rc::Rc;
 
 use super::pager::PageRef;
 use super::sqlite3_ondisk::{
-    payload_overflows, write_varint_to_vec, IndexInteriorCell, IndexLeafCell, OverflowCell,
-    DATABASE_HEADER_SIZE,
+    payload_overflows, read_varint, write_varint_to_vec, IndexInteriorCell, IndexLeafCell,
+    OverflowCell, DATABASE_HEADER_SIZE,
 };
 
 /*
@@ -114,11 +117,52 @@ impl WriteInfo {
     }
 }
 
+/// A page of a btree that is being destroyed, which still has to be freed.
+#[derive(Debug, Clone, Copy)]
+enum DestroyItem {
+    /// A btree page, whose children and overflow pages are freed as well.
+    Page(usize),
+    /// An overflow page, whose successors in the overflow chain are freed as well.
+    Overflow(usize),
+}
+
+struct DestroyInfo {
+    /// Pages that are still to be freed. The last one is freed next.
+    pending: Vec<DestroyItem>,
+}
+
+/// Key of the entry a delete removed. The cursor is moved back to it once the tree is balanced,
+/// which may have freed or split the pages on its path.
+#[derive(Clone)]
+enum DeletedKey {
+    TableRowId(u64),
+    IndexKey(Record),
+}
+
+/// State machine of a delete operation.
+#[derive(Debug, Clone, Copy)]
+enum DeleteState {
+    /// The entry was removed, and the pages it was removed from are being balanced.
+    Balance,
+    /// The entry is an index interior cell, whose predecessor was removed from its leaf to take its place.
+    ReplaceInterior,
+    /// The cursor is moved to the entry after the deleted one.
+    Reposition,
+}
+
+struct DeleteInfo {
+    state: DeleteState,
+    key: DeletedKey,
+    /// Leaf cell of the predecessor of a deleted index interior cell.
+    predecessor: Option<Vec<u8>>,
+}
+
 /// Holds the state machine for the operation that was in flight when the cursor
 /// was suspended due to IO.
 enum CursorState {
     None,
     Write(WriteInfo),
+    Destroy(DestroyInfo),
 }
 
 impl CursorState {
@@ -150,9 +194,13 @@ pub struct BTreeCursor {
     going_upwards: bool,
     /// Information maintained across execution attempts when an operation yields due to I/O.
     state: CursorState,
+    /// Progress of a delete, which balances with the state above.
+    delete_info: Option<DeleteInfo>,
     /// Page stack used to traverse the btree.
     /// Each cursor has a stack because each cursor traverses the btree independently.
     stack: PageStack,
+    /// Sort order and collation of the columns of the keys in an index btree. Unused for table btrees.
+    index_key_info: IndexKeyInfo,
 }
 
 /// Stack of pages representing the tree traversal order.
@@ -183,14 +231,27 @@ impl BTreeCursor {
             null_flag: false,
             going_upwards: false,
             state: CursorState::None,
+            delete_info: None,
             stack: PageStack {
                 current_page: RefCell::new(-1),
                 cell_indices: RefCell::new([0; BTCURSOR_MAX_DEPTH + 1]),
                 stack: RefCell::new([const { None }; BTCURSOR_MAX_DEPTH + 1]),
             },
+            index_key_info: IndexKeyInfo::default(),
         }
     }
 
+    /// Create a cursor over an index btree, whose keys are ordered according to the index definition.
+    pub fn new_index(pager: Rc<Pager>, root_page: usize, index: &Index) -> Self {
+        let mut cursor = Self::new(pager, root_page);
+        cursor.index_key_info = IndexKeyInfo::from_index(index);
+        cursor
+    }
+
+    pub fn index_key_info(&self) -> &IndexKeyInfo {
+        &self.index_key_info
+    }
+
     /// Check if the table is empty.
     /// This is done by checking if the root page has no cells.
     fn is_empty_table(&self) -> Result<CursorResult<bool>> {
@@ -203,28 +264,24 @@ impl BTreeCursor {
 
     /// Move the cursor to the previous record and return it.
     /// Used in backwards iteration.
+    /// On an interior page, the cell index is the child pointer the cursor is at, where
+    /// `cell_count` is the rightmost pointer. Coming back up from child `i`, an index page
+    /// returns the entry of cell `i - 1`, which sorts between children `i - 1` and `i`, before
+    /// moving down into child `i - 1`.
     fn get_prev_record(&mut self) -> Result<CursorResult<(Option<u64>, Option<Record>)>> {
         loop {
             let page = self.stack.top();
             let cell_idx = self.stack.current_cell_index();
 
             // moved to beginning of current page
-            // todo: find a better way to flag moved to end or begin of page
             if self.stack.current_cell_index_less_than_min() {
-                loop {
-                    if self.stack.current_cell_index() > 0 {
-                        self.stack.retreat();
-                        break;
-                    }
-                    if self.stack.has_parent() {
-                        self.stack.pop();
-                    } else {
-                        // moved to begin of btree
-                        return Ok(CursorResult::Ok((None, None)));
-                    }
+                if self.stack.has_parent() {
+                    self.going_upwards = true;
+                    self.stack.pop();
+                    continue;
                 }
-                // continue to next loop to get record from the new page
-                continue;
+                // moved to begin of btree
+                return Ok(CursorResult::Ok((None, None)));
             }
 
             let cell_idx = cell_idx as usize;
@@ -239,44 +296,89 @@ impl BTreeCursor {
                 return Ok(CursorResult::IO);
             }
             let contents = page.get().contents.as_ref().unwrap();
-
             let cell_count = contents.cell_count();
-            let cell_idx = if cell_idx >= cell_count {
-                self.stack.set_cell_index(cell_count as i32 - 1);
-                cell_count - 1
-            } else {
-                cell_idx
-            };
 
-            let cell = contents.cell_get(
-                cell_idx,
-                self.pager.clone(),
-                self.payload_overflow_threshold_max(contents.page_type()),
-                self.payload_overflow_threshold_min(contents.page_type()),
-                self.usable_space(),
-            )?;
+            if contents.is_leaf() {
+                if cell_count == 0 {
+                    self.stack.set_cell_index(-1);
+                    continue;
+                }
+                let cell_idx = cell_idx.min(cell_count - 1);
+                let cell = contents.cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.payload_overflow_threshold_max(contents.page_type()),
+                    self.payload_overflow_threshold_min(contents.page_type()),
+                    self.usable_space(),
+                )?;
+                self.stack.set_cell_index(cell_idx as i32 - 1);
+                return match cell {
+                    BTreeCell::TableLeafCell(TableLeafCell {
+                        _rowid, _payload, ..
+                    }) => {
+                        let record = crate::storage::sqlite3_ondisk::read_record(&_payload)?;
+                        Ok(CursorResult::Ok((Some(_rowid), Some(record))))
+                    }
+                    BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. }) => {
+                        let record = crate::storage::sqlite3_ondisk::read_record(&payload)?;
+                        Ok(CursorResult::Ok((index_entry_rowid(&record), Some(record))))
+                    }
+                    _ => unreachable!("leaf pages only contain leaf cells"),
+                };
+            }
 
-            match cell {
-                BTreeCell::TableInteriorCell(TableInteriorCell {
-                    _left_child_page,
-                    _rowid,
-                }) => {
-                    let mem_page = self.pager.read_page(_left_child_page as usize)?;
-                    self.stack.push(mem_page);
-                    // use cell_index = i32::MAX to tell next loop to go to the end of the current page
-                    self.stack.set_cell_index(i32::MAX);
+            let child_idx = cell_idx.min(cell_count);
+            if self.going_upwards {
+                self.going_upwards = false;
+                if child_idx == 0 {
+                    self.stack.set_cell_index(-1);
                     continue;
                 }
-                BTreeCell::TableLeafCell(TableLeafCell {
-                    _rowid, _payload, ..
-                }) => {
-                    self.stack.retreat();
-                    let record: Record = crate::storage::sqlite3_ondisk::read_record(&_payload)?;
-                    return Ok(CursorResult::Ok((Some(_rowid), Some(record))));
+                self.stack.set_cell_index(child_idx as i32 - 1);
+                if let PageType::IndexInterior = contents.page_type() {
+                    let cell = contents.cell_get(
+                        child_idx - 1,
+                        self.pager.clone(),
+                        self.payload_overflow_threshold_max(contents.page_type()),
+                        self.payload_overflow_threshold_min(contents.page_type()),
+                        self.usable_space(),
+                    )?;
+                    let BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) = cell
+                    else {
+                        unreachable!("index interior pages only contain index interior cells");
+                    };
+                    let record = crate::storage::sqlite3_ondisk::read_record(&payload)?;
+                    return Ok(CursorResult::Ok((index_entry_rowid(&record), Some(record))));
                 }
-                BTreeCell::IndexInteriorCell(_) => todo!(),
-                BTreeCell::IndexLeafCell(_) => todo!(),
+                continue;
             }
+
+            let child_page = if child_idx == cell_count {
+                contents
+                    .rightmost_pointer()
+                    .expect("interior page should have a rightmost pointer")
+            } else {
+                match contents.cell_get(
+                    child_idx,
+                    self.pager.clone(),
+                    self.payload_overflow_threshold_max(contents.page_type()),
+                    self.payload_overflow_threshold_min(contents.page_type()),
+                    self.usable_space(),
+                )? {
+                    BTreeCell::TableInteriorCell(TableInteriorCell {
+                        _left_child_page, ..
+                    }) => _left_child_page,
+                    BTreeCell::IndexInteriorCell(IndexInteriorCell {
+                        left_child_page, ..
+                    }) => left_child_page,
+                    _ => unreachable!("interior pages only contain interior cells"),
+                }
+            };
+            self.stack.set_cell_index(child_idx as i32);
+            let mem_page = self.pager.read_page(child_page as usize)?;
+            self.stack.push(mem_page);
+            // use cell_index = i32::MAX to tell next loop to go to the end of the current page
+            self.stack.set_cell_index(i32::MAX);
         }
     }
 
@@ -381,28 +483,27 @@ impl BTreeCursor {
 
                     let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
                     if predicate.is_none() {
-                        let rowid = match record.last_value() {
-                            Some(OwnedValue::Integer(rowid)) => *rowid as u64,
-                            _ => unreachable!("index cells should have an integer rowid"),
-                        };
-                        return Ok(CursorResult::Ok((Some(rowid), Some(record))));
+                        let rowid = index_entry_rowid(&record);
+                        return Ok(CursorResult::Ok((rowid, Some(record))));
                     }
 
                     let (key, op) = predicate.as_ref().unwrap();
                     let SeekKey::IndexKey(index_key) = key else {
                         unreachable!("index seek key should be a record");
                     };
+                    let order = compare_index_keys(
+                        record.get_values(),
+                        index_key.get_values(),
+                        &self.index_key_info,
+                    );
                     let found = match op {
-                        SeekOp::GT => &record > *index_key,
-                        SeekOp::GE => &record >= *index_key,
-                        SeekOp::EQ => &record == *index_key,
+                        SeekOp::GT => order.is_gt(),
+                        SeekOp::GE => order.is_ge(),
+                        SeekOp::EQ => order.is_eq(),
                     };
                     if found {
-                        let rowid = match record.last_value() {
-                            Some(OwnedValue::Integer(rowid)) => *rowid as u64,
-                            _ => unreachable!("index cells should have an integer rowid"),
-                        };
-                        return Ok(CursorResult::Ok((Some(rowid), Some(record))));
+                        let rowid = index_entry_rowid(&record);
+                        return Ok(CursorResult::Ok((rowid, Some(record))));
                     } else {
                         continue;
                     }
@@ -411,27 +512,26 @@ impl BTreeCursor {
                     self.stack.advance();
                     let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
                     if predicate.is_none() {
-                        let rowid = match record.last_value() {
-                            Some(OwnedValue::Integer(rowid)) => *rowid as u64,
-                            _ => unreachable!("index cells should have an integer rowid"),
-                        };
-                        return Ok(CursorResult::Ok((Some(rowid), Some(record))));
+                        let rowid = index_entry_rowid(&record);
+                        return Ok(CursorResult::Ok((rowid, Some(record))));
                     }
                     let (key, op) = predicate.as_ref().unwrap();
                     let SeekKey::IndexKey(index_key) = key else {
                         unreachable!("index seek key should be a record");
                     };
+                    let order = compare_index_keys(
+                        record.get_values(),
+                        index_key.get_values(),
+                        &self.index_key_info,
+                    );
                     let found = match op {
-                        SeekOp::GT => &record > *index_key,
-                        SeekOp::GE => &record >= *index_key,
-                        SeekOp::EQ => &record == *index_key,
+                        SeekOp::GT => order.is_gt(),
+                        SeekOp::GE => order.is_ge(),
+                        SeekOp::EQ => order.is_eq(),
                     };
                     if found {
-                        let rowid = match record.last_value() {
-                            Some(OwnedValue::Integer(rowid)) => *rowid as u64,
-                            _ => unreachable!("index cells should have an integer rowid"),
-                        };
-                        return Ok(CursorResult::Ok((Some(rowid), Some(record))));
+                        let rowid = index_entry_rowid(&record);
+                        return Ok(CursorResult::Ok((rowid, Some(record))));
                     } else {
                         continue;
                     }
@@ -443,7 +543,8 @@ impl BTreeCursor {
     /// Move the cursor to the record that matches the seek key and seek operation.
     /// This may be used to seek to a specific record in a point query (e.g. SELECT * FROM table WHERE col = 10)
     /// or e.g. find the first record greater than the seek key in a range query (e.g. SELECT * FROM table WHERE col > 10).
-    /// We don't include the rowid in the comparison and that's why the last value from the record is not included.
+    /// For indexes, only as many columns as the seek key has are compared, so a seek key without the rowid
+    /// matches every entry that starts with the key.
     fn do_seek(
         &mut self,
         key: SeekKey<'_>,
@@ -490,26 +591,20 @@ impl BTreeCursor {
                             unreachable!("index seek key should be a record");
                         };
                         let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                        let order = compare_index_keys(
+                            record.get_values(),
+                            index_key.get_values(),
+                            &self.index_key_info,
+                        );
                         let found = match op {
-                            SeekOp::GT => {
-                                record.get_values()[..record.len() - 1] > index_key.get_values()[..]
-                            }
-                            SeekOp::GE => {
-                                record.get_values()[..record.len() - 1]
-                                    >= index_key.get_values()[..]
-                            }
-                            SeekOp::EQ => {
-                                record.get_values()[..record.len() - 1]
-                                    == index_key.get_values()[..]
-                            }
+                            SeekOp::GT => order.is_gt(),
+                            SeekOp::GE => order.is_ge(),
+                            SeekOp::EQ => order.is_eq(),
                         };
                         self.stack.advance();
                         if found {
-                            let rowid = match record.last_value() {
-                                Some(OwnedValue::Integer(rowid)) => *rowid as u64,
-                                _ => unreachable!("index cells should have an integer rowid"),
-                            };
-                            return Ok(CursorResult::Ok((Some(rowid), Some(record))));
+                            let rowid = index_entry_rowid(&record);
+                            return Ok(CursorResult::Ok((rowid, Some(record))));
                         }
                     }
                     cell_type => {
@@ -538,6 +633,11 @@ impl BTreeCursor {
             // and get the next matching record from there.
             return self.get_next_record(Some((key, op)));
         }
+        if !matches!(op, SeekOp::EQ) {
+            // The divider that led here may be larger than any rowid left in the leaf after
+            // deletes, in which case the first match is the first row of the next leaf.
+            return self.get_next_record(None);
+        }
 
         Ok(CursorResult::Ok((None, None)))
     }
@@ -545,6 +645,7 @@ impl BTreeCursor {
     /// Move the cursor to the root page of the btree.
     fn move_to_root(&mut self) {
         let mem_page = self.pager.read_page(self.root_page).unwrap();
+        self.going_upwards = false;
         self.stack.clear();
         self.stack.push(mem_page);
     }
@@ -663,10 +764,15 @@ impl BTreeCursor {
                             unreachable!("index seek key should be a record");
                         };
                         let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                        let order = compare_index_keys(
+                            index_key.get_values(),
+                            record.get_values(),
+                            &self.index_key_info,
+                        );
                         let target_leaf_page_is_in_the_left_subtree = match cmp {
-                            SeekOp::GT => index_key < &record,
-                            SeekOp::GE => index_key <= &record,
-                            SeekOp::EQ => index_key <= &record,
+                            SeekOp::GT => order.is_lt(),
+                            SeekOp::GE => order.is_le(),
+                            SeekOp::EQ => order.is_le(),
                         };
                         if target_leaf_page_is_in_the_left_subtree {
                             // we don't advance in case of index tree internal nodes because we will visit this node going up
@@ -703,8 +809,9 @@ impl BTreeCursor {
     }
 
     /// Insert a record into the btree.
+    /// Tables are keyed by rowid, while in indexes the record is its own key.
     /// If the insert operation overflows the page, it will be split and the btree will be balanced.
-    fn insert_into_page(&mut self, key: &OwnedValue, record: &Record) -> Result<CursorResult<()>> {
+    fn insert_into_page(&mut self, key: SeekKey<'_>, record: &Record) -> Result<CursorResult<()>> {
         if let CursorState::None = &self.state {
             self.state = CursorState::Write(WriteInfo::new());
         }
@@ -719,10 +826,6 @@ impl BTreeCursor {
             match write_state {
                 WriteState::Start => {
                     let page = self.stack.top();
-                    let int_key = match key {
-                        OwnedValue::Integer(i) => *i as u64,
-                        _ => unreachable!("btree tables are indexed by integers!"),
-                    };
 
                     // get page and find cell
                     let (cell_idx, page_type) = {
@@ -732,17 +835,66 @@ impl BTreeCursor {
                         self.pager.add_dirty(page.get().id);
 
                         let page = page.get().contents.as_mut().unwrap();
-                        assert!(matches!(page.page_type(), PageType::TableLeaf));
+                        assert!(matches!(
+                            (page.page_type(), &key),
+                            (PageType::TableLeaf, SeekKey::TableRowId(_))
+                                | (PageType::IndexLeaf, SeekKey::IndexKey(_))
+                        ));
 
                         // find cell
-                        (self.find_cell(page, int_key), page.page_type())
+                        let cell_idx = match key {
+                            SeekKey::TableRowId(rowid) => self.find_cell(page, rowid),
+                            SeekKey::IndexKey(index_key) => self.find_index_cell(page, index_key),
+                        };
+                        (cell_idx, page.page_type())
                     };
 
-                    // TODO: if overwrite drop cell
+                    // if a cell with the same key already exists, overwrite it
+                    let existing_cell = {
+                        let contents = page.get().contents.as_ref().unwrap();
+                        if cell_idx < contents.cell_count() {
+                            let cell = contents.cell_get(
+                                cell_idx,
+                                self.pager.clone(),
+                                self.payload_overflow_threshold_max(page_type),
+                                self.payload_overflow_threshold_min(page_type),
+                                self.usable_space(),
+                            )?;
+                            let same_key = match (&cell, key) {
+                                (BTreeCell::TableLeafCell(leaf), SeekKey::TableRowId(rowid)) => {
+                                    leaf._rowid == rowid
+                                }
+                                (BTreeCell::IndexLeafCell(leaf), SeekKey::IndexKey(index_key)) => {
+                                    let existing =
+                                        crate::storage::sqlite3_ondisk::read_record(&leaf.payload)?;
+                                    existing.len() == index_key.len()
+                                        && compare_index_keys(
+                                            existing.get_values(),
+                                            index_key.get_values(),
+                                            &self.index_key_info,
+                                        )
+                                        .is_eq()
+                                }
+                                _ => false,
+                            };
+                            same_key.then_some(cell)
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(cell) = existing_cell {
+                        return_if_io!(self.clear_overflow_pages(&cell));
+                        let contents = page.get().contents.as_mut().unwrap();
+                        self.drop_cell(contents, cell_idx);
+                    }
 
                     // insert cell
                     let mut cell_payload: Vec<u8> = Vec::new();
-                    self.fill_cell_payload(page_type, Some(int_key), &mut cell_payload, record);
+                    let int_key = match key {
+                        SeekKey::TableRowId(rowid) => Some(rowid),
+                        SeekKey::IndexKey(_) => None,
+                    };
+                    self.fill_cell_payload(page_type, int_key, &mut cell_payload, record);
 
                     // insert
                     let overflow = {
@@ -840,7 +992,10 @@ impl BTreeCursor {
         let mut fragments_reduced = 0;
         let mut cell_length = len;
         let mut cell_block_start = offset;
-        let mut next_free_block_ptr = PAGE_HEADER_OFFSET_FIRST_FREEBLOCK as u16;
+        // Freeblock offsets are relative to the start of the page, which precedes the page header
+        // on page 1, so the pointer to the first freeblock is addressed the same way.
+        let first_free_block_ptr = (page.offset + PAGE_HEADER_OFFSET_FIRST_FREEBLOCK) as u16;
+        let mut next_free_block_ptr = first_free_block_ptr;
 
         let usable_size = {
             let db_header = self.pager.db_header.borrow();
@@ -858,13 +1013,11 @@ impl BTreeCursor {
         );
 
         // Check for empty freelist fast path
-        let mut next_free_block = if page.read_u8(next_free_block_ptr as usize + 1) == 0
-            && page.read_u8(next_free_block_ptr as usize) == 0
-        {
+        let mut next_free_block = if page.first_freeblock() == 0 {
             0 // Fast path for empty freelist
         } else {
             // Find position in free list
-            let mut block = page.read_u16(next_free_block_ptr as usize);
+            let mut block = page.first_freeblock();
             while block != 0 && block < cell_block_start {
                 if block <= next_free_block_ptr {
                     if block == 0 {
@@ -873,7 +1026,7 @@ impl BTreeCursor {
                     return Err(LimboError::Corrupt("Free block list not ascending".into()));
                 }
                 next_free_block_ptr = block;
-                block = page.read_u16(block as usize);
+                block = page.read_u16(block as usize - page.offset);
             }
             block
         };
@@ -889,7 +1042,7 @@ impl BTreeCursor {
                 return Err(LimboError::Corrupt("Invalid block overlap".into()));
             }
 
-            let next_block_size = page.read_u16(next_free_block as usize + 2) as u32;
+            let next_block_size = page.read_u16(next_free_block as usize + 2 - page.offset) as u32;
             cell_block_end = next_free_block as u32 + next_block_size;
             if cell_block_end > usable_size {
                 return Err(LimboError::Corrupt(
@@ -898,13 +1051,13 @@ impl BTreeCursor {
             }
 
             cell_length = cell_block_end as u16 - cell_block_start;
-            next_free_block = page.read_u16(next_free_block as usize);
+            next_free_block = page.read_u16(next_free_block as usize - page.offset);
         }
 
         // Coalesce with previous block if adjacent
-        if next_free_block_ptr > PAGE_HEADER_OFFSET_FIRST_FREEBLOCK as u16 {
-            let prev_block_end =
-                next_free_block_ptr as u32 + page.read_u16(next_free_block_ptr as usize + 2) as u32;
+        if next_free_block_ptr > first_free_block_ptr {
+            let prev_block_end = next_free_block_ptr as u32
+                + page.read_u16(next_free_block_ptr as usize + 2 - page.offset) as u32;
 
             if prev_block_end + 3 >= cell_block_start as u32 {
                 if prev_block_end > cell_block_start as u32 {
@@ -931,7 +1084,7 @@ impl BTreeCursor {
             if cell_block_start < content_area_start {
                 return Err(LimboError::Corrupt("Free block before content area".into()));
             }
-            if next_free_block_ptr != PAGE_HEADER_OFFSET_FIRST_FREEBLOCK as u16 {
+            if next_free_block_ptr != first_free_block_ptr {
                 return Err(LimboError::Corrupt("Invalid content area merge".into()));
             }
             // Extend content area
@@ -939,9 +1092,9 @@ impl BTreeCursor {
             page.write_u16(PAGE_HEADER_OFFSET_CELL_CONTENT_AREA, cell_block_end as u16);
         } else {
             // Insert in free list
-            page.write_u16(next_free_block_ptr as usize, cell_block_start);
-            page.write_u16(cell_block_start as usize, next_free_block);
-            page.write_u16(cell_block_start as usize + 2, cell_length);
+            page.write_u16(next_free_block_ptr as usize - page.offset, cell_block_start);
+            page.write_u16(cell_block_start as usize - page.offset, next_free_block);
+            page.write_u16(cell_block_start as usize + 2 - page.offset, cell_length);
         }
 
         Ok(())
@@ -990,7 +1143,9 @@ impl BTreeCursor {
     ) -> Result<usize> {
         // NOTE: freelist is in ascending order of keys and pc
         // unused_space is reserved bytes at the end of page, therefore we must subtract from maxpc
-        let mut free_list_pointer_addr = 1;
+        // Freeblock offsets are relative to the start of the page, which precedes the page header
+        // on page 1, so the pointer to the first freeblock is addressed the same way.
+        let mut free_list_pointer_addr = page_ref.offset + PAGE_HEADER_OFFSET_FIRST_FREEBLOCK;
         let mut pc = page_ref.first_freeblock() as usize;
 
         let usable_space = (db_header.page_size - db_header.reserved_space as u16) as usize;
@@ -1001,7 +1156,7 @@ impl BTreeCursor {
         }
 
         while pc <= maxpc {
-            let size = page_ref.read_u16(pc + 2) as usize;
+            let size = page_ref.read_u16(pc + 2 - page_ref.offset) as usize;
 
             if let Some(x) = size.checked_sub(amount) {
                 if x < 4 {
@@ -1009,8 +1164,8 @@ impl BTreeCursor {
                         return Ok(0);
                     }
 
-                    let next_ptr = page_ref.read_u16(pc);
-                    page_ref.write_u16(free_list_pointer_addr, next_ptr);
+                    let next_ptr = page_ref.read_u16(pc - page_ref.offset);
+                    page_ref.write_u16(free_list_pointer_addr - page_ref.offset, next_ptr);
 
                     let frag_count = page_ref.read_u8(PAGE_HEADER_OFFSET_FRAGMENTED_BYTES_COUNT);
                     page_ref.write_u8(
@@ -1021,17 +1176,21 @@ impl BTreeCursor {
                 } else if x + pc > maxpc {
                     return Err(LimboError::Corrupt("Free block extends beyond page".into()));
                 } else {
-                    page_ref.write_u16(pc + 2, x as u16);
+                    page_ref.write_u16(pc + 2 - page_ref.offset, x as u16);
                     return Ok(pc + x);
                 }
             }
 
             free_list_pointer_addr = pc;
-            pc = page_ref.read_u16(pc) as usize;
-            if pc <= free_list_pointer_addr && pc != 0 {
-                return Err(LimboError::Corrupt(
-                    "Free list not in ascending order".into(),
-                ));
+            pc = page_ref.read_u16(pc - page_ref.offset) as usize;
+            if pc <= free_list_pointer_addr {
+                if pc != 0 {
+                    return Err(LimboError::Corrupt(
+                        "Free list not in ascending order".into(),
+                    ));
+                }
+                // end of the free list, no free block is large enough
+                return Ok(0);
             }
         }
 
@@ -1182,10 +1341,6 @@ impl BTreeCursor {
 
                 let page = current_page.get().contents.as_mut().unwrap();
                 let page_type = page.page_type();
-                assert!(
-                    matches!(page_type, PageType::TableLeaf | PageType::TableInterior),
-                    "indexes still not supported"
-                );
 
                 write_info.split_pages.borrow_mut().clear();
                 write_info.split_pages.borrow_mut().push(current_page);
@@ -1239,6 +1394,9 @@ impl BTreeCursor {
                         BTreeCell::TableInteriorCell(interior) => {
                             interior._left_child_page as usize == current_idx
                         }
+                        BTreeCell::IndexInteriorCell(interior) => {
+                            interior.left_child_page as usize == current_idx
+                        }
                         _ => unreachable!("Parent should always be an interior page"),
                     };
                     if found {
@@ -1304,32 +1462,46 @@ impl BTreeCursor {
                     current_cell_index += cells_to_copy;
                 }
 
-                let is_leaf = {
+                let (is_leaf, is_index) = {
                     let page = self.stack.top();
                     let page = page.get().contents.as_ref().unwrap();
-                    page.is_leaf()
+                    (
+                        page.is_leaf(),
+                        matches!(
+                            page.page_type(),
+                            PageType::IndexLeaf | PageType::IndexInterior
+                        ),
+                    )
                 };
 
-                // update rightmost pointer for each page if we are in interior page
-                if !is_leaf {
+                // Interior pages and all index pages give their last cell away as the divider.
+                // Table leaf dividers are copies of the last rowid, but index keys are stored only once,
+                // so an index divider cell is moved up to the parent instead.
+                if !is_leaf || is_index {
                     for page in split_pages.iter_mut().take(split_pages_len - 1) {
                         let contents = page.get().contents.as_mut().unwrap();
 
                         assert!(contents.cell_count() >= 1);
-                        let last_cell = contents.cell_get(
-                            contents.cell_count() - 1,
-                            self.pager.clone(),
-                            self.payload_overflow_threshold_max(contents.page_type()),
-                            self.payload_overflow_threshold_min(contents.page_type()),
-                            self.usable_space(),
-                        )?;
-                        let last_cell_pointer = match last_cell {
-                            BTreeCell::TableInteriorCell(interior) => interior._left_child_page,
-                            _ => unreachable!(),
-                        };
+                        if !is_leaf {
+                            // update rightmost pointer for each page if we are in interior page
+                            let last_cell = contents.cell_get(
+                                contents.cell_count() - 1,
+                                self.pager.clone(),
+                                self.payload_overflow_threshold_max(contents.page_type()),
+                                self.payload_overflow_threshold_min(contents.page_type()),
+                                self.usable_space(),
+                            )?;
+                            let last_cell_pointer = match last_cell {
+                                BTreeCell::TableInteriorCell(interior) => interior._left_child_page,
+                                BTreeCell::IndexInteriorCell(interior) => interior.left_child_page,
+                                _ => unreachable!(),
+                            };
+                            contents.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, last_cell_pointer);
+                        }
                         self.drop_cell(contents, contents.cell_count() - 1);
-                        contents.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, last_cell_pointer);
                     }
+                }
+                if !is_leaf {
                     // last page right most pointer points to previous right most pointer before splitting
                     let last_page = split_pages.last().unwrap();
                     let last_page_contents = last_page.get().contents.as_mut().unwrap();
@@ -1339,14 +1511,50 @@ impl BTreeCursor {
                     );
                 }
 
+                {
+                    // copy last page id to right pointer
+                    // this is done before inserting the dividers, as inserting cells may move the cells of the parent around
+                    let last_pointer = split_pages.last().unwrap().get().id as u32;
+                    parent_contents.write_u32(right_pointer, last_pointer);
+                }
+
                 // insert dividers in parent
-                // we can consider dividers the first cell of each page starting from the second page
+                // we can consider dividers the last cell of each page except the last one
                 for (page_id_index, page) in
                     split_pages.iter_mut().take(split_pages_len - 1).enumerate()
                 {
                     let contents = page.get().contents.as_mut().unwrap();
                     let divider_cell_index = divider_cells_index[page_id_index];
                     let cell_payload = scratch_cells[divider_cell_index];
+                    if is_index {
+                        // An index divider is the moved cell itself, with the left child pointer
+                        // replaced by (or prepended with) the page it was taken from.
+                        let cell_without_child = if is_leaf {
+                            cell_payload
+                        } else {
+                            &cell_payload[4..]
+                        };
+                        let mut divider_cell = Vec::with_capacity(4 + cell_without_child.len());
+                        divider_cell.extend_from_slice(&(page.get().id as u32).to_be_bytes());
+                        divider_cell.extend_from_slice(cell_without_child);
+                        let cell = read_btree_cell(
+                            &divider_cell,
+                            &PageType::IndexInterior,
+                            0,
+                            self.pager.clone(),
+                            self.payload_overflow_threshold_max(PageType::IndexInterior),
+                            self.payload_overflow_threshold_min(PageType::IndexInterior),
+                            self.usable_space(),
+                        )?;
+                        let BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) = cell
+                        else {
+                            unreachable!();
+                        };
+                        let key = crate::storage::sqlite3_ondisk::read_record(&payload)?;
+                        let parent_cell_idx = self.find_index_cell(parent_contents, &key);
+                        self.insert_into_cell(parent_contents, &divider_cell, parent_cell_idx);
+                        continue;
+                    }
                     let cell = read_btree_cell(
                         cell_payload,
                         &contents.page_type(),
@@ -1370,12 +1578,6 @@ impl BTreeCursor {
                     let parent_cell_idx = self.find_cell(parent_contents, key);
                     self.insert_into_cell(parent_contents, &divider_cell, parent_cell_idx);
                 }
-
-                {
-                    // copy last page id to right pointer
-                    let last_pointer = split_pages.last().unwrap().get().id as u32;
-                    parent_contents.write_u32(right_pointer, last_pointer);
-                }
                 self.stack.pop();
                 let _ = write_info.page_copy.take();
                 (WriteState::BalanceStart, Ok(CursorResult::Ok(())))
@@ -1400,7 +1602,19 @@ impl BTreeCursor {
         };
 
         let offset = if is_page_1 { DATABASE_HEADER_SIZE } else { 0 };
-        let new_root_page = self.allocate_page(PageType::TableInterior, offset);
+        let root_page_type = match self
+            .stack
+            .top()
+            .get()
+            .contents
+            .as_ref()
+            .unwrap()
+            .page_type()
+        {
+            PageType::IndexLeaf | PageType::IndexInterior => PageType::IndexInterior,
+            PageType::TableLeaf | PageType::TableInterior => PageType::TableInterior,
+        };
+        let new_root_page = self.allocate_page(root_page_type, offset);
         {
             let current_root = self.stack.top();
             let current_root_contents = current_root.get().contents.as_ref().unwrap();
@@ -1544,9 +1758,9 @@ impl BTreeCursor {
             let read_buf = cloned_page.as_ptr();
             let write_buf = page.as_ptr();
 
+            let (cell_pointer_array_start, _) = cloned_page.cell_pointer_array_offset_and_size();
             for i in 0..cloned_page.cell_count() {
-                let cell_offset = page.offset + 8;
-                let cell_idx = cell_offset + i * 2;
+                let cell_idx = cell_pointer_array_start + i * 2;
 
                 let pc = u16::from_be_bytes([read_buf[cell_idx], read_buf[cell_idx + 1]]) as u64;
                 if pc > last_cell {
@@ -1555,35 +1769,13 @@ impl BTreeCursor {
 
                 assert!(pc <= last_cell);
 
-                let size = match page_type {
-                    PageType::TableInterior => {
-                        let (_, nr_key) = match read_varint(&read_buf[pc as usize ..]) {
-                            Ok(v) => v,
-                            Err(_) => todo!(
-                                "error while parsing varint from cell, probably treat this as corruption?"
-                            ),
-                        };
-                        4 + nr_key as u64
-                    }
-                    PageType::TableLeaf => {
-                        let (payload_size, nr_payload) = match read_varint(&read_buf[pc as usize..]) {
-                            Ok(v) => v,
-                            Err(_) => todo!(
-                                "error while parsing varint from cell, probably treat this as corruption?"
-                            ),
-                        };
-                        let (_, nr_key) = match read_varint(&read_buf[pc as usize + nr_payload..]) {
-                            Ok(v) => v,
-                            Err(_) => todo!(
-                                "error while parsing varint from cell, probably treat this as corruption?"
-                            ),
-                        };
-                        // TODO: add overflow page calculation
-                        payload_size + nr_payload as u64 + nr_key as u64
-                    }
-                    PageType::IndexInterior => todo!(),
-                    PageType::IndexLeaf => todo!(),
-                };
+                let (_, size) = cloned_page.cell_get_raw_region(
+                    i,
+                    self.payload_overflow_threshold_max(page_type.clone()),
+                    self.payload_overflow_threshold_min(page_type.clone()),
+                    usable_space as usize,
+                );
+                let size = size as u64;
                 cbrk -= size;
                 if cbrk < first_cell || pc + size > usable_space {
                     todo!("corrupt");
@@ -1875,6 +2067,36 @@ impl BTreeCursor {
         cell_idx
     }
 
+    /// Find the index of the first cell in an index page whose key is greater than or equal to the given key.
+    fn find_index_cell(&self, page: &PageContent, key: &Record) -> usize {
+        let mut cell_idx = 0;
+        let cell_count = page.cell_count();
+        while cell_idx < cell_count {
+            let payload = match page
+                .cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.payload_overflow_threshold_max(page.page_type()),
+                    self.payload_overflow_threshold_min(page.page_type()),
+                    self.usable_space(),
+                )
+                .unwrap()
+            {
+                BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. })
+                | BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) => payload,
+                cell => unreachable!("unexpected cell in index page: {:?}", cell),
+            };
+            let record = crate::storage::sqlite3_ondisk::read_record(&payload).unwrap();
+            if compare_index_keys(key.get_values(), record.get_values(), &self.index_key_info)
+                .is_le()
+            {
+                break;
+            }
+            cell_idx += 1;
+        }
+        cell_idx
+    }
+
     pub fn seek_to_last(&mut self) -> Result<CursorResult<()>> {
         return_if_io!(self.move_to_rightmost());
         let (rowid, record) = return_if_io!(self.get_next_record(None));
@@ -1897,6 +2119,8 @@ impl BTreeCursor {
     }
 
     pub fn rewind(&mut self) -> Result<CursorResult<()>> {
+        // Rewinding moves the cursor off the NULL row set by NullRow.
+        self.null_flag = false;
         self.move_to_root();
 
         let (rowid, record) = return_if_io!(self.get_next_record(None));
@@ -1906,6 +2130,7 @@ impl BTreeCursor {
     }
 
     pub fn last(&mut self) -> Result<CursorResult<()>> {
+        self.null_flag = false;
         match self.move_to_rightmost()? {
             CursorResult::Ok(_) => self.prev(),
             CursorResult::IO => Ok(CursorResult::IO),
@@ -1940,10 +2165,13 @@ impl BTreeCursor {
     }
 
     pub fn seek(&mut self, key: SeekKey<'_>, op: SeekOp) -> Result<CursorResult<bool>> {
+        // Seeking moves the cursor off the NULL row set by NullRow.
+        self.null_flag = false;
         let (rowid, record) = return_if_io!(self.do_seek(key, op));
+        let found = record.is_some();
         self.rowid.replace(rowid);
         self.record.replace(record);
-        Ok(CursorResult::Ok(rowid.is_some()))
+        Ok(CursorResult::Ok(found))
     }
 
     pub fn record(&self) -> Result<Ref<Option<Record>>> {
@@ -1964,12 +2192,65 @@ impl BTreeCursor {
             return_if_io!(self.move_to(SeekKey::TableRowId(*int_key as u64), SeekOp::EQ));
         }
 
-        return_if_io!(self.insert_into_page(key, _record));
+        return_if_io!(self.insert_into_page(SeekKey::TableRowId(*int_key as u64), _record));
         self.rowid.replace(Some(*int_key as u64));
         Ok(CursorResult::Ok(()))
     }
 
+    /// Insert a key into an index btree. The key is the record of the indexed values followed by the rowid.
+    pub fn insert_index_key(&mut self, key: &Record) -> Result<CursorResult<()>> {
+        if !matches!(self.state, CursorState::Write(_)) {
+            return_if_io!(self.move_to(SeekKey::IndexKey(key), SeekOp::GE));
+        }
+        return_if_io!(self.insert_into_page(SeekKey::IndexKey(key), key));
+        Ok(CursorResult::Ok(()))
+    }
+
     pub fn delete(&mut self) -> Result<CursorResult<()>> {
+        loop {
+            let Some(state) = self.delete_info.as_ref().map(|info| info.state) else {
+                return_if_io!(self.remove_entry());
+                if self.delete_info.is_none() {
+                    // There was no entry to delete.
+                    return Ok(CursorResult::Ok(()));
+                }
+                continue;
+            };
+            match state {
+                DeleteState::Balance => {
+                    return_if_io!(self.balance());
+                    self.state = CursorState::None;
+                    let delete_info = self.delete_info.as_mut().unwrap();
+                    delete_info.state = if delete_info.predecessor.is_some() {
+                        DeleteState::ReplaceInterior
+                    } else {
+                        DeleteState::Reposition
+                    };
+                }
+                DeleteState::ReplaceInterior => {
+                    return_if_io!(self.replace_with_predecessor());
+                }
+                DeleteState::Reposition => {
+                    let key = self.delete_info.as_ref().unwrap().key.clone();
+                    return_if_io!(self.move_after_deleted_key(&key));
+                    self.delete_info = None;
+                    return Ok(CursorResult::Ok(()));
+                }
+            }
+        }
+    }
+
+    /// Whether a delete yielded due to I/O, in which case delete() has to be called again
+    /// without moving the cursor.
+    pub fn is_delete_pending(&self) -> bool {
+        self.delete_info.is_some()
+    }
+
+    /// Remove the entry the cursor was last positioned on, e.g. by a seek, from its page.
+    /// Unlike tables, indexes keep keys in interior cells as well. The predecessor of an interior
+    /// cell, i.e. the largest key in its left subtree, is removed from its leaf instead, and
+    /// replaces the interior cell once the leaf has been dealt with.
+    fn remove_entry(&mut self) -> Result<CursorResult<()>> {
         let page = self.stack.top();
         return_if_locked!(page);
 
@@ -1978,40 +2259,76 @@ impl BTreeCursor {
             return Ok(CursorResult::IO);
         }
 
-        let target_rowid = match self.rowid.borrow().as_ref() {
-            Some(rowid) => *rowid,
-            None => return Ok(CursorResult::Ok(())),
-        };
-
         let contents = page.get().contents.as_ref().unwrap();
-
-        // TODO(Krishna): We are doing this linear search here because seek() is returning the index of previous cell.
-        // And the fix is currently not very clear to me.
-        // This finds the cell with matching rowid with in a page.
-        let mut cell_idx = None;
-        for idx in 0..contents.cell_count() {
-            let cell = contents.cell_get(
-                idx,
+        let (cell_idx, key) = if matches!(
+            contents.page_type(),
+            PageType::IndexLeaf | PageType::IndexInterior
+        ) {
+            // The cursor moves past an entry as soon as it returns it.
+            let cell_idx = self.stack.current_cell_index() - 1;
+            assert!(cell_idx >= 0, "index cursor is not positioned on an entry");
+            let cell_idx = cell_idx as usize;
+            if cell_idx >= contents.cell_count() {
+                return Err(LimboError::Corrupt(format!(
+                    "Corrupted page: cell index {} is out of bounds for page with {} cells",
+                    cell_idx,
+                    contents.cell_count()
+                )));
+            }
+            let payload = match contents.cell_get(
+                cell_idx,
                 self.pager.clone(),
                 self.payload_overflow_threshold_max(contents.page_type()),
                 self.payload_overflow_threshold_min(contents.page_type()),
                 self.usable_space(),
-            )?;
+            )? {
+                BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. })
+                | BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) => payload,
+                cell => unreachable!("unexpected cell in index page: {:?}", cell),
+            };
+            let record = crate::storage::sqlite3_ondisk::read_record(&payload)?;
+            (cell_idx, DeletedKey::IndexKey(record))
+        } else {
+            let target_rowid = match self.rowid.borrow().as_ref() {
+                Some(rowid) => *rowid,
+                None => return Ok(CursorResult::Ok(())),
+            };
 
-            if let BTreeCell::TableLeafCell(leaf_cell) = cell {
-                if leaf_cell._rowid == target_rowid {
-                    cell_idx = Some(idx);
-                    break;
+            // TODO(Krishna): We are doing this linear search here because seek() is returning the index of previous cell.
+            // And the fix is currently not very clear to me.
+            // This finds the cell with matching rowid with in a page.
+            let mut cell_idx = None;
+            for idx in 0..contents.cell_count() {
+                let cell = contents.cell_get(
+                    idx,
+                    self.pager.clone(),
+                    self.payload_overflow_threshold_max(contents.page_type()),
+                    self.payload_overflow_threshold_min(contents.page_type()),
+                    self.usable_space(),
+                )?;
+
+                if let BTreeCell::TableLeafCell(leaf_cell) = cell {
+                    if leaf_cell._rowid == target_rowid {
+                        cell_idx = Some(idx);
+                        break;
+                    }
                 }
             }
-        }
 
-        let cell_idx = match cell_idx {
-            Some(idx) => idx,
-            None => return Ok(CursorResult::Ok(())),
+            match cell_idx {
+                Some(idx) => (idx, DeletedKey::TableRowId(target_rowid)),
+                None => return Ok(CursorResult::Ok(())),
+            }
         };
 
-        let contents = page.get().contents.as_ref().unwrap();
+        if !contents.is_leaf() {
+            return self.remove_interior_entry(cell_idx, key);
+        }
+
+        if contents.cell_count() == 1 && self.stack.has_parent() {
+            return_if_io!(self.load_merge_siblings());
+        }
+
         let cell = contents.cell_get(
             cell_idx,
             self.pager.clone(),
@@ -2019,76 +2336,493 @@ impl BTreeCursor {
             self.payload_overflow_threshold_min(contents.page_type()),
             self.usable_space(),
         )?;
+        return_if_io!(self.clear_overflow_pages(&cell));
 
-        if cell_idx >= contents.cell_count() {
+        page.set_dirty();
+        self.pager.add_dirty(page.get().id);
+        let contents = page.get().contents.as_mut().unwrap();
+        self.drop_cell(contents, cell_idx);
+        if contents.cell_count() == 0 && self.stack.has_parent() {
+            self.free_empty_page()?;
+        }
+        self.start_delete_balance(key, None);
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Remove the predecessor of the index interior cell at `cell_idx` of the page at the top of
+    /// the stack from its leaf. The stack is extended down to that leaf.
+    fn remove_interior_entry(
+        &mut self,
+        cell_idx: usize,
+        key: DeletedKey,
+    ) -> Result<CursorResult<()>> {
+        let page = self.stack.top();
+        let contents = page.get().contents.as_ref().unwrap();
+        let BTreeCell::IndexInteriorCell(IndexInteriorCell {
+            left_child_page, ..
+        }) = contents.cell_get(
+            cell_idx,
+            self.pager.clone(),
+            self.payload_overflow_threshold_max(contents.page_type()),
+            self.payload_overflow_threshold_min(contents.page_type()),
+            self.usable_space(),
+        )?
+        else {
+            unreachable!("index interior pages only contain index interior cells");
+        };
+
+        // The predecessor is the last cell of the leaf at the end of the right edge of the left subtree.
+        let mut path = Vec::new();
+        let mut next_page = Some(left_child_page);
+        while let Some(page_idx) = next_page {
+            let child = self.pager.read_page(page_idx as usize)?;
+            return_if_locked!(child);
+            if !child.is_loaded() {
+                self.pager.load_page(child.clone())?;
+                return Ok(CursorResult::IO);
+            }
+            next_page = child.get().contents.as_ref().unwrap().rightmost_pointer();
+            path.push(child);
+        }
+        let leaf = path.last().unwrap().clone();
+        let leaf_contents = leaf.get().contents.as_mut().unwrap();
+        if leaf_contents.cell_count() == 0 {
             return Err(LimboError::Corrupt(format!(
-                "Corrupted page: cell index {} is out of bounds for page with {} cells",
-                cell_idx,
-                contents.cell_count()
+                "Corrupted page: non-root page {} has no cells",
+                leaf.get().id
             )));
         }
+        let depth = path.len();
+        for page in path {
+            self.stack.push(page);
+        }
+        if leaf_contents.cell_count() == 1 {
+            if let CursorResult::IO = self.load_merge_siblings()? {
+                for _ in 0..depth {
+                    self.stack.pop();
+                }
+                return Ok(CursorResult::IO);
+            }
+        }
 
-        let original_child_pointer = match &cell {
-            BTreeCell::TableInteriorCell(interior) => Some(interior._left_child_page),
-            _ => None,
+        let predecessor_idx = leaf_contents.cell_count() - 1;
+        let (start, len) = leaf_contents.cell_get_raw_region(
+            predecessor_idx,
+            self.payload_overflow_threshold_max(leaf_contents.page_type()),
+            self.payload_overflow_threshold_min(leaf_contents.page_type()),
+            self.usable_space(),
+        );
+        let predecessor = leaf_contents.as_ptr()[start..start + len].to_vec();
+
+        leaf.set_dirty();
+        self.pager.add_dirty(leaf.get().id);
+        self.drop_cell(leaf_contents, predecessor_idx);
+        if leaf_contents.cell_count() == 0 {
+            self.free_empty_page()?;
+        }
+        self.start_delete_balance(key, Some(predecessor));
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Replace the deleted index entry with its predecessor, which was removed from its leaf.
+    /// The entry is looked up again, since balancing the leaf may have moved it, down into a leaf
+    /// even, if it was the divider next to a page that became empty.
+    fn replace_with_predecessor(&mut self) -> Result<CursorResult<()>> {
+        let DeletedKey::IndexKey(key) = self.delete_info.as_ref().unwrap().key.clone() else {
+            unreachable!("only index entries are replaced by their predecessor");
         };
+        return_if_io!(self.move_to(SeekKey::IndexKey(&key), SeekOp::EQ));
 
+        // The entry is either in the leaf or in the interior cell the cursor went left of.
+        let (page, cell) = loop {
+            let page = self.stack.top();
+            let contents = page.get().contents.as_ref().unwrap();
+            let cell_idx = if contents.is_leaf() {
+                self.find_index_cell(contents, &key)
+            } else {
+                self.stack.current_cell_index() as usize
+            };
+            if cell_idx < contents.cell_count() {
+                let cell = contents.cell_get(
+                    cell_idx,
+                    self.pager.clone(),
+                    self.payload_overflow_threshold_max(contents.page_type()),
+                    self.payload_overflow_threshold_min(contents.page_type()),
+                    self.usable_space(),
+                )?;
+                let payload = match &cell {
+                    BTreeCell::IndexLeafCell(IndexLeafCell { payload, .. })
+                    | BTreeCell::IndexInteriorCell(IndexInteriorCell { payload, .. }) => payload,
+                    cell => unreachable!("unexpected cell in index page: {:?}", cell),
+                };
+                let record = crate::storage::sqlite3_ondisk::read_record(payload)?;
+                if compare_index_keys(record.get_values(), key.get_values(), &self.index_key_info)
+                    .is_eq()
+                {
+                    break (page, (cell_idx, cell));
+                }
+            }
+            if !self.stack.has_parent() {
+                return Err(LimboError::Corrupt(
+                    "deleted index entry is missing from the index".into(),
+                ));
+            }
+            self.stack.pop();
+        };
+        let (cell_idx, cell) = cell;
         return_if_io!(self.clear_overflow_pages(&cell));
 
-        let page = self.stack.top();
-        return_if_locked!(page);
-        if !page.is_loaded() {
-            self.pager.load_page(page.clone())?;
-            return Ok(CursorResult::IO);
+        let predecessor = self
+            .delete_info
+            .as_mut()
+            .unwrap()
+            .predecessor
+            .take()
+            .unwrap();
+        let mut new_cell = Vec::with_capacity(4 + predecessor.len());
+        if let BTreeCell::IndexInteriorCell(IndexInteriorCell {
+            left_child_page, ..
+        }) = cell
+        {
+            // Index leaf cells are laid out like interior cells without the left child pointer.
+            new_cell.extend_from_slice(&left_child_page.to_be_bytes());
         }
+        new_cell.extend_from_slice(&predecessor);
 
         page.set_dirty();
         self.pager.add_dirty(page.get().id);
-
         let contents = page.get().contents.as_mut().unwrap();
+        self.drop_cell(contents, cell_idx);
+        self.insert_into_cell(contents, &new_cell, cell_idx);
 
-        // If this is an interior node, we need to handle deletion differently
-        // For interior nodes:
-        // 1. Move cursor to largest entry in left subtree
-        // 2. Copy that entry to replace the one being deleted
-        // 3. Delete the leaf entry
-        if !contents.is_leaf() {
-            // 1. Move cursor to largest entry in left subtree
-            return_if_io!(self.prev());
+        let mut write_info = WriteInfo::new();
+        write_info.state = WriteState::BalanceStart;
+        self.state = CursorState::Write(write_info);
+        self.delete_info.as_mut().unwrap().state = DeleteState::Balance;
+        Ok(CursorResult::Ok(()))
+    }
 
-            let leaf_page = self.stack.top();
+    /// Balance the page at the top of the stack, which may overflow with a divider or entry that
+    /// replaced a smaller one.
+    fn start_delete_balance(&mut self, key: DeletedKey, predecessor: Option<Vec<u8>>) {
+        let mut write_info = WriteInfo::new();
+        write_info.state = WriteState::BalanceStart;
+        self.state = CursorState::Write(write_info);
+        self.delete_info = Some(DeleteInfo {
+            state: DeleteState::Balance,
+            key,
+            predecessor,
+        });
+    }
 
-            // 2. Copy that entry to replace the one being deleted
-            let leaf_contents = leaf_page.get().contents.as_ref().unwrap();
-            let leaf_cell_idx = self.stack.current_cell_index() as usize - 1;
-            let predecessor_cell = leaf_contents.cell_get(
-                leaf_cell_idx,
-                self.pager.clone(),
-                self.payload_overflow_threshold_max(leaf_contents.page_type()),
-                self.payload_overflow_threshold_min(leaf_contents.page_type()),
-                self.usable_space(),
-            )?;
+    /// Load the neighbours that the page at the top of the stack, and each ancestor that is left
+    /// without dividers in turn, are merged with should the page become empty, so that freeing it
+    /// does not yield due to I/O halfway.
+    fn load_merge_siblings(&self) -> Result<CursorResult<()>> {
+        let mut level = self.stack.current();
+        while level > 0 {
+            let page_idx = self.stack.page_at(level).get().id;
+            let parent = self.stack.page_at(level - 1);
+            let contents = parent.get().contents.as_ref().unwrap();
+            let cell_count = contents.cell_count();
+            if cell_count == 0 {
+                break;
+            }
+            let position = self.child_position(contents, page_idx)?;
+            let sibling_position = if position < cell_count {
+                position + 1
+            } else {
+                cell_count - 1
+            };
+            let sibling_idx =
+                contents.read_u32(self.child_pointer_offset(contents, sibling_position));
+            let sibling = self.pager.read_page(sibling_idx as usize)?;
+            return_if_locked!(sibling);
+            if !sibling.is_loaded() {
+                self.pager.load_page(sibling)?;
+                return Ok(CursorResult::IO);
+            }
+            if cell_count > 1 {
+                break;
+            }
+            level -= 1;
+        }
+        Ok(CursorResult::Ok(()))
+    }
 
-            // 3. Create an interior cell from the leaf cell
-            let mut cell_payload: Vec<u8> = Vec::new();
-            match predecessor_cell {
-                BTreeCell::TableLeafCell(leaf_cell) => {
-                    // Format: [left child page (4 bytes)][rowid varint]
-                    if let Some(child_pointer) = original_child_pointer {
-                        cell_payload.extend_from_slice(&child_pointer.to_be_bytes());
-                        write_varint_to_vec(leaf_cell._rowid, &mut cell_payload);
+    /// Take the empty page at the top of the stack out of the tree, i.e. a leaf without cells or
+    /// an interior page left with only its rightmost child.
+    /// The page is merged into a neighbour, which takes over its key range: the parent drops the
+    /// divider between the two, which moves into the neighbour unless it is a divider between
+    /// table leaves. A parent left without dividers is merged into its own neighbour in turn.
+    /// If the neighbour is too full for the divider, the page borrows the neighbour's nearest
+    /// cell instead, which becomes the new divider. The stack is left on the parent, which may
+    /// have to be balanced as the new divider can be larger than the old one.
+    fn free_empty_page(&mut self) -> Result<()> {
+        loop {
+            let page = self.stack.top();
+            let page_idx = page.get().id;
+            self.stack.pop();
+            let parent = self.stack.top();
+            let parent_is_root = !self.stack.has_parent();
+            parent.set_dirty();
+            self.pager.add_dirty(parent.get().id);
+            let parent_contents = parent.get().contents.as_mut().unwrap();
+            let is_index = matches!(parent_contents.page_type(), PageType::IndexInterior);
+            let cell_count = parent_contents.cell_count();
+            let position = self.child_position(parent_contents, page_idx)?;
+
+            if cell_count == 0 {
+                // Only page 1 may point to a single child without any dividers, which leaves it empty.
+                if !parent_is_root {
+                    return Err(LimboError::Corrupt(format!(
+                        "Corrupted page: non-root page {} has no cells",
+                        parent.get().id
+                    )));
+                }
+                self.pager.free_page(Some(page), page_idx)?;
+                let page_type = if is_index {
+                    PageType::IndexLeaf
+                } else {
+                    PageType::TableLeaf
+                };
+                let offset = parent_contents.offset;
+                btree_init_page(&parent, page_type, &self.pager.db_header.borrow(), offset);
+                return Ok(());
+            }
+
+            // The neighbour is the page on the right, unless the page is the rightmost child.
+            let page_is_left = position < cell_count;
+            let (divider_idx, sibling_position) = if page_is_left {
+                (position, position + 1)
+            } else {
+                (cell_count - 1, cell_count - 1)
+            };
+            let sibling_idx = parent_contents
+                .read_u32(self.child_pointer_offset(parent_contents, sibling_position))
+                as usize;
+            let sibling = self.pager.read_page(sibling_idx)?;
+            assert!(
+                sibling.is_loaded(),
+                "the neighbour of an empty page is loaded before the page is freed"
+            );
+            sibling.set_dirty();
+            self.pager.add_dirty(sibling_idx);
+            let sibling_contents = sibling.get().contents.as_mut().unwrap();
+            let (start, len) = parent_contents.cell_get_raw_region(
+                divider_idx,
+                self.payload_overflow_threshold_max(parent_contents.page_type()),
+                self.payload_overflow_threshold_min(parent_contents.page_type()),
+                self.usable_space(),
+            );
+            // Interior cells of both kinds, and index leaf cells, are laid out the same way after
+            // the left child pointer.
+            let divider_key = parent_contents.as_ptr()[start + 4..start + len].to_vec();
+
+            let page_contents = page.get().contents.as_mut().unwrap();
+            let is_leaf = page_contents.is_leaf();
+            let merged_cell = match page_contents.rightmost_pointer() {
+                None if is_index => Some(divider_key.clone()),
+                None => None,
+                Some(child) => {
+                    let left_child = if page_is_left {
+                        child
+                    } else {
+                        sibling_contents.rightmost_pointer().unwrap()
+                    };
+                    let mut cell = Vec::with_capacity(4 + divider_key.len());
+                    cell.extend_from_slice(&left_child.to_be_bytes());
+                    cell.extend_from_slice(&divider_key);
+                    Some(cell)
+                }
+            };
+            let free_space =
+                self.compute_free_space(sibling_contents, RefCell::borrow(&self.pager.db_header));
+            let fits = merged_cell
+                .as_ref()
+                .is_none_or(|cell| cell.len() + 2 <= free_space as usize);
+
+            if fits {
+                if let Some(cell) = merged_cell {
+                    if page_is_left {
+                        self.insert_into_cell(sibling_contents, &cell, 0);
+                    } else {
+                        let cell_idx = sibling_contents.cell_count();
+                        self.insert_into_cell(sibling_contents, &cell, cell_idx);
+                        if let Some(child) = page_contents.rightmost_pointer() {
+                            sibling_contents.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, child);
+                        }
                     }
                 }
-                _ => unreachable!("Expected table leaf cell"),
+                if !page_is_left {
+                    parent_contents.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, sibling_idx as u32);
+                }
+                self.drop_cell(parent_contents, divider_idx);
+                self.pager.free_page(Some(page), page_idx)?;
+
+                if parent_contents.cell_count() > 0 {
+                    return Ok(());
+                }
+                if !parent_is_root {
+                    continue;
+                }
+                if self.copy_into_root(&sibling, &parent) {
+                    self.pager.free_page(Some(sibling), sibling_idx)?;
+                }
+                return Ok(());
             }
-            self.insert_into_cell(contents, &cell_payload, cell_idx);
-            self.drop_cell(contents, cell_idx);
-        } else {
-            // For leaf nodes, simply remove the cell
-            self.drop_cell(contents, cell_idx);
+
+            let sibling_cell_count = sibling_contents.cell_count();
+            if sibling_cell_count < 2 {
+                return Err(LimboError::Corrupt(format!(
+                    "Corrupted page: page {} is full with less than two cells",
+                    sibling_idx
+                )));
+            }
+            page.set_dirty();
+            self.pager.add_dirty(page_idx);
+            let borrowed_idx = if page_is_left {
+                0
+            } else {
+                sibling_cell_count - 1
+            };
+            let (start, len) = sibling_contents.cell_get_raw_region(
+                borrowed_idx,
+                self.payload_overflow_threshold_max(sibling_contents.page_type()),
+                self.payload_overflow_threshold_min(sibling_contents.page_type()),
+                self.usable_space(),
+            );
+            let borrowed = sibling_contents.as_ptr()[start..start + len].to_vec();
+            let new_divider_key = if is_leaf && !is_index {
+                // Table leaves keep their rows, the divider is the largest rowid on the left.
+                self.insert_into_cell(page_contents, &borrowed, 0);
+                let last_left_idx = if page_is_left { 0 } else { borrowed_idx - 1 };
+                let BTreeCell::TableLeafCell(TableLeafCell { _rowid, .. }) = sibling_contents
+                    .cell_get(
+                        last_left_idx,
+                        self.pager.clone(),
+                        self.payload_overflow_threshold_max(sibling_contents.page_type()),
+                        self.payload_overflow_threshold_min(sibling_contents.page_type()),
+                        self.usable_space(),
+                    )?
+                else {
+                    unreachable!("table leaf pages only contain table leaf cells");
+                };
+                let mut key = Vec::with_capacity(9);
+                write_varint_to_vec(_rowid, &mut key);
+                key
+            } else {
+                // merged_cell is the old divider with the pointer to the right child, if any.
+                self.insert_into_cell(page_contents, merged_cell.as_ref().unwrap(), 0);
+                if is_leaf {
+                    borrowed
+                } else {
+                    let borrowed_child =
+                        u32::from_be_bytes([borrowed[0], borrowed[1], borrowed[2], borrowed[3]]);
+                    let pointer_holder = if page_is_left {
+                        &*page_contents
+                    } else {
+                        &*sibling_contents
+                    };
+                    pointer_holder.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, borrowed_child);
+                    borrowed[4..].to_vec()
+                }
+            };
+            self.drop_cell(sibling_contents, borrowed_idx);
+
+            let left_page_idx = if page_is_left { page_idx } else { sibling_idx };
+            let mut new_divider = Vec::with_capacity(4 + new_divider_key.len());
+            new_divider.extend_from_slice(&(left_page_idx as u32).to_be_bytes());
+            new_divider.extend_from_slice(&new_divider_key);
+            self.drop_cell(parent_contents, divider_idx);
+            self.insert_into_cell(parent_contents, &new_divider, divider_idx);
+            return Ok(());
+        }
+    }
+
+    /// Replace the contents of a root page with those of its only child.
+    /// Returns false if they don't fit, which is only possible on page 1, as the database header
+    /// takes up part of it. Page 1 may point to its only child without any dividers.
+    fn copy_into_root(&self, child: &PageRef, root: &PageRef) -> bool {
+        let child_contents = child.get().contents.as_ref().unwrap();
+        let offset = root.get().contents.as_ref().unwrap().offset;
+        let cells = (0..child_contents.cell_count())
+            .map(|cell_idx| {
+                child_contents.cell_get_raw_region(
+                    cell_idx,
+                    self.payload_overflow_threshold_max(child_contents.page_type()),
+                    self.payload_overflow_threshold_min(child_contents.page_type()),
+                    self.usable_space(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let cells_size = cells.iter().map(|(_, len)| len + 2).sum::<usize>();
+        if offset + child_contents.header_size() + cells_size > self.usable_space() {
+            return false;
         }
 
-        // TODO(Krishna): Implement balance after delete. I will implement after balance_nonroot is extended.
+        btree_init_page(
+            root,
+            child_contents.page_type(),
+            &self.pager.db_header.borrow(),
+            offset,
+        );
+        let root_contents = root.get().contents.as_mut().unwrap();
+        for (cell_idx, (start, len)) in cells.into_iter().enumerate() {
+            self.insert_into_cell(
+                root_contents,
+                &child_contents.as_ptr()[start..start + len],
+                cell_idx,
+            );
+        }
+        if let Some(rightmost_pointer) = child_contents.rightmost_pointer() {
+            root_contents.write_u32(PAGE_HEADER_OFFSET_RIGHTMOST_PTR, rightmost_pointer);
+        }
+        true
+    }
+
+    /// Position of the pointer to `page_idx` among the children of an interior page.
+    /// The rightmost pointer comes after those of the cells.
+    fn child_position(&self, page: &PageContent, page_idx: usize) -> Result<usize> {
+        let cell_count = page.cell_count();
+        (0..=cell_count)
+            .find(|&position| {
+                page.read_u32(self.child_pointer_offset(page, position)) as usize == page_idx
+            })
+            .ok_or_else(|| {
+                LimboError::Corrupt(format!("Corrupted page: page {} is not a child", page_idx))
+            })
+    }
+
+    /// Offset of the child pointer at `position` in an interior page, for read_u32() and write_u32().
+    fn child_pointer_offset(&self, page: &PageContent, position: usize) -> usize {
+        if position == page.cell_count() {
+            return PAGE_HEADER_OFFSET_RIGHTMOST_PTR;
+        }
+        let (start, _) = page.cell_get_raw_region(
+            position,
+            self.payload_overflow_threshold_max(page.page_type()),
+            self.payload_overflow_threshold_min(page.page_type()),
+            self.usable_space(),
+        );
+        // Cell pointers are relative to the start of the page, which precedes the header on page 1.
+        start - page.offset
+    }
+
+    /// Move the cursor to the first entry after the deleted key, so that next() returns it.
+    fn move_after_deleted_key(&mut self, key: &DeletedKey) -> Result<CursorResult<()>> {
+        let seek_key = match key {
+            DeletedKey::TableRowId(rowid) => SeekKey::TableRowId(*rowid),
+            DeletedKey::IndexKey(record) => SeekKey::IndexKey(record),
+        };
+        return_if_io!(self.move_to(seek_key.clone(), SeekOp::GE));
+        let page = self.stack.top();
+        let contents = page.get().contents.as_ref().unwrap();
+        let cell_idx = match seek_key {
+            SeekKey::TableRowId(rowid) => self.find_cell(contents, rowid),
+            SeekKey::IndexKey(record) => self.find_index_cell(contents, record),
+        };
+        self.stack.set_cell_index(cell_idx as i32);
         Ok(CursorResult::Ok(()))
     }
 
@@ -2149,6 +2883,106 @@ impl BTreeCursor {
         id as u32
     }
 
+    /// Free every page of the btree rooted at `self.root_page`, including the root page itself
+    /// and the overflow pages of its cells, by returning them to the freelist.
+    /// The btree must not be used after this.
+    pub fn btree_destroy(&mut self) -> Result<CursorResult<()>> {
+        if !matches!(self.state, CursorState::Destroy(_)) {
+            self.state = CursorState::Destroy(DestroyInfo {
+                pending: vec![DestroyItem::Page(self.root_page)],
+            });
+        }
+        loop {
+            let CursorState::Destroy(destroy_info) = &self.state else {
+                unreachable!();
+            };
+            let Some(item) = destroy_info.pending.last().copied() else {
+                break;
+            };
+            match item {
+                DestroyItem::Page(page_idx) => {
+                    let page = self.pager.read_page(page_idx)?;
+                    return_if_locked!(page);
+                    // Collect the children and the overflow chains before the page is freed, as
+                    // freeing it may overwrite its contents.
+                    let mut children = Vec::new();
+                    {
+                        let contents = page.get().contents.as_ref().unwrap();
+                        for cell_idx in 0..contents.cell_count() {
+                            let (left_child, first_overflow_page) =
+                                self.cell_child_and_overflow(contents, cell_idx)?;
+                            if let Some(first_overflow_page) = first_overflow_page {
+                                children.push(DestroyItem::Overflow(first_overflow_page as usize));
+                            }
+                            if let Some(left_child) = left_child {
+                                children.push(DestroyItem::Page(left_child as usize));
+                            }
+                        }
+                        if let Some(rightmost_pointer) = contents.rightmost_pointer() {
+                            children.push(DestroyItem::Page(rightmost_pointer as usize));
+                        }
+                    }
+                    let CursorState::Destroy(destroy_info) = &mut self.state else {
+                        unreachable!();
+                    };
+                    destroy_info.pending.pop();
+                    destroy_info.pending.extend(children);
+                    self.pager.free_page(Some(page), page_idx)?;
+                }
+                DestroyItem::Overflow(page_idx) => {
+                    let page = self.pager.read_page(page_idx)?;
+                    return_if_locked!(page);
+                    let next_page = page.get().contents.as_ref().unwrap().read_u32(0);
+                    let CursorState::Destroy(destroy_info) = &mut self.state else {
+                        unreachable!();
+                    };
+                    destroy_info.pending.pop();
+                    if next_page != 0 {
+                        destroy_info
+                            .pending
+                            .push(DestroyItem::Overflow(next_page as usize));
+                    }
+                    self.pager.free_page(Some(page), page_idx)?;
+                }
+            }
+        }
+        self.state = CursorState::None;
+        Ok(CursorResult::Ok(()))
+    }
+
+    /// Read the left child pointer and the first overflow page of a cell without reading its payload,
+    /// which may live on overflow pages that are not loaded.
+    fn cell_child_and_overflow(
+        &self,
+        contents: &PageContent,
+        cell_idx: usize,
+    ) -> Result<(Option<u32>, Option<u32>)> {
+        let page_type = contents.page_type();
+        let (start, len) = contents.cell_get_raw_region(
+            cell_idx,
+            self.payload_overflow_threshold_max(page_type),
+            self.payload_overflow_threshold_min(page_type),
+            self.usable_space(),
+        );
+        let buf = contents.as_ptr();
+        let read_u32 =
+            |pos: usize| u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+        let (left_child, payload_start) = match page_type {
+            PageType::TableInterior => return Ok((Some(read_u32(start)), None)),
+            PageType::IndexInterior => (Some(read_u32(start)), start + 4),
+            PageType::TableLeaf | PageType::IndexLeaf => (None, start),
+        };
+        let (payload_size, _) = read_varint(&buf[payload_start..])?;
+        let (overflows, _) = payload_overflows(
+            payload_size as usize,
+            self.payload_overflow_threshold_max(page_type),
+            self.payload_overflow_threshold_min(page_type),
+            self.usable_space(),
+        );
+        let first_overflow_page = overflows.then(|| read_u32(start + len - 4));
+        Ok((left_child, first_overflow_page))
+    }
+
     fn clear_overflow_pages(&self, cell: &BTreeCell) -> Result<CursorResult<()>> {
         // Get overflow info based on cell type
         let (first_overflow_page, n_overflow) = match cell {
@@ -2226,8 +3060,7 @@ impl BTreeCursor {
 
         // Calculate expected overflow pages
         let overflow_page_size = self.usable_space() - 4;
-        let n_overflow =
-            (payload_len - local_size + overflow_page_size).div_ceil(overflow_page_size);
+        let n_overflow = (payload_len - local_size).div_ceil(overflow_page_size);
         if n_overflow == 0 {
             return Err(LimboError::Corrupt("Invalid overflow calculation".into()));
         }
@@ -2281,6 +3114,11 @@ impl PageStack {
         page
     }
 
+    /// Get the page at the given depth of the stack, where the root page is at depth 0.
+    fn page_at(&self, depth: usize) -> PageRef {
+        self.stack.borrow()[depth].as_ref().unwrap().clone()
+    }
+
     /// Get the parent page of the current page.
     fn parent(&self) -> PageRef {
         let current = *self.current_page.borrow();
@@ -2314,11 +3152,6 @@ impl PageStack {
         self.cell_indices.borrow_mut()[current] += 1;
     }
 
-    fn retreat(&self) {
-        let current = self.current();
-        self.cell_indices.borrow_mut()[current] -= 1;
-    }
-
     fn set_cell_index(&self, idx: i32) {
         let current = self.current();
         self.cell_indices.borrow_mut()[current] = idx
@@ -2333,6 +3166,15 @@ impl PageStack {
     }
 }
 
+/// The rowid of an index entry, which is its last value. The entries of the b-tree of a WITHOUT
+/// ROWID table end with a column of the table instead, so their "rowid" must not be used.
+fn index_entry_rowid(record: &Record) -> Option<u64> {
+    match record.last_value() {
+        Some(OwnedValue::Integer(rowid)) => Some(*rowid as u64),
+        _ => None,
+    }
+}
+
 pub fn btree_init_page(
     page: &PageRef,
     page_type: PageType,
@@ -2750,8 +3592,8 @@ mod tests {
         let max_local = cursor.payload_overflow_threshold_max(PageType::TableLeaf);
         let usable_size = cursor.usable_space();
 
-        // Create a large payload that will definitely trigger overflow
-        let large_payload = vec![b'A'; max_local + usable_size];
+        // Create a large payload that spills into exactly three overflow pages
+        let large_payload = vec![b'A'; max_local + usable_size * 2];
 
         // Setup overflow pages (2, 3, 4) with linking
         let mut current_page = 2u32;
@@ -1,4 +1,3 @@
-#[cfg(feature = "fs")]
 use crate::error::LimboError;
 use crate::{io::Completion, Buffer, Result};
 use std::{cell::RefCell, rc::Rc};
@@ -13,14 +12,14 @@ pub trait DatabaseStorage {
     fn write_page(&self, page_idx: usize, buffer: Rc<RefCell<Buffer>>, c: Completion)
         -> Result<()>;
     fn sync(&self, c: Completion) -> Result<()>;
+    /// Shrink the database to the first `len` bytes.
+    fn truncate(&self, len: usize) -> Result<()>;
 }
 
-#[cfg(feature = "fs")]
 pub struct FileStorage {
     file: Rc<dyn crate::io::File>,
 }
 
-#[cfg(feature = "fs")]
 impl DatabaseStorage for FileStorage {
     fn read_page(&self, page_idx: usize, c: Completion) -> Result<()> {
         let r = match c {
@@ -55,9 +54,12 @@ impl DatabaseStorage for FileStorage {
     fn sync(&self, c: Completion) -> Result<()> {
         self.file.sync(c)
     }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.file.truncate(len)
+    }
 }
 
-#[cfg(feature = "fs")]
 impl FileStorage {
     pub fn new(file: Rc<dyn crate::io::File>) -> Self {
         Self { file }
@@ -6,7 +6,7 @@ use crate::storage::wal::{CheckpointResult, Wal};
 use crate::{Buffer, LimboError, Result};
 use parking_lot::RwLock;
 use std::cell::{RefCell, UnsafeCell};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -143,6 +143,29 @@ struct FlushInfo {
     in_flight_writes: Rc<RefCell<usize>>,
 }
 
+/// The state of the database at the start of a transaction or savepoint, which a rollback returns to.
+struct UndoLog {
+    /// The database header at the start.
+    db_header: DatabaseHeader,
+    /// Contents of the pages modified since the start, as they were before they were first
+    /// modified. Pages allocated since the start are not saved, they are dropped on rollback.
+    pages: HashMap<usize, Vec<u8>>,
+}
+
+impl UndoLog {
+    fn new(db_header: DatabaseHeader) -> Self {
+        Self {
+            db_header,
+            pages: HashMap::new(),
+        }
+    }
+
+    /// Whether the page has to be saved before it is modified.
+    fn needs_page(&self, page_id: usize) -> bool {
+        page_id <= self.db_header.database_size as usize && !self.pages.contains_key(&page_id)
+    }
+}
+
 /// The pager interface implements the persistence layer by providing access
 /// to pages of the database file, including caching, concurrency control, and
 /// transaction management.
@@ -158,6 +181,10 @@ pub struct Pager {
     /// I/O interface for input/output operations.
     pub io: Arc<dyn crate::io::IO>,
     dirty_pages: Rc<RefCell<HashSet<usize>>>,
+    /// The database as of the last commit, which a rollback restores.
+    undo_log: RefCell<UndoLog>,
+    /// The database as of each savepoint of the current transaction, innermost last.
+    savepoints: RefCell<Vec<UndoLog>>,
     pub db_header: Rc<RefCell<DatabaseHeader>>,
 
     flush_info: RefCell<FlushInfo>,
@@ -187,6 +214,8 @@ impl Pager {
             page_cache,
             io,
             dirty_pages: Rc::new(RefCell::new(HashSet::new())),
+            undo_log: RefCell::new(UndoLog::new(db_header_ref.borrow().clone())),
+            savepoints: RefCell::new(Vec::new()),
             db_header: db_header_ref.clone(),
             flush_info: RefCell::new(FlushInfo {
                 state: FlushState::Start,
@@ -211,13 +240,110 @@ impl Pager {
         let checkpoint_status = self.cacheflush()?;
         match checkpoint_status {
             CheckpointStatus::IO => Ok(checkpoint_status),
+            // The flush resumed one started before the last pages of the transaction were
+            // modified, which still have to be appended before the transaction commits.
+            CheckpointStatus::Done(_) if !self.dirty_pages.borrow().is_empty() => {
+                Ok(CheckpointStatus::IO)
+            }
             CheckpointStatus::Done(_) => {
+                self.wal.borrow_mut().commit();
+                self.undo_log
+                    .replace(UndoLog::new(self.db_header.borrow().clone()));
+                self.savepoints.borrow_mut().clear();
                 self.wal.borrow().end_read_tx()?;
                 Ok(checkpoint_status)
             }
         }
     }
 
+    /// Discard every change made since the last commit: the modified pages in the page cache are
+    /// replaced by their committed contents, pages allocated since are dropped, frames appended to
+    /// the WAL since are discarded and the database header is restored.
+    pub fn rollback(&self) -> Result<()> {
+        let undo_log = self
+            .undo_log
+            .replace(UndoLog::new(self.committed_db_header()));
+        self.savepoints.borrow_mut().clear();
+        {
+            let mut cache = self.page_cache.write();
+            let max_frame = self.wal.borrow().get_max_frame();
+            for page_id in self.dirty_pages.borrow_mut().drain() {
+                cache.delete(PageCacheKey::new(page_id, Some(max_frame)));
+            }
+            for (page_id, contents) in undo_log.pages {
+                let page = self.restore_page(page_id, &contents);
+                cache.insert(PageCacheKey::new(page_id, Some(max_frame)), page);
+            }
+        }
+        self.db_header.replace(undo_log.db_header);
+        self.flush_info.borrow_mut().state = FlushState::Start;
+        self.wal.borrow_mut().rollback()?;
+        self.wal.borrow().end_read_tx()?;
+        Ok(())
+    }
+
+    /// Open a savepoint, returning its index among the savepoints of the transaction.
+    pub fn open_savepoint(&self) -> usize {
+        let mut savepoints = self.savepoints.borrow_mut();
+        savepoints.push(UndoLog::new(self.db_header.borrow().clone()));
+        savepoints.len() - 1
+    }
+
+    /// Release the savepoint at `index` and the ones opened after it. Their changes stay part of
+    /// the transaction.
+    pub fn release_savepoint(&self, index: usize) {
+        self.savepoints.borrow_mut().truncate(index);
+    }
+
+    /// Discard the changes made since the savepoint at `index` was opened, releasing the ones opened
+    /// after it. The savepoint itself stays open.
+    pub fn rollback_to_savepoint(&self, index: usize) {
+        let mut savepoints = self.savepoints.borrow_mut();
+        savepoints.truncate(index + 1);
+        let savepoint = savepoints.last_mut().unwrap();
+        let db_size = savepoint.db_header.database_size as usize;
+        let mut cache = self.page_cache.write();
+        let max_frame = self.wal.borrow().get_max_frame();
+        let mut dirty_pages = self.dirty_pages.borrow_mut();
+        dirty_pages.retain(|page_id| {
+            if *page_id > db_size {
+                cache.delete(PageCacheKey::new(*page_id, Some(max_frame)));
+                return false;
+            }
+            true
+        });
+        // The restored pages may still differ from their committed contents, so they stay dirty.
+        for (page_id, contents) in savepoint.pages.drain() {
+            let page = self.restore_page(page_id, &contents);
+            page.set_dirty();
+            dirty_pages.insert(page_id);
+            cache.insert(PageCacheKey::new(page_id, Some(max_frame)), page);
+        }
+        self.db_header.replace(savepoint.db_header.clone());
+    }
+
+    fn committed_db_header(&self) -> DatabaseHeader {
+        self.undo_log.borrow().db_header.clone()
+    }
+
+    /// Build a page with the given saved contents.
+    fn restore_page(&self, page_id: usize, contents: &[u8]) -> PageRef {
+        let offset = if page_id == 1 {
+            sqlite3_ondisk::DATABASE_HEADER_SIZE
+        } else {
+            0
+        };
+        let page = allocate_page(page_id, &self.buffer_pool, offset);
+        page.get()
+            .contents
+            .as_ref()
+            .unwrap()
+            .as_ptr()
+            .copy_from_slice(contents);
+        page.set_uptodate();
+        page
+    }
+
     pub fn end_read_tx(&self) -> Result<()> {
         self.wal.borrow().end_read_tx()?;
         Ok(())
@@ -302,18 +428,50 @@ impl Pager {
         page_cache.resize(capacity);
     }
 
+    /// Marks the page as modified by the current transaction. This must happen before the page is
+    /// modified, so that its committed contents can be saved for a rollback.
     pub fn add_dirty(&self, page_id: usize) {
+        self.save_for_undo(page_id);
         // TODO: check duplicates?
         let mut dirty_pages = RefCell::borrow_mut(&self.dirty_pages);
         dirty_pages.insert(page_id);
     }
 
+    /// Saves the contents of the page the first time it is touched by the current transaction, and
+    /// by each of its savepoints. Pages past the end of the database at the start of either are new
+    /// and have nothing to restore.
+    fn save_for_undo(&self, page_id: usize) {
+        let mut undo_log = self.undo_log.borrow_mut();
+        let mut savepoints = self.savepoints.borrow_mut();
+        let mut undo_logs = std::iter::once(&mut *undo_log)
+            .chain(savepoints.iter_mut())
+            .filter(|undo_log| undo_log.needs_page(page_id))
+            .peekable();
+        if undo_logs.peek().is_none() {
+            return;
+        }
+        let mut cache = self.page_cache.write();
+        let page_key = PageCacheKey::new(page_id, Some(self.wal.borrow().get_max_frame()));
+        let Some(page) = cache.get(&page_key) else {
+            return;
+        };
+        let Some(contents) = page.get().contents.as_ref() else {
+            return;
+        };
+        for undo_log in undo_logs {
+            undo_log.pages.insert(page_id, contents.as_ptr().to_vec());
+        }
+    }
+
     pub fn cacheflush(&self) -> Result<CheckpointStatus> {
         let mut checkpoint_result = CheckpointResult::new();
         loop {
             let state = self.flush_info.borrow().state.clone();
             match state {
                 FlushState::Start => {
+                    if self.dirty_pages.borrow().is_empty() {
+                        break;
+                    }
                     let db_size = self.db_header.borrow().database_size;
                     for page_id in self.dirty_pages.borrow().iter() {
                         let mut cache = self.page_cache.write();
@@ -477,12 +635,16 @@ impl Pager {
         };
 
         self.db_header.borrow_mut().freelist_pages += 1;
+        self.persist_database_header()?;
 
         let trunk_page_id = self.db_header.borrow().freelist_trunk_page;
 
         if trunk_page_id != 0 {
             // Add as leaf to current trunk
             let trunk_page = self.read_page(trunk_page_id as usize)?;
+            while trunk_page.is_locked() {
+                self.io.run_once()?;
+            }
             let trunk_page_contents = trunk_page.get().contents.as_ref().unwrap();
             let number_of_leaf_pages = trunk_page_contents.read_u32(TRUNK_PAGE_LEAF_COUNT_OFFSET);
 
@@ -492,6 +654,9 @@ impl Pager {
             if number_of_leaf_pages < max_free_list_entries as u32 {
                 trunk_page.set_dirty();
                 self.add_dirty(trunk_page_id as usize);
+                // The freed page is not written, but it is unloaded below, so it has to be
+                // restored on rollback all the same.
+                self.save_for_undo(page_id);
 
                 trunk_page_contents
                     .write_u32(TRUNK_PAGE_LEAF_COUNT_OFFSET, number_of_leaf_pages + 1);
@@ -517,12 +682,28 @@ impl Pager {
         contents.write_u32(TRUNK_PAGE_LEAF_COUNT_OFFSET, 0);
         // Update page 1 to point to new trunk
         self.db_header.borrow_mut().freelist_trunk_page = page_id as u32;
-        // Clear flags
-        page.clear_uptodate();
-        page.clear_loaded();
+        self.persist_database_header()?;
+        // The page stays loaded: it is read again when the next page is freed.
         Ok(())
     }
 
+    /// Write the in-memory database header to page 1.
+    fn persist_database_header(&self) -> Result<()> {
+        loop {
+            let first_page_ref = self.read_page(1)?;
+            if first_page_ref.is_locked() {
+                self.io.run_once()?;
+                continue;
+            }
+            first_page_ref.set_dirty();
+            self.add_dirty(1);
+
+            let contents = first_page_ref.get().contents.as_ref().unwrap();
+            contents.write_database_header(&self.db_header.borrow());
+            return Ok(());
+        }
+    }
+
     /*
         Gets a new page that increasing the size of the page or uses a free page.
         Currently free list pages are not yet supported.
@@ -563,6 +744,27 @@ impl Pager {
         Ok(page)
     }
 
+    /// Replaces the contents of a page, as part of the current transaction, with `contents`, a
+    /// whole page that may come from another database.
+    pub fn overwrite_page(&self, page_id: usize, contents: &[u8]) {
+        self.add_dirty(page_id);
+        let page = self.restore_page(page_id, contents);
+        page.set_dirty();
+        let mut cache = self.page_cache.write();
+        let page_key = PageCacheKey::new(page_id, Some(self.wal.borrow().get_max_frame()));
+        cache.insert(page_key, page);
+    }
+
+    /// Shrinks the database file to its first `num_pages` pages. The pages past them must not be
+    /// in the WAL anymore, so this follows a checkpoint that backfilled all of it. The page cache
+    /// is dropped, as its pages may be keyed by frames that the checkpoint made reusable.
+    pub fn truncate(&self, num_pages: usize) -> Result<()> {
+        let page_size = self.db_header.borrow().page_size as usize;
+        self.page_io.truncate(num_pages * page_size)?;
+        self.page_cache.write().clear();
+        Ok(())
+    }
+
     pub fn put_loaded_page(&self, id: usize, page: PageRef) {
         let mut cache = self.page_cache.write();
         // cache insert invalidates previous page
@@ -599,9 +599,9 @@ impl PageContent {
                     usable_size,
                 );
                 if overflows {
-                    4 + to_read + n_payload + 4
+                    4 + to_read + n_payload
                 } else {
-                    4 + len_payload as usize + n_payload + 4
+                    4 + len_payload as usize + n_payload
                 }
             }
             PageType::TableInterior => {
@@ -617,9 +617,9 @@ impl PageContent {
                     usable_size,
                 );
                 if overflows {
-                    to_read + n_payload + 4
+                    to_read + n_payload
                 } else {
-                    len_payload as usize + n_payload + 4
+                    len_payload as usize + n_payload
                 }
             }
             PageType::TableLeaf => {
@@ -894,6 +894,13 @@ fn read_payload(unread: &[u8], payload_size: usize, pager: Rc<Pager>) -> (Vec<u8
                     break;
                 }
             }
+            // Cells are parsed in one go, so wait for the page instead of yielding.
+            while page.is_locked() || !page.is_loaded() {
+                if !page.is_locked() {
+                    let _ = pager.load_page(page.clone());
+                }
+                let _ = pager.io.run_once();
+            }
             let page = page.get();
             let contents = page.contents.as_mut().unwrap();
 
@@ -1192,9 +1199,10 @@ pub fn begin_read_wal_frame(
     });
     let buf = Rc::new(RefCell::new(Buffer::new(buf, drop_fn)));
     let frame = page.clone();
+    let page_idx = page.get().id;
     let complete = Box::new(move |buf: Rc<RefCell<Buffer>>| {
         let frame = frame.clone();
-        finish_read_page(2, buf, frame).unwrap();
+        finish_read_page(page_idx, buf, frame).unwrap();
     });
     let c = Completion::Read(ReadCompletion::new(buf, complete));
     io.pread(offset, c)?;
@@ -11,7 +11,7 @@ use crate::result::LimboResult;
 use crate::storage::sqlite3_ondisk::{
     begin_read_wal_frame, begin_write_wal_frame, WAL_FRAME_HEADER_SIZE, WAL_HEADER_SIZE,
 };
-use crate::{Buffer, Result};
+use crate::{Buffer, LimboError, Result};
 use crate::{Completion, Page};
 
 use self::sqlite3_ondisk::{checksum_wal, PageContent, WAL_MAGIC_BE, WAL_MAGIC_LE};
@@ -163,6 +163,12 @@ pub trait Wal {
         write_counter: Rc<RefCell<usize>>,
     ) -> Result<()>;
 
+    /// Mark the frames appended so far as committed, so that a rollback keeps them.
+    fn commit(&mut self);
+
+    /// Discard the frames appended since the last commit.
+    fn rollback(&mut self) -> Result<()>;
+
     fn should_checkpoint(&self) -> bool;
     fn checkpoint(
         &mut self,
@@ -245,6 +251,10 @@ pub struct WalFile {
     max_frame: u64,
     /// Start of range to look for frames range=(minframe..max_frame)
     min_frame: u64,
+    /// Shared max frame as of the last commit. Frames from this one on are uncommitted.
+    committed_max_frame: u64,
+    /// Checksum of the last committed frame, where appending resumes after a rollback.
+    committed_checksum: (u32, u32),
 }
 
 impl fmt::Debug for WalFile {
@@ -259,6 +269,7 @@ impl fmt::Debug for WalFile {
             .field("max_frame_read_lock_index", &self.max_frame_read_lock_index)
             .field("max_frame", &self.max_frame)
             .field("min_frame", &self.min_frame)
+            .field("committed_max_frame", &self.committed_max_frame)
             // Excluding other fields
             .finish()
     }
@@ -468,6 +479,42 @@ impl Wal for WalFile {
         Ok(())
     }
 
+    fn commit(&mut self) {
+        let shared = self.shared.read();
+        self.committed_max_frame = shared.max_frame;
+        self.committed_checksum = shared.last_checksum;
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        let mut shared = self.shared.write();
+        let committed_max_frame = self.committed_max_frame;
+        if shared.max_frame == committed_max_frame {
+            return Ok(());
+        }
+        if shared.max_frame < committed_max_frame || shared.nbackfills >= committed_max_frame.max(1)
+        {
+            return Err(LimboError::InternalError(
+                "cannot rollback frames that were already checkpointed".to_string(),
+            ));
+        }
+        debug!(
+            "wal_rollback(max_frame={}, committed_max_frame={})",
+            shared.max_frame, committed_max_frame
+        );
+        let shared = &mut *shared;
+        shared.frame_cache.retain(|_, frames| {
+            frames.retain(|frame| *frame < committed_max_frame);
+            !frames.is_empty()
+        });
+        let frame_cache = &shared.frame_cache;
+        shared
+            .pages_in_frames
+            .retain(|page| frame_cache.contains_key(page));
+        shared.max_frame = committed_max_frame;
+        shared.last_checksum = self.committed_checksum;
+        Ok(())
+    }
+
     fn should_checkpoint(&self) -> bool {
         let shared = self.shared.read();
         let frame_id = shared.max_frame as usize;
@@ -601,6 +648,10 @@ impl Wal for WalFile {
                     if everything_backfilled {
                         // Here we know that we backfilled everything, therefore we can safely
                         // reset the wal.
+                        if self.committed_max_frame == shared.max_frame {
+                            self.committed_max_frame = 0;
+                            self.committed_checksum = shared.last_checksum;
+                        }
                         shared.frame_cache.clear();
                         shared.pages_in_frames.clear();
                         shared.max_frame = 0;
@@ -679,6 +730,10 @@ impl WalFile {
                 overflow_cells: Vec::new(),
             });
         }
+        let (committed_max_frame, committed_checksum) = {
+            let shared = shared.read();
+            (shared.max_frame, shared.last_checksum)
+        };
         Self {
             io,
             shared,
@@ -697,6 +752,8 @@ impl WalFile {
             max_frame: 0,
             min_frame: 0,
             max_frame_read_lock_index: 0,
+            committed_max_frame,
+            committed_checksum,
         }
     }
 
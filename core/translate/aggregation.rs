@@ -7,6 +7,7 @@ use crate::{
 };
 
 use super::{
+    collate::expr_collation,
     emitter::{Resolver, TranslateCtx},
     expr::translate_expr,
     plan::{Aggregate, SelectPlan, TableReference},
@@ -71,6 +72,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Avg,
+                collation: None,
             });
             target_register
         }
@@ -92,6 +94,7 @@ pub fn translate_aggregation_step(
                 } else {
                     AggFunc::Count
                 },
+                collation: None,
             });
             target_register
         }
@@ -134,6 +137,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: delimiter_reg,
                 func: AggFunc::GroupConcat,
+                collation: None,
             });
 
             target_register
@@ -150,6 +154,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Max,
+                collation: expr_collation(expr, referenced_tables),
             });
             target_register
         }
@@ -165,6 +170,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Min,
+                collation: expr_collation(expr, referenced_tables),
             });
             target_register
         }
@@ -199,6 +205,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: delimiter_reg,
                 func: AggFunc::StringAgg,
+                collation: None,
             });
 
             target_register
@@ -215,6 +222,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Sum,
+                collation: None,
             });
             target_register
         }
@@ -230,6 +238,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Total,
+                collation: None,
             });
             target_register
         }
@@ -262,6 +271,7 @@ pub fn translate_aggregation_step(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::External(func.clone()),
+                collation: None,
             });
             target_register
         }
@@ -0,0 +1,1251 @@
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast;
+
+use crate::schema::{quote_ident, BTreeTable, Column, Index, Schema, Trigger, View, MAIN_DB};
+use crate::util::{normalize_ident, PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX};
+use crate::vdbe::builder::{CursorType, ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::vdbe::insn::{CmpInsFlags, InsertFlags, Insn};
+use crate::{bail_parse_error, Result, SymbolTable};
+
+use super::collate::check_column_collation;
+use super::schema_table;
+use super::trigger::StmtFormatter;
+use super::view::create_view_to_str;
+
+/// The new contents of a sqlite_schema entry of the altered table or of one of its indexes,
+/// or of a view or trigger that refers to the table.
+struct SchemaEntryUpdate {
+    /// The name of the table or index the entry describes, before the change.
+    name: String,
+    new_name: String,
+    new_tbl_name: String,
+    /// The new CREATE statement, or None for automatic indexes which have none.
+    new_sql: Option<String>,
+}
+
+impl SchemaEntryUpdate {
+    fn table(name: &str, new_table: &BTreeTable) -> Self {
+        Self {
+            name: name.to_string(),
+            new_name: new_table.name.clone(),
+            new_tbl_name: new_table.name.clone(),
+            new_sql: Some(new_table.to_sql()),
+        }
+    }
+
+    fn index(name: &str, new_index: &Index) -> Self {
+        let is_automatic = new_index
+            .name
+            .starts_with(PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX);
+        Self {
+            name: name.to_string(),
+            new_name: new_index.name.clone(),
+            new_tbl_name: new_index.table_name.clone(),
+            new_sql: (!is_automatic).then(|| new_index.to_sql()),
+        }
+    }
+
+    fn view(name: &str, new_sql: String) -> Self {
+        Self {
+            name: name.to_string(),
+            new_name: name.to_string(),
+            new_tbl_name: name.to_string(),
+            new_sql: Some(new_sql),
+        }
+    }
+
+    fn trigger(name: &str, new_tbl_name: &str, new_sql: String) -> Self {
+        Self {
+            name: name.to_string(),
+            new_name: name.to_string(),
+            new_tbl_name: new_tbl_name.to_string(),
+            new_sql: Some(new_sql),
+        }
+    }
+}
+
+/*
+ALTER TABLE is implemented like SQLite does it: the sqlite_schema entries of the table and of its
+indexes are rewritten, then the in-memory schema of the table is parsed again from them. Only
+DROP COLUMN has to rewrite the rows of the table. ADD COLUMN relies on the Column instruction
+reading the columns missing from older rows as their default value.
+*/
+pub fn translate_alter_table(
+    query_mode: QueryMode,
+    schema: &Schema,
+    tbl_name: &ast::QualifiedName,
+    body: ast::AlterTableBody,
+    syms: &SymbolTable,
+) -> Result<ProgramBuilder> {
+    let Some(table) = schema.resolve_table(tbl_name)? else {
+        bail_parse_error!("no such table: {}", tbl_name.name.0);
+    };
+    let Some(btree_table) = table.btree() else {
+        bail_parse_error!("virtual tables may not be altered");
+    };
+    if btree_table.name.starts_with("sqlite_") {
+        bail_parse_error!("table {} may not be altered", btree_table.name);
+    }
+    let indexes = schema.get_table_indices(&btree_table);
+
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 2,
+        approx_num_insns: 40,
+        approx_num_labels: 4,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let mut new_table = BTreeTable::clone(&btree_table);
+    let mut updates = Vec::new();
+    // The tables whose foreign keys reference the altered table and change with it.
+    let mut new_child_tables = Vec::new();
+    // The views and triggers that refer to what is renamed.
+    let (mut view_updates, mut trigger_updates) = (Vec::new(), Vec::new());
+    match body {
+        ast::AlterTableBody::RenameTo(new_name) => {
+            let new_name = normalize_ident(&new_name.0);
+            if schema.get_table(&new_name).is_some() || schema.get_index(&new_name).is_some() {
+                bail_parse_error!(
+                    "there is already another table or index with this name: {}",
+                    new_name
+                );
+            }
+            if new_name.starts_with("sqlite_") {
+                bail_parse_error!("object name reserved for internal use: {}", new_name);
+            }
+            let automatic_index_prefix = format!(
+                "{}{}_",
+                PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX, btree_table.name
+            );
+            for index in indexes {
+                let mut new_index = Index::clone(index);
+                new_index.table_name = new_name.clone();
+                if let Some(suffix) = index.name.strip_prefix(&automatic_index_prefix) {
+                    new_index.name = format!(
+                        "{}{}_{}",
+                        PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX, new_name, suffix
+                    );
+                }
+                updates.push(SchemaEntryUpdate::index(&index.name, &new_index));
+            }
+            for foreign_key in new_table.foreign_keys.iter_mut() {
+                if foreign_key.parent_table == btree_table.name {
+                    foreign_key.parent_table = new_name.clone();
+                }
+            }
+            for (child, _) in schema.get_referencing_foreign_keys(&btree_table) {
+                if child.name == btree_table.name
+                    || new_child_tables
+                        .iter()
+                        .any(|new_child: &BTreeTable| new_child.name == child.name)
+                {
+                    continue;
+                }
+                let mut new_child = BTreeTable::clone(&child);
+                for foreign_key in new_child.foreign_keys.iter_mut() {
+                    if foreign_key.parent_table == btree_table.name {
+                        foreign_key.parent_table = new_name.clone();
+                    }
+                }
+                new_child_tables.push(new_child);
+            }
+            (view_updates, trigger_updates) =
+                renamed_views_and_triggers(schema, &btree_table, Rename::Table(&new_name));
+            new_table.name = new_name;
+        }
+        ast::AlterTableBody::RenameColumn { old, new } => {
+            let old = normalize_ident(&old.0);
+            let new = normalize_ident(&new.0);
+            let Some((column_idx, _)) = btree_table.get_column(&old) else {
+                bail_parse_error!("no such column: \"{}\"", old);
+            };
+            if btree_table.get_column(&new).is_some() {
+                bail_parse_error!("duplicate column name: {}", new);
+            }
+            new_table.columns[column_idx].name = Some(new.clone());
+            for (pk_column, _) in new_table.primary_key_columns.iter_mut() {
+                if normalize_ident(pk_column) == old {
+                    *pk_column = new.clone();
+                }
+            }
+            for column in new_table
+                .unique_sets
+                .iter_mut()
+                .flat_map(|set| set.columns.iter_mut())
+            {
+                if column.name == old {
+                    column.name = new.clone();
+                }
+            }
+            for index in indexes {
+                if !index.columns.iter().any(|column| column.name == old) {
+                    continue;
+                }
+                let mut new_index = Index::clone(index);
+                for column in new_index.columns.iter_mut() {
+                    if column.name == old {
+                        column.name = new.clone();
+                    }
+                }
+                updates.push(SchemaEntryUpdate::index(&index.name, &new_index));
+            }
+            for foreign_key in new_table.foreign_keys.iter_mut() {
+                rename_in_place(&mut foreign_key.child_columns, &old, &new);
+                if foreign_key.parent_table == btree_table.name {
+                    rename_in_place(&mut foreign_key.parent_columns, &old, &new);
+                }
+            }
+            for (child, _) in schema.get_referencing_foreign_keys(&btree_table) {
+                if child.name == btree_table.name
+                    || new_child_tables
+                        .iter()
+                        .any(|new_child: &BTreeTable| new_child.name == child.name)
+                {
+                    continue;
+                }
+                let mut new_child = BTreeTable::clone(&child);
+                let mut changed = false;
+                for foreign_key in new_child.foreign_keys.iter_mut() {
+                    if foreign_key.parent_table == btree_table.name {
+                        changed |= rename_in_place(&mut foreign_key.parent_columns, &old, &new);
+                    }
+                }
+                if changed {
+                    new_child_tables.push(new_child);
+                }
+            }
+            (view_updates, trigger_updates) = renamed_views_and_triggers(
+                schema,
+                &btree_table,
+                Rename::Column {
+                    old: &old,
+                    new: &new,
+                },
+            );
+        }
+        ast::AlterTableBody::AddColumn(col_def) => {
+            check_column_collation(&col_def, syms)?;
+            let column = Column::from_definition(&col_def);
+            let name = column.name.clone().unwrap();
+            if btree_table.get_column(&name).is_some() {
+                bail_parse_error!("duplicate column name: {}", name);
+            }
+            if column.primary_key {
+                bail_parse_error!("Cannot add a PRIMARY KEY column");
+            }
+            if col_def
+                .constraints
+                .iter()
+                .any(|c| matches!(c.constraint, ast::ColumnConstraint::Unique(_)))
+            {
+                bail_parse_error!("Cannot add a UNIQUE column");
+            }
+            if let Some(default) = &column.default {
+                if !is_constant(default) {
+                    bail_parse_error!("Cannot add a column with non-constant default");
+                }
+                // The rows of a WITHOUT ROWID table are read through the primary key index,
+                // which doesn't know the defaults of the columns missing from old rows.
+                if !btree_table.has_rowid
+                    && !matches!(default, ast::Expr::Literal(ast::Literal::Null))
+                {
+                    bail_parse_error!(
+                        "Cannot add a column with a default to a WITHOUT ROWID table yet"
+                    );
+                }
+            }
+            if column.notnull
+                && matches!(
+                    column.default,
+                    None | Some(ast::Expr::Literal(ast::Literal::Null))
+                )
+            {
+                bail_parse_error!("Cannot add a NOT NULL column with default value NULL");
+            }
+            new_table.columns.push(column);
+        }
+        ast::AlterTableBody::DropColumn(name) => {
+            let name = normalize_ident(&name.0);
+            let Some((column_idx, column)) = btree_table.get_column(&name) else {
+                bail_parse_error!("no such column: \"{}\"", name);
+            };
+            if column.primary_key {
+                bail_parse_error!("cannot drop PRIMARY KEY column: \"{}\"", name);
+            }
+            if btree_table.columns.len() == 1 {
+                bail_parse_error!("cannot drop column \"{}\": no other columns exist", name);
+            }
+            if !btree_table.has_rowid {
+                bail_parse_error!("DROP COLUMN on WITHOUT ROWID tables is not supported");
+            }
+            if let Some(index) = indexes
+                .iter()
+                .find(|index| index.columns.iter().any(|column| column.name == name))
+            {
+                bail_parse_error!(
+                    "error in index {} after drop column: no such column: {}",
+                    index.name,
+                    name
+                );
+            }
+            if btree_table
+                .foreign_keys
+                .iter()
+                .any(|foreign_key| foreign_key.child_columns.contains(&name))
+            {
+                bail_parse_error!(
+                    "error in table {} after drop column: unknown column \"{}\" in foreign key definition",
+                    btree_table.name,
+                    name
+                );
+            }
+            check_drop_column_references(schema, &btree_table, &name)?;
+            new_table.columns.remove(column_idx);
+            emit_drop_column_rewrite(&mut program, &btree_table, column_idx);
+        }
+    }
+    updates.insert(0, SchemaEntryUpdate::table(&btree_table.name, &new_table));
+    updates.extend(take_trigger_updates(
+        &mut trigger_updates,
+        &btree_table.name,
+    ));
+    emit_schema_entry_updates(&mut program, btree_table.db, &btree_table.name, &updates);
+    for new_child in &new_child_tables {
+        let mut child_updates = vec![SchemaEntryUpdate::table(&new_child.name, new_child)];
+        child_updates.extend(take_trigger_updates(&mut trigger_updates, &new_child.name));
+        emit_schema_entry_updates(&mut program, new_child.db, &new_child.name, &child_updates);
+    }
+    let mut views = Vec::new();
+    for view_update in view_updates {
+        let view_name = view_update.name.clone();
+        let mut updates = vec![view_update];
+        updates.extend(take_trigger_updates(&mut trigger_updates, &view_name));
+        emit_schema_entry_updates(&mut program, MAIN_DB, &view_name, &updates);
+        views.push(view_name);
+    }
+    // What remains are the triggers on the other tables and views.
+    let mut trigger_tables = Vec::new();
+    while let Some((tbl_name, _)) = trigger_updates.first() {
+        let tbl_name = tbl_name.clone();
+        let updates = take_trigger_updates(&mut trigger_updates, &tbl_name);
+        emit_schema_entry_updates(&mut program, MAIN_DB, &tbl_name, &updates);
+        trigger_tables.push(tbl_name);
+    }
+
+    // TODO: SetCookie
+    for (old_name, new_name) in std::iter::once((&btree_table.name, &new_table.name)).chain(
+        new_child_tables
+            .iter()
+            .map(|new_child| (&new_child.name, &new_child.name)),
+    ) {
+        program.emit_insn(Insn::DropTable {
+            db: btree_table.db,
+            table_name: old_name.clone(),
+        });
+        program.emit_insn(Insn::ParseSchema {
+            db: btree_table.db,
+            where_clause: format!("tbl_name = '{}'", new_name.replace('\'', "''")),
+        });
+    }
+    for view_name in views {
+        program.emit_insn(Insn::DropView {
+            db: MAIN_DB,
+            view_name: view_name.clone(),
+        });
+        program.emit_insn(Insn::ParseSchema {
+            db: MAIN_DB,
+            where_clause: format!("tbl_name = '{}'", view_name.replace('\'', "''")),
+        });
+    }
+    for tbl_name in &trigger_tables {
+        for trigger in schema.triggers.get(tbl_name).into_iter().flatten() {
+            program.emit_insn(Insn::DropTrigger {
+                db: MAIN_DB,
+                trigger_name: trigger.name.clone(),
+            });
+        }
+        program.emit_insn(Insn::ParseSchema {
+            db: MAIN_DB,
+            where_clause: format!(
+                "tbl_name = '{}' AND type = 'trigger'",
+                tbl_name.replace('\'', "''")
+            ),
+        });
+    }
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
+
+/// Replace the column name `old` in `columns` by `new`, returning whether it was there.
+fn rename_in_place(columns: &mut [String], old: &str, new: &str) -> bool {
+    let mut renamed = false;
+    for column in columns.iter_mut().filter(|column| *column == old) {
+        *column = new.to_string();
+        renamed = true;
+    }
+    renamed
+}
+
+/// The sqlite_schema entries of the views and of the triggers that refer to what is renamed,
+/// with their new SQL. The entries of the triggers come with the tbl_name of their entry before
+/// the change, the table or view the trigger is on.
+fn renamed_views_and_triggers(
+    schema: &Schema,
+    table: &BTreeTable,
+    rename: Rename,
+) -> (Vec<SchemaEntryUpdate>, Vec<(String, SchemaEntryUpdate)>) {
+    // Views and triggers are only in the main database.
+    if table.db != MAIN_DB {
+        return (Vec::new(), Vec::new());
+    }
+    let mut renamer = Renamer::new(schema, table, rename);
+    let mut view_updates = schema
+        .views
+        .values()
+        .filter_map(|view| {
+            let sql = renamer.rename_view(view)?;
+            Some(SchemaEntryUpdate::view(&view.name, sql))
+        })
+        .collect::<Vec<_>>();
+    view_updates.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut trigger_updates = schema
+        .triggers
+        .values()
+        .flatten()
+        .filter_map(|trigger| {
+            let sql = renamer.rename_trigger(trigger)?;
+            let new_tbl_name = match rename {
+                Rename::Table(new) if trigger.table_name == table.name => new,
+                _ => &trigger.table_name,
+            };
+            let update = SchemaEntryUpdate::trigger(&trigger.name, new_tbl_name, sql);
+            Some((trigger.table_name.clone(), update))
+        })
+        .collect::<Vec<_>>();
+    trigger_updates.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    (view_updates, trigger_updates)
+}
+
+/// Removes the entries of the triggers on `tbl_name` from `trigger_updates`.
+fn take_trigger_updates(
+    trigger_updates: &mut Vec<(String, SchemaEntryUpdate)>,
+    tbl_name: &str,
+) -> Vec<SchemaEntryUpdate> {
+    let (taken, kept) = std::mem::take(trigger_updates)
+        .into_iter()
+        .partition::<Vec<_>, _>(|(trigger_tbl_name, _)| trigger_tbl_name == tbl_name);
+    *trigger_updates = kept;
+    taken.into_iter().map(|(_, update)| update).collect()
+}
+
+/// Fails if a view or trigger refers to the column DROP COLUMN drops, like SQLite which fails
+/// to parse the view or trigger after the drop.
+fn check_drop_column_references(schema: &Schema, table: &BTreeTable, column: &str) -> Result<()> {
+    if table.db != MAIN_DB {
+        return Ok(());
+    }
+    let mut renamer = Renamer::new(
+        schema,
+        table,
+        Rename::Column {
+            old: column,
+            new: column,
+        },
+    );
+    let mut views = schema.views.values().collect::<Vec<_>>();
+    views.sort_by(|a, b| a.name.cmp(&b.name));
+    for view in views {
+        if renamer.rename_view(view).is_some() {
+            bail_parse_error!(
+                "error in view {} after drop column: no such column: {}",
+                view.name,
+                renamer.first_reference.unwrap()
+            );
+        }
+    }
+    let mut triggers = schema.triggers.values().flatten().collect::<Vec<_>>();
+    triggers.sort_by(|a, b| a.name.cmp(&b.name));
+    for trigger in triggers {
+        if renamer.rename_trigger(trigger).is_some() {
+            bail_parse_error!(
+                "error in trigger {} after drop column: no such column: {}",
+                trigger.name,
+                renamer.first_reference.unwrap()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether the expression is a constant that ADD COLUMN accepts as a default value.
+fn is_constant(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Literal(literal) => !matches!(
+            literal,
+            ast::Literal::CurrentDate | ast::Literal::CurrentTime | ast::Literal::CurrentTimestamp
+        ),
+        ast::Expr::Unary(ast::UnaryOperator::Negative | ast::UnaryOperator::Positive, expr) => {
+            matches!(expr.as_ref(), ast::Expr::Literal(ast::Literal::Numeric(_)))
+        }
+        ast::Expr::Parenthesized(exprs) => exprs.len() == 1 && is_constant(&exprs[0]),
+        _ => false,
+    }
+}
+
+/// Emit the instructions that rewrite every row of the table without the dropped column.
+fn emit_drop_column_rewrite(program: &mut ProgramBuilder, table: &BTreeTable, column_idx: usize) {
+    let table_cursor_id = program.alloc_cursor_id(
+        Some(table.name.clone()),
+        CursorType::BTreeTable(table.clone().into()),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: table_cursor_id,
+        root_page: table.root_page.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    let loop_end_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: table_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    let column_regs_start = program.alloc_registers(table.columns.len() - 1);
+    let kept_columns = table
+        .columns
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| *idx != column_idx);
+    for (target_reg, (idx, column)) in (column_regs_start..).zip(kept_columns) {
+        // A column that is an alias for the rowid is stored as NULL in the record
+        if column.is_rowid_alias {
+            program.emit_null(target_reg);
+        } else {
+            program.emit_insn(Insn::Column {
+                cursor_id: table_cursor_id,
+                column: idx,
+                dest: target_reg,
+            });
+        }
+    }
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: column_regs_start,
+        count: table.columns.len() - 1,
+        dest_reg: record_reg,
+    });
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::RowId {
+        cursor_id: table_cursor_id,
+        dest: rowid_reg,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor: table_cursor_id,
+        key_reg: rowid_reg,
+        record_reg,
+        flag: InsertFlags::default().update().skip_nchange(),
+    });
+    program.emit_insn(Insn::InsertAwait {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: table_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+}
+
+/// Emit the instructions that rewrite the schema table entries of the table and its indexes
+/// in the database `db`.
+fn emit_schema_entry_updates(
+    program: &mut ProgramBuilder,
+    db: usize,
+    table_name: &str,
+    updates: &[SchemaEntryUpdate],
+) {
+    let sqlite_schema = schema_table(db);
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(sqlite_schema.name.clone()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    let table_name_reg = program.emit_string8_new_reg(table_name.to_string());
+    program.mark_last_insn_constant();
+    let name_regs = updates
+        .iter()
+        .map(|update| {
+            let reg = program.emit_string8_new_reg(update.name.clone());
+            program.mark_last_insn_constant();
+            reg
+        })
+        .collect::<Vec<_>>();
+    let column_reg = program.alloc_register();
+    let record_start = program.alloc_registers(5);
+    let record_reg = program.alloc_register();
+    let rowid_reg = program.alloc_register();
+
+    let loop_end_label = program.allocate_label();
+    let next_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 2,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: table_name_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 1,
+        dest: column_reg,
+    });
+    for (update, name_reg) in updates.iter().zip(name_regs) {
+        let next_update_label = program.allocate_label();
+        program.emit_insn(Insn::Ne {
+            lhs: column_reg,
+            rhs: name_reg,
+            target_pc: next_update_label,
+            flags: CmpInsFlags::default(),
+            collation: None,
+        });
+        // type, name, tbl_name, rootpage, sql
+        program.emit_insn(Insn::Column {
+            cursor_id: sqlite_schema_cursor_id,
+            column: 0,
+            dest: record_start,
+        });
+        program.emit_string8(update.new_name.clone(), record_start + 1);
+        program.emit_string8(update.new_tbl_name.clone(), record_start + 2);
+        program.emit_insn(Insn::Column {
+            cursor_id: sqlite_schema_cursor_id,
+            column: 3,
+            dest: record_start + 3,
+        });
+        match &update.new_sql {
+            Some(sql) => program.emit_string8(sql.clone(), record_start + 4),
+            None => program.emit_null(record_start + 4),
+        }
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: record_start,
+            count: 5,
+            dest_reg: record_reg,
+        });
+        program.emit_insn(Insn::RowId {
+            cursor_id: sqlite_schema_cursor_id,
+            dest: rowid_reg,
+        });
+        program.emit_insn(Insn::InsertAsync {
+            cursor: sqlite_schema_cursor_id,
+            key_reg: rowid_reg,
+            record_reg,
+            flag: InsertFlags::default().update(),
+        });
+        program.emit_insn(Insn::InsertAwait {
+            cursor_id: sqlite_schema_cursor_id,
+        });
+        program.emit_insn(Insn::Goto {
+            target_pc: next_label,
+        });
+        program.resolve_label(next_update_label, program.offset());
+    }
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+}
+
+/// What ALTER TABLE renames in the views and triggers that refer to the altered table.
+#[derive(Clone, Copy)]
+enum Rename<'a> {
+    /// RENAME TO the new name.
+    Table(&'a str),
+    /// RENAME COLUMN, or DROP COLUMN which looks for the references to the column by renaming
+    /// it to itself.
+    Column { old: &'a str, new: &'a str },
+}
+
+/// A table of the FROM clause of a select, or the target of a trigger command.
+struct ScopeTable {
+    /// The name the statement refers to the table by: its alias or its name.
+    name: String,
+    /// Whether the name is an alias, which renaming the table doesn't change.
+    aliased: bool,
+    /// The b-tree table, unless it is a view, a subquery or a common table expression.
+    table: Option<Rc<BTreeTable>>,
+}
+
+/// Renames the altered table or one of its columns in the statements of views and triggers.
+/// Like SQLite, the names are resolved the way the statement would resolve them, so that only
+/// the references to the altered table are renamed.
+struct Renamer<'a> {
+    schema: &'a Schema,
+    table: &'a BTreeTable,
+    rename: Rename<'a>,
+    /// The tables in scope in the enclosing selects, the innermost last.
+    scopes: Vec<Vec<ScopeTable>>,
+    /// The common table expressions in scope, which hide the tables with the same name.
+    ctes: Vec<String>,
+    /// Whether the statement is the one of a trigger on the altered table, whose NEW and OLD
+    /// rows are rows of the table.
+    on_altered_table: bool,
+    /// The first reference to what is renamed, as written in the statement.
+    first_reference: Option<String>,
+}
+
+impl<'a> Renamer<'a> {
+    fn new(schema: &'a Schema, table: &'a BTreeTable, rename: Rename<'a>) -> Self {
+        Self {
+            schema,
+            table,
+            rename,
+            scopes: Vec::new(),
+            ctes: Vec::new(),
+            on_altered_table: false,
+            first_reference: None,
+        }
+    }
+
+    /// The CREATE VIEW statement of the view after the rename, if the view refers to what is
+    /// renamed.
+    fn rename_view(&mut self, view: &View) -> Option<String> {
+        self.first_reference = None;
+        let mut select = view.select.clone();
+        self.rename_select(&mut select);
+        self.first_reference.as_ref()?;
+        let columns = view.columns.as_ref().map(|columns| {
+            columns
+                .iter()
+                .map(|column| ast::IndexedColumn {
+                    col_name: ast::Name(quote_ident(column)),
+                    collation_name: None,
+                    order: None,
+                })
+                .collect::<Vec<_>>()
+        });
+        Some(create_view_to_str(
+            &ast::QualifiedName::single(ast::Name(quote_ident(&view.name))),
+            columns.as_deref(),
+            &select,
+        ))
+    }
+
+    /// The CREATE TRIGGER statement of the trigger after the rename, if the trigger refers to
+    /// what is renamed.
+    fn rename_trigger(&mut self, trigger: &Trigger) -> Option<String> {
+        self.first_reference = None;
+        let mut tbl_name = ast::QualifiedName::single(ast::Name(quote_ident(&trigger.table_name)));
+        self.on_altered_table = self
+            .rename_table_name(&mut tbl_name)
+            .is_some_and(|table| table.name == self.table.name && table.db == self.table.db);
+        let mut event = trigger.event.clone();
+        if let (true, ast::TriggerEvent::UpdateOf(columns)) = (self.on_altered_table, &mut event) {
+            self.rename_columns(columns);
+        }
+        let mut when_clause = trigger.when_clause.clone();
+        self.rename_opt_expr(when_clause.as_mut());
+        let mut commands = trigger.commands.clone();
+        for command in commands.iter_mut() {
+            self.rename_command(command);
+        }
+        self.on_altered_table = false;
+        self.first_reference.as_ref()?;
+        let stmt = ast::Stmt::CreateTrigger(Box::new(ast::CreateTrigger {
+            temporary: false,
+            if_not_exists: false,
+            trigger_name: ast::QualifiedName::single(ast::Name(quote_ident(&trigger.name))),
+            time: Some(trigger.time),
+            event,
+            tbl_name,
+            for_each_row: trigger.for_each_row,
+            when_clause,
+            commands,
+        }));
+        Some(StmtFormatter { stmt: &stmt }.to_string())
+    }
+
+    fn rename_command(&mut self, command: &mut ast::TriggerCmd) {
+        match command {
+            ast::TriggerCmd::Insert(insert) => {
+                let target = self.rename_target(&mut insert.tbl_name);
+                if self.is_altered(&target) {
+                    if let Some(columns) = insert.col_names.as_mut() {
+                        self.rename_columns(columns);
+                    }
+                }
+                self.rename_select(&mut insert.select);
+                if insert.upsert.is_none() {
+                    return;
+                }
+                // The excluded row of an upsert is a row of the target table.
+                let excluded = ScopeTable {
+                    name: "excluded".to_string(),
+                    aliased: true,
+                    table: target.table.clone(),
+                };
+                let is_altered = self.is_altered(&target);
+                self.scopes.push(vec![target, excluded]);
+                let mut upsert = insert.upsert.as_mut();
+                while let Some(clause) = upsert {
+                    if let Some(index) = clause.index.as_deref_mut() {
+                        self.rename_exprs(index.targets.iter_mut().map(|target| &mut target.expr));
+                        self.rename_opt_expr(index.where_clause.as_mut());
+                    }
+                    if let ast::UpsertDo::Set { sets, where_clause } = clause.do_clause.as_mut() {
+                        self.rename_sets(sets, is_altered);
+                        self.rename_opt_expr(where_clause.as_mut());
+                    }
+                    upsert = clause.next.as_deref_mut();
+                }
+                self.scopes.pop();
+            }
+            ast::TriggerCmd::Delete(delete) => {
+                let target = self.rename_target(&mut delete.tbl_name);
+                self.scopes.push(vec![target]);
+                self.rename_opt_expr(delete.where_clause.as_mut());
+                self.scopes.pop();
+            }
+            ast::TriggerCmd::Update(update) => {
+                let target = self.rename_target(&mut update.tbl_name);
+                let is_altered = self.is_altered(&target);
+                let mut scope = vec![target];
+                if let Some(from) = update.from.as_mut() {
+                    self.rename_from_tables(from, &mut scope);
+                }
+                self.scopes.push(scope);
+                if let Some(from) = update.from.as_mut() {
+                    self.rename_join_constraints(from);
+                }
+                self.rename_sets(&mut update.sets, is_altered);
+                self.rename_opt_expr(update.where_clause.as_mut());
+                self.scopes.pop();
+            }
+            ast::TriggerCmd::Select(select) => self.rename_select(select),
+        }
+    }
+
+    /// Renames the table a trigger command modifies, returning it as the table in scope in the
+    /// command.
+    fn rename_target(&mut self, tbl_name: &mut ast::Name) -> ScopeTable {
+        let mut name = ast::QualifiedName::single(tbl_name.clone());
+        let table = self.rename_table_name(&mut name);
+        *tbl_name = name.name;
+        ScopeTable {
+            name: table
+                .as_ref()
+                .map_or_else(|| normalize_ident(&tbl_name.0), |table| table.name.clone()),
+            aliased: false,
+            table,
+        }
+    }
+
+    fn rename_sets(&mut self, sets: &mut [ast::Set], is_altered: bool) {
+        for set in sets.iter_mut() {
+            if is_altered {
+                self.rename_columns(&mut set.col_names);
+            }
+            self.rename_expr(&mut set.expr);
+        }
+    }
+
+    /// Renames the renamed column in a list of columns of the altered table.
+    fn rename_columns(&mut self, columns: &mut ast::DistinctNames) {
+        let Rename::Column { old, new } = self.rename else {
+            return;
+        };
+        let Some(column) = columns
+            .iter()
+            .find(|column| normalize_ident(&column.0) == old)
+        else {
+            return;
+        };
+        self.reference(&column.0);
+        let mut renamed = columns.iter().map(|column| {
+            if normalize_ident(&column.0) == old {
+                ast::Name(quote_ident(new))
+            } else {
+                column.clone()
+            }
+        });
+        let mut new_columns = ast::DistinctNames::new(renamed.next().unwrap());
+        for column in renamed {
+            new_columns.insert(column).unwrap();
+        }
+        *columns = new_columns;
+    }
+
+    /// Whether `name`, as written in a FROM clause, is the altered table. When renaming the
+    /// table, the name is renamed.
+    fn rename_table_name(&mut self, name: &mut ast::QualifiedName) -> Option<Rc<BTreeTable>> {
+        let table_name = normalize_ident(&name.name.0);
+        if name.db_name.is_none() && self.ctes.contains(&table_name) {
+            return None;
+        }
+        let table = self.schema.get_btree_table(&table_name)?;
+        if table.name == self.table.name && table.db == self.table.db {
+            if let Rename::Table(new) = self.rename {
+                self.reference(&name.name.0);
+                name.name = ast::Name(quote_ident(new));
+            }
+        }
+        Some(table)
+    }
+
+    fn is_altered(&self, table: &ScopeTable) -> bool {
+        table
+            .table
+            .as_ref()
+            .is_some_and(|table| table.name == self.table.name && table.db == self.table.db)
+    }
+
+    fn reference(&mut self, reference: &str) {
+        if self.first_reference.is_none() {
+            self.first_reference = Some(reference.to_string());
+        }
+    }
+
+    /// The table of the innermost scope that `name` refers to.
+    fn resolve_table(&self, name: &str) -> Option<&ScopeTable> {
+        let name = normalize_ident(name);
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().find(|table| table.name == name))
+    }
+
+    /// Whether the unqualified column `name` is a column of the altered table: the first
+    /// scope with a table that has the column must have the altered table among them.
+    fn resolves_to_altered_table(&self, name: &str) -> bool {
+        for scope in self.scopes.iter().rev() {
+            let mut tables = scope.iter().filter(|table| {
+                table
+                    .table
+                    .as_ref()
+                    .is_some_and(|table| table.get_column(name).is_some())
+            });
+            if let Some(table) = tables.next() {
+                return self.is_altered(table) || tables.any(|table| self.is_altered(table));
+            }
+        }
+        false
+    }
+
+    fn rename_qualified(&mut self, qualifier: &mut ast::Name, column: &mut ast::Name) {
+        let Some(table) = self.resolve_table(&qualifier.0) else {
+            let row = normalize_ident(&qualifier.0);
+            if let (true, "new" | "old", Rename::Column { old, new }) =
+                (self.on_altered_table, row.as_str(), self.rename)
+            {
+                if normalize_ident(&column.0) == old {
+                    self.reference(&format!("{}.{}", qualifier.0, column.0));
+                    *column = ast::Name(quote_ident(new));
+                }
+            }
+            return;
+        };
+        if !self.is_altered(table) {
+            return;
+        }
+        match self.rename {
+            Rename::Table(new) if !table.aliased => {
+                self.reference(&format!("{}.{}", qualifier.0, column.0));
+                *qualifier = ast::Name(quote_ident(new));
+            }
+            Rename::Table(_) => {}
+            Rename::Column { old, new } => {
+                if normalize_ident(&column.0) == old {
+                    self.reference(&format!("{}.{}", qualifier.0, column.0));
+                    *column = ast::Name(quote_ident(new));
+                }
+            }
+        }
+    }
+
+    fn rename_opt_expr(&mut self, expr: Option<&mut ast::Expr>) {
+        if let Some(expr) = expr {
+            self.rename_expr(expr);
+        }
+    }
+
+    fn rename_exprs<'e>(&mut self, exprs: impl IntoIterator<Item = &'e mut ast::Expr>) {
+        for expr in exprs {
+            self.rename_expr(expr);
+        }
+    }
+
+    fn rename_expr(&mut self, expr: &mut ast::Expr) {
+        match expr {
+            ast::Expr::Id(column) => {
+                if let Rename::Column { old, new } = self.rename {
+                    if normalize_ident(&column.0) == old && self.resolves_to_altered_table(old) {
+                        self.reference(&column.0);
+                        column.0 = quote_ident(new);
+                    }
+                }
+            }
+            ast::Expr::Qualified(qualifier, column)
+            | ast::Expr::DoublyQualified(_, qualifier, column) => {
+                self.rename_qualified(qualifier, column)
+            }
+            ast::Expr::Between {
+                lhs, start, end, ..
+            } => {
+                self.rename_expr(lhs);
+                self.rename_expr(start);
+                self.rename_expr(end);
+            }
+            ast::Expr::Binary(lhs, _, rhs) => {
+                self.rename_expr(lhs);
+                self.rename_expr(rhs);
+            }
+            ast::Expr::Case {
+                base,
+                when_then_pairs,
+                else_expr,
+            } => {
+                self.rename_opt_expr(base.as_deref_mut());
+                for (when, then) in when_then_pairs.iter_mut() {
+                    self.rename_expr(when);
+                    self.rename_expr(then);
+                }
+                self.rename_opt_expr(else_expr.as_deref_mut());
+            }
+            ast::Expr::Cast { expr, .. }
+            | ast::Expr::Collate(expr, _)
+            | ast::Expr::IsNull(expr)
+            | ast::Expr::NotNull(expr)
+            | ast::Expr::Unary(_, expr) => self.rename_expr(expr),
+            ast::Expr::Exists(select) | ast::Expr::Subquery(select) => self.rename_select(select),
+            ast::Expr::FunctionCall {
+                args,
+                order_by,
+                filter_over,
+                ..
+            } => {
+                self.rename_exprs(args.iter_mut().flatten());
+                self.rename_exprs(order_by.iter_mut().flatten().map(|o| &mut o.expr));
+                self.rename_function_tail(filter_over.as_mut());
+            }
+            ast::Expr::FunctionCallStar { filter_over, .. } => {
+                self.rename_function_tail(filter_over.as_mut())
+            }
+            ast::Expr::SubqueryResult {
+                lhs, outer_refs, ..
+            } => {
+                self.rename_opt_expr(lhs.as_deref_mut());
+                self.rename_exprs(outer_refs.iter_mut());
+            }
+            ast::Expr::InList { lhs, rhs, .. } => {
+                self.rename_expr(lhs);
+                self.rename_exprs(rhs.iter_mut().flatten());
+            }
+            ast::Expr::InSelect { lhs, rhs, .. } => {
+                self.rename_expr(lhs);
+                self.rename_select(rhs);
+            }
+            ast::Expr::InTable { lhs, rhs, args, .. } => {
+                self.rename_expr(lhs);
+                self.rename_table_name(rhs);
+                self.rename_exprs(args.iter_mut().flatten());
+            }
+            ast::Expr::Like {
+                lhs, rhs, escape, ..
+            } => {
+                self.rename_expr(lhs);
+                self.rename_expr(rhs);
+                self.rename_opt_expr(escape.as_deref_mut());
+            }
+            ast::Expr::Parenthesized(exprs) => self.rename_exprs(exprs.iter_mut()),
+            ast::Expr::Raise(_, message) => self.rename_opt_expr(message.as_deref_mut()),
+            ast::Expr::Column { .. }
+            | ast::Expr::Literal(_)
+            | ast::Expr::Name(_)
+            | ast::Expr::OuterRef(_)
+            | ast::Expr::Param(_)
+            | ast::Expr::Register(_)
+            | ast::Expr::RowId { .. }
+            | ast::Expr::Variable(_) => {}
+        }
+    }
+
+    fn rename_function_tail(&mut self, tail: Option<&mut ast::FunctionTail>) {
+        let Some(tail) = tail else {
+            return;
+        };
+        self.rename_opt_expr(tail.filter_clause.as_deref_mut());
+        if let Some(ast::Over::Window(window)) = tail.over_clause.as_deref_mut() {
+            self.rename_window(window);
+        }
+    }
+
+    fn rename_window(&mut self, window: &mut ast::Window) {
+        self.rename_exprs(window.partition_by.iter_mut().flatten());
+        self.rename_exprs(window.order_by.iter_mut().flatten().map(|o| &mut o.expr));
+    }
+
+    fn rename_select(&mut self, select: &mut ast::Select) {
+        let ctes_len = self.ctes.len();
+        if let Some(with) = select.with.as_mut() {
+            for cte in with.ctes.iter_mut() {
+                self.ctes.push(normalize_ident(&cte.tbl_name.0));
+                self.rename_select(&mut cte.select);
+            }
+        }
+        // Without compound selects, ORDER BY can refer to the tables of the select.
+        if select.body.compounds.is_none() {
+            let scope = self.rename_one_select(&mut select.body.select);
+            self.scopes.push(scope);
+            self.rename_exprs(select.order_by.iter_mut().flatten().map(|o| &mut o.expr));
+            self.scopes.pop();
+        } else {
+            self.rename_one_select(&mut select.body.select);
+            for compound in select.body.compounds.iter_mut().flatten() {
+                self.rename_one_select(&mut compound.select);
+            }
+            self.rename_exprs(select.order_by.iter_mut().flatten().map(|o| &mut o.expr));
+        }
+        if let Some(limit) = select.limit.as_mut() {
+            self.rename_expr(&mut limit.expr);
+            self.rename_opt_expr(limit.offset.as_mut());
+        }
+        self.ctes.truncate(ctes_len);
+    }
+
+    /// Renames in the select, returning the tables of its FROM clause.
+    fn rename_one_select(&mut self, select: &mut ast::OneSelect) -> Vec<ScopeTable> {
+        let ast::OneSelect::Select(select) = select else {
+            if let ast::OneSelect::Values(rows) = select {
+                self.rename_exprs(rows.iter_mut().flatten());
+            }
+            return Vec::new();
+        };
+        let mut scope = Vec::new();
+        if let Some(from) = select.from.as_mut() {
+            self.rename_from_tables(from, &mut scope);
+        }
+        self.scopes.push(scope);
+        if let Some(from) = select.from.as_mut() {
+            self.rename_join_constraints(from);
+        }
+        for column in select.columns.iter_mut() {
+            match column {
+                ast::ResultColumn::Expr(expr, _) => self.rename_expr(expr),
+                ast::ResultColumn::TableStar(qualifier) => {
+                    let is_altered_name = self
+                        .resolve_table(&qualifier.0)
+                        .is_some_and(|table| self.is_altered(table) && !table.aliased);
+                    if let (true, Rename::Table(new)) = (is_altered_name, self.rename) {
+                        self.reference(&qualifier.0);
+                        *qualifier = ast::Name(quote_ident(new));
+                    }
+                }
+                ast::ResultColumn::Star => {}
+            }
+        }
+        self.rename_opt_expr(select.where_clause.as_mut());
+        if let Some(group_by) = select.group_by.as_mut() {
+            self.rename_exprs(group_by.exprs.iter_mut());
+            self.rename_opt_expr(group_by.having.as_deref_mut());
+        }
+        for window_def in select.window_clause.iter_mut().flatten() {
+            self.rename_window(&mut window_def.window);
+        }
+        self.scopes.pop().unwrap()
+    }
+
+    /// Renames the tables of the FROM clause and the subqueries in it, adding the tables to
+    /// `scope`.
+    fn rename_from_tables(&mut self, from: &mut ast::FromClause, scope: &mut Vec<ScopeTable>) {
+        let tables = from
+            .select
+            .as_deref_mut()
+            .into_iter()
+            .chain(from.joins.iter_mut().flatten().map(|join| &mut join.table));
+        for select_table in tables {
+            let (name, alias, table) = match select_table {
+                ast::SelectTable::Table(name, alias, _) => {
+                    let table = self.rename_table_name(name);
+                    // The statement still refers to a renamed table by its old name.
+                    let name = table
+                        .as_ref()
+                        .map_or_else(|| normalize_ident(&name.name.0), |t| t.name.clone());
+                    (Some(name), alias, table)
+                }
+                ast::SelectTable::TableCall(name, args, alias) => {
+                    self.rename_exprs(args.iter_mut().flatten());
+                    (Some(normalize_ident(&name.name.0)), alias, None)
+                }
+                ast::SelectTable::Select(select, alias) => {
+                    self.rename_select(select);
+                    (None, alias, None)
+                }
+                ast::SelectTable::Sub(from, alias) => {
+                    self.rename_from_tables(from, scope);
+                    (None, alias, None)
+                }
+            };
+            let (name, aliased) = match (alias, name) {
+                (Some(ast::As::As(alias) | ast::As::Elided(alias)), _) => {
+                    (normalize_ident(&alias.0), true)
+                }
+                (None, Some(name)) => (name, false),
+                (None, None) => continue,
+            };
+            scope.push(ScopeTable {
+                name,
+                aliased,
+                table,
+            });
+        }
+    }
+
+    fn rename_join_constraints(&mut self, from: &mut ast::FromClause) {
+        if let Some(ast::SelectTable::Sub(from, _)) = from.select.as_deref_mut() {
+            self.rename_join_constraints(from);
+        }
+        for join in from.joins.iter_mut().flatten() {
+            if let ast::SelectTable::Sub(from, _) = &mut join.table {
+                self.rename_join_constraints(from);
+            }
+            if let Some(ast::JoinConstraint::On(expr)) = join.constraint.as_mut() {
+                self.rename_expr(expr);
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::schema::{Schema, TEMP_DB};
+use crate::translate::{ProgramBuilder, ProgramBuilderOpts};
+use crate::vdbe::insn::Insn;
+use crate::{bail_parse_error, QueryMode, Result};
+use limbo_sqlite3_parser::ast;
+
+/// ANALYZE gathers the statistics of every table of every database, ANALYZE with the name of a
+/// database the ones of its tables, and ANALYZE with the name of a table or an index the ones of
+/// that table.
+pub fn translate_analyze(
+    schema: &Schema,
+    name: Option<&ast::QualifiedName>,
+) -> Result<ProgramBuilder> {
+    let targets: Vec<(usize, Option<String>)> = match name {
+        None => schema.databases().map(|schema| (schema.db, None)).collect(),
+        Some(ast::QualifiedName {
+            db_name: None,
+            name: ast::Name(db_name),
+            ..
+        }) if schema.database_index(db_name).is_some() => {
+            let db = schema.database_index(db_name).unwrap();
+            // The temp database has no tables until it is first used
+            if db == TEMP_DB && schema.temp.is_none() {
+                vec![]
+            } else {
+                vec![(db, None)]
+            }
+        }
+        Some(name) => {
+            if let Some(table) = schema.resolve_table(name)? {
+                match table.btree() {
+                    Some(table) => vec![(table.db, Some(table.name.clone()))],
+                    None => vec![],
+                }
+            } else if let Some(index) = schema.resolve_index(name)? {
+                vec![(index.db, Some(index.table_name.clone()))]
+            } else {
+                bail_parse_error!("no such table: {}", name.name.0);
+            }
+        }
+    };
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 2 + targets.len(),
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    for (db, table_name) in targets {
+        program.emit_insn(Insn::Analyze { db, table_name });
+    }
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
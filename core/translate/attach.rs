@@ -0,0 +1,76 @@
+use crate::translate::emitter::Resolver;
+use crate::translate::expr::translate_expr;
+use crate::translate::{ProgramBuilder, ProgramBuilderOpts};
+use crate::vdbe::insn::Insn;
+use crate::{bail_parse_error, QueryMode, Result, SymbolTable};
+use limbo_sqlite3_parser::ast;
+
+pub fn translate_attach(
+    expr: &ast::Expr,
+    db_name: &ast::Expr,
+    key: Option<&ast::Expr>,
+    syms: &SymbolTable,
+) -> Result<ProgramBuilder> {
+    if key.is_some() {
+        bail_parse_error!("ATTACH with KEY not supported");
+    }
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 4,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    let resolver = Resolver::new(syms);
+    let path_reg = program.alloc_register();
+    translate_attach_operand(&mut program, expr, path_reg, &resolver)?;
+    let name_reg = program.alloc_register();
+    translate_attach_operand(&mut program, db_name, name_reg, &resolver)?;
+    program.emit_insn(Insn::Attach { path_reg, name_reg });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
+
+pub fn translate_detach(db_name: &ast::Expr, syms: &SymbolTable) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 3,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    let resolver = Resolver::new(syms);
+    let name_reg = program.alloc_register();
+    translate_attach_operand(&mut program, db_name, name_reg, &resolver)?;
+    program.emit_insn(Insn::Detach { name_reg });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
+
+/// The file name and schema name of ATTACH and DETACH are expressions, except that a bare
+/// identifier stands for its own name, as in `ATTACH 'data.db' AS data`.
+fn translate_attach_operand(
+    program: &mut ProgramBuilder,
+    expr: &ast::Expr,
+    target_register: usize,
+    resolver: &Resolver,
+) -> Result<()> {
+    match expr {
+        ast::Expr::Id(ast::Id(name)) | ast::Expr::Name(ast::Name(name)) => {
+            program.emit_insn(Insn::String8 {
+                value: name.clone(),
+                dest: target_register,
+            });
+        }
+        expr => {
+            translate_expr(program, None, expr, target_register, resolver)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast;
+
+use crate::translate::plan::TableReference;
+use crate::types::OwnedValue;
+use crate::util::normalize_ident;
+use crate::{LimboError, Result, SymbolTable};
+
+/// The function of a collation registered with `Database::create_collation`.
+pub type CollationFn = Rc<dyn Fn(&str, &str) -> Ordering>;
+
+/// A collation registered with `Database::create_collation`, as named by a COLLATE clause.
+#[derive(Clone)]
+pub struct CustomCollation {
+    name: Rc<str>,
+    /// The function of the collation, None until the program that uses the collation is built.
+    compare: Option<CollationFn>,
+}
+
+impl PartialEq for CustomCollation {
+    fn eq(&self, other: &Self) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+    }
+}
+
+impl Eq for CustomCollation {}
+
+impl std::fmt::Debug for CustomCollation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("CustomCollation").field(&self.name).finish()
+    }
+}
+
+/// A collating sequence, which defines how two strings compare.
+/// https://www.sqlite.org/datatype3.html#collation
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum CollationSeq {
+    /// Compares strings byte by byte.
+    #[default]
+    Binary,
+    /// Like BINARY, except that the 26 upper case ASCII letters are folded to lower case.
+    NoCase,
+    /// Like BINARY, except that trailing spaces are ignored.
+    Rtrim,
+    /// A collation registered with `Database::create_collation`. The schema can name a collation
+    /// that is registered after the schema is loaded, so its function is looked up in the symbol
+    /// table of the database when a program that uses it is built, see [CollationSeq::resolve].
+    Custom(CustomCollation),
+}
+
+impl CollationSeq {
+    /// The built-in collations, in the order in which PRAGMA collation_list lists them.
+    pub const BUILTIN: [CollationSeq; 3] = [
+        CollationSeq::Rtrim,
+        CollationSeq::NoCase,
+        CollationSeq::Binary,
+    ];
+
+    /// The collation with the given name, which must be a built-in or registered one.
+    pub(crate) fn new(name: &str, syms: &SymbolTable) -> Result<Self> {
+        let mut collation = Self::named(name);
+        collation.resolve(syms)?;
+        Ok(collation)
+    }
+
+    /// The collation with the given name, without checking that it exists: the function of a
+    /// registered collation is only looked up by [CollationSeq::resolve].
+    pub fn named(name: &str) -> Self {
+        Self::builtin(name).unwrap_or_else(|| {
+            Self::Custom(CustomCollation {
+                name: normalize_ident(name).into(),
+                compare: None,
+            })
+        })
+    }
+
+    pub(crate) fn builtin(name: &str) -> Option<Self> {
+        match normalize_ident(name).as_str() {
+            "binary" => Some(Self::Binary),
+            "nocase" => Some(Self::NoCase),
+            "rtrim" => Some(Self::Rtrim),
+            _ => None,
+        }
+    }
+
+    /// Looks up the function of a registered collation in the symbol table.
+    pub(crate) fn resolve(&mut self, syms: &SymbolTable) -> Result<()> {
+        if let Self::Custom(collation) = self {
+            match syms.resolve_collation(&collation.name) {
+                Some(compare) => collation.compare = Some(compare),
+                None => {
+                    return Err(LimboError::ParseError(format!(
+                        "no such collation sequence: {}",
+                        collation.name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The built-in collations and then the registered ones, in the order in which
+    /// PRAGMA collation_list lists them.
+    pub(crate) fn list(syms: &SymbolTable) -> Vec<CollationSeq> {
+        let mut list = Self::BUILTIN.to_vec();
+        list.extend(syms.collations.iter().map(|(name, compare)| {
+            Self::Custom(CustomCollation {
+                name: name.clone(),
+                compare: Some(compare.clone()),
+            })
+        }));
+        list
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Binary => "BINARY",
+            Self::NoCase => "NOCASE",
+            Self::Rtrim => "RTRIM",
+            Self::Custom(collation) => &collation.name,
+        }
+    }
+
+    pub fn compare_strings(&self, lhs: &str, rhs: &str) -> Ordering {
+        match self {
+            Self::Binary => lhs.cmp(rhs),
+            Self::NoCase => {
+                let lhs = lhs.bytes().map(|b| b.to_ascii_lowercase());
+                let rhs = rhs.bytes().map(|b| b.to_ascii_lowercase());
+                lhs.cmp(rhs)
+            }
+            Self::Rtrim => lhs.trim_end_matches(' ').cmp(rhs.trim_end_matches(' ')),
+            Self::Custom(collation) => {
+                let compare = collation
+                    .compare
+                    .as_ref()
+                    .expect("the collations of a program are resolved when it is built");
+                compare(lhs, rhs)
+            }
+        }
+    }
+
+    /// Compares two values. The collation only matters when both are text, any other pair of
+    /// values compares the usual way.
+    pub fn compare(&self, lhs: &OwnedValue, rhs: &OwnedValue) -> Ordering {
+        match (lhs, rhs) {
+            (OwnedValue::Text(lhs), OwnedValue::Text(rhs)) => {
+                self.compare_strings(lhs.as_str(), rhs.as_str())
+            }
+            _ => lhs.cmp(rhs),
+        }
+    }
+}
+
+/// Checks that the collation of a column definition exists. The schema can use a collation that
+/// is not registered yet, but a new column can't.
+pub fn check_column_collation(col_def: &ast::ColumnDefinition, syms: &SymbolTable) -> Result<()> {
+    for constraint in &col_def.constraints {
+        if let ast::ColumnConstraint::Collate { collation_name } = &constraint.constraint {
+            CollationSeq::new(&collation_name.0, syms)?;
+        }
+    }
+    Ok(())
+}
+
+/// The collation of a COLLATE operator in an expression. It applies to the operators the
+/// expression is an operand of too, the one of the left-hand operand first, e.g. both
+/// `x COLLATE NOCASE || y` and `x || y COLLATE NOCASE` compare with NOCASE.
+pub fn explicit_collation(expr: &ast::Expr) -> Option<CollationSeq> {
+    match expr {
+        // The name is checked when the expression is translated
+        ast::Expr::Collate(_, name) => Some(CollationSeq::named(name)),
+        ast::Expr::Binary(lhs, _, rhs) => {
+            explicit_collation(lhs).or_else(|| explicit_collation(rhs))
+        }
+        ast::Expr::Unary(_, expr) | ast::Expr::Cast { expr, .. } => explicit_collation(expr),
+        ast::Expr::Parenthesized(exprs)
+        | ast::Expr::FunctionCall {
+            args: Some(exprs), ..
+        } => exprs.iter().find_map(explicit_collation),
+        _ => None,
+    }
+}
+
+/// The collation of the column an expression is, if it is one. A column without a COLLATE
+/// clause has the BINARY collation, which takes precedence over the one of the other operand.
+fn column_collation(expr: &ast::Expr, tables: &[TableReference]) -> Option<CollationSeq> {
+    match expr {
+        ast::Expr::Column { table, column, .. } => Some(
+            tables
+                .get(*table)?
+                .columns()
+                .get(*column)?
+                .collation
+                .clone()
+                .unwrap_or_default(),
+        ),
+        ast::Expr::Unary(ast::UnaryOperator::Positive, expr) | ast::Expr::Cast { expr, .. } => {
+            column_collation(expr, tables)
+        }
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => column_collation(&exprs[0], tables),
+        _ => None,
+    }
+}
+
+/// The collation of an expression, e.g. the one ORDER BY sorts a term with: the one of a COLLATE
+/// operator, or else the one of the column the expression is.
+pub fn expr_collation(expr: &ast::Expr, tables: &[TableReference]) -> Option<CollationSeq> {
+    explicit_collation(expr).or_else(|| column_collation(expr, tables))
+}
+
+/// The collation that two expressions compare with. A COLLATE operator takes precedence over
+/// the collation of a column, and the left-hand side over the right-hand side.
+/// https://www.sqlite.org/datatype3.html#assigning_collating_sequences_from_sql
+pub fn comparison_collation(
+    lhs: &ast::Expr,
+    rhs: &ast::Expr,
+    tables: &[TableReference],
+) -> Option<CollationSeq> {
+    explicit_collation(lhs)
+        .or_else(|| explicit_collation(rhs))
+        .or_else(|| column_collation(lhs, tables))
+        .or_else(|| column_collation(rhs, tables))
+}
+
+impl std::fmt::Display for CollationSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collation_names() {
+        let syms = SymbolTable::new();
+        assert_eq!(
+            CollationSeq::new("NoCase", &syms).unwrap(),
+            CollationSeq::NoCase
+        );
+        assert_eq!(
+            CollationSeq::new("\"rtrim\"", &syms).unwrap(),
+            CollationSeq::Rtrim
+        );
+        assert!(CollationSeq::new("french", &syms).is_err());
+    }
+
+    #[test]
+    fn test_compare_strings() {
+        use Ordering::*;
+        assert_eq!(CollationSeq::Binary.compare_strings("a", "A"), Greater);
+        assert_eq!(CollationSeq::NoCase.compare_strings("abc", "ABC"), Equal);
+        assert_eq!(CollationSeq::NoCase.compare_strings("ab", "ABC"), Less);
+        // Only ASCII letters are folded
+        assert_eq!(CollationSeq::NoCase.compare_strings("é", "É"), Greater);
+        assert_eq!(CollationSeq::Rtrim.compare_strings("a  ", "a"), Equal);
+        assert_eq!(CollationSeq::Rtrim.compare_strings(" a", "a"), Less);
+    }
+}
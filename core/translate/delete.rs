@@ -2,22 +2,48 @@ use crate::schema::Table;
 use crate::translate::emitter::emit_program;
 use crate::translate::optimizer::optimize_plan;
 use crate::translate::plan::{DeletePlan, Operation, Plan};
-use crate::translate::planner::{parse_limit, parse_where};
+use crate::translate::planner::{
+    parse_limit, parse_returning, parse_where, plan_subqueries_in_expr, Scope,
+};
+use crate::translate::trigger::emit_view_delete;
 use crate::vdbe::builder::{ProgramBuilder, ProgramBuilderOpts, QueryMode};
 use crate::{schema::Schema, Result, SymbolTable};
-use limbo_sqlite3_parser::ast::{Expr, Limit, QualifiedName};
+use limbo_sqlite3_parser::ast::{
+    Expr, Limit, QualifiedName, ResultColumn, TriggerEvent, TriggerTime,
+};
 
 use super::plan::TableReference;
 
+#[allow(clippy::too_many_arguments)]
 pub fn translate_delete(
     query_mode: QueryMode,
     schema: &Schema,
     tbl_name: &QualifiedName,
     where_clause: Option<Box<Expr>>,
     limit: Option<Box<Limit>>,
+    returning: Option<Vec<ResultColumn>>,
     syms: &SymbolTable,
+    foreign_keys: bool,
 ) -> Result<ProgramBuilder> {
-    let mut delete_plan = prepare_delete_plan(schema, tbl_name, where_clause, limit)?;
+    if let Some(view) = schema.get_view(&tbl_name.name.0) {
+        let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+            query_mode,
+            num_cursors: 1,
+            approx_num_insns: 20,
+            approx_num_labels: 4,
+        });
+        program.foreign_keys = foreign_keys;
+        if limit.is_some() {
+            crate::bail_parse_error!("LIMIT is not supported in a DELETE from a view");
+        }
+        if returning.is_some() {
+            crate::bail_parse_error!("RETURNING is not supported in a DELETE from a view");
+        }
+        emit_view_delete(&mut program, schema, syms, &view, where_clause)?;
+        return Ok(program);
+    }
+    let mut delete_plan =
+        prepare_delete_plan(schema, tbl_name, where_clause, limit, returning, syms)?;
     optimize_plan(&mut delete_plan, schema)?;
     let Plan::Delete(ref delete) = delete_plan else {
         panic!("delete_plan is not a DeletePlan");
@@ -28,7 +54,8 @@ pub fn translate_delete(
         approx_num_insns: estimate_num_instructions(delete),
         approx_num_labels: 0,
     });
-    emit_program(&mut program, delete_plan, syms)?;
+    program.foreign_keys = foreign_keys;
+    emit_program(&mut program, delete_plan, schema, syms)?;
     Ok(program)
 }
 
@@ -37,47 +64,75 @@ pub fn prepare_delete_plan(
     tbl_name: &QualifiedName,
     where_clause: Option<Box<Expr>>,
     limit: Option<Box<Limit>>,
+    returning: Option<Vec<ResultColumn>>,
+    syms: &SymbolTable,
 ) -> Result<Plan> {
-    let table = match schema.get_table(tbl_name.name.0.as_str()) {
+    let table = match schema.resolve_table(tbl_name)? {
         Some(table) => table,
+        None if schema.get_view(tbl_name.name.0.as_str()).is_some() => {
+            crate::bail_parse_error!("cannot modify {} because it is a view", tbl_name)
+        }
         None => crate::bail_corrupt_error!("Parse error: no such table: {}", tbl_name),
     };
-    let table = if let Some(table) = table.virtual_table() {
-        Table::Virtual(table.clone())
+    let (table, indexes, triggers) = if let Some(table) = table.virtual_table() {
+        (Table::Virtual(table.clone()), vec![], vec![])
     } else if let Some(table) = table.btree() {
-        Table::BTree(table.clone())
+        let triggers = [TriggerTime::Before, TriggerTime::After]
+            .into_iter()
+            .flat_map(|time| schema.get_table_triggers(&table, time, TriggerEvent::Delete, &[]))
+            .collect();
+        (
+            Table::BTree(table.clone()),
+            schema.get_table_indices(&table).to_vec(),
+            triggers,
+        )
     } else {
         crate::bail_corrupt_error!("Table is neither a virtual table nor a btree table");
     };
     let name = tbl_name.name.0.as_str().to_string();
     let table_references = vec![TableReference {
+        database: TableReference::database_name(schema, &table),
         table,
         identifier: name,
         op: Operation::Scan { iter_dir: None },
         join_info: None,
     }];
 
+    // Subqueries in the WHERE clause can refer to the table being deleted from.
+    let subquery_scope = Scope::for_expr_subqueries(table_references.clone(), vec![], None);
+    let mut subqueries = vec![];
     let mut where_predicates = vec![];
 
     // Parse the WHERE clause
-    parse_where(
-        where_clause.map(|e| *e),
-        &table_references,
-        None,
-        &mut where_predicates,
-    )?;
+    let mut where_clause = where_clause.map(|e| *e);
+    if let Some(where_expr) = where_clause.as_mut() {
+        plan_subqueries_in_expr(
+            where_expr,
+            schema,
+            syms,
+            &subquery_scope,
+            None,
+            &mut subqueries,
+        )?;
+    }
+    parse_where(where_clause, &table_references, None, &mut where_predicates)?;
+
+    let result_columns = parse_returning(returning, &table_references)?;
 
     // Parse the LIMIT/OFFSET clause
     let (resolved_limit, resolved_offset) = limit.map_or(Ok((None, None)), |l| parse_limit(*l))?;
 
     let plan = DeletePlan {
         table_references,
-        result_columns: vec![],
+        result_columns,
         where_clause: where_predicates,
         order_by: None,
         limit: resolved_limit,
         offset: resolved_offset,
         contains_constant_false_condition: false,
+        indexes,
+        triggers,
+        subqueries,
     };
 
     Ok(Plan::Delete(plan))
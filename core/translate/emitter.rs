@@ -3,26 +3,53 @@
 
 use limbo_sqlite3_parser::ast::{self};
 
+use std::rc::Rc;
+
+use crate::error::SQLITE_CONSTRAINT_PRIMARYKEY;
 use crate::function::Func;
-use crate::translate::plan::{DeletePlan, Plan, Search};
+use crate::schema::{Column, PseudoTable, Schema};
+use crate::translate::index::{
+    emit_index_delete, emit_index_insert, emit_index_key, emit_open_index_cursors,
+    emit_unique_check, IndexKeySource,
+};
+use crate::translate::plan::{DeletePlan, Plan, ResultSetColumn, TableReference, UpdatePlan};
+use crate::types::{OwnedValue, Record};
 use crate::util::exprs_are_equivalent;
-use crate::vdbe::builder::ProgramBuilder;
-use crate::vdbe::{insn::Insn, BranchOffset};
+use crate::vdbe::builder::{CursorType, ProgramBuilder};
+use crate::vdbe::insn::{CmpInsFlags, InsertFlags, OnError};
+use crate::vdbe::{insn::Insn, BranchOffset, CursorID};
 use crate::{Result, SymbolTable};
 
 use super::aggregation::emit_ungrouped_aggregation;
-use super::expr::{translate_condition_expr, ConditionMetadata};
+use super::collate::expr_collation;
+use super::expr::{translate_condition_expr, translate_expr, ConditionMetadata};
+use super::foreign_key::{emit_fk_actions, emit_fk_checks, has_foreign_keys};
 use super::group_by::{emit_group_by, init_group_by, GroupByMetadata};
-use super::main_loop::{close_loop, emit_loop, init_loop, open_loop, LeftJoinMetadata, LoopLabels};
+use super::main_loop::{
+    close_loop, emit_loop, emit_unmatched_right_join_rows, init_loop, open_loop, LeftJoinMetadata,
+    LoopLabels, RightJoinMetadata,
+};
 use super::order_by::{emit_order_by, init_order_by, SortMetadata};
 use super::plan::Operation;
-use super::plan::{SelectPlan, TableReference};
-use super::subquery::emit_subqueries;
+use super::plan::{SelectPlan, SubqueryType};
+use super::subquery::{emit_expr_subqueries, emit_subqueries};
+use super::trigger::{
+    alloc_trigger_params, emit_cursor_row_params, emit_fire_triggers, emit_null_row_params,
+    emit_row_params, new_row_params, trigger_column_names,
+};
+use super::window::{emit_window, init_window, WindowMetadata};
 
 #[derive(Debug)]
 pub struct Resolver<'a> {
     pub symbol_table: &'a SymbolTable,
     pub expr_to_reg_cache: Vec<(&'a ast::Expr, usize)>,
+    /// The coroutines of the subqueries used in the expressions of the query, indexed by subquery_id
+    pub subqueries: Vec<SubqueryCoroutine>,
+    /// In a correlated subquery, the first register of the values it takes from its enclosing query
+    pub outer_refs_start_reg: Option<usize>,
+    /// When firing a trigger, the first register of the OLD and NEW rows passed to its program.
+    /// The WHEN clause of the trigger reads them from there instead of with the Param instruction.
+    pub params_start_reg: Option<usize>,
 }
 
 impl<'a> Resolver<'a> {
@@ -30,6 +57,9 @@ impl<'a> Resolver<'a> {
         Self {
             symbol_table,
             expr_to_reg_cache: Vec::new(),
+            subqueries: Vec::new(),
+            outer_refs_start_reg: None,
+            params_start_reg: None,
         }
     }
 
@@ -51,6 +81,22 @@ impl<'a> Resolver<'a> {
     }
 }
 
+/// The emitted coroutine of a subquery used in an expression.
+/// translate_expr() runs the coroutine from the start every time the expression is evaluated,
+/// unless the subquery is not correlated and its result is kept in `reg_once`.
+#[derive(Debug, Clone, Copy)]
+pub struct SubqueryCoroutine {
+    pub subquery_type: SubqueryType,
+    pub yield_reg: usize,
+    pub coroutine_implementation_start: BranchOffset,
+    pub result_columns_start_reg: usize,
+    /// The registers the subquery reads its values of the enclosing query from, if it is correlated
+    pub outer_refs_start_reg: Option<usize>,
+    /// For a scalar or EXISTS subquery that is not correlated, the register that is set once the
+    /// subquery has run, followed by the register that keeps its result.
+    pub reg_once: Option<usize>,
+}
+
 /// The TranslateCtx struct holds various information and labels used during bytecode generation.
 /// It is used for maintaining state and control flow during the bytecode
 /// generation process.
@@ -75,9 +121,16 @@ pub struct TranslateCtx<'a> {
     pub meta_group_by: Option<GroupByMetadata>,
     // metadata for the order by operator
     pub meta_sort: Option<SortMetadata>,
+    // metadata for the window functions, one for each window
+    pub meta_window: Vec<WindowMetadata>,
+    // cursor of the ephemeral table that holds the rows a SELECT DISTINCT has emitted so far
+    pub distinct_cursor: Option<CursorID>,
     /// mapping between table loop index and associated metadata (for left joins only)
     /// this metadata exists for the right table in a given left join
     pub meta_left_joins: Vec<Option<LeftJoinMetadata>>,
+    /// mapping between table loop index and associated metadata (for right and full outer joins only)
+    /// this metadata exists for the right table in a given right join
+    pub meta_right_joins: Vec<Option<RightJoinMetadata>>,
     // We need to emit result columns in the order they are present in the SELECT, but they may not be in the same order in the ORDER BY sorter.
     // This vector holds the indexes of the result columns in the ORDER BY sorter.
     pub result_column_indexes_in_orderby_sorter: Vec<usize>,
@@ -97,8 +150,15 @@ pub enum OperationMode {
     DELETE,
 }
 
+/// The kind of transaction a program needs in order to run
+#[derive(Debug, Clone, Copy)]
+pub enum TransactionMode {
+    Read,
+    Write,
+}
+
 /// Initialize the program with basic setup and return initial metadata and labels
-fn prologue<'a>(
+pub fn prologue<'a>(
     program: &mut ProgramBuilder,
     syms: &'a SymbolTable,
     table_count: usize,
@@ -122,7 +182,10 @@ fn prologue<'a>(
         reg_result_cols_start: None,
         meta_group_by: None,
         meta_left_joins: (0..table_count).map(|_| None).collect(),
+        meta_right_joins: (0..table_count).map(|_| None).collect(),
         meta_sort: None,
+        meta_window: vec![],
+        distinct_cursor: None,
         result_column_indexes_in_orderby_sorter: (0..result_column_count).collect(),
         result_columns_to_skip_in_orderby_sorter: None,
         resolver: Resolver::new(syms),
@@ -134,18 +197,22 @@ fn prologue<'a>(
 /// Clean up and finalize the program, resolving any remaining labels
 /// Note that although these are the final instructions, typically an SQLite
 /// query will jump to the Transaction instruction via init_label.
-fn epilogue(
+pub fn epilogue(
     program: &mut ProgramBuilder,
     init_label: BranchOffset,
     start_offset: BranchOffset,
+    txn_mode: TransactionMode,
 ) -> Result<()> {
     program.emit_insn(Insn::Halt {
         err_code: 0,
         description: String::new(),
+        on_error: OnError::Abort,
     });
 
     program.resolve_label(init_label, program.offset());
-    program.emit_insn(Insn::Transaction { write: false });
+    program.emit_insn(Insn::Transaction {
+        write: matches!(txn_mode, TransactionMode::Write),
+    });
 
     program.emit_constant_insns();
     program.emit_insn(Insn::Goto {
@@ -157,10 +224,17 @@ fn epilogue(
 
 /// Main entry point for emitting bytecode for a SQL query
 /// Takes a query plan and generates the corresponding bytecode program
-pub fn emit_program(program: &mut ProgramBuilder, plan: Plan, syms: &SymbolTable) -> Result<()> {
+/// The schema is needed to compile the programs of the triggers that an UPDATE or DELETE fires.
+pub fn emit_program(
+    program: &mut ProgramBuilder,
+    plan: Plan,
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<()> {
     match plan {
         Plan::Select(plan) => emit_program_for_select(program, plan, syms),
-        Plan::Delete(plan) => emit_program_for_delete(program, plan, syms),
+        Plan::Delete(plan) => emit_program_for_delete(program, plan, schema, syms),
+        Plan::Update(plan) => emit_program_for_update(program, plan, schema, syms),
     }
 }
 
@@ -179,7 +253,7 @@ fn emit_program_for_select(
     // Trivial exit on LIMIT 0
     if let Some(limit) = plan.limit {
         if limit == 0 {
-            epilogue(program, init_label, start_offset)?;
+            epilogue(program, init_label, start_offset, TransactionMode::Read)?;
         }
     }
 
@@ -187,7 +261,7 @@ fn emit_program_for_select(
     emit_query(program, &mut plan, &mut t_ctx)?;
 
     // Finalize program
-    epilogue(program, init_label, start_offset)?;
+    epilogue(program, init_label, start_offset, TransactionMode::Read)?;
     program.result_columns = plan.result_columns;
     program.table_references = plan.table_references;
     Ok(())
@@ -200,6 +274,7 @@ pub fn emit_query<'a>(
 ) -> Result<usize> {
     // Emit subqueries first so the results can be read in the main query loop.
     emit_subqueries(program, t_ctx, &mut plan.table_references)?;
+    emit_expr_subqueries(program, &mut t_ctx.resolver, &mut plan.subqueries)?;
 
     if t_ctx.reg_limit.is_none() {
         t_ctx.reg_limit = plan.limit.map(|_| program.alloc_register());
@@ -218,6 +293,17 @@ pub fn emit_query<'a>(
     // e.g. SELECT COUNT(*) WHERE 0 returns a row with 0, not an empty result set
     let after_main_loop_label = program.allocate_label();
     t_ctx.label_main_loop_end = Some(after_main_loop_label);
+
+    // Reset the accumulators of aggregates without GROUP BY, since a subquery can be run more than once
+    if plan.group_by.is_none() && !plan.aggregates.is_empty() {
+        let agg_start_reg = program.alloc_registers(plan.aggregates.len());
+        program.emit_insn(Insn::Null {
+            dest: agg_start_reg,
+            dest_end: Some(agg_start_reg + plan.aggregates.len() - 1),
+        });
+        t_ctx.reg_agg_start = Some(agg_start_reg);
+    }
+
     if plan.contains_constant_false_condition {
         program.emit_insn(Insn::Goto {
             target_pc: after_main_loop_label,
@@ -229,11 +315,35 @@ pub fn emit_query<'a>(
 
     // Initialize cursors and other resources needed for query execution
     if let Some(ref mut order_by) = plan.order_by {
-        init_order_by(program, t_ctx, order_by)?;
+        init_order_by(program, t_ctx, order_by, &plan.table_references)?;
     }
 
     if let Some(ref mut group_by) = plan.group_by {
-        init_group_by(program, t_ctx, group_by, &plan.aggregates)?;
+        init_group_by(
+            program,
+            t_ctx,
+            group_by,
+            &plan.aggregates,
+            &plan.table_references,
+        )?;
+    }
+
+    if !plan.windows.is_empty() {
+        init_window(program, t_ctx, &plan.windows, &plan.table_references)?;
+    }
+
+    if plan.distinct {
+        let distinct_cursor = program.alloc_cursor_id(None, CursorType::Ephemeral);
+        program.emit_insn(Insn::OpenEphemeral {
+            cursor_id: distinct_cursor,
+            distinct: true,
+            collations: plan
+                .result_columns
+                .iter()
+                .map(|rc| expr_collation(&rc.expr, &plan.table_references))
+                .collect(),
+        });
+        t_ctx.distinct_cursor = Some(distinct_cursor);
     }
     init_loop(
         program,
@@ -268,6 +378,9 @@ pub fn emit_query<'a>(
     // Clean up and close the main execution loop
     close_loop(program, t_ctx, &plan.table_references)?;
 
+    // Process the rows of the right tables of RIGHT and FULL OUTER JOINs that had no match
+    emit_unmatched_right_join_rows(program, t_ctx, plan)?;
+
     program.resolve_label(after_main_loop_label, program.offset());
 
     let mut order_by_necessary = plan.order_by.is_some() && !plan.contains_constant_false_condition;
@@ -280,6 +393,9 @@ pub fn emit_query<'a>(
         emit_ungrouped_aggregation(program, t_ctx, plan)?;
         // Single row result for aggregates without GROUP BY, so ORDER BY not needed
         order_by_necessary = false;
+    } else if !plan.windows.is_empty() && !plan.contains_constant_false_condition {
+        // Compute the window functions over the sorted rows, and emit or sort the results
+        emit_window(program, t_ctx, plan)?;
     }
 
     // Process ORDER BY results if needed
@@ -290,9 +406,15 @@ pub fn emit_query<'a>(
     Ok(t_ctx.reg_result_cols_start.unwrap())
 }
 
+/// DELETE normally deletes each row matching the WHERE clause as soon as the loop over the table
+/// finds it. A subquery may read the table as well, though, so a DELETE with subqueries is emitted
+/// in two passes like UPDATE: the first pass collects the keys of the rows to delete into a sorter,
+/// and the second pass seeks to each of them and deletes it. The subqueries in the WHERE clause
+/// then see the table as it was before the statement.
 fn emit_program_for_delete(
     program: &mut ProgramBuilder,
-    plan: DeletePlan,
+    mut plan: DeletePlan,
+    schema: &Schema,
     syms: &SymbolTable,
 ) -> Result<()> {
     let (mut t_ctx, init_label, start_offset) = prologue(
@@ -301,6 +423,22 @@ fn emit_program_for_delete(
         plan.table_references.len(),
         plan.result_columns.len(),
     )?;
+    emit_expr_subqueries(program, &mut t_ctx.resolver, &mut plan.subqueries)?;
+
+    let table_reference = plan.table_references.first().unwrap();
+    let primary_key_len = primary_key_len(table_reference);
+    // The sorter records are the rowids of the rows to delete, or their primary keys in a WITHOUT ROWID table.
+    let key_sorter =
+        (!plan.subqueries.is_empty() && table_reference.btree().is_some()).then(|| {
+            let sort_cursor = program.alloc_cursor_id(None, CursorType::Sorter);
+            program.emit_insn(Insn::SorterOpen {
+                cursor_id: sort_cursor,
+                columns: 0,
+                order: Record::new(vec![]),
+                collations: vec![],
+            });
+            sort_cursor
+        });
 
     // No rows will be read from source table loops if there is a constant false condition eg. WHERE 0
     let after_main_loop_label = program.allocate_label();
@@ -310,6 +448,8 @@ fn emit_program_for_delete(
             target_pc: after_main_loop_label,
         });
     }
+    // The registers of the rows of the RETURNING clause
+    t_ctx.reg_result_cols_start = Some(program.alloc_registers(plan.result_columns.len()));
 
     // Initialize cursors and other resources needed for query execution
     init_loop(
@@ -318,6 +458,7 @@ fn emit_program_for_delete(
         &plan.table_references,
         &OperationMode::DELETE,
     )?;
+    let index_cursor_ids = emit_open_index_cursors(program, &plan.indexes);
 
     // Set up main query execution loop
     open_loop(
@@ -326,46 +467,234 @@ fn emit_program_for_delete(
         &plan.table_references,
         &plan.where_clause,
     )?;
-    emit_delete_insns(program, &mut t_ctx, &plan.table_references, &plan.limit)?;
+    match key_sorter {
+        Some(sort_cursor) => {
+            let cursor_id = program.resolve_cursor_id(&table_reference.identifier);
+            let key_reg = emit_row_key(program, cursor_id, primary_key_len);
+            let record_reg = program.alloc_register();
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: key_reg,
+                count: primary_key_len.unwrap_or(1),
+                dest_reg: record_reg,
+            });
+            program.emit_insn(Insn::SorterInsert {
+                cursor_id: sort_cursor,
+                record_reg,
+            });
+        }
+        None => {
+            let next_row_label = t_ctx.labels_main_loop[0].next;
+            emit_delete_insns(
+                program,
+                &t_ctx,
+                &plan,
+                &index_cursor_ids,
+                next_row_label,
+                after_main_loop_label,
+                schema,
+                syms,
+            )?;
+        }
+    }
 
     // Clean up and close the main execution loop
     close_loop(program, &mut t_ctx, &plan.table_references)?;
 
     program.resolve_label(after_main_loop_label, program.offset());
 
+    if let Some(sort_cursor) = key_sorter {
+        emit_delete_second_pass(
+            program,
+            &t_ctx,
+            &plan,
+            sort_cursor,
+            &index_cursor_ids,
+            schema,
+            syms,
+        )?;
+    }
+
     // Finalize program
-    epilogue(program, init_label, start_offset)?;
+    epilogue(program, init_label, start_offset, TransactionMode::Write)?;
     program.result_columns = plan.result_columns;
     program.table_references = plan.table_references;
     Ok(())
 }
 
+/// Emits the second pass of a DELETE with subqueries: loops over the keys collected in the sorter
+/// and deletes the corresponding rows.
+fn emit_delete_second_pass(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    plan: &DeletePlan,
+    sort_cursor: CursorID,
+    index_cursor_ids: &[CursorID],
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<()> {
+    let table_reference = plan.table_references.first().unwrap();
+    let cursor_id = program.resolve_cursor_id(&table_reference.identifier);
+    let primary_key_len = primary_key_len(table_reference);
+    let key_len = primary_key_len.unwrap_or(1);
+    let (pseudo_cursor, reg_sorter_data) = emit_open_sorter_pseudo(program, key_len);
+
+    let sort_loop_start_label = program.allocate_label();
+    let sort_loop_next_label = program.allocate_label();
+    let sort_loop_end_label = program.allocate_label();
+
+    program.emit_insn(Insn::SorterSort {
+        cursor_id: sort_cursor,
+        pc_if_empty: sort_loop_end_label,
+    });
+    program.resolve_label(sort_loop_start_label, program.offset());
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sort_cursor,
+        dest_reg: reg_sorter_data,
+        pseudo_cursor,
+    });
+    let key_reg = program.alloc_registers(key_len);
+    for i in 0..key_len {
+        program.emit_insn(Insn::Column {
+            cursor_id: pseudo_cursor,
+            column: i,
+            dest: key_reg + i,
+        });
+    }
+    emit_seek_row(
+        program,
+        cursor_id,
+        key_reg,
+        primary_key_len,
+        sort_loop_next_label,
+    );
+    emit_delete_insns(
+        program,
+        t_ctx,
+        plan,
+        index_cursor_ids,
+        sort_loop_next_label,
+        sort_loop_end_label,
+        schema,
+        syms,
+    )?;
+
+    program.resolve_label(sort_loop_next_label, program.offset());
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sort_cursor,
+        pc_if_next: sort_loop_start_label,
+    });
+    program.resolve_label(sort_loop_end_label, program.offset());
+
+    Ok(())
+}
+
+/// The number of columns of the primary key of a WITHOUT ROWID table, which identifies its rows
+/// instead of the rowid.
+fn primary_key_len(table_reference: &TableReference) -> Option<usize> {
+    table_reference
+        .btree()
+        .and_then(|table| table.primary_key_index())
+        .map(|index| index.columns.len())
+}
+
+/// Reads the key of the row the table cursor points to into new registers and returns the first
+/// one. The key is the rowid, or the primary key of a WITHOUT ROWID table, which is the start of its record.
+fn emit_row_key(
+    program: &mut ProgramBuilder,
+    cursor_id: CursorID,
+    primary_key_len: Option<usize>,
+) -> usize {
+    match primary_key_len {
+        Some(primary_key_len) => {
+            let key_reg = program.alloc_registers(primary_key_len);
+            for i in 0..primary_key_len {
+                program.emit_insn(Insn::Column {
+                    cursor_id,
+                    column: i,
+                    dest: key_reg + i,
+                });
+            }
+            key_reg
+        }
+        None => {
+            let key_reg = program.alloc_register();
+            program.emit_insn(Insn::RowId {
+                cursor_id,
+                dest: key_reg,
+            });
+            key_reg
+        }
+    }
+}
+
+/// Moves the table cursor to the row with the key in the registers starting at `key_reg`,
+/// jumping to `not_found_label` if there is no such row.
+fn emit_seek_row(
+    program: &mut ProgramBuilder,
+    cursor_id: CursorID,
+    key_reg: usize,
+    primary_key_len: Option<usize>,
+    not_found_label: BranchOffset,
+) {
+    match primary_key_len {
+        Some(num_regs) => {
+            program.emit_insn(Insn::SeekGE {
+                is_index: true,
+                cursor_id,
+                start_reg: key_reg,
+                num_regs,
+                target_pc: not_found_label,
+            });
+            program.emit_insn(Insn::IdxGT {
+                cursor_id,
+                start_reg: key_reg,
+                num_regs,
+                target_pc: not_found_label,
+            });
+        }
+        None => program.emit_insn(Insn::SeekRowid {
+            cursor_id,
+            src_reg: key_reg,
+            target_pc: not_found_label,
+        }),
+    }
+}
+
+/// Emits the deletion of the row the table cursor points to. A row that is skipped, because a
+/// BEFORE trigger ignored it or deleted it, jumps to `next_row_label`, and reaching the LIMIT
+/// jumps to `loop_end_label`.
+#[allow(clippy::too_many_arguments)]
 fn emit_delete_insns(
     program: &mut ProgramBuilder,
-    t_ctx: &mut TranslateCtx,
-    table_references: &[TableReference],
-    limit: &Option<isize>,
+    t_ctx: &TranslateCtx,
+    plan: &DeletePlan,
+    index_cursor_ids: &[CursorID],
+    next_row_label: BranchOffset,
+    loop_end_label: BranchOffset,
+    schema: &Schema,
+    syms: &SymbolTable,
 ) -> Result<()> {
-    let table_reference = table_references.first().unwrap();
+    let table_reference = plan.table_references.first().unwrap();
     let cursor_id = match &table_reference.op {
-        Operation::Scan { .. } => program.resolve_cursor_id(&table_reference.identifier),
-        Operation::Search(search) => match search {
-            Search::RowidEq { .. } | Search::RowidSearch { .. } => {
-                program.resolve_cursor_id(&table_reference.identifier)
-            }
-            Search::IndexSearch { index, .. } => program.resolve_cursor_id(&index.name),
-        },
+        Operation::Scan { .. } | Operation::Search(_) => {
+            program.resolve_cursor_id(&table_reference.identifier)
+        }
         _ => return Ok(()),
     };
 
-    // Emit the instructions to delete the row
-    let key_reg = program.alloc_register();
-    program.emit_insn(Insn::RowId {
-        cursor_id,
-        dest: key_reg,
-    });
+    // Emit the instructions to delete the row. A row of a WITHOUT ROWID table is identified
+    // by its primary key instead of the rowid.
+    let primary_key_len = primary_key_len(table_reference);
+    let key_reg = emit_row_key(program, cursor_id, primary_key_len);
 
     if let Some(vtab) = table_reference.virtual_table() {
+        emit_returning_row(
+            program,
+            &plan.table_references,
+            &plan.result_columns,
+            t_ctx.reg_result_cols_start.unwrap(),
+            &t_ctx.resolver,
+        )?;
         let conflict_action = 0u16;
         let start_reg = key_reg;
 
@@ -382,10 +711,83 @@ fn emit_delete_insns(
             conflict_action,
         });
     } else {
+        let btree_table = table_reference.btree().unwrap();
+        let trigger_columns = trigger_column_names(&btree_table);
+        let num_columns = trigger_columns.len();
+        let foreign_keys = program.foreign_keys && has_foreign_keys(schema, &btree_table);
+        let trigger_params_reg = (!plan.triggers.is_empty() || foreign_keys).then(|| {
+            let params_start_reg = alloc_trigger_params(program, num_columns);
+            emit_cursor_row_params(program, &btree_table, cursor_id, params_start_reg);
+            emit_null_row_params(
+                program,
+                num_columns,
+                new_row_params(params_start_reg, num_columns),
+            );
+            params_start_reg
+        });
+        if let (Some(params_start_reg), false) = (trigger_params_reg, plan.triggers.is_empty()) {
+            emit_fire_triggers(
+                program,
+                schema,
+                syms,
+                &plan.triggers,
+                ast::TriggerTime::Before,
+                &trigger_columns,
+                params_start_reg,
+                next_row_label,
+            )?;
+            // The BEFORE triggers may have changed the table, so seek the row again.
+            emit_seek_row(program, cursor_id, key_reg, primary_key_len, next_row_label);
+        }
+        if foreign_keys {
+            emit_fk_checks(
+                program,
+                schema,
+                &btree_table,
+                trigger_params_reg,
+                None,
+                None,
+            )?;
+        }
+        // The RETURNING clause returns the row as it was before the deletion
+        emit_returning_row(
+            program,
+            &plan.table_references,
+            &plan.result_columns,
+            t_ctx.reg_result_cols_start.unwrap(),
+            &t_ctx.resolver,
+        )?;
+        for (index, &index_cursor_id) in plan.indexes.iter().zip(index_cursor_ids) {
+            let key_start = emit_index_key(
+                program,
+                &btree_table,
+                index,
+                IndexKeySource::Cursor(cursor_id),
+            )?;
+            emit_index_delete(program, &btree_table, index, index_cursor_id, key_start);
+        }
         program.emit_insn(Insn::DeleteAsync { cursor_id });
-        program.emit_insn(Insn::DeleteAwait { cursor_id });
+        program.emit_insn(Insn::DeleteAwait {
+            cursor_id,
+            skip_nchange: false,
+        });
+        if let (Some(params_start_reg), true) = (trigger_params_reg, foreign_keys) {
+            emit_fk_actions(program, schema, syms, &btree_table, params_start_reg, None)?;
+        }
+        if let Some(params_start_reg) = trigger_params_reg {
+            emit_fire_triggers(
+                program,
+                schema,
+                syms,
+                &plan.triggers,
+                ast::TriggerTime::After,
+                &trigger_columns,
+                params_start_reg,
+                next_row_label,
+            )?;
+        }
     }
-    if let Some(limit) = limit {
+    if let Some(limit) = &plan.limit {
         let limit_reg = program.alloc_register();
         program.emit_insn(Insn::Integer {
             value: *limit as i64,
@@ -394,9 +796,538 @@ fn emit_delete_insns(
         program.mark_last_insn_constant();
         program.emit_insn(Insn::DecrJumpZero {
             reg: limit_reg,
-            target_pc: t_ctx.label_main_loop_end.unwrap(),
+            target_pc: loop_end_label,
         })
     }
 
     Ok(())
 }
+
+/// Emits a row of the RETURNING clause of an INSERT or DELETE, if it has one. The columns of the
+/// written row are read from the table cursor, which must point at the row.
+pub fn emit_returning_row(
+    program: &mut ProgramBuilder,
+    table_references: &[TableReference],
+    result_columns: &[ResultSetColumn],
+    start_reg: usize,
+    resolver: &Resolver,
+) -> Result<()> {
+    if result_columns.is_empty() {
+        return Ok(());
+    }
+    for (i, column) in result_columns.iter().enumerate() {
+        translate_expr(
+            program,
+            Some(table_references),
+            &column.expr,
+            start_reg + i,
+            resolver,
+        )?;
+    }
+    program.emit_insn(Insn::ResultRow {
+        start_reg,
+        count: result_columns.len(),
+    });
+    Ok(())
+}
+
+/// UPDATE is emitted in two passes. The first pass loops over the rows matching the WHERE clause
+/// and collects their rowids (preceded by the ORDER BY keys, if any) into a sorter. The second
+/// pass loops over the sorter, seeks to each row and rewrites it. Collecting the rowids first
+/// means that modifying the table never disturbs the loop that is reading it, and rows whose
+/// rowid changes are not visited twice.
+fn emit_program_for_update(
+    program: &mut ProgramBuilder,
+    mut plan: UpdatePlan,
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<()> {
+    let (mut t_ctx, init_label, start_offset) =
+        prologue(program, syms, plan.table_references.len(), 0)?;
+    emit_expr_subqueries(program, &mut t_ctx.resolver, &mut plan.subqueries)?;
+
+    let table_reference = plan.table_references.first().unwrap();
+    let order_by = plan.order_by.as_deref().unwrap_or_default();
+
+    // The sorter records are the ORDER BY keys followed by the rowid of the row to update.
+    // Without ORDER BY there are no keys and the sorter keeps the rows in the order they were visited.
+    let sort_cursor = program.alloc_cursor_id(None, CursorType::Sorter);
+    let order = order_by
+        .iter()
+        .map(|(_, direction)| OwnedValue::Integer(*direction as i64))
+        .collect();
+    program.emit_insn(Insn::SorterOpen {
+        cursor_id: sort_cursor,
+        columns: order_by.len(),
+        order: Record::new(order),
+        collations: order_by
+            .iter()
+            .map(|(expr, _)| expr_collation(expr, &plan.table_references))
+            .collect(),
+    });
+
+    // No rows will be read from source table loops if there is a constant false condition eg. WHERE 0
+    let after_main_loop_label = program.allocate_label();
+    t_ctx.label_main_loop_end = Some(after_main_loop_label);
+    if plan.contains_constant_false_condition || plan.limit == Some(0) {
+        program.emit_insn(Insn::Goto {
+            target_pc: after_main_loop_label,
+        });
+    }
+
+    // Initialize cursors and other resources needed for query execution
+    init_loop(
+        program,
+        &mut t_ctx,
+        &plan.table_references,
+        &OperationMode::UPDATE,
+    )?;
+    let table_cursor_id = program.resolve_cursor_id(&table_reference.identifier);
+    let index_cursor_ids = emit_open_index_cursors(program, &plan.indexes);
+
+    // Set up main query execution loop
+    open_loop(
+        program,
+        &mut t_ctx,
+        &plan.table_references,
+        &plan.where_clause,
+    )?;
+
+    let reg_sorter_start = program.alloc_registers(order_by.len() + 1);
+    for (i, (expr, _)) in order_by.iter().enumerate() {
+        translate_expr(
+            program,
+            Some(&plan.table_references),
+            expr,
+            reg_sorter_start + i,
+            &t_ctx.resolver,
+        )?;
+    }
+    program.emit_insn(Insn::RowId {
+        cursor_id: table_cursor_id,
+        dest: reg_sorter_start + order_by.len(),
+    });
+    let reg_sorter_record = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: reg_sorter_start,
+        count: order_by.len() + 1,
+        dest_reg: reg_sorter_record,
+    });
+    program.emit_insn(Insn::SorterInsert {
+        cursor_id: sort_cursor,
+        record_reg: reg_sorter_record,
+    });
+
+    // Clean up and close the main execution loop
+    close_loop(program, &mut t_ctx, &plan.table_references)?;
+
+    program.resolve_label(after_main_loop_label, program.offset());
+
+    emit_update_insns(
+        program,
+        &t_ctx,
+        &plan,
+        sort_cursor,
+        table_cursor_id,
+        &index_cursor_ids,
+        schema,
+        syms,
+    )?;
+
+    // Finalize program
+    epilogue(program, init_label, start_offset, TransactionMode::Write)?;
+    program.table_references = plan.table_references;
+    Ok(())
+}
+
+/// Emits the second pass of an UPDATE: loops over the rowids collected in the sorter
+/// and rewrites the corresponding rows.
+#[allow(clippy::too_many_arguments)]
+fn emit_update_insns(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    plan: &UpdatePlan,
+    sort_cursor: usize,
+    table_cursor_id: usize,
+    index_cursor_ids: &[CursorID],
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<()> {
+    let num_sorter_columns = plan.order_by.as_ref().map_or(0, |o| o.len()) + 1;
+    let (pseudo_cursor, reg_sorter_data) = emit_open_sorter_pseudo(program, num_sorter_columns);
+
+    let sort_loop_start_label = program.allocate_label();
+    let sort_loop_next_label = program.allocate_label();
+    let sort_loop_end_label = program.allocate_label();
+
+    program.emit_insn(Insn::SorterSort {
+        cursor_id: sort_cursor,
+        pc_if_empty: sort_loop_end_label,
+    });
+    program.resolve_label(sort_loop_start_label, program.offset());
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sort_cursor,
+        dest_reg: reg_sorter_data,
+        pseudo_cursor,
+    });
+
+    if let Some(offset) = plan.offset {
+        let offset_reg = program.alloc_register();
+        program.emit_insn(Insn::Integer {
+            value: offset as i64,
+            dest: offset_reg,
+        });
+        program.mark_last_insn_constant();
+        program.emit_insn(Insn::IfPos {
+            reg: offset_reg,
+            target_pc: sort_loop_next_label,
+            decrement_by: 1,
+        });
+    }
+
+    let old_rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::Column {
+        cursor_id: pseudo_cursor,
+        column: num_sorter_columns - 1,
+        dest: old_rowid_reg,
+    });
+    program.emit_insn(Insn::SeekRowid {
+        cursor_id: table_cursor_id,
+        src_reg: old_rowid_reg,
+        target_pc: sort_loop_next_label,
+    });
+
+    emit_update_row(
+        program,
+        plan,
+        &t_ctx.resolver,
+        table_cursor_id,
+        index_cursor_ids,
+        old_rowid_reg,
+        sort_loop_next_label,
+        schema,
+        syms,
+    )?;
+
+    if let Some(limit) = plan.limit {
+        let limit_reg = program.alloc_register();
+        program.emit_insn(Insn::Integer {
+            value: limit as i64,
+            dest: limit_reg,
+        });
+        program.mark_last_insn_constant();
+        program.emit_insn(Insn::DecrJumpZero {
+            reg: limit_reg,
+            target_pc: sort_loop_end_label,
+        });
+    }
+
+    program.resolve_label(sort_loop_next_label, program.offset());
+    program.emit_insn(Insn::SorterNext {
+        cursor_id: sort_cursor,
+        pc_if_next: sort_loop_start_label,
+    });
+    program.resolve_label(sort_loop_end_label, program.offset());
+
+    Ok(())
+}
+
+/// Opens the pseudo cursor that the records of the sorter of an UPDATE or DELETE, which have
+/// `num_columns` columns, are read through. Returns the cursor and the register of the record.
+fn emit_open_sorter_pseudo(program: &mut ProgramBuilder, num_columns: usize) -> (CursorID, usize) {
+    let pseudo_columns = (0..num_columns)
+        .map(|_| {
+            let ty = crate::schema::Type::Null;
+            Column {
+                name: None,
+                primary_key: false,
+                ty,
+                ty_str: ty.to_string().to_uppercase(),
+                is_rowid_alias: false,
+                notnull: false,
+                default: None,
+                collation: None,
+            }
+        })
+        .collect();
+    let pseudo_cursor = program.alloc_cursor_id(
+        None,
+        CursorType::Pseudo(Rc::new(PseudoTable {
+            columns: pseudo_columns,
+        })),
+    );
+    let reg_sorter_data = program.alloc_register();
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: pseudo_cursor,
+        content_reg: reg_sorter_data,
+        num_fields: num_columns,
+    });
+    (pseudo_cursor, reg_sorter_data)
+}
+
+/// Emits the rewrite of the row with the rowid in `old_rowid_reg` by the SET clause of an UPDATE.
+/// The table cursor must point to that row. `index_cursor_ids` are the cursors of the indexes of
+/// the plan. A row that is skipped, because a BEFORE trigger ignored it or deleted it, jumps to
+/// `skip_label`. Returns the register of the rowid of the row after the update.
+#[allow(clippy::too_many_arguments)]
+pub fn emit_update_row(
+    program: &mut ProgramBuilder,
+    plan: &UpdatePlan,
+    resolver: &Resolver,
+    table_cursor_id: CursorID,
+    index_cursor_ids: &[CursorID],
+    old_rowid_reg: usize,
+    skip_label: BranchOffset,
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<usize> {
+    let table_reference = plan.table_references.first().unwrap();
+    let btree_table = table_reference.btree().unwrap();
+
+    // Evaluate the new values of all columns before touching the row,
+    // so that every SET expression sees the old row.
+    let rowid_alias_index = btree_table.columns.iter().position(|c| c.is_rowid_alias);
+    let rowid_set_expr = plan
+        .set_clauses
+        .iter()
+        .find(|(idx, _)| Some(*idx) == rowid_alias_index)
+        .map(|(_, expr)| expr);
+    let new_rowid_reg = match rowid_set_expr {
+        Some(expr) => {
+            let reg = program.alloc_register();
+            translate_expr(program, Some(&plan.table_references), expr, reg, resolver)?;
+            program.emit_insn(Insn::MustBeInt { reg });
+            reg
+        }
+        None => old_rowid_reg,
+    };
+    let column_regs_start = program.alloc_registers(btree_table.columns.len());
+    for (idx, column) in btree_table.columns.iter().enumerate() {
+        let target_reg = column_regs_start + idx;
+        // A column that is an alias for the rowid is stored as NULL in the record
+        if column.is_rowid_alias {
+            program.emit_insn(Insn::Null {
+                dest: target_reg,
+                dest_end: None,
+            });
+            continue;
+        }
+        match plan.set_clauses.iter().find(|(i, _)| *i == idx) {
+            Some((_, expr)) => {
+                translate_expr(
+                    program,
+                    Some(&plan.table_references),
+                    expr,
+                    target_reg,
+                    resolver,
+                )?;
+            }
+            None => {
+                program.emit_insn(Insn::Column {
+                    cursor_id: table_cursor_id,
+                    column: idx,
+                    dest: target_reg,
+                });
+            }
+        }
+    }
+
+    let trigger_columns = trigger_column_names(&btree_table);
+    let num_columns = trigger_columns.len();
+    let foreign_keys = program.foreign_keys && has_foreign_keys(schema, &btree_table);
+    let changed_columns = plan
+        .set_clauses
+        .iter()
+        .map(|(idx, _)| *idx)
+        .collect::<Vec<_>>();
+    let trigger_params_reg = (!plan.triggers.is_empty() || foreign_keys).then(|| {
+        let params_start_reg = alloc_trigger_params(program, num_columns);
+        emit_cursor_row_params(program, &btree_table, table_cursor_id, params_start_reg);
+        emit_row_params(
+            program,
+            &btree_table,
+            new_rowid_reg,
+            column_regs_start,
+            new_row_params(params_start_reg, num_columns),
+        );
+        params_start_reg
+    });
+    if let (Some(params_start_reg), false) = (trigger_params_reg, plan.triggers.is_empty()) {
+        emit_fire_triggers(
+            program,
+            schema,
+            syms,
+            &plan.triggers,
+            ast::TriggerTime::Before,
+            &trigger_columns,
+            params_start_reg,
+            skip_label,
+        )?;
+        // The BEFORE triggers may have changed the table, so seek the row again.
+        program.emit_insn(Insn::SeekRowid {
+            cursor_id: table_cursor_id,
+            src_reg: old_rowid_reg,
+            target_pc: skip_label,
+        });
+    }
+
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: column_regs_start,
+        count: btree_table.columns.len(),
+        dest_reg: record_reg,
+    });
+
+    // Check the UNIQUE indexes against the new keys, then remove the old keys while the
+    // table cursor still points to the old row.
+    let mut new_index_keys = Vec::with_capacity(plan.indexes.len());
+    for (index, &index_cursor_id) in plan.indexes.iter().zip(index_cursor_ids) {
+        let key_start = emit_index_key(
+            program,
+            &btree_table,
+            index,
+            IndexKeySource::Registers {
+                columns_start: column_regs_start,
+                rowid_reg: new_rowid_reg,
+            },
+        )?;
+        if index.unique {
+            emit_unique_check(
+                program,
+                index,
+                index_cursor_id,
+                key_start,
+                Some(old_rowid_reg),
+            );
+        }
+        new_index_keys.push(key_start);
+    }
+    if let (Some(params_start_reg), true) = (trigger_params_reg, foreign_keys) {
+        emit_fk_checks(
+            program,
+            schema,
+            &btree_table,
+            Some(params_start_reg),
+            Some(new_row_params(params_start_reg, num_columns)),
+            Some(&changed_columns),
+        )?;
+    }
+    for (index, &index_cursor_id) in plan.indexes.iter().zip(index_cursor_ids) {
+        let old_key_start = emit_index_key(
+            program,
+            &btree_table,
+            index,
+            IndexKeySource::Cursor(table_cursor_id),
+        )?;
+        emit_index_delete(program, &btree_table, index, index_cursor_id, old_key_start);
+    }
+
+    let update_in_place_label = program.allocate_label();
+    let after_insert_label = program.allocate_label();
+    if let (Some(rowid_alias_index), Some(_)) = (rowid_alias_index, rowid_set_expr) {
+        // The rowid changes, so the row is moved: check that the new rowid is free,
+        // delete the old row and insert the new one.
+        program.emit_insn(Insn::Eq {
+            lhs: new_rowid_reg,
+            rhs: old_rowid_reg,
+            target_pc: update_in_place_label,
+            flags: CmpInsFlags::default(),
+            collation: None,
+        });
+        let rowid_free_label = program.allocate_label();
+        program.emit_insn(Insn::NotExists {
+            cursor: table_cursor_id,
+            rowid_reg: new_rowid_reg,
+            target_pc: rowid_free_label,
+        });
+        let rowid_column_name = btree_table.columns[rowid_alias_index]
+            .name
+            .as_ref()
+            .unwrap();
+        program.emit_insn(Insn::Halt {
+            err_code: SQLITE_CONSTRAINT_PRIMARYKEY,
+            description: format!("{}.{}", btree_table.name, rowid_column_name),
+            on_error: OnError::Abort,
+        });
+        program.resolve_label(rowid_free_label, program.offset());
+
+        program.emit_insn(Insn::SeekRowid {
+            cursor_id: table_cursor_id,
+            src_reg: old_rowid_reg,
+            target_pc: skip_label,
+        });
+        program.emit_insn(Insn::DeleteAsync {
+            cursor_id: table_cursor_id,
+        });
+        program.emit_insn(Insn::DeleteAwait {
+            cursor_id: table_cursor_id,
+            skip_nchange: false,
+        });
+        // Position the cursor for the insert of the new rowid
+        let insert_label = program.allocate_label();
+        program.emit_insn(Insn::NotExists {
+            cursor: table_cursor_id,
+            rowid_reg: new_rowid_reg,
+            target_pc: insert_label,
+        });
+        program.resolve_label(insert_label, program.offset());
+        // The delete above already counted this row as changed
+        program.emit_insn(Insn::InsertAsync {
+            cursor: table_cursor_id,
+            key_reg: new_rowid_reg,
+            record_reg,
+            flag: InsertFlags::default().update().skip_nchange(),
+        });
+        program.emit_insn(Insn::InsertAwait {
+            cursor_id: table_cursor_id,
+        });
+        program.emit_insn(Insn::Goto {
+            target_pc: after_insert_label,
+        });
+    }
+    program.resolve_label(update_in_place_label, program.offset());
+    program.emit_insn(Insn::InsertAsync {
+        cursor: table_cursor_id,
+        key_reg: old_rowid_reg,
+        record_reg,
+        flag: InsertFlags::default().update(),
+    });
+    program.emit_insn(Insn::InsertAwait {
+        cursor_id: table_cursor_id,
+    });
+    program.resolve_label(after_insert_label, program.offset());
+
+    for ((index, &index_cursor_id), &key_start) in plan
+        .indexes
+        .iter()
+        .zip(index_cursor_ids)
+        .zip(new_index_keys.iter())
+    {
+        emit_index_insert(program, &btree_table, index, index_cursor_id, key_start);
+    }
+
+    if let (Some(params_start_reg), true) = (trigger_params_reg, foreign_keys) {
+        emit_fk_actions(
+            program,
+            schema,
+            syms,
+            &btree_table,
+            params_start_reg,
+            Some(&changed_columns),
+        )?;
+    }
+    if let Some(params_start_reg) = trigger_params_reg {
+        emit_fire_triggers(
+            program,
+            schema,
+            syms,
+            &plan.triggers,
+            ast::TriggerTime::After,
+            &trigger_columns,
+            params_start_reg,
+            skip_label,
+        )?;
+    }
+
+    Ok(new_rowid_reg)
+}
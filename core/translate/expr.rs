@@ -1,5 +1,6 @@
 use limbo_sqlite3_parser::ast::{self, UnaryOperator};
 
+use crate::error::{SQLITE_CONSTRAINT_TRIGGER, SQLITE_IGNORE};
 #[cfg(feature = "json")]
 use crate::function::JsonFunc;
 use crate::function::{Func, FuncCtx, MathFuncArity, ScalarFunc, VectorFunc};
@@ -7,13 +8,14 @@ use crate::schema::{Table, Type};
 use crate::util::normalize_ident;
 use crate::vdbe::{
     builder::ProgramBuilder,
-    insn::{CmpInsFlags, Insn},
+    insn::{CmpInsFlags, Insn, OnError},
     BranchOffset,
 };
 use crate::Result;
 
+use super::collate::{comparison_collation, expr_collation, CollationSeq};
 use super::emitter::Resolver;
-use super::plan::{Operation, TableReference};
+use super::plan::{Operation, SubqueryType, TableReference};
 
 #[derive(Debug, Clone, Copy)]
 pub struct ConditionMetadata {
@@ -44,7 +46,8 @@ macro_rules! emit_cmp_insn {
         $op_true:ident,
         $op_false:ident,
         $lhs:expr,
-        $rhs:expr
+        $rhs:expr,
+        $collation:expr
     ) => {{
         if $cond.jump_if_condition_is_true {
             $program.emit_insn(Insn::$op_true {
@@ -52,6 +55,7 @@ macro_rules! emit_cmp_insn {
                 rhs: $rhs,
                 target_pc: $cond.jump_target_when_true,
                 flags: CmpInsFlags::default(),
+                collation: $collation,
             });
         } else {
             $program.emit_insn(Insn::$op_false {
@@ -59,6 +63,7 @@ macro_rules! emit_cmp_insn {
                 rhs: $rhs,
                 target_pc: $cond.jump_target_when_false,
                 flags: CmpInsFlags::default().jump_if_null(),
+                collation: $collation,
             });
         }
     }};
@@ -71,7 +76,8 @@ macro_rules! emit_cmp_null_insn {
         $op_true:ident,
         $op_false:ident,
         $lhs:expr,
-        $rhs:expr
+        $rhs:expr,
+        $collation:expr
     ) => {{
         if $cond.jump_if_condition_is_true {
             $program.emit_insn(Insn::$op_true {
@@ -79,6 +85,7 @@ macro_rules! emit_cmp_null_insn {
                 rhs: $rhs,
                 target_pc: $cond.jump_target_when_true,
                 flags: CmpInsFlags::default().null_eq(),
+                collation: $collation,
             });
         } else {
             $program.emit_insn(Insn::$op_false {
@@ -86,6 +93,7 @@ macro_rules! emit_cmp_null_insn {
                 rhs: $rhs,
                 target_pc: $cond.jump_target_when_false,
                 flags: CmpInsFlags::default().null_eq(),
+                collation: $collation,
             });
         }
     }};
@@ -186,7 +194,6 @@ pub fn translate_condition_expr(
     resolver: &Resolver,
 ) -> Result<()> {
     match expr {
-        ast::Expr::Between { .. } => todo!(),
         ast::Expr::Binary(lhs, ast::Operator::And, rhs) => {
             // In a binary AND, never jump to the parent 'jump_target_when_true' label on the first condition, because
             // the second condition MUST also be true. Instead we instruct the child expression to jump to a local
@@ -254,30 +261,95 @@ pub fn translate_condition_expr(
             let rhs_reg = program.alloc_register();
             translate_and_mark(program, Some(referenced_tables), lhs, lhs_reg, resolver)?;
             translate_and_mark(program, Some(referenced_tables), rhs, rhs_reg, resolver)?;
+            let collation = comparison_collation(lhs, rhs, referenced_tables);
             match op {
                 ast::Operator::Greater => {
-                    emit_cmp_insn!(program, condition_metadata, Gt, Le, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Gt,
+                        Le,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::GreaterEquals => {
-                    emit_cmp_insn!(program, condition_metadata, Ge, Lt, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Ge,
+                        Lt,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::Less => {
-                    emit_cmp_insn!(program, condition_metadata, Lt, Ge, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Lt,
+                        Ge,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::LessEquals => {
-                    emit_cmp_insn!(program, condition_metadata, Le, Gt, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Le,
+                        Gt,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::Equals => {
-                    emit_cmp_insn!(program, condition_metadata, Eq, Ne, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Eq,
+                        Ne,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::NotEquals => {
-                    emit_cmp_insn!(program, condition_metadata, Ne, Eq, lhs_reg, rhs_reg)
+                    emit_cmp_insn!(
+                        program,
+                        condition_metadata,
+                        Ne,
+                        Eq,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::Is => {
-                    emit_cmp_null_insn!(program, condition_metadata, Eq, Ne, lhs_reg, rhs_reg)
+                    emit_cmp_null_insn!(
+                        program,
+                        condition_metadata,
+                        Eq,
+                        Ne,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 ast::Operator::IsNot => {
-                    emit_cmp_null_insn!(program, condition_metadata, Ne, Eq, lhs_reg, rhs_reg)
+                    emit_cmp_null_insn!(
+                        program,
+                        condition_metadata,
+                        Ne,
+                        Eq,
+                        lhs_reg,
+                        rhs_reg,
+                        collation
+                    )
                 }
                 _ => unreachable!(),
             }
@@ -292,7 +364,12 @@ pub fn translate_condition_expr(
         | ast::Expr::FunctionCall { .. }
         | ast::Expr::Column { .. }
         | ast::Expr::RowId { .. }
-        | ast::Expr::Case { .. } => {
+        | ast::Expr::Case { .. }
+        | ast::Expr::Between { .. }
+        | ast::Expr::SubqueryResult { .. }
+        | ast::Expr::OuterRef(_)
+        | ast::Expr::Param(_)
+        | ast::Expr::Register(_) => {
             let reg = program.alloc_register();
             translate_expr(program, Some(referenced_tables), expr, reg, resolver)?;
             emit_cond_jump(program, condition_metadata, reg);
@@ -360,6 +437,7 @@ pub fn translate_condition_expr(
                     let last_condition = i == rhs.len() - 1;
                     let _ =
                         translate_expr(program, Some(referenced_tables), expr, rhs_reg, resolver)?;
+                    let collation = comparison_collation(lhs, expr, referenced_tables);
                     // If this is not the last condition, we need to jump to the 'jump_target_when_true' label if the condition is true.
                     if !last_condition {
                         program.emit_insn(Insn::Eq {
@@ -367,6 +445,7 @@ pub fn translate_condition_expr(
                             rhs: rhs_reg,
                             target_pc: jump_target_when_true,
                             flags: CmpInsFlags::default(),
+                            collation,
                         });
                     } else {
                         // If this is the last condition, we need to jump to the 'jump_target_when_false' label if there is no match.
@@ -375,6 +454,7 @@ pub fn translate_condition_expr(
                             rhs: rhs_reg,
                             target_pc: condition_metadata.jump_target_when_false,
                             flags: CmpInsFlags::default().jump_if_null(),
+                            collation,
                         });
                     }
                 }
@@ -396,6 +476,7 @@ pub fn translate_condition_expr(
                         rhs: rhs_reg,
                         target_pc: condition_metadata.jump_target_when_false,
                         flags: CmpInsFlags::default().jump_if_null(),
+                        collation: comparison_collation(lhs, expr, referenced_tables),
                     });
                 }
                 // If we got here, then none of the conditions were a match, so we jump to the 'jump_target_when_true' label if 'jump_if_condition_is_true'.
@@ -492,13 +573,93 @@ pub fn translate_expr(
         return Ok(target_register);
     }
     match expr {
-        ast::Expr::Between { .. } => todo!(),
+        // The values of window functions are computed by emit_window() and resolved from the cache above,
+        // so this is a window function in e.g. a WHERE clause.
+        ast::Expr::FunctionCall {
+            name,
+            filter_over:
+                Some(ast::FunctionTail {
+                    over_clause: Some(_),
+                    ..
+                }),
+            ..
+        }
+        | ast::Expr::FunctionCallStar {
+            name,
+            filter_over:
+                Some(ast::FunctionTail {
+                    over_clause: Some(_),
+                    ..
+                }),
+        } => {
+            crate::bail_parse_error!("misuse of window function {}()", name.0)
+        }
+        ast::Expr::Between {
+            lhs,
+            not,
+            start,
+            end,
+        } => {
+            // `x BETWEEN a AND b` is `x >= a AND x <= b`, but x is only evaluated once.
+            let lhs_reg = program.alloc_register();
+            let start_reg = program.alloc_register();
+            let end_reg = program.alloc_register();
+            let cmp_start_reg = program.alloc_register();
+            let cmp_end_reg = program.alloc_register();
+            translate_expr(program, referenced_tables, lhs, lhs_reg, resolver)?;
+            translate_expr(program, referenced_tables, start, start_reg, resolver)?;
+            translate_expr(program, referenced_tables, end, end_reg, resolver)?;
+            let tables = referenced_tables.unwrap_or_default();
+            let if_true_label = program.allocate_label();
+            wrap_eval_jump_expr_zero_or_null(
+                program,
+                Insn::Ge {
+                    lhs: lhs_reg,
+                    rhs: start_reg,
+                    target_pc: if_true_label,
+                    flags: CmpInsFlags::default(),
+                    collation: comparison_collation(lhs, start, tables),
+                },
+                cmp_start_reg,
+                if_true_label,
+                lhs_reg,
+                start_reg,
+            );
+            let if_true_label = program.allocate_label();
+            wrap_eval_jump_expr_zero_or_null(
+                program,
+                Insn::Le {
+                    lhs: lhs_reg,
+                    rhs: end_reg,
+                    target_pc: if_true_label,
+                    flags: CmpInsFlags::default(),
+                    collation: comparison_collation(lhs, end, tables),
+                },
+                cmp_end_reg,
+                if_true_label,
+                lhs_reg,
+                end_reg,
+            );
+            program.emit_insn(Insn::And {
+                lhs: cmp_start_reg,
+                rhs: cmp_end_reg,
+                dest: target_register,
+            });
+            if *not {
+                program.emit_insn(Insn::Not {
+                    reg: target_register,
+                    dest: target_register,
+                });
+            }
+            Ok(target_register)
+        }
         ast::Expr::Binary(e1, op, e2) => {
             let e1_reg = program.alloc_registers(2);
             let e2_reg = e1_reg + 1;
 
             translate_expr(program, referenced_tables, e1, e1_reg, resolver)?;
             translate_expr(program, referenced_tables, e2, e2_reg, resolver)?;
+            let collation = comparison_collation(e1, e2, referenced_tables.unwrap_or_default());
 
             match op {
                 ast::Operator::NotEquals => {
@@ -510,6 +671,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -526,6 +688,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -542,6 +705,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -558,6 +722,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -574,6 +739,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -590,6 +756,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -683,6 +850,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default().null_eq(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -697,6 +865,7 @@ pub fn translate_expr(
                             rhs: e2_reg,
                             target_pc: if_true_label,
                             flags: CmpInsFlags::default().null_eq(),
+                            collation,
                         },
                         target_register,
                         if_true_label,
@@ -769,6 +938,11 @@ pub fn translate_expr(
                         target_pc: next_case_label,
                         // A NULL result is considered untrue when evaluating WHEN terms.
                         flags: CmpInsFlags::default().jump_if_null(),
+                        collation: comparison_collation(
+                            base.as_ref().unwrap(),
+                            when_expr,
+                            referenced_tables.unwrap_or_default(),
+                        ),
                     }),
                     // CASE WHEN 0 THEN 0 ELSE 1 becomes ifnot 0 branch to next clause
                     None => program.emit_insn(Insn::IfNot {
@@ -831,14 +1005,20 @@ pub fn translate_expr(
             });
             Ok(target_register)
         }
-        ast::Expr::Collate(_, _) => todo!(),
-        ast::Expr::DoublyQualified(_, _, _) => todo!(),
-        ast::Expr::Exists(_) => todo!(),
+        ast::Expr::Collate(expr, collation) => {
+            // The collation only changes how the value compares, which the comparisons it is an
+            // operand of take care of.
+            CollationSeq::new(collation, resolver.symbol_table)?;
+            translate_expr(program, referenced_tables, expr, target_register, resolver)
+        }
+        ast::Expr::DoublyQualified(_, _, _) => {
+            unreachable!("DoublyQualified should be resolved to a Column before translation")
+        }
         ast::Expr::FunctionCall {
             name,
             distinctness: _,
             args,
-            filter_over: _,
+            filter_over,
             order_by: _,
         } => {
             let args_count = if let Some(args) = args { args.len() } else { 0 };
@@ -848,6 +1028,16 @@ pub fn translate_expr(
             if func_type.is_none() {
                 crate::bail_parse_error!("unknown function {}", name.0);
             }
+            if filter_over
+                .as_ref()
+                .is_some_and(|tail| tail.filter_clause.is_some())
+                && !matches!(func_type, Some(Func::Agg(_)))
+            {
+                crate::bail_parse_error!(
+                    "FILTER may not be used with non-aggregate {}()",
+                    func_name
+                );
+            }
 
             let func_ctx = FuncCtx {
                 func: func_type.unwrap(),
@@ -1881,7 +2071,7 @@ pub fn translate_expr(
                 // If we are reading a column from a table, we find the cursor that corresponds to
                 // the table and read the column from the cursor.
                 Operation::Scan { .. } | Operation::Search(_) => match &table_reference.table {
-                    Table::BTree(_) => {
+                    Table::BTree(btree) => {
                         let cursor_id = program.resolve_cursor_id(&table_reference.identifier);
                         if *is_rowid_alias {
                             program.emit_insn(Insn::RowId {
@@ -1891,7 +2081,7 @@ pub fn translate_expr(
                         } else {
                             program.emit_insn(Insn::Column {
                                 cursor_id,
-                                column: *column,
+                                column: btree.column_record_position(*column),
                                 dest: target_register,
                             });
                         }
@@ -1917,6 +2107,13 @@ pub fn translate_expr(
                 Operation::Subquery {
                     result_columns_start_reg,
                     ..
+                }
+                | Operation::RecursiveCte {
+                    result_columns_start_reg,
+                    ..
+                }
+                | Operation::CteRow {
+                    result_columns_start_reg,
                 } => {
                     program.emit_insn(Insn::Copy {
                         src_reg: result_columns_start_reg + *column,
@@ -1925,6 +2122,16 @@ pub fn translate_expr(
                     });
                     Ok(target_register)
                 }
+                // A MATERIALIZED CTE is read from the ephemeral table holding its rows.
+                Operation::MaterializedCte { .. } => {
+                    let cursor_id = program.resolve_cursor_id(&table_reference.identifier);
+                    program.emit_insn(Insn::Column {
+                        cursor_id,
+                        column: *column,
+                        dest: target_register,
+                    });
+                    Ok(target_register)
+                }
             }
         }
         ast::Expr::RowId { database: _, table } => {
@@ -1936,9 +2143,71 @@ pub fn translate_expr(
             });
             Ok(target_register)
         }
-        ast::Expr::InList { .. } => todo!(),
-        ast::Expr::InSelect { .. } => todo!(),
-        ast::Expr::InTable { .. } => todo!(),
+        ast::Expr::InList { lhs, not, rhs } => {
+            // `x IN (...)` is 1 if x is equal to one of the values. Otherwise it is NULL if x or
+            // one of the values is NULL, and 0 if not. `NOT IN` negates the result.
+            let values = rhs.as_deref().unwrap_or_default();
+            program.emit_insn(Insn::Integer {
+                value: *not as i64,
+                dest: target_register,
+            });
+            // Even `NULL IN ()` is false.
+            if values.is_empty() {
+                return Ok(target_register);
+            }
+            let lhs_reg = program.alloc_register();
+            translate_expr(program, referenced_tables, lhs, lhs_reg, resolver)?;
+            let label_found = program.allocate_label();
+            let label_done = program.allocate_label();
+            let label_lhs_not_null = program.allocate_label();
+            program.emit_insn(Insn::NotNull {
+                reg: lhs_reg,
+                target_pc: label_lhs_not_null,
+            });
+            program.emit_insn(Insn::Null {
+                dest: target_register,
+                dest_end: None,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: label_done,
+            });
+            program.resolve_label(label_lhs_not_null, program.offset());
+            for value in values {
+                let value_reg = program.alloc_register();
+                let label_next_value = program.allocate_label();
+                translate_expr(program, referenced_tables, value, value_reg, resolver)?;
+                program.emit_insn(Insn::Eq {
+                    lhs: lhs_reg,
+                    rhs: value_reg,
+                    target_pc: label_found,
+                    flags: CmpInsFlags::default(),
+                    collation: comparison_collation(
+                        lhs,
+                        value,
+                        referenced_tables.unwrap_or_default(),
+                    ),
+                });
+                program.emit_insn(Insn::NotNull {
+                    reg: value_reg,
+                    target_pc: label_next_value,
+                });
+                program.emit_insn(Insn::Null {
+                    dest: target_register,
+                    dest_end: None,
+                });
+                program.resolve_label(label_next_value, program.offset());
+            }
+            program.emit_insn(Insn::Goto {
+                target_pc: label_done,
+            });
+            program.resolve_label(label_found, program.offset());
+            program.emit_insn(Insn::Integer {
+                value: !*not as i64,
+                dest: target_register,
+            });
+            program.resolve_label(label_done, program.offset());
+            Ok(target_register)
+        }
         ast::Expr::IsNull(_) => todo!(),
         ast::Expr::Like { not, .. } => {
             let like_reg = if *not {
@@ -2032,8 +2301,82 @@ pub fn translate_expr(
         ast::Expr::Qualified(_, _) => {
             unreachable!("Qualified should be resolved to a Column before translation")
         }
-        ast::Expr::Raise(_, _) => todo!(),
-        ast::Expr::Subquery(_) => todo!(),
+        ast::Expr::Raise(resolve_type, message) => {
+            if program.active_triggers.is_empty() {
+                crate::bail_parse_error!("RAISE() may only be used within a trigger-program");
+            }
+            match resolve_type {
+                ast::ResolveType::Ignore => program.emit_halt_err(SQLITE_IGNORE, String::new()),
+                _ => {
+                    let Some(ast::Expr::Literal(ast::Literal::String(message))) =
+                        message.as_deref()
+                    else {
+                        crate::bail_parse_error!("the message of RAISE() must be a string literal");
+                    };
+                    program.emit_insn(Insn::Halt {
+                        err_code: SQLITE_CONSTRAINT_TRIGGER,
+                        description: sanitize_string(message),
+                        on_error: OnError::from_resolve_type(*resolve_type),
+                    });
+                }
+            }
+            Ok(target_register)
+        }
+        // Subqueries are planned as an ast::Expr::SubqueryResult where they are supported
+        ast::Expr::Exists(_)
+        | ast::Expr::InSelect { .. }
+        | ast::Expr::InTable { .. }
+        | ast::Expr::Subquery(_) => {
+            crate::bail_parse_error!("subqueries are not supported here")
+        }
+        ast::Expr::SubqueryResult {
+            subquery_id,
+            lhs,
+            not_in,
+            outer_refs,
+        } => translate_subquery_result(
+            program,
+            referenced_tables,
+            *subquery_id,
+            lhs.as_deref(),
+            *not_in,
+            outer_refs,
+            target_register,
+            resolver,
+        ),
+        ast::Expr::OuterRef(idx) => {
+            let Some(outer_refs_start_reg) = resolver.outer_refs_start_reg else {
+                unreachable!("OuterRef outside of a correlated subquery");
+            };
+            program.emit_insn(Insn::Copy {
+                src_reg: outer_refs_start_reg + *idx,
+                dst_reg: target_register,
+                amount: 0,
+            });
+            Ok(target_register)
+        }
+        ast::Expr::Register(reg) => {
+            program.emit_insn(Insn::Copy {
+                src_reg: *reg,
+                dst_reg: target_register,
+                amount: 0,
+            });
+            Ok(target_register)
+        }
+        ast::Expr::Param(offset) => {
+            match resolver.params_start_reg {
+                Some(params_start_reg) => program.emit_insn(Insn::Copy {
+                    src_reg: params_start_reg + *offset,
+                    dst_reg: target_register,
+                    amount: 0,
+                }),
+                None => program.emit_insn(Insn::Param {
+                    offset: *offset,
+                    dest: target_register,
+                }),
+            }
+            Ok(target_register)
+        }
         ast::Expr::Unary(op, expr) => match (op, expr.as_ref()) {
             (
                 UnaryOperator::Negative | UnaryOperator::Positive,
@@ -2148,6 +2491,170 @@ pub fn translate_expr(
 /// The logic for handling "NOT LIKE" is different depending on whether the expression
 /// is a conditional jump or not. This is why the caller handles the "NOT LIKE" behavior;
 /// see [translate_condition_expr] and [translate_expr] for implementations.
+/// Runs the coroutine of a subquery used in an expression and stores its result in `target_register`:
+/// - a scalar subquery results in the first column of its first row, or NULL if it returns no rows.
+/// - EXISTS results in 1 if the subquery returns any rows, 0 otherwise.
+/// - `lhs IN (...)` results in 1 if any row is equal to lhs. Otherwise it results in NULL if
+///   lhs or any row is NULL, and 0 if not. NOT IN negates the result.
+#[allow(clippy::too_many_arguments)]
+fn translate_subquery_result(
+    program: &mut ProgramBuilder,
+    referenced_tables: Option<&[TableReference]>,
+    subquery_id: usize,
+    lhs: Option<&ast::Expr>,
+    not_in: bool,
+    outer_refs: &[ast::Expr],
+    target_register: usize,
+    resolver: &Resolver,
+) -> Result<usize> {
+    let subquery = resolver.subqueries[subquery_id];
+    // Pass the values of this query that a correlated subquery refers to.
+    if let Some(outer_refs_start_reg) = subquery.outer_refs_start_reg {
+        for (i, expr) in outer_refs.iter().enumerate() {
+            translate_expr(
+                program,
+                referenced_tables,
+                expr,
+                outer_refs_start_reg + i,
+                resolver,
+            )?;
+        }
+    }
+    let lhs_reg = match lhs {
+        Some(lhs) => {
+            let lhs_reg = program.alloc_register();
+            Some(translate_expr(
+                program,
+                referenced_tables,
+                lhs,
+                lhs_reg,
+                resolver,
+            )?)
+        }
+        None => None,
+    };
+    // A subquery that is not correlated only runs the first time, after that its result is copied.
+    let label_cached = subquery.reg_once.map(|reg_once| {
+        let label_cached = program.allocate_label();
+        program.emit_insn(Insn::If {
+            reg: reg_once,
+            target_pc: label_cached,
+            jump_if_null: false,
+        });
+        program.emit_insn(Insn::Integer {
+            value: 1,
+            dest: reg_once,
+        });
+        label_cached
+    });
+    // Otherwise the subquery is run from the start every time the expression is evaluated.
+    program.emit_insn(Insn::InitCoroutine {
+        yield_reg: subquery.yield_reg,
+        jump_on_definition: BranchOffset::Offset(0),
+        start_offset: subquery.coroutine_implementation_start,
+    });
+    let label_done = program.allocate_label();
+    match subquery.subquery_type {
+        SubqueryType::Scalar => {
+            program.emit_insn(Insn::Null {
+                dest: target_register,
+                dest_end: None,
+            });
+            program.emit_insn(Insn::Yield {
+                yield_reg: subquery.yield_reg,
+                end_offset: label_done,
+            });
+            program.emit_insn(Insn::Copy {
+                src_reg: subquery.result_columns_start_reg,
+                dst_reg: target_register,
+                amount: 0,
+            });
+        }
+        SubqueryType::Exists => {
+            program.emit_insn(Insn::Integer {
+                value: 0,
+                dest: target_register,
+            });
+            program.emit_insn(Insn::Yield {
+                yield_reg: subquery.yield_reg,
+                end_offset: label_done,
+            });
+            program.emit_insn(Insn::Integer {
+                value: 1,
+                dest: target_register,
+            });
+        }
+        SubqueryType::In => {
+            let lhs_reg = lhs_reg.expect("IN subquery must have a left-hand side");
+            let rhs_reg = subquery.result_columns_start_reg;
+            let label_next_row = program.allocate_label();
+            let label_null = program.allocate_label();
+            program.emit_insn(Insn::Integer {
+                value: not_in as i64,
+                dest: target_register,
+            });
+            program.resolve_label(label_next_row, program.offset());
+            program.emit_insn(Insn::Yield {
+                yield_reg: subquery.yield_reg,
+                end_offset: label_done,
+            });
+            program.emit_insn(Insn::IsNull {
+                reg: lhs_reg,
+                target_pc: label_null,
+            });
+            program.emit_insn(Insn::IsNull {
+                reg: rhs_reg,
+                target_pc: label_null,
+            });
+            program.emit_insn(Insn::Ne {
+                lhs: lhs_reg,
+                rhs: rhs_reg,
+                target_pc: label_next_row,
+                flags: CmpInsFlags::default(),
+                collation: lhs
+                    .and_then(|lhs| expr_collation(lhs, referenced_tables.unwrap_or_default())),
+            });
+            // A matching row decides the result, so the rest of the rows are not needed.
+            program.emit_insn(Insn::Integer {
+                value: !not_in as i64,
+                dest: target_register,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: label_done,
+            });
+            // Without a matching row, a NULL makes the result NULL.
+            program.resolve_label(label_null, program.offset());
+            program.emit_insn(Insn::Null {
+                dest: target_register,
+                dest_end: None,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: label_next_row,
+            });
+        }
+    }
+    program.resolve_label(label_done, program.offset());
+    if let (Some(reg_once), Some(label_cached)) = (subquery.reg_once, label_cached) {
+        let label_end = program.allocate_label();
+        program.emit_insn(Insn::Copy {
+            src_reg: target_register,
+            dst_reg: reg_once + 1,
+            amount: 0,
+        });
+        program.emit_insn(Insn::Goto {
+            target_pc: label_end,
+        });
+        program.resolve_label(label_cached, program.offset());
+        program.emit_insn(Insn::Copy {
+            src_reg: reg_once + 1,
+            dst_reg: target_register,
+            amount: 0,
+        });
+        program.resolve_label(label_end, program.offset());
+    }
+    Ok(target_register)
+}
+
 fn translate_like_base(
     program: &mut ProgramBuilder,
     referenced_tables: Option<&[TableReference]>,
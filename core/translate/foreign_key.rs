@@ -0,0 +1,798 @@
+//! Foreign keys.
+//!
+//! When PRAGMA foreign_keys is on, the statements that change a table check the FOREIGN KEY
+//! constraints the table is the child or the parent of. As in SQLite, a check does not fail right
+//! away: a change that leaves a row of a child table without a parent row adds one to a count of
+//! violations, and a change that gives such a row its parent row back subtracts one. A statement
+//! fails if it ends with violations of immediate constraints, and COMMIT fails if the transaction
+//! has violations of deferred constraints, see the FkCounter instruction.
+//!
+//! The rows are passed to the checks in the layout of the OLD and NEW rows of triggers: the rowid
+//! followed by the columns. The ON DELETE and ON UPDATE actions that change the child table are
+//! compiled into sub-programs, like the commands of triggers, which read the OLD and NEW rows of the
+//! parent table with the Param instruction.
+
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast;
+
+use crate::error::SQLITE_CONSTRAINT_FOREIGNKEY;
+use crate::schema::{quote_ident, BTreeTable, ForeignKey, Index, Schema, MAIN_DB};
+use crate::translate::collate::CollationSeq;
+use crate::translate::delete::prepare_delete_plan;
+use crate::translate::emitter::emit_program;
+use crate::translate::optimizer::optimize_plan;
+use crate::translate::plan::Plan;
+use crate::translate::trigger::new_row_params;
+use crate::translate::update::prepare_update_plan;
+use crate::util::normalize_ident;
+use crate::vdbe::builder::{CursorType, ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::vdbe::insn::{CmpInsFlags, Insn, OnError};
+use crate::vdbe::{BranchOffset, CursorID};
+use crate::{bail_parse_error, Result, SymbolTable};
+
+/// How the rows of the parent table are looked up by their key.
+enum ParentKey {
+    Rowid,
+    /// The key of a unique index, or the primary key of a WITHOUT ROWID table.
+    Index(Rc<Index>),
+}
+
+/// A foreign key, with the columns of its parent key resolved.
+struct ResolvedForeignKey {
+    child: Rc<BTreeTable>,
+    id: usize,
+    parent: Rc<BTreeTable>,
+    /// The columns of the parent table in the parent key, in the order of the key.
+    parent_columns: Vec<usize>,
+    /// The columns of the child table that reference the parent key, in the order of the key.
+    child_columns: Vec<usize>,
+    key: ParentKey,
+}
+
+impl ResolvedForeignKey {
+    fn foreign_key(&self) -> &ForeignKey {
+        &self.child.foreign_keys[self.id]
+    }
+
+    fn deferred(&self) -> bool {
+        self.foreign_key().deferred
+    }
+
+    fn references_itself(&self) -> bool {
+        self.child.db == self.parent.db
+            && normalize_ident(&self.child.name) == normalize_ident(&self.parent.name)
+    }
+
+    /// The collation the values of the key column `k` are compared with.
+    fn collation(&self, k: usize) -> Option<CollationSeq> {
+        self.parent.columns[self.parent_columns[k]]
+            .collation
+            .clone()
+    }
+}
+
+/// Resolves the foreign key `id` of the child table. Returns None if the parent table does not
+/// exist, and fails if the parent key is neither the primary key nor a unique key of the parent
+/// table.
+fn resolve_foreign_key(
+    schema: &Schema,
+    child: &Rc<BTreeTable>,
+    id: usize,
+) -> Result<Option<ResolvedForeignKey>> {
+    let foreign_key = &child.foreign_keys[id];
+    let Some(parent) = schema
+        .get_table_in(child.db, &foreign_key.parent_table)
+        .and_then(|table| table.btree())
+    else {
+        return Ok(None);
+    };
+    let mismatch = || {
+        crate::LimboError::ParseError(format!(
+            "foreign key mismatch - \"{}\" referencing \"{}\"",
+            child.name, parent.name
+        ))
+    };
+    let parent_names = if foreign_key.parent_columns.is_empty() {
+        parent
+            .primary_key_columns
+            .iter()
+            .map(|(name, _)| normalize_ident(name))
+            .collect()
+    } else {
+        foreign_key.parent_columns.clone()
+    };
+    if parent_names.is_empty() || parent_names.len() != foreign_key.child_columns.len() {
+        return Err(mismatch());
+    }
+    let mut parent_columns = vec![];
+    for name in &parent_names {
+        let (column, _) = parent.get_column(name).ok_or_else(mismatch)?;
+        parent_columns.push(column);
+    }
+
+    let (key, child_names) =
+        if parent_columns.len() == 1 && parent.columns[parent_columns[0]].is_rowid_alias {
+            (ParentKey::Rowid, foreign_key.child_columns.clone())
+        } else {
+            let same_columns = |index: &Index| {
+                index.columns.len() == parent_names.len()
+                    && index.columns.iter().all(|c| parent_names.contains(&c.name))
+            };
+            let index = parent
+                .primary_key_index()
+                .filter(|index| same_columns(index))
+                .or_else(|| {
+                    schema
+                        .get_table_indices(&parent)
+                        .iter()
+                        .find(|index| index.unique && same_columns(index))
+                        .cloned()
+                })
+                .ok_or_else(mismatch)?;
+            // The key is looked up in the order of the columns of the index
+            let mut child_names = vec![];
+            let mut index_columns = vec![];
+            for column in &index.columns {
+                let k = parent_names.iter().position(|n| *n == column.name).unwrap();
+                child_names.push(foreign_key.child_columns[k].clone());
+                index_columns.push(parent_columns[k]);
+            }
+            parent_columns = index_columns;
+            (ParentKey::Index(index), child_names)
+        };
+    let child_columns = child_names
+        .iter()
+        .map(|name| child.get_column(name).map(|(column, _)| column))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(mismatch)?;
+    Ok(Some(ResolvedForeignKey {
+        child: child.clone(),
+        id,
+        parent,
+        parent_columns,
+        child_columns,
+        key,
+    }))
+}
+
+/// Resolves a foreign key for a statement that changes its child or parent table, which fails if
+/// the parent table does not exist.
+fn resolve_for_change(
+    schema: &Schema,
+    child: &Rc<BTreeTable>,
+    id: usize,
+) -> Result<ResolvedForeignKey> {
+    match resolve_foreign_key(schema, child, id)? {
+        Some(foreign_key) => Ok(foreign_key),
+        None => bail_parse_error!(
+            "no such table: {}.{}",
+            schema.database_name(child.db),
+            child.foreign_keys[id].parent_table
+        ),
+    }
+}
+
+/// Whether changes to `table` have FOREIGN KEY constraints to check, as the child or the parent
+/// table of a foreign key.
+pub fn has_foreign_keys(schema: &Schema, table: &BTreeTable) -> bool {
+    !table.foreign_keys.is_empty() || !schema.get_referencing_foreign_keys(table).is_empty()
+}
+
+/// Emits the checks of the FOREIGN KEY constraints for a change to a row of `table`, before it is
+/// written. The OLD and NEW rows start at `old_row_reg` and `new_row_reg`, in the layout of the
+/// parameters of trigger programs: a DELETE has only an OLD row, and an INSERT only a NEW row. An
+/// UPDATE only checks the foreign keys with columns in `changed_columns`.
+pub fn emit_fk_checks(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    table: &Rc<BTreeTable>,
+    old_row_reg: Option<usize>,
+    new_row_reg: Option<usize>,
+    changed_columns: Option<&[usize]>,
+) -> Result<()> {
+    let changes = |columns: &[usize]| {
+        changed_columns.is_none_or(|changed| columns.iter().any(|c| changed.contains(c)))
+    };
+    // The row as a child row, which needs a parent row
+    for id in 0..table.foreign_keys.len() {
+        let foreign_key = resolve_for_change(schema, table, id)?;
+        if !changes(&foreign_key.child_columns) {
+            continue;
+        }
+        if let Some(row_reg) = old_row_reg {
+            emit_parent_lookup(program, &foreign_key, row_reg, -1);
+        }
+        if let Some(row_reg) = new_row_reg {
+            emit_parent_lookup(program, &foreign_key, row_reg, 1);
+        }
+    }
+    // The row as a parent row, which child rows can reference
+    for (child, id) in schema.get_referencing_foreign_keys(table) {
+        let foreign_key = resolve_for_change(schema, &child, id)?;
+        if !changes(&foreign_key.parent_columns) {
+            continue;
+        }
+        if let Some(row_reg) = new_row_reg {
+            emit_count_children(program, &foreign_key, row_reg, -1);
+        }
+        if let Some(row_reg) = old_row_reg {
+            emit_count_children(program, &foreign_key, row_reg, 1);
+        }
+    }
+    Ok(())
+}
+
+/// Emits the ON DELETE or ON UPDATE actions of the foreign keys that reference `table`, for a row
+/// that was just deleted, or updated if `changed_columns` are the columns the UPDATE sets. The OLD
+/// and NEW rows are in the trigger parameters starting at `params_start_reg`.
+pub fn emit_fk_actions(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    table: &Rc<BTreeTable>,
+    params_start_reg: usize,
+    changed_columns: Option<&[usize]>,
+) -> Result<()> {
+    let num_columns = table.columns.len();
+    let new_row_reg = new_row_params(params_start_reg, num_columns);
+    for (child, id) in schema.get_referencing_foreign_keys(table) {
+        let foreign_key = resolve_for_change(schema, &child, id)?;
+        let action = match changed_columns {
+            Some(changed) => {
+                if !foreign_key
+                    .parent_columns
+                    .iter()
+                    .any(|c| changed.contains(c))
+                {
+                    continue;
+                }
+                foreign_key.foreign_key().on_update
+            }
+            None => foreign_key.foreign_key().on_delete,
+        };
+        let action_id = format!(
+            "{}.{}.{}",
+            child.name,
+            id,
+            if changed_columns.is_some() {
+                "update"
+            } else {
+                "delete"
+            }
+        );
+        let sub_program = match action {
+            ast::RefAct::NoAction => continue,
+            ast::RefAct::Restrict => None,
+            _ if program.fk_action.as_ref() == Some(&action_id) => None,
+            // An action that fires back its ancestor is not run again.
+            _ if program.active_fk_actions.contains(&action_id) => continue,
+            _ => Some(compile_fk_action(
+                program,
+                schema,
+                syms,
+                &foreign_key,
+                action,
+                changed_columns.is_some(),
+                &action_id,
+            )?),
+        };
+
+        let skip_label = program.allocate_label();
+        if changed_columns.is_some() {
+            // The action only applies when the parent key changes
+            let key_changed_label = program.allocate_label();
+            for (k, &column) in foreign_key.parent_columns.iter().enumerate() {
+                program.emit_insn(Insn::Ne {
+                    lhs: params_start_reg + 1 + column,
+                    rhs: new_row_reg + 1 + column,
+                    target_pc: key_changed_label,
+                    flags: CmpInsFlags::default().null_eq(),
+                    collation: foreign_key.collation(k),
+                });
+            }
+            program.emit_insn(Insn::Goto {
+                target_pc: skip_label,
+            });
+            program.resolve_label(key_changed_label, program.offset());
+        }
+        match (action, sub_program) {
+            (ast::RefAct::Restrict, _) => {
+                let key_reg = |k: usize| params_start_reg + 1 + foreign_key.parent_columns[k];
+                emit_child_scan(program, &foreign_key, key_reg, None, |program| {
+                    program.emit_insn(Insn::Halt {
+                        err_code: SQLITE_CONSTRAINT_FOREIGNKEY,
+                        description: String::new(),
+                        on_error: OnError::Abort,
+                    });
+                });
+            }
+            (_, sub_program) => {
+                let sub_program =
+                    sub_program.map(|sub_program| program.add_sub_program(sub_program));
+                program.emit_insn(Insn::Program {
+                    params_start_reg,
+                    num_params: 2 * (num_columns + 1),
+                    sub_program,
+                    ignore_jump: skip_label,
+                });
+            }
+        }
+        program.resolve_label(skip_label, program.offset());
+    }
+    Ok(())
+}
+
+/// Emits the DELETE of every row of `table` that DROP TABLE runs first when foreign keys are on, as
+/// SQLite does: the ON DELETE actions of the foreign keys that reference the table run, and the
+/// statement fails before the table is dropped if rows still reference it. Triggers do not fire.
+/// A table that is the child of deferred foreign keys only has its rows deleted if there are
+/// violations of deferred constraints, which the rows may be the cause of.
+pub fn emit_drop_table_delete(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    table: &BTreeTable,
+    defer_foreign_keys: bool,
+) -> Result<()> {
+    let skip_label = program.allocate_label();
+    if schema.get_referencing_foreign_keys(table).is_empty() {
+        if !defer_foreign_keys && !table.foreign_keys.iter().any(|fk| fk.deferred) {
+            return Ok(());
+        }
+        program.emit_insn(Insn::FkIfZero {
+            deferred: true,
+            target_pc: skip_label,
+        });
+    }
+
+    let mut sub_program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    sub_program.foreign_keys = true;
+    let tbl_name = ast::QualifiedName::fullname(
+        ast::Name(schema.database_name(table.db).to_string()),
+        ast::Name(quote_ident(&table.name)),
+    );
+    let mut plan = prepare_delete_plan(schema, &tbl_name, None, None, None, syms)?;
+    if let Plan::Delete(delete) = &mut plan {
+        delete.triggers.clear();
+    }
+    optimize_plan(&mut plan, schema)?;
+    emit_program(&mut sub_program, plan, schema, syms)?;
+    let sub_program = program.add_sub_program(sub_program);
+    program.emit_insn(Insn::Program {
+        params_start_reg: 0,
+        num_params: 0,
+        sub_program: Some(sub_program),
+        ignore_jump: skip_label,
+    });
+
+    // The changes to the schema can't be undone by the end of the statement, so the violations of
+    // immediate constraints are checked before
+    if !defer_foreign_keys {
+        let no_violations_label = program.allocate_label();
+        program.emit_insn(Insn::FkIfZero {
+            deferred: false,
+            target_pc: no_violations_label,
+        });
+        program.emit_insn(Insn::Halt {
+            err_code: SQLITE_CONSTRAINT_FOREIGNKEY,
+            description: String::new(),
+            on_error: OnError::Abort,
+        });
+        program.resolve_label(no_violations_label, program.offset());
+    }
+    program.resolve_label(skip_label, program.offset());
+    Ok(())
+}
+
+/// Compiles a CASCADE, SET NULL or SET DEFAULT action into a program that deletes or updates the
+/// rows of the child table that reference the OLD row of the parent table.
+fn compile_fk_action(
+    program: &ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    foreign_key: &ResolvedForeignKey,
+    action: ast::RefAct,
+    update: bool,
+    action_id: &str,
+) -> Result<ProgramBuilder> {
+    let mut sub_program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    sub_program.foreign_keys = true;
+    sub_program.active_triggers = program.active_triggers.clone();
+    sub_program.active_fk_actions = program.active_fk_actions.clone();
+    sub_program.active_fk_actions.push(action_id.to_string());
+    sub_program.fk_action = Some(action_id.to_string());
+
+    let child = &foreign_key.child;
+    let new_row_start = new_row_params(0, foreign_key.parent.columns.len());
+    let child_column_name = |k: usize| {
+        let column = &child.columns[foreign_key.child_columns[k]];
+        ast::Name(quote_ident(column.name.as_deref().unwrap_or_default()))
+    };
+    let where_clause = (0..foreign_key.child_columns.len())
+        .map(|k| {
+            ast::Expr::Binary(
+                Box::new(ast::Expr::Id(ast::Id(child_column_name(k).0))),
+                ast::Operator::Equals,
+                Box::new(ast::Expr::Param(1 + foreign_key.parent_columns[k])),
+            )
+        })
+        .reduce(|lhs, rhs| ast::Expr::Binary(Box::new(lhs), ast::Operator::And, Box::new(rhs)))
+        .map(Box::new);
+    let tbl_name = ast::QualifiedName::fullname(
+        ast::Name(schema.database_name(child.db).to_string()),
+        ast::Name(quote_ident(&child.name)),
+    );
+
+    let mut plan = if action == ast::RefAct::Cascade && !update {
+        prepare_delete_plan(schema, &tbl_name, where_clause, None, None, syms)?
+    } else {
+        let sets = (0..foreign_key.child_columns.len())
+            .map(|k| ast::Set {
+                col_names: ast::DistinctNames::single(child_column_name(k)),
+                expr: match action {
+                    ast::RefAct::Cascade => {
+                        ast::Expr::Param(new_row_start + 1 + foreign_key.parent_columns[k])
+                    }
+                    ast::RefAct::SetDefault => child.columns[foreign_key.child_columns[k]]
+                        .default
+                        .clone()
+                        .unwrap_or(ast::Expr::Literal(ast::Literal::Null)),
+                    _ => ast::Expr::Literal(ast::Literal::Null),
+                },
+            })
+            .collect();
+        prepare_update_plan(
+            schema,
+            ast::Update {
+                with: None,
+                or_conflict: None,
+                tbl_name,
+                indexed: None,
+                sets,
+                from: None,
+                where_clause,
+                returning: None,
+                order_by: None,
+                limit: None,
+            },
+            syms,
+        )?
+    };
+    optimize_plan(&mut plan, schema)?;
+    emit_program(&mut sub_program, plan, schema, syms)?;
+    Ok(sub_program)
+}
+
+/// Emits the lookup of the parent row of the child row starting at `row_reg`, which adds
+/// `increment` to the count of violations if there is none. A child row with a NULL in its
+/// columns needs no parent row.
+fn emit_parent_lookup(
+    program: &mut ProgramBuilder,
+    foreign_key: &ResolvedForeignKey,
+    row_reg: usize,
+    increment: i64,
+) {
+    let deferred = foreign_key.deferred();
+    let ok_label = program.allocate_label();
+    if increment < 0 {
+        program.emit_insn(Insn::FkIfZero {
+            deferred,
+            target_pc: ok_label,
+        });
+    }
+    let child_reg = |k: usize| row_reg + 1 + foreign_key.child_columns[k];
+    for k in 0..foreign_key.child_columns.len() {
+        program.emit_insn(Insn::IsNull {
+            reg: child_reg(k),
+            target_pc: ok_label,
+        });
+    }
+    if increment > 0 && foreign_key.references_itself() {
+        // The new row can be its own parent row, which is not in the table yet
+        let lookup_label = program.allocate_label();
+        for k in 0..foreign_key.child_columns.len() {
+            program.emit_insn(Insn::Ne {
+                lhs: child_reg(k),
+                rhs: row_reg + 1 + foreign_key.parent_columns[k],
+                target_pc: lookup_label,
+                flags: CmpInsFlags::default(),
+                collation: foreign_key.collation(k),
+            });
+        }
+        program.emit_insn(Insn::Goto {
+            target_pc: ok_label,
+        });
+        program.resolve_label(lookup_label, program.offset());
+    }
+    let not_found_label = program.allocate_label();
+    emit_parent_seek(program, foreign_key, child_reg, not_found_label);
+    program.emit_insn(Insn::Goto {
+        target_pc: ok_label,
+    });
+    program.resolve_label(not_found_label, program.offset());
+    program.emit_insn(Insn::FkCounter {
+        deferred,
+        increment,
+    });
+    program.resolve_label(ok_label, program.offset());
+}
+
+/// Emits the seek of the parent row whose key is in the registers `child_reg(k)`, which jumps to
+/// `not_found_label` if there is none.
+fn emit_parent_seek(
+    program: &mut ProgramBuilder,
+    foreign_key: &ResolvedForeignKey,
+    child_reg: impl Fn(usize) -> usize,
+    not_found_label: BranchOffset,
+) {
+    match &foreign_key.key {
+        ParentKey::Rowid => {
+            let cursor_id = emit_open_read(
+                program,
+                CursorType::BTreeTable(foreign_key.parent.clone()),
+                foreign_key.parent.root_page,
+            );
+            program.emit_insn(Insn::SeekRowid {
+                cursor_id,
+                src_reg: child_reg(0),
+                target_pc: not_found_label,
+            });
+        }
+        ParentKey::Index(index) => {
+            let cursor_id = emit_open_read(
+                program,
+                CursorType::BTreeIndex(index.clone()),
+                index.root_page,
+            );
+            let num_regs = foreign_key.child_columns.len();
+            let key_reg = program.alloc_registers(num_regs);
+            for k in 0..num_regs {
+                program.emit_insn(Insn::Copy {
+                    src_reg: child_reg(k),
+                    dst_reg: key_reg + k,
+                    amount: 0,
+                });
+            }
+            program.emit_insn(Insn::NoConflict {
+                cursor_id,
+                target_pc: not_found_label,
+                record_reg: key_reg,
+                num_regs,
+            });
+        }
+    }
+}
+
+/// Emits the count of the child rows that reference the parent row starting at `row_reg`, which
+/// adds `increment` to the count of violations for each of them. When a row of a table that
+/// references itself is deleted, its reference to itself goes away with it.
+fn emit_count_children(
+    program: &mut ProgramBuilder,
+    foreign_key: &ResolvedForeignKey,
+    row_reg: usize,
+    increment: i64,
+) {
+    let deferred = foreign_key.deferred();
+    let done_label = program.allocate_label();
+    if increment < 0 {
+        program.emit_insn(Insn::FkIfZero {
+            deferred,
+            target_pc: done_label,
+        });
+    }
+    let exclude_rowid_reg = (increment > 0 && foreign_key.references_itself()).then_some(row_reg);
+    let key_reg = |k: usize| row_reg + 1 + foreign_key.parent_columns[k];
+    emit_child_scan(
+        program,
+        foreign_key,
+        key_reg,
+        exclude_rowid_reg,
+        |program| {
+            program.emit_insn(Insn::FkCounter {
+                deferred,
+                increment,
+            });
+        },
+    );
+    program.resolve_label(done_label, program.offset());
+}
+
+/// Emits a scan of the child table for the rows that reference the parent key in the registers
+/// `key_reg(k)`, except the one whose rowid is in `exclude_rowid_reg`, and emits `on_match` for
+/// each of them.
+fn emit_child_scan(
+    program: &mut ProgramBuilder,
+    foreign_key: &ResolvedForeignKey,
+    key_reg: impl Fn(usize) -> usize,
+    exclude_rowid_reg: Option<usize>,
+    on_match: impl FnOnce(&mut ProgramBuilder),
+) {
+    let child = &foreign_key.child;
+    let done_label = program.allocate_label();
+    for k in 0..foreign_key.parent_columns.len() {
+        program.emit_insn(Insn::IsNull {
+            reg: key_reg(k),
+            target_pc: done_label,
+        });
+    }
+    let cursor_id = emit_open_read(
+        program,
+        CursorType::for_table(child.clone()),
+        child.root_page,
+    );
+    program.emit_insn(Insn::RewindAsync { cursor_id });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id,
+        pc_if_empty: done_label,
+    });
+    let loop_start = program.offset();
+    let next_label = program.allocate_label();
+    let value_reg = program.alloc_register();
+    for (k, &column) in foreign_key.child_columns.iter().enumerate() {
+        emit_child_column(program, child, cursor_id, column, value_reg);
+        program.emit_insn(Insn::Ne {
+            lhs: value_reg,
+            rhs: key_reg(k),
+            target_pc: next_label,
+            flags: CmpInsFlags::default().jump_if_null(),
+            collation: foreign_key.collation(k),
+        });
+    }
+    if let (Some(rowid_reg), true) = (exclude_rowid_reg, child.has_rowid) {
+        program.emit_insn(Insn::RowId {
+            cursor_id,
+            dest: value_reg,
+        });
+        program.emit_insn(Insn::Eq {
+            lhs: value_reg,
+            rhs: rowid_reg,
+            target_pc: next_label,
+            flags: CmpInsFlags::default(),
+            collation: None,
+        });
+    }
+    on_match(program);
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync { cursor_id });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(done_label, program.offset());
+}
+
+fn emit_open_read(
+    program: &mut ProgramBuilder,
+    cursor_type: CursorType,
+    root_page: usize,
+) -> CursorID {
+    let cursor_id = program.alloc_cursor_id(None, cursor_type);
+    program.emit_insn(Insn::OpenReadAsync {
+        cursor_id,
+        root_page,
+    });
+    program.emit_insn(Insn::OpenReadAwait {});
+    cursor_id
+}
+
+/// Reads a column of the row a cursor on the child table points to.
+fn emit_child_column(
+    program: &mut ProgramBuilder,
+    child: &BTreeTable,
+    cursor_id: CursorID,
+    column: usize,
+    dest: usize,
+) {
+    if child.columns[column].is_rowid_alias {
+        program.emit_insn(Insn::RowId { cursor_id, dest });
+    } else {
+        program.emit_insn(Insn::Column {
+            cursor_id,
+            column: child.column_record_position(column),
+            dest,
+        });
+    }
+}
+
+/// Emits the program of PRAGMA foreign_key_check, which returns the table, the rowid, the parent
+/// table and the foreign key id of each row of `table`, or of every table of the main database,
+/// that has no parent row.
+pub fn emit_foreign_key_check(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    table: Option<&str>,
+) -> Result<()> {
+    let tables = match table {
+        Some(name) => match schema.get_table_in(MAIN_DB, name).and_then(|t| t.btree()) {
+            Some(table) => vec![table],
+            None => bail_parse_error!("no such table: {}", name),
+        },
+        None => {
+            let mut tables = schema
+                .database(MAIN_DB)
+                .tables
+                .values()
+                .filter_map(|table| table.btree())
+                .filter(|table| !table.foreign_keys.is_empty())
+                .collect::<Vec<_>>();
+            tables.sort_by(|a, b| a.name.cmp(&b.name));
+            tables
+        }
+    };
+    let result_reg = program.alloc_registers(4);
+    for child in tables {
+        for id in 0..child.foreign_keys.len() {
+            let foreign_key = resolve_foreign_key(schema, &child, id)?;
+            let child_columns = match &foreign_key {
+                Some(foreign_key) => foreign_key.child_columns.clone(),
+                // Every row references a missing parent row, unless it has a NULL in its columns
+                None => child.foreign_keys[id]
+                    .child_columns
+                    .iter()
+                    .filter_map(|name| child.get_column(name).map(|(column, _)| column))
+                    .collect(),
+            };
+            let done_label = program.allocate_label();
+            let cursor_id = emit_open_read(
+                program,
+                CursorType::for_table(child.clone()),
+                child.root_page,
+            );
+            program.emit_insn(Insn::RewindAsync { cursor_id });
+            program.emit_insn(Insn::RewindAwait {
+                cursor_id,
+                pc_if_empty: done_label,
+            });
+            let loop_start = program.offset();
+            let next_label = program.allocate_label();
+            let values_reg = program.alloc_registers(child.columns.len());
+            for &column in &child_columns {
+                emit_child_column(program, &child, cursor_id, column, values_reg + column);
+                program.emit_insn(Insn::IsNull {
+                    reg: values_reg + column,
+                    target_pc: next_label,
+                });
+            }
+            if let Some(foreign_key) = &foreign_key {
+                let not_found_label = program.allocate_label();
+                let child_reg = |k: usize| values_reg + foreign_key.child_columns[k];
+                emit_parent_seek(program, foreign_key, child_reg, not_found_label);
+                program.emit_insn(Insn::Goto {
+                    target_pc: next_label,
+                });
+                program.resolve_label(not_found_label, program.offset());
+            }
+            program.emit_string8(child.name.clone(), result_reg);
+            if child.has_rowid {
+                program.emit_insn(Insn::RowId {
+                    cursor_id,
+                    dest: result_reg + 1,
+                });
+            } else {
+                program.emit_null(result_reg + 1);
+            }
+            program.emit_string8(child.foreign_keys[id].parent_table.clone(), result_reg + 2);
+            program.emit_int(id as i64, result_reg + 3);
+            program.emit_result_row(result_reg, 4);
+            program.resolve_label(next_label, program.offset());
+            program.emit_insn(Insn::NextAsync { cursor_id });
+            program.emit_insn(Insn::NextAwait {
+                cursor_id,
+                pc_if_next: loop_start,
+            });
+            program.resolve_label(done_label, program.offset());
+        }
+    }
+    Ok(())
+}
@@ -15,6 +15,7 @@ use crate::{
 };
 
 use super::{
+    collate::expr_collation,
     emitter::{Resolver, TranslateCtx},
     expr::{translate_condition_expr, translate_expr, ConditionMetadata},
     order_by::order_by_sorter_insert,
@@ -51,6 +52,7 @@ pub fn init_group_by(
     t_ctx: &mut TranslateCtx,
     group_by: &GroupBy,
     aggregates: &[Aggregate],
+    tables: &[TableReference],
 ) -> Result<()> {
     let num_aggs = aggregates.len();
 
@@ -73,6 +75,11 @@ pub fn init_group_by(
         cursor_id: sort_cursor,
         columns: aggregates.len() + group_by.exprs.len(),
         order: Record::new(order),
+        collations: group_by
+            .exprs
+            .iter()
+            .map(|expr| expr_collation(expr, tables))
+            .collect(),
     });
 
     program.add_comment(program.offset(), "clear group by abort flag");
@@ -156,13 +163,13 @@ pub fn emit_group_by<'a>(
 
     let group_by = plan.group_by.as_ref().unwrap();
 
-    // all group by columns and all arguments of agg functions are in the sorter.
+    // all group by columns, all arguments of agg functions and the values of their FILTER conditions are in the sorter.
     // the sort keys are the group by columns (the aggregation within groups is done based on how long the sort keys remain the same)
     let sorter_column_count = group_by.exprs.len()
         + plan
             .aggregates
             .iter()
-            .map(|agg| agg.args.len())
+            .map(|agg| agg.num_sorter_columns())
             .sum::<usize>();
     // sorter column names do not matter
     let ty = crate::schema::Type::Null;
@@ -175,6 +182,7 @@ pub fn emit_group_by<'a>(
             is_rowid_alias: false,
             notnull: false,
             default: None,
+            collation: None,
         })
         .collect::<Vec<_>>();
 
@@ -222,6 +230,11 @@ pub fn emit_group_by<'a>(
         start_reg_a: reg_group_exprs_cmp,
         start_reg_b: groups_start_reg,
         count: group_by.exprs.len(),
+        collations: group_by
+            .exprs
+            .iter()
+            .map(|expr| expr_collation(expr, &plan.table_references))
+            .collect(),
     });
 
     let agg_step_label = program.allocate_label();
@@ -272,6 +285,21 @@ pub fn emit_group_by<'a>(
     let mut cursor_index = group_by.exprs.len();
     for (i, agg) in plan.aggregates.iter().enumerate() {
         let agg_result_reg = start_reg + i;
+        // Skip the row if the FILTER condition of the aggregate is not true
+        let label_skip_agg_step = program.allocate_label();
+        if agg.filter.is_some() {
+            let reg_filter = program.alloc_register();
+            program.emit_insn(Insn::Column {
+                cursor_id: pseudo_cursor,
+                column: cursor_index + agg.args.len(),
+                dest: reg_filter,
+            });
+            program.emit_insn(Insn::IfNot {
+                reg: reg_filter,
+                target_pc: label_skip_agg_step,
+                jump_if_null: true,
+            });
+        }
         translate_aggregation_step_groupby(
             program,
             &plan.table_references,
@@ -281,7 +309,8 @@ pub fn emit_group_by<'a>(
             agg_result_reg,
             &t_ctx.resolver,
         )?;
-        cursor_index += agg.args.len();
+        program.resolve_label(label_skip_agg_step, program.offset());
+        cursor_index += agg.num_sorter_columns();
     }
 
     // We only emit the group by columns if we are going to start a new group (i.e. the prev group will not accumulate any more values into the aggregations)
@@ -470,6 +499,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Avg,
+                collation: None,
             });
             target_register
         }
@@ -485,6 +515,7 @@ pub fn translate_aggregation_step_groupby(
                 } else {
                     AggFunc::Count
                 },
+                collation: None,
             });
             target_register
         }
@@ -526,6 +557,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: delimiter_reg,
                 func: AggFunc::GroupConcat,
+                collation: None,
             });
 
             target_register
@@ -541,6 +573,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Max,
+                collation: expr_collation(&agg.args[0], referenced_tables),
             });
             target_register
         }
@@ -555,6 +588,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Min,
+                collation: expr_collation(&agg.args[0], referenced_tables),
             });
             target_register
         }
@@ -588,6 +622,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: delimiter_reg,
                 func: AggFunc::StringAgg,
+                collation: None,
             });
 
             target_register
@@ -603,6 +638,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Sum,
+                collation: None,
             });
             target_register
         }
@@ -617,6 +653,7 @@ pub fn translate_aggregation_step_groupby(
                 col: expr_reg,
                 delimiter: 0,
                 func: AggFunc::Total,
+                collation: None,
             });
             target_register
         }
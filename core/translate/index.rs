@@ -0,0 +1,660 @@
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast::{self, SortOrder, SortedColumn};
+
+use crate::error::SQLITE_CONSTRAINT_UNIQUE;
+use crate::schema::{BTreeTable, Index, IndexColumn, Order, Schema};
+use crate::translate::collate::CollationSeq;
+use crate::util::{normalize_ident, PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX};
+use crate::vdbe::builder::{CursorType, ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::vdbe::insn::{CmpInsFlags, Insn, RegisterOrLiteral};
+use crate::vdbe::CursorID;
+use crate::{bail_parse_error, LimboError, Result, SymbolTable};
+
+use super::{
+    emit_schema_entry, schema_table, SchemaEntryType, SQLITE_TABLEID, SQLITE_TEMP_TABLEID,
+};
+
+/// Where the values of an index key are read from.
+#[derive(Debug, Clone, Copy)]
+pub enum IndexKeySource {
+    /// A row of the table laid out in consecutive registers starting at `columns_start`,
+    /// with its rowid in `rowid_reg`.
+    Registers {
+        columns_start: usize,
+        rowid_reg: usize,
+    },
+    /// The row the table cursor is positioned on.
+    Cursor(CursorID),
+}
+
+/// The number of values in a key of `index`: the indexed columns followed by the rowid,
+/// or by the primary key columns missing from the index for a WITHOUT ROWID table.
+pub fn index_key_len(table: &BTreeTable, index: &Index) -> usize {
+    if table.has_rowid {
+        index.columns.len() + 1
+    } else {
+        index.columns.len() + table.primary_key_columns_not_in(index).len()
+    }
+}
+
+/// Emit the instructions that copy the key of `index` for a single table row into
+/// consecutive registers: the indexed columns in index order followed by the rowid,
+/// or by the primary key columns missing from the index for a WITHOUT ROWID table.
+/// Returns the first of those registers.
+pub fn emit_index_key(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    index: &Index,
+    source: IndexKeySource,
+) -> Result<usize> {
+    let key_start = program.alloc_registers(index_key_len(table, index));
+    let mut key_columns = Vec::with_capacity(index.columns.len());
+    for index_column in index.columns.iter() {
+        let Some((column_idx, _)) = table.get_column(&index_column.name) else {
+            return Err(LimboError::InternalError(format!(
+                "column {} of index {} not found in table {}",
+                index_column.name, index.name, table.name
+            )));
+        };
+        key_columns.push(column_idx);
+    }
+    key_columns.extend(table.primary_key_columns_not_in(index));
+    for (i, &column_idx) in key_columns.iter().enumerate() {
+        let column = &table.columns[column_idx];
+        let dest = key_start + i;
+        match source {
+            // The rowid alias column is stored as NULL, its value is the rowid.
+            IndexKeySource::Registers { rowid_reg, .. } if column.is_rowid_alias => {
+                program.emit_insn(Insn::Copy {
+                    src_reg: rowid_reg,
+                    dst_reg: dest,
+                    amount: 0,
+                });
+            }
+            IndexKeySource::Registers { columns_start, .. } => {
+                program.emit_insn(Insn::Copy {
+                    src_reg: columns_start + column_idx,
+                    dst_reg: dest,
+                    amount: 0,
+                });
+            }
+            IndexKeySource::Cursor(cursor_id) if column.is_rowid_alias => {
+                program.emit_insn(Insn::RowId { cursor_id, dest });
+            }
+            IndexKeySource::Cursor(cursor_id) => {
+                program.emit_insn(Insn::Column {
+                    cursor_id,
+                    column: table.column_record_position(column_idx),
+                    dest,
+                });
+            }
+        }
+    }
+    if !table.has_rowid {
+        return Ok(key_start);
+    }
+    let rowid_dest = key_start + index.columns.len();
+    match source {
+        IndexKeySource::Registers { rowid_reg, .. } => {
+            program.emit_insn(Insn::Copy {
+                src_reg: rowid_reg,
+                dst_reg: rowid_dest,
+                amount: 0,
+            });
+        }
+        IndexKeySource::Cursor(cursor_id) => {
+            program.emit_insn(Insn::RowId {
+                cursor_id,
+                dest: rowid_dest,
+            });
+        }
+    }
+    Ok(key_start)
+}
+
+/// Open a write cursor on each of the given indexes and return the cursor ids in the same order.
+/// An index that already has a cursor open under its name, e.g. because the statement searches it,
+/// keeps using that cursor.
+pub fn emit_open_index_cursors(
+    program: &mut ProgramBuilder,
+    indexes: &[Rc<Index>],
+) -> Vec<CursorID> {
+    indexes
+        .iter()
+        .map(|index| {
+            let existing = program.cursor_ref.iter().position(|(name, cursor_type)| {
+                cursor_type.is_index() && name.as_deref() == Some(index.name.as_str())
+            });
+            if let Some(cursor_id) = existing {
+                return cursor_id;
+            }
+            let cursor_id = program.alloc_cursor_id(
+                Some(index.name.clone()),
+                CursorType::BTreeIndex(index.clone()),
+            );
+            program.emit_insn(Insn::OpenWriteAsync {
+                cursor_id,
+                root_page: index.root_page.into(),
+            });
+            program.emit_insn(Insn::OpenWriteAwait {});
+            cursor_id
+        })
+        .collect()
+}
+
+/// Emit a check that no other row of the table has the same values in the columns of a UNIQUE
+/// index as the key in the registers starting at `key_start`, and halt with a constraint error
+/// if one does. Keys containing NULLs never conflict. If `own_rowid_reg` is given, an entry
+/// belonging to that rowid is the row itself and not a conflict.
+pub fn emit_unique_check(
+    program: &mut ProgramBuilder,
+    index: &Index,
+    index_cursor_id: CursorID,
+    key_start: usize,
+    own_rowid_reg: Option<usize>,
+) {
+    debug_assert!(index.unique);
+    let no_conflict_label = program.allocate_label();
+    program.emit_insn(Insn::NoConflict {
+        cursor_id: index_cursor_id,
+        target_pc: no_conflict_label,
+        record_reg: key_start,
+        num_regs: index.columns.len(),
+    });
+    if let Some(own_rowid_reg) = own_rowid_reg {
+        let conflicting_rowid_reg = program.alloc_register();
+        program.emit_insn(Insn::IdxRowId {
+            cursor_id: index_cursor_id,
+            dest: conflicting_rowid_reg,
+        });
+        program.emit_insn(Insn::Eq {
+            lhs: conflicting_rowid_reg,
+            rhs: own_rowid_reg,
+            target_pc: no_conflict_label,
+            flags: CmpInsFlags::default(),
+            collation: None,
+        });
+    }
+    program.emit_halt_err(
+        SQLITE_CONSTRAINT_UNIQUE,
+        unique_constraint_description(index),
+    );
+    program.resolve_label(no_conflict_label, program.offset());
+}
+
+/// The columns of a UNIQUE index, as reported when the index constraint fails.
+pub fn unique_constraint_description(index: &Index) -> String {
+    index
+        .columns
+        .iter()
+        .map(|column| format!("{}.{}", index.table_name, column.name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Emit the insertion of the key in the registers starting at `key_start` into an index.
+pub fn emit_index_insert(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    index: &Index,
+    index_cursor_id: CursorID,
+    key_start: usize,
+) {
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: key_start,
+        count: index_key_len(table, index),
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::IdxInsertAsync {
+        cursor_id: index_cursor_id,
+        record_reg,
+        nchange: false,
+    });
+    program.emit_insn(Insn::IdxInsertAwait {
+        cursor_id: index_cursor_id,
+    });
+}
+
+/// Emit the deletion of the key in the registers starting at `key_start` from an index.
+pub fn emit_index_delete(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    index: &Index,
+    index_cursor_id: CursorID,
+    key_start: usize,
+) {
+    program.emit_insn(Insn::IdxDelete {
+        cursor_id: index_cursor_id,
+        start_reg: key_start,
+        num_regs: index_key_len(table, index),
+    });
+}
+
+fn create_index_to_str(
+    unique: bool,
+    idx_name: &str,
+    tbl_name: &str,
+    columns: &[SortedColumn],
+) -> String {
+    let columns = columns
+        .iter()
+        .map(|column| match column.order {
+            Some(SortOrder::Asc) => format!("{} ASC", column.expr),
+            Some(SortOrder::Desc) => format!("{} DESC", column.expr),
+            None => column.expr.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "CREATE {}INDEX {} ON {} ({})",
+        if unique { "UNIQUE " } else { "" },
+        idx_name,
+        tbl_name,
+        columns
+    )
+}
+
+/// The index a CREATE INDEX statement defines.
+pub struct IndexDefinition<'a> {
+    pub unique: bool,
+    pub if_not_exists: bool,
+    pub idx_name: &'a ast::QualifiedName,
+    pub tbl_name: &'a ast::Name,
+    pub columns: &'a [SortedColumn],
+    pub where_clause: Option<&'a ast::Expr>,
+}
+
+/* Example:
+
+sqlite> EXPLAIN CREATE INDEX idx ON t (b);
+addr  opcode         p1    p2    p3    p4             p5  comment
+----  -------------  ----  ----  ----  -------------  --  -------------
+0     Init           0     33    0                    0   Start at 33
+...
+3     CreateBtree    0     2     2                    0   r[2]=root iDb=0 flags=2
+4     OpenWrite      0     1     0     5              0   root=1 iDb=0; sqlite_master
+5     NewRowid       0     1     0                    0   r[1]=rowid
+6     String8        0     3     0     index          0   r[3]='index'
+...
+10    String8        0     7     0     CREATE INDEX idx ON t (b) 0   r[7]='CREATE INDEX idx ON t (b)'
+11    MakeRecord     3     5     4     BBBDB          0   r[4]=mkrec(r[3..7])
+12    Insert         0     4     1                    24  intkey=r[1] data=r[4]
+13    SorterOpen     3     0     1     k(2,,)         0
+14    OpenRead       1     2     0     2              0   root=2 iDb=0; t
+15    Rewind         1     21    0                    0
+16      Column         1     1     9                    0   r[9]= cursor 1 column 1
+17      Rowid          1     10    0                    0   r[10]= rowid of 1
+18      MakeRecord     9     2     8                    0   r[8]=mkrec(r[9..10])
+19      SorterInsert   3     8     0                    0   key=r[8]
+20    Next           1     16    0                    1
+21    OpenWrite      2     2     0     k(2,,)         11  root=2 iDb=0
+22    SorterSort     3     27    0                    0
+23      SorterData     3     8     2                    0   r[8]=data
+24      SeekEnd        2     0     0                    0
+25      IdxInsert      2     8     0                    16  key=r[8]
+26    SorterNext     3     23    0                    0
+27    Close          1     0     0                    0
+28    Close          2     0     0                    0
+29    Close          3     0     0                    0
+30    SetCookie      0     1     2                    0
+31    ParseSchema    0     0     0     name='idx' AND type='index' 0
+32    Halt           0     0     0                    0
+33    Transaction    0     1     1     0              1   usesStmtJournal=1
+34    Goto           0     1     0                    0
+
+We insert the keys straight into the index instead of sorting them first, and only write the
+sqlite_schema entry once the index has been built, so that a UNIQUE violation found while building
+leaves nothing behind.
+*/
+pub fn translate_create_index(
+    query_mode: QueryMode,
+    schema: &Schema,
+    definition: IndexDefinition,
+    syms: &SymbolTable,
+) -> Result<ProgramBuilder> {
+    let IndexDefinition {
+        unique,
+        if_not_exists,
+        idx_name,
+        tbl_name,
+        columns,
+        where_clause,
+    } = definition;
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 3,
+        approx_num_insns: 40,
+        approx_num_labels: 4,
+    });
+    // The schema qualifier of the index name names the database of the table
+    let table = schema.resolve_table(&ast::QualifiedName {
+        db_name: idx_name.db_name.clone(),
+        name: tbl_name.clone(),
+        alias: None,
+    })?;
+    let Some(table) = table.and_then(|table| table.btree()) else {
+        if schema.get_view(&tbl_name.0).is_some() {
+            bail_parse_error!("views may not be indexed");
+        }
+        bail_parse_error!("no such table: {}", tbl_name.0);
+    };
+    let index_name = normalize_ident(&idx_name.name.0);
+    let database = schema.database(table.db);
+    if database.get_index_in(&index_name).is_some() {
+        if if_not_exists {
+            let init_label = program.emit_init();
+            let start_offset = program.offset();
+            program.emit_halt();
+            program.resolve_label(init_label, program.offset());
+            program.emit_transaction(true);
+            program.emit_constant_insns();
+            program.emit_goto(start_offset);
+            return Ok(program);
+        }
+        bail_parse_error!("index {} already exists", idx_name.name.0);
+    }
+    if database.tables.contains_key(&index_name) {
+        bail_parse_error!("there is already a table named {}", idx_name.name.0);
+    }
+    if index_name.starts_with("sqlite_") {
+        bail_parse_error!("object name reserved for internal use: {}", idx_name.name.0);
+    }
+    if table.name == SQLITE_TABLEID || table.name == SQLITE_TEMP_TABLEID {
+        bail_parse_error!("table {} may not be indexed", table.name);
+    }
+    if where_clause.is_some() {
+        bail_parse_error!("Partial indexes are not supported yet");
+    }
+
+    let mut index_columns = Vec::with_capacity(columns.len());
+    for column in columns {
+        let (expr, collation) = match &column.expr {
+            ast::Expr::Collate(expr, collation) => {
+                (expr.as_ref(), Some(CollationSeq::new(collation, syms)?))
+            }
+            expr => (expr, None),
+        };
+        let name = match expr {
+            ast::Expr::Id(name) => &name.0,
+            ast::Expr::Name(name) => &name.0,
+            _ => bail_parse_error!("Indexes on expressions are not supported yet"),
+        };
+        let Some((_, table_column)) = table.get_column(name) else {
+            bail_parse_error!("no such column: {}", name);
+        };
+        index_columns.push(IndexColumn {
+            name: normalize_ident(name),
+            order: match column.order {
+                Some(SortOrder::Desc) => Order::Descending,
+                _ => Order::Ascending,
+            },
+            collation: collation.or_else(|| table_column.collation.clone()),
+        });
+    }
+    if index_columns.len() >= 64 {
+        bail_parse_error!("too many columns on {}", idx_name.name.0);
+    }
+    // The root page is only known at runtime, the cursors read it from a register.
+    let index = Rc::new(Index {
+        db: table.db,
+        name: index_name.clone(),
+        table_name: table.name.clone(),
+        root_page: 0,
+        columns: index_columns,
+        unique,
+    });
+    let sql = create_index_to_str(unique, &idx_name.name.0, &tbl_name.0, columns);
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let index_root_reg = program.alloc_register();
+    program.emit_insn(Insn::CreateBtree {
+        db: table.db,
+        root: index_root_reg,
+        flags: 2, // Index leaf page
+    });
+
+    let index_cursor_id = program.alloc_cursor_id(
+        Some(index.name.clone()),
+        CursorType::BTreeIndex(index.clone()),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: index_cursor_id,
+        root_page: RegisterOrLiteral::Register(index_root_reg),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Fill the index with the rows that are already in the table
+    let table_cursor_id = program.alloc_cursor_id(
+        Some(table.name.clone()),
+        CursorType::for_table(table.clone()),
+    );
+    program.emit_insn(Insn::OpenReadAsync {
+        cursor_id: table_cursor_id,
+        root_page: table.root_page,
+    });
+    program.emit_insn(Insn::OpenReadAwait {});
+
+    let loop_end_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: table_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    let key_start = emit_index_key(
+        &mut program,
+        &table,
+        &index,
+        IndexKeySource::Cursor(table_cursor_id),
+    )?;
+    if unique {
+        emit_unique_check(&mut program, &index, index_cursor_id, key_start, None);
+    }
+    emit_index_insert(&mut program, &table, &index, index_cursor_id, key_start);
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: table_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+
+    // The index goes in the database of its table
+    let sqlite_schema = schema_table(table.db);
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(sqlite_schema.name.clone()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+    emit_schema_entry(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::Index,
+        &idx_name.name.0,
+        &table.name,
+        index_root_reg,
+        Some(sql),
+    );
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::ParseSchema {
+        db: table.db,
+        where_clause: format!("name = '{}' AND type = 'index'", idx_name.name.0),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
+
+/* Example:
+
+sqlite> EXPLAIN DROP INDEX idx;
+addr  opcode         p1    p2    p3    p4             p5  comment
+----  -------------  ----  ----  ----  -------------  --  -------------
+0     Init           0     16    0                    0   Start at 16
+...
+3     Null           0     1     0                    0   r[1]=NULL
+4     OpenWrite      0     1     0     5              0   root=1 iDb=0; sqlite_master
+5     Rewind         0     12    0                    0
+6       Column         0     1     2                    0   r[2]= cursor 0 column 1
+7       Ne             3     11    2     BINARY-8       82  if r[2]!=r[3] goto 11
+8       Column         0     0     2                    0   r[2]= cursor 0 column 0
+9       Ne             4     11    2     BINARY-8       82  if r[2]!=r[4] goto 11
+10      Delete         0     0     0     sqlite_master  2
+11    Next           0     6     0                    1
+12    Destroy        2     5     0                    0
+13    SetCookie      0     1     3                    0
+14    DropIndex      0     0     0     idx            0
+15    Halt           0     0     0                    0
+16    Transaction    0     1     2     0              1   usesStmtJournal=1
+17    String8        0     3     0     idx            0   r[3]='idx'
+18    String8        0     4     0     index          0   r[4]='index'
+19    Goto           0     1     0                    0
+
+We match the sqlite_schema row on its root page rather than on its name, since the name is
+stored as it was written in the CREATE INDEX statement.
+*/
+pub fn translate_drop_index(
+    query_mode: QueryMode,
+    schema: &Schema,
+    if_exists: bool,
+    idx_name: &ast::QualifiedName,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    let Some(index) = schema.resolve_index(idx_name)? else {
+        if if_exists {
+            let init_label = program.emit_init();
+            let start_offset = program.offset();
+            program.emit_halt();
+            program.resolve_label(init_label, program.offset());
+            program.emit_transaction(true);
+            program.emit_constant_insns();
+            program.emit_goto(start_offset);
+            return Ok(program);
+        }
+        bail_parse_error!("no such index: {}", idx_name.name.0);
+    };
+    if index
+        .name
+        .starts_with(PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX)
+    {
+        bail_parse_error!(
+            "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+        );
+    }
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let sqlite_schema = schema_table(index.db);
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(sqlite_schema.name.clone()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    let type_reg = program.emit_string8_new_reg(SchemaEntryType::Index.as_str().to_string());
+    program.mark_last_insn_constant();
+    let root_page_reg = program.alloc_register();
+    program.emit_insn(Insn::Integer {
+        value: index.root_page as i64,
+        dest: root_page_reg,
+    });
+    program.mark_last_insn_constant();
+    let column_reg = program.alloc_register();
+
+    let loop_end_label = program.allocate_label();
+    let next_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 0,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: type_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 3,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: root_page_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::DeleteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::DeleteAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        skip_nchange: false,
+    });
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+
+    let former_root_reg = program.alloc_register();
+    program.emit_insn(Insn::Destroy {
+        root: index.root_page,
+        former_root_reg,
+        db: index.db,
+    });
+
+    // TODO: SetCookie
+    let db = index.db;
+    program.emit_insn(Insn::DropIndex { index, db });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
@@ -2,11 +2,29 @@ use std::ops::Deref;
 use std::rc::Rc;
 
 use limbo_sqlite3_parser::ast::{
-    DistinctNames, Expr, InsertBody, OneSelect, QualifiedName, ResolveType, ResultColumn, With,
+    DistinctNames, Expr, InsertBody, Literal, Name, OneSelect, QualifiedName, ResolveType,
+    ResultColumn, TriggerEvent, TriggerTime, Update, Upsert, UpsertDo, UpsertIndex, With,
 };
 
-use crate::error::SQLITE_CONSTRAINT_PRIMARYKEY;
-use crate::schema::Table;
+use crate::error::{
+    SQLITE_CONSTRAINT_NOTNULL, SQLITE_CONSTRAINT_PRIMARYKEY, SQLITE_CONSTRAINT_UNIQUE,
+};
+use crate::schema::{BTreeTable, Index, Table};
+use crate::translate::emitter::{emit_returning_row, emit_update_row};
+use crate::translate::expr::{translate_condition_expr, ConditionMetadata};
+use crate::translate::foreign_key::{emit_fk_checks, has_foreign_keys};
+use crate::translate::index::{
+    emit_index_delete, emit_index_insert, emit_index_key, emit_open_index_cursors,
+    unique_constraint_description, IndexKeySource,
+};
+use crate::translate::plan::{Operation, Plan, TableReference, UpdatePlan};
+use crate::translate::planner::{parse_returning, plan_subqueries_in_expr, Scope};
+use crate::translate::subquery::emit_expr_subqueries;
+use crate::translate::trigger::{
+    alloc_trigger_params, emit_fire_triggers, emit_null_row_params, emit_row_params,
+    emit_view_insert, new_row_params, trigger_column_names, RowRefs,
+};
+use crate::translate::update::{is_rowid_name, prepare_update_plan};
 use crate::util::normalize_ident;
 use crate::vdbe::builder::{ProgramBuilderOpts, QueryMode};
 use crate::vdbe::BranchOffset;
@@ -15,7 +33,7 @@ use crate::{
     translate::expr::translate_expr,
     vdbe::{
         builder::{CursorType, ProgramBuilder},
-        insn::Insn,
+        insn::{InsertFlags, Insn, OnError},
     },
     SymbolTable,
 };
@@ -32,8 +50,9 @@ pub fn translate_insert(
     tbl_name: &QualifiedName,
     columns: &Option<DistinctNames>,
     body: &InsertBody,
-    _returning: &Option<Vec<ResultColumn>>,
+    returning: &Option<Vec<ResultColumn>>,
     syms: &SymbolTable,
+    foreign_keys: bool,
 ) -> Result<ProgramBuilder> {
     let mut program = ProgramBuilder::new(ProgramBuilderOpts {
         query_mode,
@@ -41,29 +60,76 @@ pub fn translate_insert(
         approx_num_insns: 30,
         approx_num_labels: 5,
     });
+    program.foreign_keys = foreign_keys;
+    emit_insert(
+        &mut program,
+        schema,
+        with,
+        on_conflict,
+        tbl_name,
+        columns,
+        body,
+        returning,
+        syms,
+    )?;
+    Ok(program)
+}
+
+/// Emits the program of an INSERT statement, which is also the program of an INSERT command of a trigger.
+#[allow(clippy::too_many_arguments)]
+pub fn emit_insert(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    with: &Option<With>,
+    on_conflict: &Option<ResolveType>,
+    tbl_name: &QualifiedName,
+    columns: &Option<DistinctNames>,
+    body: &InsertBody,
+    returning: &Option<Vec<ResultColumn>>,
+    syms: &SymbolTable,
+) -> Result<()> {
     if with.is_some() {
         crate::bail_parse_error!("WITH clause is not supported");
     }
-    if on_conflict.is_some() {
-        crate::bail_parse_error!("ON CONFLICT clause is not supported");
-    }
 
     let table_name = &tbl_name.name;
-    let table = match schema.get_table(table_name.0.as_str()) {
+    let table = match schema.resolve_table(tbl_name)? {
         Some(table) => table,
-        None => crate::bail_corrupt_error!("Parse error: no such table: {}", table_name),
+        None => match schema.get_view(table_name.0.as_str()) {
+            Some(view) => {
+                if returning.is_some() {
+                    crate::bail_parse_error!("RETURNING is not supported in an INSERT into a view");
+                }
+                if let InsertBody::Select(_, Some(_)) = body {
+                    crate::bail_parse_error!("cannot UPSERT a view");
+                }
+                let InsertBody::Select(select, None) = body else {
+                    crate::bail_parse_error!("INSERT into a view only supports VALUES");
+                };
+                let OneSelect::Values(values) = select.body.select.deref() else {
+                    crate::bail_parse_error!("INSERT into a view only supports VALUES");
+                };
+                return emit_view_insert(program, schema, syms, &view, columns, values);
+            }
+            None => crate::bail_corrupt_error!("Parse error: no such table: {}", table_name),
+        },
     };
-    let resolver = Resolver::new(syms);
+    let mut resolver = Resolver::new(syms);
     if let Some(virtual_table) = &table.virtual_table() {
+        if returning.is_some() {
+            crate::bail_parse_error!(
+                "RETURNING is not supported in an INSERT into a virtual table"
+            );
+        }
         translate_virtual_table_insert(
-            &mut program,
+            program,
             virtual_table.clone(),
             columns,
             body,
             on_conflict,
             &resolver,
         )?;
-        return Ok(program);
+        return Ok(());
     }
     let init_label = program.allocate_label();
     program.emit_insn(Insn::Init {
@@ -74,24 +140,43 @@ pub fn translate_insert(
     let Some(btree_table) = table.btree() else {
         crate::bail_corrupt_error!("Parse error: no such table: {}", table_name);
     };
-    if !btree_table.has_rowid {
-        crate::bail_parse_error!("INSERT into WITHOUT ROWID table is not supported");
-    }
 
     let cursor_id = program.alloc_cursor_id(
         Some(table_name.0.clone()),
-        CursorType::BTreeTable(btree_table.clone()),
+        CursorType::for_table(btree_table.clone()),
     );
     let root_page = btree_table.root_page;
+    let indexes = schema.get_table_indices(&btree_table);
+    let table_references = vec![TableReference {
+        table: Table::BTree(btree_table.clone()),
+        identifier: table_name.0.clone(),
+        database: Some(schema.database_name(btree_table.db).to_string()),
+        op: Operation::Scan { iter_dir: None },
+        join_info: None,
+    }];
+    let result_columns = parse_returning(returning.clone(), &table_references)?;
+    let result_columns_start_reg = program.alloc_registers(result_columns.len());
     let values = match body {
-        InsertBody::Select(select, None) => match &select.body.select.deref() {
+        InsertBody::Select(select, _) => match &select.body.select.deref() {
             OneSelect::Values(values) => values,
             _ => todo!(),
         },
         _ => todo!(),
     };
+    // The subqueries in the VALUES can't refer to the table, since the row is not in it yet.
+    let subquery_scope = Scope::for_expr_subqueries(vec![], vec![], None);
+    let mut subqueries = vec![];
+    let mut values = values.clone();
+    for expr in values.iter_mut().flatten() {
+        plan_subqueries_in_expr(expr, schema, syms, &subquery_scope, None, &mut subqueries)?;
+    }
+    emit_expr_subqueries(program, &mut resolver, &mut subqueries)?;
 
-    let column_mappings = resolve_columns_for_insert(&table, columns, values)?;
+    let column_mappings = resolve_columns_for_insert(&table, columns, &values)?;
+    let before_triggers =
+        schema.get_table_triggers(&btree_table, TriggerTime::Before, TriggerEvent::Insert, &[]);
+    let after_triggers =
+        schema.get_table_triggers(&btree_table, TriggerTime::After, TriggerEvent::Insert, &[]);
     // Check if rowid was provided (through INTEGER PRIMARY KEY as a rowid alias)
     let rowid_alias_index = btree_table.columns.iter().position(|c| c.is_rowid_alias);
     let has_user_provided_rowid = {
@@ -116,14 +201,54 @@ pub fn translate_insert(
         }
     };
 
+    let upserts = match body {
+        InsertBody::Select(_, Some(upsert)) => prepare_upserts(
+            schema,
+            &btree_table,
+            indexes,
+            upsert,
+            rowid_reg,
+            column_registers_start,
+            syms,
+        )?,
+        _ => vec![],
+    };
+
     let record_register = program.alloc_register();
     let halt_label = program.allocate_label();
+    // A trigger program halted by RAISE(IGNORE) skips the row
+    let skip_row_label = program.allocate_label();
+    let trigger_columns = trigger_column_names(&btree_table);
+    let foreign_keys = program.foreign_keys && has_foreign_keys(schema, &btree_table);
+    let trigger_params_reg =
+        (!before_triggers.is_empty() || !after_triggers.is_empty() || foreign_keys)
+            .then(|| alloc_trigger_params(program, num_cols));
     let mut loop_start_offset = BranchOffset::Offset(0);
 
     let inserting_multiple_rows = values.len() > 1;
+    let index_cursor_ids;
 
     // Multiple rows - use coroutine for value population
     if inserting_multiple_rows {
+        // Like in SQLite, all the rows are evaluated before the first one is inserted, so the
+        // subqueries that keep their result are run now rather than when their row is reached.
+        for (subquery_id, subquery) in resolver.subqueries.iter().enumerate() {
+            if subquery.reg_once.is_some() {
+                let reg = program.alloc_register();
+                translate_expr(
+                    program,
+                    None,
+                    &Expr::SubqueryResult {
+                        subquery_id,
+                        lhs: None,
+                        not_in: false,
+                        outer_refs: vec![],
+                    },
+                    reg,
+                    &resolver,
+                )?;
+            }
+        }
         let yield_reg = program.alloc_register();
         let jump_on_definition_label = program.allocate_label();
         program.emit_insn(Insn::InitCoroutine {
@@ -132,9 +257,9 @@ pub fn translate_insert(
             start_offset: program.offset().add(1u32),
         });
 
-        for value in values {
+        for value in values.iter() {
             populate_column_registers(
-                &mut program,
+                program,
                 value,
                 &column_mappings,
                 column_registers_start,
@@ -152,13 +277,12 @@ pub fn translate_insert(
 
         program.emit_insn(Insn::OpenWriteAsync {
             cursor_id,
-            root_page,
+            root_page: root_page.into(),
         });
         program.emit_insn(Insn::OpenWriteAwait {});
+        index_cursor_ids = emit_open_index_cursors(program, indexes);
 
         // Main loop
-        // FIXME: rollback is not implemented. E.g. if you insert 2 rows and one fails to unique constraint violation,
-        // the other row will still be inserted.
         loop_start_offset = program.offset();
         program.emit_insn(Insn::Yield {
             yield_reg,
@@ -168,12 +292,13 @@ pub fn translate_insert(
         // Single row - populate registers directly
         program.emit_insn(Insn::OpenWriteAsync {
             cursor_id,
-            root_page,
+            root_page: root_page.into(),
         });
         program.emit_insn(Insn::OpenWriteAwait {});
+        index_cursor_ids = emit_open_index_cursors(program, indexes);
 
         populate_column_registers(
-            &mut program,
+            program,
             &values[0],
             &column_mappings,
             column_registers_start,
@@ -201,6 +326,57 @@ pub fn translate_insert(
             // for the row record, the rowid alias column is always set to NULL
             program.emit_insn(Insn::SoftNull { reg });
         }
+    }
+
+    if let Some(params_start_reg) = trigger_params_reg {
+        emit_null_row_params(program, num_cols, params_start_reg);
+    }
+    if let (Some(params_start_reg), false) = (trigger_params_reg, before_triggers.is_empty()) {
+        // The rowid of the new row is not known yet in BEFORE triggers, where NEW.rowid is
+        // the rowid given by the user, or -1.
+        let before_rowid_reg = program.alloc_register();
+        if has_user_provided_rowid {
+            program.emit_insn(Insn::Copy {
+                src_reg: rowid_reg,
+                dst_reg: before_rowid_reg,
+                amount: 0,
+            });
+            let rowid_given_label = program.allocate_label();
+            program.emit_insn(Insn::NotNull {
+                reg: before_rowid_reg,
+                target_pc: rowid_given_label,
+            });
+            program.emit_insn(Insn::Integer {
+                value: -1,
+                dest: before_rowid_reg,
+            });
+            program.resolve_label(rowid_given_label, program.offset());
+        } else {
+            program.emit_insn(Insn::Integer {
+                value: -1,
+                dest: before_rowid_reg,
+            });
+        }
+        emit_row_params(
+            program,
+            &btree_table,
+            before_rowid_reg,
+            column_registers_start,
+            new_row_params(params_start_reg, num_cols),
+        );
+        emit_fire_triggers(
+            program,
+            schema,
+            syms,
+            &before_triggers,
+            TriggerTime::Before,
+            &trigger_columns,
+            params_start_reg,
+            skip_row_label,
+        )?;
+    }
+
+    if rowid_alias_reg.is_some() {
         // the user provided rowid value might itself be NULL. If it is, we create a new rowid on the next instruction.
         program.emit_insn(Insn::NotNull {
             reg: rowid_reg,
@@ -208,12 +384,23 @@ pub fn translate_insert(
         });
     }
 
-    // Create new rowid if a) not provided by user or b) provided by user but is NULL
-    program.emit_insn(Insn::NewRowid {
-        cursor: cursor_id,
-        rowid_reg,
-        prev_largest_reg: 0,
-    });
+    if btree_table.has_rowid {
+        // Create new rowid if a) not provided by user or b) provided by user but is NULL
+        program.emit_insn(Insn::NewRowid {
+            cursor: cursor_id,
+            rowid_reg,
+            prev_largest_reg: 0,
+        });
+    } else {
+        // The primary key identifies the rows of a WITHOUT ROWID table, so it can't be NULL
+        emit_primary_key_not_null_checks(
+            program,
+            &btree_table,
+            column_registers_start,
+            on_conflict,
+            skip_row_label,
+        );
+    }
 
     if let Some(must_be_int_label) = check_rowid_is_integer_label {
         program.resolve_label(must_be_int_label, program.offset());
@@ -221,50 +408,372 @@ pub fn translate_insert(
         program.emit_insn(Insn::MustBeInt { reg: rowid_reg });
     }
 
-    // Check uniqueness constraint for rowid if it was provided by user.
+    // The record of a WITHOUT ROWID table starts with the primary key, which is the key the
+    // row is stored and looked up by.
+    let record_start_reg = if btree_table.has_rowid {
+        column_registers_start
+    } else {
+        let record_start_reg = program.alloc_registers(num_cols);
+        for (i, column_idx) in btree_table
+            .columns_in_record_order()
+            .into_iter()
+            .enumerate()
+        {
+            program.emit_insn(Insn::Copy {
+                src_reg: column_registers_start + column_idx,
+                dst_reg: record_start_reg + i,
+                amount: 0,
+            });
+        }
+        record_start_reg
+    };
+    let primary_key_len = btree_table
+        .primary_key_index()
+        .map_or(0, |index| index.columns.len());
+
+    // Build the keys of all indexes, then check the new row against the uniqueness constraints
+    // before anything is written.
+    let mut index_keys = Vec::with_capacity(indexes.len());
+    for index in indexes.iter() {
+        index_keys.push(emit_index_key(
+            program,
+            &btree_table,
+            index,
+            IndexKeySource::Registers {
+                columns_start: column_registers_start,
+                rowid_reg,
+            },
+        )?);
+    }
+    // The rowid can only conflict with an existing row if the user provided it.
     // When the DB allocates it there are no need for separate uniqueness checks.
-    if has_user_provided_rowid {
-        let make_record_label = program.allocate_label();
+    let constraints = has_user_provided_rowid
+        .then_some(UniqueConstraint::Rowid)
+        .into_iter()
+        .chain((!btree_table.has_rowid).then_some(UniqueConstraint::PrimaryKey))
+        .chain(
+            indexes
+                .iter()
+                .enumerate()
+                .filter(|(_, index)| index.unique)
+                .map(|(i, _)| UniqueConstraint::Index(i)),
+        );
+    let conflict_actions = conflict_actions(constraints, on_conflict, &upserts);
+    let replaces_rows = conflict_actions
+        .iter()
+        .any(|(_, action)| matches!(action, ConflictAction::Resolve(ResolveType::Replace)));
+    for (constraint, action) in conflict_actions {
+        let no_conflict_label = program.allocate_label();
+        let conflicting_rowid_reg = match constraint {
+            UniqueConstraint::Rowid => {
+                program.emit_insn(Insn::NotExists {
+                    cursor: cursor_id,
+                    rowid_reg,
+                    target_pc: no_conflict_label,
+                });
+                if matches!(
+                    action,
+                    ConflictAction::Resolve(ResolveType::Replace) | ConflictAction::DoUpdate(_)
+                ) {
+                    // The conflicting row is read or deleted, so move the table cursor to it
+                    program.emit_insn(Insn::SeekRowid {
+                        cursor_id,
+                        src_reg: rowid_reg,
+                        target_pc: no_conflict_label,
+                    });
+                }
+                rowid_reg
+            }
+            // A conflict leaves the table cursor on the conflicting row
+            UniqueConstraint::PrimaryKey => {
+                program.emit_insn(Insn::NoConflict {
+                    cursor_id,
+                    target_pc: no_conflict_label,
+                    record_reg: record_start_reg,
+                    num_regs: primary_key_len,
+                });
+                rowid_reg
+            }
+            UniqueConstraint::Index(i) if !btree_table.has_rowid => {
+                program.emit_insn(Insn::NoConflict {
+                    cursor_id: index_cursor_ids[i],
+                    target_pc: no_conflict_label,
+                    record_reg: index_keys[i],
+                    num_regs: indexes[i].columns.len(),
+                });
+                // The index entry ends with the primary key columns the index doesn't contain,
+                // so the primary key of the conflicting row is read from it.
+                let primary_key_reg = program.alloc_registers(primary_key_len);
+                let suffix = btree_table.primary_key_columns_not_in(&indexes[i]);
+                for (j, column_idx) in btree_table.columns_in_record_order()[..primary_key_len]
+                    .iter()
+                    .enumerate()
+                {
+                    let column = match indexes[i].columns.iter().position(|c| {
+                        btree_table
+                            .get_column(&c.name)
+                            .is_some_and(|(idx, _)| idx == *column_idx)
+                    }) {
+                        Some(position) => position,
+                        None => {
+                            indexes[i].columns.len()
+                                + suffix.iter().position(|c| c == column_idx).unwrap()
+                        }
+                    };
+                    program.emit_insn(Insn::Column {
+                        cursor_id: index_cursor_ids[i],
+                        column,
+                        dest: primary_key_reg + j,
+                    });
+                }
+                program.emit_insn(Insn::SeekGE {
+                    is_index: true,
+                    cursor_id,
+                    start_reg: primary_key_reg,
+                    num_regs: primary_key_len,
+                    target_pc: no_conflict_label,
+                });
+                rowid_reg
+            }
+            UniqueConstraint::Index(i) => {
+                program.emit_insn(Insn::NoConflict {
+                    cursor_id: index_cursor_ids[i],
+                    target_pc: no_conflict_label,
+                    record_reg: index_keys[i],
+                    num_regs: indexes[i].columns.len(),
+                });
+                let reg = program.alloc_register();
+                program.emit_insn(Insn::IdxRowId {
+                    cursor_id: index_cursor_ids[i],
+                    dest: reg,
+                });
+                program.emit_insn(Insn::SeekRowid {
+                    cursor_id,
+                    src_reg: reg,
+                    target_pc: no_conflict_label,
+                });
+                reg
+            }
+        };
+        match action {
+            ConflictAction::Resolve(ResolveType::Ignore) | ConflictAction::DoNothing => {
+                program.emit_insn(Insn::Goto {
+                    target_pc: skip_row_label,
+                });
+            }
+            ConflictAction::Resolve(ResolveType::Replace) => {
+                // Delete the conflicting row, which the table cursor points to, to make room for
+                // the new one. The deletion is not counted as a change.
+                for (index, &index_cursor_id) in indexes.iter().zip(index_cursor_ids.iter()) {
+                    let key_start = emit_index_key(
+                        program,
+                        &btree_table,
+                        index,
+                        IndexKeySource::Cursor(cursor_id),
+                    )?;
+                    emit_index_delete(program, &btree_table, index, index_cursor_id, key_start);
+                }
+                program.emit_insn(Insn::DeleteAsync { cursor_id });
+                program.emit_insn(Insn::DeleteAwait {
+                    cursor_id,
+                    skip_nchange: true,
+                });
+            }
+            ConflictAction::Resolve(resolve_type) => {
+                let (err_code, description) = match constraint {
+                    UniqueConstraint::Rowid => {
+                        let rowid_column_name = rowid_alias_index
+                            .and_then(|index| btree_table.columns[index].name.as_deref())
+                            .unwrap_or("rowid");
+                        (
+                            SQLITE_CONSTRAINT_PRIMARYKEY,
+                            format!("{}.{}", table_name.0, rowid_column_name),
+                        )
+                    }
+                    UniqueConstraint::PrimaryKey => (
+                        SQLITE_CONSTRAINT_PRIMARYKEY,
+                        unique_constraint_description(&btree_table.primary_key_index().unwrap()),
+                    ),
+                    UniqueConstraint::Index(i) => (
+                        SQLITE_CONSTRAINT_UNIQUE,
+                        unique_constraint_description(&indexes[i]),
+                    ),
+                };
+                program.emit_insn(Insn::Halt {
+                    err_code,
+                    description,
+                    on_error: OnError::from_resolve_type(resolve_type),
+                });
+            }
+            ConflictAction::DoUpdate(upsert) => {
+                // The conflicting row, which the table cursor points to, is updated instead.
+                // The subqueries of the DO UPDATE clause are numbered apart from those of the INSERT.
+                let mut upsert_resolver = Resolver::new(syms);
+                emit_expr_subqueries(
+                    program,
+                    &mut upsert_resolver,
+                    &mut upsert.subqueries.clone(),
+                )?;
+                for term in upsert.where_clause.iter() {
+                    let jump_target_when_true = program.allocate_label();
+                    translate_condition_expr(
+                        program,
+                        &upsert.table_references,
+                        &term.expr,
+                        ConditionMetadata {
+                            jump_if_condition_is_true: false,
+                            jump_target_when_true,
+                            jump_target_when_false: skip_row_label,
+                        },
+                        &upsert_resolver,
+                    )?;
+                    program.resolve_label(jump_target_when_true, program.offset());
+                }
+                let update_index_cursor_ids = upsert
+                    .indexes
+                    .iter()
+                    .map(|update_index| {
+                        let i = indexes
+                            .iter()
+                            .position(|index| index.name == update_index.name)
+                            .unwrap();
+                        index_cursor_ids[i]
+                    })
+                    .collect::<Vec<_>>();
+                let updated_rowid_reg = emit_update_row(
+                    program,
+                    upsert,
+                    &upsert_resolver,
+                    cursor_id,
+                    &update_index_cursor_ids,
+                    conflicting_rowid_reg,
+                    skip_row_label,
+                    schema,
+                    syms,
+                )?;
+                if !result_columns.is_empty() {
+                    // The RETURNING clause returns the updated row
+                    program.emit_insn(Insn::SeekRowid {
+                        cursor_id,
+                        src_reg: updated_rowid_reg,
+                        target_pc: skip_row_label,
+                    });
+                    emit_returning_row(
+                        program,
+                        &table_references,
+                        &result_columns,
+                        result_columns_start_reg,
+                        &resolver,
+                    )?;
+                }
+                program.emit_insn(Insn::Goto {
+                    target_pc: skip_row_label,
+                });
+            }
+        }
+        program.resolve_label(no_conflict_label, program.offset());
+    }
+    if let (Some(params_start_reg), true) = (trigger_params_reg, foreign_keys) {
+        let new_row_reg = new_row_params(params_start_reg, num_cols);
+        emit_row_params(
+            program,
+            &btree_table,
+            rowid_reg,
+            column_registers_start,
+            new_row_reg,
+        );
+        emit_fk_checks(program, schema, &btree_table, None, Some(new_row_reg), None)?;
+    }
+    if replaces_rows && btree_table.has_rowid {
+        // Deleting a row moved the table cursor, so position it for the insert again
+        let insert_label = program.allocate_label();
         program.emit_insn(Insn::NotExists {
             cursor: cursor_id,
             rowid_reg,
-            target_pc: make_record_label,
-        });
-        let rowid_column_name = if let Some(index) = rowid_alias_index {
-            btree_table
-                .columns
-                .get(index)
-                .unwrap()
-                .name
-                .as_ref()
-                .expect("column name is None")
-        } else {
-            "rowid"
-        };
-
-        program.emit_insn(Insn::Halt {
-            err_code: SQLITE_CONSTRAINT_PRIMARYKEY,
-            description: format!("{}.{}", table_name.0, rowid_column_name),
+            target_pc: insert_label,
         });
-
-        program.resolve_label(make_record_label, program.offset());
+        program.resolve_label(insert_label, program.offset());
     }
 
     // Create and insert the record
     program.emit_insn(Insn::MakeRecord {
-        start_reg: column_registers_start,
+        start_reg: record_start_reg,
         count: num_cols,
         dest_reg: record_register,
     });
 
-    program.emit_insn(Insn::InsertAsync {
-        cursor: cursor_id,
-        key_reg: rowid_reg,
-        record_reg: record_register,
-        flag: 0,
-    });
-    program.emit_insn(Insn::InsertAwait { cursor_id });
+    if btree_table.has_rowid {
+        program.emit_insn(Insn::InsertAsync {
+            cursor: cursor_id,
+            key_reg: rowid_reg,
+            record_reg: record_register,
+            flag: InsertFlags::default(),
+        });
+        program.emit_insn(Insn::InsertAwait { cursor_id });
+    } else {
+        program.emit_insn(Insn::IdxInsertAsync {
+            cursor_id,
+            record_reg: record_register,
+            nchange: true,
+        });
+        program.emit_insn(Insn::IdxInsertAwait { cursor_id });
+    }
 
+    for ((index, &index_cursor_id), &key_start) in indexes
+        .iter()
+        .zip(index_cursor_ids.iter())
+        .zip(index_keys.iter())
+    {
+        emit_index_insert(program, &btree_table, index, index_cursor_id, key_start);
+    }
+
+    if !result_columns.is_empty() {
+        // The RETURNING clause returns the row as it was inserted, before the AFTER triggers run
+        if btree_table.has_rowid {
+            program.emit_insn(Insn::SeekRowid {
+                cursor_id,
+                src_reg: rowid_reg,
+                target_pc: skip_row_label,
+            });
+        } else {
+            program.emit_insn(Insn::SeekGE {
+                is_index: true,
+                cursor_id,
+                start_reg: record_start_reg,
+                num_regs: primary_key_len,
+                target_pc: skip_row_label,
+            });
+        }
+        emit_returning_row(
+            program,
+            &table_references,
+            &result_columns,
+            result_columns_start_reg,
+            &resolver,
+        )?;
+    }
+
+    if let (Some(params_start_reg), false) = (trigger_params_reg, after_triggers.is_empty()) {
+        emit_row_params(
+            program,
+            &btree_table,
+            rowid_reg,
+            column_registers_start,
+            new_row_params(params_start_reg, num_cols),
+        );
+        emit_fire_triggers(
+            program,
+            schema,
+            syms,
+            &after_triggers,
+            TriggerTime::After,
+            &trigger_columns,
+            params_start_reg,
+            skip_row_label,
+        )?;
+    }
+
+    program.resolve_label(skip_row_label, program.offset());
     if inserting_multiple_rows {
         // For multiple rows, loop back
         program.emit_insn(Insn::Goto {
@@ -276,6 +785,7 @@ pub fn translate_insert(
     program.emit_insn(Insn::Halt {
         err_code: 0,
         description: String::new(),
+        on_error: OnError::Abort,
     });
 
     program.resolve_label(init_label, program.offset());
@@ -284,8 +794,240 @@ pub fn translate_insert(
     program.emit_insn(Insn::Goto {
         target_pc: start_offset,
     });
+    program.result_columns = result_columns;
+    program.table_references = table_references;
 
-    Ok(program)
+    Ok(())
+}
+
+/// Emit the checks that the primary key columns of a new row of a WITHOUT ROWID table are not NULL.
+/// With OR IGNORE a row that fails them is skipped, otherwise the statement halts with an error.
+fn emit_primary_key_not_null_checks(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    columns_start: usize,
+    on_conflict: &Option<ResolveType>,
+    skip_row_label: BranchOffset,
+) {
+    for (name, _) in table.primary_key_columns.iter() {
+        let Some((column_idx, column)) = table.get_column(name) else {
+            continue;
+        };
+        let not_null_label = program.allocate_label();
+        program.emit_insn(Insn::NotNull {
+            reg: columns_start + column_idx,
+            target_pc: not_null_label,
+        });
+        match on_conflict {
+            Some(ResolveType::Ignore) => program.emit_insn(Insn::Goto {
+                target_pc: skip_row_label,
+            }),
+            _ => program.emit_insn(Insn::Halt {
+                err_code: SQLITE_CONSTRAINT_NOTNULL,
+                description: format!(
+                    "{}.{}",
+                    table.name,
+                    column.name.as_deref().unwrap_or_default()
+                ),
+                on_error: OnError::from_resolve_type(on_conflict.unwrap_or(ResolveType::Abort)),
+            }),
+        }
+        program.resolve_label(not_null_label, program.offset());
+    }
+}
+
+/// A uniqueness constraint that a new row is checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UniqueConstraint {
+    /// The rowid, which is the INTEGER PRIMARY KEY if the table has one
+    Rowid,
+    /// The PRIMARY KEY of a WITHOUT ROWID table
+    PrimaryKey,
+    /// A UNIQUE index, by its position in the indexes of the table
+    Index(usize),
+}
+
+/// A clause of an upsert, `ON CONFLICT [(target)] DO NOTHING|UPDATE`.
+struct UpsertClause {
+    /// The constraint the clause applies to, or None for a last clause without a conflict
+    /// target, which applies to any constraint
+    target: Option<UniqueConstraint>,
+    /// The DO UPDATE clause as an update of the conflicting row, or None for DO NOTHING
+    update: Option<UpdatePlan>,
+}
+
+/// What is done when a new row conflicts with an existing row.
+enum ConflictAction<'a> {
+    /// The conflict resolution of the statement: `INSERT OR <resolve type>`, ABORT by default
+    Resolve(ResolveType),
+    /// An upsert DO NOTHING clause, the new row is skipped
+    DoNothing,
+    /// An upsert DO UPDATE clause, the conflicting row is updated instead of inserting the new row
+    DoUpdate(&'a UpdatePlan),
+}
+
+/// Prepares the clauses of an upsert. The `excluded` row of the DO UPDATE clauses, the row that
+/// was not inserted, is read from the registers of the new row.
+fn prepare_upserts(
+    schema: &Schema,
+    table: &BTreeTable,
+    indexes: &[Rc<Index>],
+    upsert: &Upsert,
+    rowid_reg: usize,
+    columns_start: usize,
+    syms: &SymbolTable,
+) -> Result<Vec<UpsertClause>> {
+    let resolve = |row: &Name, column: &Name| {
+        if normalize_ident(&row.0) != "excluded" {
+            return Ok(None);
+        }
+        let column_name = normalize_ident(&column.0);
+        match table.get_column(&column_name) {
+            // The rowid alias column is stored as NULL, its value is the rowid.
+            Some((_, c)) if c.is_rowid_alias => Ok(Some(Expr::Register(rowid_reg))),
+            Some((i, _)) => Ok(Some(Expr::Register(columns_start + i))),
+            None if is_rowid_name(&column_name) => Ok(Some(Expr::Register(rowid_reg))),
+            None => crate::bail_parse_error!("no such column: {}.{}", row.0, column.0),
+        }
+    };
+    let excluded = RowRefs::new(&resolve);
+
+    let mut clauses = vec![];
+    let mut upsert = Some(upsert);
+    while let Some(clause) = upsert {
+        let target = clause
+            .index
+            .as_deref()
+            .map(|index| upsert_target(table, indexes, index))
+            .transpose()?;
+        let update = match clause.do_clause.as_ref() {
+            UpsertDo::Nothing => None,
+            UpsertDo::Set { .. } if !table.has_rowid => {
+                crate::bail_parse_error!(
+                    "DO UPDATE upserts on WITHOUT ROWID tables are not supported"
+                )
+            }
+            UpsertDo::Set { sets, where_clause } => {
+                let mut sets = sets.clone();
+                let mut where_clause = where_clause.clone();
+                for set in sets.iter_mut() {
+                    excluded.bind_expr(&mut set.expr)?;
+                }
+                excluded.bind_opt_expr(where_clause.as_mut())?;
+                let update = Update {
+                    with: None,
+                    or_conflict: None,
+                    tbl_name: QualifiedName::single(Name(table.name.clone())),
+                    indexed: None,
+                    sets,
+                    from: None,
+                    where_clause: where_clause.map(Box::new),
+                    returning: None,
+                    order_by: None,
+                    limit: None,
+                };
+                let Plan::Update(plan) = prepare_update_plan(schema, update, syms)? else {
+                    unreachable!("prepare_update_plan() returns an update plan");
+                };
+                Some(plan)
+            }
+        };
+        clauses.push(UpsertClause { target, update });
+        upsert = clause.next.as_deref();
+    }
+    Ok(clauses)
+}
+
+/// The uniqueness constraint that is the conflict target of an upsert clause: the INTEGER PRIMARY KEY,
+/// or a UNIQUE index on exactly the target columns.
+fn upsert_target(
+    table: &BTreeTable,
+    indexes: &[Rc<Index>],
+    target: &UpsertIndex,
+) -> Result<UniqueConstraint> {
+    let columns = target
+        .targets
+        .iter()
+        .map(|column| match &column.expr {
+            Expr::Id(name) => Some(normalize_ident(&name.0)),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+    // Partial indexes are not supported, so a target with a WHERE clause matches no index.
+    if let (Some(columns), None) = (columns, &target.where_clause) {
+        if let [column] = columns.as_slice() {
+            if table
+                .get_column(column)
+                .is_some_and(|(_, c)| c.is_rowid_alias)
+            {
+                return Ok(UniqueConstraint::Rowid);
+            }
+        }
+        if let Some(index) = table.primary_key_index() {
+            if index.columns.len() == columns.len()
+                && columns
+                    .iter()
+                    .all(|column| index.columns.iter().any(|c| c.name == *column))
+            {
+                return Ok(UniqueConstraint::PrimaryKey);
+            }
+        }
+        let index = indexes.iter().position(|index| {
+            index.unique
+                && index.columns.len() == columns.len()
+                && columns
+                    .iter()
+                    .all(|column| index.columns.iter().any(|c| c.name == *column))
+        });
+        if let Some(i) = index {
+            return Ok(UniqueConstraint::Index(i));
+        }
+    }
+    crate::bail_parse_error!(
+        "ON CONFLICT clause does not match any PRIMARY KEY or UNIQUE constraint"
+    )
+}
+
+/// The order in which the constraints are checked, with what is done on a conflict.
+/// An upsert clause applies to its conflict target, which is checked first. Other constraints
+/// get the conflict resolution of the statement. The constraints with REPLACE are checked last,
+/// so that rows are only deleted once the new row is known to be inserted.
+fn conflict_actions<'a>(
+    constraints: impl Iterator<Item = UniqueConstraint>,
+    on_conflict: &Option<ResolveType>,
+    upserts: &'a [UpsertClause],
+) -> Vec<(UniqueConstraint, ConflictAction<'a>)> {
+    let mut actions = constraints
+        .map(|constraint| {
+            let upsert = upserts
+                .iter()
+                .position(|upsert| upsert.target.map_or(true, |target| target == constraint));
+            match upsert {
+                Some(i) => {
+                    let action = match &upserts[i].update {
+                        Some(plan) => ConflictAction::DoUpdate(plan),
+                        None => ConflictAction::DoNothing,
+                    };
+                    let order = if upserts[i].target.is_some() {
+                        i
+                    } else {
+                        upserts.len()
+                    };
+                    (order, constraint, action)
+                }
+                None => {
+                    let resolve_type = on_conflict.unwrap_or(ResolveType::Abort);
+                    let order = upserts.len() + (resolve_type == ResolveType::Replace) as usize;
+                    (order, constraint, ConflictAction::Resolve(resolve_type))
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    actions.sort_by_key(|(order, _, _)| *order);
+    actions
+        .into_iter()
+        .map(|(_, constraint, action)| (constraint, action))
+        .collect()
 }
 
 #[derive(Debug)]
@@ -423,6 +1165,18 @@ fn populate_column_registers(
             if write_directly_to_rowid_reg {
                 program.emit_insn(Insn::SoftNull { reg: target_reg });
             }
+        } else if let Some(default) = mapping.column.default.as_ref().filter(|default| {
+            // TODO: CURRENT_TIME, CURRENT_DATE and CURRENT_TIMESTAMP defaults
+            !mapping.column.is_rowid_alias
+                && !matches!(
+                    default,
+                    Expr::Literal(
+                        Literal::CurrentDate | Literal::CurrentTime | Literal::CurrentTimestamp
+                    )
+                )
+        }) {
+            // Column was not specified - use its default value if it has one
+            translate_expr(program, None, default, target_reg, resolver)?;
         } else {
             // Column was not specified - use NULL if it is nullable, otherwise error
             // Rowid alias columns can be NULL because we will autogenerate a rowid in that case.
@@ -529,6 +1283,7 @@ fn translate_virtual_table_insert(
     program.emit_insn(Insn::Halt {
         err_code: 0,
         description: String::new(),
+        on_error: OnError::Abort,
     });
 
     program.resolve_label(halt_label, program.offset());
@@ -1,12 +1,12 @@
 use limbo_sqlite3_parser::ast;
 
 use crate::{
-    schema::Table,
+    schema::{Index, Table},
     translate::result_row::emit_select_result,
     vdbe::{
         builder::{CursorType, ProgramBuilder},
         insn::{CmpInsFlags, Insn},
-        BranchOffset,
+        BranchOffset, CursorID,
     },
     Result,
 };
@@ -20,6 +20,8 @@ use super::{
         IterationDirection, Operation, Search, SelectPlan, SelectQueryType, TableReference,
         WhereTerm,
     },
+    subquery::emit_coroutine_into_ephemeral,
+    window::window_sorter_insert,
 };
 
 // Metadata for handling LEFT JOIN operations
@@ -33,13 +35,20 @@ pub struct LeftJoinMetadata {
     pub label_match_flag_check_value: BranchOffset,
 }
 
+// Metadata for handling RIGHT and FULL OUTER JOIN operations
+#[derive(Debug)]
+pub struct RightJoinMetadata {
+    // cursor of the ephemeral table that holds the rowids of the rows of the right table that had a match
+    pub matched_rows_cursor_id: CursorID,
+}
+
 /// Jump labels for each loop in the query's main execution loop
 #[derive(Debug, Clone, Copy)]
 pub struct LoopLabels {
     /// jump to the start of the loop body
     loop_start: BranchOffset,
     /// jump to the NextAsync instruction (or equivalent)
-    next: BranchOffset,
+    pub next: BranchOffset,
     /// jump to the end of the loop, exiting it
     loop_end: BranchOffset,
 }
@@ -76,13 +85,26 @@ pub fn init_loop(
                 };
                 t_ctx.meta_left_joins[table_index] = Some(lj_metadata);
             }
+            // The rows of the right table of a RIGHT JOIN that have a match are recorded,
+            // so that the other ones can be emitted after the main loop.
+            if join_info.right {
+                let matched_rows_cursor_id = program.alloc_cursor_id(None, CursorType::Ephemeral);
+                program.emit_insn(Insn::OpenEphemeral {
+                    cursor_id: matched_rows_cursor_id,
+                    distinct: true,
+                    collations: vec![],
+                });
+                t_ctx.meta_right_joins[table_index] = Some(RightJoinMetadata {
+                    matched_rows_cursor_id,
+                });
+            }
         }
         match &table.op {
             Operation::Scan { .. } => {
                 let cursor_id = program.alloc_cursor_id(
                     Some(table.identifier.clone()),
                     match &table.table {
-                        Table::BTree(_) => CursorType::BTreeTable(table.btree().unwrap().clone()),
+                        Table::BTree(_) => CursorType::for_table(table.btree().unwrap().clone()),
                         Table::Virtual(_) => {
                             CursorType::VirtualTable(table.virtual_table().unwrap().clone())
                         }
@@ -98,11 +120,11 @@ pub fn init_loop(
                         });
                         program.emit_insn(Insn::OpenReadAwait {});
                     }
-                    (OperationMode::DELETE, Table::BTree(_)) => {
+                    (OperationMode::DELETE | OperationMode::UPDATE, Table::BTree(_)) => {
                         let root_page = table.btree().unwrap().root_page;
                         program.emit_insn(Insn::OpenWriteAsync {
                             cursor_id,
-                            root_page,
+                            root_page: root_page.into(),
                         });
                         program.emit_insn(Insn::OpenWriteAwait {});
                     }
@@ -122,7 +144,7 @@ pub fn init_loop(
             Operation::Search(search) => {
                 let table_cursor_id = program.alloc_cursor_id(
                     Some(table.identifier.clone()),
-                    CursorType::BTreeTable(table.btree().unwrap().clone()),
+                    CursorType::for_table(table.btree().unwrap().clone()),
                 );
 
                 match mode {
@@ -133,10 +155,10 @@ pub fn init_loop(
                         });
                         program.emit_insn(Insn::OpenReadAwait {});
                     }
-                    OperationMode::DELETE => {
+                    OperationMode::DELETE | OperationMode::UPDATE => {
                         program.emit_insn(Insn::OpenWriteAsync {
                             cursor_id: table_cursor_id,
-                            root_page: table.table.get_root_page(),
+                            root_page: table.table.get_root_page().into(),
                         });
                         program.emit_insn(Insn::OpenWriteAwait {});
                     }
@@ -145,7 +167,10 @@ pub fn init_loop(
                     }
                 }
 
-                if let Search::IndexSearch { index, .. } = search {
+                if let Some(index) = search
+                    .index()
+                    .filter(|index| !table.btree().unwrap().is_primary_key_index(index))
+                {
                     let index_cursor_id = program.alloc_cursor_id(
                         Some(index.name.clone()),
                         CursorType::BTreeIndex(index.clone()),
@@ -159,10 +184,10 @@ pub fn init_loop(
                             });
                             program.emit_insn(Insn::OpenReadAwait);
                         }
-                        OperationMode::DELETE => {
+                        OperationMode::DELETE | OperationMode::UPDATE => {
                             program.emit_insn(Insn::OpenWriteAsync {
                                 cursor_id: index_cursor_id,
-                                root_page: index.root_page,
+                                root_page: index.root_page.into(),
                             });
                             program.emit_insn(Insn::OpenWriteAwait {});
                         }
@@ -172,6 +197,9 @@ pub fn init_loop(
                     }
                 }
             }
+            Operation::RecursiveCte { .. } | Operation::MaterializedCte { .. } => {
+                program.alloc_cursor_id(Some(table.identifier.clone()), CursorType::Ephemeral);
+            }
             _ => {}
         }
     }
@@ -179,6 +207,191 @@ pub fn init_loop(
     Ok(())
 }
 
+/// Emits the loop of an IN list search, e.g. `WHERE id IN (1, 2, 3)`.
+/// A coroutine evaluates the values of the list and seeks to each distinct non-NULL value in turn,
+/// yielding every row that matches it. The loop itself is a Yield at `loop_start`, which jumps
+/// to `loop_end` when the coroutine has run out of rows, just like the loop over a subquery.
+#[allow(clippy::too_many_arguments)]
+fn emit_in_list_search(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    tables: &[TableReference],
+    table_cursor_id: usize,
+    index_cursor_id: Option<usize>,
+    values: &[WhereTerm],
+    loop_start: BranchOffset,
+    loop_end: BranchOffset,
+) -> Result<()> {
+    let yield_reg = program.alloc_register();
+    let return_reg = program.alloc_register();
+    let key_reg = program.alloc_register();
+    let values_start_reg = program.alloc_registers(values.len());
+    let label_coroutine_end = program.allocate_label();
+    let label_seek = program.allocate_label();
+    let label_seek_done = program.allocate_label();
+
+    // The coroutine is reinitialized every time the loop is entered, since the values can refer to outer tables.
+    program.emit_insn(Insn::InitCoroutine {
+        yield_reg,
+        jump_on_definition: label_coroutine_end,
+        start_offset: program.offset().add(1u32),
+    });
+    for (i, value) in values.iter().enumerate() {
+        let value_reg = values_start_reg + i;
+        let label_next_value = program.allocate_label();
+        translate_expr(
+            program,
+            Some(tables),
+            &value.expr,
+            value_reg,
+            &t_ctx.resolver,
+        )?;
+        // NULL never matches, and a duplicate value would yield its rows twice.
+        program.emit_insn(Insn::IsNull {
+            reg: value_reg,
+            target_pc: label_next_value,
+        });
+        for prev_value_reg in values_start_reg..value_reg {
+            program.emit_insn(Insn::Eq {
+                lhs: value_reg,
+                rhs: prev_value_reg,
+                target_pc: label_next_value,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+        }
+        program.emit_insn(Insn::Copy {
+            src_reg: value_reg,
+            dst_reg: key_reg,
+            amount: 0,
+        });
+        program.emit_insn(Insn::Gosub {
+            target_pc: label_seek,
+            return_reg,
+        });
+        program.resolve_label(label_next_value, program.offset());
+    }
+    program.emit_insn(Insn::EndCoroutine { yield_reg });
+
+    // Subroutine that yields the rows matching the value in key_reg.
+    program.resolve_label(label_seek, program.offset());
+    match index_cursor_id {
+        Some(index_cursor_id) => {
+            program.emit_insn(Insn::SeekGE {
+                is_index: true,
+                cursor_id: index_cursor_id,
+                start_reg: key_reg,
+                num_regs: 1,
+                target_pc: label_seek_done,
+            });
+            let label_index_scan = program.offset();
+            program.emit_insn(Insn::IdxGT {
+                cursor_id: index_cursor_id,
+                start_reg: key_reg,
+                num_regs: 1,
+                target_pc: label_seek_done,
+            });
+            program.emit_insn(Insn::Yield {
+                yield_reg,
+                end_offset: BranchOffset::Offset(0),
+            });
+            program.emit_insn(Insn::NextAsync {
+                cursor_id: index_cursor_id,
+            });
+            program.emit_insn(Insn::NextAwait {
+                cursor_id: index_cursor_id,
+                pc_if_next: label_index_scan,
+            });
+        }
+        None => {
+            program.emit_insn(Insn::SeekRowid {
+                cursor_id: table_cursor_id,
+                src_reg: key_reg,
+                target_pc: label_seek_done,
+            });
+            program.emit_insn(Insn::Yield {
+                yield_reg,
+                end_offset: BranchOffset::Offset(0),
+            });
+        }
+    }
+    program.resolve_label(label_seek_done, program.offset());
+    program.emit_insn(Insn::Return { return_reg });
+    program.resolve_label(label_coroutine_end, program.offset());
+
+    program.resolve_label(loop_start, program.offset());
+    program.emit_insn(Insn::Yield {
+        yield_reg,
+        end_offset: loop_end,
+    });
+    if let Some(index_cursor_id) = index_cursor_id {
+        emit_deferred_seek(program, index_cursor_id, table_cursor_id);
+    }
+    Ok(())
+}
+
+/// The cursor that iterates over `index` in a search of `table`. The primary key index of
+/// a WITHOUT ROWID table is the table itself, so the table cursor is used.
+fn resolve_index_cursor_id(
+    program: &ProgramBuilder,
+    table: &TableReference,
+    index: &Index,
+) -> usize {
+    match table.btree() {
+        Some(btree) if btree.is_primary_key_index(index) => {
+            program.resolve_cursor_id(&table.identifier)
+        }
+        _ => program.resolve_cursor_id(&index.name),
+    }
+}
+
+/// Emit the seek of the table cursor to the row of the index entry the index cursor is on,
+/// unless the index is the table itself.
+fn emit_deferred_seek(
+    program: &mut ProgramBuilder,
+    index_cursor_id: usize,
+    table_cursor_id: usize,
+) {
+    if index_cursor_id != table_cursor_id {
+        program.emit_insn(Insn::DeferredSeek {
+            index_cursor_id,
+            table_cursor_id,
+        });
+    }
+}
+
+/// Emits the predicates that are evaluated in the loop of the table at `table_index`,
+/// jumping to `next` when one of them is false.
+fn emit_loop_predicates(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    tables: &[TableReference],
+    predicates: &[WhereTerm],
+    table_index: usize,
+    next: BranchOffset,
+) -> Result<()> {
+    for cond in predicates
+        .iter()
+        .filter(|cond| cond.should_eval_at_loop(table_index))
+    {
+        let jump_target_when_true = program.allocate_label();
+        let condition_metadata = ConditionMetadata {
+            jump_if_condition_is_true: false,
+            jump_target_when_true,
+            jump_target_when_false: next,
+        };
+        translate_condition_expr(
+            program,
+            tables,
+            &cond.expr,
+            condition_metadata,
+            &t_ctx.resolver,
+        )?;
+        program.resolve_label(jump_target_when_true, program.offset());
+    }
+    Ok(())
+}
+
 /// Set up the main query execution loop
 /// For example in the case of a nested table scan, this means emitting the RewindAsync instruction
 /// for all tables involved, outermost first.
@@ -188,7 +401,31 @@ pub fn open_loop(
     tables: &[TableReference],
     predicates: &[WhereTerm],
 ) -> Result<()> {
-    for (table_index, table) in tables.iter().enumerate() {
+    open_loops_from(program, t_ctx, tables, predicates, 0)
+}
+
+/// Opens the loops of the tables from the one at `first_table_index` on.
+fn open_loops_from(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx,
+    tables: &[TableReference],
+    predicates: &[WhereTerm],
+    first_table_index: usize,
+) -> Result<()> {
+    for (table_index, table) in tables.iter().enumerate().skip(first_table_index) {
+        // The conditions of the right table of an OUTER JOIN other than the ones of the join are
+        // evaluated once it is known whether the row has a match, so that they filter the rows
+        // emitted with NULLs for it too.
+        let is_outer_join = table
+            .join_info
+            .as_ref()
+            .is_some_and(|join_info| join_info.outer || join_info.right);
+        let (loop_predicates, deferred_predicates): (Vec<_>, Vec<_>) =
+            predicates.iter().cloned().partition(|cond| {
+                !is_outer_join || cond.from_outer_join || !cond.should_eval_at_loop(table_index)
+            });
+        let predicates = &loop_predicates[..];
+
         let LoopLabels {
             loop_start,
             loop_end,
@@ -259,6 +496,73 @@ pub fn open_loop(
                     program.resolve_label(jump_target_when_true, program.offset());
                 }
             }
+            Operation::RecursiveCte {
+                initial,
+                union_all,
+                result_columns_start_reg,
+                initial_result_columns_start_reg,
+                ..
+            } => {
+                // The queue of the rows whose recursive step has yet to run. With UNION it keeps the rows
+                // that have already been taken from it too, so that producing a row twice is detected.
+                let cursor_id = program.resolve_cursor_id(&table.identifier);
+                program.emit_insn(Insn::OpenEphemeral {
+                    cursor_id,
+                    distinct: !*union_all,
+                    collations: vec![],
+                });
+                let SelectQueryType::Subquery {
+                    yield_reg,
+                    coroutine_implementation_start,
+                } = initial.query_type
+                else {
+                    unreachable!("initial SELECT of a recursive CTE must be a coroutine");
+                };
+                emit_coroutine_into_ephemeral(
+                    program,
+                    yield_reg,
+                    coroutine_implementation_start,
+                    *initial_result_columns_start_reg,
+                    table.columns().len(),
+                    cursor_id,
+                );
+                program.emit_insn(Insn::EphemeralRewind {
+                    cursor_id,
+                    pc_if_empty: loop_end,
+                });
+                program.resolve_label(loop_start, program.offset());
+                for i in 0..table.columns().len() {
+                    program.emit_insn(Insn::Column {
+                        cursor_id,
+                        column: i,
+                        dest: result_columns_start_reg + i,
+                    });
+                }
+                emit_loop_predicates(program, t_ctx, tables, predicates, table_index, next)?;
+            }
+            Operation::CteRow { .. } => {
+                // The row is already in registers, so the loop runs exactly once.
+                program.resolve_label(loop_start, program.offset());
+                emit_loop_predicates(program, t_ctx, tables, predicates, table_index, next)?;
+            }
+            Operation::MaterializedCte {
+                materialization, ..
+            } => {
+                let materialization = materialization
+                    .get()
+                    .expect("MATERIALIZED CTE is emitted before its loop");
+                let cursor_id = program.resolve_cursor_id(&table.identifier);
+                program.emit_insn(Insn::OpenDup {
+                    new_cursor_id: cursor_id,
+                    original_cursor_id: materialization.cursor_id,
+                });
+                program.emit_insn(Insn::EphemeralRewind {
+                    cursor_id,
+                    pc_if_empty: loop_end,
+                });
+                program.resolve_label(loop_start, program.offset());
+                emit_loop_predicates(program, t_ctx, tables, predicates, table_index, next)?;
+            }
             Operation::Scan { iter_dir } => {
                 let cursor_id = program.resolve_cursor_id(&table.identifier);
 
@@ -338,9 +642,12 @@ pub fn open_loop(
                 let table_cursor_id = program.resolve_cursor_id(&table.identifier);
                 // Open the loop for the index search.
                 // Rowid equality point lookups are handled with a SeekRowid instruction which does not loop, since it is a single row lookup.
-                if !matches!(search, Search::RowidEq { .. }) {
+                if matches!(
+                    search,
+                    Search::RowidSearch { .. } | Search::IndexSearch { .. }
+                ) {
                     let index_cursor_id = if let Search::IndexSearch { index, .. } = search {
-                        Some(program.resolve_cursor_id(&index.name))
+                        Some(resolve_index_cursor_id(program, table, index))
                     } else {
                         None
                     };
@@ -350,7 +657,7 @@ pub fn open_loop(
                             cmp_expr, cmp_op, ..
                         } => (cmp_expr, cmp_op),
                         Search::RowidSearch { cmp_expr, cmp_op } => (cmp_expr, cmp_op),
-                        Search::RowidEq { .. } => unreachable!(),
+                        _ => unreachable!(),
                     };
 
                     // TODO this only handles ascending indexes
@@ -435,6 +742,7 @@ pub fn open_loop(
                                     rhs: cmp_reg,
                                     target_pc: loop_end,
                                     flags: CmpInsFlags::default(),
+                                    collation: None,
                                 });
                             }
                         }
@@ -457,6 +765,7 @@ pub fn open_loop(
                                     rhs: cmp_reg,
                                     target_pc: loop_end,
                                     flags: CmpInsFlags::default(),
+                                    collation: None,
                                 });
                             }
                         }
@@ -464,13 +773,82 @@ pub fn open_loop(
                     }
 
                     if let Some(index_cursor_id) = index_cursor_id {
-                        program.emit_insn(Insn::DeferredSeek {
-                            index_cursor_id,
-                            table_cursor_id,
+                        emit_deferred_seek(program, index_cursor_id, table_cursor_id);
+                    }
+                }
+
+                if let Search::Range { index, start, end } = search {
+                    let index_cursor_id = index
+                        .as_ref()
+                        .map(|index| resolve_index_cursor_id(program, table, index));
+                    let start_reg = program.alloc_register();
+                    let end_reg = program.alloc_register();
+                    translate_expr(
+                        program,
+                        Some(tables),
+                        &start.expr,
+                        start_reg,
+                        &t_ctx.resolver,
+                    )?;
+                    translate_expr(program, Some(tables), &end.expr, end_reg, &t_ctx.resolver)?;
+                    // Nothing is BETWEEN a NULL bound, and seeking to a NULL would find the NULL keys of the index.
+                    program.emit_insn(Insn::IsNull {
+                        reg: start_reg,
+                        target_pc: loop_end,
+                    });
+                    program.emit_insn(Insn::IsNull {
+                        reg: end_reg,
+                        target_pc: loop_end,
+                    });
+                    program.emit_insn(Insn::SeekGE {
+                        is_index: index_cursor_id.is_some(),
+                        cursor_id: index_cursor_id.unwrap_or(table_cursor_id),
+                        start_reg,
+                        num_regs: 1,
+                        target_pc: loop_end,
+                    });
+                    program.resolve_label(loop_start, program.offset());
+                    // Stop at the first key after the end of the range.
+                    if let Some(index_cursor_id) = index_cursor_id {
+                        program.emit_insn(Insn::IdxGT {
+                            cursor_id: index_cursor_id,
+                            start_reg: end_reg,
+                            num_regs: 1,
+                            target_pc: loop_end,
+                        });
+                        emit_deferred_seek(program, index_cursor_id, table_cursor_id);
+                    } else {
+                        let rowid_reg = program.alloc_register();
+                        program.emit_insn(Insn::RowId {
+                            cursor_id: table_cursor_id,
+                            dest: rowid_reg,
+                        });
+                        program.emit_insn(Insn::Gt {
+                            lhs: rowid_reg,
+                            rhs: end_reg,
+                            target_pc: loop_end,
+                            flags: CmpInsFlags::default(),
+                            collation: None,
                         });
                     }
                 }
 
+                if let Search::InList { index, values } = search {
+                    let index_cursor_id = index
+                        .as_ref()
+                        .map(|index| resolve_index_cursor_id(program, table, index));
+                    emit_in_list_search(
+                        program,
+                        t_ctx,
+                        tables,
+                        table_cursor_id,
+                        index_cursor_id,
+                        values,
+                        loop_start,
+                        loop_end,
+                    )?;
+                }
+
                 if let Search::RowidEq { cmp_expr } = search {
                     let src_reg = program.alloc_register();
                     translate_expr(
@@ -508,6 +886,25 @@ pub fn open_loop(
             }
         }
 
+        // Record that the row of the right table of a RIGHT JOIN has a match.
+        if let Some(right_join) = t_ctx.meta_right_joins[table_index].as_ref() {
+            let cursor_id = program.resolve_cursor_id(&table.identifier);
+            let rowid_reg = program.alloc_registers(2);
+            program.emit_insn(Insn::RowId {
+                cursor_id,
+                dest: rowid_reg,
+            });
+            program.emit_insn(Insn::MakeRecord {
+                start_reg: rowid_reg,
+                count: 1,
+                dest_reg: rowid_reg + 1,
+            });
+            program.emit_insn(Insn::EphemeralInsert {
+                cursor_id: right_join.matched_rows_cursor_id,
+                record_reg: rowid_reg + 1,
+            });
+        }
+
         // Set the match flag to true if this is a LEFT JOIN.
         // At this point of execution we are going to emit columns for the left table,
         // and either emit columns or NULLs for the right table, depending on whether the null_flag is set
@@ -522,6 +919,16 @@ pub fn open_loop(
                 });
             }
         }
+
+        let next = t_ctx.labels_main_loop[table_index].next;
+        emit_loop_predicates(
+            program,
+            t_ctx,
+            tables,
+            &deferred_predicates,
+            table_index,
+            next,
+        )?;
     }
 
     Ok(())
@@ -532,11 +939,13 @@ pub fn open_loop(
 /// - a GROUP BY sorter (grouping is done by sorting based on the GROUP BY keys and aggregating while the GROUP BY keys match)
 /// - an ORDER BY sorter (when there is no GROUP BY, but there is an ORDER BY)
 /// - an AggStep (the columns are collected for aggregation, which is finished later)
+/// - a window sorter (the window functions are computed over the sorted rows after the loop)
 /// - a QueryResult (there is none of the above, so the loop either emits a ResultRow, or if it's a subquery, yields to the parent query)
 enum LoopEmitTarget {
     GroupBySorter,
     OrderBySorter,
     AggStep,
+    WindowSorter,
     QueryResult,
 }
 
@@ -556,6 +965,10 @@ pub fn emit_loop(
     if !plan.aggregates.is_empty() {
         return emit_loop_source(program, t_ctx, plan, LoopEmitTarget::AggStep);
     }
+    // if we have window functions, we emit a record into the window sorter.
+    if !plan.windows.is_empty() {
+        return emit_loop_source(program, t_ctx, plan, LoopEmitTarget::WindowSorter);
+    }
     // if we DONT have a group by, but we have an order by, we emit a record into the order by sorter.
     if plan.order_by.is_some() {
         return emit_loop_source(program, t_ctx, plan, LoopEmitTarget::OrderBySorter);
@@ -581,7 +994,7 @@ fn emit_loop_source(
             let aggregate_arguments_count = plan
                 .aggregates
                 .iter()
-                .map(|agg| agg.args.len())
+                .map(|agg| agg.num_sorter_columns())
                 .sum::<usize>();
             let column_count = sort_keys_count + aggregate_arguments_count;
             let start_reg = program.alloc_registers(column_count);
@@ -607,7 +1020,9 @@ fn emit_loop_source(
                 // the sorter will have two scalars: u.first_name and u.age.
                 // these are then sorted by u.first_name, and for each u.first_name, we sum the u.age.
                 // the actual aggregation is done later.
-                for expr in agg.args.iter() {
+                // An aggregate with a FILTER clause also stores the value of the filter condition,
+                // so that rows for which it is not true can be skipped when aggregating.
+                for expr in agg.args.iter().chain(agg.filter.iter()) {
                     let agg_reg = cur_reg;
                     cur_reg += 1;
                     translate_expr(
@@ -636,10 +1051,10 @@ fn emit_loop_source(
             Ok(())
         }
         LoopEmitTarget::OrderBySorter => order_by_sorter_insert(program, t_ctx, plan),
+        LoopEmitTarget::WindowSorter => window_sorter_insert(program, t_ctx, plan, 0),
         LoopEmitTarget::AggStep => {
             let num_aggs = plan.aggregates.len();
-            let start_reg = program.alloc_registers(num_aggs);
-            t_ctx.reg_agg_start = Some(start_reg);
+            let start_reg = t_ctx.reg_agg_start.unwrap();
 
             // In planner.rs, we have collected all aggregates from the SELECT clause, including ones where the aggregate is embedded inside
             // a more complex expression. Some examples: length(sum(x)), sum(x) + avg(y), sum(x) + 1, etc.
@@ -647,6 +1062,23 @@ fn emit_loop_source(
             // Instead, we accumulate the intermediate results of all aggreagates, and evaluate any expressions that do not contain aggregates.
             for (i, agg) in plan.aggregates.iter().enumerate() {
                 let reg = start_reg + i;
+                // Skip the row if the FILTER condition of the aggregate is not true
+                let label_skip_agg_step = program.allocate_label();
+                if let Some(filter) = &agg.filter {
+                    let jump_target_when_true = program.allocate_label();
+                    translate_condition_expr(
+                        program,
+                        &plan.table_references,
+                        filter,
+                        ConditionMetadata {
+                            jump_if_condition_is_true: false,
+                            jump_target_when_true,
+                            jump_target_when_false: label_skip_agg_step,
+                        },
+                        &t_ctx.resolver,
+                    )?;
+                    program.resolve_label(jump_target_when_true, program.offset());
+                }
                 translate_aggregation_step(
                     program,
                     &plan.table_references,
@@ -654,6 +1086,7 @@ fn emit_loop_source(
                     reg,
                     &t_ctx.resolver,
                 )?;
+                program.resolve_label(label_skip_agg_step, program.offset());
             }
             for (i, rc) in plan.result_columns.iter().enumerate() {
                 if rc.contains_aggregates {
@@ -678,9 +1111,10 @@ fn emit_loop_source(
                 plan.aggregates.is_empty(),
                 "We should not get here with aggregates"
             );
+            // A row skipped by OFFSET moves on to the next row of the innermost loop
             let offset_jump_to = t_ctx
                 .labels_main_loop
-                .first()
+                .last()
                 .map(|l| l.next)
                 .or(t_ctx.label_main_loop_end);
             emit_select_result(
@@ -703,6 +1137,16 @@ pub fn close_loop(
     program: &mut ProgramBuilder,
     t_ctx: &mut TranslateCtx,
     tables: &[TableReference],
+) -> Result<()> {
+    close_loops_from(program, t_ctx, tables, 0)
+}
+
+/// Closes the loops of the tables from the one at `first_table_index` on.
+fn close_loops_from(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx,
+    tables: &[TableReference],
+    first_table_index: usize,
 ) -> Result<()> {
     // We close the loops for all tables in reverse order, i.e. innermost first.
     // OPEN t1
@@ -712,7 +1156,7 @@ pub fn close_loop(
     //     CLOSE t3
     //   CLOSE t2
     // CLOSE t1
-    for (idx, table) in tables.iter().rev().enumerate() {
+    for (idx, table) in tables[first_table_index..].iter().rev().enumerate() {
         let table_index = tables.len() - idx - 1;
         let loop_labels = *t_ctx
             .labels_main_loop
@@ -729,6 +1173,45 @@ pub fn close_loop(
                     target_pc: loop_labels.loop_start,
                 });
             }
+            Operation::RecursiveCte {
+                recursive,
+                recursive_result_columns_start_reg,
+                ..
+            } => {
+                program.resolve_label(loop_labels.next, program.offset());
+                // Run the recursive step for the current row, adding the rows it produces to the queue.
+                let cursor_id = program.resolve_cursor_id(&table.identifier);
+                let SelectQueryType::Subquery {
+                    yield_reg,
+                    coroutine_implementation_start,
+                } = recursive.query_type
+                else {
+                    unreachable!("recursive SELECT of a recursive CTE must be a coroutine");
+                };
+                emit_coroutine_into_ephemeral(
+                    program,
+                    yield_reg,
+                    coroutine_implementation_start,
+                    *recursive_result_columns_start_reg,
+                    table.columns().len(),
+                    cursor_id,
+                );
+                program.emit_insn(Insn::EphemeralNext {
+                    cursor_id,
+                    pc_if_next: loop_labels.loop_start,
+                });
+            }
+            Operation::CteRow { .. } => {
+                program.resolve_label(loop_labels.next, program.offset());
+            }
+            Operation::MaterializedCte { .. } => {
+                program.resolve_label(loop_labels.next, program.offset());
+                let cursor_id = program.resolve_cursor_id(&table.identifier);
+                program.emit_insn(Insn::EphemeralNext {
+                    cursor_id,
+                    pc_if_next: loop_labels.loop_start,
+                });
+            }
             Operation::Scan { iter_dir, .. } => {
                 program.resolve_label(loop_labels.next, program.offset());
                 let cursor_id = program.resolve_cursor_id(&table.identifier);
@@ -769,18 +1252,29 @@ pub fn close_loop(
             Operation::Search(search) => {
                 program.resolve_label(loop_labels.next, program.offset());
                 // Rowid equality point lookups are handled with a SeekRowid instruction which does not loop, so there is no need to emit a NextAsync instruction.
-                if !matches!(search, Search::RowidEq { .. }) {
-                    let cursor_id = match search {
-                        Search::IndexSearch { index, .. } => program.resolve_cursor_id(&index.name),
-                        Search::RowidSearch { .. } => program.resolve_cursor_id(&table.identifier),
-                        Search::RowidEq { .. } => unreachable!(),
-                    };
+                match search {
+                    Search::RowidEq { .. } => {}
+                    // The rows of an IN list search are produced by a coroutine, so like a subquery
+                    // it jumps back to the Yield instruction to get the next row.
+                    Search::InList { .. } => {
+                        program.emit_insn(Insn::Goto {
+                            target_pc: loop_labels.loop_start,
+                        });
+                    }
+                    Search::RowidSearch { .. }
+                    | Search::IndexSearch { .. }
+                    | Search::Range { .. } => {
+                        let cursor_id = match search.index() {
+                            Some(index) => resolve_index_cursor_id(program, table, index),
+                            None => program.resolve_cursor_id(&table.identifier),
+                        };
 
-                    program.emit_insn(Insn::NextAsync { cursor_id });
-                    program.emit_insn(Insn::NextAwait {
-                        cursor_id,
-                        pc_if_next: loop_labels.loop_start,
-                    });
+                        program.emit_insn(Insn::NextAsync { cursor_id });
+                        program.emit_insn(Insn::NextAwait {
+                            cursor_id,
+                            pc_if_next: loop_labels.loop_start,
+                        });
+                    }
                 }
             }
         }
@@ -807,14 +1301,7 @@ pub fn close_loop(
                 // but since it's a LEFT JOIN, we still need to emit a row with NULLs for the right table.
                 // In that case, we now enter the routine that does exactly that.
                 // First we set the right table cursor's "pseudo null bit" on, which means any Insn::Column will return NULL
-                let right_cursor_id = match &table.op {
-                    Operation::Scan { .. } => program.resolve_cursor_id(&table.identifier),
-                    Operation::Search { .. } => program.resolve_cursor_id(&table.identifier),
-                    _ => unreachable!(),
-                };
-                program.emit_insn(Insn::NullRow {
-                    cursor_id: right_cursor_id,
-                });
+                emit_null_row(program, table);
                 // Then we jump to setting the left join match flag to 1 again,
                 // but this time the right table cursor will set everything to null.
                 // This leads to emitting a row with cols from the left + nulls from the right,
@@ -831,3 +1318,116 @@ pub fn close_loop(
     }
     Ok(())
 }
+
+/// Makes the columns of the table read as NULL, until its loop moves on to another row.
+fn emit_null_row(program: &mut ProgramBuilder, table: &TableReference) {
+    match &table.op {
+        Operation::Scan { .. } | Operation::Search { .. } => {
+            program.emit_insn(Insn::NullRow {
+                cursor_id: program.resolve_cursor_id(&table.identifier),
+            });
+        }
+        // A subquery has no cursor, its columns are read from the registers of its result row.
+        Operation::Subquery {
+            plan,
+            result_columns_start_reg,
+        } => {
+            program.emit_insn(Insn::Null {
+                dest: *result_columns_start_reg,
+                dest_end: Some(*result_columns_start_reg + plan.result_columns.len() - 1),
+            });
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Emits the rows of the right tables of RIGHT and FULL OUTER JOINs that matched no row of the tables
+/// before them, once the main loop is done. Each such table is scanned again, and its rows that were
+/// not recorded as matched go through the loops of the tables after it, with NULLs for the tables
+/// before it. A right table is processed before the ones after it, whose matches it can add to.
+pub fn emit_unmatched_right_join_rows(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx,
+    plan: &mut SelectPlan,
+) -> Result<()> {
+    for table_index in 0..plan.table_references.len() {
+        let Some(right_join) = t_ctx.meta_right_joins[table_index].as_ref() else {
+            continue;
+        };
+        let matched_rows_cursor_id = right_join.matched_rows_cursor_id;
+        let labels = LoopLabels::new(program);
+        let table = &plan.table_references[table_index];
+        let cursor_id = program.resolve_cursor_id(&table.identifier);
+        program.emit_insn(Insn::RewindAsync { cursor_id });
+        program.emit_insn(Insn::RewindAwait {
+            cursor_id,
+            pc_if_empty: labels.loop_end,
+        });
+        program.resolve_label(labels.loop_start, program.offset());
+        let rowid_reg = program.alloc_registers(2);
+        program.emit_insn(Insn::RowId {
+            cursor_id,
+            dest: rowid_reg,
+        });
+        program.emit_insn(Insn::MakeRecord {
+            start_reg: rowid_reg,
+            count: 1,
+            dest_reg: rowid_reg + 1,
+        });
+        program.emit_insn(Insn::EphemeralFound {
+            cursor_id: matched_rows_cursor_id,
+            record_reg: rowid_reg + 1,
+            target_pc: labels.next,
+        });
+        for left_table in plan.table_references[..table_index].iter() {
+            emit_null_row(program, left_table);
+        }
+
+        // The loop over the table stands in for the loops of the tables before it,
+        // and the tables after it get new loops.
+        for loop_labels in t_ctx.labels_main_loop[..=table_index].iter_mut() {
+            *loop_labels = labels;
+        }
+        for later_table_index in table_index + 1..plan.table_references.len() {
+            t_ctx.labels_main_loop[later_table_index] = LoopLabels::new(program);
+            if let Some(lj_meta) = t_ctx.meta_left_joins[later_table_index].as_mut() {
+                lj_meta.label_match_flag_set_true = program.allocate_label();
+                lj_meta.label_match_flag_check_value = program.allocate_label();
+            }
+        }
+
+        // The conditions of the join were not met, but the other ones are evaluated as usual
+        let where_predicates: Vec<_> = plan
+            .where_clause
+            .iter()
+            .filter(|cond| !cond.from_outer_join)
+            .cloned()
+            .collect();
+        emit_loop_predicates(
+            program,
+            t_ctx,
+            &plan.table_references,
+            &where_predicates,
+            table_index,
+            labels.next,
+        )?;
+        open_loops_from(
+            program,
+            t_ctx,
+            &plan.table_references,
+            &plan.where_clause,
+            table_index + 1,
+        )?;
+        emit_loop(program, t_ctx, plan)?;
+        close_loops_from(program, t_ctx, &plan.table_references, table_index + 1)?;
+
+        program.resolve_label(labels.next, program.offset());
+        program.emit_insn(Insn::NextAsync { cursor_id });
+        program.emit_insn(Insn::NextAwait {
+            cursor_id,
+            pc_if_next: labels.loop_start,
+        });
+        program.resolve_label(labels.loop_end, program.offset());
+    }
+    Ok(())
+}
@@ -8,10 +8,16 @@
 //! will read rows from the database and filter them according to a WHERE clause.
 
 pub(crate) mod aggregation;
+pub(crate) mod alter;
+pub(crate) mod analyze;
+pub(crate) mod attach;
+pub(crate) mod collate;
 pub(crate) mod delete;
 pub(crate) mod emitter;
 pub(crate) mod expr;
+pub(crate) mod foreign_key;
 pub(crate) mod group_by;
+pub(crate) mod index;
 pub(crate) mod insert;
 pub(crate) mod main_loop;
 pub(crate) mod optimizer;
@@ -23,22 +29,52 @@ pub(crate) mod result_row;
 pub(crate) mod select;
 pub(crate) mod subquery;
 pub(crate) mod transaction;
+pub(crate) mod trigger;
+pub(crate) mod update;
+pub(crate) mod vacuum;
+pub(crate) mod view;
+pub(crate) mod window;
 
-use crate::schema::Schema;
+use crate::schema::{
+    affinity, quote_ident, sqlite_schema_table, sqlite_temp_schema_table, Affinity, BTreeTable,
+    Schema, MAIN_DB, TEMP_DB,
+};
 use crate::storage::pager::Pager;
 use crate::storage::sqlite3_ondisk::DatabaseHeader;
+use crate::translate::alter::translate_alter_table;
+use crate::translate::analyze::translate_analyze;
+use crate::translate::attach::{translate_attach, translate_detach};
+use crate::translate::collate::{check_column_collation, CollationSeq};
 use crate::translate::delete::translate_delete;
-use crate::util::PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX;
+use crate::translate::emitter::{epilogue, prologue, TransactionMode};
+use crate::translate::foreign_key::emit_drop_table_delete;
+use crate::translate::optimizer::optimize_plan;
+use crate::translate::plan::{select_star, Plan, SelectPlan, SelectQueryType, TableReference};
+use crate::translate::select::prepare_select_plan;
+use crate::translate::subquery::emit_subquery;
+use crate::util::{normalize_ident, PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX};
 use crate::vdbe::builder::{CursorType, ProgramBuilderOpts, QueryMode};
-use crate::vdbe::{builder::ProgramBuilder, insn::Insn, Program};
-use crate::{bail_parse_error, Connection, LimboError, Result, SymbolTable};
+use crate::vdbe::{
+    builder::ProgramBuilder,
+    insn::{CmpInsFlags, InsertFlags, Insn, RegisterOrLiteral},
+    BranchOffset, Program,
+};
+use crate::{bail_parse_error, Connection, Result, SymbolTable};
+use index::{translate_create_index, translate_drop_index, IndexDefinition};
 use insert::translate_insert;
 use limbo_sqlite3_parser::ast::{self, fmt::ToTokens, CreateVirtualTable, Delete, Insert};
 use select::translate_select;
 use std::cell::RefCell;
 use std::fmt::Display;
 use std::rc::{Rc, Weak};
-use transaction::{translate_tx_begin, translate_tx_commit};
+use transaction::{
+    translate_release, translate_rollback_to, translate_savepoint, translate_tx_begin,
+    translate_tx_commit, translate_tx_rollback,
+};
+use trigger::{translate_create_trigger, translate_drop_trigger};
+use update::translate_update;
+use vacuum::translate_vacuum;
+use view::{translate_create_view, translate_drop_view};
 
 /// Translate SQL statement into bytecode program.
 pub fn translate(
@@ -51,28 +87,77 @@ pub fn translate(
     query_mode: QueryMode,
 ) -> Result<Program> {
     let mut change_cnt_on = false;
+    let foreign_keys = connection
+        .upgrade()
+        .is_some_and(|connection| connection.foreign_keys.get());
 
-    let program = match stmt {
-        ast::Stmt::AlterTable(_) => bail_parse_error!("ALTER TABLE not supported yet"),
-        ast::Stmt::Analyze(_) => bail_parse_error!("ANALYZE not supported yet"),
-        ast::Stmt::Attach { .. } => bail_parse_error!("ATTACH not supported yet"),
+    let mut program = match stmt {
+        ast::Stmt::AlterTable(alter) => {
+            let (tbl_name, body) = *alter;
+            translate_alter_table(query_mode, schema, &tbl_name, body, syms)?
+        }
+        ast::Stmt::Analyze(name) => translate_analyze(schema, name.as_ref())?,
+        ast::Stmt::Attach { expr, db_name, key } => {
+            translate_attach(&expr, &db_name, key.as_deref(), syms)?
+        }
         ast::Stmt::Begin(tx_type, tx_name) => translate_tx_begin(tx_type, tx_name)?,
         ast::Stmt::Commit(tx_name) => translate_tx_commit(tx_name)?,
-        ast::Stmt::CreateIndex { .. } => bail_parse_error!("CREATE INDEX not supported yet"),
+        ast::Stmt::CreateIndex {
+            unique,
+            if_not_exists,
+            idx_name,
+            tbl_name,
+            columns,
+            where_clause,
+        } => translate_create_index(
+            query_mode,
+            schema,
+            IndexDefinition {
+                unique,
+                if_not_exists,
+                idx_name: &idx_name,
+                tbl_name: &tbl_name,
+                columns: &columns,
+                where_clause: where_clause.as_deref(),
+            },
+            syms,
+        )?,
         ast::Stmt::CreateTable {
             temporary,
             if_not_exists,
             tbl_name,
             body,
+        } => translate_create_table(
+            query_mode,
+            tbl_name,
+            temporary,
+            *body,
+            if_not_exists,
+            schema,
+            syms,
+        )?,
+        ast::Stmt::CreateTrigger(create_trigger) => {
+            translate_create_trigger(query_mode, schema, *create_trigger)?
+        }
+        ast::Stmt::CreateView {
+            temporary,
+            if_not_exists,
+            view_name,
+            columns,
+            select,
         } => {
             if temporary {
-                bail_parse_error!("TEMPORARY table not supported yet");
+                bail_parse_error!("TEMPORARY view not supported yet");
             }
-
-            translate_create_table(query_mode, tbl_name, *body, if_not_exists, schema)?
+            translate_create_view(
+                query_mode,
+                schema,
+                &view_name,
+                columns.as_deref(),
+                &select,
+                if_not_exists,
+            )?
         }
-        ast::Stmt::CreateTrigger { .. } => bail_parse_error!("CREATE TRIGGER not supported yet"),
-        ast::Stmt::CreateView { .. } => bail_parse_error!("CREATE VIEW not supported yet"),
         ast::Stmt::CreateVirtualTable(vtab) => {
             translate_create_virtual_table(*vtab, schema, query_mode)?
         }
@@ -81,16 +166,51 @@ pub fn translate(
                 tbl_name,
                 where_clause,
                 limit,
+                returning,
                 ..
             } = *delete;
             change_cnt_on = true;
-            translate_delete(query_mode, schema, &tbl_name, where_clause, limit, syms)?
+            translate_delete(
+                query_mode,
+                schema,
+                &tbl_name,
+                where_clause,
+                limit,
+                returning,
+                syms,
+                foreign_keys,
+            )?
+        }
+        ast::Stmt::Detach(db_name) => translate_detach(&db_name, syms)?,
+        ast::Stmt::DropIndex {
+            if_exists,
+            idx_name,
+        } => translate_drop_index(query_mode, schema, if_exists, &idx_name)?,
+        ast::Stmt::DropTable {
+            if_exists,
+            tbl_name,
+        } => {
+            let defer_foreign_keys = connection
+                .upgrade()
+                .is_some_and(|connection| connection.defer_foreign_keys.get());
+            translate_drop_table(
+                query_mode,
+                &tbl_name,
+                if_exists,
+                schema,
+                syms,
+                foreign_keys,
+                defer_foreign_keys,
+            )?
         }
-        ast::Stmt::Detach(_) => bail_parse_error!("DETACH not supported yet"),
-        ast::Stmt::DropIndex { .. } => bail_parse_error!("DROP INDEX not supported yet"),
-        ast::Stmt::DropTable { .. } => bail_parse_error!("DROP TABLE not supported yet"),
-        ast::Stmt::DropTrigger { .. } => bail_parse_error!("DROP TRIGGER not supported yet"),
-        ast::Stmt::DropView { .. } => bail_parse_error!("DROP VIEW not supported yet"),
+        ast::Stmt::DropTrigger {
+            if_exists,
+            trigger_name,
+        } => translate_drop_trigger(query_mode, schema, if_exists, &trigger_name)?,
+        ast::Stmt::DropView {
+            if_exists,
+            view_name,
+        } => translate_drop_view(query_mode, schema, if_exists, &view_name)?,
         ast::Stmt::Pragma(name, body) => pragma::translate_pragma(
             query_mode,
             schema,
@@ -98,14 +218,29 @@ pub fn translate(
             body.map(|b| *b),
             database_header.clone(),
             pager,
+            connection.clone(),
         )?,
         ast::Stmt::Reindex { .. } => bail_parse_error!("REINDEX not supported yet"),
-        ast::Stmt::Release(_) => bail_parse_error!("RELEASE not supported yet"),
-        ast::Stmt::Rollback { .. } => bail_parse_error!("ROLLBACK not supported yet"),
-        ast::Stmt::Savepoint(_) => bail_parse_error!("SAVEPOINT not supported yet"),
+        ast::Stmt::Release(name) => translate_release(name)?,
+        ast::Stmt::Rollback {
+            tx_name,
+            savepoint_name,
+        } => {
+            if let Some(savepoint_name) = savepoint_name {
+                translate_rollback_to(savepoint_name)?
+            } else {
+                translate_tx_rollback(tx_name)?
+            }
+        }
+        ast::Stmt::Savepoint(name) => translate_savepoint(name)?,
         ast::Stmt::Select(select) => translate_select(query_mode, schema, *select, syms)?,
-        ast::Stmt::Update { .. } => bail_parse_error!("UPDATE not supported yet"),
-        ast::Stmt::Vacuum(_, _) => bail_parse_error!("VACUUM not supported yet"),
+        ast::Stmt::Update(update) => {
+            change_cnt_on = true;
+            translate_update(query_mode, schema, *update, syms, foreign_keys)?
+        }
+        ast::Stmt::Vacuum(db_name, into) => {
+            translate_vacuum(schema, db_name.as_ref(), into.as_deref(), syms)?
+        }
         ast::Stmt::Insert(insert) => {
             let Insert {
                 with,
@@ -126,10 +261,12 @@ pub fn translate(
                 &body,
                 &returning,
                 syms,
+                foreign_keys,
             )?
         }
     };
 
+    program.resolve_collations(syms)?;
     Ok(program.build(database_header, connection, change_cnt_on))
 }
 
@@ -176,6 +313,8 @@ addr  opcode         p1    p2    p3    p4             p5  comment
 enum SchemaEntryType {
     Table,
     Index,
+    View,
+    Trigger,
 }
 
 impl SchemaEntryType {
@@ -183,10 +322,25 @@ impl SchemaEntryType {
         match self {
             SchemaEntryType::Table => "table",
             SchemaEntryType::Index => "index",
+            SchemaEntryType::View => "view",
+            SchemaEntryType::Trigger => "trigger",
         }
     }
 }
 const SQLITE_TABLEID: &str = "sqlite_schema";
+const SQLITE_TEMP_TABLEID: &str = "sqlite_temp_schema";
+
+/// The schema table of the database `db`: sqlite_schema, or sqlite_temp_schema for the temp database.
+fn schema_table(db: usize) -> Rc<BTreeTable> {
+    if db == TEMP_DB {
+        Rc::new(sqlite_temp_schema_table())
+    } else {
+        Rc::new(BTreeTable {
+            db,
+            ..sqlite_schema_table()
+        })
+    }
+}
 
 fn emit_schema_entry(
     program: &mut ProgramBuilder,
@@ -240,155 +394,158 @@ fn emit_schema_entry(
         cursor: sqlite_schema_cursor_id,
         key_reg: rowid_reg,
         record_reg,
-        flag: 0,
+        flag: InsertFlags::default(),
     });
     program.emit_insn(Insn::InsertAwait {
         cursor_id: sqlite_schema_cursor_id,
     });
 }
 
-struct PrimaryKeyColumnInfo<'a> {
-    name: &'a String,
-    is_descending: bool,
+/// Deletes the sqlite_schema entries of the given type and name.
+fn emit_schema_entry_delete(
+    program: &mut ProgramBuilder,
+    sqlite_schema_cursor_id: usize,
+    entry_type: SchemaEntryType,
+    name: &str,
+) {
+    let type_reg = program.emit_string8_new_reg(entry_type.as_str().to_string());
+    program.mark_last_insn_constant();
+    let name_reg = program.emit_string8_new_reg(name.to_string());
+    program.mark_last_insn_constant();
+    let column_reg = program.alloc_register();
+
+    let loop_end_label = program.allocate_label();
+    let next_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 0,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: type_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 1,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: name_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::DeleteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::DeleteAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        skip_nchange: false,
+    });
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
 }
 
-/// Check if an automatic PRIMARY KEY index is required for the table.
-/// If so, create a register for the index root page and return it.
-///
-/// An automatic PRIMARY KEY index is not required if:
-/// - The table has no PRIMARY KEY
-/// - The table has a single-column PRIMARY KEY whose typename is _exactly_ "INTEGER" e.g. not "INT".
-///   In this case, the PRIMARY KEY column becomes an alias for the rowid.
-///
-/// Otherwise, an automatic PRIMARY KEY index is required.
-fn check_automatic_pk_index_required(
+/// Check the PRIMARY KEY and UNIQUE constraints of a table, whose columns must be columns of the
+/// table. A table has at most one primary key, which a WITHOUT ROWID table must have.
+fn check_table_constraints(
     body: &ast::CreateTableBody,
-    program: &mut ProgramBuilder,
     tbl_name: &str,
-) -> Result<Option<usize>> {
-    match body {
-        ast::CreateTableBody::ColumnsAndConstraints {
-            columns,
-            constraints,
-            options,
-        } => {
-            let mut primary_key_definition = None;
-
-            // Check table constraints for PRIMARY KEY
-            if let Some(constraints) = constraints {
-                for constraint in constraints {
-                    if let ast::TableConstraint::PrimaryKey {
-                        columns: pk_cols, ..
-                    } = &constraint.constraint
-                    {
-                        let primary_key_column_results: Vec<Result<PrimaryKeyColumnInfo>> = pk_cols
-                            .iter()
-                            .map(|col| match &col.expr {
-                                ast::Expr::Id(name) => Ok(PrimaryKeyColumnInfo {
-                                    name: &name.0,
-                                    is_descending: matches!(col.order, Some(ast::SortOrder::Desc)),
-                                }),
-                                _ => Err(LimboError::ParseError(
-                                    "expressions prohibited in PRIMARY KEY and UNIQUE constraints"
-                                        .to_string(),
-                                )),
-                            })
-                            .collect();
-
-                        for result in primary_key_column_results {
-                            if let Err(e) = result {
-                                bail_parse_error!("{}", e);
-                            }
-                            let pk_info = result?;
-
-                            let column_name = pk_info.name;
-                            let column_def = columns.get(&ast::Name(column_name.clone()));
-                            if column_def.is_none() {
-                                bail_parse_error!("No such column: {}", column_name);
-                            }
-
-                            if matches!(
-                                primary_key_definition,
-                                Some(PrimaryKeyDefinitionType::Simple { .. })
-                            ) {
-                                primary_key_definition = Some(PrimaryKeyDefinitionType::Composite);
-                                continue;
-                            }
-                            if primary_key_definition.is_none() {
-                                let column_def = column_def.unwrap();
-                                let typename =
-                                    column_def.col_type.as_ref().map(|t| t.name.as_str());
-                                let is_descending = pk_info.is_descending;
-                                primary_key_definition = Some(PrimaryKeyDefinitionType::Simple {
-                                    typename,
-                                    is_descending,
-                                });
-                            }
-                        }
-                    }
+    syms: &SymbolTable,
+) -> Result<()> {
+    let ast::CreateTableBody::ColumnsAndConstraints {
+        columns,
+        constraints,
+        options,
+    } = body
+    else {
+        return Ok(());
+    };
+    let mut has_primary_key = false;
+    for (_, col_def) in columns.iter() {
+        for constraint in &col_def.constraints {
+            if matches!(
+                constraint.constraint,
+                ast::ColumnConstraint::PrimaryKey { .. }
+            ) {
+                if has_primary_key {
+                    bail_parse_error!("table {} has more than one primary key", tbl_name);
                 }
+                has_primary_key = true;
             }
-
-            // Check column constraints for PRIMARY KEY
-            for (_, col_def) in columns.iter() {
-                for constraint in &col_def.constraints {
-                    if matches!(
-                        constraint.constraint,
-                        ast::ColumnConstraint::PrimaryKey { .. }
-                    ) {
-                        if primary_key_definition.is_some() {
-                            bail_parse_error!("table {} has more than one primary key", tbl_name);
-                        }
-                        let typename = col_def.col_type.as_ref().map(|t| t.name.as_str());
-                        primary_key_definition = Some(PrimaryKeyDefinitionType::Simple {
-                            typename,
-                            is_descending: false,
-                        });
-                    }
+        }
+    }
+    for constraint in constraints.iter().flatten() {
+        let constraint_columns = match &constraint.constraint {
+            ast::TableConstraint::PrimaryKey { columns, .. } => {
+                if has_primary_key {
+                    bail_parse_error!("table {} has more than one primary key", tbl_name);
                 }
+                has_primary_key = true;
+                columns
             }
-
-            // Check if table has rowid
-            if options.contains(ast::TableOptions::WITHOUT_ROWID) {
-                bail_parse_error!("WITHOUT ROWID tables are not supported yet");
-            }
-
-            // Check if we need an automatic index
-            let needs_auto_index = if let Some(primary_key_definition) = &primary_key_definition {
-                match primary_key_definition {
-                    PrimaryKeyDefinitionType::Simple {
-                        typename,
-                        is_descending,
-                    } => {
-                        let is_integer =
-                            typename.is_some() && typename.unwrap().to_uppercase() == "INTEGER";
-                        !is_integer || *is_descending
+            ast::TableConstraint::Unique { columns, .. } => columns,
+            _ => continue,
+        };
+        for column in constraint_columns {
+            let name = match &column.expr {
+                ast::Expr::Id(name) => name,
+                // The collation of the automatic index of a UNIQUE constraint can differ from the
+                // one of the column
+                ast::Expr::Collate(expr, collation)
+                    if matches!(constraint.constraint, ast::TableConstraint::Unique { .. }) =>
+                {
+                    CollationSeq::new(collation, syms)?;
+                    match expr.as_ref() {
+                        ast::Expr::Id(name) => name,
+                        _ => bail_parse_error!(
+                            "expressions prohibited in PRIMARY KEY and UNIQUE constraints"
+                        ),
                     }
-                    PrimaryKeyDefinitionType::Composite => true,
                 }
-            } else {
-                false
+                _ => bail_parse_error!(
+                    "expressions prohibited in PRIMARY KEY and UNIQUE constraints"
+                ),
             };
-
-            if needs_auto_index {
-                let index_root_reg = program.alloc_register();
-                Ok(Some(index_root_reg))
-            } else {
-                Ok(None)
+            if !columns.contains_key(&ast::Name(name.0.clone())) {
+                bail_parse_error!("No such column: {}", name.0);
             }
         }
-        ast::CreateTableBody::AsSelect(_) => {
-            bail_parse_error!("CREATE TABLE AS SELECT not supported yet")
-        }
     }
+    if options.contains(ast::TableOptions::WITHOUT_ROWID) && !has_primary_key {
+        bail_parse_error!("PRIMARY KEY missing on table {}", tbl_name);
+    }
+    Ok(())
 }
 
 fn translate_create_table(
     query_mode: QueryMode,
     tbl_name: ast::QualifiedName,
+    temporary: bool,
     body: ast::CreateTableBody,
     if_not_exists: bool,
     schema: &Schema,
+    syms: &SymbolTable,
 ) -> Result<ProgramBuilder> {
     let mut program = ProgramBuilder::new(ProgramBuilderOpts {
         query_mode,
@@ -396,7 +553,17 @@ fn translate_create_table(
         approx_num_insns: 30,
         approx_num_labels: 1,
     });
-    if schema.get_table(tbl_name.name.0.as_str()).is_some() {
+    // A TEMP table, or one whose name is qualified with temp, goes in the temp database
+    let db = match &tbl_name.db_name {
+        None if temporary => TEMP_DB,
+        None => MAIN_DB,
+        Some(db_name) if temporary && normalize_ident(&db_name.0) != "temp" => {
+            bail_parse_error!("temporary table name must be unqualified")
+        }
+        Some(db_name) => schema.resolve_database(db_name)?,
+    };
+    // The table may have the name of a table of another database, which it then hides or is hidden by
+    if schema.get_table_in(db, tbl_name.name.0.as_str()).is_some() {
         if if_not_exists {
             let init_label = program.emit_init();
             let start_offset = program.offset();
@@ -410,8 +577,32 @@ fn translate_create_table(
         }
         bail_parse_error!("Table {} already exists", tbl_name);
     }
+    if db == MAIN_DB && schema.get_view(tbl_name.name.0.as_str()).is_some() {
+        if if_not_exists {
+            let init_label = program.emit_init();
+            let start_offset = program.offset();
+            program.emit_halt();
+            program.resolve_label(init_label, program.offset());
+            program.emit_transaction(true);
+            program.emit_constant_insns();
+            program.emit_goto(start_offset);
+
+            return Ok(program);
+        }
+        bail_parse_error!("view {} already exists", tbl_name);
+    }
+    if let ast::CreateTableBody::AsSelect(select) = body {
+        return translate_create_table_as_select(program, &tbl_name, db, *select, schema, syms);
+    }
+    if let ast::CreateTableBody::ColumnsAndConstraints { columns, .. } = &body {
+        for col_def in columns.values() {
+            check_column_collation(col_def, syms)?;
+        }
+    }
+    check_table_constraints(&body, &tbl_name.name.0, syms)?;
 
     let sql = create_table_body_to_str(&tbl_name, &body);
+    let table = BTreeTable::from_sql(&sql, 0)?;
 
     let parse_schema_label = program.allocate_label();
     let init_label = program.emit_init();
@@ -421,15 +612,15 @@ fn translate_create_table(
     // TODO: SetCookie
     // TODO: SetCookie
 
-    // Create the table B-tree
+    // Create the table B-tree. The rows of a WITHOUT ROWID table are index entries keyed by the primary key.
     let table_root_reg = program.alloc_register();
     program.emit_insn(Insn::CreateBtree {
-        db: 0,
+        db,
         root: table_root_reg,
-        flags: 1, // Table leaf page
+        flags: if table.has_rowid { 1 } else { 2 }, // Table or index leaf page
     });
 
-    // Create an automatic index B-tree if needed
+    // Create the B-trees of the automatic indexes of the PRIMARY KEY and UNIQUE constraints
     //
     // NOTE: we are deviating from SQLite bytecode here. For some reason, SQLite first creates a placeholder entry
     // for the table in sqlite_schema, then writes the index to sqlite_schema, then UPDATEs the table placeholder entry
@@ -453,23 +644,31 @@ fn translate_create_table(
     // https://github.com/sqlite/sqlite/blob/95f6df5b8d55e67d1e34d2bff217305a2f21b1fb/src/build.c#L2856-L2871
     // https://github.com/sqlite/sqlite/blob/95f6df5b8d55e67d1e34d2bff217305a2f21b1fb/src/build.c#L1334C5-L1336C65
 
-    let index_root_reg = check_automatic_pk_index_required(&body, &mut program, &tbl_name.name.0)?;
-    if let Some(index_root_reg) = index_root_reg {
+    //
+    // The primary key index of a WITHOUT ROWID table is the table itself, but it still takes a number
+    // in the names of the automatic indexes.
+    let mut index_root_regs = vec![];
+    for (i, set) in table.unique_sets.iter().enumerate() {
+        if set.is_primary_key && !table.has_rowid {
+            continue;
+        }
+        let index_root_reg = program.alloc_register();
         program.emit_insn(Insn::CreateBtree {
-            db: 0,
+            db,
             root: index_root_reg,
             flags: 2, // Index leaf page
         });
+        index_root_regs.push((i + 1, index_root_reg));
     }
 
-    let table = schema.get_btree_table(SQLITE_TABLEID).unwrap();
+    let table = schema_table(db);
     let sqlite_schema_cursor_id = program.alloc_cursor_id(
-        Some(SQLITE_TABLEID.to_owned()),
+        Some(table.name.clone()),
         CursorType::BTreeTable(table.clone()),
     );
     program.emit_insn(Insn::OpenWriteAsync {
         cursor_id: sqlite_schema_cursor_id,
-        root_page: 1,
+        root_page: 1.into(),
     });
     program.emit_insn(Insn::OpenWriteAwait {});
 
@@ -484,11 +683,11 @@ fn translate_create_table(
         Some(sql),
     );
 
-    // If we need an automatic index, add its entry to sqlite_schema
-    if let Some(index_root_reg) = index_root_reg {
+    // Add the entries of the automatic indexes to sqlite_schema
+    for (n, index_root_reg) in index_root_regs {
         let index_name = format!(
-            "{}{}_1",
-            PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX, tbl_name.name.0
+            "{}{}_{}",
+            PRIMARY_KEY_AUTOMATIC_INDEX_NAME_PREFIX, tbl_name.name.0, n
         );
         emit_schema_entry(
             &mut program,
@@ -505,9 +704,10 @@ fn translate_create_table(
     // TODO: SetCookie
     //
     // TODO: remove format, it sucks for performance but is convenient
-    let parse_schema_where_clause = format!("tbl_name = '{}' AND type != 'trigger'", tbl_name);
+    let parse_schema_where_clause =
+        format!("tbl_name = '{}' AND type != 'trigger'", tbl_name.name.0);
     program.emit_insn(Insn::ParseSchema {
-        db: sqlite_schema_cursor_id,
+        db,
         where_clause: parse_schema_where_clause,
     });
 
@@ -521,12 +721,460 @@ fn translate_create_table(
     Ok(program)
 }
 
-enum PrimaryKeyDefinitionType<'a> {
-    Simple {
-        typename: Option<&'a str>,
-        is_descending: bool,
-    },
-    Composite,
+/// Translate CREATE TABLE ... AS SELECT. The table gets a column for each result column of the query,
+/// with the declared type that matches the affinity of its expression, and the rows of the query are
+/// inserted into it as the query runs.
+fn translate_create_table_as_select(
+    mut program: ProgramBuilder,
+    tbl_name: &ast::QualifiedName,
+    db: usize,
+    select: ast::Select,
+    schema: &Schema,
+    syms: &SymbolTable,
+) -> Result<ProgramBuilder> {
+    // The planner rewrites the result column expressions, so keep them as written to name the columns
+    let result_columns = match select.body.select.as_ref() {
+        ast::OneSelect::Select(select) => select.columns.clone(),
+        ast::OneSelect::Values(_) => vec![],
+    };
+    let mut plan = prepare_select_plan(schema, select, syms, None)?;
+    optimize_plan(&mut plan, schema)?;
+    let Plan::Select(mut plan) = plan else {
+        unreachable!("prepare_select_plan() returns a select plan");
+    };
+    // The rows of the query are yielded by a coroutine, like those of a subquery
+    plan.query_type = SelectQueryType::Subquery {
+        yield_reg: 0,
+        coroutine_implementation_start: BranchOffset::Offset(0),
+    };
+    let columns = table_columns_from_select(&plan, &result_columns);
+    let sql = create_table_as_select_to_str(&tbl_name.name.0, &columns);
+    let mut table = BTreeTable::from_sql(&sql, 0)?;
+    table.db = db;
+
+    let (_, init_label, start_offset) = prologue(&mut program, syms, 0, 0)?;
+    let table_root_reg = program.alloc_register();
+    program.emit_insn(Insn::CreateBtree {
+        db,
+        root: table_root_reg,
+        flags: 1, // Table leaf page
+    });
+    let sqlite_schema = schema_table(db);
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(sqlite_schema.name.clone()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+    emit_schema_entry(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::Table,
+        &tbl_name.name.0,
+        &tbl_name.name.0,
+        table_root_reg,
+        Some(sql),
+    );
+
+    let table_cursor_id = program.alloc_cursor_id(
+        Some(tbl_name.name.0.clone()),
+        CursorType::BTreeTable(Rc::new(table)),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: table_cursor_id,
+        root_page: RegisterOrLiteral::Register(table_root_reg),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Insert each row the query yields into the new table. Like in SQLite, these inserts are
+    // not counted by changes().
+    let result_columns_start_reg = emit_subquery(&mut program, &mut plan, syms, None)?;
+    let SelectQueryType::Subquery {
+        yield_reg,
+        coroutine_implementation_start,
+    } = plan.query_type
+    else {
+        unreachable!("the query is a coroutine");
+    };
+    let label_query_done = program.allocate_label();
+    let record_reg = program.alloc_register();
+    let rowid_reg = program.alloc_register();
+    program.emit_insn(Insn::InitCoroutine {
+        yield_reg,
+        jump_on_definition: BranchOffset::Offset(0),
+        start_offset: coroutine_implementation_start,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Yield {
+        yield_reg,
+        end_offset: label_query_done,
+    });
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: result_columns_start_reg,
+        count: columns.len(),
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::NewRowid {
+        cursor: table_cursor_id,
+        rowid_reg,
+        prev_largest_reg: 0,
+    });
+    program.emit_insn(Insn::InsertAsync {
+        cursor: table_cursor_id,
+        key_reg: rowid_reg,
+        record_reg,
+        flag: InsertFlags::default().skip_nchange(),
+    });
+    program.emit_insn(Insn::InsertAwait {
+        cursor_id: table_cursor_id,
+    });
+    program.emit_insn(Insn::Goto {
+        target_pc: loop_start,
+    });
+    program.resolve_label(label_query_done, program.offset());
+
+    // TODO: SetCookie
+    let parse_schema_where_clause =
+        format!("tbl_name = '{}' AND type != 'trigger'", tbl_name.name.0);
+    program.emit_insn(Insn::ParseSchema {
+        db,
+        where_clause: parse_schema_where_clause,
+    });
+    epilogue(
+        &mut program,
+        init_label,
+        start_offset,
+        TransactionMode::Write,
+    )?;
+    Ok(program)
+}
+
+/// The name and declared type of each column of a table created from the result columns of a query.
+/// A result column is named after its alias or the column it reads, or otherwise its expression as
+/// written, and names that are already taken get a `:N` suffix.
+fn table_columns_from_select(
+    plan: &SelectPlan,
+    result_columns: &[ast::ResultColumn],
+) -> Vec<(String, &'static str)> {
+    // The name each result column gets from the query as written, if it doesn't just read a column
+    let mut written_names = Vec::with_capacity(plan.result_columns.len());
+    for result_column in result_columns {
+        match result_column {
+            ast::ResultColumn::Star => {
+                let mut star_columns = vec![];
+                select_star(&plan.table_references, &mut star_columns);
+                written_names.extend(star_columns.iter().map(|_| None));
+            }
+            ast::ResultColumn::TableStar(name) => {
+                let name = normalize_ident(&name.0);
+                let column_count = plan
+                    .table_references
+                    .iter()
+                    .find(|table| table.identifier == name)
+                    .map_or(0, |table| table.columns().len());
+                written_names.extend((0..column_count).map(|_| None));
+            }
+            ast::ResultColumn::Expr(_, Some(ast::As::As(alias) | ast::As::Elided(alias))) => {
+                written_names.push(Some(dequote_ident(&alias.0)));
+            }
+            ast::ResultColumn::Expr(expr, None) => match unparenthesized(expr) {
+                ast::Expr::Id(_) | ast::Expr::Qualified(..) | ast::Expr::DoublyQualified(..) => {
+                    written_names.push(None)
+                }
+                _ => written_names.push(Some(expr.to_string())),
+            },
+        }
+    }
+
+    let mut columns: Vec<(String, &'static str)> = Vec::with_capacity(plan.result_columns.len());
+    for (i, result_column) in plan.result_columns.iter().enumerate() {
+        let name = match (written_names.get(i), unparenthesized(&result_column.expr)) {
+            (Some(Some(name)), _) => name.clone(),
+            (_, ast::Expr::RowId { .. }) => "rowid".to_string(),
+            (_, ast::Expr::Column { table, column, .. }) if result_column.alias.is_none() => {
+                plan.table_references[*table].columns()[*column]
+                    .name
+                    .clone()
+                    .unwrap_or_default()
+            }
+            _ => match result_column.name(&plan.table_references) {
+                Some(name) => name.clone(),
+                None => result_column.expr.to_string(),
+            },
+        };
+        let base_name = match name.rsplit_once(':') {
+            Some((base, suffix))
+                if !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                base.to_string()
+            }
+            _ => name.clone(),
+        };
+        let mut unique_name = name;
+        let mut count = 0;
+        while columns
+            .iter()
+            .any(|(taken, _)| taken.eq_ignore_ascii_case(&unique_name))
+        {
+            count += 1;
+            unique_name = format!("{}:{}", base_name, count);
+        }
+        let declared_type = match expr_affinity(&result_column.expr, &plan.table_references) {
+            Some(Affinity::Text) => "TEXT",
+            Some(Affinity::Numeric) => "NUM",
+            Some(Affinity::Integer) => "INT",
+            Some(Affinity::Real) => "REAL",
+            Some(Affinity::Blob) | None => "",
+        };
+        columns.push((unique_name, declared_type));
+    }
+    columns
+}
+
+/// The affinity of an expression, which only columns and CASTs have.
+fn expr_affinity(expr: &ast::Expr, tables: &[TableReference]) -> Option<Affinity> {
+    match expr {
+        ast::Expr::Column { table, column, .. } => tables[*table]
+            .columns()
+            .get(*column)
+            .map(|column| affinity(&column.ty_str.to_uppercase())),
+        ast::Expr::RowId { .. } => Some(Affinity::Integer),
+        ast::Expr::Cast { type_name, .. } => Some(affinity(
+            &type_name
+                .as_ref()
+                .map_or(String::new(), |type_name| type_name.name.to_uppercase()),
+        )),
+        ast::Expr::Collate(expr, _) => expr_affinity(expr, tables),
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => expr_affinity(&exprs[0], tables),
+        _ => None,
+    }
+}
+
+/// The expression inside any parentheses around a single expression.
+fn unparenthesized(mut expr: &ast::Expr) -> &ast::Expr {
+    while let ast::Expr::Parenthesized(exprs) = expr {
+        match exprs.as_slice() {
+            [inner] => expr = inner,
+            _ => break,
+        }
+    }
+    expr
+}
+
+/// Strip the quotes from an identifier, keeping its case.
+fn dequote_ident(identifier: &str) -> String {
+    for (start, end) in [('"', '"'), ('[', ']'), ('`', '`'), ('\'', '\'')] {
+        if identifier.len() >= 2 && identifier.starts_with(start) && identifier.ends_with(end) {
+            let inner = &identifier[1..identifier.len() - 1];
+            return if start == end {
+                inner.replace(&format!("{}{}", end, end), &end.to_string())
+            } else {
+                inner.to_string()
+            };
+        }
+    }
+    identifier.to_string()
+}
+
+/// The CREATE TABLE statement of a table created by CREATE TABLE ... AS SELECT, as SQLite writes it:
+/// the column definitions are on one line, or on a line each if they don't fit.
+fn create_table_as_select_to_str(tbl_name: &str, columns: &[(String, &str)]) -> String {
+    // The length of a name as a quoted identifier
+    let quoted_len = |name: &str| name.len() + name.matches('"').count() + 2;
+    let line_len = quoted_len(tbl_name)
+        + columns
+            .iter()
+            .map(|(name, _)| quoted_len(name) + 5)
+            .sum::<usize>();
+    let (first_sep, sep, end) = if line_len < 50 {
+        ("", ",", ")")
+    } else {
+        ("\n  ", ",\n  ", "\n)")
+    };
+    let mut sql = format!("CREATE TABLE {}(", tbl_name);
+    for (i, (name, declared_type)) in columns.iter().enumerate() {
+        sql.push_str(if i == 0 { first_sep } else { sep });
+        sql.push_str(&quote_ident(name));
+        if !declared_type.is_empty() {
+            sql.push(' ');
+            sql.push_str(declared_type);
+        }
+    }
+    sql.push_str(end);
+    sql
+}
+
+/*
+Example (from SQLite, with the bookkeeping of moved root pages elided):
+sqlite> CREATE TABLE t(a, b);
+sqlite> CREATE INDEX t_a ON t(a);
+sqlite> EXPLAIN DROP TABLE t;
+addr  opcode         p1    p2    p3    p4             p5  comment
+----  -------------  ----  ----  ----  -------------  --  -------------
+0     Init           0     60    0                    0   Start at 60
+1     Null           0     1     0                    0   r[1]=NULL
+2     OpenWrite      0     1     0     5              0   root=1 iDb=0; sqlite_master
+3     Rewind         0     11    0                    0
+4       Column         0     2     2                    0   r[2]= cursor 0 column 2
+5       Ne             3     10    2     BINARY-8       82  if r[2]!=r[3] goto 10
+6       Column         0     0     2                    0   r[2]= cursor 0 column 0
+7       Eq             4     10    2     BINARY-8       82  if r[2]==r[4] goto 10
+8       Rowid          0     5     0                    0   r[5]=sqlite_master.rowid
+9       Delete         0     0     0                    2
+10    Next           0     4     0                    1
+11    Destroy        3     2     0                    0
+...
+34    Destroy        2     2     0                    0
+...
+57    DropTable      0     0     0     t              0
+58    SetCookie      0     1     3                    0
+59    Halt           0     0     0                    0
+60    Transaction    0     1     2     0              1   usesStmtJournal=1
+61    String8        0     3     0     t              0   r[3]='t'
+62    String8        0     4     0     trigger        0   r[4]='trigger'
+63    Goto           0     1     0                    0
+*/
+fn translate_drop_table(
+    query_mode: QueryMode,
+    tbl_name: &ast::QualifiedName,
+    if_exists: bool,
+    schema: &Schema,
+    syms: &SymbolTable,
+    foreign_keys: bool,
+    defer_foreign_keys: bool,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 30,
+        approx_num_labels: 2,
+    });
+    let table = match schema.resolve_table(tbl_name) {
+        Ok(table) => table,
+        Err(_) if if_exists => None,
+        Err(err) => return Err(err),
+    };
+    let Some(table) = table else {
+        if schema.get_view(&tbl_name.name.0).is_some() {
+            bail_parse_error!("use DROP VIEW to delete view {}", tbl_name.name.0);
+        }
+        if if_exists {
+            let init_label = program.emit_init();
+            let start_offset = program.offset();
+            program.emit_halt();
+            program.resolve_label(init_label, program.offset());
+            program.emit_transaction(true);
+            program.emit_constant_insns();
+            program.emit_goto(start_offset);
+
+            return Ok(program);
+        }
+        bail_parse_error!("no such table: {}", tbl_name.name.0);
+    };
+    let table_name = table.get_name();
+    if table_name.to_lowercase().starts_with("sqlite_") {
+        bail_parse_error!("table {} may not be dropped", table_name);
+    }
+
+    let db = table.btree().map_or(MAIN_DB, |table| table.db);
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    if foreign_keys {
+        if let Some(btree_table) = table.btree() {
+            emit_drop_table_delete(&mut program, schema, syms, &btree_table, defer_foreign_keys)?;
+        }
+    }
+
+    let sqlite_schema = schema_table(db);
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(sqlite_schema.name.clone()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Remove the entries of the table and its indexes from sqlite_schema
+    let table_name_reg = program.emit_string8_new_reg(table_name.to_string());
+    program.mark_last_insn_constant();
+    let column_reg = program.alloc_register();
+    let loop_end_label = program.allocate_label();
+    let next_label = program.allocate_label();
+    program.emit_insn(Insn::RewindAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::RewindAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_empty: loop_end_label,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Column {
+        cursor_id: sqlite_schema_cursor_id,
+        column: 2,
+        dest: column_reg,
+    });
+    program.emit_insn(Insn::Ne {
+        lhs: column_reg,
+        rhs: table_name_reg,
+        target_pc: next_label,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::DeleteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::DeleteAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        skip_nchange: false,
+    });
+    program.resolve_label(next_label, program.offset());
+    program.emit_insn(Insn::NextAsync {
+        cursor_id: sqlite_schema_cursor_id,
+    });
+    program.emit_insn(Insn::NextAwait {
+        cursor_id: sqlite_schema_cursor_id,
+        pc_if_next: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+
+    // Free the pages of the table and index b-trees. Virtual tables have none.
+    if let Some(btree_table) = table.btree() {
+        let former_root_reg = program.alloc_register();
+        let root_pages = std::iter::once(btree_table.root_page).chain(
+            schema
+                .get_table_indices(&btree_table)
+                .iter()
+                .map(|index| index.root_page),
+        );
+        for root in root_pages {
+            program.emit_insn(Insn::Destroy {
+                root,
+                former_root_reg,
+                db,
+            });
+        }
+    }
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::DropTable {
+        db,
+        table_name: table_name.to_string(),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
 }
 
 struct TableFormatter<'a> {
@@ -548,7 +1196,9 @@ fn create_table_body_to_str(tbl_name: &ast::QualifiedName, body: &ast::CreateTab
             constraints: _,
             options: _,
         } => {}
-        ast::CreateTableBody::AsSelect(_select) => todo!("as select not yet supported"),
+        ast::CreateTableBody::AsSelect(_) => {
+            unreachable!("CREATE TABLE ... AS SELECT has its own statement text")
+        }
     }
     sql
 }
@@ -654,7 +1304,7 @@ fn translate_create_virtual_table(
     );
     program.emit_insn(Insn::OpenWriteAsync {
         cursor_id: sqlite_schema_cursor_id,
-        root_page: 1,
+        root_page: 1.into(),
     });
     program.emit_insn(Insn::OpenWriteAwait {});
 
@@ -671,7 +1321,7 @@ fn translate_create_virtual_table(
 
     let parse_schema_where_clause = format!("tbl_name = '{}' AND type != 'trigger'", table_name);
     program.emit_insn(Insn::ParseSchema {
-        db: sqlite_schema_cursor_id,
+        db: MAIN_DB,
         where_clause: parse_schema_where_clause,
     });
 
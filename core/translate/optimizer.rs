@@ -3,19 +3,34 @@ use std::{collections::HashMap, rc::Rc};
 use limbo_sqlite3_parser::ast;
 
 use crate::{
-    schema::{Index, Schema},
+    schema::{Index, Order, Schema},
     Result,
 };
 
+use super::collate::{comparison_collation, expr_collation};
 use super::plan::{
-    DeletePlan, Direction, IterationDirection, Operation, Plan, Search, SelectPlan, TableReference,
-    WhereTerm,
+    DeletePlan, Direction, EvalAt, IterationDirection, Operation, Plan, ResultSetColumn, Search,
+    SelectPlan, TableReference, UpdatePlan, WhereTerm,
 };
+use super::planner::determine_where_to_eval_expr;
+
+/// The number of rows assumed for a table that ANALYZE has no statistics for, as in SQLite.
+const DEFAULT_ROW_COUNT: f64 = 1_048_576.0;
+/// The number of rows assumed to have the same value in the first column of an index that ANALYZE
+/// has no statistics for, as in SQLite.
+const DEFAULT_ROWS_PER_KEY: f64 = 10.0;
+/// The fraction of the rows of a table assumed to be on the right side of each bound of a range.
+const RANGE_BOUND_SELECTIVITY: f64 = 0.25;
+/// The fraction of the rows assumed to satisfy a WHERE term that is not used to search a table.
+const TERM_SELECTIVITY: f64 = 0.25;
+/// Joins of more tables keep the order of the FROM clause rather than comparing every order.
+const MAX_REORDERED_TABLES: usize = 10;
 
 pub fn optimize_plan(plan: &mut Plan, schema: &Schema) -> Result<()> {
     match plan {
         Plan::Select(plan) => optimize_select_plan(plan, schema),
         Plan::Delete(plan) => optimize_delete_plan(plan, schema),
+        Plan::Update(plan) => optimize_update_plan(plan, schema),
     }
 }
 
@@ -25,6 +40,7 @@ pub fn optimize_plan(plan: &mut Plan, schema: &Schema) -> Result<()> {
  * but having them separate makes them easier to understand
  */
 fn optimize_select_plan(plan: &mut SelectPlan, schema: &Schema) -> Result<()> {
+    push_predicates_into_subqueries(plan)?;
     optimize_subqueries(plan, schema)?;
     rewrite_exprs_select(plan)?;
     if let ConstantConditionEliminationResult::ImpossibleCondition =
@@ -34,10 +50,13 @@ fn optimize_select_plan(plan: &mut SelectPlan, schema: &Schema) -> Result<()> {
         return Ok(());
     }
 
+    let available_indexes = schema.all_indices();
+    reorder_joins(plan, &available_indexes, schema)?;
     use_indexes(
         &mut plan.table_references,
-        &schema.indexes,
+        &available_indexes,
         &mut plan.where_clause,
+        schema,
     )?;
 
     eliminate_unnecessary_orderby(plan, schema)?;
@@ -46,6 +65,9 @@ fn optimize_select_plan(plan: &mut SelectPlan, schema: &Schema) -> Result<()> {
 }
 
 fn optimize_delete_plan(plan: &mut DeletePlan, schema: &Schema) -> Result<()> {
+    for subquery in plan.subqueries.iter_mut() {
+        optimize_select_plan(&mut subquery.plan, schema)?;
+    }
     rewrite_exprs_delete(plan)?;
     if let ConstantConditionEliminationResult::ImpossibleCondition =
         eliminate_constant_conditions(&mut plan.where_clause)?
@@ -54,10 +76,35 @@ fn optimize_delete_plan(plan: &mut DeletePlan, schema: &Schema) -> Result<()> {
         return Ok(());
     }
 
+    // The rows are deleted from every index of the table while the loop is running,
+    // so the loop itself must not iterate over one of them.
+    use_indexes(
+        &mut plan.table_references,
+        &HashMap::new(),
+        &mut plan.where_clause,
+        schema,
+    )?;
+
+    Ok(())
+}
+
+fn optimize_update_plan(plan: &mut UpdatePlan, schema: &Schema) -> Result<()> {
+    for subquery in plan.subqueries.iter_mut() {
+        optimize_select_plan(&mut subquery.plan, schema)?;
+    }
+    rewrite_exprs_update(plan)?;
+    if let ConstantConditionEliminationResult::ImpossibleCondition =
+        eliminate_constant_conditions(&mut plan.where_clause)?
+    {
+        plan.contains_constant_false_condition = true;
+        return Ok(());
+    }
+
     use_indexes(
         &mut plan.table_references,
-        &schema.indexes,
+        &schema.all_indices(),
         &mut plan.where_clause,
+        schema,
     )?;
 
     Ok(())
@@ -65,14 +112,146 @@ fn optimize_delete_plan(plan: &mut DeletePlan, schema: &Schema) -> Result<()> {
 
 fn optimize_subqueries(plan: &mut SelectPlan, schema: &Schema) -> Result<()> {
     for table in plan.table_references.iter_mut() {
-        if let Operation::Subquery { plan, .. } = &mut table.op {
-            optimize_select_plan(&mut *plan, schema)?;
+        match &mut table.op {
+            Operation::Subquery { plan, .. } | Operation::MaterializedCte { plan, .. } => {
+                optimize_select_plan(&mut *plan, schema)?;
+            }
+            Operation::RecursiveCte {
+                initial, recursive, ..
+            } => {
+                optimize_select_plan(&mut *initial, schema)?;
+                optimize_select_plan(&mut *recursive, schema)?;
+            }
+            _ => {}
         }
     }
+    for subquery in plan.subqueries.iter_mut() {
+        optimize_select_plan(&mut subquery.plan, schema)?;
+    }
 
     Ok(())
 }
 
+/// Moves the WHERE terms that only refer to the columns of a FROM clause subquery (e.g. an expanded view)
+/// into the WHERE clause of the subquery, so that the subquery produces fewer rows and can use its indexes
+/// to find them. For example, in `SELECT * FROM (SELECT a, b + c AS s FROM t) WHERE a = 5`,
+/// the term `a = 5` becomes a term of the subquery on the column `t.a`.
+fn push_predicates_into_subqueries(plan: &mut SelectPlan) -> Result<()> {
+    let mut i = 0;
+    while i < plan.where_clause.len() {
+        let term = &plan.where_clause[i];
+        let EvalAt::Loop(table_index) = term.eval_at else {
+            i += 1;
+            continue;
+        };
+        let table = &mut plan.table_references[table_index];
+        let is_outer_join = table.join_info.as_ref().is_some_and(|j| j.outer);
+        let Operation::Subquery { plan: subplan, .. } = &mut table.op else {
+            i += 1;
+            continue;
+        };
+        // Filtering the rows of the subquery is only the same as filtering its result rows
+        // if every result row comes from exactly one row of the subquery's loops.
+        let can_push = !term.from_outer_join
+            && !is_outer_join
+            && subplan.group_by.is_none()
+            && subplan.aggregates.is_empty()
+            && subplan.windows.is_empty()
+            && subplan.limit.is_none()
+            && subplan.offset.is_none()
+            && subplan
+                .table_references
+                .iter()
+                .all(|t| t.join_info.as_ref().is_none_or(|j| !j.outer && !j.right));
+        if !can_push {
+            i += 1;
+            continue;
+        }
+        let mut expr = term.expr.clone();
+        if !substitute_subquery_columns(&mut expr, table_index, &subplan.result_columns) {
+            i += 1;
+            continue;
+        }
+        let eval_at = determine_where_to_eval_expr(&expr)?;
+        subplan.where_clause.push(WhereTerm {
+            expr,
+            from_outer_join: false,
+            eval_at,
+        });
+        plan.where_clause.remove(i);
+    }
+
+    Ok(())
+}
+
+/// Replaces the references to the columns of the subquery at `table_index` in `expr` with the
+/// expressions of the subquery's result columns. Returns false, leaving `expr` in an unspecified state,
+/// if the expression refers to anything else than the subquery columns, or if a column refers to
+/// a result column whose expression cannot be evaluated in a WHERE clause of the subquery.
+fn substitute_subquery_columns(
+    expr: &mut ast::Expr,
+    table_index: usize,
+    result_columns: &[ResultSetColumn],
+) -> bool {
+    match expr {
+        ast::Expr::Column { table, column, .. } => {
+            if *table != table_index {
+                return false;
+            }
+            let result_expr = &result_columns[*column].expr;
+            if !is_simple_expr(result_expr) {
+                return false;
+            }
+            *expr = result_expr.clone();
+            true
+        }
+        ast::Expr::Literal(_) => true,
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => [lhs, start, end]
+            .into_iter()
+            .all(|e| substitute_subquery_columns(e, table_index, result_columns)),
+        ast::Expr::Binary(lhs, _, rhs) => {
+            substitute_subquery_columns(lhs, table_index, result_columns)
+                && substitute_subquery_columns(rhs, table_index, result_columns)
+        }
+        ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Unary(_, expr) => {
+            substitute_subquery_columns(expr, table_index, result_columns)
+        }
+        ast::Expr::InList { lhs, rhs, .. } => {
+            substitute_subquery_columns(lhs, table_index, result_columns)
+                && rhs
+                    .iter_mut()
+                    .flatten()
+                    .all(|e| substitute_subquery_columns(e, table_index, result_columns))
+        }
+        ast::Expr::Parenthesized(exprs) => exprs
+            .iter_mut()
+            .all(|e| substitute_subquery_columns(e, table_index, result_columns)),
+        _ => false,
+    }
+}
+
+/// Whether the expression is built only from columns, literals and operators,
+/// so that evaluating it somewhere else in a query gives the same value.
+fn is_simple_expr(expr: &ast::Expr) -> bool {
+    match expr {
+        ast::Expr::Column { .. } | ast::Expr::RowId { .. } | ast::Expr::Literal(_) => true,
+        ast::Expr::Binary(lhs, _, rhs) => is_simple_expr(lhs) && is_simple_expr(rhs),
+        ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Unary(_, expr) => is_simple_expr(expr),
+        ast::Expr::Parenthesized(exprs) => exprs.iter().all(is_simple_expr),
+        _ => false,
+    }
+}
+
 fn query_is_already_ordered_by(
     table_references: &[TableReference],
     key: &mut ast::Expr,
@@ -83,17 +262,39 @@ fn query_is_already_ordered_by(
         return Ok(false);
     }
     let table_reference = first_table.unwrap();
+    // The rows of a WITHOUT ROWID table and of an index are in the order of the collation of their columns
+    let collation = expr_collation(key, table_references).unwrap_or_default();
+    let column_collation_matches = |index: &Index| {
+        index
+            .columns
+            .first()
+            .map(|column| column.collation.clone().unwrap_or_default())
+            == Some(collation)
+    };
     match &table_reference.op {
-        Operation::Scan { .. } => Ok(key.is_rowid_alias_of(0)),
+        Operation::Scan { .. } => Ok(key.is_rowid_alias_of(0)
+            || (key.is_primary_key_of(0, table_reference)
+                && table_reference
+                    .btree()
+                    .and_then(|table| table.primary_key_index())
+                    .is_some_and(|index| column_collation_matches(&index)))),
         Operation::Search(search) => match search {
             Search::RowidEq { .. } => Ok(key.is_rowid_alias_of(0)),
             Search::RowidSearch { .. } => Ok(key.is_rowid_alias_of(0)),
-            Search::IndexSearch { index, .. } => {
+            Search::IndexSearch { index, .. }
+            | Search::Range {
+                index: Some(index), ..
+            } => {
                 let index_rc = key.check_index_scan(0, &table_reference, available_indexes)?;
-                let index_is_the_same =
-                    index_rc.map(|irc| Rc::ptr_eq(index, &irc)).unwrap_or(false);
+                let index_is_the_same = index_rc
+                    .map(|irc| irc.root_page == index.root_page)
+                    .unwrap_or(false)
+                    && column_collation_matches(index);
                 Ok(index_is_the_same)
             }
+            Search::Range { index: None, .. } => Ok(key.is_rowid_alias_of(0)),
+            // The values of an IN list are searched in the order they are listed in
+            Search::InList { .. } => Ok(false),
         },
         _ => Ok(false),
     }
@@ -106,6 +307,18 @@ fn eliminate_unnecessary_orderby(plan: &mut SelectPlan, schema: &Schema) -> Resu
     if plan.table_references.len() == 0 {
         return Ok(());
     }
+    // The rows come out of the window sorter in the order of the window, not the order of the scan
+    if !plan.windows.is_empty() {
+        return Ok(());
+    }
+    // The rows of the right table of a RIGHT or FULL OUTER JOIN that had no match come last
+    if plan
+        .table_references
+        .iter()
+        .any(|table| table.join_info.as_ref().is_some_and(|join| join.right))
+    {
+        return Ok(());
+    }
 
     let o = plan.order_by.as_mut().unwrap();
 
@@ -116,8 +329,15 @@ fn eliminate_unnecessary_orderby(plan: &mut SelectPlan, schema: &Schema) -> Resu
 
     let (key, direction) = o.first_mut().unwrap();
 
+    // Searches always iterate forwards
+    if *direction == Direction::Descending
+        && matches!(plan.table_references[0].op, Operation::Search(_))
+    {
+        return Ok(());
+    }
+
     let already_ordered =
-        query_is_already_ordered_by(&plan.table_references, key, &schema.indexes)?;
+        query_is_already_ordered_by(&plan.table_references, key, &schema.all_indices())?;
 
     if already_ordered {
         push_scan_direction(&mut plan.table_references[0], direction);
@@ -128,46 +348,428 @@ fn eliminate_unnecessary_orderby(plan: &mut SelectPlan, schema: &Schema) -> Resu
 }
 
 /**
- * Use indexes where possible.
+ * Use indexes where it is cheaper than scanning the table.
  * Right now we make decisions about using indexes ONLY based on condition expressions, not e.g. ORDER BY or others.
  * This is just because we are WIP.
  *
  * When this function is called, condition expressions from both the actual WHERE clause and the JOIN clauses are in the where_clause vector.
- * If we find a condition that can be used to index scan, we pop it off from the where_clause vector and put it into a Search operation.
+ * Of the conditions that can be used to search a table, we pick the one whose search is estimated to read the fewest rows,
+ * pop it off from the where_clause vector and put it into a Search operation.
  * We put it there simply because it makes it a bit easier to track during translation.
  */
 fn use_indexes(
     table_references: &mut [TableReference],
     available_indexes: &HashMap<String, Vec<Rc<Index>>>,
     where_clause: &mut Vec<WhereTerm>,
+    schema: &Schema,
 ) -> Result<()> {
     if where_clause.is_empty() {
         return Ok(());
     }
 
-    'outer: for (table_index, table_reference) in table_references.iter_mut().enumerate() {
-        if let Operation::Scan { .. } = &mut table_reference.op {
-            let mut i = 0;
-            while i < where_clause.len() {
-                let cond = where_clause.get_mut(i).unwrap();
-                if let Some(index_search) = try_extract_index_search_expression(
-                    cond,
-                    table_index,
-                    &table_reference,
-                    available_indexes,
-                )? {
-                    where_clause.remove(i);
-                    table_reference.op = Operation::Search(index_search);
-                    continue 'outer;
-                }
-                i += 1;
+    for table_index in 0..table_references.len() {
+        let table_reference = &table_references[table_index];
+        if let Operation::Scan { .. } = &table_reference.op {
+            // The right table of an OUTER JOIN is searched with the conditions of the join only,
+            // since the other conditions are evaluated after a row with NULLs may have been emitted.
+            let is_outer_join = table_reference
+                .join_info
+                .as_ref()
+                .is_some_and(|join| join.outer || join.right);
+            let (search, _) = best_access(
+                table_index,
+                table_references,
+                where_clause,
+                |term| {
+                    where_clause[term].should_eval_at_loop(table_index)
+                        && (!is_outer_join || where_clause[term].from_outer_join)
+                },
+                available_indexes,
+                schema,
+            )?;
+            if let Some((term, search)) = search {
+                where_clause.remove(term);
+                table_references[table_index].op = Operation::Search(search);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The estimated cost of a loop over a table, in rows read, and the number of rows it produces.
+#[derive(Debug, Clone, Copy)]
+struct AccessCost {
+    cost: f64,
+    rows: f64,
+}
+
+/// The cheapest way to loop over the table at `table_index`: a scan, or a search using one of the
+/// WHERE terms for which `usable` is true, given the index of the term. Returns the index of the
+/// term and the search if it is a search, and the cost of the loop, whose rows are filtered by the
+/// other usable terms.
+fn best_access(
+    table_index: usize,
+    table_references: &[TableReference],
+    where_clause: &[WhereTerm],
+    usable: impl Fn(usize) -> bool,
+    available_indexes: &HashMap<String, Vec<Rc<Index>>>,
+    schema: &Schema,
+) -> Result<(Option<(usize, Search)>, AccessCost)> {
+    let table_reference = &table_references[table_index];
+    let row_count = estimated_row_count(table_reference, schema);
+    let mut best_search = None;
+    let mut best_cost = AccessCost {
+        cost: row_count,
+        rows: row_count,
+    };
+    let mut num_usable_terms = 0;
+    for (i, term) in where_clause.iter().enumerate() {
+        if !usable(i) {
+            continue;
+        }
+        num_usable_terms += 1;
+        // The term is usable at the loop of the table even if the tables are not in this order yet
+        let mut term = WhereTerm {
+            eval_at: EvalAt::Loop(table_index),
+            ..term.clone()
+        };
+        let Some(search) = try_extract_index_search_expression(
+            &mut term,
+            table_index,
+            table_references,
+            available_indexes,
+        )?
+        else {
+            continue;
+        };
+        let cost = estimate_search(&search, table_reference, row_count, schema);
+        // A search is preferred to a scan of the same cost, and the first term to the later ones
+        let is_cheaper = match best_search {
+            None => cost.cost <= best_cost.cost,
+            Some(_) => cost.cost < best_cost.cost,
+        };
+        if is_cheaper {
+            best_search = Some((i, search));
+            best_cost = cost;
+        }
+    }
+    let num_filtering_terms = num_usable_terms - best_search.is_some() as i32;
+    best_cost.rows *= TERM_SELECTIVITY.powi(num_filtering_terms);
+    Ok((best_search, best_cost))
+}
+
+/// The estimated number of rows of a table, from the statistics of ANALYZE if there are any.
+fn estimated_row_count(table_reference: &TableReference, schema: &Schema) -> f64 {
+    table_reference
+        .btree()
+        .and_then(|table| {
+            schema
+                .get_table_stats(&table)
+                .map(|stats| stats.row_count as f64)
+        })
+        .unwrap_or(DEFAULT_ROW_COUNT)
+}
+
+/// The estimated number of rows of a table of `row_count` rows that have the same value in the
+/// first column of `index`.
+fn estimated_rows_per_key(
+    table_reference: &TableReference,
+    index: &Index,
+    row_count: f64,
+    schema: &Schema,
+) -> f64 {
+    if index.unique && index.columns.len() == 1 {
+        return 1.0;
+    }
+    table_reference
+        .btree()
+        .and_then(|table| {
+            let stats = schema.get_table_stats(&table)?;
+            let rows_per_key = stats.index_rows_per_key(&table, index)?;
+            rows_per_key.first().map(|rows| *rows as f64)
+        })
+        .unwrap_or(DEFAULT_ROWS_PER_KEY.min(row_count))
+}
+
+/// The estimated cost of one search of a table of `row_count` rows. Each seek costs the depth of
+/// the b-tree, and each row found through a secondary index is looked up in the table again.
+fn estimate_search(
+    search: &Search,
+    table_reference: &TableReference,
+    row_count: f64,
+    schema: &Schema,
+) -> AccessCost {
+    let seek_cost = (row_count + 1.0).log2();
+    let (num_seeks, rows) = match search {
+        Search::RowidEq { .. } => (1.0, 1.0),
+        Search::RowidSearch { .. } => (1.0, row_count * RANGE_BOUND_SELECTIVITY),
+        Search::IndexSearch { index, cmp_op, .. } => match cmp_op {
+            ast::Operator::Equals => (
+                1.0,
+                estimated_rows_per_key(table_reference, index, row_count, schema),
+            ),
+            _ => (1.0, row_count * RANGE_BOUND_SELECTIVITY),
+        },
+        Search::Range { .. } => (1.0, row_count * RANGE_BOUND_SELECTIVITY.powi(2)),
+        Search::InList { index, values } => {
+            let rows_per_value = match index {
+                Some(index) => estimated_rows_per_key(table_reference, index, row_count, schema),
+                None => 1.0,
+            };
+            (values.len() as f64, values.len() as f64 * rows_per_value)
+        }
+    };
+    let rows = rows.min(row_count);
+    let is_secondary_index = search.index().is_some_and(|index| {
+        table_reference
+            .btree()
+            .is_some_and(|table| !table.is_primary_key_index(index))
+    });
+    let row_cost = if is_secondary_index { 2.0 } else { 1.0 };
+    AccessCost {
+        cost: num_seeks * seek_cost + rows * row_cost,
+        rows,
+    }
+}
+
+/// Reorder the tables of an inner join so that the nested loops over them are estimated to read the
+/// fewest rows, by comparing the cheapest order of every set of tables, built from the cheapest
+/// orders of its subsets. The cost of a loop over a table depends on the tables of the outer loops,
+/// whose columns the WHERE terms can use to search it, and is paid for each row they produce.
+/// The order of the FROM clause is kept unless another one is cheaper.
+fn reorder_joins(
+    plan: &mut SelectPlan,
+    available_indexes: &HashMap<String, Vec<Rc<Index>>>,
+    schema: &Schema,
+) -> Result<()> {
+    let num_tables = plan.table_references.len();
+    if !(2..=MAX_REORDERED_TABLES).contains(&num_tables) {
+        return Ok(());
+    }
+    // The right table of an outer join must be looped over inside the loops of the tables before it,
+    // and subqueries produce their rows without being searched.
+    if plan.table_references.iter().any(|table| {
+        table.btree().is_none()
+            || !matches!(table.op, Operation::Scan { .. })
+            || table
+                .join_info
+                .as_ref()
+                .is_some_and(|join| join.outer || join.right)
+    }) {
+        return Ok(());
+    }
+    let term_tables: Vec<usize> = plan
+        .where_clause
+        .iter()
+        .map(|term| {
+            (0..num_tables)
+                .filter(|&table_index| references_table(&term.expr, table_index))
+                .fold(0, |tables, table_index| tables | 1 << table_index)
+        })
+        .collect();
+    // For each set of tables, the cost of the cheapest order found for them, the number of rows
+    // their loops produce, and the order.
+    let mut best_orders: Vec<Option<(f64, f64, Vec<usize>)>> = vec![None; 1 << num_tables];
+    best_orders[0] = Some((0.0, 1.0, vec![]));
+    for tables in 0..best_orders.len() {
+        let Some((cost, rows, order)) = best_orders[tables].clone() else {
+            continue;
+        };
+        for table_index in 0..num_tables {
+            if tables & 1 << table_index != 0 {
+                continue;
+            }
+            let available_tables = tables | 1 << table_index;
+            let (_, access) = best_access(
+                table_index,
+                &plan.table_references,
+                &plan.where_clause,
+                |term| {
+                    term_tables[term] & 1 << table_index != 0
+                        && term_tables[term] & !available_tables == 0
+                },
+                available_indexes,
+                schema,
+            )?;
+            let new_cost = cost + rows * access.cost;
+            // Orders of about the same cost are not worth swapping for
+            let is_cheaper = best_orders[available_tables]
+                .as_ref()
+                .map_or(true, |(best_cost, _, _)| {
+                    new_cost < best_cost * (1.0 - 1e-9)
+                });
+            if is_cheaper {
+                let mut new_order = order.clone();
+                new_order.push(table_index);
+                best_orders[available_tables] = Some((new_cost, rows * access.rows, new_order));
             }
         }
     }
+    let (_, _, order) = best_orders.pop().flatten().unwrap();
+    if order
+        .iter()
+        .enumerate()
+        .all(|(i, table_index)| i == *table_index)
+    {
+        return Ok(());
+    }
+    let mut new_positions = vec![0; num_tables];
+    for (position, table_index) in order.iter().enumerate() {
+        new_positions[*table_index] = position;
+    }
+    let mut tables: Vec<_> = std::mem::take(&mut plan.table_references)
+        .into_iter()
+        .map(Some)
+        .collect();
+    plan.table_references = order
+        .iter()
+        .map(|table_index| tables[*table_index].take().unwrap())
+        .collect();
+    remap_tables_select(plan, &new_positions)
+}
 
+/// Make the column references of the plan refer to the tables by their new positions, after the
+/// table at each index `i` moved to `new_positions[i]`, and evaluate the WHERE terms at the loop of
+/// the last table they refer to in the new order.
+fn remap_tables_select(plan: &mut SelectPlan, new_positions: &[usize]) -> Result<()> {
+    for rc in plan.result_columns.iter_mut() {
+        remap_tables(&mut rc.expr, new_positions);
+    }
+    for agg in plan.aggregates.iter_mut() {
+        remap_tables(&mut agg.original_expr, new_positions);
+        for arg in agg.args.iter_mut() {
+            remap_tables(arg, new_positions);
+        }
+        if let Some(filter) = &mut agg.filter {
+            remap_tables(filter, new_positions);
+        }
+    }
+    for term in plan.where_clause.iter_mut() {
+        remap_tables(&mut term.expr, new_positions);
+        term.eval_at = determine_where_to_eval_expr(&term.expr)?;
+    }
+    if let Some(group_by) = &mut plan.group_by {
+        for expr in group_by.exprs.iter_mut() {
+            remap_tables(expr, new_positions);
+        }
+        for expr in group_by.having.iter_mut().flatten() {
+            remap_tables(expr, new_positions);
+        }
+    }
+    if let Some(order_by) = &mut plan.order_by {
+        for (expr, _) in order_by.iter_mut() {
+            remap_tables(expr, new_positions);
+        }
+    }
+    for window in plan.windows.iter_mut() {
+        for expr in window.partition_by.iter_mut() {
+            remap_tables(expr, new_positions);
+        }
+        for (expr, _) in window.order_by.iter_mut() {
+            remap_tables(expr, new_positions);
+        }
+        for func in window.functions.iter_mut() {
+            remap_tables(&mut func.original_expr, new_positions);
+            for arg in func.args.iter_mut() {
+                remap_tables(arg, new_positions);
+            }
+        }
+    }
     Ok(())
 }
 
+/// Make the column references of the expression refer to the tables by their new positions.
+fn remap_tables(expr: &mut ast::Expr, new_positions: &[usize]) {
+    match expr {
+        ast::Expr::Column { table, .. } | ast::Expr::RowId { table, .. } => {
+            *table = new_positions[*table];
+        }
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => {
+            remap_tables(lhs, new_positions);
+            remap_tables(start, new_positions);
+            remap_tables(end, new_positions);
+        }
+        ast::Expr::Binary(lhs, _, rhs) => {
+            remap_tables(lhs, new_positions);
+            remap_tables(rhs, new_positions);
+        }
+        ast::Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            for expr in base.iter_mut().chain(else_expr.iter_mut()) {
+                remap_tables(expr, new_positions);
+            }
+            for (when, then) in when_then_pairs.iter_mut() {
+                remap_tables(when, new_positions);
+                remap_tables(then, new_positions);
+            }
+        }
+        ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Unary(_, expr) => remap_tables(expr, new_positions),
+        ast::Expr::FunctionCall {
+            args, filter_over, ..
+        } => {
+            for arg in args.iter_mut().flatten() {
+                remap_tables(arg, new_positions);
+            }
+            if let Some(ast::FunctionTail {
+                filter_clause: Some(filter),
+                ..
+            }) = filter_over
+            {
+                remap_tables(filter, new_positions);
+            }
+        }
+        ast::Expr::FunctionCallStar {
+            filter_over:
+                Some(ast::FunctionTail {
+                    filter_clause: Some(filter),
+                    ..
+                }),
+            ..
+        } => remap_tables(filter, new_positions),
+        ast::Expr::InList { lhs, rhs, .. } => {
+            remap_tables(lhs, new_positions);
+            for expr in rhs.iter_mut().flatten() {
+                remap_tables(expr, new_positions);
+            }
+        }
+        ast::Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            remap_tables(lhs, new_positions);
+            remap_tables(rhs, new_positions);
+            if let Some(escape) = escape {
+                remap_tables(escape, new_positions);
+            }
+        }
+        ast::Expr::Parenthesized(exprs) => {
+            for expr in exprs.iter_mut() {
+                remap_tables(expr, new_positions);
+            }
+        }
+        ast::Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            if let Some(lhs) = lhs {
+                remap_tables(lhs, new_positions);
+            }
+            for expr in outer_refs.iter_mut() {
+                remap_tables(expr, new_positions);
+            }
+        }
+        _ => {}
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 enum ConstantConditionEliminationResult {
     Continue,
@@ -223,6 +825,9 @@ fn rewrite_exprs_select(plan: &mut SelectPlan) -> Result<()> {
     }
     for agg in plan.aggregates.iter_mut() {
         rewrite_expr(&mut agg.original_expr)?;
+        if let Some(filter) = &mut agg.filter {
+            rewrite_expr(filter)?;
+        }
     }
     for cond in plan.where_clause.iter_mut() {
         rewrite_expr(&mut cond.expr)?;
@@ -237,6 +842,20 @@ fn rewrite_exprs_select(plan: &mut SelectPlan) -> Result<()> {
             rewrite_expr(expr)?;
         }
     }
+    for window in plan.windows.iter_mut() {
+        for expr in window.partition_by.iter_mut() {
+            rewrite_expr(expr)?;
+        }
+        for (expr, _) in window.order_by.iter_mut() {
+            rewrite_expr(expr)?;
+        }
+        for func in window.functions.iter_mut() {
+            rewrite_expr(&mut func.original_expr)?;
+            for arg in func.args.iter_mut() {
+                rewrite_expr(arg)?;
+            }
+        }
+    }
 
     Ok(())
 }
@@ -248,6 +867,21 @@ fn rewrite_exprs_delete(plan: &mut DeletePlan) -> Result<()> {
     Ok(())
 }
 
+fn rewrite_exprs_update(plan: &mut UpdatePlan) -> Result<()> {
+    for (_, expr) in plan.set_clauses.iter_mut() {
+        rewrite_expr(expr)?;
+    }
+    for cond in plan.where_clause.iter_mut() {
+        rewrite_expr(&mut cond.expr)?;
+    }
+    if let Some(order_by) = &mut plan.order_by {
+        for (expr, _) in order_by.iter_mut() {
+            rewrite_expr(expr)?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConstantPredicate {
     AlwaysTrue,
@@ -272,6 +906,7 @@ pub trait Optimizable {
             .map_or(false, |c| c == ConstantPredicate::AlwaysFalse))
     }
     fn is_rowid_alias_of(&self, table_index: usize) -> bool;
+    fn is_primary_key_of(&self, table_index: usize, table_reference: &TableReference) -> bool;
     fn check_index_scan(
         &mut self,
         table_index: usize,
@@ -291,6 +926,17 @@ impl Optimizable for ast::Expr {
             _ => false,
         }
     }
+    /// Whether the expression is the first primary key column of a WITHOUT ROWID table,
+    /// which is the column its rows are sorted by.
+    fn is_primary_key_of(&self, table_index: usize, table_reference: &TableReference) -> bool {
+        let Self::Column { table, column, .. } = self else {
+            return false;
+        };
+        let Some(btree) = table_reference.btree() else {
+            return false;
+        };
+        *table == table_index && !btree.has_rowid && btree.column_record_position(*column) == 0
+    }
     fn check_index_scan(
         &mut self,
         table_index: usize,
@@ -302,17 +948,34 @@ impl Optimizable for ast::Expr {
                 if *table != table_index {
                     return Ok(None);
                 }
-                let Some(available_indexes_for_table) =
-                    available_indexes.get(table_reference.table.get_name())
-                else {
+                // The secondary indexes of a WITHOUT ROWID table lead to its rows by primary key
+                // instead of rowid, which searches can't follow yet, so only the table itself is searched.
+                let Some(btree) = table_reference.btree() else {
                     return Ok(None);
                 };
+                let available_indexes_for_table = match btree.primary_key_index() {
+                    Some(index) => vec![index],
+                    // A temp table and a table of the main database can have the same name
+                    None => match available_indexes.get(&btree.name) {
+                        Some(indexes) => indexes
+                            .iter()
+                            .filter(|index| index.db == btree.db)
+                            .cloned()
+                            .collect(),
+                        None => return Ok(None),
+                    },
+                };
                 let Some(column) = table_reference.table.get_column_at(*column) else {
                     return Ok(None);
                 };
                 for index in available_indexes_for_table.iter() {
+                    // Index searches assume that the first column is sorted in ascending order
+                    let first_column = index.columns.first().unwrap();
+                    if first_column.order != Order::Ascending {
+                        continue;
+                    }
                     if let Some(name) = column.name.as_ref() {
-                        if &index.columns.first().unwrap().name == name {
+                        if &first_column.name == name {
                             return Ok(Some(index.clone()));
                         }
                     }
@@ -496,13 +1159,85 @@ fn opposite_cmp_op(op: ast::Operator) -> ast::Operator {
 pub fn try_extract_index_search_expression(
     cond: &mut WhereTerm,
     table_index: usize,
-    table_reference: &TableReference,
+    table_references: &[TableReference],
     available_indexes: &HashMap<String, Vec<Rc<Index>>>,
 ) -> Result<Option<Search>> {
     if !cond.should_eval_at_loop(table_index) {
         return Ok(None);
     }
+    let table_reference = &table_references[table_index];
+    // An index is only ordered the way a comparison needs it if its collation is the one of the comparison
+    let collation_matches = |index: &Index, lhs: &ast::Expr, rhs: &ast::Expr| {
+        index.columns[0].collation.clone().unwrap_or_default()
+            == comparison_collation(lhs, rhs, table_references).unwrap_or_default()
+    };
+    let (from_outer_join, eval_at) = (cond.from_outer_join, cond.eval_at);
+    let search_term = |expr: ast::Expr| WhereTerm {
+        expr,
+        from_outer_join,
+        eval_at,
+    };
     match &mut cond.expr {
+        // `x BETWEEN start AND end` is a range search on the rowid or an index on x
+        ast::Expr::Between {
+            lhs,
+            not: false,
+            start,
+            end,
+        } => {
+            if references_table(start, table_index) || references_table(end, table_index) {
+                return Ok(None);
+            }
+            let index = if lhs.is_rowid_alias_of(table_index) {
+                None
+            } else {
+                match lhs.check_index_scan(table_index, table_reference, available_indexes)? {
+                    Some(index)
+                        if collation_matches(&index, lhs, start)
+                            && collation_matches(&index, lhs, end) =>
+                    {
+                        Some(index)
+                    }
+                    _ => return Ok(None),
+                }
+            };
+            Ok(Some(Search::Range {
+                index,
+                start: search_term(start.take_ownership()),
+                end: search_term(end.take_ownership()),
+            }))
+        }
+        // `x IN (...)` is a search for each value of the list on the rowid or an index on x
+        ast::Expr::InList {
+            lhs,
+            not: false,
+            rhs: Some(values),
+        } => {
+            if values.is_empty() || values.iter().any(|v| references_table(v, table_index)) {
+                return Ok(None);
+            }
+            let index = if lhs.is_rowid_alias_of(table_index) {
+                None
+            } else {
+                match lhs.check_index_scan(table_index, table_reference, available_indexes)? {
+                    Some(index)
+                        if values
+                            .iter()
+                            .all(|value| collation_matches(&index, lhs, value)) =>
+                    {
+                        Some(index)
+                    }
+                    _ => return Ok(None),
+                }
+            };
+            Ok(Some(Search::InList {
+                index,
+                values: std::mem::take(values)
+                    .into_iter()
+                    .map(search_term)
+                    .collect(),
+            }))
+        }
         ast::Expr::Binary(lhs, operator, rhs) => {
             if lhs.is_rowid_alias_of(table_index) {
                 match operator {
@@ -564,8 +1299,9 @@ pub fn try_extract_index_search_expression(
                 }
             }
 
-            if let Some(index_rc) =
-                lhs.check_index_scan(table_index, &table_reference, available_indexes)?
+            if let Some(index_rc) = lhs
+                .check_index_scan(table_index, table_reference, available_indexes)?
+                .filter(|index| collation_matches(index, lhs, rhs))
             {
                 match operator {
                     ast::Operator::Equals
@@ -588,8 +1324,9 @@ pub fn try_extract_index_search_expression(
                 }
             }
 
-            if let Some(index_rc) =
-                rhs.check_index_scan(table_index, &table_reference, available_indexes)?
+            if let Some(index_rc) = rhs
+                .check_index_scan(table_index, table_reference, available_indexes)?
+                .filter(|index| collation_matches(index, lhs, rhs))
             {
                 match operator {
                     ast::Operator::Equals
@@ -618,6 +1355,68 @@ pub fn try_extract_index_search_expression(
     }
 }
 
+/// Whether the expression refers to a column of the table at `table_index`.
+/// A search on a table can only use values that are known before the table is looped over.
+fn references_table(expr: &ast::Expr, table_index: usize) -> bool {
+    match expr {
+        ast::Expr::Column { table, .. } | ast::Expr::RowId { table, .. } => *table == table_index,
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => [lhs, start, end]
+            .iter()
+            .any(|e| references_table(e, table_index)),
+        ast::Expr::Binary(lhs, _, rhs) => {
+            references_table(lhs, table_index) || references_table(rhs, table_index)
+        }
+        ast::Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            base.iter()
+                .chain(else_expr.iter())
+                .any(|e| references_table(e, table_index))
+                || when_then_pairs.iter().any(|(when, then)| {
+                    references_table(when, table_index) || references_table(then, table_index)
+                })
+        }
+        ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Unary(_, expr) => references_table(expr, table_index),
+        ast::Expr::FunctionCall { args, .. } => args
+            .iter()
+            .flatten()
+            .any(|e| references_table(e, table_index)),
+        ast::Expr::InList { lhs, rhs, .. } => {
+            references_table(lhs, table_index)
+                || rhs
+                    .iter()
+                    .flatten()
+                    .any(|e| references_table(e, table_index))
+        }
+        ast::Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            references_table(lhs, table_index)
+                || references_table(rhs, table_index)
+                || escape
+                    .as_ref()
+                    .is_some_and(|e| references_table(e, table_index))
+        }
+        ast::Expr::Parenthesized(exprs) => exprs.iter().any(|e| references_table(e, table_index)),
+        ast::Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            lhs.as_ref()
+                .is_some_and(|e| references_table(e, table_index))
+                || outer_refs.iter().any(|e| references_table(e, table_index))
+        }
+        _ => false,
+    }
+}
+
 fn rewrite_expr(expr: &mut ast::Expr) -> Result<()> {
     match expr {
         ast::Expr::Id(id) => {
@@ -633,42 +1432,17 @@ fn rewrite_expr(expr: &mut ast::Expr) -> Result<()> {
             Ok(())
         }
         ast::Expr::Between {
-            lhs,
-            not,
-            start,
-            end,
+            lhs, start, end, ..
         } => {
-            // Convert `y NOT BETWEEN x AND z` to `x > y OR y > z`
-            let (lower_op, upper_op) = if *not {
-                (ast::Operator::Greater, ast::Operator::Greater)
-            } else {
-                // Convert `y BETWEEN x AND z` to `x <= y AND y <= z`
-                (ast::Operator::LessEquals, ast::Operator::LessEquals)
-            };
-
-            rewrite_expr(start)?;
             rewrite_expr(lhs)?;
+            rewrite_expr(start)?;
             rewrite_expr(end)?;
-
-            let start = start.take_ownership();
-            let lhs = lhs.take_ownership();
-            let end = end.take_ownership();
-
-            let lower_bound = ast::Expr::Binary(Box::new(start), lower_op, Box::new(lhs.clone()));
-            let upper_bound = ast::Expr::Binary(Box::new(lhs), upper_op, Box::new(end));
-
-            if *not {
-                *expr = ast::Expr::Binary(
-                    Box::new(lower_bound),
-                    ast::Operator::Or,
-                    Box::new(upper_bound),
-                );
-            } else {
-                *expr = ast::Expr::Binary(
-                    Box::new(lower_bound),
-                    ast::Operator::And,
-                    Box::new(upper_bound),
-                );
+            Ok(())
+        }
+        ast::Expr::InList { lhs, rhs, .. } => {
+            rewrite_expr(lhs)?;
+            for value in rhs.iter_mut().flatten() {
+                rewrite_expr(value)?;
             }
             Ok(())
         }
@@ -698,6 +1472,17 @@ fn rewrite_expr(expr: &mut ast::Expr) -> Result<()> {
             rewrite_expr(arg)?;
             Ok(())
         }
+        ast::Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            if let Some(lhs) = lhs {
+                rewrite_expr(lhs)?;
+            }
+            for outer_ref in outer_refs.iter_mut() {
+                rewrite_expr(outer_ref)?;
+            }
+            Ok(())
+        }
         _ => Ok(()),
     }
 }
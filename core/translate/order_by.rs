@@ -14,10 +14,11 @@ use crate::{
 };
 
 use super::{
+    collate::expr_collation,
     emitter::TranslateCtx,
     expr::translate_expr,
-    plan::{Direction, ResultSetColumn, SelectPlan},
-    result_row::{emit_offset, emit_result_row_and_limit},
+    plan::{Direction, ResultSetColumn, SelectPlan, TableReference},
+    result_row::{emit_distinct_check, emit_offset, emit_result_row_and_limit},
 };
 
 // Metadata for handling ORDER BY operations
@@ -34,6 +35,7 @@ pub fn init_order_by(
     program: &mut ProgramBuilder,
     t_ctx: &mut TranslateCtx,
     order_by: &[(ast::Expr, Direction)],
+    tables: &[TableReference],
 ) -> Result<()> {
     let sort_cursor = program.alloc_cursor_id(None, CursorType::Sorter);
     t_ctx.meta_sort = Some(SortMetadata {
@@ -48,6 +50,10 @@ pub fn init_order_by(
         cursor_id: sort_cursor,
         columns: order_by.len(),
         order: Record::new(order),
+        collations: order_by
+            .iter()
+            .map(|(expr, _)| expr_collation(expr, tables))
+            .collect(),
     });
     Ok(())
 }
@@ -77,6 +83,7 @@ pub fn emit_order_by(
             is_rowid_alias: false,
             notnull: false,
             default: None,
+            collation: None,
         });
     }
     for i in 0..result_columns.len() {
@@ -95,6 +102,7 @@ pub fn emit_order_by(
             is_rowid_alias: false,
             notnull: false,
             default: None,
+            collation: None,
         });
     }
 
@@ -170,7 +178,12 @@ pub fn order_by_sorter_insert(
     let order_by_len = order_by.len();
     let result_columns = &plan.result_columns;
     // If any result columns can be skipped due to being an exact duplicate of a sort key, we need to know which ones and their new index in the ORDER BY sorter.
-    let result_columns_to_skip = order_by_deduplicate_result_columns(order_by, result_columns);
+    // A SELECT DISTINCT keeps all of them, since the row is checked for duplicates from the consecutive registers of the result columns.
+    let result_columns_to_skip = if t_ctx.distinct_cursor.is_some() {
+        None
+    } else {
+        order_by_deduplicate_result_columns(order_by, result_columns)
+    };
     let result_columns_to_skip_len = result_columns_to_skip
         .as_ref()
         .map(|v| v.len())
@@ -222,6 +235,16 @@ pub fn order_by_sorter_insert(
         reg_sorter_data,
     } = *t_ctx.meta_sort.as_mut().unwrap();
 
+    let label_duplicate = program.allocate_label();
+    if let Some(distinct_cursor) = t_ctx.distinct_cursor {
+        emit_distinct_check(
+            program,
+            distinct_cursor,
+            start_reg + order_by_len,
+            result_columns.len(),
+            label_duplicate,
+        );
+    }
     sorter_insert(
         program,
         start_reg,
@@ -229,6 +252,7 @@ pub fn order_by_sorter_insert(
         sort_cursor,
         reg_sorter_data,
     );
+    program.resolve_label(label_duplicate, program.offset());
     Ok(())
 }
 
@@ -1,20 +1,22 @@
 use core::fmt;
 use limbo_sqlite3_parser::ast;
 use std::{
+    cell::Cell,
     cmp::Ordering,
     fmt::{Display, Formatter},
     rc::Rc,
 };
 
 use crate::{
-    function::AggFunc,
-    schema::{BTreeTable, Column, Index, Table},
-    vdbe::BranchOffset,
+    function::{AggFunc, WindowFunc},
+    schema::{BTreeTable, Column, Index, Schema, Table, Trigger, MAIN_DB},
+    vdbe::{BranchOffset, CursorID},
     VirtualTable,
 };
 use crate::{
     schema::{PseudoTable, Type},
-    translate::plan::Plan::{Delete, Select},
+    translate::collate::expr_collation,
+    translate::plan::Plan::{Delete, Select, Update},
 };
 
 #[derive(Debug, Clone)]
@@ -58,7 +60,7 @@ pub struct WhereTerm {
     pub expr: ast::Expr,
     /// Is this condition originally from an OUTER JOIN?
     /// If so, we need to evaluate it at the loop of the right table in that JOIN,
-    /// regardless of which tables it references.
+    /// regardless of which tables it references, before the other conditions of that loop.
     /// We also cannot e.g. short circuit the entire query in the optimizer if the condition is statically false.
     pub from_outer_join: bool,
     pub eval_at: EvalAt,
@@ -106,11 +108,12 @@ impl Ord for EvalAt {
     }
 }
 
-/// A query plan is either a SELECT or a DELETE (for now)
+/// A query plan is either a SELECT, a DELETE or an UPDATE (for now)
 #[derive(Debug, Clone)]
 pub enum Plan {
     Select(SelectPlan),
     Delete(DeletePlan),
+    Update(UpdatePlan),
 }
 
 /// The type of the query, either top level or subquery
@@ -125,12 +128,38 @@ pub enum SelectQueryType {
     },
 }
 
+/// The kind of value a subquery used in an expression evaluates to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubqueryType {
+    /// `(SELECT ...)`: the first column of the first row, or NULL if there are no rows
+    Scalar,
+    /// `EXISTS (SELECT ...)`: 1 if there are any rows, 0 otherwise
+    Exists,
+    /// `lhs IN (SELECT ...)`: 1 if a row is equal to the left-hand side, NULL if there is no such row
+    /// but a row is NULL, 0 otherwise
+    In,
+}
+
+/// A subquery used in an expression, e.g. `SELECT * FROM t WHERE t.x IN (SELECT y FROM u)`.
+/// Like a subquery in the FROM clause, it is emitted as a coroutine, which is reinitialized every time
+/// the expression is evaluated. A correlated subquery refers to columns of the enclosing query through
+/// ast::Expr::OuterRef, whose values are copied into registers before the coroutine is run.
+#[derive(Debug, Clone)]
+pub struct ExprSubquery {
+    pub plan: Box<SelectPlan>,
+    pub subquery_type: SubqueryType,
+    /// number of values of the enclosing query that the subquery refers to
+    pub num_outer_refs: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct SelectPlan {
     /// List of table references in loop order, outermost first.
     pub table_references: Vec<TableReference>,
     /// the columns inside SELECT ... FROM
     pub result_columns: Vec<ResultSetColumn>,
+    /// whether duplicate rows are removed from the result (SELECT DISTINCT)
+    pub distinct: bool,
     /// where clause split into a vec at 'AND' boundaries. all join conditions also get shoved in here,
     /// and we keep track of which join they came from (mainly for OUTER JOIN processing)
     pub where_clause: Vec<WhereTerm>,
@@ -148,6 +177,10 @@ pub struct SelectPlan {
     pub contains_constant_false_condition: bool,
     /// query type (top level or subquery)
     pub query_type: SelectQueryType,
+    /// subqueries used in expressions, referred to by the subquery_id of an ast::Expr::SubqueryResult
+    pub subqueries: Vec<ExprSubquery>,
+    /// the windows of the window functions in the result columns and order by clause, in the order they are computed
+    pub windows: Vec<Window>,
 }
 
 #[allow(dead_code)]
@@ -167,6 +200,37 @@ pub struct DeletePlan {
     pub offset: Option<isize>,
     /// query contains a constant condition that is always false
     pub contains_constant_false_condition: bool,
+    /// indexes of the table, which have their entries for the deleted rows removed
+    pub indexes: Vec<Rc<Index>>,
+    /// BEFORE and AFTER DELETE triggers of the table
+    pub triggers: Vec<Rc<Trigger>>,
+    /// subqueries used in the WHERE clause, indexed by the subquery_id of an Expr::SubqueryResult
+    pub subqueries: Vec<ExprSubquery>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdatePlan {
+    /// List of table references. Update is always a single table.
+    pub table_references: Vec<TableReference>,
+    /// SET clause, as (column index in the table, new value expression) pairs.
+    /// The expressions are bound to the table, so they can reference the old row.
+    pub set_clauses: Vec<(usize, ast::Expr)>,
+    /// where clause split into a vec at 'AND' boundaries.
+    pub where_clause: Vec<WhereTerm>,
+    /// order by clause
+    pub order_by: Option<Vec<(ast::Expr, Direction)>>,
+    /// limit clause
+    pub limit: Option<isize>,
+    /// offset clause
+    pub offset: Option<isize>,
+    /// query contains a constant condition that is always false
+    pub contains_constant_false_condition: bool,
+    /// indexes of the table whose keys may be changed by the SET clause
+    pub indexes: Vec<Rc<Index>>,
+    /// BEFORE and AFTER UPDATE triggers of the table that fire for the columns in the SET clause
+    pub triggers: Vec<Rc<Trigger>>,
+    /// subqueries used in the SET, WHERE and ORDER BY clauses, indexed by the subquery_id of an Expr::SubqueryResult
+    pub subqueries: Vec<ExprSubquery>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -176,6 +240,7 @@ pub enum IterationDirection {
 }
 
 pub fn select_star(tables: &[TableReference], out_columns: &mut Vec<ResultSetColumn>) {
+    let first_column = out_columns.len();
     for (current_table_index, table) in tables.iter().enumerate() {
         let maybe_using_cols = table
             .join_info
@@ -210,14 +275,66 @@ pub fn select_star(tables: &[TableReference], out_columns: &mut Vec<ResultSetCol
                     contains_aggregates: false,
                 }),
         );
+        // The rows of the right table of a RIGHT or FULL OUTER JOIN without a match have NULLs for
+        // the tables before it, so the columns of its USING clause are taken from either side.
+        let Some(using_cols) = maybe_using_cols.filter(|_| table.join_info.as_ref().unwrap().right)
+        else {
+            continue;
+        };
+        for using_col in using_cols.iter() {
+            let Some((column, col)) = table.columns().iter().enumerate().find(|(_, col)| {
+                col.name
+                    .as_ref()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(&using_col.0))
+            }) else {
+                continue;
+            };
+            let Some(left_column) = out_columns[first_column..].iter_mut().find(|rc| {
+                rc.name(tables)
+                    .is_some_and(|name| name.eq_ignore_ascii_case(&using_col.0))
+            }) else {
+                continue;
+            };
+            let name = left_column.name(tables).cloned();
+            let left_expr = std::mem::replace(
+                &mut left_column.expr,
+                ast::Expr::Literal(ast::Literal::Null),
+            );
+            left_column.alias = name;
+            left_column.expr = coalesce_using_column(
+                left_expr,
+                ast::Expr::Column {
+                    database: None,
+                    table: current_table_index,
+                    column,
+                    is_rowid_alias: col.is_rowid_alias,
+                },
+            );
+        }
+    }
+}
+
+/// The value of a column of a USING clause, which is NULL on the left side of the join in the rows
+/// of a RIGHT or FULL OUTER JOIN without a match.
+pub fn coalesce_using_column(left: ast::Expr, right: ast::Expr) -> ast::Expr {
+    ast::Expr::FunctionCall {
+        name: ast::Id("coalesce".to_string()),
+        distinctness: None,
+        args: Some(vec![left, right]),
+        order_by: None,
+        filter_over: None,
     }
 }
 
 /// Join information for a table reference.
 #[derive(Debug, Clone)]
 pub struct JoinInfo {
-    /// Whether this is an OUTER JOIN.
+    /// Whether this is a LEFT or FULL OUTER JOIN, which emits the rows of the tables before it
+    /// with NULLs for this table when it has no matching row.
     pub outer: bool,
+    /// Whether this is a RIGHT or FULL OUTER JOIN, which emits the rows of this table that matched
+    /// no row of the tables before it, with NULLs for them, after all the other rows.
+    pub right: bool,
     /// The USING clause for the join, if any. NATURAL JOIN is transformed into USING (col1, col2, ...).
     pub using: Option<ast::DistinctNames>,
 }
@@ -226,7 +343,7 @@ pub struct JoinInfo {
 /// For example, SELECT * FROM users u JOIN products p JOIN (SELECT * FROM users) sub
 /// has three table references:
 /// 1. operation=Scan, table=users, table_identifier=u, reference_type=BTreeTable, join_info=None
-/// 2. operation=Scan, table=products, table_identifier=p, reference_type=BTreeTable, join_info=Some(JoinInfo { outer: false, using: None }),
+/// 2. operation=Scan, table=products, table_identifier=p, reference_type=BTreeTable, join_info=Some(JoinInfo { outer: false, right: false, using: None }),
 /// 3. operation=Subquery, table=users, table_identifier=sub, reference_type=Subquery, join_info=None
 #[derive(Debug, Clone)]
 pub struct TableReference {
@@ -236,6 +353,8 @@ pub struct TableReference {
     pub table: Table,
     /// The name of the table as referred to in the query, either the literal name or an alias e.g. "users" or "u"
     pub identifier: String,
+    /// The name of the database the table belongs to, e.g. "main", or None if the table is not read from a database.
+    pub database: Option<String>,
     /// The join info for this table reference, if it is the right side of a join (which all except the first table reference have)
     pub join_info: Option<JoinInfo>,
 }
@@ -262,6 +381,45 @@ pub enum Operation {
         plan: Box<SelectPlan>,
         result_columns_start_reg: usize,
     },
+    /// Recursive CTE operation
+    /// This operation produces the rows of a recursive CTE. The rows of the initial SELECT are put in a queue,
+    /// and the recursive SELECT is run once for every row taken from the queue, adding its own rows to the queue.
+    RecursiveCte {
+        initial: Box<SelectPlan>,
+        recursive: Box<SelectPlan>,
+        /// Whether the two SELECTs are combined with UNION ALL. With UNION, rows that were already produced are discarded.
+        union_all: bool,
+        /// The registers holding the row taken from the queue.
+        result_columns_start_reg: usize,
+        initial_result_columns_start_reg: usize,
+        recursive_result_columns_start_reg: usize,
+    },
+    /// The reference of a recursive CTE to itself in its recursive SELECT.
+    /// It is a single row, the row of the queue that the recursive SELECT is run for.
+    CteRow {
+        result_columns_start_reg: usize,
+    },
+    /// MATERIALIZED CTE operation
+    /// The rows of a CTE declared AS MATERIALIZED are computed once into an ephemeral table,
+    /// which is scanned by all the references to the CTE.
+    MaterializedCte {
+        plan: Box<SelectPlan>,
+        /// Shared by all the references to the CTE, set when the first of them is emitted.
+        materialization: Rc<Cell<Option<CteMaterialization>>>,
+    },
+}
+
+/// The bytecode resources of a MATERIALIZED CTE.
+#[derive(Clone, Copy, Debug)]
+pub struct CteMaterialization {
+    /// The cursor of the ephemeral table holding the rows of the CTE.
+    pub cursor_id: CursorID,
+    /// Set to 1 once the ephemeral table has been filled.
+    pub reg_materialized: usize,
+    /// The coroutine of the CTE's SelectPlan.
+    pub yield_reg: usize,
+    pub coroutine_implementation_start: BranchOffset,
+    pub result_columns_start_reg: usize,
 }
 
 impl TableReference {
@@ -281,20 +439,7 @@ impl TableReference {
 
     /// Creates a new TableReference for a subquery.
     pub fn new_subquery(identifier: String, plan: SelectPlan, join_info: Option<JoinInfo>) -> Self {
-        let table = Table::Pseudo(Rc::new(PseudoTable::new_with_columns(
-            plan.result_columns
-                .iter()
-                .map(|rc| Column {
-                    name: rc.name(&plan.table_references).map(String::clone),
-                    ty: Type::Text, // FIXME: infer proper type
-                    ty_str: "TEXT".to_string(),
-                    is_rowid_alias: false,
-                    primary_key: false,
-                    notnull: false,
-                    default: None,
-                })
-                .collect(),
-        )));
+        let table = result_columns_table(&plan);
         Self {
             op: Operation::Subquery {
                 plan: Box::new(plan),
@@ -302,13 +447,95 @@ impl TableReference {
             },
             table,
             identifier: identifier.clone(),
+            database: None,
             join_info,
         }
     }
 
+    /// Creates the table reference that produces the rows of a recursive CTE.
+    /// The columns of the CTE are the result columns of its initial SELECT.
+    pub fn new_recursive_cte(
+        identifier: String,
+        initial: SelectPlan,
+        recursive: SelectPlan,
+        union_all: bool,
+    ) -> Self {
+        Self {
+            table: result_columns_table(&initial),
+            op: Operation::RecursiveCte {
+                initial: Box::new(initial),
+                recursive: Box::new(recursive),
+                union_all,
+                // Will be set in the bytecode emission phase
+                result_columns_start_reg: 0,
+                initial_result_columns_start_reg: 0,
+                recursive_result_columns_start_reg: 0,
+            },
+            identifier,
+            database: None,
+            join_info: None,
+        }
+    }
+
+    /// Creates the reference of a recursive CTE to itself, where `initial` is the plan of its initial SELECT.
+    pub fn new_cte_row(identifier: String, initial: &SelectPlan) -> Self {
+        Self {
+            op: Operation::CteRow {
+                result_columns_start_reg: 0, // Will be set in the bytecode emission phase
+            },
+            table: result_columns_table(initial),
+            identifier,
+            database: None,
+            join_info: None,
+        }
+    }
+
+    /// Creates a reference to a MATERIALIZED CTE. All the references to the same CTE share `materialization`.
+    pub fn new_materialized_cte(
+        identifier: String,
+        plan: SelectPlan,
+        materialization: Rc<Cell<Option<CteMaterialization>>>,
+    ) -> Self {
+        Self {
+            table: result_columns_table(&plan),
+            op: Operation::MaterializedCte {
+                plan: Box::new(plan),
+                materialization,
+            },
+            identifier,
+            database: None,
+            join_info: None,
+        }
+    }
+
     pub fn columns(&self) -> &[Column] {
         self.table.columns()
     }
+
+    /// The name of the database `table` is read from, as the `database` of a table reference.
+    pub fn database_name(schema: &Schema, table: &Table) -> Option<String> {
+        let db = table.btree().map_or(MAIN_DB, |table| table.db);
+        Some(schema.database_name(db).to_string())
+    }
+}
+
+/// Returns a table whose columns are the result columns of the given plan.
+fn result_columns_table(plan: &SelectPlan) -> Table {
+    Table::Pseudo(Rc::new(PseudoTable::new_with_columns(
+        plan.result_columns
+            .iter()
+            .map(|rc| Column {
+                name: rc.name(&plan.table_references).map(String::clone),
+                ty: Type::Text, // FIXME: infer proper type
+                ty_str: "TEXT".to_string(),
+                is_rowid_alias: false,
+                primary_key: false,
+                notnull: false,
+                default: None,
+                collation: expr_collation(&rc.expr, &plan.table_references),
+            })
+            .collect(),
+    )))
 }
 
 /// An enum that represents a search operation that can be used to search for a row in a table using an index
@@ -329,6 +556,30 @@ pub enum Search {
         cmp_op: ast::Operator,
         cmp_expr: WhereTerm,
     },
+    /// A range search for `BETWEEN start AND end`, using the secondary index if there is one, otherwise the rowid.
+    /// Seeks to the start of the range with SeekGE and stops after the end of the range.
+    Range {
+        index: Option<Rc<Index>>,
+        start: WhereTerm,
+        end: WhereTerm,
+    },
+    /// An equality search for each distinct value of an IN list, using the secondary index if there is one,
+    /// otherwise the rowid. The rows are produced by a coroutine that seeks to each value in turn.
+    InList {
+        index: Option<Rc<Index>>,
+        values: Vec<WhereTerm>,
+    },
+}
+
+impl Search {
+    /// The secondary index the search uses, if any
+    pub fn index(&self) -> Option<&Rc<Index>> {
+        match self {
+            Search::RowidEq { .. } | Search::RowidSearch { .. } => None,
+            Search::IndexSearch { index, .. } => Some(index),
+            Search::Range { index, .. } | Search::InList { index, .. } => index.as_ref(),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -350,9 +601,19 @@ impl Display for Direction {
 pub struct Aggregate {
     pub func: AggFunc,
     pub args: Vec<ast::Expr>,
+    /// The condition of a FILTER (WHERE ...) clause. Only the rows for which it is true are aggregated.
+    pub filter: Option<ast::Expr>,
     pub original_expr: ast::Expr,
 }
 
+impl Aggregate {
+    /// The number of columns the aggregate takes up in the GROUP BY sorter:
+    /// its arguments, followed by the value of its FILTER condition, if any.
+    pub fn num_sorter_columns(&self) -> usize {
+        self.args.len() + self.filter.is_some() as usize
+    }
+}
+
 impl Display for Aggregate {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let args_str = self
@@ -361,16 +622,53 @@ impl Display for Aggregate {
             .map(|arg| arg.to_string())
             .collect::<Vec<String>>()
             .join(", ");
-        write!(f, "{:?}({})", self.func, args_str)
+        write!(f, "{:?}({})", self.func, args_str)?;
+        if let Some(filter) = &self.filter {
+            write!(f, " FILTER (WHERE {})", filter)?;
+        }
+        Ok(())
     }
 }
 
+/// The frame of a window function, i.e. the rows of the partition around the current row that
+/// an aggregate or first_value/last_value/nth_value is computed over.
+/// The bounds are never UNBOUNDED FOLLOWING for the start, or UNBOUNDED PRECEDING for the end,
+/// and the offsets of PRECEDING and FOLLOWING are non-negative numeric literals.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowFrame {
+    /// ROWS or RANGE (GROUPS is not supported)
+    pub mode: ast::FrameMode,
+    pub start: ast::FrameBound,
+    pub end: ast::FrameBound,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WindowFunction {
+    pub func: WindowFunc,
+    pub args: Vec<ast::Expr>,
+    pub frame: WindowFrame,
+    /// the function call as it appears in the query, including its OVER clause
+    pub original_expr: ast::Expr,
+}
+
+/// A window of a query that uses window functions, e.g. `SELECT rank() OVER (PARTITION BY a ORDER BY b) FROM t`.
+/// The rows of the query are sorted by the partition and order keys, after which the window functions
+/// are computed for each row in turn. The window functions of a window have the same partition and order
+/// keys, but each can have its own frame.
+#[derive(Clone, Debug)]
+pub struct Window {
+    pub partition_by: Vec<ast::Expr>,
+    pub order_by: Vec<(ast::Expr, Direction)>,
+    pub functions: Vec<WindowFunction>,
+}
+
 /// For EXPLAIN QUERY PLAN
 impl Display for Plan {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Select(select_plan) => select_plan.fmt(f),
             Delete(delete_plan) => delete_plan.fmt(f),
+            Update(update_plan) => update_plan.fmt(f),
         }
     }
 }
@@ -402,15 +700,15 @@ impl Display for SelectPlan {
 
                     writeln!(f, "{}SCAN {}", indent, table_name)?;
                 }
-                Operation::Search(search) => match search {
-                    Search::RowidEq { .. } | Search::RowidSearch { .. } => {
+                Operation::Search(search) => match search.index() {
+                    None => {
                         writeln!(
                             f,
                             "{}SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)",
                             indent, reference.identifier
                         )?;
                     }
-                    Search::IndexSearch { index, .. } => {
+                    Some(index) => {
                         writeln!(
                             f,
                             "{}SEARCH {} USING INDEX {}",
@@ -425,6 +723,51 @@ impl Display for SelectPlan {
                         writeln!(f, "{}   {}", indent, line)?;
                     }
                 }
+                Operation::RecursiveCte {
+                    initial,
+                    recursive,
+                    union_all,
+                    ..
+                } => {
+                    let operator = if *union_all { "UNION ALL" } else { "UNION" };
+                    writeln!(
+                        f,
+                        "{}RECURSIVE CTE {} ({})",
+                        indent, reference.identifier, operator
+                    )?;
+                    writeln!(f, "{}   SETUP", indent)?;
+                    for line in format!("{}", initial).lines() {
+                        writeln!(f, "{}      {}", indent, line)?;
+                    }
+                    writeln!(f, "{}   RECURSIVE STEP", indent)?;
+                    for line in format!("{}", recursive).lines() {
+                        writeln!(f, "{}      {}", indent, line)?;
+                    }
+                }
+                Operation::CteRow { .. } => {
+                    writeln!(f, "{}SCAN {}", indent, reference.identifier)?;
+                }
+                Operation::MaterializedCte { plan, .. } => {
+                    writeln!(f, "{}MATERIALIZE {}", indent, reference.identifier)?;
+                    for line in format!("{}", plan).lines() {
+                        writeln!(f, "{}   {}", indent, line)?;
+                    }
+                }
+            }
+        }
+        for (i, subquery) in self.subqueries.iter().enumerate() {
+            let kind = match subquery.subquery_type {
+                SubqueryType::Scalar => "SCALAR",
+                SubqueryType::Exists | SubqueryType::In => "LIST",
+            };
+            let correlated = if subquery.num_outer_refs > 0 {
+                "CORRELATED "
+            } else {
+                ""
+            };
+            writeln!(f, "`--{}{} SUBQUERY {}", correlated, kind, i + 1)?;
+            for line in format!("{}", subquery.plan).lines() {
+                writeln!(f, "   {}", line)?;
             }
         }
         Ok(())
@@ -452,7 +795,10 @@ impl Display for DeletePlan {
                 Operation::Search { .. } => {
                     panic!("DELETE plans should not contain search operations");
                 }
-                Operation::Subquery { .. } => {
+                Operation::Subquery { .. }
+                | Operation::RecursiveCte { .. }
+                | Operation::CteRow { .. }
+                | Operation::MaterializedCte { .. } => {
                     panic!("DELETE plans should not contain subqueries");
                 }
             }
@@ -460,3 +806,46 @@ impl Display for DeletePlan {
         Ok(())
     }
 }
+
+impl Display for UpdatePlan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "QUERY PLAN")?;
+
+        // Update plan should only have one table reference
+        if let Some(reference) = self.table_references.first() {
+            let indent = "`--";
+
+            match &reference.op {
+                Operation::Scan { .. } => {
+                    writeln!(f, "{}SCAN {}", indent, reference.identifier)?;
+                }
+                Operation::Search(search) => match search.index() {
+                    None => {
+                        writeln!(
+                            f,
+                            "{}SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)",
+                            indent, reference.identifier
+                        )?;
+                    }
+                    Some(index) => {
+                        writeln!(
+                            f,
+                            "{}SEARCH {} USING INDEX {}",
+                            indent, reference.identifier, index.name
+                        )?;
+                    }
+                },
+                Operation::Subquery { .. }
+                | Operation::RecursiveCte { .. }
+                | Operation::CteRow { .. }
+                | Operation::MaterializedCte { .. } => {
+                    panic!("UPDATE plans should not contain subqueries");
+                }
+            }
+            if self.order_by.is_some() {
+                writeln!(f, "`--USE TEMP B-TREE FOR ORDER BY")?;
+            }
+        }
+        Ok(())
+    }
+}
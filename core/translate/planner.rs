@@ -1,20 +1,27 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
 use super::{
     plan::{
-        Aggregate, EvalAt, JoinInfo, Operation, Plan, ResultSetColumn, SelectPlan, SelectQueryType,
-        TableReference, WhereTerm,
+        coalesce_using_column, select_star, Aggregate, CteMaterialization, Direction, EvalAt,
+        ExprSubquery, JoinInfo, Operation, Plan, ResultSetColumn, SelectPlan, SelectQueryType,
+        SubqueryType, TableReference, WhereTerm, Window, WindowFrame, WindowFunction,
     },
     select::prepare_select_plan,
     SymbolTable,
 };
 use crate::{
-    function::Func,
+    function::{AggFunc, Func, WindowFunc},
     schema::{Schema, Table},
     util::{exprs_are_equivalent, normalize_ident, vtable_args},
     vdbe::BranchOffset,
     Result,
 };
 use limbo_sqlite3_parser::ast::{
-    self, Expr, FromClause, JoinType, Limit, Materialized, UnaryOperator, With,
+    self, CompoundOperator, Expr, FromClause, IndexedColumn, JoinType, Limit, Materialized,
+    QualifiedName, UnaryOperator, With,
 };
 
 pub const ROWID: &str = "rowid";
@@ -27,7 +34,29 @@ pub fn resolve_aggregates(expr: &Expr, aggs: &mut Vec<Aggregate>) -> bool {
         return true;
     }
     match expr {
-        Expr::FunctionCall { name, args, .. } => {
+        // A window function is computed over a window of rows, not the rows of a group.
+        Expr::FunctionCall {
+            filter_over:
+                Some(ast::FunctionTail {
+                    over_clause: Some(_),
+                    ..
+                }),
+            ..
+        }
+        | Expr::FunctionCallStar {
+            filter_over:
+                Some(ast::FunctionTail {
+                    over_clause: Some(_),
+                    ..
+                }),
+            ..
+        } => false,
+        Expr::FunctionCall {
+            name,
+            args,
+            filter_over,
+            ..
+        } => {
             let args_count = if let Some(args) = &args {
                 args.len()
             } else {
@@ -38,6 +67,7 @@ pub fn resolve_aggregates(expr: &Expr, aggs: &mut Vec<Aggregate>) -> bool {
                     aggs.push(Aggregate {
                         func: f,
                         args: args.clone().unwrap_or_default(),
+                        filter: aggregate_filter(filter_over),
                         original_expr: expr.clone(),
                     });
                     true
@@ -53,13 +83,14 @@ pub fn resolve_aggregates(expr: &Expr, aggs: &mut Vec<Aggregate>) -> bool {
                 }
             }
         }
-        Expr::FunctionCallStar { name, .. } => {
+        Expr::FunctionCallStar { name, filter_over } => {
             if let Ok(Func::Agg(f)) =
                 Func::resolve_function(normalize_ident(name.0.as_str()).as_str(), 0)
             {
                 aggs.push(Aggregate {
                     func: f,
                     args: vec![],
+                    filter: aggregate_filter(filter_over),
                     original_expr: expr.clone(),
                 });
                 true
@@ -78,11 +109,435 @@ pub fn resolve_aggregates(expr: &Expr, aggs: &mut Vec<Aggregate>) -> bool {
             contains_aggregates |= resolve_aggregates(expr, aggs);
             contains_aggregates
         }
+        Expr::SubqueryResult { lhs: Some(lhs), .. } => resolve_aggregates(lhs, aggs),
+        Expr::Between {
+            lhs, start, end, ..
+        } => {
+            let mut contains_aggregates = false;
+            contains_aggregates |= resolve_aggregates(lhs, aggs);
+            contains_aggregates |= resolve_aggregates(start, aggs);
+            contains_aggregates |= resolve_aggregates(end, aggs);
+            contains_aggregates
+        }
+        Expr::InList { lhs, rhs, .. } => {
+            let mut contains_aggregates = resolve_aggregates(lhs, aggs);
+            for value in rhs.iter().flatten() {
+                contains_aggregates |= resolve_aggregates(value, aggs);
+            }
+            contains_aggregates
+        }
         // TODO: handle other expressions that may contain aggregates
         _ => false,
     }
 }
 
+/// Returns the condition of the FILTER (WHERE ...) clause of an aggregate function call, if any.
+pub fn aggregate_filter(filter_over: &Option<ast::FunctionTail>) -> Option<Expr> {
+    filter_over
+        .as_ref()
+        .and_then(|tail| tail.filter_clause.as_deref().cloned())
+}
+
+/// Adds the window function calls in `expr`, e.g. `rank() OVER (ORDER BY x)`, to the windows of the query,
+/// and returns true if there were any. `window_defs` are the named windows of the WINDOW clause.
+pub fn resolve_window_functions(
+    expr: &Expr,
+    referenced_tables: &[TableReference],
+    result_columns: Option<&[ResultSetColumn]>,
+    window_defs: &[ast::WindowDef],
+    windows: &mut Vec<Window>,
+) -> Result<bool> {
+    let mut resolve = |e: &Expr| {
+        resolve_window_functions(e, referenced_tables, result_columns, window_defs, windows)
+    };
+    match expr {
+        Expr::FunctionCall {
+            name,
+            distinctness,
+            args,
+            filter_over:
+                Some(ast::FunctionTail {
+                    filter_clause,
+                    over_clause: Some(over),
+                }),
+            ..
+        } => {
+            if distinctness.is_some() {
+                crate::bail_parse_error!("DISTINCT is not supported for window functions");
+            }
+            if filter_clause.is_some() {
+                crate::bail_parse_error!("FILTER is not supported for window functions");
+            }
+            let args = args.clone().unwrap_or_default();
+            let func = WindowFunc::resolve_function(&normalize_ident(name.0.as_str()), args.len())?;
+            add_window_function(
+                func,
+                args,
+                over,
+                expr,
+                referenced_tables,
+                result_columns,
+                window_defs,
+                windows,
+            )?;
+            Ok(true)
+        }
+        Expr::FunctionCallStar {
+            name,
+            filter_over:
+                Some(ast::FunctionTail {
+                    filter_clause,
+                    over_clause: Some(over),
+                }),
+        } => {
+            if filter_clause.is_some() {
+                crate::bail_parse_error!("FILTER is not supported for window functions");
+            }
+            let func = WindowFunc::resolve_function(&normalize_ident(name.0.as_str()), 0)?;
+            add_window_function(
+                func,
+                vec![],
+                over,
+                expr,
+                referenced_tables,
+                result_columns,
+                window_defs,
+                windows,
+            )?;
+            Ok(true)
+        }
+        Expr::FunctionCall { args, .. } => {
+            let mut contains_window_functions = false;
+            for arg in args.iter().flatten() {
+                contains_window_functions |= resolve(arg)?;
+            }
+            Ok(contains_window_functions)
+        }
+        Expr::Between {
+            lhs, start, end, ..
+        } => Ok(resolve(lhs)? | resolve(start)? | resolve(end)?),
+        Expr::Binary(lhs, _, rhs) => Ok(resolve(lhs)? | resolve(rhs)?),
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            let mut contains_window_functions = false;
+            for e in base.iter().chain(else_expr.iter()) {
+                contains_window_functions |= resolve(e)?;
+            }
+            for (when, then) in when_then_pairs {
+                contains_window_functions |= resolve(when)? | resolve(then)?;
+            }
+            Ok(contains_window_functions)
+        }
+        Expr::Cast { expr, .. }
+        | Expr::Collate(expr, _)
+        | Expr::IsNull(expr)
+        | Expr::NotNull(expr)
+        | Expr::Unary(_, expr) => resolve(expr),
+        Expr::InList { lhs, rhs, .. } => {
+            let mut contains_window_functions = resolve(lhs)?;
+            for e in rhs.iter().flatten() {
+                contains_window_functions |= resolve(e)?;
+            }
+            Ok(contains_window_functions)
+        }
+        Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            let mut contains_window_functions = resolve(lhs)? | resolve(rhs)?;
+            if let Some(escape) = escape {
+                contains_window_functions |= resolve(escape)?;
+            }
+            Ok(contains_window_functions)
+        }
+        Expr::Parenthesized(exprs) => {
+            let mut contains_window_functions = false;
+            for e in exprs {
+                contains_window_functions |= resolve(e)?;
+            }
+            Ok(contains_window_functions)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_window_function(
+    func: WindowFunc,
+    mut args: Vec<Expr>,
+    over: &ast::Over,
+    original_expr: &Expr,
+    referenced_tables: &[TableReference],
+    result_columns: Option<&[ResultSetColumn]>,
+    window_defs: &[ast::WindowDef],
+    windows: &mut Vec<Window>,
+) -> Result<()> {
+    match &func {
+        WindowFunc::Agg(AggFunc::External(_)) => {
+            crate::bail_parse_error!(
+                "extension aggregate functions are not supported as window functions"
+            )
+        }
+        // COUNT() and COUNT(*) count every row
+        WindowFunc::Agg(AggFunc::Count0) => {
+            args = vec![Expr::Literal(ast::Literal::Numeric("1".to_string()))];
+        }
+        WindowFunc::Ntile if !is_positive_integer_or_non_literal(&args[0]) => {
+            crate::bail_parse_error!("argument of ntile must be a positive integer")
+        }
+        WindowFunc::NthValue if !is_positive_integer_or_non_literal(&args[1]) => {
+            crate::bail_parse_error!("second argument to nth_value must be a positive integer")
+        }
+        _ => {}
+    }
+
+    let ast::Window {
+        partition_by,
+        order_by,
+        frame_clause,
+        ..
+    } = resolve_window_definition(over, window_defs)?;
+    let mut partition_by = partition_by.unwrap_or_default();
+    for expr in partition_by.iter_mut() {
+        bind_column_references(expr, referenced_tables, result_columns)?;
+    }
+    let mut order_by = order_by
+        .unwrap_or_default()
+        .into_iter()
+        .map(|o| {
+            let direction = match o.order {
+                Some(ast::SortOrder::Desc) => Direction::Descending,
+                _ => Direction::Ascending,
+            };
+            (o.expr, direction)
+        })
+        .collect::<Vec<_>>();
+    for (expr, _) in order_by.iter_mut() {
+        bind_column_references(expr, referenced_tables, result_columns)?;
+    }
+    let frame = resolve_window_frame(frame_clause, order_by.len())?;
+
+    let same_keys = |window: &Window| {
+        window.partition_by.len() == partition_by.len()
+            && window
+                .partition_by
+                .iter()
+                .zip(partition_by.iter())
+                .all(|(a, b)| exprs_are_equivalent(a, b))
+            && window.order_by.len() == order_by.len()
+            && window
+                .order_by
+                .iter()
+                .zip(order_by.iter())
+                .all(|((a, dir_a), (b, dir_b))| dir_a == dir_b && exprs_are_equivalent(a, b))
+    };
+    let window = match windows.iter().position(same_keys) {
+        Some(i) => &mut windows[i],
+        None => {
+            // Like SQLite, the windows are computed in the reverse order of their first use,
+            // so the rows come out in the order of the first window when there is no ORDER BY.
+            windows.insert(
+                0,
+                Window {
+                    partition_by,
+                    order_by,
+                    functions: vec![],
+                },
+            );
+            &mut windows[0]
+        }
+    };
+    if !window
+        .functions
+        .iter()
+        .any(|f| exprs_are_equivalent(&f.original_expr, original_expr))
+    {
+        window.functions.push(WindowFunction {
+            func,
+            args,
+            frame,
+            original_expr: original_expr.clone(),
+        });
+    }
+    Ok(())
+}
+
+fn is_positive_integer_or_non_literal(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(ast::Literal::Numeric(n)) => n.parse::<i64>().is_ok_and(|n| n > 0),
+        Expr::Literal(_) | Expr::Unary(..) => false,
+        _ => true,
+    }
+}
+
+/// Resolves the window of an OVER clause, which can refer to a window of the WINDOW clause,
+/// e.g. `OVER w` or `OVER (w ORDER BY x)`.
+fn resolve_window_definition(
+    over: &ast::Over,
+    window_defs: &[ast::WindowDef],
+) -> Result<ast::Window> {
+    resolve_window_definition_from(over, &window_defs.iter().collect::<Vec<_>>())
+}
+
+fn resolve_window_definition_from(
+    over: &ast::Over,
+    window_defs: &[&ast::WindowDef],
+) -> Result<ast::Window> {
+    // As in SQLite, the last window of a given name wins. A window's base is looked up among the other windows,
+    // so that a window cannot refer to itself.
+    let lookup = |name: &ast::Name| {
+        let name = normalize_ident(name.0.as_str());
+        match window_defs
+            .iter()
+            .rposition(|def| normalize_ident(def.name.0.as_str()) == name)
+        {
+            Some(i) => {
+                let other_defs = window_defs[..i]
+                    .iter()
+                    .chain(window_defs[i + 1..].iter())
+                    .copied()
+                    .collect::<Vec<_>>();
+                resolve_window_definition_from(
+                    &ast::Over::Window(window_defs[i].window.clone()),
+                    &other_defs,
+                )
+            }
+            None => crate::bail_parse_error!("no such window: {}", name),
+        }
+    };
+    let window = match over {
+        ast::Over::Name(name) => return lookup(name),
+        ast::Over::Window(window) => window,
+    };
+    let Some(base_name) = &window.base else {
+        return Ok(window.clone());
+    };
+    let base = lookup(base_name)?;
+    if window.partition_by.is_some() {
+        crate::bail_parse_error!(
+            "cannot override PARTITION clause of window: {}",
+            base_name.0
+        );
+    }
+    if window.order_by.is_some() && base.order_by.is_some() {
+        crate::bail_parse_error!("cannot override ORDER BY clause of window: {}", base_name.0);
+    }
+    if base.frame_clause.is_some() {
+        crate::bail_parse_error!(
+            "cannot override frame specification of window: {}",
+            base_name.0
+        );
+    }
+    Ok(ast::Window {
+        base: None,
+        partition_by: base.partition_by,
+        order_by: window.order_by.clone().or(base.order_by),
+        frame_clause: window.frame_clause.clone(),
+    })
+}
+
+/// Checks the frame specification of a window, which defaults to
+/// `RANGE BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW`.
+fn resolve_window_frame(
+    frame_clause: Option<ast::FrameClause>,
+    num_order_by_terms: usize,
+) -> Result<WindowFrame> {
+    let Some(frame_clause) = frame_clause else {
+        return Ok(WindowFrame {
+            mode: ast::FrameMode::Range,
+            start: ast::FrameBound::UnboundedPreceding,
+            end: ast::FrameBound::CurrentRow,
+        });
+    };
+    let ast::FrameClause {
+        mode,
+        start,
+        end,
+        exclude,
+    } = frame_clause;
+    if mode == ast::FrameMode::Groups {
+        crate::bail_parse_error!("GROUPS frames are not supported");
+    }
+    if !matches!(exclude, None | Some(ast::FrameExclude::NoOthers)) {
+        crate::bail_parse_error!("EXCLUDE is not supported in frame specifications");
+    }
+    let end = end.unwrap_or(ast::FrameBound::CurrentRow);
+    use ast::FrameBound::*;
+    match (&start, &end) {
+        (UnboundedFollowing, _)
+        | (_, UnboundedPreceding)
+        | (Following(_), Preceding(_) | CurrentRow)
+        | (CurrentRow, Preceding(_)) => {
+            crate::bail_parse_error!("unsupported frame specification")
+        }
+        _ => {}
+    }
+    for (bound, which) in [(&start, "starting"), (&end, "ending")] {
+        let (Preceding(offset) | Following(offset)) = bound else {
+            continue;
+        };
+        let offset = match offset.as_ref() {
+            Expr::Literal(ast::Literal::Numeric(n)) => n.as_str(),
+            _ => "",
+        };
+        match mode {
+            ast::FrameMode::Rows => {
+                if offset.parse::<u64>().is_err() {
+                    crate::bail_parse_error!(
+                        "frame {} offset must be a non-negative integer",
+                        which
+                    );
+                }
+            }
+            _ => {
+                if !offset.parse::<f64>().is_ok_and(|n| n >= 0.0) {
+                    crate::bail_parse_error!(
+                        "frame {} offset must be a non-negative number",
+                        which
+                    );
+                }
+                if num_order_by_terms != 1 {
+                    crate::bail_parse_error!(
+                        "RANGE with offset PRECEDING/FOLLOWING requires one ORDER BY expression"
+                    );
+                }
+            }
+        }
+    }
+    Ok(WindowFrame { mode, start, end })
+}
+
+/// Binds the column `id` of the table at `tbl_idx` in `referenced_tables`, or its rowid.
+fn bind_table_column(
+    referenced_tables: &[TableReference],
+    tbl_idx: usize,
+    id: &ast::Name,
+) -> Result<Expr> {
+    let normalized_id = normalize_ident(id.0.as_str());
+    if has_rowid(&referenced_tables[tbl_idx]) {
+        if let Some(row_id_expr) = parse_row_id(&normalized_id, tbl_idx, || false)? {
+            return Ok(row_id_expr);
+        }
+    }
+    let col_idx = referenced_tables[tbl_idx].columns().iter().position(|c| {
+        c.name
+            .as_ref()
+            .map_or(false, |name| name.eq_ignore_ascii_case(&normalized_id))
+    });
+    let Some(col_idx) = col_idx else {
+        crate::bail_parse_error!("Column {} not found", normalized_id);
+    };
+    let col = &referenced_tables[tbl_idx].columns()[col_idx];
+    Ok(Expr::Column {
+        database: None, // TODO: support different databases
+        table: tbl_idx,
+        column: col_idx,
+        is_rowid_alias: col.is_rowid_alias,
+    })
+}
+
 pub fn bind_column_references(
     expr: &mut Expr,
     referenced_tables: &[TableReference],
@@ -97,7 +552,7 @@ pub fn bind_column_references(
             }
             let normalized_id = normalize_ident(id.0.as_str());
 
-            if !referenced_tables.is_empty() {
+            if !referenced_tables.is_empty() && has_rowid(&referenced_tables[0]) {
                 if let Some(row_id_expr) =
                     parse_row_id(&normalized_id, 0, || referenced_tables.len() != 1)?
                 {
@@ -113,21 +568,32 @@ pub fn bind_column_references(
                         .as_ref()
                         .map_or(false, |name| name.eq_ignore_ascii_case(&normalized_id))
                 });
-                if col_idx.is_some() {
-                    if match_result.is_some() {
-                        crate::bail_parse_error!("Column {} is ambiguous", id.0);
-                    }
-                    let col = table.columns().get(col_idx.unwrap()).unwrap();
-                    match_result = Some((tbl_idx, col_idx.unwrap(), col.is_rowid_alias));
+                if let Some(col_idx) = col_idx {
+                    let col = table.columns().get(col_idx).unwrap();
+                    let column = Expr::Column {
+                        database: None, // TODO: support different databases
+                        table: tbl_idx,
+                        column: col_idx,
+                        is_rowid_alias: col.is_rowid_alias,
+                    };
+                    match_result = match match_result {
+                        None => Some(column),
+                        // A column of a USING clause names the column of both sides of the join.
+                        // Its value is the one of the left side, except in the rows of a RIGHT or
+                        // FULL OUTER JOIN without a match, which only have the right side.
+                        Some(left) if joins_using(table, &normalized_id) => {
+                            if table.join_info.as_ref().is_some_and(|info| info.right) {
+                                Some(coalesce_using_column(left, column))
+                            } else {
+                                Some(left)
+                            }
+                        }
+                        Some(_) => crate::bail_parse_error!("Column {} is ambiguous", id.0),
+                    };
                 }
             }
-            if let Some((tbl_idx, col_idx, is_rowid_alias)) = match_result {
-                *expr = Expr::Column {
-                    database: None, // TODO: support different databases
-                    table: tbl_idx,
-                    column: col_idx,
-                    is_rowid_alias,
-                };
+            if let Some(column) = match_result {
+                *expr = column;
                 return Ok(());
             }
 
@@ -153,31 +619,26 @@ pub fn bind_column_references(
                 crate::bail_parse_error!("Table {} not found", normalized_table_name);
             }
             let tbl_idx = matching_tbl_idx.unwrap();
-            let normalized_id = normalize_ident(id.0.as_str());
-
-            if let Some(row_id_expr) = parse_row_id(&normalized_id, tbl_idx, || false)? {
-                *expr = row_id_expr;
-
-                return Ok(());
-            }
-            let col_idx = referenced_tables[tbl_idx].columns().iter().position(|c| {
-                c.name
-                    .as_ref()
-                    .map_or(false, |name| name.eq_ignore_ascii_case(&normalized_id))
+            *expr = bind_table_column(referenced_tables, tbl_idx, id)?;
+            Ok(())
+        }
+        Expr::DoublyQualified(db, tbl, id) => {
+            let normalized_db_name = normalize_ident(db.0.as_str());
+            let normalized_table_name = normalize_ident(tbl.0.as_str());
+            let matching_tbl_idx = referenced_tables.iter().position(|t| {
+                t.identifier.eq_ignore_ascii_case(&normalized_table_name)
+                    && t.database
+                        .as_ref()
+                        .is_some_and(|database| database.eq_ignore_ascii_case(&normalized_db_name))
             });
-            if col_idx.is_none() {
-                crate::bail_parse_error!("Column {} not found", normalized_id);
-            }
-            let col = referenced_tables[tbl_idx]
-                .columns()
-                .get(col_idx.unwrap())
-                .unwrap();
-            *expr = Expr::Column {
-                database: None, // TODO: support different databases
-                table: tbl_idx,
-                column: col_idx.unwrap(),
-                is_rowid_alias: col.is_rowid_alias,
+            let Some(tbl_idx) = matching_tbl_idx else {
+                crate::bail_parse_error!(
+                    "Table {}.{} not found",
+                    normalized_db_name,
+                    normalized_table_name
+                );
             };
+            *expr = bind_table_column(referenced_tables, tbl_idx, id)?;
             Ok(())
         }
         Expr::Between {
@@ -216,28 +677,60 @@ pub fn bind_column_references(
         Expr::Cast { expr, type_name: _ } => {
             bind_column_references(expr, referenced_tables, result_columns)
         }
-        Expr::Collate(expr, _string) => {
-            bind_column_references(expr, referenced_tables, result_columns)
-        }
+        Expr::Collate(expr, _) => bind_column_references(expr, referenced_tables, result_columns),
         Expr::FunctionCall {
             name: _,
             distinctness: _,
             args,
             order_by: _,
-            filter_over: _,
+            filter_over,
         } => {
             if let Some(args) = args {
                 for arg in args {
                     bind_column_references(arg, referenced_tables, result_columns)?;
                 }
             }
+            if let Some(ast::FunctionTail {
+                filter_clause: Some(filter),
+                ..
+            }) = filter_over
+            {
+                bind_column_references(filter, referenced_tables, result_columns)?;
+            }
             Ok(())
         }
         // Already bound earlier
-        Expr::Column { .. } | Expr::RowId { .. } => Ok(()),
-        Expr::DoublyQualified(_, _, _) => todo!(),
-        Expr::Exists(_) => todo!(),
-        Expr::FunctionCallStar { .. } => Ok(()),
+        Expr::Column { .. }
+        | Expr::RowId { .. }
+        | Expr::OuterRef(_)
+        | Expr::Param(_)
+        | Expr::Register(_) => Ok(()),
+        // Subqueries are planned by plan_subqueries_in_expr() before binding,
+        // which is not done for every kind of expression yet.
+        Expr::Exists(_) | Expr::InSelect { .. } | Expr::InTable { .. } | Expr::Subquery(_) => {
+            crate::bail_parse_error!("subqueries are not supported here")
+        }
+        Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            if let Some(lhs) = lhs {
+                bind_column_references(lhs, referenced_tables, result_columns)?;
+            }
+            for outer_ref in outer_refs {
+                bind_column_references(outer_ref, referenced_tables, result_columns)?;
+            }
+            Ok(())
+        }
+        Expr::FunctionCallStar { filter_over, .. } => {
+            if let Some(ast::FunctionTail {
+                filter_clause: Some(filter),
+                ..
+            }) = filter_over
+            {
+                bind_column_references(filter, referenced_tables, result_columns)?;
+            }
+            Ok(())
+        }
         Expr::InList { lhs, not: _, rhs } => {
             bind_column_references(lhs, referenced_tables, result_columns)?;
             if let Some(rhs) = rhs {
@@ -247,8 +740,6 @@ pub fn bind_column_references(
             }
             Ok(())
         }
-        Expr::InSelect { .. } => todo!(),
-        Expr::InTable { .. } => todo!(),
         Expr::IsNull(expr) => {
             bind_column_references(expr, referenced_tables, result_columns)?;
             Ok(())
@@ -270,8 +761,8 @@ pub fn bind_column_references(
             }
             Ok(())
         }
-        Expr::Raise(_, _) => todo!(),
-        Expr::Subquery(_) => todo!(),
+        // The error message of RAISE() is a string literal
+        Expr::Raise(_, _) => Ok(()),
         Expr::Unary(_, expr) => {
             bind_column_references(expr, referenced_tables, result_columns)?;
             Ok(())
@@ -289,27 +780,24 @@ fn parse_from_clause_table<'a>(
     match table {
         ast::SelectTable::Table(qualified_name, maybe_alias, _) => {
             let normalized_qualified_name = normalize_ident(qualified_name.name.0.as_str());
+            let alias = maybe_alias
+                .map(|a| match a {
+                    ast::As::As(id) => id,
+                    ast::As::Elided(id) => id,
+                })
+                .map(|a| a.0);
             // Check if the FROM clause table is referring to a CTE in the current scope.
             if let Some(cte) = scope
                 .ctes
                 .iter()
                 .find(|cte| cte.name == normalized_qualified_name)
             {
-                // CTE can be rewritten as a subquery.
-                // TODO: find a way not to clone the CTE plan here.
-                let cte_table =
-                    TableReference::new_subquery(cte.name.clone(), cte.plan.clone(), None);
+                let cte_table = cte.table_reference(alias);
                 scope.tables.push(cte_table);
                 return Ok(());
             };
             // Check if our top level schema has this table.
-            if let Some(table) = schema.get_table(&normalized_qualified_name) {
-                let alias = maybe_alias
-                    .map(|a| match a {
-                        ast::As::As(id) => id,
-                        ast::As::Elided(id) => id,
-                    })
-                    .map(|a| a.0);
+            if let Some(table) = schema.resolve_table(&qualified_name)? {
                 let tbl_ref = if let Table::Virtual(tbl) = table.as_ref() {
                     Table::Virtual(tbl.clone())
                 } else if let Table::BTree(table) = table.as_ref() {
@@ -321,12 +809,59 @@ fn parse_from_clause_table<'a>(
                 };
                 scope.tables.push(TableReference {
                     op: Operation::Scan { iter_dir: None },
+                    database: TableReference::database_name(schema, &tbl_ref),
                     table: tbl_ref,
                     identifier: alias.unwrap_or(normalized_qualified_name),
                     join_info: None,
                 });
                 return Ok(());
             };
+            // Check if our top level schema has a view with this name, and expand it into a subquery.
+            if let Some(view) = schema.get_view(&normalized_qualified_name) {
+                let mut ancestor = Some(&*scope);
+                while let Some(s) = ancestor {
+                    if s.view.as_deref() == Some(view.name.as_str()) {
+                        crate::bail_parse_error!("view {} is circularly defined", view.name);
+                    }
+                    ancestor = s.parent;
+                }
+                // The SELECT of a view cannot see the tables and CTEs of the query that uses the view.
+                let view_scope = Scope {
+                    tables: vec![],
+                    ctes: vec![],
+                    parent: Some(&*scope),
+                    outer_refs: None,
+                    view: Some(view.name.clone()),
+                };
+                let Plan::Select(mut subplan) =
+                    prepare_select_plan(schema, view.select.clone(), syms, Some(&view_scope))?
+                else {
+                    unreachable!();
+                };
+                if let Some(columns) = &view.columns {
+                    if columns.len() != subplan.result_columns.len() {
+                        crate::bail_parse_error!(
+                            "expected {} columns for '{}' but got {}",
+                            columns.len(),
+                            view.name,
+                            subplan.result_columns.len()
+                        );
+                    }
+                    for (result_column, column) in subplan.result_columns.iter_mut().zip(columns) {
+                        result_column.alias = Some(column.clone());
+                    }
+                }
+                subplan.query_type = SelectQueryType::Subquery {
+                    yield_reg: usize::MAX, // will be set later in bytecode emission
+                    coroutine_implementation_start: BranchOffset::Placeholder, // will be set later in bytecode emission
+                };
+                scope.tables.push(TableReference::new_subquery(
+                    alias.unwrap_or(normalized_qualified_name),
+                    subplan,
+                    None,
+                ));
+                return Ok(());
+            }
 
             // Check if the outer query scope has this table.
             if let Some(outer_scope) = scope.parent {
@@ -344,9 +879,7 @@ fn parse_from_clause_table<'a>(
                     .iter()
                     .find(|cte| cte.name == normalized_qualified_name)
                 {
-                    // TODO: avoid cloning the CTE plan here.
-                    let cte_table =
-                        TableReference::new_subquery(cte.name.clone(), cte.plan.clone(), None);
+                    let cte_table = cte.table_reference(alias);
                     scope.tables.push(cte_table);
                     return Ok(());
                 }
@@ -403,6 +936,7 @@ fn parse_from_clause_table<'a>(
                 join_info: None,
                 table: Table::Virtual(vtab),
                 identifier: alias,
+                database: None,
             });
 
             Ok(())
@@ -441,6 +975,29 @@ pub struct Scope<'a> {
     ctes: Vec<Cte>,
     /// The parent scope, if any. For example, a second CTE has access to the first CTE via the parent scope.
     parent: Option<&'a Scope<'a>>,
+    /// Only set in the scope of subqueries used in expressions, which can refer to the columns of
+    /// the tables in this scope. Collects these references while such a subquery is being planned.
+    outer_refs: Option<RefCell<Vec<Expr>>>,
+    /// Only set in the scope of the SELECT of a view, to the name of the view.
+    /// Used to detect views that are defined in terms of themselves.
+    view: Option<String>,
+}
+
+impl<'a> Scope<'a> {
+    /// Creates the scope of the subqueries used in the expressions of a query with the given tables and CTEs.
+    pub fn for_expr_subqueries(
+        tables: Vec<TableReference>,
+        ctes: Vec<Cte>,
+        parent: Option<&'a Scope<'a>>,
+    ) -> Self {
+        Self {
+            tables,
+            ctes,
+            parent,
+            outer_refs: Some(RefCell::new(vec![])),
+            view: None,
+        }
+    }
 }
 
 pub struct Cte {
@@ -449,6 +1006,211 @@ pub struct Cte {
     /// The query plan for the CTE.
     /// Currently we only support SELECT queries in CTEs.
     plan: SelectPlan,
+    /// Set if the CTE is declared AS MATERIALIZED. Shared by all the references to the CTE.
+    materialization: Option<Rc<Cell<Option<CteMaterialization>>>>,
+    /// Whether this is a recursive CTE referred to from its own recursive SELECT,
+    /// in which case `plan` is the plan of its initial SELECT.
+    recursive_reference: bool,
+}
+
+impl Cte {
+    /// Returns a table reference to the CTE, named `alias` if there is one.
+    fn table_reference(&self, alias: Option<String>) -> TableReference {
+        let identifier = alias.unwrap_or_else(|| self.name.clone());
+        if self.recursive_reference {
+            return TableReference::new_cte_row(identifier, &self.plan);
+        }
+        // TODO: find a way not to clone the CTE plan here.
+        match &self.materialization {
+            Some(materialization) => TableReference::new_materialized_cte(
+                identifier,
+                self.plan.clone(),
+                materialization.clone(),
+            ),
+            // A CTE that is not materialized can be rewritten as a subquery.
+            None => TableReference::new_subquery(identifier, self.plan.clone(), None),
+        }
+    }
+}
+
+/// Names the result columns of a CTE after the column list of its declaration, e.g. `cnt(x)`.
+fn set_cte_column_names(
+    plan: &mut SelectPlan,
+    cte_name: &str,
+    columns: Option<&[IndexedColumn]>,
+) -> Result<()> {
+    let Some(columns) = columns else {
+        return Ok(());
+    };
+    if columns.len() != plan.result_columns.len() {
+        crate::bail_parse_error!(
+            "table {} has {} values for {} columns",
+            cte_name,
+            plan.result_columns.len(),
+            columns.len()
+        );
+    }
+    for (result_column, column) in plan.result_columns.iter_mut().zip(columns) {
+        result_column.alias = Some(normalize_ident(&column.col_name.0));
+    }
+    Ok(())
+}
+
+/// Returns true if a recursive CTE is referred to from the given plan, or from one of its subqueries.
+fn has_recursive_reference(plan: &SelectPlan) -> bool {
+    plan.table_references.iter().any(|t| match &t.op {
+        Operation::CteRow { .. } => true,
+        Operation::Subquery { plan, .. } | Operation::MaterializedCte { plan, .. } => {
+            has_recursive_reference(plan)
+        }
+        Operation::RecursiveCte {
+            initial, recursive, ..
+        } => has_recursive_reference(initial) || has_recursive_reference(recursive),
+        Operation::Scan { .. } | Operation::Search(_) => false,
+    }) || plan
+        .subqueries
+        .iter()
+        .any(|s| has_recursive_reference(&s.plan))
+}
+
+/// Plans a recursive CTE, whose SELECT is an initial SELECT combined with UNION or UNION ALL
+/// with a recursive SELECT that refers to the CTE itself, e.g.
+///
+/// WITH RECURSIVE cnt(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM cnt WHERE x < 10) SELECT x FROM cnt
+///
+/// The resulting plan has a single table reference, whose Operation::RecursiveCte contains the plans of both SELECTs,
+/// plus the LIMIT and OFFSET of the CTE, which stop the recursion once enough rows have been produced.
+fn plan_recursive_cte(
+    schema: &Schema,
+    cte_name: &str,
+    columns: Option<&[IndexedColumn]>,
+    select: ast::Select,
+    syms: &SymbolTable,
+    scope: &mut Scope,
+) -> Result<SelectPlan> {
+    if select.with.is_some() {
+        crate::bail_parse_error!("WITH clause in a recursive CTE is not supported");
+    }
+    if select.order_by.is_some() {
+        crate::bail_parse_error!("ORDER BY in a recursive CTE is not supported");
+    }
+    let mut compounds = select.body.compounds.unwrap_or_default();
+    if compounds.len() != 1 {
+        crate::bail_parse_error!(
+            "recursive CTE {} must combine one initial SELECT and one recursive SELECT",
+            cte_name
+        );
+    }
+    let compound = compounds.pop().unwrap();
+    let union_all = match compound.operator {
+        CompoundOperator::UnionAll => true,
+        CompoundOperator::Union => false,
+        _ => crate::bail_parse_error!("recursive CTE {} must use UNION or UNION ALL", cte_name),
+    };
+    let as_subquery = |one_select: Box<ast::OneSelect>| ast::Select {
+        with: None,
+        body: ast::SelectBody {
+            select: one_select,
+            compounds: None,
+        },
+        order_by: None,
+        limit: None,
+    };
+
+    let Plan::Select(mut initial) =
+        prepare_select_plan(schema, as_subquery(select.body.select), syms, Some(scope))?
+    else {
+        unreachable!();
+    };
+    set_cte_column_names(&mut initial, cte_name, columns)?;
+    initial.query_type = SelectQueryType::Subquery {
+        yield_reg: usize::MAX, // will be set later in bytecode emission
+        coroutine_implementation_start: BranchOffset::Placeholder, // will be set later in bytecode emission
+    };
+
+    // The recursive SELECT sees the CTE as a table with the columns of the initial SELECT.
+    scope.ctes.push(Cte {
+        name: cte_name.to_string(),
+        plan: initial.clone(),
+        materialization: None,
+        recursive_reference: true,
+    });
+    let recursive = prepare_select_plan(schema, as_subquery(compound.select), syms, Some(scope));
+    scope.ctes.pop();
+    let Plan::Select(mut recursive) = recursive? else {
+        unreachable!();
+    };
+    recursive.query_type = SelectQueryType::Subquery {
+        yield_reg: usize::MAX, // will be set later in bytecode emission
+        coroutine_implementation_start: BranchOffset::Placeholder, // will be set later in bytecode emission
+    };
+
+    if recursive.result_columns.len() != initial.result_columns.len() {
+        crate::bail_parse_error!(
+            "SELECTs to the left and right of {} do not have the same number of result columns",
+            if union_all { "UNION ALL" } else { "UNION" }
+        );
+    }
+    let self_references: Vec<_> = recursive
+        .table_references
+        .iter()
+        .filter(|t| matches!(t.op, Operation::CteRow { .. }))
+        .collect();
+    if self_references.is_empty() {
+        crate::bail_parse_error!("compound SELECTs are not yet supported");
+    }
+    if self_references.len() > 1 {
+        crate::bail_parse_error!("multiple references to recursive table: {}", cte_name);
+    }
+    if self_references[0]
+        .join_info
+        .as_ref()
+        .is_some_and(|join_info| join_info.outer)
+    {
+        crate::bail_parse_error!("recursive reference in an outer join: {}", cte_name);
+    }
+    if recursive
+        .subqueries
+        .iter()
+        .any(|s| has_recursive_reference(&s.plan))
+        || recursive.table_references.iter().any(|t| match &t.op {
+            Operation::Subquery { plan, .. } => has_recursive_reference(plan),
+            _ => false,
+        })
+    {
+        crate::bail_parse_error!("recursive reference in a subquery: {}", cte_name);
+    }
+    if recursive.group_by.is_some()
+        || !recursive.aggregates.is_empty()
+        || !recursive.windows.is_empty()
+    {
+        crate::bail_parse_error!("recursive aggregate queries not supported");
+    }
+
+    let (limit, offset) = select.limit.map_or(Ok((None, None)), |l| parse_limit(*l))?;
+    let table_references = vec![TableReference::new_recursive_cte(
+        cte_name.to_string(),
+        initial,
+        recursive,
+        union_all,
+    )];
+    let mut result_columns = vec![];
+    select_star(&table_references, &mut result_columns);
+    Ok(SelectPlan {
+        table_references,
+        result_columns,
+        distinct: false,
+        where_clause: vec![],
+        group_by: None,
+        order_by: None,
+        aggregates: vec![],
+        limit,
+        offset,
+        contains_constant_false_condition: false,
+        query_type: SelectQueryType::TopLevel,
+        subqueries: vec![],
+        windows: vec![],
+    })
 }
 
 pub fn parse_from<'a>(
@@ -458,29 +1220,21 @@ pub fn parse_from<'a>(
     with: Option<With>,
     out_where_clause: &mut Vec<WhereTerm>,
     outer_scope: Option<&'a Scope<'a>>,
-) -> Result<Vec<TableReference>> {
+) -> Result<(Vec<TableReference>, Vec<Cte>)> {
     if from.as_ref().and_then(|f| f.select.as_ref()).is_none() {
-        return Ok(vec![]);
+        return Ok((vec![], vec![]));
     }
 
     let mut scope = Scope {
         tables: vec![],
         ctes: vec![],
         parent: outer_scope,
+        outer_refs: None,
+        view: None,
     };
 
     if let Some(with) = with {
-        if with.recursive {
-            crate::bail_parse_error!("Recursive CTEs are not yet supported");
-        }
         for cte in with.ctes {
-            if cte.materialized == Materialized::Yes {
-                crate::bail_parse_error!("Materialized CTEs are not yet supported");
-            }
-            if cte.columns.is_some() {
-                crate::bail_parse_error!("CTE columns are not yet supported");
-            }
-
             // Check if normalized name conflicts with catalog tables or other CTEs
             // TODO: sqlite actually allows overriding a catalog table with a CTE.
             // We should carry over the 'Scope' struct to all of our identifier resolution.
@@ -503,18 +1257,37 @@ pub fn parse_from<'a>(
             }
 
             // CTE can refer to other CTEs that came before it, plus any schema tables or tables in the outer scope.
-            let cte_plan = prepare_select_plan(schema, *cte.select, syms, Some(&scope))?;
-            let Plan::Select(mut cte_plan) = cte_plan else {
-                crate::bail_parse_error!("Only SELECT queries are currently supported in CTEs");
+            // In a WITH RECURSIVE clause, a compound SELECT can also refer to the CTE itself.
+            let mut cte_plan = if with.recursive && cte.select.body.compounds.is_some() {
+                plan_recursive_cte(
+                    schema,
+                    &cte_name_normalized,
+                    cte.columns.as_deref(),
+                    *cte.select,
+                    syms,
+                    &mut scope,
+                )?
+            } else {
+                let cte_plan = prepare_select_plan(schema, *cte.select, syms, Some(&scope))?;
+                let Plan::Select(mut cte_plan) = cte_plan else {
+                    crate::bail_parse_error!("Only SELECT queries are currently supported in CTEs");
+                };
+                set_cte_column_names(&mut cte_plan, &cte_name_normalized, cte.columns.as_deref())?;
+                cte_plan
             };
             // CTE can be rewritten as a subquery.
             cte_plan.query_type = SelectQueryType::Subquery {
                 yield_reg: usize::MAX, // will be set later in bytecode emission
                 coroutine_implementation_start: BranchOffset::Placeholder, // will be set later in bytecode emission
             };
+            // A MATERIALIZED CTE is computed once, instead of once per reference.
+            let materialization =
+                (cte.materialized == Materialized::Yes).then(|| Rc::new(Cell::new(None)));
             scope.ctes.push(Cte {
                 name: cte_name_normalized,
                 plan: cte_plan,
+                materialization,
+                recursive_reference: false,
             });
         }
     }
@@ -528,7 +1301,317 @@ pub fn parse_from<'a>(
         parse_join(schema, join, syms, &mut scope, out_where_clause)?;
     }
 
-    Ok(scope.tables)
+    Ok((scope.tables, scope.ctes))
+}
+
+/// Plans the subqueries in an expression: scalar subqueries, EXISTS and IN (SELECT ...).
+/// Each subquery gets its own SelectPlan, which is added to `out_subqueries`, and is replaced in the
+/// expression with an Expr::SubqueryResult that refers to it by its index.
+///
+/// `scope` is the scope created with Scope::for_expr_subqueries() for the query the expression belongs to.
+/// If that query is itself a subquery used in an expression, its references to the columns of the
+/// enclosing queries are replaced with Expr::OuterRef here, before the expression is bound.
+pub fn plan_subqueries_in_expr(
+    expr: &mut Expr,
+    schema: &Schema,
+    syms: &SymbolTable,
+    scope: &Scope,
+    result_columns: Option<&[ResultSetColumn]>,
+    out_subqueries: &mut Vec<ExprSubquery>,
+) -> Result<()> {
+    match expr {
+        Expr::Id(_) | Expr::Qualified(_, _) | Expr::DoublyQualified(_, _, _) => {
+            if let Some(outer_ref) = resolve_outer_ref(expr, scope, result_columns) {
+                *expr = outer_ref;
+            }
+            Ok(())
+        }
+        Expr::Exists(_) | Expr::InSelect { .. } | Expr::InTable { .. } | Expr::Subquery(_) => {
+            plan_subquery(expr, schema, syms, scope, result_columns, out_subqueries)
+        }
+        Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            // The left-hand side of IN and the values passed to a correlated subquery belong to this query.
+            // They are planned like any other expression, so e.g. a value that the subquery takes from
+            // a query further out becomes a value that this query takes from its enclosing query.
+            if let Some(lhs) = lhs {
+                plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            for outer_ref in outer_refs {
+                plan_subqueries_in_expr(
+                    outer_ref,
+                    schema,
+                    syms,
+                    scope,
+                    result_columns,
+                    out_subqueries,
+                )?;
+            }
+            Ok(())
+        }
+        Expr::Between {
+            lhs, start, end, ..
+        } => {
+            plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+            plan_subqueries_in_expr(start, schema, syms, scope, result_columns, out_subqueries)?;
+            plan_subqueries_in_expr(end, schema, syms, scope, result_columns, out_subqueries)
+        }
+        Expr::Binary(lhs, _, rhs) => {
+            plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+            plan_subqueries_in_expr(rhs, schema, syms, scope, result_columns, out_subqueries)
+        }
+        Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            if let Some(base) = base {
+                plan_subqueries_in_expr(base, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            for (when, then) in when_then_pairs {
+                plan_subqueries_in_expr(when, schema, syms, scope, result_columns, out_subqueries)?;
+                plan_subqueries_in_expr(then, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            if let Some(else_expr) = else_expr {
+                plan_subqueries_in_expr(
+                    else_expr,
+                    schema,
+                    syms,
+                    scope,
+                    result_columns,
+                    out_subqueries,
+                )?;
+            }
+            Ok(())
+        }
+        Expr::Cast { expr, .. }
+        | Expr::Collate(expr, _)
+        | Expr::IsNull(expr)
+        | Expr::NotNull(expr)
+        | Expr::Unary(_, expr) => {
+            plan_subqueries_in_expr(expr, schema, syms, scope, result_columns, out_subqueries)
+        }
+        Expr::FunctionCall { args, .. } => {
+            for arg in args.iter_mut().flatten() {
+                plan_subqueries_in_expr(arg, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            Ok(())
+        }
+        Expr::InList { lhs, rhs, .. } => {
+            plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+            for arg in rhs.iter_mut().flatten() {
+                plan_subqueries_in_expr(arg, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            Ok(())
+        }
+        Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+            plan_subqueries_in_expr(rhs, schema, syms, scope, result_columns, out_subqueries)?;
+            if let Some(escape) = escape {
+                plan_subqueries_in_expr(
+                    escape,
+                    schema,
+                    syms,
+                    scope,
+                    result_columns,
+                    out_subqueries,
+                )?;
+            }
+            Ok(())
+        }
+        Expr::Parenthesized(exprs) => {
+            for expr in exprs {
+                plan_subqueries_in_expr(expr, schema, syms, scope, result_columns, out_subqueries)?;
+            }
+            Ok(())
+        }
+        Expr::Column { .. }
+        | Expr::FunctionCallStar { .. }
+        | Expr::Literal(_)
+        | Expr::Name(_)
+        | Expr::OuterRef(_)
+        | Expr::Param(_)
+        | Expr::Raise(_, _)
+        | Expr::Register(_)
+        | Expr::RowId { .. }
+        | Expr::Variable(_) => Ok(()),
+    }
+}
+
+/// Plans a scalar subquery, EXISTS or IN (SELECT ...) expression and replaces it with an Expr::SubqueryResult.
+fn plan_subquery(
+    expr: &mut Expr,
+    schema: &Schema,
+    syms: &SymbolTable,
+    scope: &Scope,
+    result_columns: Option<&[ResultSetColumn]>,
+    out_subqueries: &mut Vec<ExprSubquery>,
+) -> Result<()> {
+    let (mut plan, subquery_type, mut lhs, not_in) =
+        match std::mem::replace(expr, Expr::Literal(ast::Literal::Null)) {
+            Expr::Subquery(select) => (
+                prepare_expr_subquery_plan(schema, *select, syms, scope)?,
+                SubqueryType::Scalar,
+                None,
+                false,
+            ),
+            Expr::Exists(select) => (
+                prepare_expr_subquery_plan(schema, *select, syms, scope)?,
+                SubqueryType::Exists,
+                None,
+                false,
+            ),
+            Expr::InSelect { lhs, not, rhs } => (
+                prepare_expr_subquery_plan(schema, *rhs, syms, scope)?,
+                SubqueryType::In,
+                Some(lhs),
+                not,
+            ),
+            Expr::InTable {
+                lhs,
+                not,
+                rhs,
+                args,
+            } => {
+                if args.is_some() {
+                    crate::bail_parse_error!("table-valued functions are not supported in IN");
+                }
+                (
+                    prepare_in_table_plan(schema, rhs, syms, scope)?,
+                    SubqueryType::In,
+                    Some(lhs),
+                    not,
+                )
+            }
+            _ => unreachable!("plan_subquery called on non-subquery expression"),
+        };
+    if subquery_type != SubqueryType::Exists && plan.result_columns.len() != 1 {
+        crate::bail_parse_error!(
+            "sub-select returns {} columns - expected 1",
+            plan.result_columns.len()
+        );
+    }
+    plan.query_type = SelectQueryType::Subquery {
+        yield_reg: usize::MAX, // will be set later in bytecode emission
+        coroutine_implementation_start: BranchOffset::Placeholder, // will be set later in bytecode emission
+    };
+    let mut outer_refs = scope
+        .outer_refs
+        .as_ref()
+        .map(RefCell::take)
+        .unwrap_or_default();
+    out_subqueries.push(ExprSubquery {
+        plan: Box::new(plan),
+        subquery_type,
+        num_outer_refs: outer_refs.len(),
+    });
+    let subquery_id = out_subqueries.len() - 1;
+
+    if let Some(lhs) = lhs.as_mut() {
+        plan_subqueries_in_expr(lhs, schema, syms, scope, result_columns, out_subqueries)?;
+    }
+    for outer_ref in outer_refs.iter_mut() {
+        plan_subqueries_in_expr(
+            outer_ref,
+            schema,
+            syms,
+            scope,
+            result_columns,
+            out_subqueries,
+        )?;
+    }
+    *expr = Expr::SubqueryResult {
+        subquery_id,
+        lhs,
+        not_in,
+        outer_refs,
+    };
+    Ok(())
+}
+
+fn prepare_expr_subquery_plan(
+    schema: &Schema,
+    select: ast::Select,
+    syms: &SymbolTable,
+    scope: &Scope,
+) -> Result<SelectPlan> {
+    let Plan::Select(plan) = prepare_select_plan(schema, select, syms, Some(scope))? else {
+        unreachable!();
+    };
+    Ok(plan)
+}
+
+/// `lhs IN tbl` is planned as `lhs IN (SELECT * FROM tbl)`.
+fn prepare_in_table_plan(
+    schema: &Schema,
+    tbl_name: QualifiedName,
+    syms: &SymbolTable,
+    scope: &Scope,
+) -> Result<SelectPlan> {
+    let mut table_scope = Scope {
+        tables: vec![],
+        ctes: vec![],
+        parent: Some(scope),
+        outer_refs: None,
+        view: None,
+    };
+    parse_from_clause_table(
+        schema,
+        ast::SelectTable::Table(tbl_name, None, None),
+        &mut table_scope,
+        syms,
+    )?;
+    let mut result_columns = vec![];
+    select_star(&table_scope.tables, &mut result_columns);
+    Ok(SelectPlan {
+        table_references: table_scope.tables,
+        result_columns,
+        distinct: false,
+        where_clause: vec![],
+        group_by: None,
+        order_by: None,
+        aggregates: vec![],
+        limit: None,
+        offset: None,
+        contains_constant_false_condition: false,
+        query_type: SelectQueryType::TopLevel,
+        subqueries: vec![],
+        windows: vec![],
+    })
+}
+
+/// If `expr` is a column reference that cannot be resolved in the query of `scope`, but in one of
+/// the queries that it is a subquery of, registers it as a value that the query takes from its
+/// enclosing query and returns the Expr::OuterRef to replace it with.
+fn resolve_outer_ref(
+    expr: &Expr,
+    scope: &Scope,
+    result_columns: Option<&[ResultSetColumn]>,
+) -> Option<Expr> {
+    let enclosing_scope = scope.parent?;
+    let outer_refs = enclosing_scope.outer_refs.as_ref()?;
+    if bind_column_references(&mut expr.clone(), &scope.tables, result_columns).is_ok() {
+        return None;
+    }
+    let mut current = Some(enclosing_scope);
+    while let Some(outer_scope) = current.filter(|s| s.outer_refs.is_some()) {
+        if bind_column_references(&mut expr.clone(), &outer_scope.tables, None).is_ok() {
+            let mut outer_refs = outer_refs.borrow_mut();
+            let idx = match outer_refs.iter().position(|e| e == expr) {
+                Some(idx) => idx,
+                None => {
+                    outer_refs.push(expr.clone());
+                    outer_refs.len() - 1
+                }
+            };
+            return Some(Expr::OuterRef(idx));
+        }
+        current = outer_scope.parent;
+    }
+    None
 }
 
 pub fn parse_where(
@@ -544,7 +1627,8 @@ pub fn parse_where(
             bind_column_references(expr, table_references, result_columns)?;
         }
         for expr in predicates {
-            let eval_at = determine_where_to_eval_expr(&expr)?;
+            let eval_at =
+                eval_after_right_joins(determine_where_to_eval_expr(&expr)?, table_references);
             out_where_clause.push(WhereTerm {
                 expr,
                 from_outer_join: false,
@@ -557,6 +1641,38 @@ pub fn parse_where(
     }
 }
 
+/// Parses the RETURNING clause of an INSERT or DELETE into the result columns of the statement,
+/// bound to the table being written to.
+pub fn parse_returning(
+    returning: Option<Vec<ast::ResultColumn>>,
+    table_references: &[TableReference],
+) -> Result<Vec<ResultSetColumn>> {
+    let mut result_columns = vec![];
+    for column in returning.into_iter().flatten() {
+        match column {
+            ast::ResultColumn::Star => select_star(table_references, &mut result_columns),
+            ast::ResultColumn::TableStar(_) => {
+                crate::bail_parse_error!("RETURNING may not use \"TABLE.*\" wildcards")
+            }
+            ast::ResultColumn::Expr(mut expr, alias) => {
+                // An expression without an alias is named after its text, unless it is a column.
+                let text = expr.to_string();
+                bind_column_references(&mut expr, table_references, None)?;
+                let alias = match alias {
+                    Some(ast::As::As(alias) | ast::As::Elided(alias)) => Some(alias.0),
+                    None => (!matches!(expr, Expr::Column { .. })).then_some(text),
+                };
+                result_columns.push(ResultSetColumn {
+                    expr,
+                    alias,
+                    contains_aggregates: false,
+                });
+            }
+        }
+    }
+    Ok(result_columns)
+}
+
 /**
   Returns the earliest point at which a WHERE term can be evaluated.
   For expressions referencing tables, this is the innermost loop that contains a row for each
@@ -564,7 +1680,7 @@ pub fn parse_where(
   For expressions not referencing any tables (e.g. constants), this is before the main loop is
   opened, because they do not need any table data.
 */
-fn determine_where_to_eval_expr<'a>(predicate: &'a ast::Expr) -> Result<EvalAt> {
+pub fn determine_where_to_eval_expr<'a>(predicate: &'a ast::Expr) -> Result<EvalAt> {
     let mut eval_at: EvalAt = EvalAt::BeforeLoop;
     match predicate {
         ast::Expr::Binary(e1, _, e2) => {
@@ -633,21 +1749,31 @@ fn determine_where_to_eval_expr<'a>(predicate: &'a ast::Expr) -> Result<EvalAt>
         Expr::DoublyQualified(_, _, _) => {
             unreachable!("DoublyQualified should be resolved to a Column before resolving eval_at")
         }
-        Expr::Exists(_) => {
-            todo!("exists not supported yet")
+        Expr::Exists(_) | Expr::InSelect { .. } | Expr::InTable { .. } | Expr::Subquery(_) => {
+            unreachable!("Subqueries should be planned before resolving eval_at")
         }
+        Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            if let Some(lhs) = lhs {
+                eval_at = eval_at.max(determine_where_to_eval_expr(lhs)?);
+            }
+            for outer_ref in outer_refs {
+                eval_at = eval_at.max(determine_where_to_eval_expr(outer_ref)?);
+            }
+        }
+        // A value of the enclosing query is constant while the subquery runs
+        Expr::OuterRef(_) => {}
+        // So is a value of the row that fired a trigger while the trigger program runs
+        Expr::Param(_) => {}
+        // and a value the statement computed into a register before running the query
+        Expr::Register(_) => {}
         Expr::FunctionCall { args, .. } => {
             for arg in args.as_ref().unwrap_or(&vec![]).iter() {
                 eval_at = eval_at.max(determine_where_to_eval_expr(arg)?);
             }
         }
         Expr::FunctionCallStar { .. } => {}
-        Expr::InSelect { .. } => {
-            todo!("in select not supported yet")
-        }
-        Expr::InTable { .. } => {
-            todo!("in table not supported yet")
-        }
         Expr::IsNull(expr) => {
             eval_at = eval_at.max(determine_where_to_eval_expr(expr)?);
         }
@@ -660,12 +1786,7 @@ fn determine_where_to_eval_expr<'a>(predicate: &'a ast::Expr) -> Result<EvalAt>
                 eval_at = eval_at.max(determine_where_to_eval_expr(expr)?);
             }
         }
-        Expr::Raise(_, _) => {
-            todo!("raise not supported yet")
-        }
-        Expr::Subquery(_) => {
-            todo!("subquery not supported yet")
-        }
+        Expr::Raise(_, _) => {}
         Expr::Unary(_, expr) => {
             eval_at = eval_at.max(determine_where_to_eval_expr(expr)?);
         }
@@ -690,15 +1811,38 @@ fn parse_join<'a>(
 
     parse_from_clause_table(schema, table, scope, syms)?;
 
-    let (outer, natural) = match join_operator {
+    let (outer, right, natural) = match join_operator {
         ast::JoinOperator::TypedJoin(Some(join_type)) => {
-            let is_outer = join_type.contains(JoinType::OUTER);
+            let is_outer = join_type.contains(JoinType::LEFT);
+            let is_right = join_type.contains(JoinType::RIGHT);
             let is_natural = join_type.contains(JoinType::NATURAL);
-            (is_outer, is_natural)
+            (is_outer, is_right, is_natural)
         }
-        _ => (false, false),
+        _ => (false, false, false),
     };
 
+    if right {
+        // The rows of the right table that had no match are read again after the main loop,
+        // and the tables before it are set to NULL.
+        let (right_table, left_tables) = scope.tables.split_last().unwrap();
+        let has_rowid = right_table.btree().is_some_and(|table| {
+            table.has_rowid && matches!(right_table.op, Operation::Scan { .. })
+        });
+        if !has_rowid {
+            crate::bail_parse_error!(
+                "RIGHT and FULL OUTER JOIN are only supported when the right side is a table with a rowid"
+            );
+        }
+        if left_tables.iter().any(|table| {
+            !matches!(table.table, Table::BTree(_))
+                && !matches!(table.op, Operation::Subquery { .. })
+        }) {
+            crate::bail_parse_error!(
+                "RIGHT and FULL OUTER JOIN are only supported when the left side is made of tables and subqueries"
+            );
+        }
+    }
+
     let mut using = None;
 
     if natural && constraint.is_some() {
@@ -756,14 +1900,14 @@ fn parse_join<'a>(
                 }
                 for pred in preds {
                     let cur_table_idx = scope.tables.len() - 1;
-                    let eval_at = if outer {
+                    let eval_at = if outer || right {
                         EvalAt::Loop(cur_table_idx)
                     } else {
-                        determine_where_to_eval_expr(&pred)?
+                        eval_after_right_joins(determine_where_to_eval_expr(&pred)?, &scope.tables)
                     };
                     out_where_clause.push(WhereTerm {
                         expr: pred,
-                        from_outer_join: outer,
+                        from_outer_join: outer || right,
                         eval_at,
                     });
                 }
@@ -826,14 +1970,14 @@ fn parse_join<'a>(
                             is_rowid_alias: right_col.is_rowid_alias,
                         }),
                     );
-                    let eval_at = if outer {
+                    let eval_at = if outer || right {
                         EvalAt::Loop(cur_table_idx)
                     } else {
-                        determine_where_to_eval_expr(&expr)?
+                        eval_after_right_joins(determine_where_to_eval_expr(&expr)?, &scope.tables)
                     };
                     out_where_clause.push(WhereTerm {
                         expr,
-                        from_outer_join: outer,
+                        from_outer_join: outer || right,
                         eval_at,
                     });
                 }
@@ -845,11 +1989,31 @@ fn parse_join<'a>(
     assert!(scope.tables.len() >= 2);
     let last_idx = scope.tables.len() - 1;
     let rightmost_table = scope.tables.get_mut(last_idx).unwrap();
-    rightmost_table.join_info = Some(JoinInfo { outer, using });
+    rightmost_table.join_info = Some(JoinInfo {
+        outer,
+        right,
+        using,
+    });
 
     Ok(())
 }
 
+/// The loop at which a condition that is not part of an OUTER JOIN is evaluated, given the loop of
+/// the last table it refers to. It can't filter the rows of the tables of a RIGHT or FULL OUTER JOIN
+/// before their match is recorded, since a row of the right table that matched must not be emitted
+/// again with NULLs, so it is evaluated at the loop of the last such join at the earliest.
+fn eval_after_right_joins(eval_at: EvalAt, tables: &[TableReference]) -> EvalAt {
+    let last_right_join = tables
+        .iter()
+        .rposition(|table| table.join_info.as_ref().is_some_and(|join| join.right));
+    match (eval_at, last_right_join) {
+        (EvalAt::Loop(table_index), Some(right_join_index)) if table_index < right_join_index => {
+            EvalAt::Loop(right_join_index)
+        }
+        _ => eval_at,
+    }
+}
+
 pub fn parse_limit(limit: Limit) -> Result<(Option<isize>, Option<isize>)> {
     let offset_val = match limit.offset {
         Some(offset_expr) => match offset_expr {
@@ -898,6 +2062,24 @@ pub fn break_predicate_at_and_boundaries(predicate: Expr, out_predicates: &mut V
     }
 }
 
+/// Whether a table is joined to the tables before it with a USING clause that has the column.
+fn joins_using(table: &TableReference, column_name: &str) -> bool {
+    table
+        .join_info
+        .as_ref()
+        .and_then(|join_info| join_info.using.as_ref())
+        .is_some_and(|using| {
+            using
+                .iter()
+                .any(|using_col| using_col.0.eq_ignore_ascii_case(column_name))
+        })
+}
+
+/// Whether the rows of a table have a rowid, which is not the case for WITHOUT ROWID tables.
+fn has_rowid(table: &TableReference) -> bool {
+    table.btree().map_or(true, |btree| btree.has_rowid)
+}
+
 fn parse_row_id<F>(column_name: &str, table_id: usize, fn_check: F) -> Result<Option<Expr>>
 where
     F: FnOnce() -> bool,
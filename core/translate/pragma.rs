@@ -4,16 +4,18 @@
 use limbo_sqlite3_parser::ast;
 use limbo_sqlite3_parser::ast::PragmaName;
 use std::cell::RefCell;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 
-use crate::schema::Schema;
+use crate::schema::{ref_act_name, Schema};
 use crate::storage::sqlite3_ondisk::{DatabaseHeader, MIN_PAGE_CACHE_SIZE};
 use crate::storage::wal::CheckpointMode;
+use crate::translate::collate::CollationSeq;
+use crate::translate::foreign_key::emit_foreign_key_check;
 use crate::util::normalize_ident;
 use crate::vdbe::builder::{ProgramBuilder, ProgramBuilderOpts, QueryMode};
 use crate::vdbe::insn::{Cookie, Insn};
 use crate::vdbe::BranchOffset;
-use crate::{bail_parse_error, Pager};
+use crate::{bail_parse_error, Connection, Pager};
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 
@@ -40,6 +42,7 @@ pub fn translate_pragma(
     body: Option<ast::PragmaBody>,
     database_header: Rc<RefCell<DatabaseHeader>>,
     pager: Rc<Pager>,
+    connection: Weak<Connection>,
 ) -> crate::Result<ProgramBuilder> {
     let mut program = ProgramBuilder::new(ProgramBuilderOpts {
         query_mode,
@@ -62,11 +65,68 @@ pub fn translate_pragma(
     };
 
     match body {
+        // The databases are those of the connection, which the schema does not know the paths of.
+        _ if pragma == PragmaName::DatabaseList => {
+            let connection = connection
+                .upgrade()
+                .expect("connection dropped while translating a statement");
+            let base_reg = program.alloc_registers(3);
+            for (seq, name, file) in connection.database_list() {
+                program.emit_int(seq as i64, base_reg);
+                program.emit_string8(name, base_reg + 1);
+                program.emit_string8(file, base_reg + 2);
+                program.emit_result_row(base_reg, 3);
+            }
+        }
+        // The custom collations are registered on the database rather than stored in its schema.
+        _ if pragma == PragmaName::CollationList => {
+            let connection = connection
+                .upgrade()
+                .expect("connection dropped while translating a statement");
+            let base_reg = program.alloc_registers(2);
+            for (seq, collation) in CollationSeq::list(&connection.db.syms.borrow())
+                .iter()
+                .enumerate()
+            {
+                program.emit_int(seq as i64, base_reg);
+                program.emit_string8(collation.name().to_string(), base_reg + 1);
+                program.emit_result_row(base_reg, 2);
+            }
+        }
+        // The flags are those of the connection, and are set when the statement is prepared.
+        _ if matches!(
+            pragma,
+            PragmaName::ForeignKeys | PragmaName::DeferForeignKeys
+        ) =>
+        {
+            let connection = connection
+                .upgrade()
+                .expect("connection dropped while translating a statement");
+            let flag = if pragma == PragmaName::ForeignKeys {
+                &connection.foreign_keys
+            } else {
+                &connection.defer_foreign_keys
+            };
+            match body {
+                None => {
+                    let register = program.alloc_register();
+                    program.emit_bool(flag.get(), register);
+                    program.emit_result_row(register, 1);
+                }
+                Some(ast::PragmaBody::Equals(value) | ast::PragmaBody::Call(value)) => {
+                    let value = pragma_bool_value(&value)?;
+                    // Like SQLite, foreign key enforcement cannot change inside a transaction.
+                    if pragma == PragmaName::DeferForeignKeys || *connection.auto_commit.borrow() {
+                        flag.set(value);
+                    }
+                }
+            }
+        }
         None => {
             query_pragma(pragma, schema, None, database_header.clone(), &mut program)?;
         }
         Some(ast::PragmaBody::Equals(value)) => match pragma {
-            PragmaName::TableInfo => {
+            PragmaName::TableInfo | PragmaName::ForeignKeyList | PragmaName::ForeignKeyCheck => {
                 query_pragma(
                     pragma,
                     schema,
@@ -88,7 +148,7 @@ pub fn translate_pragma(
             }
         },
         Some(ast::PragmaBody::Call(value)) => match pragma {
-            PragmaName::TableInfo => {
+            PragmaName::TableInfo | PragmaName::ForeignKeyList | PragmaName::ForeignKeyCheck => {
                 query_pragma(
                     pragma,
                     schema,
@@ -140,7 +200,14 @@ fn update_pragma(
             query_pragma(PragmaName::JournalMode, schema, None, header, program)?;
             Ok(())
         }
+        PragmaName::CollationList => {
+            unreachable!("collation_list is translated by translate_pragma")
+        }
         PragmaName::LegacyFileFormat => Ok(()),
+        PragmaName::DatabaseList => unreachable!("database_list is translated by translate_pragma"),
+        PragmaName::ForeignKeys | PragmaName::DeferForeignKeys => {
+            unreachable!("{} is translated by translate_pragma", pragma)
+        }
         PragmaName::WalCheckpoint => {
             query_pragma(PragmaName::WalCheckpoint, schema, None, header, program)?;
             Ok(())
@@ -153,7 +220,7 @@ fn update_pragma(
             // TODO: Implement updating user_version
             todo!("updating user_version not yet implemented")
         }
-        PragmaName::TableInfo => {
+        PragmaName::TableInfo | PragmaName::ForeignKeyList | PragmaName::ForeignKeyCheck => {
             // because we need control over the write parameter for the transaction,
             // this should be unreachable. We have to force-call query_pragma before
             // getting here
@@ -182,7 +249,53 @@ fn query_pragma(
             program.emit_string8("wal".into(), register);
             program.emit_result_row(register, 1);
         }
+        PragmaName::CollationList => {
+            unreachable!("collation_list is translated by translate_pragma")
+        }
         PragmaName::LegacyFileFormat => {}
+        PragmaName::DatabaseList => unreachable!("database_list is translated by translate_pragma"),
+        PragmaName::ForeignKeys | PragmaName::DeferForeignKeys => {
+            unreachable!("{} is translated by translate_pragma", pragma)
+        }
+        PragmaName::ForeignKeyCheck => {
+            let table = match value {
+                Some(ast::Expr::Name(name)) => Some(normalize_ident(&name.0)),
+                _ => None,
+            };
+            emit_foreign_key_check(program, schema, table.as_deref())?;
+        }
+        PragmaName::ForeignKeyList => {
+            let table = match value {
+                Some(ast::Expr::Name(name)) => schema.get_btree_table(&normalize_ident(&name.0)),
+                _ => None,
+            };
+            let base_reg = register;
+            program.alloc_registers(7);
+            for (id, foreign_key) in table.iter().flat_map(|t| t.foreign_keys.iter().enumerate()) {
+                for (seq, child_column) in foreign_key.child_columns.iter().enumerate() {
+                    program.emit_int(id as i64, base_reg);
+                    program.emit_int(seq as i64, base_reg + 1);
+                    program.emit_string8(foreign_key.parent_table.clone(), base_reg + 2);
+                    program.emit_string8(child_column.clone(), base_reg + 3);
+                    match foreign_key.parent_columns.get(seq) {
+                        Some(parent_column) => {
+                            program.emit_string8(parent_column.clone(), base_reg + 4)
+                        }
+                        None => program.emit_null(base_reg + 4),
+                    }
+                    program.emit_string8(
+                        ref_act_name(foreign_key.on_update).to_string(),
+                        base_reg + 5,
+                    );
+                    program.emit_string8(
+                        ref_act_name(foreign_key.on_delete).to_string(),
+                        base_reg + 6,
+                    );
+                    program.emit_string8("NONE".to_string(), base_reg + 7);
+                    program.emit_result_row(base_reg, 8);
+                }
+            }
+        }
         PragmaName::WalCheckpoint => {
             // Checkpoint uses 3 registers: P1, P2, P3. Ref Insn::Checkpoint for more info.
             // Allocate two more here as one was allocated at the top.
@@ -261,6 +374,23 @@ fn query_pragma(
     Ok(())
 }
 
+/// The value of a boolean pragma: ON, OFF, YES, NO, TRUE, FALSE or a number.
+fn pragma_bool_value(value: &ast::Expr) -> crate::Result<bool> {
+    let text = match value {
+        ast::Expr::Id(ast::Id(text))
+        | ast::Expr::Name(ast::Name(text))
+        | ast::Expr::Literal(ast::Literal::String(text))
+        | ast::Expr::Literal(ast::Literal::Numeric(text)) => text.trim_matches('\'').to_lowercase(),
+        ast::Expr::Literal(ast::Literal::Keyword(text)) => text.to_lowercase(),
+        _ => bail_parse_error!("Not a valid value"),
+    };
+    Ok(match text.as_str() {
+        "on" | "yes" | "true" => true,
+        "off" | "no" | "false" => false,
+        _ => text.parse::<f64>().is_ok_and(|n| n != 0.0),
+    })
+}
+
 fn update_cache_size(value: i64, header: Rc<RefCell<DatabaseHeader>>, pager: Rc<Pager>) {
     let mut cache_size_unformatted: i64 = value;
     let mut cache_size = if cache_size_unformatted < 0 {
@@ -1,5 +1,5 @@
 use crate::{
-    vdbe::{builder::ProgramBuilder, insn::Insn, BranchOffset},
+    vdbe::{builder::ProgramBuilder, insn::Insn, BranchOffset, CursorID},
     Result,
 };
 
@@ -11,6 +11,7 @@ use super::{
 
 /// Emits the bytecode for:
 /// - all result columns
+/// - skipping the row if it is a duplicate and the query is a SELECT DISTINCT
 /// - result row (or if a subquery, yields to the parent query)
 /// - limit
 pub fn emit_select_result(
@@ -20,8 +21,12 @@ pub fn emit_select_result(
     label_on_limit_reached: Option<BranchOffset>,
     offset_jump_to: Option<BranchOffset>,
 ) -> Result<()> {
-    if let (Some(jump_to), Some(_)) = (offset_jump_to, label_on_limit_reached) {
-        emit_offset(program, t_ctx, plan, jump_to)?;
+    // Duplicate rows of a SELECT DISTINCT do not count towards the OFFSET,
+    // so it is applied once the row is known to be a new one.
+    if t_ctx.distinct_cursor.is_none() {
+        if let (Some(jump_to), Some(_)) = (offset_jump_to, label_on_limit_reached) {
+            emit_offset(program, t_ctx, plan, jump_to)?;
+        }
     }
 
     let start_reg = t_ctx.reg_result_cols_start.unwrap();
@@ -35,10 +40,50 @@ pub fn emit_select_result(
             &t_ctx.resolver,
         )?;
     }
+    let label_duplicate = program.allocate_label();
+    if let Some(distinct_cursor) = t_ctx.distinct_cursor {
+        emit_distinct_check(
+            program,
+            distinct_cursor,
+            start_reg,
+            plan.result_columns.len(),
+            label_duplicate,
+        );
+        if let (Some(jump_to), Some(_)) = (offset_jump_to, label_on_limit_reached) {
+            emit_offset(program, t_ctx, plan, jump_to)?;
+        }
+    }
     emit_result_row_and_limit(program, t_ctx, plan, start_reg, label_on_limit_reached)?;
+    program.resolve_label(label_duplicate, program.offset());
     Ok(())
 }
 
+/// Emits the bytecode that jumps to `label_duplicate` if the result row in the `count` registers
+/// starting at `start_reg` has been emitted before by a SELECT DISTINCT, and records it otherwise.
+pub fn emit_distinct_check(
+    program: &mut ProgramBuilder,
+    distinct_cursor: CursorID,
+    start_reg: usize,
+    count: usize,
+    label_duplicate: BranchOffset,
+) {
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::MakeRecord {
+        start_reg,
+        count,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::EphemeralFound {
+        cursor_id: distinct_cursor,
+        record_reg,
+        target_pc: label_duplicate,
+    });
+    program.emit_insn(Insn::EphemeralInsert {
+        cursor_id: distinct_cursor,
+        record_reg,
+    });
+}
+
 /// Emits the bytecode for:
 /// - result row (or if a subquery, yields to the parent query)
 /// - limit
@@ -1,12 +1,13 @@
 use super::emitter::emit_program;
-use super::plan::{select_star, Operation, Search, SelectQueryType};
+use super::plan::{select_star, Operation, SelectQueryType};
 use super::planner::Scope;
 use crate::function::{AggFunc, ExtFunc, Func};
 use crate::translate::optimizer::optimize_plan;
 use crate::translate::plan::{Aggregate, Direction, GroupBy, Plan, ResultSetColumn, SelectPlan};
 use crate::translate::planner::{
-    bind_column_references, break_predicate_at_and_boundaries, parse_from, parse_limit,
-    parse_where, resolve_aggregates,
+    aggregate_filter, bind_column_references, break_predicate_at_and_boundaries, parse_from,
+    parse_limit, parse_where, plan_subqueries_in_expr, resolve_aggregates,
+    resolve_window_functions,
 };
 use crate::util::normalize_ident;
 use crate::vdbe::builder::{ProgramBuilderOpts, QueryMode};
@@ -33,7 +34,7 @@ pub fn translate_select(
         approx_num_insns: estimate_num_instructions(select),
         approx_num_labels: estimate_num_labels(select),
     });
-    emit_program(&mut program, select_plan, syms)?;
+    emit_program(&mut program, select_plan, schema, syms)?;
     Ok(program)
 }
 
@@ -46,12 +47,14 @@ pub fn prepare_select_plan<'a>(
     match *select.body.select {
         ast::OneSelect::Select(select_inner) => {
             let SelectInner {
+                distinctness,
                 mut columns,
                 from,
                 where_clause,
                 group_by,
-                ..
+                window_clause,
             } = *select_inner;
+            let window_defs = window_clause.unwrap_or_default();
             let col_count = columns.len();
             if col_count == 0 {
                 crate::bail_parse_error!("SELECT without columns is not allowed");
@@ -62,9 +65,14 @@ pub fn prepare_select_plan<'a>(
             let with = select.with;
 
             // Parse the FROM clause into a vec of TableReferences. Fold all the join conditions expressions into the WHERE clause.
-            let table_references =
+            let (table_references, ctes) =
                 parse_from(schema, from, syms, with, &mut where_predicates, outer_scope)?;
 
+            // Subqueries used in expressions can refer to the tables and CTEs of this query.
+            let subquery_scope =
+                Scope::for_expr_subqueries(table_references.clone(), ctes, outer_scope);
+            let mut subqueries = vec![];
+
             // Preallocate space for the result columns
             let result_columns = Vec::with_capacity(
                 columns
@@ -89,6 +97,7 @@ pub fn prepare_select_plan<'a>(
             let mut plan = SelectPlan {
                 table_references,
                 result_columns,
+                distinct: matches!(distinctness, Some(ast::Distinctness::Distinct)),
                 where_clause: where_predicates,
                 group_by: None,
                 order_by: None,
@@ -97,6 +106,8 @@ pub fn prepare_select_plan<'a>(
                 offset: None,
                 contains_constant_false_condition: false,
                 query_type: SelectQueryType::TopLevel,
+                subqueries: vec![],
+                windows: vec![],
             };
 
             let mut aggregate_expressions = Vec::new();
@@ -131,17 +142,44 @@ pub fn prepare_select_plan<'a>(
                         }
                     }
                     ResultColumn::Expr(ref mut expr, maybe_alias) => {
+                        plan_subqueries_in_expr(
+                            expr,
+                            schema,
+                            syms,
+                            &subquery_scope,
+                            Some(&plan.result_columns),
+                            &mut subqueries,
+                        )?;
                         bind_column_references(
                             expr,
                             &plan.table_references,
                             Some(&plan.result_columns),
                         )?;
+                        if resolve_window_functions(
+                            expr,
+                            &plan.table_references,
+                            Some(&plan.result_columns),
+                            &window_defs,
+                            &mut plan.windows,
+                        )? {
+                            let contains_aggregates =
+                                resolve_aggregates(expr, &mut aggregate_expressions);
+                            plan.result_columns.push(ResultSetColumn {
+                                alias: maybe_alias.as_ref().map(|alias| match alias {
+                                    ast::As::Elided(alias) => alias.0.clone(),
+                                    ast::As::As(alias) => alias.0.clone(),
+                                }),
+                                expr: expr.clone(),
+                                contains_aggregates,
+                            });
+                            continue;
+                        }
                         match expr {
                             ast::Expr::FunctionCall {
                                 name,
                                 distinctness: _,
                                 args,
-                                filter_over: _,
+                                filter_over,
                                 order_by: _,
                             } => {
                                 let args_count = if let Some(args) = &args {
@@ -171,6 +209,7 @@ pub fn prepare_select_plan<'a>(
                                         let agg = Aggregate {
                                             func: f,
                                             args: agg_args.clone(),
+                                            filter: aggregate_filter(filter_over),
                                             original_expr: expr.clone(),
                                         };
                                         aggregate_expressions.push(agg.clone());
@@ -219,6 +258,7 @@ pub fn prepare_select_plan<'a>(
                                                 let agg = Aggregate {
                                                     func: AggFunc::External(f.func.clone().into()),
                                                     args: args.as_ref().unwrap().clone(),
+                                                    filter: aggregate_filter(filter_over),
                                                     original_expr: expr.clone(),
                                                 };
                                                 aggregate_expressions.push(agg.clone());
@@ -242,10 +282,7 @@ pub fn prepare_select_plan<'a>(
                                     }
                                 }
                             }
-                            ast::Expr::FunctionCallStar {
-                                name,
-                                filter_over: _,
-                            } => {
+                            ast::Expr::FunctionCallStar { name, filter_over } => {
                                 if let Ok(Func::Agg(f)) = Func::resolve_function(
                                     normalize_ident(name.0.as_str()).as_str(),
                                     0,
@@ -255,6 +292,7 @@ pub fn prepare_select_plan<'a>(
                                         args: vec![ast::Expr::Literal(ast::Literal::Numeric(
                                             "1".to_string(),
                                         ))],
+                                        filter: aggregate_filter(filter_over),
                                         original_expr: expr.clone(),
                                     };
                                     aggregate_expressions.push(agg.clone());
@@ -291,6 +329,17 @@ pub fn prepare_select_plan<'a>(
             }
 
             // Parse the actual WHERE clause and add its conditions to the plan WHERE clause that already contains the join conditions.
+            let mut where_clause = where_clause;
+            if let Some(where_expr) = where_clause.as_mut() {
+                plan_subqueries_in_expr(
+                    where_expr,
+                    schema,
+                    syms,
+                    &subquery_scope,
+                    Some(&plan.result_columns),
+                    &mut subqueries,
+                )?;
+            }
             parse_where(
                 where_clause,
                 &plan.table_references,
@@ -301,6 +350,14 @@ pub fn prepare_select_plan<'a>(
             if let Some(mut group_by) = group_by {
                 for expr in group_by.exprs.iter_mut() {
                     replace_column_number_with_copy_of_column_expr(expr, &plan.result_columns)?;
+                    plan_subqueries_in_expr(
+                        expr,
+                        schema,
+                        syms,
+                        &subquery_scope,
+                        Some(&plan.result_columns),
+                        &mut subqueries,
+                    )?;
                     bind_column_references(
                         expr,
                         &plan.table_references,
@@ -314,6 +371,14 @@ pub fn prepare_select_plan<'a>(
                         let mut predicates = vec![];
                         break_predicate_at_and_boundaries(*having, &mut predicates);
                         for expr in predicates.iter_mut() {
+                            plan_subqueries_in_expr(
+                                expr,
+                                schema,
+                                syms,
+                                &subquery_scope,
+                                Some(&plan.result_columns),
+                                &mut subqueries,
+                            )?;
                             bind_column_references(
                                 expr,
                                 &plan.table_references,
@@ -349,12 +414,27 @@ pub fn prepare_select_plan<'a>(
                         &mut o.expr,
                         &plan.result_columns,
                     )?;
+                    plan_subqueries_in_expr(
+                        &mut o.expr,
+                        schema,
+                        syms,
+                        &subquery_scope,
+                        Some(&plan.result_columns),
+                        &mut subqueries,
+                    )?;
 
                     bind_column_references(
                         &mut o.expr,
                         &plan.table_references,
                         Some(&plan.result_columns),
                     )?;
+                    resolve_window_functions(
+                        &o.expr,
+                        &plan.table_references,
+                        Some(&plan.result_columns),
+                        &window_defs,
+                        &mut plan.windows,
+                    )?;
                     resolve_aggregates(&o.expr, &mut plan.aggregates);
 
                     key.push((
@@ -368,6 +448,15 @@ pub fn prepare_select_plan<'a>(
                 plan.order_by = Some(key);
             }
 
+            plan.subqueries = subqueries;
+
+            if !plan.windows.is_empty() && (plan.group_by.is_some() || !plan.aggregates.is_empty())
+            {
+                crate::bail_parse_error!(
+                    "window functions are not supported together with GROUP BY or aggregate functions"
+                );
+            }
+
             // Parse the LIMIT/OFFSET clause
             (plan.limit, plan.offset) =
                 select.limit.map_or(Ok((None, None)), |l| parse_limit(*l))?;
@@ -410,17 +499,31 @@ fn count_plan_required_cursors(plan: &SelectPlan) -> usize {
         .iter()
         .map(|t| match &t.op {
             Operation::Scan { .. } => 1,
-            Operation::Search(search) => match search {
-                Search::RowidEq { .. } | Search::RowidSearch { .. } => 1,
-                Search::IndexSearch { .. } => 2, // btree cursor and index cursor
+            Operation::Search(search) => match search.index() {
+                None => 1,
+                Some(_) => 2, // btree cursor and index cursor
             },
             Operation::Subquery { plan, .. } => count_plan_required_cursors(plan),
+            Operation::RecursiveCte {
+                initial, recursive, ..
+            } => 1 + count_plan_required_cursors(initial) + count_plan_required_cursors(recursive),
+            Operation::CteRow { .. } => 0,
+            // The cursor of the reference, plus the one of the ephemeral table if this reference fills it
+            Operation::MaterializedCte { plan, .. } => 2 + count_plan_required_cursors(plan),
         })
         .sum();
-    let num_sorter_cursors = plan.group_by.is_some() as usize + plan.order_by.is_some() as usize;
-    let num_pseudo_cursors = plan.group_by.is_some() as usize + plan.order_by.is_some() as usize;
+    let num_subquery_cursors: usize = plan
+        .subqueries
+        .iter()
+        .map(|s| count_plan_required_cursors(&s.plan))
+        .sum();
+    let num_sorter_cursors =
+        plan.group_by.is_some() as usize + plan.order_by.is_some() as usize + plan.windows.len();
+    let num_pseudo_cursors = plan.group_by.is_some() as usize
+        + plan.order_by.is_some() as usize
+        + 2 * plan.windows.len();
 
-    num_table_cursors + num_sorter_cursors + num_pseudo_cursors
+    num_table_cursors + num_subquery_cursors + num_sorter_cursors + num_pseudo_cursors
 }
 
 fn estimate_num_instructions(select: &SelectPlan) -> usize {
@@ -431,17 +534,35 @@ fn estimate_num_instructions(select: &SelectPlan) -> usize {
             Operation::Scan { .. } => 10,
             Operation::Search(_) => 15,
             Operation::Subquery { plan, .. } => 10 + estimate_num_instructions(plan),
+            Operation::RecursiveCte {
+                initial, recursive, ..
+            } => 20 + estimate_num_instructions(initial) + estimate_num_instructions(recursive),
+            Operation::CteRow { .. } => 2,
+            Operation::MaterializedCte { plan, .. } => 20 + estimate_num_instructions(plan),
         })
         .sum();
 
+    let subquery_instructions: usize = select
+        .subqueries
+        .iter()
+        .map(|s| 10 + estimate_num_instructions(&s.plan))
+        .sum();
+
     let group_by_instructions = select.group_by.is_some() as usize * 10;
     let order_by_instructions = select.order_by.is_some() as usize * 10;
+    let window_instructions = select
+        .windows
+        .iter()
+        .map(|w| 40 + w.functions.len() * 20)
+        .sum::<usize>();
     let condition_instructions = select.where_clause.len() * 3;
 
     let num_instructions = 20
         + table_instructions
+        + subquery_instructions
         + group_by_instructions
         + order_by_instructions
+        + window_instructions
         + condition_instructions;
 
     num_instructions
@@ -457,16 +578,37 @@ fn estimate_num_labels(select: &SelectPlan) -> usize {
             Operation::Scan { .. } => 3,
             Operation::Search(_) => 3,
             Operation::Subquery { plan, .. } => 3 + estimate_num_labels(plan),
+            Operation::RecursiveCte {
+                initial, recursive, ..
+            } => 7 + estimate_num_labels(initial) + estimate_num_labels(recursive),
+            Operation::CteRow { .. } => 3,
+            Operation::MaterializedCte { plan, .. } => 6 + estimate_num_labels(plan),
         })
         .sum::<usize>()
         + 1;
 
+    let subquery_labels: usize = select
+        .subqueries
+        .iter()
+        .map(|s| 3 + estimate_num_labels(&s.plan))
+        .sum();
+
     let group_by_labels = select.group_by.is_some() as usize * 10;
     let order_by_labels = select.order_by.is_some() as usize * 10;
+    let window_labels = select
+        .windows
+        .iter()
+        .map(|w| 10 + w.functions.len() * 8)
+        .sum::<usize>();
     let condition_labels = select.where_clause.len() * 2;
 
-    let num_labels =
-        init_halt_labels + table_labels + group_by_labels + order_by_labels + condition_labels;
+    let num_labels = init_halt_labels
+        + table_labels
+        + subquery_labels
+        + group_by_labels
+        + order_by_labels
+        + window_labels
+        + condition_labels;
 
     num_labels
 }
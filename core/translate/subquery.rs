@@ -1,12 +1,19 @@
 use crate::{
-    vdbe::{builder::ProgramBuilder, insn::Insn},
-    Result,
+    vdbe::{
+        builder::{CursorType, ProgramBuilder},
+        insn::Insn,
+        BranchOffset, CursorID,
+    },
+    Result, SymbolTable,
 };
 
 use super::{
-    emitter::{emit_query, Resolver, TranslateCtx},
+    emitter::{emit_query, Resolver, SubqueryCoroutine, TranslateCtx},
     main_loop::LoopLabels,
-    plan::{Operation, SelectPlan, SelectQueryType, TableReference},
+    plan::{
+        CteMaterialization, ExprSubquery, Operation, SelectPlan, SelectQueryType, SubqueryType,
+        TableReference,
+    },
 };
 
 /// Emit the subqueries contained in the FROM clause.
@@ -17,22 +24,186 @@ pub fn emit_subqueries(
     tables: &mut [TableReference],
 ) -> Result<()> {
     for table in tables.iter_mut() {
-        if let Operation::Subquery {
-            plan,
-            result_columns_start_reg,
-        } = &mut table.op
-        {
-            // Emit the subquery and get the start register of the result columns.
-            let result_columns_start = emit_subquery(program, plan, t_ctx)?;
-            // Set the start register of the subquery's result columns.
-            // This is done so that translate_expr() can read the result columns of the subquery,
-            // as if it were reading from a regular table.
-            *result_columns_start_reg = result_columns_start;
+        let num_columns = table.columns().len();
+        match &mut table.op {
+            Operation::Subquery {
+                plan,
+                result_columns_start_reg,
+            } => {
+                // Emit the subquery and get the start register of the result columns.
+                let result_columns_start =
+                    emit_subquery(program, plan, t_ctx.resolver.symbol_table, None)?;
+                // Set the start register of the subquery's result columns.
+                // This is done so that translate_expr() can read the result columns of the subquery,
+                // as if it were reading from a regular table.
+                *result_columns_start_reg = result_columns_start;
+            }
+            Operation::RecursiveCte {
+                initial,
+                recursive,
+                result_columns_start_reg,
+                initial_result_columns_start_reg,
+                recursive_result_columns_start_reg,
+                ..
+            } => {
+                *initial_result_columns_start_reg =
+                    emit_subquery(program, initial, t_ctx.resolver.symbol_table, None)?;
+                // The row taken from the queue is read into these registers, where the recursive SELECT
+                // reads it from when it refers to the CTE.
+                *result_columns_start_reg = program.alloc_registers(num_columns);
+                for table in recursive.table_references.iter_mut() {
+                    if let Operation::CteRow {
+                        result_columns_start_reg: cte_row_start_reg,
+                    } = &mut table.op
+                    {
+                        *cte_row_start_reg = *result_columns_start_reg;
+                    }
+                }
+                *recursive_result_columns_start_reg =
+                    emit_subquery(program, recursive, t_ctx.resolver.symbol_table, None)?;
+            }
+            Operation::MaterializedCte {
+                plan,
+                materialization,
+            } => {
+                // The CTE's coroutine is emitted only once, by the first reference to it.
+                let m = match materialization.get() {
+                    Some(m) => m,
+                    None => {
+                        let result_columns_start_reg =
+                            emit_subquery(program, plan, t_ctx.resolver.symbol_table, None)?;
+                        let SelectQueryType::Subquery {
+                            yield_reg,
+                            coroutine_implementation_start,
+                        } = plan.query_type
+                        else {
+                            unreachable!("MATERIALIZED CTE must be a coroutine");
+                        };
+                        let m = CteMaterialization {
+                            cursor_id: program.alloc_cursor_id(None, CursorType::Ephemeral),
+                            reg_materialized: program.alloc_register(),
+                            yield_reg,
+                            coroutine_implementation_start,
+                            result_columns_start_reg,
+                        };
+                        materialization.set(Some(m));
+                        m
+                    }
+                };
+                // Every reference checks whether the ephemeral table has been filled yet,
+                // since it is not known which of them is reached first.
+                let label_materialized = program.allocate_label();
+                program.emit_insn(Insn::If {
+                    reg: m.reg_materialized,
+                    target_pc: label_materialized,
+                    jump_if_null: false,
+                });
+                program.emit_insn(Insn::OpenEphemeral {
+                    cursor_id: m.cursor_id,
+                    distinct: false,
+                    collations: vec![],
+                });
+                emit_coroutine_into_ephemeral(
+                    program,
+                    m.yield_reg,
+                    m.coroutine_implementation_start,
+                    m.result_columns_start_reg,
+                    num_columns,
+                    m.cursor_id,
+                );
+                program.emit_insn(Insn::Integer {
+                    value: 1,
+                    dest: m.reg_materialized,
+                });
+                program.resolve_label(label_materialized, program.offset());
+            }
+            _ => {}
         }
     }
     Ok(())
 }
 
+/// Runs a coroutine until it is done, inserting each of the rows it yields into the ephemeral table open in `cursor_id`.
+pub fn emit_coroutine_into_ephemeral(
+    program: &mut ProgramBuilder,
+    yield_reg: usize,
+    coroutine_implementation_start: BranchOffset,
+    result_columns_start_reg: usize,
+    num_columns: usize,
+    cursor_id: CursorID,
+) {
+    let label_coroutine_done = program.allocate_label();
+    let record_reg = program.alloc_register();
+    program.emit_insn(Insn::InitCoroutine {
+        yield_reg,
+        jump_on_definition: BranchOffset::Offset(0),
+        start_offset: coroutine_implementation_start,
+    });
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Yield {
+        yield_reg,
+        end_offset: label_coroutine_done,
+    });
+    program.emit_insn(Insn::MakeRecord {
+        start_reg: result_columns_start_reg,
+        count: num_columns,
+        dest_reg: record_reg,
+    });
+    program.emit_insn(Insn::EphemeralInsert {
+        cursor_id,
+        record_reg,
+    });
+    program.emit_insn(Insn::Goto {
+        target_pc: loop_start,
+    });
+    program.resolve_label(label_coroutine_done, program.offset());
+}
+
+/// Emit the subqueries used in expressions, i.e. scalar subqueries, EXISTS and IN (SELECT ...).
+/// Like the subqueries in the FROM clause, they are emitted as coroutines before the main query loop.
+/// translate_expr() then runs the coroutine of a subquery wherever the expression is evaluated.
+/// A correlated subquery reads the values it takes from the enclosing query from registers,
+/// which translate_expr() fills in before running it. The result of a scalar or EXISTS subquery
+/// that is not correlated is kept after it first runs, so that, like in SQLite, an UPDATE or DELETE
+/// sees the value it had before any row was changed.
+pub fn emit_expr_subqueries(
+    program: &mut ProgramBuilder,
+    resolver: &mut Resolver,
+    subqueries: &mut [ExprSubquery],
+) -> Result<()> {
+    for subquery in subqueries.iter_mut() {
+        let outer_refs_start_reg = if subquery.num_outer_refs > 0 {
+            Some(program.alloc_registers(subquery.num_outer_refs))
+        } else {
+            None
+        };
+        let result_columns_start_reg = emit_subquery(
+            program,
+            &mut subquery.plan,
+            resolver.symbol_table,
+            outer_refs_start_reg,
+        )?;
+        let SelectQueryType::Subquery {
+            yield_reg,
+            coroutine_implementation_start,
+        } = subquery.plan.query_type
+        else {
+            unreachable!("expression subquery must be a coroutine");
+        };
+        let reg_once = (subquery.num_outer_refs == 0 && subquery.subquery_type != SubqueryType::In)
+            .then(|| program.alloc_registers(2));
+        resolver.subqueries.push(SubqueryCoroutine {
+            subquery_type: subquery.subquery_type,
+            yield_reg,
+            coroutine_implementation_start,
+            result_columns_start_reg,
+            outer_refs_start_reg,
+            reg_once,
+        });
+    }
+    Ok(())
+}
+
 /// Emit a subquery and return the start register of the result columns.
 /// This is done by emitting a coroutine that stores the result columns in sequential registers.
 /// Each subquery in a FROM clause has its own separate SelectPlan which is wrapped in a coroutine.
@@ -46,10 +217,12 @@ pub fn emit_subqueries(
 ///
 /// Since a subquery has its own SelectPlan, it can contain nested subqueries,
 /// which can contain even more nested subqueries, etc.
-pub fn emit_subquery<'a>(
+/// `outer_refs_start_reg` is where a correlated subquery reads its values of the enclosing query from.
+pub fn emit_subquery(
     program: &mut ProgramBuilder,
     plan: &mut SelectPlan,
-    t_ctx: &mut TranslateCtx<'a>,
+    syms: &SymbolTable,
+    outer_refs_start_reg: Option<usize>,
 ) -> Result<usize> {
     let yield_reg = program.alloc_register();
     let coroutine_implementation_start_offset = program.offset().add(1u32);
@@ -73,7 +246,10 @@ pub fn emit_subquery<'a>(
         label_main_loop_end: None,
         meta_group_by: None,
         meta_left_joins: (0..plan.table_references.len()).map(|_| None).collect(),
+        meta_right_joins: (0..plan.table_references.len()).map(|_| None).collect(),
         meta_sort: None,
+        meta_window: vec![],
+        distinct_cursor: None,
         reg_agg_start: None,
         reg_result_cols_start: None,
         result_column_indexes_in_orderby_sorter: (0..plan.result_columns.len()).collect(),
@@ -81,7 +257,10 @@ pub fn emit_subquery<'a>(
         reg_limit: plan.limit.map(|_| program.alloc_register()),
         reg_offset: plan.offset.map(|_| program.alloc_register()),
         reg_limit_offset_sum: plan.offset.map(|_| program.alloc_register()),
-        resolver: Resolver::new(t_ctx.resolver.symbol_table),
+        resolver: Resolver {
+            outer_refs_start_reg,
+            ..Resolver::new(syms)
+        },
     };
     let subquery_body_end_label = program.allocate_label();
     program.emit_insn(Insn::InitCoroutine {
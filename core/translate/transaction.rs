@@ -1,5 +1,6 @@
 use crate::translate::{ProgramBuilder, ProgramBuilderOpts};
-use crate::vdbe::insn::Insn;
+use crate::util::normalize_ident;
+use crate::vdbe::insn::{Insn, SavepointOp};
 use crate::{bail_parse_error, QueryMode, Result};
 use limbo_sqlite3_parser::ast::{Name, TransactionType};
 
@@ -53,3 +54,70 @@ pub fn translate_tx_commit(_tx_name: Option<Name>) -> Result<ProgramBuilder> {
     program.emit_goto(start_offset);
     Ok(program)
 }
+
+pub fn translate_tx_rollback(_tx_name: Option<Name>) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 0,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    program.emit_insn(Insn::AutoCommit {
+        auto_commit: true,
+        rollback: true,
+    });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
+
+pub fn translate_savepoint(name: Name) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 0,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    // Outside of a transaction, a savepoint starts one like BEGIN IMMEDIATE does.
+    program.emit_insn(Insn::Transaction { write: true });
+    program.emit_insn(Insn::Savepoint {
+        op: SavepointOp::Begin,
+        name: normalize_ident(&name.0),
+    });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
+
+pub fn translate_release(name: Name) -> Result<ProgramBuilder> {
+    translate_savepoint_op(SavepointOp::Release, name)
+}
+
+pub fn translate_rollback_to(name: Name) -> Result<ProgramBuilder> {
+    translate_savepoint_op(SavepointOp::Rollback, name)
+}
+
+fn translate_savepoint_op(op: SavepointOp, name: Name) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 0,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    program.emit_insn(Insn::Savepoint {
+        op,
+        name: normalize_ident(&name.0),
+    });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
@@ -0,0 +1,1050 @@
+//! Triggers.
+//!
+//! The commands of a trigger are compiled into sub-programs of the program of the statement that fires
+//! the trigger, one per command. For each row that is inserted, deleted or updated, the statement puts
+//! the OLD and NEW rows in a block of registers and runs the sub-programs with the Program instruction.
+//! The layout of the block is the rowid and the columns of the OLD row, followed by the rowid and the
+//! columns of the NEW row. Inside a trigger program, the references to the OLD and NEW rows are
+//! Expr::Param expressions, which read the block with the Param instruction.
+
+use std::fmt::Display;
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast::{self, fmt::ToTokens};
+
+use crate::schema::{BTreeTable, Schema, Trigger, View, MAIN_DB, TEMP_DB};
+use crate::translate::delete::prepare_delete_plan;
+use crate::translate::emitter::{emit_program, epilogue, prologue, Resolver, TransactionMode};
+use crate::translate::expr::{translate_condition_expr, translate_expr, ConditionMetadata};
+use crate::translate::insert::emit_insert;
+use crate::translate::optimizer::optimize_plan;
+use crate::translate::plan::{Plan, SelectQueryType};
+use crate::translate::select::prepare_select_plan;
+use crate::translate::subquery::emit_subquery;
+use crate::translate::update::{is_rowid_name, prepare_update_plan};
+use crate::util::normalize_ident;
+use crate::vdbe::builder::{CursorType, ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::vdbe::insn::Insn;
+use crate::vdbe::{BranchOffset, CursorID};
+use crate::{bail_parse_error, Result, SymbolTable};
+
+use super::view::{check_main_database, emit_noop};
+use super::{emit_schema_entry, emit_schema_entry_delete, SchemaEntryType, SQLITE_TABLEID};
+
+pub(crate) struct StmtFormatter<'a> {
+    pub(crate) stmt: &'a ast::Stmt,
+}
+impl Display for StmtFormatter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.stmt.to_fmt(f)
+    }
+}
+
+pub fn translate_create_trigger(
+    query_mode: QueryMode,
+    schema: &Schema,
+    mut create_trigger: ast::CreateTrigger,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 1,
+    });
+    if create_trigger.temporary {
+        bail_parse_error!("TEMPORARY triggers are not supported yet");
+    }
+    check_main_database(schema, &create_trigger.trigger_name, "trigger")?;
+    let name = normalize_ident(&create_trigger.trigger_name.name.0);
+    if schema.get_trigger(&name).is_some() {
+        if create_trigger.if_not_exists {
+            emit_noop(&mut program);
+            return Ok(program);
+        }
+        bail_parse_error!(
+            "trigger {} already exists",
+            create_trigger.trigger_name.name.0
+        );
+    }
+    let tbl_name = &create_trigger.tbl_name.name.0;
+    let table_name = normalize_ident(tbl_name);
+    if table_name.starts_with("sqlite_") {
+        bail_parse_error!("cannot create trigger on system table");
+    }
+    let time = create_trigger.time.unwrap_or(ast::TriggerTime::Before);
+    let table = schema.resolve_table(&create_trigger.tbl_name)?;
+    match (table, schema.get_view(&table_name)) {
+        (Some(table), _) => {
+            if table.virtual_table().is_some() {
+                bail_parse_error!("cannot create triggers on virtual tables");
+            }
+            match table.btree().map_or(MAIN_DB, |table| table.db) {
+                MAIN_DB => {}
+                TEMP_DB => bail_parse_error!("triggers on temp tables are not supported yet"),
+                db => bail_parse_error!(
+                    "trigger {} cannot reference objects in database {}",
+                    create_trigger.trigger_name.name.0,
+                    schema.database_name(db)
+                ),
+            }
+            if time == ast::TriggerTime::InsteadOf {
+                bail_parse_error!("cannot create INSTEAD OF trigger on table: {}", tbl_name);
+            }
+        }
+        (None, Some(_)) => {
+            if time != ast::TriggerTime::InsteadOf {
+                let time = match time {
+                    ast::TriggerTime::Before => "BEFORE",
+                    _ => "AFTER",
+                };
+                bail_parse_error!("cannot create {} trigger on view: {}", time, tbl_name);
+            }
+        }
+        (None, None) => bail_parse_error!("no such table: main.{}", tbl_name),
+    }
+
+    create_trigger.if_not_exists = false;
+    let stmt = ast::Stmt::CreateTrigger(Box::new(create_trigger));
+    let sql = StmtFormatter { stmt: &stmt }.to_string();
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let sqlite_schema = schema.get_btree_table(SQLITE_TABLEID).unwrap();
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(SQLITE_TABLEID.to_owned()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Triggers have no b-tree, so their root page is 0.
+    emit_schema_entry(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::Trigger,
+        &name,
+        &table_name,
+        0,
+        Some(sql),
+    );
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::ParseSchema {
+        db: MAIN_DB,
+        where_clause: format!("type = 'trigger' AND name = '{}'", name),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
+
+pub fn translate_drop_trigger(
+    query_mode: QueryMode,
+    schema: &Schema,
+    if_exists: bool,
+    trigger_name: &ast::QualifiedName,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    check_main_database(schema, trigger_name, "trigger")?;
+    let Some(trigger) = schema.get_trigger(&trigger_name.name.0) else {
+        if if_exists {
+            emit_noop(&mut program);
+            return Ok(program);
+        }
+        bail_parse_error!("no such trigger: {}", trigger_name.name.0);
+    };
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let sqlite_schema = schema.get_btree_table(SQLITE_TABLEID).unwrap();
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(SQLITE_TABLEID.to_owned()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Remove the entry of the trigger from sqlite_schema
+    emit_schema_entry_delete(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::Trigger,
+        &trigger.name,
+    );
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::DropTrigger {
+        db: 0,
+        trigger_name: trigger.name.clone(),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
+
+/// The names of the columns of a table, as seen by the NEW and OLD references of its triggers.
+pub fn trigger_column_names(table: &BTreeTable) -> Vec<String> {
+    table
+        .columns
+        .iter()
+        .map(|column| {
+            column
+                .name
+                .as_deref()
+                .map(normalize_ident)
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Allocates the registers for the OLD and NEW rows passed to trigger programs. Returns the first one.
+pub fn alloc_trigger_params(program: &mut ProgramBuilder, num_columns: usize) -> usize {
+    program.alloc_registers(2 * (num_columns + 1))
+}
+
+/// The first register of the NEW row in the registers allocated by alloc_trigger_params().
+pub fn new_row_params(params_start_reg: usize, num_columns: usize) -> usize {
+    params_start_reg + num_columns + 1
+}
+
+/// Sets the OLD or NEW row of the trigger parameters starting at `dest` to NULL, for the triggers of
+/// an INSERT, which has no OLD row, and of a DELETE, which has no NEW row.
+pub fn emit_null_row_params(program: &mut ProgramBuilder, num_columns: usize, dest: usize) {
+    program.emit_insn(Insn::Null {
+        dest,
+        dest_end: Some(dest + num_columns),
+    });
+}
+
+/// Copies a row of the table into the trigger parameters starting at `dest`.
+/// A rowid alias column is stored as NULL, so it gets the value of the rowid.
+pub fn emit_row_params(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    rowid_reg: usize,
+    columns_start_reg: usize,
+    dest: usize,
+) {
+    program.emit_insn(Insn::Copy {
+        src_reg: rowid_reg,
+        dst_reg: dest,
+        amount: 0,
+    });
+    program.emit_insn(Insn::Copy {
+        src_reg: columns_start_reg,
+        dst_reg: dest + 1,
+        amount: table.columns.len() - 1,
+    });
+    if let Some(rowid_alias_index) = table.columns.iter().position(|c| c.is_rowid_alias) {
+        program.emit_insn(Insn::Copy {
+            src_reg: rowid_reg,
+            dst_reg: dest + 1 + rowid_alias_index,
+            amount: 0,
+        });
+    }
+}
+
+/// Reads the row the table cursor points to into the trigger parameters starting at `dest`.
+/// The rowid of a row of a WITHOUT ROWID table is NULL.
+pub fn emit_cursor_row_params(
+    program: &mut ProgramBuilder,
+    table: &BTreeTable,
+    cursor_id: CursorID,
+    dest: usize,
+) {
+    if table.has_rowid {
+        program.emit_insn(Insn::RowId { cursor_id, dest });
+    } else {
+        program.emit_insn(Insn::Null {
+            dest,
+            dest_end: None,
+        });
+    }
+    for (i, column) in table.columns.iter().enumerate() {
+        if column.is_rowid_alias {
+            program.emit_insn(Insn::Copy {
+                src_reg: dest,
+                dst_reg: dest + 1 + i,
+                amount: 0,
+            });
+        } else {
+            program.emit_insn(Insn::Column {
+                cursor_id,
+                column: table.column_record_position(i),
+                dest: dest + 1 + i,
+            });
+        }
+    }
+}
+
+/// Emits the code that fires the triggers for one row, whose OLD and NEW values are in the registers
+/// starting at `params_start_reg`. `columns` are the column names of the table or view of the triggers.
+/// The WHEN clause of each trigger is evaluated here, and its commands are run as sub-programs.
+/// A trigger program that is halted by RAISE(IGNORE) jumps to `ignore_label`.
+#[allow(clippy::too_many_arguments)]
+pub fn emit_fire_triggers(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    triggers: &[Rc<Trigger>],
+    time: ast::TriggerTime,
+    columns: &[String],
+    params_start_reg: usize,
+    ignore_label: BranchOffset,
+) -> Result<()> {
+    for trigger in triggers.iter().filter(|trigger| trigger.time == time) {
+        // Triggers are not recursive: a trigger does not fire for the changes made by its own program.
+        if program.active_triggers.contains(&trigger.name) {
+            continue;
+        }
+        let resolve = |row: &ast::Name, column: &ast::Name| {
+            resolve_trigger_param(trigger, columns, row, column)
+        };
+        let params = RowRefs::new(&resolve);
+        let skip_trigger_label = program.allocate_label();
+        if let Some(when_clause) = &trigger.when_clause {
+            let mut when_clause = when_clause.clone();
+            params.bind_expr(&mut when_clause)?;
+            let resolver = Resolver {
+                params_start_reg: Some(params_start_reg),
+                ..Resolver::new(syms)
+            };
+            let jump_target_when_true = program.allocate_label();
+            translate_condition_expr(
+                program,
+                &[],
+                &when_clause,
+                ConditionMetadata {
+                    jump_if_condition_is_true: false,
+                    jump_target_when_true,
+                    jump_target_when_false: skip_trigger_label,
+                },
+                &resolver,
+            )?;
+            program.resolve_label(jump_target_when_true, program.offset());
+        }
+        for command in &trigger.commands {
+            let sub_program =
+                compile_trigger_command(program, schema, syms, trigger, &params, command)?;
+            let sub_program = program.add_sub_program(sub_program);
+            program.emit_insn(Insn::Program {
+                params_start_reg,
+                num_params: 2 * (columns.len() + 1),
+                sub_program: Some(sub_program),
+                ignore_jump: ignore_label,
+            });
+        }
+        program.resolve_label(skip_trigger_label, program.offset());
+    }
+    Ok(())
+}
+
+/// Compiles a command of a trigger into a program, which runs in the program that fires the trigger.
+fn compile_trigger_command(
+    program: &ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    trigger: &Trigger,
+    params: &RowRefs,
+    command: &ast::TriggerCmd,
+) -> Result<ProgramBuilder> {
+    let mut sub_program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    sub_program.active_triggers = program.active_triggers.clone();
+    sub_program.active_triggers.push(trigger.name.clone());
+    sub_program.foreign_keys = program.foreign_keys;
+    sub_program.active_fk_actions = program.active_fk_actions.clone();
+
+    let mut command = command.clone();
+    params.bind_command(&mut command)?;
+    match command {
+        ast::TriggerCmd::Insert(insert) => {
+            let ast::TriggerCmdInsert {
+                or_conflict,
+                tbl_name,
+                col_names,
+                select,
+                upsert,
+                returning,
+            } = *insert;
+            if returning.is_some() {
+                crate::bail_parse_error!("cannot use RETURNING in a trigger");
+            }
+            emit_insert(
+                &mut sub_program,
+                schema,
+                &None,
+                &or_conflict,
+                &ast::QualifiedName::single(tbl_name),
+                &col_names,
+                &ast::InsertBody::Select(select, upsert),
+                &returning,
+                syms,
+            )?;
+        }
+        ast::TriggerCmd::Delete(delete) => {
+            let ast::TriggerCmdDelete {
+                tbl_name,
+                where_clause,
+            } = *delete;
+            let where_clause = where_clause.map(Box::new);
+            match schema.get_view(&tbl_name.0) {
+                Some(view) => {
+                    emit_view_delete(&mut sub_program, schema, syms, &view, where_clause)?
+                }
+                None => {
+                    let tbl_name = ast::QualifiedName::single(tbl_name);
+                    let mut plan =
+                        prepare_delete_plan(schema, &tbl_name, where_clause, None, None, syms)?;
+                    optimize_plan(&mut plan, schema)?;
+                    emit_program(&mut sub_program, plan, schema, syms)?;
+                }
+            }
+        }
+        ast::TriggerCmd::Update(update) => {
+            let ast::TriggerCmdUpdate {
+                or_conflict,
+                tbl_name,
+                sets,
+                from,
+                where_clause,
+            } = *update;
+            let where_clause = where_clause.map(Box::new);
+            match schema.get_view(&tbl_name.0) {
+                Some(view) => {
+                    emit_view_update(&mut sub_program, schema, syms, &view, sets, where_clause)?
+                }
+                None => {
+                    let update = ast::Update {
+                        with: None,
+                        or_conflict,
+                        tbl_name: ast::QualifiedName::single(tbl_name),
+                        indexed: None,
+                        sets,
+                        from,
+                        where_clause,
+                        returning: None,
+                        order_by: None,
+                        limit: None,
+                    };
+                    let mut plan = prepare_update_plan(schema, update, syms)?;
+                    optimize_plan(&mut plan, schema)?;
+                    emit_program(&mut sub_program, plan, schema, syms)?;
+                }
+            }
+        }
+        ast::TriggerCmd::Select(select) => {
+            let mut plan = prepare_select_plan(schema, *select, syms, None)?;
+            optimize_plan(&mut plan, schema)?;
+            emit_program(&mut sub_program, plan, schema, syms)?;
+        }
+    }
+    Ok(sub_program)
+}
+
+/// The offset of `row.column` in the parameters of the programs of `trigger`, if `row` is OLD or NEW.
+fn resolve_trigger_param(
+    trigger: &Trigger,
+    columns: &[String],
+    row: &ast::Name,
+    column: &ast::Name,
+) -> Result<Option<ast::Expr>> {
+    let row_start = match normalize_ident(&row.0).as_str() {
+        "old" if trigger.event != ast::TriggerEvent::Insert => 0,
+        "new" if trigger.event != ast::TriggerEvent::Delete => columns.len() + 1,
+        "old" | "new" => bail_parse_error!("no such column: {}.{}", row.0, column.0),
+        _ => return Ok(None),
+    };
+    let column_name = normalize_ident(&column.0);
+    match columns.iter().position(|c| *c == column_name) {
+        Some(i) => Ok(Some(ast::Expr::Param(row_start + 1 + i))),
+        None if is_rowid_name(&column_name) => Ok(Some(ast::Expr::Param(row_start))),
+        None => bail_parse_error!("no such column: {}.{}", row.0, column.0),
+    }
+}
+
+/// Rewrites the references `row.column` to a row that is not a table of the statement, like the OLD
+/// and NEW rows of a trigger or the `excluded` row of an upsert, into the expressions returned by
+/// `resolve`, including in subqueries. The references `resolve` returns None for are left alone.
+pub(crate) struct RowRefs<'a> {
+    resolve: &'a dyn Fn(&ast::Name, &ast::Name) -> Result<Option<ast::Expr>>,
+}
+
+impl<'a> RowRefs<'a> {
+    pub(crate) fn new(
+        resolve: &'a dyn Fn(&ast::Name, &ast::Name) -> Result<Option<ast::Expr>>,
+    ) -> Self {
+        Self { resolve }
+    }
+
+    fn bind_command(&self, command: &mut ast::TriggerCmd) -> Result<()> {
+        match command {
+            ast::TriggerCmd::Insert(insert) => {
+                self.bind_select(&mut insert.select)?;
+                self.bind_upsert(insert.upsert.as_mut())
+            }
+            ast::TriggerCmd::Delete(delete) => self.bind_opt_expr(delete.where_clause.as_mut()),
+            ast::TriggerCmd::Update(update) => {
+                for set in update.sets.iter_mut() {
+                    self.bind_expr(&mut set.expr)?;
+                }
+                if let Some(from) = update.from.as_mut() {
+                    self.bind_from(from)?;
+                }
+                self.bind_opt_expr(update.where_clause.as_mut())
+            }
+            ast::TriggerCmd::Select(select) => self.bind_select(select),
+        }
+    }
+
+    pub(crate) fn bind_upsert(&self, mut upsert: Option<&mut ast::Upsert>) -> Result<()> {
+        while let Some(clause) = upsert {
+            if let Some(index) = clause.index.as_deref_mut() {
+                self.bind_opt_expr(index.where_clause.as_mut())?;
+            }
+            if let ast::UpsertDo::Set { sets, where_clause } = clause.do_clause.as_mut() {
+                self.bind_exprs(sets.iter_mut().map(|set| &mut set.expr))?;
+                self.bind_opt_expr(where_clause.as_mut())?;
+            }
+            upsert = clause.next.as_deref_mut();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bind_opt_expr(&self, expr: Option<&mut ast::Expr>) -> Result<()> {
+        match expr {
+            Some(expr) => self.bind_expr(expr),
+            None => Ok(()),
+        }
+    }
+
+    fn bind_exprs<'e>(&self, exprs: impl IntoIterator<Item = &'e mut ast::Expr>) -> Result<()> {
+        for expr in exprs {
+            self.bind_expr(expr)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bind_expr(&self, expr: &mut ast::Expr) -> Result<()> {
+        match expr {
+            ast::Expr::Qualified(row, column) => {
+                if let Some(resolved) = (self.resolve)(row, column)? {
+                    *expr = resolved;
+                }
+                Ok(())
+            }
+            ast::Expr::Between {
+                lhs, start, end, ..
+            } => {
+                self.bind_expr(lhs)?;
+                self.bind_expr(start)?;
+                self.bind_expr(end)
+            }
+            ast::Expr::Binary(lhs, _, rhs) => {
+                self.bind_expr(lhs)?;
+                self.bind_expr(rhs)
+            }
+            ast::Expr::Case {
+                base,
+                when_then_pairs,
+                else_expr,
+            } => {
+                self.bind_opt_expr(base.as_deref_mut())?;
+                for (when, then) in when_then_pairs.iter_mut() {
+                    self.bind_expr(when)?;
+                    self.bind_expr(then)?;
+                }
+                self.bind_opt_expr(else_expr.as_deref_mut())
+            }
+            ast::Expr::Cast { expr, .. }
+            | ast::Expr::Collate(expr, _)
+            | ast::Expr::IsNull(expr)
+            | ast::Expr::NotNull(expr)
+            | ast::Expr::Unary(_, expr) => self.bind_expr(expr),
+            ast::Expr::Exists(select) | ast::Expr::Subquery(select) => self.bind_select(select),
+            ast::Expr::FunctionCall {
+                args,
+                order_by,
+                filter_over,
+                ..
+            } => {
+                self.bind_exprs(args.iter_mut().flatten())?;
+                self.bind_exprs(order_by.iter_mut().flatten().map(|o| &mut o.expr))?;
+                self.bind_function_tail(filter_over.as_mut())
+            }
+            ast::Expr::FunctionCallStar { filter_over, .. } => {
+                self.bind_function_tail(filter_over.as_mut())
+            }
+            ast::Expr::SubqueryResult {
+                lhs, outer_refs, ..
+            } => {
+                self.bind_opt_expr(lhs.as_deref_mut())?;
+                self.bind_exprs(outer_refs.iter_mut())
+            }
+            ast::Expr::InList { lhs, rhs, .. } => {
+                self.bind_expr(lhs)?;
+                self.bind_exprs(rhs.iter_mut().flatten())
+            }
+            ast::Expr::InSelect { lhs, rhs, .. } => {
+                self.bind_expr(lhs)?;
+                self.bind_select(rhs)
+            }
+            ast::Expr::InTable { lhs, args, .. } => {
+                self.bind_expr(lhs)?;
+                self.bind_exprs(args.iter_mut().flatten())
+            }
+            ast::Expr::Like {
+                lhs, rhs, escape, ..
+            } => {
+                self.bind_expr(lhs)?;
+                self.bind_expr(rhs)?;
+                self.bind_opt_expr(escape.as_deref_mut())
+            }
+            ast::Expr::Parenthesized(exprs) => self.bind_exprs(exprs.iter_mut()),
+            ast::Expr::Raise(_, message) => self.bind_opt_expr(message.as_deref_mut()),
+            ast::Expr::Column { .. }
+            | ast::Expr::DoublyQualified(..)
+            | ast::Expr::Id(_)
+            | ast::Expr::Literal(_)
+            | ast::Expr::Name(_)
+            | ast::Expr::OuterRef(_)
+            | ast::Expr::Param(_)
+            | ast::Expr::Register(_)
+            | ast::Expr::RowId { .. }
+            | ast::Expr::Variable(_) => Ok(()),
+        }
+    }
+
+    fn bind_function_tail(&self, tail: Option<&mut ast::FunctionTail>) -> Result<()> {
+        let Some(tail) = tail else {
+            return Ok(());
+        };
+        self.bind_opt_expr(tail.filter_clause.as_deref_mut())?;
+        if let Some(ast::Over::Window(window)) = tail.over_clause.as_deref_mut() {
+            self.bind_window(window)?;
+        }
+        Ok(())
+    }
+
+    fn bind_window(&self, window: &mut ast::Window) -> Result<()> {
+        self.bind_exprs(window.partition_by.iter_mut().flatten())?;
+        self.bind_exprs(window.order_by.iter_mut().flatten().map(|o| &mut o.expr))
+    }
+
+    fn bind_select(&self, select: &mut ast::Select) -> Result<()> {
+        if let Some(with) = select.with.as_mut() {
+            for cte in with.ctes.iter_mut() {
+                self.bind_select(&mut cte.select)?;
+            }
+        }
+        self.bind_one_select(&mut select.body.select)?;
+        for compound in select.body.compounds.iter_mut().flatten() {
+            self.bind_one_select(&mut compound.select)?;
+        }
+        self.bind_exprs(select.order_by.iter_mut().flatten().map(|o| &mut o.expr))?;
+        if let Some(limit) = select.limit.as_mut() {
+            self.bind_expr(&mut limit.expr)?;
+            self.bind_opt_expr(limit.offset.as_mut())?;
+        }
+        Ok(())
+    }
+
+    fn bind_one_select(&self, select: &mut ast::OneSelect) -> Result<()> {
+        match select {
+            ast::OneSelect::Select(select) => {
+                for column in select.columns.iter_mut() {
+                    if let ast::ResultColumn::Expr(expr, _) = column {
+                        self.bind_expr(expr)?;
+                    }
+                }
+                if let Some(from) = select.from.as_mut() {
+                    self.bind_from(from)?;
+                }
+                self.bind_opt_expr(select.where_clause.as_mut())?;
+                if let Some(group_by) = select.group_by.as_mut() {
+                    self.bind_exprs(group_by.exprs.iter_mut())?;
+                    self.bind_opt_expr(group_by.having.as_deref_mut())?;
+                }
+                for window_def in select.window_clause.iter_mut().flatten() {
+                    self.bind_window(&mut window_def.window)?;
+                }
+                Ok(())
+            }
+            ast::OneSelect::Values(rows) => self.bind_exprs(rows.iter_mut().flatten()),
+        }
+    }
+
+    fn bind_from(&self, from: &mut ast::FromClause) -> Result<()> {
+        if let Some(table) = from.select.as_deref_mut() {
+            self.bind_select_table(table)?;
+        }
+        for join in from.joins.iter_mut().flatten() {
+            self.bind_select_table(&mut join.table)?;
+            if let Some(ast::JoinConstraint::On(expr)) = join.constraint.as_mut() {
+                self.bind_expr(expr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_select_table(&self, table: &mut ast::SelectTable) -> Result<()> {
+        match table {
+            ast::SelectTable::Table(..) => Ok(()),
+            ast::SelectTable::TableCall(_, args, _) => self.bind_exprs(args.iter_mut().flatten()),
+            ast::SelectTable::Select(select, _) => self.bind_select(select),
+            ast::SelectTable::Sub(from, _) => self.bind_from(from),
+        }
+    }
+}
+
+/// The INSTEAD OF triggers of the view for `event`. Without any, the view cannot be modified.
+fn instead_of_triggers(
+    schema: &Schema,
+    view: &View,
+    event: ast::TriggerEvent,
+    updated_columns: &[String],
+) -> Result<Vec<Rc<Trigger>>> {
+    let triggers = schema.get_triggers(
+        &view.name,
+        ast::TriggerTime::InsteadOf,
+        event,
+        updated_columns,
+    );
+    if triggers.is_empty() {
+        bail_parse_error!("cannot modify {} because it is a view", view.name);
+    }
+    Ok(triggers)
+}
+
+/// SELECT `columns` FROM `view` WHERE `where_clause`
+fn select_from_view(
+    view: &View,
+    columns: Vec<ast::ResultColumn>,
+    where_clause: Option<ast::Expr>,
+) -> ast::Select {
+    let from = ast::FromClause::single(ast::SelectTable::Table(
+        ast::QualifiedName::single(ast::Name(view.name.clone())),
+        None,
+        None,
+    ));
+    ast::Select {
+        with: None,
+        body: ast::SelectBody {
+            select: Box::new(ast::OneSelect::Select(Box::new(ast::SelectInner {
+                distinctness: None,
+                columns,
+                from: Some(from),
+                where_clause,
+                group_by: None,
+                window_clause: None,
+            }))),
+            compounds: None,
+        },
+        order_by: None,
+        limit: None,
+    }
+}
+
+/// The names of the columns of a view.
+fn view_column_names(schema: &Schema, syms: &SymbolTable, view: &View) -> Result<Vec<String>> {
+    let select = select_from_view(view, vec![ast::ResultColumn::Star], None);
+    let Plan::Select(plan) = prepare_select_plan(schema, select, syms, None)? else {
+        unreachable!();
+    };
+    Ok(plan.table_references[0]
+        .columns()
+        .iter()
+        .map(|column| {
+            column
+                .name
+                .as_deref()
+                .map(normalize_ident)
+                .unwrap_or_default()
+        })
+        .collect())
+}
+
+/// Emits an INSERT into a view, which runs its INSTEAD OF INSERT triggers for each row of the VALUES clause.
+pub fn emit_view_insert(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    view: &View,
+    columns: &Option<ast::DistinctNames>,
+    values: &[Vec<ast::Expr>],
+) -> Result<()> {
+    let triggers = instead_of_triggers(schema, view, ast::TriggerEvent::Insert, &[])?;
+    let view_columns = view_column_names(schema, syms, view)?;
+
+    // The index in the VALUES rows of the value of each column of the view
+    let value_indexes: Vec<Option<usize>> = match columns {
+        None => {
+            let num_values = values.first().map_or(0, |row| row.len());
+            if num_values != view_columns.len() {
+                bail_parse_error!(
+                    "table {} has {} columns but {} values were supplied",
+                    view.name,
+                    view_columns.len(),
+                    num_values
+                );
+            }
+            (0..view_columns.len()).map(Some).collect()
+        }
+        Some(columns) => {
+            let mut value_indexes = vec![None; view_columns.len()];
+            for (value_index, column) in columns.iter().enumerate() {
+                let column = normalize_ident(&column.0);
+                let Some(i) = view_columns.iter().position(|c| *c == column) else {
+                    bail_parse_error!("table {} has no column named {}", view.name, column);
+                };
+                value_indexes[i] = Some(value_index);
+            }
+            value_indexes
+        }
+    };
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let resolver = Resolver::new(syms);
+    let num_columns = view_columns.len();
+    let params_start_reg = alloc_trigger_params(program, num_columns);
+    let new_row_reg = new_row_params(params_start_reg, num_columns);
+    for row in values {
+        if row.len() != value_indexes.iter().flatten().count() {
+            bail_parse_error!("all VALUES must have the same number of terms");
+        }
+        emit_null_row_params(program, num_columns, params_start_reg);
+        program.emit_insn(Insn::Null {
+            dest: new_row_reg,
+            dest_end: None,
+        });
+        for (i, value_index) in value_indexes.iter().enumerate() {
+            match value_index {
+                Some(value_index) => {
+                    translate_expr(
+                        program,
+                        None,
+                        &row[*value_index],
+                        new_row_reg + 1 + i,
+                        &resolver,
+                    )?;
+                }
+                None => program.emit_insn(Insn::Null {
+                    dest: new_row_reg + 1 + i,
+                    dest_end: None,
+                }),
+            }
+        }
+        let next_row_label = program.allocate_label();
+        emit_fire_triggers(
+            program,
+            schema,
+            syms,
+            &triggers,
+            ast::TriggerTime::InsteadOf,
+            &view_columns,
+            params_start_reg,
+            next_row_label,
+        )?;
+        program.resolve_label(next_row_label, program.offset());
+    }
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+    Ok(())
+}
+
+/// Emits a DELETE from a view, which runs its INSTEAD OF DELETE triggers for each row of the view
+/// that matches the WHERE clause.
+pub fn emit_view_delete(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    view: &View,
+    where_clause: Option<Box<ast::Expr>>,
+) -> Result<()> {
+    let triggers = instead_of_triggers(schema, view, ast::TriggerEvent::Delete, &[])?;
+    let view_columns = view_column_names(schema, syms, view)?;
+    let select = select_from_view(
+        view,
+        vec![ast::ResultColumn::Star],
+        where_clause.map(|e| *e),
+    );
+    emit_view_rows_loop(
+        program,
+        schema,
+        syms,
+        &triggers,
+        &view_columns,
+        select,
+        false,
+    )
+}
+
+/// Emits an UPDATE of a view, which runs its INSTEAD OF UPDATE triggers for each row of the view
+/// that matches the WHERE clause.
+pub fn emit_view_update(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    view: &View,
+    sets: Vec<ast::Set>,
+    where_clause: Option<Box<ast::Expr>>,
+) -> Result<()> {
+    let view_columns = view_column_names(schema, syms, view)?;
+
+    // The new value of each column of the view is its SET expression, or its old value.
+    let mut new_values: Vec<ast::Expr> = (0..view_columns.len())
+        .map(|column| ast::Expr::Column {
+            database: None,
+            table: 0,
+            column,
+            is_rowid_alias: false,
+        })
+        .collect();
+    let mut updated_columns = Vec::with_capacity(sets.len());
+    for set in sets {
+        let exprs = match set.expr {
+            expr if set.col_names.len() == 1 => vec![expr],
+            ast::Expr::Parenthesized(exprs) if exprs.len() == set.col_names.len() => exprs,
+            _ => bail_parse_error!("{} columns assigned 1 values", set.col_names.len()),
+        };
+        for (column, expr) in set.col_names.iter().zip(exprs) {
+            let column = normalize_ident(&column.0);
+            let Some(i) = view_columns.iter().position(|c| *c == column) else {
+                bail_parse_error!("no such column: {}", column);
+            };
+            new_values[i] = expr;
+            updated_columns.push(column);
+        }
+    }
+    let triggers = instead_of_triggers(schema, view, ast::TriggerEvent::Update, &updated_columns)?;
+
+    let mut columns = vec![ast::ResultColumn::Star];
+    columns.extend(
+        new_values
+            .into_iter()
+            .map(|expr| ast::ResultColumn::Expr(expr, None)),
+    );
+    let select = select_from_view(view, columns, where_clause.map(|e| *e));
+    emit_view_rows_loop(
+        program,
+        schema,
+        syms,
+        &triggers,
+        &view_columns,
+        select,
+        true,
+    )
+}
+
+/// Runs `select`, which reads the rows of a view, as a coroutine and fires the INSTEAD OF triggers for
+/// each of its rows. The result columns are the old values of the columns of the view, followed by
+/// their new values if `has_new_row`.
+fn emit_view_rows_loop(
+    program: &mut ProgramBuilder,
+    schema: &Schema,
+    syms: &SymbolTable,
+    triggers: &[Rc<Trigger>],
+    view_columns: &[String],
+    select: ast::Select,
+    has_new_row: bool,
+) -> Result<()> {
+    let mut plan = prepare_select_plan(schema, select, syms, None)?;
+    optimize_plan(&mut plan, schema)?;
+    let Plan::Select(mut plan) = plan else {
+        unreachable!();
+    };
+    plan.query_type = SelectQueryType::Subquery {
+        yield_reg: usize::MAX, // will be set by emit_subquery()
+        coroutine_implementation_start: BranchOffset::Placeholder, // will be set by emit_subquery()
+    };
+
+    let (_, init_label, start_offset) = prologue(program, syms, 0, 0)?;
+    let result_columns_start_reg = emit_subquery(program, &mut plan, syms, None)?;
+    let SelectQueryType::Subquery { yield_reg, .. } = plan.query_type else {
+        unreachable!();
+    };
+
+    let num_columns = view_columns.len();
+    let params_start_reg = alloc_trigger_params(program, num_columns);
+    let new_row_reg = new_row_params(params_start_reg, num_columns);
+    let loop_end_label = program.allocate_label();
+    let next_row_label = program.allocate_label();
+    let loop_start = program.offset();
+    program.emit_insn(Insn::Yield {
+        yield_reg,
+        end_offset: loop_end_label,
+    });
+    // Rows of views have no rowid
+    program.emit_insn(Insn::Null {
+        dest: params_start_reg,
+        dest_end: None,
+    });
+    program.emit_insn(Insn::Copy {
+        src_reg: result_columns_start_reg,
+        dst_reg: params_start_reg + 1,
+        amount: num_columns - 1,
+    });
+    if has_new_row {
+        program.emit_insn(Insn::Null {
+            dest: new_row_reg,
+            dest_end: None,
+        });
+        program.emit_insn(Insn::Copy {
+            src_reg: result_columns_start_reg + num_columns,
+            dst_reg: new_row_reg + 1,
+            amount: num_columns - 1,
+        });
+    } else {
+        emit_null_row_params(program, num_columns, new_row_reg);
+    }
+    emit_fire_triggers(
+        program,
+        schema,
+        syms,
+        triggers,
+        ast::TriggerTime::InsteadOf,
+        view_columns,
+        params_start_reg,
+        next_row_label,
+    )?;
+    program.resolve_label(next_row_label, program.offset());
+    program.emit_insn(Insn::Goto {
+        target_pc: loop_start,
+    });
+    program.resolve_label(loop_end_label, program.offset());
+
+    epilogue(program, init_label, start_offset, TransactionMode::Write)
+}
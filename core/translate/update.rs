@@ -0,0 +1,307 @@
+use crate::schema::Table;
+use crate::translate::emitter::emit_program;
+use crate::translate::optimizer::optimize_plan;
+use crate::translate::plan::{Direction, Operation, Plan, UpdatePlan};
+use crate::translate::planner::{
+    bind_column_references, parse_limit, parse_where, plan_subqueries_in_expr, Scope,
+};
+use crate::translate::trigger::emit_view_update;
+use crate::util::normalize_ident;
+use crate::vdbe::builder::{ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::{bail_parse_error, schema::Schema, Result, SymbolTable};
+use limbo_sqlite3_parser::ast::{self, Expr, Update};
+
+use super::plan::TableReference;
+
+/*
+ * Example:
+ *
+ * limbo> EXPLAIN UPDATE t SET b = b + 1 WHERE a > 5;
+ * addr  opcode             p1    p2    p3    p4             p5  comment
+ * ----  -----------------  ----  ----  ----  -------------  --  -------
+ * 0     Init               0     27    0                    0   Start at 27
+ * 1     SorterOpen         0     0     0     k(0,)          0   cursor=0
+ * 2     OpenWriteAsync     1     2     0                    0
+ * 3     OpenWriteAwait     0     0     0                    0
+ * 4     RewindAsync        1     0     0                    0
+ * 5     RewindAwait        1     13    0                    0   Rewind table t
+ * 6       Column           1     0     1                    0   r[1]=t.a
+ * 7       Le               1     2     11                   0   if r[1]<=r[2] goto 11
+ * 8       RowId            1     3     0                    0   r[3]=t.rowid
+ * 9       MakeRecord       3     1     4                    0   r[4]=mkrec(r[3..3])
+ * 10      SorterInsert     0     4     0     0              0   key=r[4]
+ * 11    NextAsync          1     0     0                    0
+ * 12    NextAwait          1     6     0                    0
+ * 13    OpenPseudo         2     5     1                    0   1 columns in r[5]
+ * 14    SorterSort         0     26    0                    0
+ * 15      SorterData       0     5     2                    0   r[5]=data
+ * 16      Column           2     0     6                    0   r[6]=cursor 2.column 0
+ * 17      SeekRowid        1     6     25                   0   if (r[6]!=t.rowid) goto 25
+ * 18      Column           1     0     7                    0   r[7]=t.a
+ * 19      Column           1     1     9                    0   r[9]=t.b
+ * 20      Integer          1     10    0                    0   r[10]=1
+ * 21      Add              9     10    8                    0   r[8]=r[9]+r[10]
+ * 22      MakeRecord       7     2     11                   0   r[11]=mkrec(r[7..8])
+ * 23      InsertAsync      1     11    6                    1
+ * 24      InsertAwait      1     0     0                    0
+ * 25    SorterNext         0     15    0                    0
+ * 26    Halt               0     0     0                    0
+ * 27    Transaction        0     1     0                    0
+ * 28    Integer            5     2     0                    0   r[2]=5
+ * 29    Goto               0     1     0                    0
+ */
+pub fn translate_update(
+    query_mode: QueryMode,
+    schema: &Schema,
+    update: Update,
+    syms: &SymbolTable,
+    foreign_keys: bool,
+) -> Result<ProgramBuilder> {
+    if let Some(view) = schema.get_view(&update.tbl_name.name.0) {
+        let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+            query_mode,
+            num_cursors: 1,
+            approx_num_insns: 30,
+            approx_num_labels: 8,
+        });
+        program.foreign_keys = foreign_keys;
+        if update.order_by.is_some() || update.limit.is_some() {
+            bail_parse_error!("ORDER BY and LIMIT are not supported in an UPDATE of a view");
+        }
+        emit_view_update(
+            &mut program,
+            schema,
+            syms,
+            &view,
+            update.sets,
+            update.where_clause,
+        )?;
+        return Ok(program);
+    }
+    let mut update_plan = prepare_update_plan(schema, update, syms)?;
+    optimize_plan(&mut update_plan, schema)?;
+    let Plan::Update(ref update) = update_plan else {
+        panic!("update_plan is not an UpdatePlan");
+    };
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 4,
+        approx_num_insns: estimate_num_instructions(update),
+        approx_num_labels: 8,
+    });
+    program.foreign_keys = foreign_keys;
+    emit_program(&mut program, update_plan, schema, syms)?;
+    Ok(program)
+}
+
+pub fn prepare_update_plan(schema: &Schema, update: Update, syms: &SymbolTable) -> Result<Plan> {
+    let Update {
+        with,
+        or_conflict,
+        tbl_name,
+        indexed,
+        sets,
+        from,
+        where_clause,
+        returning,
+        order_by,
+        limit,
+    } = update;
+    if with.is_some() {
+        bail_parse_error!("WITH clause is not supported");
+    }
+    if or_conflict.is_some() {
+        bail_parse_error!("UPDATE OR ... is not supported");
+    }
+    if indexed.is_some() {
+        bail_parse_error!("INDEXED BY clause is not supported");
+    }
+    if from.is_some() {
+        bail_parse_error!("UPDATE FROM is not supported");
+    }
+    if returning.is_some() {
+        bail_parse_error!("RETURNING clause is not supported");
+    }
+
+    let table_name = normalize_ident(tbl_name.name.0.as_str());
+    let table = match schema.resolve_table(&tbl_name)? {
+        Some(table) => table,
+        None if schema.get_view(&table_name).is_some() => {
+            bail_parse_error!("cannot modify {} because it is a view", tbl_name)
+        }
+        None => bail_parse_error!("no such table: {}", tbl_name),
+    };
+    if table.virtual_table().is_some() {
+        bail_parse_error!("UPDATE on virtual tables is not supported");
+    }
+    let Some(btree_table) = table.btree() else {
+        bail_parse_error!("no such table: {}", tbl_name);
+    };
+    if !btree_table.has_rowid {
+        bail_parse_error!("UPDATE on WITHOUT ROWID table is not supported");
+    }
+    let table_references = vec![TableReference {
+        table: Table::BTree(btree_table.clone()),
+        identifier: table_name,
+        database: Some(schema.database_name(btree_table.db).to_string()),
+        op: Operation::Scan { iter_dir: None },
+        join_info: None,
+    }];
+    // Subqueries in the SET, WHERE and ORDER BY clauses can refer to the table being updated.
+    let subquery_scope = Scope::for_expr_subqueries(table_references.clone(), vec![], None);
+    let mut subqueries = vec![];
+
+    // Resolve the SET clause into (column index, expression) pairs.
+    // A column may be assigned more than once, in which case the last assignment wins.
+    let mut set_clauses: Vec<(usize, Expr)> = Vec::with_capacity(sets.len());
+    for set in sets {
+        let exprs = match set.expr {
+            expr if set.col_names.len() == 1 => vec![expr],
+            Expr::Parenthesized(exprs) if exprs.len() == set.col_names.len() => exprs,
+            Expr::Parenthesized(exprs) => bail_parse_error!(
+                "{} columns assigned {} values",
+                set.col_names.len(),
+                exprs.len()
+            ),
+            _ => bail_parse_error!("{} columns assigned 1 values", set.col_names.len()),
+        };
+        for (col_name, mut expr) in set.col_names.iter().zip(exprs) {
+            let col_name = normalize_ident(col_name.0.as_str());
+            let col_idx = match btree_table.columns.iter().position(|c| {
+                c.name
+                    .as_ref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(&col_name))
+            }) {
+                Some(idx) => idx,
+                None if is_rowid_name(&col_name) => {
+                    match btree_table.columns.iter().position(|c| c.is_rowid_alias) {
+                        Some(idx) => idx,
+                        None => bail_parse_error!(
+                            "UPDATE of the rowid of a table without an INTEGER PRIMARY KEY is not supported"
+                        ),
+                    }
+                }
+                None => bail_parse_error!("no such column: {}", col_name),
+            };
+            plan_subqueries_in_expr(
+                &mut expr,
+                schema,
+                syms,
+                &subquery_scope,
+                None,
+                &mut subqueries,
+            )?;
+            bind_column_references(&mut expr, &table_references, None)?;
+            set_clauses.retain(|(idx, _)| *idx != col_idx);
+            set_clauses.push((col_idx, expr));
+        }
+    }
+
+    let mut where_predicates = vec![];
+
+    // Parse the WHERE clause
+    let mut where_clause = where_clause.map(|e| *e);
+    if let Some(where_expr) = where_clause.as_mut() {
+        plan_subqueries_in_expr(
+            where_expr,
+            schema,
+            syms,
+            &subquery_scope,
+            None,
+            &mut subqueries,
+        )?;
+    }
+    parse_where(where_clause, &table_references, None, &mut where_predicates)?;
+
+    // Parse the ORDER BY clause
+    let order_by = order_by
+        .map(|order_by| {
+            order_by
+                .into_iter()
+                .map(|mut o| {
+                    plan_subqueries_in_expr(
+                        &mut o.expr,
+                        schema,
+                        syms,
+                        &subquery_scope,
+                        None,
+                        &mut subqueries,
+                    )?;
+                    bind_column_references(&mut o.expr, &table_references, None)?;
+                    Ok((
+                        o.expr,
+                        o.order.map_or(Direction::Ascending, |o| match o {
+                            ast::SortOrder::Asc => Direction::Ascending,
+                            ast::SortOrder::Desc => Direction::Descending,
+                        }),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
+
+    // Parse the LIMIT/OFFSET clause
+    let (resolved_limit, resolved_offset) = limit.map_or(Ok((None, None)), |l| parse_limit(*l))?;
+
+    // Every index key ends with the rowid, so changing the rowid affects all of them
+    let updates_rowid = set_clauses
+        .iter()
+        .any(|(idx, _)| btree_table.columns[*idx].is_rowid_alias);
+    let indexes = schema
+        .get_table_indices(&btree_table)
+        .iter()
+        .filter(|index| {
+            updates_rowid
+                || index.columns.iter().any(|index_column| {
+                    set_clauses.iter().any(|(idx, _)| {
+                        btree_table.columns[*idx].name.as_deref() == Some(&index_column.name)
+                    })
+                })
+        })
+        .cloned()
+        .collect();
+
+    let updated_columns: Vec<String> = set_clauses
+        .iter()
+        .filter_map(|(idx, _)| btree_table.columns[*idx].name.as_deref())
+        .map(normalize_ident)
+        .collect();
+    let triggers = [ast::TriggerTime::Before, ast::TriggerTime::After]
+        .into_iter()
+        .flat_map(|time| {
+            schema.get_table_triggers(
+                &btree_table,
+                time,
+                ast::TriggerEvent::Update,
+                &updated_columns,
+            )
+        })
+        .collect();
+
+    let plan = UpdatePlan {
+        table_references,
+        set_clauses,
+        where_clause: where_predicates,
+        order_by,
+        limit: resolved_limit,
+        offset: resolved_offset,
+        contains_constant_false_condition: false,
+        indexes,
+        triggers,
+        subqueries,
+    };
+
+    Ok(Plan::Update(plan))
+}
+
+pub fn is_rowid_name(name: &str) -> bool {
+    ["rowid", "_rowid_", "oid"]
+        .iter()
+        .any(|alias| name.eq_ignore_ascii_case(alias))
+}
+
+fn estimate_num_instructions(plan: &UpdatePlan) -> usize {
+    let base = 30;
+
+    base + plan.table_references.len() * 10 + plan.set_clauses.len() * 2
+}
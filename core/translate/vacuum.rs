@@ -0,0 +1,40 @@
+use crate::schema::{Schema, MAIN_DB};
+use crate::translate::emitter::Resolver;
+use crate::translate::expr::translate_expr;
+use crate::translate::{ProgramBuilder, ProgramBuilderOpts};
+use crate::vdbe::insn::Insn;
+use crate::{QueryMode, Result, SymbolTable};
+use limbo_sqlite3_parser::ast;
+
+pub fn translate_vacuum(
+    schema: &Schema,
+    db_name: Option<&ast::Name>,
+    into: Option<&ast::Expr>,
+    syms: &SymbolTable,
+) -> Result<ProgramBuilder> {
+    let db = match db_name {
+        Some(db_name) => schema.resolve_database(db_name)?,
+        None => MAIN_DB,
+    };
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode: QueryMode::Normal,
+        num_cursors: 0,
+        approx_num_insns: 4,
+        approx_num_labels: 0,
+    });
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    let into_reg = match into {
+        Some(expr) => {
+            let reg = program.alloc_register();
+            translate_expr(&mut program, None, expr, reg, &Resolver::new(syms))?;
+            Some(reg)
+        }
+        None => None,
+    };
+    program.emit_insn(Insn::Vacuum { db, into_reg });
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_goto(start_offset);
+    Ok(program)
+}
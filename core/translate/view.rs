@@ -0,0 +1,201 @@
+use std::fmt::Display;
+
+use limbo_sqlite3_parser::ast::{self, fmt::ToTokens};
+
+use crate::schema::{Schema, MAIN_DB};
+use crate::util::normalize_ident;
+use crate::vdbe::builder::{CursorType, ProgramBuilder, ProgramBuilderOpts, QueryMode};
+use crate::vdbe::insn::Insn;
+use crate::{bail_parse_error, Result};
+
+use super::{emit_schema_entry, emit_schema_entry_delete, SchemaEntryType, SQLITE_TABLEID};
+
+struct SelectFormatter<'a> {
+    select: &'a ast::Select,
+}
+impl Display for SelectFormatter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.select.to_fmt(f)
+    }
+}
+
+/// The CREATE VIEW statement that describes the view, as stored in sqlite_schema.
+pub(crate) fn create_view_to_str(
+    view_name: &ast::QualifiedName,
+    columns: Option<&[ast::IndexedColumn]>,
+    select: &ast::Select,
+) -> String {
+    let mut sql = format!("CREATE VIEW {}", view_name.name.0);
+    if let Some(columns) = columns {
+        let columns = columns
+            .iter()
+            .map(|column| column.col_name.0.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        sql.push_str(&format!("({})", columns));
+    }
+    sql.push_str(&format!(" AS {}", SelectFormatter { select }));
+    sql
+}
+
+pub(crate) fn emit_noop(program: &mut ProgramBuilder) {
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+}
+
+/// Views and triggers can only be in the main database, so `name` must not be qualified with
+/// the name of another database.
+pub(crate) fn check_main_database(
+    schema: &Schema,
+    name: &ast::QualifiedName,
+    kind: &str,
+) -> Result<()> {
+    if let Some(db_name) = &name.db_name {
+        if schema.resolve_database(db_name)? != MAIN_DB {
+            bail_parse_error!("{}s are only supported in the main database", kind);
+        }
+    }
+    Ok(())
+}
+
+pub fn translate_create_view(
+    query_mode: QueryMode,
+    schema: &Schema,
+    view_name: &ast::QualifiedName,
+    columns: Option<&[ast::IndexedColumn]>,
+    select: &ast::Select,
+    if_not_exists: bool,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 1,
+    });
+    check_main_database(schema, view_name, "view")?;
+    let name = normalize_ident(&view_name.name.0);
+    let existing = if schema.get_view(&name).is_some() {
+        Some("view")
+    } else if schema.get_table(&name).is_some() {
+        Some("table")
+    } else {
+        None
+    };
+    if let Some(kind) = existing {
+        if if_not_exists {
+            emit_noop(&mut program);
+            return Ok(program);
+        }
+        bail_parse_error!("{} {} already exists", kind, view_name.name.0);
+    }
+    if schema.get_index(&name).is_some() {
+        bail_parse_error!("there is already an index named {}", view_name.name.0);
+    }
+
+    let sql = create_view_to_str(view_name, columns, select);
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let sqlite_schema = schema.get_btree_table(SQLITE_TABLEID).unwrap();
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(SQLITE_TABLEID.to_owned()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Views have no b-tree, so their root page is 0.
+    emit_schema_entry(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::View,
+        &name,
+        &name,
+        0,
+        Some(sql),
+    );
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::ParseSchema {
+        db: MAIN_DB,
+        where_clause: format!("tbl_name = '{}' AND type != 'trigger'", name),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
+
+pub fn translate_drop_view(
+    query_mode: QueryMode,
+    schema: &Schema,
+    if_exists: bool,
+    view_name: &ast::QualifiedName,
+) -> Result<ProgramBuilder> {
+    let mut program = ProgramBuilder::new(ProgramBuilderOpts {
+        query_mode,
+        num_cursors: 1,
+        approx_num_insns: 20,
+        approx_num_labels: 2,
+    });
+    check_main_database(schema, view_name, "view")?;
+    let Some(view) = schema.get_view(&view_name.name.0) else {
+        if schema.get_table(&view_name.name.0).is_some() {
+            bail_parse_error!("use DROP TABLE to delete table {}", view_name.name.0);
+        }
+        if if_exists {
+            emit_noop(&mut program);
+            return Ok(program);
+        }
+        bail_parse_error!("no such view: {}", view_name.name.0);
+    };
+
+    let init_label = program.emit_init();
+    let start_offset = program.offset();
+
+    let sqlite_schema = schema.get_btree_table(SQLITE_TABLEID).unwrap();
+    let sqlite_schema_cursor_id = program.alloc_cursor_id(
+        Some(SQLITE_TABLEID.to_owned()),
+        CursorType::BTreeTable(sqlite_schema),
+    );
+    program.emit_insn(Insn::OpenWriteAsync {
+        cursor_id: sqlite_schema_cursor_id,
+        root_page: 1.into(),
+    });
+    program.emit_insn(Insn::OpenWriteAwait {});
+
+    // Remove the entry of the view from sqlite_schema
+    emit_schema_entry_delete(
+        &mut program,
+        sqlite_schema_cursor_id,
+        SchemaEntryType::View,
+        &view.name,
+    );
+
+    // TODO: SetCookie
+    program.emit_insn(Insn::DropView {
+        db: 0,
+        view_name: view.name.clone(),
+    });
+
+    program.emit_halt();
+    program.resolve_label(init_label, program.offset());
+    program.emit_transaction(true);
+    program.emit_constant_insns();
+    program.emit_goto(start_offset);
+
+    Ok(program)
+}
@@ -0,0 +1,1541 @@
+use std::rc::Rc;
+
+use limbo_sqlite3_parser::ast;
+
+use crate::{
+    function::{AggFunc, WindowFunc},
+    schema::{Column, PseudoTable},
+    types::{OwnedValue, Record},
+    util::exprs_are_equivalent,
+    vdbe::{
+        builder::{CursorType, ProgramBuilder},
+        insn::{CmpInsFlags, Insn},
+        BranchOffset,
+    },
+    Result,
+};
+
+use super::{
+    collate::{expr_collation, CollationSeq},
+    emitter::TranslateCtx,
+    expr::translate_expr,
+    order_by::{order_by_sorter_insert, sorter_insert},
+    plan::{Direction, SelectPlan, TableReference, Window, WindowFrame, WindowFunction},
+    result_row::emit_select_result,
+};
+
+// Metadata for computing window functions
+#[derive(Debug)]
+pub struct WindowMetadata {
+    // Cursor ID for the Sorter where the rows of the query are sorted by the partition and order keys of the window
+    pub sort_cursor: usize,
+    // Register where the sorter data is inserted and later retrieved from
+    pub reg_sorter_data: usize,
+}
+
+/// Initialize resources needed for computing window functions
+pub fn init_window(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx,
+    windows: &[Window],
+    tables: &[TableReference],
+) -> Result<()> {
+    for window in windows {
+        let sort_cursor = program.alloc_cursor_id(None, CursorType::Sorter);
+        let order = window
+            .partition_by
+            .iter()
+            .map(|_| OwnedValue::Integer(Direction::Ascending as i64))
+            .chain(
+                window
+                    .order_by
+                    .iter()
+                    .map(|(_, direction)| OwnedValue::Integer(*direction as i64)),
+            )
+            .collect();
+        let (partition_collations, order_collations) = window_key_collations(window, tables);
+        program.emit_insn(Insn::SorterOpen {
+            cursor_id: sort_cursor,
+            columns: window.partition_by.len() + window.order_by.len(),
+            order: Record::new(order),
+            collations: [partition_collations, order_collations].concat(),
+        });
+        t_ctx.meta_window.push(WindowMetadata {
+            sort_cursor,
+            reg_sorter_data: program.alloc_register(),
+        });
+    }
+    Ok(())
+}
+
+/// The collations of the partition keys and of the order keys of a window.
+fn window_key_collations(
+    window: &Window,
+    tables: &[TableReference],
+) -> (Vec<Option<CollationSeq>>, Vec<Option<CollationSeq>>) {
+    (
+        window
+            .partition_by
+            .iter()
+            .map(|expr| expr_collation(expr, tables))
+            .collect(),
+        window
+            .order_by
+            .iter()
+            .map(|(expr, _)| expr_collation(expr, tables))
+            .collect(),
+    )
+}
+
+/// The rows in the window sorter consist of the partition keys, the order keys, the arguments of each
+/// window function, and the columns that the result columns and ORDER BY clause refer to (the payload).
+/// The partition and order keys are the sort keys.
+struct SorterLayout {
+    order_keys_start: usize,
+    args_start: Vec<usize>,
+    payload_start: usize,
+    column_count: usize,
+}
+
+impl SorterLayout {
+    fn new(window: &Window, payload_len: usize) -> Self {
+        let order_keys_start = window.partition_by.len();
+        let mut next_column = order_keys_start + window.order_by.len();
+        let args_start = window
+            .functions
+            .iter()
+            .map(|f| {
+                let start = next_column;
+                next_column += f.args.len();
+                start
+            })
+            .collect();
+        Self {
+            order_keys_start,
+            args_start,
+            payload_start: next_column,
+            column_count: next_column + payload_len,
+        }
+    }
+}
+
+/// Returns the column references of the result columns and ORDER BY clause, except those in the arguments
+/// of window functions. Their values are stored in the sorter of the window at `window_idx`, so that the
+/// result columns can be computed from the sorted rows. The windows are computed one after the other, so the
+/// payload also holds the column references of the windows computed after this one, and the values of the
+/// window functions computed before it.
+fn window_payload(plan: &SelectPlan, window_idx: usize) -> Vec<&ast::Expr> {
+    let mut payload = vec![];
+    for rc in plan.result_columns.iter() {
+        collect_column_refs(&rc.expr, &mut payload);
+    }
+    for (expr, _) in plan.order_by.iter().flatten() {
+        collect_column_refs(expr, &mut payload);
+    }
+    for window in plan.windows[window_idx + 1..].iter() {
+        for expr in window_sorter_exprs(window) {
+            collect_column_refs(expr, &mut payload);
+        }
+    }
+    for window in plan.windows[..window_idx].iter() {
+        for func in window.functions.iter() {
+            payload.push(&func.original_expr);
+        }
+    }
+    payload
+}
+
+/// The partition keys, the order keys and the arguments of the functions of a window, in the order of the
+/// columns of its sorter.
+fn window_sorter_exprs(window: &Window) -> impl Iterator<Item = &ast::Expr> {
+    window
+        .partition_by
+        .iter()
+        .chain(window.order_by.iter().map(|(expr, _)| expr))
+        .chain(window.functions.iter().flat_map(|f| f.args.iter()))
+}
+
+fn collect_column_refs<'a>(expr: &'a ast::Expr, out: &mut Vec<&'a ast::Expr>) {
+    match expr {
+        ast::Expr::Column { .. } | ast::Expr::RowId { .. } => {
+            if !out.iter().any(|e| exprs_are_equivalent(e, expr)) {
+                out.push(expr);
+            }
+        }
+        ast::Expr::FunctionCall {
+            filter_over:
+                Some(ast::FunctionTail {
+                    over_clause: Some(_),
+                    ..
+                }),
+            ..
+        } => {}
+        ast::Expr::FunctionCall { args, .. } => {
+            for arg in args.iter().flatten() {
+                collect_column_refs(arg, out);
+            }
+        }
+        ast::Expr::Between {
+            lhs, start, end, ..
+        } => {
+            collect_column_refs(lhs, out);
+            collect_column_refs(start, out);
+            collect_column_refs(end, out);
+        }
+        ast::Expr::Binary(lhs, _, rhs) => {
+            collect_column_refs(lhs, out);
+            collect_column_refs(rhs, out);
+        }
+        ast::Expr::Case {
+            base,
+            when_then_pairs,
+            else_expr,
+        } => {
+            for e in base.iter().chain(else_expr.iter()) {
+                collect_column_refs(e, out);
+            }
+            for (when, then) in when_then_pairs {
+                collect_column_refs(when, out);
+                collect_column_refs(then, out);
+            }
+        }
+        ast::Expr::Cast { expr, .. }
+        | ast::Expr::Collate(expr, _)
+        | ast::Expr::IsNull(expr)
+        | ast::Expr::NotNull(expr)
+        | ast::Expr::Unary(_, expr) => collect_column_refs(expr, out),
+        ast::Expr::InList { lhs, rhs, .. } => {
+            collect_column_refs(lhs, out);
+            for e in rhs.iter().flatten() {
+                collect_column_refs(e, out);
+            }
+        }
+        ast::Expr::Like {
+            lhs, rhs, escape, ..
+        } => {
+            collect_column_refs(lhs, out);
+            collect_column_refs(rhs, out);
+            if let Some(escape) = escape {
+                collect_column_refs(escape, out);
+            }
+        }
+        ast::Expr::Parenthesized(exprs) => {
+            for e in exprs {
+                collect_column_refs(e, out);
+            }
+        }
+        ast::Expr::SubqueryResult {
+            lhs, outer_refs, ..
+        } => {
+            for e in lhs.iter().map(|lhs| lhs.as_ref()).chain(outer_refs.iter()) {
+                collect_column_refs(e, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Emits the bytecode for inserting a row into the sorter of the window at `window_idx`: a row of the main loop
+/// for the first window, or a row of the previous window for the others.
+pub fn window_sorter_insert(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx,
+    plan: &SelectPlan,
+    window_idx: usize,
+) -> Result<()> {
+    let window = &plan.windows[window_idx];
+    let payload = window_payload(plan, window_idx);
+    let layout = SorterLayout::new(window, payload.len());
+    let exprs = window_sorter_exprs(window).chain(payload);
+    let start_reg = program.alloc_registers(layout.column_count);
+    for (i, expr) in exprs.enumerate() {
+        translate_expr(
+            program,
+            Some(&plan.table_references),
+            expr,
+            start_reg + i,
+            &t_ctx.resolver,
+        )?;
+    }
+    let WindowMetadata {
+        sort_cursor,
+        reg_sorter_data,
+    } = t_ctx.meta_window[window_idx];
+    sorter_insert(
+        program,
+        start_reg,
+        layout.column_count,
+        sort_cursor,
+        reg_sorter_data,
+    );
+    Ok(())
+}
+
+/// The registers and cursors that the window functions are computed with.
+/// Rows are addressed by their 0-based position in the sorted window sorter.
+struct WindowRegs {
+    sort_cursor: usize,
+    // Pseudo cursor for reading rows other than the current row, e.g. the rows of a frame
+    scan_cursor: usize,
+    reg_scan_data: usize,
+    // Pseudo cursor for reading the current row
+    cur_cursor: usize,
+    reg_one: usize,
+    // Position of the current row
+    reg_row: usize,
+    // Position of the first row of the current partition
+    reg_partition_start: usize,
+    // Position after the last row of the current partition
+    reg_partition_end: usize,
+    reg_partition_size: usize,
+    // Position of the first peer of the current row, i.e. the first row with the same order keys
+    reg_peer_start: usize,
+    reg_dense_rank: usize,
+    // The order keys of the current row, and those of a scanned row right after them (so that they can be compared)
+    reg_order_keys: usize,
+    reg_scan_order_keys: usize,
+    num_order_keys: usize,
+    order_keys_start: usize,
+    // Collations of the order keys
+    order_collations: Vec<Option<CollationSeq>>,
+    // Direction of the single order key of a RANGE frame with an offset
+    order_direction: Direction,
+    // Scratch register for a row position
+    reg_scan_row: usize,
+}
+
+impl WindowRegs {
+    /// Reads the row at the position in `reg_pos` into the scan cursor, or jumps to `label_out_of_range`
+    /// if there is no such row.
+    fn emit_read_row(
+        &self,
+        program: &mut ProgramBuilder,
+        reg_pos: usize,
+        label_out_of_range: BranchOffset,
+    ) {
+        program.emit_insn(Insn::SorterSeek {
+            cursor_id: self.sort_cursor,
+            row_reg: reg_pos,
+            pc_if_out_of_range: label_out_of_range,
+        });
+        program.emit_insn(Insn::SorterData {
+            cursor_id: self.sort_cursor,
+            dest_reg: self.reg_scan_data,
+            pseudo_cursor: self.scan_cursor,
+        });
+    }
+
+    fn emit_increment(&self, program: &mut ProgramBuilder, reg: usize) {
+        program.emit_insn(Insn::Add {
+            lhs: reg,
+            rhs: self.reg_one,
+            dest: reg,
+        });
+    }
+}
+
+/// The registers of a single window function
+struct FunctionRegs {
+    result: usize,
+    // Scratch registers for the arguments of a row of the frame
+    args: usize,
+    // The first and last position of the frame of the current row. The frame is empty if start > end.
+    frame_start: usize,
+    frame_end: usize,
+    // The offsets of the frame bounds, if they are n PRECEDING or n FOLLOWING
+    start_offset: Option<usize>,
+    end_offset: Option<usize>,
+    // The accumulator of an aggregate whose frame starts at the start of the partition,
+    // and the position of the last row that was added to it
+    acc: usize,
+    last_accumulated_row: usize,
+    // Collation of the argument of min() and max(), which they compare text with
+    collation: Option<CollationSeq>,
+}
+
+/// The frame the function is computed over. cume_dist() always uses the default frame, whose end is the last peer of the current row.
+fn effective_frame(func: &WindowFunction) -> Option<WindowFrame> {
+    match func.func {
+        WindowFunc::CumeDist => Some(WindowFrame {
+            mode: ast::FrameMode::Range,
+            start: ast::FrameBound::UnboundedPreceding,
+            end: ast::FrameBound::CurrentRow,
+        }),
+        _ if func.func.uses_frame() => Some(func.frame.clone()),
+        _ => None,
+    }
+}
+
+/// An aggregate whose frame starts at the start of the partition is computed incrementally,
+/// by adding the rows that enter the frame to the accumulator. Otherwise it is recomputed for every row.
+fn is_incremental_aggregate(func: &WindowFunction) -> bool {
+    matches!(func.func, WindowFunc::Agg(_))
+        && matches!(func.frame.start, ast::FrameBound::UnboundedPreceding)
+}
+
+/// Emits the bytecode for computing the window functions and outputting the rows of the query.
+/// This is called when the main query execution loop has finished processing, and all rows
+/// are in the sorter of the first window.
+///
+/// The windows are computed one after the other. The rows of each window, with the values of its functions,
+/// are inserted into the sorter of the next window, and those of the last window are output.
+pub fn emit_window<'a>(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx<'a>,
+    plan: &'a SelectPlan,
+) -> Result<()> {
+    for window_idx in 0..plan.windows.len() {
+        emit_window_functions(program, t_ctx, plan, window_idx)?;
+    }
+    Ok(())
+}
+
+/// Emits the bytecode for computing the functions of the window at `window_idx` over the rows in its sorter.
+///
+/// The sorted rows are processed one partition at a time. The end of a partition is found by scanning
+/// ahead for a row with different partition keys, after which the window functions are computed for each
+/// row of the partition in turn. Functions like lag() and aggregates over a frame read other rows of the
+/// partition by seeking the sorter to their position.
+fn emit_window_functions<'a>(
+    program: &mut ProgramBuilder,
+    t_ctx: &mut TranslateCtx<'a>,
+    plan: &'a SelectPlan,
+    window_idx: usize,
+) -> Result<()> {
+    let window = &plan.windows[window_idx];
+    let payload = window_payload(plan, window_idx);
+    let layout = SorterLayout::new(window, payload.len());
+    let WindowMetadata {
+        sort_cursor,
+        reg_sorter_data,
+    } = t_ctx.meta_window[window_idx];
+
+    // sorter column names do not matter
+    let ty = crate::schema::Type::Null;
+    let pseudo_table = Rc::new(PseudoTable {
+        columns: (0..layout.column_count)
+            .map(|_| Column {
+                name: None,
+                primary_key: false,
+                ty,
+                ty_str: ty.to_string().to_uppercase(),
+                is_rowid_alias: false,
+                notnull: false,
+                default: None,
+                collation: None,
+            })
+            .collect(),
+    });
+    let cur_cursor = program.alloc_cursor_id(None, CursorType::Pseudo(pseudo_table.clone()));
+    let scan_cursor = program.alloc_cursor_id(None, CursorType::Pseudo(pseudo_table));
+    let reg_scan_data = program.alloc_register();
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: cur_cursor,
+        content_reg: reg_sorter_data,
+        num_fields: layout.column_count,
+    });
+    program.emit_insn(Insn::OpenPseudo {
+        cursor_id: scan_cursor,
+        content_reg: reg_scan_data,
+        num_fields: layout.column_count,
+    });
+
+    let num_partition_keys = window.partition_by.len();
+    let num_order_keys = window.order_by.len();
+    let (partition_collations, order_collations) =
+        window_key_collations(window, &plan.table_references);
+    let regs = WindowRegs {
+        sort_cursor,
+        scan_cursor,
+        reg_scan_data,
+        cur_cursor,
+        reg_one: program.alloc_register(),
+        reg_row: program.alloc_register(),
+        reg_partition_start: program.alloc_register(),
+        reg_partition_end: program.alloc_register(),
+        reg_partition_size: program.alloc_register(),
+        reg_peer_start: program.alloc_register(),
+        reg_dense_rank: program.alloc_register(),
+        reg_order_keys: program.alloc_registers(num_order_keys),
+        reg_scan_order_keys: program.alloc_registers(num_order_keys),
+        num_order_keys,
+        order_keys_start: layout.order_keys_start,
+        order_collations,
+        order_direction: window
+            .order_by
+            .first()
+            .map_or(Direction::Ascending, |(_, direction)| *direction),
+        reg_scan_row: program.alloc_register(),
+    };
+    let reg_prev_order_keys = program.alloc_registers(num_order_keys);
+    let reg_partition_keys = program.alloc_registers(num_partition_keys);
+    let reg_scan_partition_keys = program.alloc_registers(num_partition_keys);
+    let reg_payload = program.alloc_registers(payload.len());
+
+    let mut function_regs = Vec::with_capacity(window.functions.len());
+    for func in window.functions.iter() {
+        let offset_reg =
+            |program: &mut ProgramBuilder, bound: &ast::FrameBound| -> Result<Option<usize>> {
+                match bound {
+                    ast::FrameBound::Preceding(offset) | ast::FrameBound::Following(offset) => {
+                        let reg = program.alloc_register();
+                        translate_expr(program, None, offset, reg, &t_ctx.resolver)?;
+                        Ok(Some(reg))
+                    }
+                    _ => Ok(None),
+                }
+            };
+        let frame = effective_frame(func);
+        function_regs.push(FunctionRegs {
+            result: program.alloc_register(),
+            // group_concat() and string_agg() also need a register for the delimiter
+            args: program.alloc_registers(func.args.len().max(2)),
+            frame_start: program.alloc_register(),
+            frame_end: program.alloc_register(),
+            start_offset: match &frame {
+                Some(frame) => offset_reg(program, &frame.start)?,
+                None => None,
+            },
+            end_offset: match &frame {
+                Some(frame) => offset_reg(program, &frame.end)?,
+                None => None,
+            },
+            acc: program.alloc_register(),
+            last_accumulated_row: program.alloc_register(),
+            collation: func
+                .args
+                .first()
+                .and_then(|arg| expr_collation(arg, &plan.table_references)),
+        });
+    }
+
+    let label_window_end = program.allocate_label();
+    let label_partition_start = program.allocate_label();
+    let label_partition_scan = program.allocate_label();
+    let label_partition_scan_done = program.allocate_label();
+    let label_row_start = program.allocate_label();
+    let label_next_row = program.allocate_label();
+
+    program.emit_insn(Insn::Integer {
+        value: 1,
+        dest: regs.reg_one,
+    });
+    program.emit_insn(Insn::SorterSort {
+        cursor_id: sort_cursor,
+        pc_if_empty: label_window_end,
+    });
+    program.emit_insn(Insn::Integer {
+        value: 0,
+        dest: regs.reg_row,
+    });
+
+    // Find the end of the partition that starts at the current row
+    program.resolve_label(label_partition_start, program.offset());
+    program.add_comment(program.offset(), "start of partition");
+    regs.emit_read_row(program, regs.reg_row, label_window_end);
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_row,
+        dst_reg: regs.reg_partition_start,
+        amount: 0,
+    });
+    for i in 0..num_partition_keys {
+        program.emit_insn(Insn::Column {
+            cursor_id: scan_cursor,
+            column: i,
+            dest: reg_partition_keys + i,
+        });
+    }
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_row,
+        dst_reg: regs.reg_scan_row,
+        amount: 0,
+    });
+    program.resolve_label(label_partition_scan, program.offset());
+    regs.emit_increment(program, regs.reg_scan_row);
+    regs.emit_read_row(program, regs.reg_scan_row, label_partition_scan_done);
+    if num_partition_keys > 0 {
+        for i in 0..num_partition_keys {
+            program.emit_insn(Insn::Column {
+                cursor_id: scan_cursor,
+                column: i,
+                dest: reg_scan_partition_keys + i,
+            });
+        }
+        program.emit_insn(Insn::Compare {
+            start_reg_a: reg_partition_keys,
+            start_reg_b: reg_scan_partition_keys,
+            count: num_partition_keys,
+            collations: partition_collations.clone(),
+        });
+        program.emit_insn(Insn::Jump {
+            target_pc_lt: label_partition_scan_done,
+            target_pc_eq: label_partition_scan,
+            target_pc_gt: label_partition_scan_done,
+        });
+    } else {
+        program.emit_insn(Insn::Goto {
+            target_pc: label_partition_scan,
+        });
+    }
+    program.resolve_label(label_partition_scan_done, program.offset());
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_scan_row,
+        dst_reg: regs.reg_partition_end,
+        amount: 0,
+    });
+    program.emit_insn(Insn::Subtract {
+        lhs: regs.reg_partition_end,
+        rhs: regs.reg_partition_start,
+        dest: regs.reg_partition_size,
+    });
+
+    // Reset the state that is carried from row to row within a partition
+    program.emit_insn(Insn::Integer {
+        value: 0,
+        dest: regs.reg_dense_rank,
+    });
+    for (func, f_regs) in window.functions.iter().zip(function_regs.iter()) {
+        if effective_frame(func).is_some() {
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_partition_start,
+                dst_reg: f_regs.frame_start,
+                amount: 0,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_partition_start,
+                rhs: regs.reg_one,
+                dest: f_regs.frame_end,
+            });
+        }
+        if is_incremental_aggregate(func) {
+            program.emit_insn(Insn::Null {
+                dest: f_regs.acc,
+                dest_end: None,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_partition_start,
+                rhs: regs.reg_one,
+                dest: f_regs.last_accumulated_row,
+            });
+        }
+    }
+
+    // Read the current row
+    program.resolve_label(label_row_start, program.offset());
+    program.emit_insn(Insn::Ge {
+        lhs: regs.reg_row,
+        rhs: regs.reg_partition_end,
+        target_pc: label_partition_start,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::SorterSeek {
+        cursor_id: sort_cursor,
+        row_reg: regs.reg_row,
+        pc_if_out_of_range: label_window_end,
+    });
+    program.emit_insn(Insn::SorterData {
+        cursor_id: sort_cursor,
+        dest_reg: reg_sorter_data,
+        pseudo_cursor: cur_cursor,
+    });
+    for i in 0..num_order_keys {
+        program.emit_insn(Insn::Column {
+            cursor_id: cur_cursor,
+            column: layout.order_keys_start + i,
+            dest: regs.reg_order_keys + i,
+        });
+    }
+    for i in 0..payload.len() {
+        program.emit_insn(Insn::Column {
+            cursor_id: cur_cursor,
+            column: layout.payload_start + i,
+            dest: reg_payload + i,
+        });
+    }
+
+    // A new peer group starts at the start of the partition, and at every row whose order keys differ from those of the previous row.
+    let label_new_peer_group = program.allocate_label();
+    let label_same_peer_group = program.allocate_label();
+    program.emit_insn(Insn::Eq {
+        lhs: regs.reg_row,
+        rhs: regs.reg_partition_start,
+        target_pc: label_new_peer_group,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    if num_order_keys > 0 {
+        program.emit_insn(Insn::Compare {
+            start_reg_a: regs.reg_order_keys,
+            start_reg_b: reg_prev_order_keys,
+            count: num_order_keys,
+            collations: regs.order_collations.clone(),
+        });
+        program.emit_insn(Insn::Jump {
+            target_pc_lt: label_new_peer_group,
+            target_pc_eq: label_same_peer_group,
+            target_pc_gt: label_new_peer_group,
+        });
+    } else {
+        program.emit_insn(Insn::Goto {
+            target_pc: label_same_peer_group,
+        });
+    }
+    program.resolve_label(label_new_peer_group, program.offset());
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_row,
+        dst_reg: regs.reg_peer_start,
+        amount: 0,
+    });
+    regs.emit_increment(program, regs.reg_dense_rank);
+    program.resolve_label(label_same_peer_group, program.offset());
+    if num_order_keys > 0 {
+        program.emit_insn(Insn::Copy {
+            src_reg: regs.reg_order_keys,
+            dst_reg: reg_prev_order_keys,
+            amount: num_order_keys - 1,
+        });
+    }
+
+    for ((func, f_regs), args_start) in window
+        .functions
+        .iter()
+        .zip(function_regs.iter())
+        .zip(layout.args_start.iter())
+    {
+        emit_window_function(program, t_ctx, &regs, func, f_regs, *args_start)?;
+    }
+
+    // Output the row, or pass it on to the next window. The result columns are computed from the payload and
+    // the values of the window functions.
+    let cache_len = t_ctx.resolver.expr_to_reg_cache.len();
+    for (i, expr) in payload.into_iter().enumerate() {
+        t_ctx
+            .resolver
+            .expr_to_reg_cache
+            .push((expr, reg_payload + i));
+    }
+    for (func, f_regs) in window.functions.iter().zip(function_regs.iter()) {
+        t_ctx
+            .resolver
+            .expr_to_reg_cache
+            .push((&func.original_expr, f_regs.result));
+    }
+    if window_idx + 1 < plan.windows.len() {
+        window_sorter_insert(program, t_ctx, plan, window_idx + 1)?;
+        t_ctx.resolver.expr_to_reg_cache.truncate(cache_len);
+    } else {
+        match &plan.order_by {
+            None => {
+                emit_select_result(
+                    program,
+                    t_ctx,
+                    plan,
+                    Some(label_window_end),
+                    Some(label_next_row),
+                )?;
+            }
+            Some(_) => {
+                order_by_sorter_insert(program, t_ctx, plan)?;
+            }
+        }
+    }
+
+    program.resolve_label(label_next_row, program.offset());
+    regs.emit_increment(program, regs.reg_row);
+    program.emit_insn(Insn::Goto {
+        target_pc: label_row_start,
+    });
+
+    program.resolve_label(label_window_end, program.offset());
+    Ok(())
+}
+
+/// Emits the bytecode that computes the value of a window function for the current row.
+fn emit_window_function(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    regs: &WindowRegs,
+    func: &WindowFunction,
+    f_regs: &FunctionRegs,
+    args_start: usize,
+) -> Result<()> {
+    let result = f_regs.result;
+    // Reads an argument of the function for the current row
+    let read_arg = |program: &mut ProgramBuilder, i: usize, dest: usize| {
+        program.emit_insn(Insn::Column {
+            cursor_id: regs.cur_cursor,
+            column: args_start + i,
+            dest,
+        });
+    };
+    if let Some(frame) = effective_frame(func) {
+        emit_frame_bounds(program, regs, &frame, f_regs)?;
+    }
+    let label_done = program.allocate_label();
+    match &func.func {
+        WindowFunc::RowNumber => {
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_row,
+                rhs: regs.reg_partition_start,
+                dest: result,
+            });
+            regs.emit_increment(program, result);
+        }
+        WindowFunc::Rank => {
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_peer_start,
+                rhs: regs.reg_partition_start,
+                dest: result,
+            });
+            regs.emit_increment(program, result);
+        }
+        WindowFunc::DenseRank => {
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_dense_rank,
+                dst_reg: result,
+                amount: 0,
+            });
+        }
+        WindowFunc::PercentRank => {
+            // (rank - 1) / (partition size - 1), or 0 if the partition has a single row
+            let reg_tmp = f_regs.args;
+            program.emit_insn(Insn::Real {
+                value: 0.0,
+                dest: result,
+            });
+            program.emit_insn(Insn::Le {
+                lhs: regs.reg_partition_size,
+                rhs: regs.reg_one,
+                target_pc: label_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_peer_start,
+                rhs: regs.reg_partition_start,
+                dest: result,
+            });
+            program.emit_insn(Insn::Real {
+                value: 1.0,
+                dest: reg_tmp,
+            });
+            program.emit_insn(Insn::Multiply {
+                lhs: result,
+                rhs: reg_tmp,
+                dest: result,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_partition_size,
+                rhs: regs.reg_one,
+                dest: reg_tmp,
+            });
+            program.emit_insn(Insn::Divide {
+                lhs: result,
+                rhs: reg_tmp,
+                dest: result,
+            });
+        }
+        WindowFunc::CumeDist => {
+            // (position of the last peer of the current row in the partition + 1) / partition size
+            let reg_tmp = f_regs.args;
+            program.emit_insn(Insn::Subtract {
+                lhs: f_regs.frame_end,
+                rhs: regs.reg_partition_start,
+                dest: result,
+            });
+            regs.emit_increment(program, result);
+            program.emit_insn(Insn::Real {
+                value: 1.0,
+                dest: reg_tmp,
+            });
+            program.emit_insn(Insn::Multiply {
+                lhs: result,
+                rhs: reg_tmp,
+                dest: result,
+            });
+            program.emit_insn(Insn::Divide {
+                lhs: result,
+                rhs: regs.reg_partition_size,
+                dest: result,
+            });
+        }
+        WindowFunc::Ntile => {
+            // The rows of the partition are divided into n buckets. If the rows do not divide evenly,
+            // the first (size % n) buckets get one row more than the others.
+            let reg_n = f_regs.args;
+            let reg_zero = f_regs.frame_start;
+            let reg_pos = program.alloc_register();
+            let reg_bucket_size = program.alloc_register();
+            let reg_remainder = program.alloc_register();
+            let reg_large_rows = program.alloc_register();
+            let label_small_bucket = program.allocate_label();
+            read_arg(program, 0, reg_n);
+            program.emit_insn(Insn::Null {
+                dest: result,
+                dest_end: None,
+            });
+            program.emit_insn(Insn::Integer {
+                value: 0,
+                dest: reg_zero,
+            });
+            program.emit_insn(Insn::Le {
+                lhs: reg_n,
+                rhs: reg_zero,
+                target_pc: label_done,
+                flags: CmpInsFlags::default().jump_if_null(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_row,
+                rhs: regs.reg_partition_start,
+                dest: reg_pos,
+            });
+            program.emit_insn(Insn::Divide {
+                lhs: regs.reg_partition_size,
+                rhs: reg_n,
+                dest: reg_bucket_size,
+            });
+            program.emit_insn(Insn::Remainder {
+                lhs: regs.reg_partition_size,
+                rhs: reg_n,
+                dest: reg_remainder,
+            });
+            // The rows in the larger buckets
+            regs.emit_increment(program, reg_bucket_size);
+            program.emit_insn(Insn::Multiply {
+                lhs: reg_remainder,
+                rhs: reg_bucket_size,
+                dest: reg_large_rows,
+            });
+            program.emit_insn(Insn::Ge {
+                lhs: reg_pos,
+                rhs: reg_large_rows,
+                target_pc: label_small_bucket,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Divide {
+                lhs: reg_pos,
+                rhs: reg_bucket_size,
+                dest: result,
+            });
+            regs.emit_increment(program, result);
+            program.emit_insn(Insn::Goto {
+                target_pc: label_done,
+            });
+            program.resolve_label(label_small_bucket, program.offset());
+            program.emit_insn(Insn::Subtract {
+                lhs: reg_bucket_size,
+                rhs: regs.reg_one,
+                dest: reg_bucket_size,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: reg_pos,
+                rhs: reg_large_rows,
+                dest: reg_pos,
+            });
+            program.emit_insn(Insn::Divide {
+                lhs: reg_pos,
+                rhs: reg_bucket_size,
+                dest: result,
+            });
+            program.emit_insn(Insn::Add {
+                lhs: result,
+                rhs: reg_remainder,
+                dest: result,
+            });
+            regs.emit_increment(program, result);
+        }
+        WindowFunc::Lag | WindowFunc::Lead => {
+            // The value of the row the given number of rows (1 by default) before or after the current row,
+            // or the default value (NULL by default) if there is no such row in the partition.
+            let reg_pos = f_regs.frame_start;
+            let reg_offset = f_regs.frame_end;
+            let label_default = program.allocate_label();
+            if func.args.len() > 1 {
+                read_arg(program, 1, reg_offset);
+            } else {
+                program.emit_insn(Insn::Integer {
+                    value: 1,
+                    dest: reg_offset,
+                });
+            }
+            program.emit_insn(if func.func == WindowFunc::Lag {
+                Insn::Subtract {
+                    lhs: regs.reg_row,
+                    rhs: reg_offset,
+                    dest: reg_pos,
+                }
+            } else {
+                Insn::Add {
+                    lhs: regs.reg_row,
+                    rhs: reg_offset,
+                    dest: reg_pos,
+                }
+            });
+            program.emit_insn(Insn::Lt {
+                lhs: reg_pos,
+                rhs: regs.reg_partition_start,
+                target_pc: label_default,
+                flags: CmpInsFlags::default().jump_if_null(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Ge {
+                lhs: reg_pos,
+                rhs: regs.reg_partition_end,
+                target_pc: label_default,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            regs.emit_read_row(program, reg_pos, label_default);
+            program.emit_insn(Insn::Column {
+                cursor_id: regs.scan_cursor,
+                column: args_start,
+                dest: result,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: label_done,
+            });
+            program.resolve_label(label_default, program.offset());
+            if func.args.len() > 2 {
+                read_arg(program, 2, result);
+            } else {
+                program.emit_insn(Insn::Null {
+                    dest: result,
+                    dest_end: None,
+                });
+            }
+        }
+        WindowFunc::FirstValue | WindowFunc::LastValue | WindowFunc::NthValue => {
+            let reg_pos = program.alloc_register();
+            program.emit_insn(Insn::Null {
+                dest: result,
+                dest_end: None,
+            });
+            match func.func {
+                WindowFunc::FirstValue => {
+                    program.emit_insn(Insn::Copy {
+                        src_reg: f_regs.frame_start,
+                        dst_reg: reg_pos,
+                        amount: 0,
+                    });
+                }
+                WindowFunc::LastValue => {
+                    program.emit_insn(Insn::Copy {
+                        src_reg: f_regs.frame_end,
+                        dst_reg: reg_pos,
+                        amount: 0,
+                    });
+                }
+                _ => {
+                    // The n-th row of the frame
+                    read_arg(program, 1, reg_pos);
+                    program.emit_insn(Insn::Lt {
+                        lhs: reg_pos,
+                        rhs: regs.reg_one,
+                        target_pc: label_done,
+                        flags: CmpInsFlags::default().jump_if_null(),
+                        collation: None,
+                    });
+                    program.emit_insn(Insn::Add {
+                        lhs: reg_pos,
+                        rhs: f_regs.frame_start,
+                        dest: reg_pos,
+                    });
+                    program.emit_insn(Insn::Subtract {
+                        lhs: reg_pos,
+                        rhs: regs.reg_one,
+                        dest: reg_pos,
+                    });
+                }
+            }
+            // NULL if the frame does not contain the row
+            program.emit_insn(Insn::Lt {
+                lhs: reg_pos,
+                rhs: f_regs.frame_start,
+                target_pc: label_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Gt {
+                lhs: reg_pos,
+                rhs: f_regs.frame_end,
+                target_pc: label_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            regs.emit_read_row(program, reg_pos, label_done);
+            program.emit_insn(Insn::Column {
+                cursor_id: regs.scan_cursor,
+                column: args_start,
+                dest: result,
+            });
+        }
+        WindowFunc::Agg(agg_func) => {
+            let label_loop = program.allocate_label();
+            let label_loop_end = program.allocate_label();
+            if is_incremental_aggregate(func) {
+                // Add the rows that entered the frame to the accumulator, and compute the result from a copy of it
+                program.resolve_label(label_loop, program.offset());
+                program.emit_insn(Insn::Ge {
+                    lhs: f_regs.last_accumulated_row,
+                    rhs: f_regs.frame_end,
+                    target_pc: label_loop_end,
+                    flags: CmpInsFlags::default(),
+                    collation: None,
+                });
+                regs.emit_increment(program, f_regs.last_accumulated_row);
+                regs.emit_read_row(program, f_regs.last_accumulated_row, label_loop_end);
+                emit_agg_step(
+                    program, t_ctx, regs, func, agg_func, f_regs, f_regs.acc, args_start,
+                )?;
+                program.emit_insn(Insn::Goto {
+                    target_pc: label_loop,
+                });
+                program.resolve_label(label_loop_end, program.offset());
+                program.emit_insn(Insn::Copy {
+                    src_reg: f_regs.acc,
+                    dst_reg: result,
+                    amount: 0,
+                });
+            } else {
+                // Aggregate the rows of the frame from scratch
+                program.emit_insn(Insn::Null {
+                    dest: result,
+                    dest_end: None,
+                });
+                program.emit_insn(Insn::Copy {
+                    src_reg: f_regs.frame_start,
+                    dst_reg: regs.reg_scan_row,
+                    amount: 0,
+                });
+                program.resolve_label(label_loop, program.offset());
+                program.emit_insn(Insn::Gt {
+                    lhs: regs.reg_scan_row,
+                    rhs: f_regs.frame_end,
+                    target_pc: label_loop_end,
+                    flags: CmpInsFlags::default(),
+                    collation: None,
+                });
+                regs.emit_read_row(program, regs.reg_scan_row, label_loop_end);
+                emit_agg_step(
+                    program, t_ctx, regs, func, agg_func, f_regs, result, args_start,
+                )?;
+                regs.emit_increment(program, regs.reg_scan_row);
+                program.emit_insn(Insn::Goto {
+                    target_pc: label_loop,
+                });
+                program.resolve_label(label_loop_end, program.offset());
+            }
+            program.emit_insn(Insn::AggFinal {
+                register: result,
+                func: agg_func.clone(),
+            });
+        }
+    }
+    program.resolve_label(label_done, program.offset());
+    Ok(())
+}
+
+/// Emits the AggStep of an aggregate window function for the row in the scan cursor.
+#[allow(clippy::too_many_arguments)]
+fn emit_agg_step(
+    program: &mut ProgramBuilder,
+    t_ctx: &TranslateCtx,
+    regs: &WindowRegs,
+    func: &WindowFunction,
+    agg_func: &AggFunc,
+    f_regs: &FunctionRegs,
+    acc_reg: usize,
+    args_start: usize,
+) -> Result<()> {
+    for i in 0..func.args.len() {
+        program.emit_insn(Insn::Column {
+            cursor_id: regs.scan_cursor,
+            column: args_start + i,
+            dest: f_regs.args + i,
+        });
+    }
+    let delimiter = match agg_func {
+        AggFunc::GroupConcat | AggFunc::StringAgg => {
+            if func.args.len() < 2 {
+                translate_expr(
+                    program,
+                    None,
+                    &ast::Expr::Literal(ast::Literal::String(String::from("\",\""))),
+                    f_regs.args + 1,
+                    &t_ctx.resolver,
+                )?;
+            }
+            f_regs.args + 1
+        }
+        _ => 0,
+    };
+    program.emit_insn(Insn::AggStep {
+        acc_reg,
+        col: f_regs.args,
+        delimiter,
+        func: agg_func.clone(),
+        collation: match agg_func {
+            AggFunc::Min | AggFunc::Max => f_regs.collation.clone(),
+            _ => None,
+        },
+    });
+    Ok(())
+}
+
+/// Emits the bytecode that moves the start and end of the frame of a window function to the frame of the current row.
+/// As the current row moves forward, both bounds only ever move forward within the partition, so the bounds of
+/// RANGE frames are found by scanning forward from their position for the previous row.
+fn emit_frame_bounds(
+    program: &mut ProgramBuilder,
+    regs: &WindowRegs,
+    frame: &WindowFrame,
+    f_regs: &FunctionRegs,
+) -> Result<()> {
+    let rows = frame.mode == ast::FrameMode::Rows;
+    let label_start_done = program.allocate_label();
+    match &frame.start {
+        // Set at the start of the partition
+        ast::FrameBound::UnboundedPreceding => {}
+        ast::FrameBound::CurrentRow => {
+            program.emit_insn(Insn::Copy {
+                src_reg: if rows {
+                    regs.reg_row
+                } else {
+                    regs.reg_peer_start
+                },
+                dst_reg: f_regs.frame_start,
+                amount: 0,
+            });
+        }
+        ast::FrameBound::Preceding(_) if rows => {
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_row,
+                rhs: f_regs.start_offset.unwrap(),
+                dest: f_regs.frame_start,
+            });
+            program.emit_insn(Insn::Ge {
+                lhs: f_regs.frame_start,
+                rhs: regs.reg_partition_start,
+                target_pc: label_start_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_partition_start,
+                dst_reg: f_regs.frame_start,
+                amount: 0,
+            });
+        }
+        ast::FrameBound::Following(_) if rows => {
+            program.emit_insn(Insn::Add {
+                lhs: regs.reg_row,
+                rhs: f_regs.start_offset.unwrap(),
+                dest: f_regs.frame_start,
+            });
+        }
+        bound @ (ast::FrameBound::Preceding(_) | ast::FrameBound::Following(_)) => {
+            // The first row whose order key is not before the current row's key minus or plus the offset.
+            // If the current row's key is NULL, the frame starts at its first peer.
+            let reg_bound = program.alloc_register();
+            let label_null_key = program.allocate_label();
+            let label_advance = program.allocate_label();
+            let label_loop = program.allocate_label();
+            emit_range_bound(
+                program,
+                regs,
+                bound,
+                f_regs.start_offset.unwrap(),
+                reg_bound,
+                label_null_key,
+            );
+            program.resolve_label(label_loop, program.offset());
+            program.emit_insn(Insn::Ge {
+                lhs: f_regs.frame_start,
+                rhs: regs.reg_partition_end,
+                target_pc: label_start_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            regs.emit_read_row(program, f_regs.frame_start, label_start_done);
+            let reg_key = regs.reg_scan_order_keys;
+            program.emit_insn(Insn::Column {
+                cursor_id: regs.scan_cursor,
+                column: regs.order_keys_start,
+                dest: reg_key,
+            });
+            match regs.order_direction {
+                Direction::Ascending => {
+                    // NULLs come first, so they are before any bound
+                    program.emit_insn(Insn::IsNull {
+                        reg: reg_key,
+                        target_pc: label_advance,
+                    });
+                    program.emit_insn(Insn::Lt {
+                        lhs: reg_key,
+                        rhs: reg_bound,
+                        target_pc: label_advance,
+                        flags: CmpInsFlags::default(),
+                        collation: None,
+                    });
+                }
+                Direction::Descending => {
+                    program.emit_insn(Insn::Gt {
+                        lhs: reg_key,
+                        rhs: reg_bound,
+                        target_pc: label_advance,
+                        flags: CmpInsFlags::default(),
+                        collation: None,
+                    });
+                }
+            }
+            program.emit_insn(Insn::Goto {
+                target_pc: label_start_done,
+            });
+            program.resolve_label(label_advance, program.offset());
+            regs.emit_increment(program, f_regs.frame_start);
+            program.emit_insn(Insn::Goto {
+                target_pc: label_loop,
+            });
+            program.resolve_label(label_null_key, program.offset());
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_peer_start,
+                dst_reg: f_regs.frame_start,
+                amount: 0,
+            });
+        }
+        ast::FrameBound::UnboundedFollowing => {
+            unreachable!("frame cannot start at UNBOUNDED FOLLOWING")
+        }
+    }
+    program.resolve_label(label_start_done, program.offset());
+
+    let label_end_done = program.allocate_label();
+    match &frame.end {
+        ast::FrameBound::UnboundedFollowing => {
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_partition_end,
+                rhs: regs.reg_one,
+                dest: f_regs.frame_end,
+            });
+        }
+        ast::FrameBound::CurrentRow if rows => {
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_row,
+                dst_reg: f_regs.frame_end,
+                amount: 0,
+            });
+        }
+        ast::FrameBound::CurrentRow => {
+            emit_peer_end(program, regs, f_regs.frame_end);
+        }
+        ast::FrameBound::Preceding(_) if rows => {
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_row,
+                rhs: f_regs.end_offset.unwrap(),
+                dest: f_regs.frame_end,
+            });
+        }
+        ast::FrameBound::Following(_) if rows => {
+            program.emit_insn(Insn::Add {
+                lhs: regs.reg_row,
+                rhs: f_regs.end_offset.unwrap(),
+                dest: f_regs.frame_end,
+            });
+            program.emit_insn(Insn::Lt {
+                lhs: f_regs.frame_end,
+                rhs: regs.reg_partition_end,
+                target_pc: label_end_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            program.emit_insn(Insn::Subtract {
+                lhs: regs.reg_partition_end,
+                rhs: regs.reg_one,
+                dest: f_regs.frame_end,
+            });
+        }
+        bound @ (ast::FrameBound::Preceding(_) | ast::FrameBound::Following(_)) => {
+            // The last row whose order key is not after the current row's key minus or plus the offset.
+            // If the current row's key is NULL, the frame ends at its last peer.
+            let reg_bound = program.alloc_register();
+            let label_null_key = program.allocate_label();
+            let label_include = program.allocate_label();
+            let label_loop = program.allocate_label();
+            emit_range_bound(
+                program,
+                regs,
+                bound,
+                f_regs.end_offset.unwrap(),
+                reg_bound,
+                label_null_key,
+            );
+            program.resolve_label(label_loop, program.offset());
+            program.emit_insn(Insn::Add {
+                lhs: f_regs.frame_end,
+                rhs: regs.reg_one,
+                dest: regs.reg_scan_row,
+            });
+            program.emit_insn(Insn::Ge {
+                lhs: regs.reg_scan_row,
+                rhs: regs.reg_partition_end,
+                target_pc: label_end_done,
+                flags: CmpInsFlags::default(),
+                collation: None,
+            });
+            regs.emit_read_row(program, regs.reg_scan_row, label_end_done);
+            let reg_key = regs.reg_scan_order_keys;
+            program.emit_insn(Insn::Column {
+                cursor_id: regs.scan_cursor,
+                column: regs.order_keys_start,
+                dest: reg_key,
+            });
+            match regs.order_direction {
+                Direction::Ascending => {
+                    program.emit_insn(Insn::IsNull {
+                        reg: reg_key,
+                        target_pc: label_include,
+                    });
+                    program.emit_insn(Insn::Le {
+                        lhs: reg_key,
+                        rhs: reg_bound,
+                        target_pc: label_include,
+                        flags: CmpInsFlags::default(),
+                        collation: None,
+                    });
+                }
+                Direction::Descending => {
+                    program.emit_insn(Insn::Ge {
+                        lhs: reg_key,
+                        rhs: reg_bound,
+                        target_pc: label_include,
+                        flags: CmpInsFlags::default(),
+                        collation: None,
+                    });
+                }
+            }
+            program.emit_insn(Insn::Goto {
+                target_pc: label_end_done,
+            });
+            program.resolve_label(label_include, program.offset());
+            program.emit_insn(Insn::Copy {
+                src_reg: regs.reg_scan_row,
+                dst_reg: f_regs.frame_end,
+                amount: 0,
+            });
+            program.emit_insn(Insn::Goto {
+                target_pc: label_loop,
+            });
+            program.resolve_label(label_null_key, program.offset());
+            emit_peer_end(program, regs, f_regs.frame_end);
+        }
+        ast::FrameBound::UnboundedPreceding => {
+            unreachable!("frame cannot end at UNBOUNDED PRECEDING")
+        }
+    }
+    program.resolve_label(label_end_done, program.offset());
+    Ok(())
+}
+
+/// Computes the bound of a RANGE frame with an offset into `reg_bound`: the current row's order key
+/// minus or plus the offset, depending on the direction of the order key. Jumps to `label_null_key` if the key is NULL.
+fn emit_range_bound(
+    program: &mut ProgramBuilder,
+    regs: &WindowRegs,
+    bound: &ast::FrameBound,
+    reg_offset: usize,
+    reg_bound: usize,
+    label_null_key: BranchOffset,
+) {
+    program.emit_insn(Insn::IsNull {
+        reg: regs.reg_order_keys,
+        target_pc: label_null_key,
+    });
+    let towards_start = matches!(bound, ast::FrameBound::Preceding(_));
+    let ascending = regs.order_direction == Direction::Ascending;
+    program.emit_insn(if towards_start == ascending {
+        Insn::Subtract {
+            lhs: regs.reg_order_keys,
+            rhs: reg_offset,
+            dest: reg_bound,
+        }
+    } else {
+        Insn::Add {
+            lhs: regs.reg_order_keys,
+            rhs: reg_offset,
+            dest: reg_bound,
+        }
+    });
+}
+
+/// Moves `reg_frame_end` to the last peer of the current row, i.e. the last row with the same order keys.
+/// Without order keys, all rows of the partition are peers.
+fn emit_peer_end(program: &mut ProgramBuilder, regs: &WindowRegs, reg_frame_end: usize) {
+    if regs.num_order_keys == 0 {
+        program.emit_insn(Insn::Subtract {
+            lhs: regs.reg_partition_end,
+            rhs: regs.reg_one,
+            dest: reg_frame_end,
+        });
+        return;
+    }
+    let label_loop = program.allocate_label();
+    let label_peer = program.allocate_label();
+    let label_done = program.allocate_label();
+    program.emit_insn(Insn::Ge {
+        lhs: reg_frame_end,
+        rhs: regs.reg_row,
+        target_pc: label_loop,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_row,
+        dst_reg: reg_frame_end,
+        amount: 0,
+    });
+    program.resolve_label(label_loop, program.offset());
+    program.emit_insn(Insn::Add {
+        lhs: reg_frame_end,
+        rhs: regs.reg_one,
+        dest: regs.reg_scan_row,
+    });
+    program.emit_insn(Insn::Ge {
+        lhs: regs.reg_scan_row,
+        rhs: regs.reg_partition_end,
+        target_pc: label_done,
+        flags: CmpInsFlags::default(),
+        collation: None,
+    });
+    regs.emit_read_row(program, regs.reg_scan_row, label_done);
+    for i in 0..regs.num_order_keys {
+        program.emit_insn(Insn::Column {
+            cursor_id: regs.scan_cursor,
+            column: regs.order_keys_start + i,
+            dest: regs.reg_scan_order_keys + i,
+        });
+    }
+    program.emit_insn(Insn::Compare {
+        start_reg_a: regs.reg_order_keys,
+        start_reg_b: regs.reg_scan_order_keys,
+        count: regs.num_order_keys,
+        collations: regs.order_collations.clone(),
+    });
+    program.emit_insn(Insn::Jump {
+        target_pc_lt: label_done,
+        target_pc_eq: label_peer,
+        target_pc_gt: label_done,
+    });
+    program.resolve_label(label_peer, program.offset());
+    program.emit_insn(Insn::Copy {
+        src_reg: regs.reg_scan_row,
+        dst_reg: reg_frame_end,
+        amount: 0,
+    });
+    program.emit_insn(Insn::Goto {
+        target_pc: label_loop,
+    });
+    program.resolve_label(label_done, program.offset());
+}
@@ -3,8 +3,11 @@ use limbo_ext::{AggCtx, FinalizeFunction, StepFunction};
 use crate::error::LimboError;
 use crate::ext::{ExtValue, ExtValueType};
 use crate::pseudo::PseudoCursor;
+use crate::schema::{Index, Order};
 use crate::storage::btree::BTreeCursor;
 use crate::storage::sqlite3_ondisk::write_varint;
+use crate::translate::collate::CollationSeq;
+use crate::vdbe::ephemeral::EphemeralCursor;
 use crate::vdbe::sorter::Sorter;
 use crate::vdbe::VTabOpaqueCursor;
 use crate::Result;
@@ -691,6 +694,7 @@ pub enum Cursor {
     Pseudo(PseudoCursor),
     Sorter(Sorter),
     Virtual(VTabOpaqueCursor),
+    Ephemeral(EphemeralCursor),
 }
 
 impl Cursor {
@@ -710,6 +714,10 @@ impl Cursor {
         Self::Sorter(cursor)
     }
 
+    pub fn new_ephemeral(cursor: EphemeralCursor) -> Self {
+        Self::Ephemeral(cursor)
+    }
+
     pub fn as_table_mut(&mut self) -> &mut BTreeCursor {
         match self {
             Self::Table(cursor) => cursor,
@@ -744,6 +752,13 @@ impl Cursor {
             _ => panic!("Cursor is not a virtual cursor"),
         }
     }
+
+    pub fn as_ephemeral_mut(&mut self) -> &mut EphemeralCursor {
+        match self {
+            Self::Ephemeral(cursor) => cursor,
+            _ => panic!("Cursor is not an ephemeral cursor"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -759,12 +774,91 @@ pub enum SeekOp {
     GT,
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum SeekKey<'a> {
     TableRowId(u64),
     IndexKey(&'a Record),
 }
 
+/// Sort order of the columns of an index key.
+/// Bit `i` is set when column `i` is sorted in descending order. The rowid that
+/// ends every index key is always sorted in ascending order.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct IndexKeySortOrder(u64);
+
+impl IndexKeySortOrder {
+    pub fn from_index(index: &Index) -> Self {
+        let mut spec = 0;
+        for (i, column) in index.columns.iter().enumerate() {
+            if column.order == Order::Descending {
+                assert!(
+                    i < 64,
+                    "descending index columns past the 64th are not supported"
+                );
+                spec |= 1 << i;
+            }
+        }
+        Self(spec)
+    }
+
+    pub fn is_descending(&self, column: usize) -> bool {
+        column < 64 && self.0 & (1 << column) != 0
+    }
+}
+
+/// How the keys of an index are ordered: the sort order and the collation of each column.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct IndexKeyInfo {
+    sort_order: IndexKeySortOrder,
+    collations: Vec<Option<CollationSeq>>,
+}
+
+impl IndexKeyInfo {
+    pub fn from_index(index: &Index) -> Self {
+        Self {
+            sort_order: IndexKeySortOrder::from_index(index),
+            collations: index
+                .columns
+                .iter()
+                .map(|column| column.collation.clone())
+                .collect(),
+        }
+    }
+
+    /// Keys whose columns are all in ascending order, with the given collations.
+    pub fn ascending(collations: Vec<Option<CollationSeq>>) -> Self {
+        Self {
+            sort_order: IndexKeySortOrder::default(),
+            collations,
+        }
+    }
+}
+
+/// Compare two index keys column by column, following the sort order and the collation of each column.
+/// Only as many columns as the shorter key has are compared, so a key compares equal
+/// to every key it is a prefix of. This is what lets a seek key that omits the rowid
+/// (or trailing columns) match all the index entries that start with it.
+pub fn compare_index_keys(
+    l: &[OwnedValue],
+    r: &[OwnedValue],
+    key_info: &IndexKeyInfo,
+) -> std::cmp::Ordering {
+    for (i, (l, r)) in l.iter().zip(r.iter()).enumerate() {
+        let cmp = match key_info.collations.get(i) {
+            Some(Some(collation)) => collation.compare(l, r),
+            _ => l.cmp(r),
+        };
+        if cmp.is_ne() {
+            return if key_info.sort_order.is_descending(i) {
+                cmp.reverse()
+            } else {
+                cmp
+            };
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
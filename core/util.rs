@@ -43,13 +43,14 @@ pub fn parse_schema_rows(
     syms: &SymbolTable,
 ) -> Result<()> {
     if let Some(mut rows) = rows {
+        let mut indexes = Vec::new();
         let mut automatic_indexes = Vec::new();
         loop {
             match rows.step()? {
                 StepResult::Row => {
                     let row = rows.row().unwrap();
                     let ty = row.get::<&str>(0)?;
-                    if !["table", "index"].contains(&ty) {
+                    if !["table", "index", "view", "trigger"].contains(&ty) {
                         continue;
                     }
                     match ty {
@@ -61,7 +62,9 @@ pub fn parse_schema_rows(
                                 let vtab = syms.vtabs.get(name).unwrap().clone();
                                 schema.add_virtual_table(vtab);
                             } else {
-                                let table = schema::BTreeTable::from_sql(sql, root_page as usize)?;
+                                let mut table =
+                                    schema::BTreeTable::from_sql(sql, root_page as usize)?;
+                                table.db = schema.db;
                                 schema.add_btree_table(Rc::new(table));
                             }
                         }
@@ -69,13 +72,18 @@ pub fn parse_schema_rows(
                             let root_page: i64 = row.get::<i64>(3)?;
                             match row.get::<&str>(4) {
                                 Ok(sql) => {
-                                    let index = schema::Index::from_sql(sql, root_page as usize)?;
-                                    schema.add_index(Rc::new(index));
+                                    let table_name = row.get::<&str>(2)?;
+                                    indexes.push((
+                                        sql.to_string(),
+                                        table_name.to_string(),
+                                        root_page,
+                                    ));
                                 }
                                 _ => {
-                                    // Automatic index on primary key, e.g.
-                                    // table|foo|foo|2|CREATE TABLE foo (a text PRIMARY KEY, b)
+                                    // Automatic index on a primary key or unique constraint, e.g.
+                                    // table|foo|foo|2|CREATE TABLE foo (a text PRIMARY KEY, b UNIQUE)
                                     // index|sqlite_autoindex_foo_1|foo|3|
+                                    // index|sqlite_autoindex_foo_2|foo|4|
                                     let index_name = row.get::<&str>(1)?;
                                     let table_name = row.get::<&str>(2)?;
                                     let root_page = row.get::<i64>(3)?;
@@ -87,6 +95,16 @@ pub fn parse_schema_rows(
                                 }
                             }
                         }
+                        "view" => {
+                            let sql: &str = row.get::<&str>(4)?;
+                            let view = schema::View::from_sql(sql)?;
+                            schema.add_view(Rc::new(view));
+                        }
+                        "trigger" => {
+                            let sql: &str = row.get::<&str>(4)?;
+                            let trigger = schema::Trigger::from_sql(sql)?;
+                            schema.add_trigger(Rc::new(trigger));
+                        }
                         _ => continue,
                     }
                 }
@@ -100,11 +118,16 @@ pub fn parse_schema_rows(
                 StepResult::Busy => break,
             }
         }
+        // Indexes are processed after all tables are loaded into memory, as they need the columns of their table
+        for (sql, table_name, root_page) in indexes {
+            let table = schema.get_btree_table(&table_name).unwrap();
+            let mut index = schema::Index::from_sql(&sql, root_page as usize, &table)?;
+            index.db = schema.db;
+            schema.add_index(Rc::new(index));
+        }
         for (index_name, table_name, root_page) in automatic_indexes {
-            // We need to process these after all tables are loaded into memory due to the schema.get_table() call
             let table = schema.get_btree_table(&table_name).unwrap();
-            let index =
-                schema::Index::automatic_from_primary_key(&table, &index_name, root_page as usize)?;
+            let index = schema::Index::automatic(&table, &index_name, root_page as usize)?;
             schema.add_index(Rc::new(index));
         }
     }
@@ -259,13 +282,14 @@ pub fn exprs_are_equivalent(expr1: &Expr, expr2: &Expr) -> bool {
                             filter_clause: fc2,
                             over_clause: oc2,
                         }),
-                    ) => match ((fc1, fc2), (oc1, oc2)) {
-                        ((Some(fc1), Some(fc2)), (Some(oc1), Some(oc2))) => {
-                            exprs_are_equivalent(fc1, fc2) && oc1 == oc2
-                        }
-                        ((Some(fc1), Some(fc2)), _) => exprs_are_equivalent(fc1, fc2),
-                        _ => false,
-                    },
+                    ) => {
+                        let filters_match = match (fc1, fc2) {
+                            (Some(fc1), Some(fc2)) => exprs_are_equivalent(fc1, fc2),
+                            (None, None) => true,
+                            _ => false,
+                        };
+                        filters_match && oc1 == oc2
+                    }
                     _ => false,
                 }
         }
@@ -377,6 +401,7 @@ pub fn columns_from_create_table_body(body: &ast::CreateTableBody) -> crate::Res
                         }
                         _ => None,
                     }),
+                collation: None,
                 notnull: column_def.constraints.iter().any(|c| {
                     matches!(
                         c.constraint,
@@ -0,0 +1,322 @@
+//! VACUUM and VACUUM INTO.
+//!
+//! Both build a compact copy of a database in memory, by copying the rows of each of its b-trees
+//! into a new database in the order of the schema table, so that the copy has no free pages and its
+//! b-trees are packed. VACUUM INTO writes the pages of the copy to a new file. A plain VACUUM writes
+//! them over the pages of the database in a write transaction, so that they go through the WAL like
+//! any other change, then checkpoints the WAL and truncates the database file.
+
+use crate::schema::{Index, Schema, MAIN_DB, TEMP_DB};
+use crate::storage::btree::BTreeCursor;
+use crate::storage::database::{DatabaseStorage, FileStorage};
+use crate::storage::pager::{PageRef, Pager};
+use crate::storage::sqlite3_ondisk::begin_sync;
+use crate::types::{CursorResult, OwnedValue, Record};
+use crate::util::parse_schema_rows;
+use crate::{
+    open_memory_pager, AuxDatabase, CheckpointStatus, Completion, Connection, LimboError,
+    LimboResult, OpenFlags, Result, SymbolTable, WriteCompletion, IO,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+impl Connection {
+    /// Rebuild the database `db` so that it has no free pages or, if `into` is set, write a
+    /// compacted copy of it to a new database file at that path, leaving the database as it is.
+    pub(crate) fn vacuum(self: &Rc<Connection>, db: usize, into: Option<&str>) -> Result<()> {
+        if !*self.auto_commit.borrow() {
+            return Err(LimboError::TxError(
+                "cannot VACUUM from within a transaction".to_string(),
+            ));
+        }
+        let database = match db {
+            MAIN_DB => None,
+            db => Some(self.aux_database(db)?),
+        };
+        let database = database.as_deref();
+        let pager = database
+            .map_or(&self.pager, |database| &database.pager)
+            .clone();
+        let write = into.is_none();
+        if write {
+            // A cache flush left waiting for its I/O would resume in place of the one that commits
+            // the new pages, which would then never be written to the WAL.
+            loop {
+                match pager.cacheflush()? {
+                    CheckpointStatus::Done(_) => break,
+                    CheckpointStatus::IO => pager.io.run_once()?,
+                }
+            }
+        }
+        self.begin_vacuum(database, write)?;
+        let result = {
+            let schema = match database {
+                Some(database) => database.schema.borrow(),
+                None => self.schema.borrow(),
+            };
+            compact_copy(&pager, &schema, &self.db.syms.borrow()).and_then(|copy| match into {
+                Some(path) => write_database_file(&copy, &self.file_io()?, path),
+                None => overwrite_database(&pager, &copy),
+            })
+        };
+        self.end_vacuum(database, write, result.is_ok())?;
+        result?;
+        if write {
+            checkpoint_and_truncate(&pager)?;
+            self.reload_schema(database)?;
+        }
+        Ok(())
+    }
+
+    /// Begin the transaction VACUUM reads the database `database` in, none standing for the main
+    /// database, which is a write transaction if it rebuilds the database.
+    fn begin_vacuum(&self, database: Option<&AuxDatabase>, write: bool) -> Result<()> {
+        let result = match database {
+            Some(database) => database.begin(write)?,
+            None => match self.pager.begin_read_tx()? {
+                LimboResult::Ok if write => {
+                    let result = self.pager.begin_write_tx()?;
+                    if let LimboResult::Busy = result {
+                        self.pager.end_read_tx()?;
+                    }
+                    result
+                }
+                result => result,
+            },
+        };
+        if let LimboResult::Busy = result {
+            return Err(LimboError::LockingError("database is locked".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Commit or roll back the transaction begun by [Self::begin_vacuum].
+    fn end_vacuum(&self, database: Option<&AuxDatabase>, write: bool, commit: bool) -> Result<()> {
+        if let Some(database) = database {
+            return if commit {
+                database.commit()
+            } else {
+                database.rollback()
+            };
+        }
+        match (write, commit) {
+            (false, _) => self.pager.end_read_tx(),
+            (true, true) => loop {
+                match self.pager.end_tx()? {
+                    CheckpointStatus::Done(_) => return Ok(()),
+                    CheckpointStatus::IO => self.pager.io.run_once()?,
+                }
+            },
+            (true, false) => self.pager.rollback(),
+        }
+    }
+
+    /// Parse the schema of a database again once VACUUM has moved the root pages of its b-trees.
+    fn reload_schema(self: &Rc<Connection>, database: Option<&AuxDatabase>) -> Result<()> {
+        let syms = self.db.syms.borrow();
+        let Some(database) = database else {
+            let mut schema = Schema::new();
+            let rows = self.query("SELECT * FROM sqlite_schema")?;
+            parse_schema_rows(rows, &mut schema, self.pager.io.clone(), &syms)?;
+            self.schema.replace(schema);
+            let stats = self.read_stats("main")?;
+            self.schema.borrow_mut().stats = stats;
+            return Ok(());
+        };
+        let (mut schema, schema_table) = if database.db == TEMP_DB {
+            (Schema::new_temp(), "sqlite_temp_schema")
+        } else {
+            (
+                Schema::new_attached(database.db, &database.name),
+                "sqlite_schema",
+            )
+        };
+        let rows = self.query(format!("SELECT * FROM {}.{}", database.name, schema_table))?;
+        parse_schema_rows(rows, &mut schema, self.pager.io.clone(), &syms)?;
+        database.schema.replace(schema);
+        let stats = self.read_stats(&database.name)?;
+        database.schema.borrow_mut().stats = stats;
+        Ok(())
+    }
+}
+
+/// Copy the database of `pager`, whose schema is `schema`, into a new database in memory.
+fn compact_copy(pager: &Rc<Pager>, schema: &Schema, syms: &SymbolTable) -> Result<Rc<Pager>> {
+    let mut db_header = pager.db_header.borrow().clone();
+    db_header.database_size = 1;
+    db_header.freelist_trunk_page = 0;
+    db_header.freelist_pages = 0;
+    let copy = Rc::new(open_memory_pager("vacuum", &db_header)?);
+    copy.begin_read_tx()?;
+    copy.begin_write_tx()?;
+    copy_btrees(pager, schema, syms, &copy)?;
+    loop {
+        match copy.end_tx()? {
+            CheckpointStatus::Done(_) => return Ok(copy),
+            CheckpointStatus::IO => copy.io.run_once()?,
+        }
+    }
+}
+
+/// Copy the b-trees of the database of `source`, whose schema is `schema`, into the empty database
+/// of `target`, in the order of the schema table. The rows of the schema table are copied with the
+/// root pages of the new b-trees. The keys of the indexes are compared with the collations of `syms`.
+fn copy_btrees(
+    source: &Rc<Pager>,
+    schema: &Schema,
+    syms: &SymbolTable,
+    target: &Rc<Pager>,
+) -> Result<()> {
+    // The b-trees keyed by records rather than by rowid: the indexes and the WITHOUT ROWID tables.
+    let mut indexes: HashMap<usize, Rc<Index>> = HashMap::new();
+    for index in schema.indexes.values().flatten() {
+        indexes.insert(index.root_page, index.with_resolved_collations(syms)?);
+    }
+    for table in schema.tables.values() {
+        if let Some(index) = table.btree().and_then(|table| table.primary_key_index()) {
+            indexes.insert(index.root_page, index.with_resolved_collations(syms)?);
+        }
+    }
+    let mut schema_rows = BTreeCursor::new(source.clone(), 1);
+    let mut new_schema_rows = BTreeCursor::new(target.clone(), 1);
+    run_io(source, || schema_rows.rewind())?;
+    while !schema_rows.is_empty() {
+        let rowid = schema_rows.rowid()?.unwrap();
+        let mut values = schema_rows.record()?.as_ref().unwrap().get_values().clone();
+        if let OwnedValue::Integer(root_page) = values[3] {
+            if root_page != 0 {
+                let root_page = root_page as usize;
+                let index = indexes.get(&root_page).map(|index| index.as_ref());
+                let new_root_page = copy_btree(source, root_page, index, target)?;
+                values[3] = OwnedValue::Integer(new_root_page as i64);
+            }
+        }
+        let record = Record::new(values);
+        let key = OwnedValue::Integer(rowid as i64);
+        run_io(target, || new_schema_rows.insert(&key, &record, false))?;
+        run_io(source, || schema_rows.next())?;
+    }
+    Ok(())
+}
+
+/// Copy the b-tree at `root_page` of `source` into a new b-tree of `target`, returning its root
+/// page. `index` is the index whose records key the b-tree, none for a table keyed by rowid.
+fn copy_btree(
+    source: &Rc<Pager>,
+    root_page: usize,
+    index: Option<&Index>,
+    target: &Rc<Pager>,
+) -> Result<usize> {
+    let flags = if index.is_some() { 2 } else { 1 };
+    let new_root_page = BTreeCursor::new(target.clone(), 0).btree_create(flags) as usize;
+    let (mut rows, mut new_rows) = match index {
+        Some(index) => (
+            BTreeCursor::new_index(source.clone(), root_page, index),
+            BTreeCursor::new_index(target.clone(), new_root_page, index),
+        ),
+        None => (
+            BTreeCursor::new(source.clone(), root_page),
+            BTreeCursor::new(target.clone(), new_root_page),
+        ),
+    };
+    run_io(source, || rows.rewind())?;
+    while !rows.is_empty() {
+        let record = rows.record()?.clone().unwrap();
+        if index.is_some() {
+            run_io(target, || new_rows.insert_index_key(&record))?;
+        } else {
+            let key = OwnedValue::Integer(rows.rowid()?.unwrap() as i64);
+            run_io(target, || new_rows.insert(&key, &record, false))?;
+        }
+        run_io(source, || rows.next())?;
+    }
+    Ok(new_root_page)
+}
+
+/// Replace the pages of the database of `pager` with the ones of `copy`, in the write transaction
+/// of `pager`. The pages past the end of the copy are left out of the database.
+fn overwrite_database(pager: &Pager, copy: &Pager) -> Result<()> {
+    let num_pages = copy.db_header.borrow().database_size as usize;
+    for page_id in 1..=num_pages {
+        let page = read_page(copy, page_id)?;
+        pager.overwrite_page(page_id, page.get().contents.as_ref().unwrap().as_ptr());
+    }
+    pager.db_header.replace(copy.db_header.borrow().clone());
+    Ok(())
+}
+
+/// Write the pages of `copy` to a new database file at `path`.
+fn write_database_file(copy: &Pager, io: &Arc<dyn IO>, path: &str) -> Result<()> {
+    let file = io.open_file(path, OpenFlags::Create, false)?;
+    if file.size()? > 0 {
+        return Err(LimboError::InvalidArgument(
+            "output file already exists".to_string(),
+        ));
+    }
+    let storage: Rc<dyn DatabaseStorage> = Rc::new(FileStorage::new(file));
+    let num_pages = copy.db_header.borrow().database_size as usize;
+    for page_id in 1..=num_pages {
+        let page = read_page(copy, page_id)?;
+        let buffer = page.get().contents.as_ref().unwrap().buffer.clone();
+        let written = Rc::new(RefCell::new(false));
+        let completion = {
+            let written = written.clone();
+            Completion::Write(WriteCompletion::new(Box::new(move |_| {
+                *written.borrow_mut() = true;
+            })))
+        };
+        storage.write_page(page_id, buffer, completion)?;
+        while !*written.borrow() {
+            io.run_once()?;
+        }
+    }
+    let syncing = Rc::new(RefCell::new(false));
+    begin_sync(storage.clone(), syncing.clone())?;
+    while *syncing.borrow() {
+        io.run_once()?;
+    }
+    Ok(())
+}
+
+/// Write the WAL of `pager` back to the database file, and shrink the file to the size of the
+/// database unless readers kept part of the WAL from being written back.
+fn checkpoint_and_truncate(pager: &Pager) -> Result<()> {
+    let checkpoint_result = loop {
+        match pager.checkpoint()? {
+            CheckpointStatus::Done(checkpoint_result) => break checkpoint_result,
+            CheckpointStatus::IO => pager.io.run_once()?,
+        }
+    };
+    if checkpoint_result.num_checkpointed_frames == checkpoint_result.num_wal_frames {
+        let num_pages = pager.db_header.borrow().database_size as usize;
+        pager.truncate(num_pages)?;
+    }
+    Ok(())
+}
+
+/// Read a page, waiting for the read to complete.
+fn read_page(pager: &Pager, page_id: usize) -> Result<PageRef> {
+    let page = pager.read_page(page_id)?;
+    if !page.is_loaded() {
+        pager.load_page(page.clone())?;
+    }
+    while page.is_locked() {
+        pager.io.run_once()?;
+    }
+    Ok(page)
+}
+
+/// Run a cursor operation to completion, running the I/O of `pager` while it waits on it.
+pub(crate) fn run_io<T>(
+    pager: &Pager,
+    mut op: impl FnMut() -> Result<CursorResult<T>>,
+) -> Result<T> {
+    loop {
+        match op()? {
+            CursorResult::Ok(value) => return Ok(value),
+            CursorResult::IO => pager.io.run_once()?,
+        }
+    }
+}
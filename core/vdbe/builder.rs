@@ -6,13 +6,13 @@ use std::{
 
 use crate::{
     parameters::Parameters,
-    schema::{BTreeTable, Index, PseudoTable},
+    schema::{BTreeTable, Index, PseudoTable, MAIN_DB},
     storage::sqlite3_ondisk::DatabaseHeader,
     translate::plan::{ResultSetColumn, TableReference},
-    Connection, VirtualTable,
+    Connection, Result, SymbolTable, VirtualTable,
 };
 
-use super::{BranchOffset, CursorID, Insn, InsnReference, Program};
+use super::{insn::OnError, BranchOffset, CursorID, Insn, InsnReference, Program};
 #[allow(dead_code)]
 pub struct ProgramBuilder {
     next_free_register: usize,
@@ -33,6 +33,18 @@ pub struct ProgramBuilder {
     pub parameters: Parameters,
     pub result_columns: Vec<ResultSetColumn>,
     pub table_references: Vec<TableReference>,
+    /// The trigger programs run by the Program instructions of this program.
+    sub_programs: Vec<ProgramBuilder>,
+    /// The triggers whose programs are being compiled, outermost first. A trigger does not fire
+    /// again from its own program, and RAISE() can only be used in a trigger program.
+    pub active_triggers: Vec<String>,
+    /// Whether the program enforces FOREIGN KEY constraints, as PRAGMA foreign_keys was on when it
+    /// was compiled.
+    pub foreign_keys: bool,
+    /// The FOREIGN KEY actions whose programs are being compiled, outermost first, and the one of
+    /// this program. An action that fires again from its own program runs that program again.
+    pub active_fk_actions: Vec<String>,
+    pub fk_action: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -42,12 +54,31 @@ pub enum CursorType {
     Pseudo(Rc<PseudoTable>),
     Sorter,
     VirtualTable(Rc<VirtualTable>),
+    Ephemeral,
 }
 
 impl CursorType {
+    /// The type of a cursor over the rows of `table`. The rows of a WITHOUT ROWID table are
+    /// stored in the b-tree of its primary key index.
+    pub fn for_table(table: Rc<BTreeTable>) -> Self {
+        match table.primary_key_index() {
+            Some(index) => CursorType::BTreeIndex(index),
+            None => CursorType::BTreeTable(table),
+        }
+    }
+
     pub fn is_index(&self) -> bool {
         matches!(self, CursorType::BTreeIndex(_))
     }
+
+    /// The database of the b-tree a table or index cursor reads.
+    pub fn db(&self) -> usize {
+        match self {
+            CursorType::BTreeTable(table) => table.db,
+            CursorType::BTreeIndex(index) => index.db,
+            _ => MAIN_DB,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -82,9 +113,20 @@ impl ProgramBuilder {
             parameters: Parameters::new(),
             result_columns: Vec::new(),
             table_references: Vec::new(),
+            sub_programs: Vec::new(),
+            active_triggers: Vec::new(),
+            foreign_keys: false,
+            active_fk_actions: Vec::new(),
+            fk_action: None,
         }
     }
 
+    /// Adds a trigger program to be run by a Program instruction, and returns its index.
+    pub fn add_sub_program(&mut self, sub_program: ProgramBuilder) -> usize {
+        self.sub_programs.push(sub_program);
+        self.sub_programs.len() - 1
+    }
+
     pub fn alloc_register(&mut self) -> usize {
         let reg = self.next_free_register;
         self.next_free_register += 1;
@@ -153,17 +195,15 @@ impl ProgramBuilder {
         self.emit_insn(Insn::Halt {
             err_code: 0,
             description: String::new(),
+            on_error: OnError::Abort,
         });
     }
 
-    // no users yet, but I want to avoid someone else in the future
-    // just adding parameters to emit_halt! If you use this, remove the
-    // clippy warning please.
-    #[allow(dead_code)]
     pub fn emit_halt_err(&mut self, err_code: usize, description: String) {
         self.emit_insn(Insn::Halt {
             err_code,
             description,
+            on_error: OnError::Abort,
         });
     }
 
@@ -340,6 +380,20 @@ impl ProgramBuilder {
                 Insn::SorterSort { pc_if_empty, .. } => {
                     resolve(pc_if_empty, "SorterSort");
                 }
+                Insn::EphemeralFound { target_pc, .. } => {
+                    resolve(target_pc, "EphemeralFound");
+                }
+                Insn::EphemeralRewind { pc_if_empty, .. } => {
+                    resolve(pc_if_empty, "EphemeralRewind");
+                }
+                Insn::EphemeralNext { pc_if_next, .. } => {
+                    resolve(pc_if_next, "EphemeralNext");
+                }
+                Insn::SorterSeek {
+                    pc_if_out_of_range, ..
+                } => {
+                    resolve(pc_if_out_of_range, "SorterSeek");
+                }
                 Insn::NotNull {
                     reg: _reg,
                     target_pc,
@@ -369,6 +423,9 @@ impl ProgramBuilder {
                 } => {
                     resolve(target_pc, "NotExists");
                 }
+                Insn::NoConflict { target_pc, .. } => {
+                    resolve(target_pc, "NoConflict");
+                }
                 Insn::Yield {
                     yield_reg: _,
                     end_offset,
@@ -417,6 +474,12 @@ impl ProgramBuilder {
                 Insn::VFilter { pc_if_empty, .. } => {
                     resolve(pc_if_empty, "VFilter");
                 }
+                Insn::Program { ignore_jump, .. } => {
+                    resolve(ignore_jump, "Program");
+                }
+                Insn::FkIfZero { target_pc, .. } => {
+                    resolve(target_pc, "FkIfZero");
+                }
                 _ => {}
             }
         }
@@ -424,10 +487,13 @@ impl ProgramBuilder {
     }
 
     // translate table to cursor id
+    // A subquery can open a cursor on a table with the same identifier as a table of the enclosing
+    // query. Subqueries are emitted before the loops of the enclosing query are opened, so the most
+    // recently opened cursor is the one that belongs to the query being emitted.
     pub fn resolve_cursor_id(&self, table_identifier: &str) -> CursorID {
         self.cursor_ref
             .iter()
-            .position(|(t_ident, _)| {
+            .rposition(|(t_ident, _)| {
                 t_ident
                     .as_ref()
                     .is_some_and(|ident| ident == table_identifier)
@@ -435,6 +501,43 @@ impl ProgramBuilder {
             .unwrap()
     }
 
+    /// Looks up the functions of the registered collations that the program compares text with,
+    /// which fails if one of them is not registered.
+    pub fn resolve_collations(&mut self, syms: &SymbolTable) -> Result<()> {
+        for insn in self.insns.iter_mut() {
+            match insn {
+                Insn::Eq { collation, .. }
+                | Insn::Ne { collation, .. }
+                | Insn::Lt { collation, .. }
+                | Insn::Le { collation, .. }
+                | Insn::Gt { collation, .. }
+                | Insn::Ge { collation, .. }
+                | Insn::AggStep { collation, .. } => {
+                    if let Some(collation) = collation {
+                        collation.resolve(syms)?;
+                    }
+                }
+                Insn::Compare { collations, .. }
+                | Insn::SorterOpen { collations, .. }
+                | Insn::OpenEphemeral { collations, .. } => {
+                    for collation in collations.iter_mut().flatten() {
+                        collation.resolve(syms)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (_, cursor_type) in self.cursor_ref.iter_mut() {
+            if let CursorType::BTreeIndex(index) = cursor_type {
+                *index = index.with_resolved_collations(syms)?;
+            }
+        }
+        for sub_program in self.sub_programs.iter_mut() {
+            sub_program.resolve_collations(syms)?;
+        }
+        Ok(())
+    }
+
     pub fn build(
         mut self,
         database_header: Rc<RefCell<DatabaseHeader>>,
@@ -447,6 +550,13 @@ impl ProgramBuilder {
             "constant_insns is not empty when build() is called, did you forget to call emit_constant_insns()?"
         );
         self.parameters.list.dedup();
+        let sub_programs = self
+            .sub_programs
+            .into_iter()
+            .map(|sub_program| {
+                sub_program.build(database_header.clone(), connection.clone(), false)
+            })
+            .collect();
         Program {
             max_registers: self.next_free_register,
             insns: self.insns,
@@ -459,6 +569,7 @@ impl ProgramBuilder {
             change_cnt_on,
             result_columns: self.result_columns,
             table_references: self.table_references,
+            sub_programs,
         }
     }
 }
@@ -0,0 +1,131 @@
+use crate::translate::collate::CollationSeq;
+use crate::types::{compare_index_keys, IndexKeyInfo, OwnedValue, Record};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+/// The rows of an ephemeral table, which is an in-memory table that only lives as long as the statement.
+/// Rows are kept in insertion order, so an ephemeral table can also be used as a queue:
+/// rows inserted while a cursor iterates over the table are visited by that cursor too.
+struct EphemeralRows {
+    records: Vec<Record>,
+    /// the rows inserted so far, if duplicate rows are discarded on insertion
+    distinct: Option<BTreeSet<DistinctRow>>,
+}
+
+/// A row of a table that discards duplicate rows, which compares with the collation of each column.
+struct DistinctRow {
+    record: Record,
+    key_info: Rc<IndexKeyInfo>,
+}
+
+impl PartialEq for DistinctRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for DistinctRow {}
+
+impl PartialOrd for DistinctRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DistinctRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_index_keys(
+            self.record.get_values(),
+            other.record.get_values(),
+            &self.key_info,
+        )
+        .then_with(|| self.record.len().cmp(&other.record.len()))
+    }
+}
+
+/// A cursor over an ephemeral table.
+/// Several cursors can share the rows of the same table (see the OpenDup instruction),
+/// each with its own position.
+pub struct EphemeralCursor {
+    rows: Rc<RefCell<EphemeralRows>>,
+    /// how the columns of the rows compare when duplicate rows are discarded
+    key_info: Rc<IndexKeyInfo>,
+    /// index of the current row
+    current: usize,
+}
+
+impl EphemeralCursor {
+    /// `collations` are the collations of the columns that rows are compared with to find duplicates.
+    pub fn new(distinct: bool, collations: Vec<Option<CollationSeq>>) -> Self {
+        Self {
+            rows: Rc::new(RefCell::new(EphemeralRows {
+                records: Vec::new(),
+                distinct: distinct.then(BTreeSet::new),
+            })),
+            key_info: Rc::new(IndexKeyInfo::ascending(collations)),
+            current: 0,
+        }
+    }
+
+    fn distinct_row(&self, record: &Record) -> DistinctRow {
+        DistinctRow {
+            record: record.clone(),
+            key_info: self.key_info.clone(),
+        }
+    }
+
+    /// Returns a new cursor over the same rows as this one, positioned at the first row.
+    pub fn duplicate(&self) -> Self {
+        Self {
+            rows: self.rows.clone(),
+            key_info: self.key_info.clone(),
+            current: 0,
+        }
+    }
+
+    pub fn rewind(&mut self) {
+        self.current = 0;
+    }
+
+    pub fn next(&mut self) {
+        self.current += 1;
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.current < self.rows.borrow().records.len()
+    }
+
+    /// Returns the value of the given column of the current row, if the cursor points at a row.
+    pub fn column(&self, column: usize) -> Option<OwnedValue> {
+        self.rows
+            .borrow()
+            .records
+            .get(self.current)
+            .map(|record| record.get_value(column).clone())
+    }
+
+    /// Appends a row to the table, unless the table discards duplicate rows and already has this one.
+    pub fn insert(&mut self, record: &Record) {
+        let row = self.distinct_row(record);
+        let mut rows = self.rows.borrow_mut();
+        if let Some(distinct) = rows.distinct.as_mut() {
+            if !distinct.insert(row) {
+                return;
+            }
+        }
+        rows.records.push(record.clone());
+    }
+
+    /// Returns whether the table has the given row. Only a table that discards duplicate rows can
+    /// be searched.
+    pub fn contains(&self, record: &Record) -> bool {
+        self.rows
+            .borrow()
+            .distinct
+            .as_ref()
+            .expect("only a table that discards duplicate rows can be searched")
+            .contains(&self.distinct_row(record))
+    }
+}
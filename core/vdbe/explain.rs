@@ -1,6 +1,7 @@
+use crate::translate::collate::CollationSeq;
 use crate::vdbe::builder::CursorType;
 
-use super::{Insn, InsnReference, OwnedValue, Program};
+use super::{insn::RegisterOrLiteral, Insn, InsnReference, OwnedValue, Program};
 use std::rc::Rc;
 
 pub fn insn_to_str(
@@ -139,6 +140,7 @@ pub fn insn_to_str(
                 start_reg_a,
                 start_reg_b,
                 count,
+                ..
             } => (
                 "Compare",
                 *start_reg_a as i32,
@@ -209,13 +211,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Eq",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!(
                     "if r[{}]==r[{}] goto {}",
@@ -228,13 +231,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Ne",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!(
                     "if r[{}]!=r[{}] goto {}",
@@ -247,13 +251,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Lt",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!("if r[{}]<r[{}] goto {}", lhs, rhs, target_pc.to_debug_int()),
             ),
@@ -261,13 +266,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Le",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!(
                     "if r[{}]<=r[{}] goto {}",
@@ -280,13 +286,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Gt",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!("if r[{}]>r[{}] goto {}", lhs, rhs, target_pc.to_debug_int()),
             ),
@@ -294,13 +301,14 @@ pub fn insn_to_str(
                 lhs,
                 rhs,
                 target_pc,
+                collation,
                 ..
             } => (
                 "Ge",
                 *lhs as i32,
                 *rhs as i32,
                 target_pc.to_debug_int(),
-                OwnedValue::build_text(""),
+                OwnedValue::build_text(collation.as_ref().map_or("", |c| c.name())),
                 0,
                 format!(
                     "if r[{}]>=r[{}] goto {}",
@@ -507,7 +515,7 @@ pub fn insn_to_str(
                         let name = pseudo_table.columns.get(*column).unwrap().name.as_ref();
                         name
                     }
-                    CursorType::Sorter => None,
+                    CursorType::Sorter | CursorType::Ephemeral => None,
                     CursorType::VirtualTable(v) => v.columns.get(*column).unwrap().name.as_ref(),
                 };
                 (
@@ -582,10 +590,11 @@ pub fn insn_to_str(
             Insn::Halt {
                 err_code,
                 description: _,
+                on_error,
             } => (
                 "Halt",
                 *err_code as i32,
-                0,
+                *on_error as i32,
                 0,
                 OwnedValue::build_text(""),
                 0,
@@ -829,6 +838,7 @@ pub fn insn_to_str(
                 acc_reg,
                 delimiter: _,
                 col,
+                collation: _,
             } => (
                 "AggStep",
                 0,
@@ -851,20 +861,25 @@ pub fn insn_to_str(
                 cursor_id,
                 columns,
                 order,
+                collations,
             } => {
                 let _p4 = String::new();
                 let to_print: Vec<String> = order
                     .get_values()
                     .iter()
-                    .map(|v| match v {
-                        OwnedValue::Integer(i) => {
-                            if *i == 0 {
-                                "B".to_string()
-                            } else {
-                                "-B".to_string()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        let collation = match collations.get(i) {
+                            Some(Some(collation)) if *collation != CollationSeq::Binary => {
+                                collation.name()
                             }
+                            _ => "B",
+                        };
+                        match v {
+                            OwnedValue::Integer(0) => collation.to_string(),
+                            OwnedValue::Integer(_) => format!("-{}", collation),
+                            _ => unreachable!(),
                         }
-                        _ => unreachable!(),
                     })
                     .collect();
                 (
@@ -926,6 +941,98 @@ pub fn insn_to_str(
                 0,
                 "".to_string(),
             ),
+            Insn::SorterSeek {
+                cursor_id,
+                row_reg,
+                pc_if_out_of_range,
+            } => (
+                "SorterSeek",
+                *cursor_id as i32,
+                pc_if_out_of_range.to_debug_int(),
+                *row_reg as i32,
+                OwnedValue::build_text(""),
+                0,
+                format!("row=r[{}]", row_reg),
+            ),
+            Insn::OpenEphemeral {
+                cursor_id,
+                distinct,
+                ..
+            } => (
+                "OpenEphemeral",
+                *cursor_id as i32,
+                0,
+                0,
+                OwnedValue::build_text(""),
+                *distinct as u16,
+                "".to_string(),
+            ),
+            Insn::OpenDup {
+                new_cursor_id,
+                original_cursor_id,
+            } => (
+                "OpenDup",
+                *new_cursor_id as i32,
+                *original_cursor_id as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                "".to_string(),
+            ),
+            Insn::EphemeralInsert {
+                cursor_id,
+                record_reg,
+            } => (
+                "EphemeralInsert",
+                *cursor_id as i32,
+                *record_reg as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("key=r[{}]", record_reg),
+            ),
+            Insn::EphemeralFound {
+                cursor_id,
+                record_reg,
+                target_pc,
+            } => (
+                "EphemeralFound",
+                *cursor_id as i32,
+                target_pc.to_debug_int(),
+                *record_reg as i32,
+                OwnedValue::build_text(""),
+                0,
+                format!(
+                    "if r[{}] in cursor {} goto {}",
+                    record_reg,
+                    cursor_id,
+                    target_pc.to_debug_int()
+                ),
+            ),
+            Insn::EphemeralRewind {
+                cursor_id,
+                pc_if_empty,
+            } => (
+                "EphemeralRewind",
+                *cursor_id as i32,
+                pc_if_empty.to_debug_int(),
+                0,
+                OwnedValue::build_text(""),
+                0,
+                "".to_string(),
+            ),
+            Insn::EphemeralNext {
+                cursor_id,
+                pc_if_next,
+            } => (
+                "EphemeralNext",
+                *cursor_id as i32,
+                pc_if_next.to_debug_int(),
+                0,
+                OwnedValue::build_text(""),
+                0,
+                "".to_string(),
+            ),
             Insn::Function {
                 constant_mask,
                 start_reg,
@@ -996,7 +1103,7 @@ pub fn insn_to_str(
                 *record_reg as i32,
                 *key_reg as i32,
                 OwnedValue::build_text(""),
-                *flag as u16,
+                flag.get() as u16,
                 "".to_string(),
             ),
             Insn::InsertAwait { cursor_id } => (
@@ -1017,15 +1124,76 @@ pub fn insn_to_str(
                 0,
                 "".to_string(),
             ),
-            Insn::DeleteAwait { cursor_id } => (
+            Insn::DeleteAwait {
+                cursor_id,
+                skip_nchange,
+            } => (
                 "DeleteAwait",
                 *cursor_id as i32,
+                *skip_nchange as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                "".to_string(),
+            ),
+            Insn::IdxInsertAsync {
+                cursor_id,
+                record_reg,
+                nchange,
+            } => (
+                "IdxInsertAsync",
+                *cursor_id as i32,
+                *record_reg as i32,
+                0,
+                OwnedValue::build_text(""),
+                *nchange as u16,
+                format!("key=r[{}]", record_reg),
+            ),
+            Insn::IdxInsertAwait { cursor_id } => (
+                "IdxInsertAwait",
+                *cursor_id as i32,
                 0,
                 0,
                 OwnedValue::build_text(""),
                 0,
                 "".to_string(),
             ),
+            Insn::IdxDelete {
+                cursor_id,
+                start_reg,
+                num_regs,
+            } => (
+                "IdxDelete",
+                *cursor_id as i32,
+                *start_reg as i32,
+                *num_regs as i32,
+                OwnedValue::build_text(""),
+                0,
+                format!("key=r[{}..{}]", start_reg, start_reg + num_regs - 1),
+            ),
+            Insn::IdxRowId { cursor_id, dest } => (
+                "IdxRowid",
+                *cursor_id as i32,
+                *dest as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("r[{}]=rowid", dest),
+            ),
+            Insn::NoConflict {
+                cursor_id,
+                target_pc,
+                record_reg,
+                num_regs,
+            } => (
+                "NoConflict",
+                *cursor_id as i32,
+                target_pc.to_debug_int(),
+                *record_reg as i32,
+                OwnedValue::build_text(&format!("{num_regs}")),
+                0,
+                format!("key=r[{}..{}]", record_reg, record_reg + num_regs - 1),
+            ),
             Insn::NewRowid {
                 cursor,
                 rowid_reg,
@@ -1092,11 +1260,17 @@ pub fn insn_to_str(
             } => (
                 "OpenWriteAsync",
                 *cursor_id as i32,
-                *root_page as i32,
+                match root_page {
+                    RegisterOrLiteral::Literal(page) => *page as i32,
+                    RegisterOrLiteral::Register(reg) => *reg as i32,
+                },
                 0,
                 OwnedValue::build_text(""),
                 0,
-                "".to_string(),
+                match root_page {
+                    RegisterOrLiteral::Literal(page) => format!("root={}", page),
+                    RegisterOrLiteral::Register(reg) => format!("root=r[{}]", reg),
+                },
             ),
             Insn::OpenWriteAwait {} => (
                 "OpenWriteAwait",
@@ -1165,6 +1339,155 @@ pub fn insn_to_str(
                 0,
                 where_clause.clone(),
             ),
+            Insn::Destroy {
+                root,
+                former_root_reg,
+                db,
+            } => (
+                "Destroy",
+                *root as i32,
+                *former_root_reg as i32,
+                *db as i32,
+                OwnedValue::build_text(""),
+                0,
+                format!("root={} iDb={}", root, db),
+            ),
+            Insn::Attach { path_reg, name_reg } => (
+                "Attach",
+                *path_reg as i32,
+                *name_reg as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("attach(r[{}]) AS r[{}]", path_reg, name_reg),
+            ),
+            Insn::Detach { name_reg } => (
+                "Detach",
+                *name_reg as i32,
+                0,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("detach(r[{}])", name_reg),
+            ),
+            Insn::Vacuum { db, into_reg } => (
+                "Vacuum",
+                *db as i32,
+                into_reg.map_or(0, |reg| reg as i32),
+                0,
+                OwnedValue::build_text(""),
+                0,
+                match into_reg {
+                    Some(reg) => format!("vacuum(iDb={}) INTO r[{}]", db, reg),
+                    None => format!("vacuum(iDb={})", db),
+                },
+            ),
+            Insn::Analyze { db, table_name } => (
+                "Analyze",
+                *db as i32,
+                0,
+                0,
+                OwnedValue::build_text(table_name.as_deref().unwrap_or("")),
+                0,
+                match table_name {
+                    Some(table_name) => format!("analyze(iDb={}) {}", db, table_name),
+                    None => format!("analyze(iDb={})", db),
+                },
+            ),
+            Insn::DropTable { db, table_name } => (
+                "DropTable",
+                *db as i32,
+                0,
+                0,
+                OwnedValue::build_text(table_name),
+                0,
+                format!("DROP TABLE {}", table_name),
+            ),
+            Insn::DropView { db, view_name } => (
+                "DropView",
+                *db as i32,
+                0,
+                0,
+                OwnedValue::build_text(view_name),
+                0,
+                format!("DROP VIEW {}", view_name),
+            ),
+            Insn::DropTrigger { db, trigger_name } => (
+                "DropTrigger",
+                *db as i32,
+                0,
+                0,
+                OwnedValue::build_text(trigger_name),
+                0,
+                format!("DROP TRIGGER {}", trigger_name),
+            ),
+            Insn::Program {
+                params_start_reg,
+                num_params,
+                sub_program,
+                ignore_jump,
+            } => (
+                "Program",
+                *params_start_reg as i32,
+                ignore_jump.to_debug_int(),
+                *num_params as i32,
+                OwnedValue::build_text(&match sub_program {
+                    Some(sub_program) => format!("program {}", sub_program),
+                    None => "program self".to_string(),
+                }),
+                0,
+                format!(
+                    "call trigger program with r[{}..{}]",
+                    params_start_reg,
+                    params_start_reg + num_params - 1
+                ),
+            ),
+            Insn::FkCounter {
+                deferred,
+                increment,
+            } => (
+                "FkCounter",
+                *deferred as i32,
+                *increment as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("fkctr[{}] += {}", *deferred as i32, increment),
+            ),
+            Insn::FkIfZero {
+                deferred,
+                target_pc,
+            } => (
+                "FkIfZero",
+                *deferred as i32,
+                target_pc.to_debug_int(),
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!(
+                    "if fkctr[{}] == 0 goto {}",
+                    *deferred as i32,
+                    target_pc.to_debug_int()
+                ),
+            ),
+            Insn::Param { offset, dest } => (
+                "Param",
+                *offset as i32,
+                *dest as i32,
+                0,
+                OwnedValue::build_text(""),
+                0,
+                format!("r[{}]=param[{}]", dest, offset),
+            ),
+            Insn::DropIndex { index, db } => (
+                "DropIndex",
+                *db as i32,
+                0,
+                0,
+                OwnedValue::build_text(&index.name),
+                0,
+                format!("DROP INDEX {}", index.name),
+            ),
             Insn::LastAwait { .. } => (
                 "LastAwait",
                 0,
@@ -1306,6 +1629,15 @@ pub fn insn_to_str(
                 0,
                 format!("auto_commit={}, rollback={}", auto_commit, rollback),
             ),
+            Insn::Savepoint { op, name } => (
+                "Savepoint",
+                *op as i32,
+                0,
+                0,
+                OwnedValue::build_text(name),
+                0,
+                format!("{:?} savepoint {}", op, name),
+            ),
         };
     format!(
         "{:<4}  {:<17}  {:<4}  {:<4}  {:<4}  {:<13}  {:<2}  {}",
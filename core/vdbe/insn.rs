@@ -1,9 +1,13 @@
 use std::num::NonZero;
+use std::rc::Rc;
 
 use super::{cast_text_to_numeric, AggFunc, BranchOffset, CursorID, FuncCtx, PageIdx};
+use crate::schema::Index;
 use crate::storage::wal::CheckpointMode;
+use crate::translate::collate::CollationSeq;
 use crate::types::{OwnedValue, Record};
 use limbo_macros::Description;
+use limbo_sqlite3_parser::ast::ResolveType;
 
 macro_rules! final_agg_values {
     ($var:ident) => {
@@ -48,6 +52,58 @@ impl CmpInsFlags {
     }
 }
 
+/// Flags provided to the InsertAsync instruction which determine how the insert affects the
+/// connection's change counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InsertFlags(u8);
+
+impl InsertFlags {
+    /// The insert is part of an UPDATE, so last_insert_rowid() is left untouched.
+    pub const UPDATE: u8 = 0x01;
+    /// The insert is not counted by changes() and total_changes().
+    pub const SKIP_NCHANGE: u8 = 0x02;
+
+    fn has(&self, flag: u8) -> bool {
+        (self.0 & flag) != 0
+    }
+
+    pub fn update(mut self) -> Self {
+        self.0 |= InsertFlags::UPDATE;
+        self
+    }
+
+    pub fn skip_nchange(mut self) -> Self {
+        self.0 |= InsertFlags::SKIP_NCHANGE;
+        self
+    }
+
+    pub fn is_update(&self) -> bool {
+        self.has(InsertFlags::UPDATE)
+    }
+
+    pub fn has_skip_nchange(&self) -> bool {
+        self.has(InsertFlags::SKIP_NCHANGE)
+    }
+
+    pub fn get(&self) -> u8 {
+        self.0
+    }
+}
+
+/// An operand that is either known when the program is built or only computed at runtime,
+/// e.g. the root page of a b-tree created by the same statement.
+#[derive(Clone, Copy, Debug)]
+pub enum RegisterOrLiteral<T: Copy> {
+    Register(usize),
+    Literal(T),
+}
+
+impl From<PageIdx> for RegisterOrLiteral<PageIdx> {
+    fn from(value: PageIdx) -> Self {
+        RegisterOrLiteral::Literal(value)
+    }
+}
+
 #[derive(Description, Debug)]
 pub enum Insn {
     // Initialize the program state and jump to the given PC.
@@ -92,6 +148,8 @@ pub enum Insn {
         start_reg_a: usize,
         start_reg_b: usize,
         count: usize,
+        /// P4. The collation of each pair of registers, BINARY if there is none.
+        collations: Vec<Option<CollationSeq>>,
     },
     // Place the result of rhs bitwise AND lhs in third register.
     BitAnd {
@@ -158,6 +216,8 @@ pub enum Insn {
         /// Without the jump_if_null flag it would not jump because the logical comparison "id != NULL" is never true.
         /// This flag indicates that if either is null we should still jump.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     // Compare two registers and jump to the given PC if they are not equal.
     Ne {
@@ -168,6 +228,8 @@ pub enum Insn {
         ///
         /// jump_if_null jumps if either of the operands is null. Used for "jump when false" logic.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     // Compare two registers and jump to the given PC if the left-hand side is less than the right-hand side.
     Lt {
@@ -176,6 +238,8 @@ pub enum Insn {
         target_pc: BranchOffset,
         /// jump_if_null: Jump if either of the operands is null. Used for "jump when false" logic.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     // Compare two registers and jump to the given PC if the left-hand side is less than or equal to the right-hand side.
     Le {
@@ -184,6 +248,8 @@ pub enum Insn {
         target_pc: BranchOffset,
         /// jump_if_null: Jump if either of the operands is null. Used for "jump when false" logic.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     // Compare two registers and jump to the given PC if the left-hand side is greater than the right-hand side.
     Gt {
@@ -192,6 +258,8 @@ pub enum Insn {
         target_pc: BranchOffset,
         /// jump_if_null: Jump if either of the operands is null. Used for "jump when false" logic.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     // Compare two registers and jump to the given PC if the left-hand side is greater than or equal to the right-hand side.
     Ge {
@@ -200,6 +268,8 @@ pub enum Insn {
         target_pc: BranchOffset,
         /// jump_if_null: Jump if either of the operands is null. Used for "jump when false" logic.
         flags: CmpInsFlags,
+        /// The collation of the comparison of two text values, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
     /// Jump to target_pc if r\[reg\] != 0 or (r\[reg\] == NULL && r\[jump_if_null\] != 0)
     If {
@@ -341,6 +411,8 @@ pub enum Insn {
     Halt {
         err_code: usize,
         description: String,
+        /// On an error, how the changes made so far are undone.
+        on_error: OnError,
     },
 
     // Start a transaction.
@@ -354,6 +426,12 @@ pub enum Insn {
         rollback: bool,
     },
 
+    // Open (P1=0), release (P1=1) or roll back to (P1=2) the savepoint named P4.
+    Savepoint {
+        op: SavepointOp,
+        name: String,
+    },
+
     // Branch to the given PC.
     Goto {
         target_pc: BranchOffset,
@@ -488,6 +566,8 @@ pub enum Insn {
         col: usize,
         delimiter: usize,
         func: AggFunc,
+        /// P4. The collation min() and max() compare text with, BINARY if there is none.
+        collation: Option<CollationSeq>,
     },
 
     AggFinal {
@@ -500,6 +580,8 @@ pub enum Insn {
         cursor_id: CursorID, // P1
         columns: usize,      // P2
         order: Record,       // P4. 0 if ASC and 1 if DESC
+        /// P4. The collation of each sort key, BINARY if there is none.
+        collations: Vec<Option<CollationSeq>>,
     },
 
     // Insert a row into the sorter.
@@ -527,6 +609,54 @@ pub enum Insn {
         pc_if_next: BranchOffset,
     },
 
+    // Move to the row of the sorted sorter whose 0-based position is in row_reg,
+    // or jump to pc_if_out_of_range if there is no such row.
+    SorterSeek {
+        cursor_id: CursorID,
+        row_reg: usize,
+        pc_if_out_of_range: BranchOffset,
+    },
+
+    // Open a new ephemeral table, which is an in-memory table that is dropped when the statement ends.
+    // If distinct is set, inserting a row that is already in the table does nothing.
+    // Text columns are compared with the collations in collations, BINARY if there is none.
+    OpenEphemeral {
+        cursor_id: CursorID,
+        distinct: bool,
+        collations: Vec<Option<CollationSeq>>,
+    },
+
+    // Open a new cursor over the same ephemeral table as original_cursor_id.
+    OpenDup {
+        new_cursor_id: CursorID,
+        original_cursor_id: CursorID,
+    },
+
+    // Append the record in record_reg to an ephemeral table.
+    EphemeralInsert {
+        cursor_id: CursorID,
+        record_reg: usize,
+    },
+
+    // Jump to target_pc if the ephemeral table, which must discard duplicate rows, has the record in record_reg.
+    EphemeralFound {
+        cursor_id: CursorID,
+        record_reg: usize,
+        target_pc: BranchOffset,
+    },
+
+    // Move to the first row of an ephemeral table, or jump to pc_if_empty if it has no rows.
+    EphemeralRewind {
+        cursor_id: CursorID,
+        pc_if_empty: BranchOffset,
+    },
+
+    // Advance to the next row of an ephemeral table. Rows inserted during the iteration are visited too.
+    EphemeralNext {
+        cursor_id: CursorID,
+        pc_if_next: BranchOffset,
+    },
+
     // Function
     Function {
         constant_mask: i32, // P1
@@ -554,7 +684,7 @@ pub enum Insn {
         cursor: CursorID,
         key_reg: usize,    // Must be int.
         record_reg: usize, // Blob of record data.
-        flag: usize,       // Flags used by insert, for now not used.
+        flag: InsertFlags,
     },
 
     InsertAwait {
@@ -567,6 +697,45 @@ pub enum Insn {
 
     DeleteAwait {
         cursor_id: CursorID,
+        /// The deletion is not counted by changes() and total_changes().
+        skip_nchange: bool,
+    },
+
+    /// Insert the record in register P2 into the index opened by cursor P1.
+    /// The record is the indexed column values followed by the rowid.
+    /// If nchange is set, the insert is counted by changes(), as it is a row of a WITHOUT ROWID table.
+    IdxInsertAsync {
+        cursor_id: CursorID,
+        record_reg: usize,
+        nchange: bool,
+    },
+
+    IdxInsertAwait {
+        cursor_id: CursorID,
+    },
+
+    /// The P3 registers starting at P2 form an index key, including the rowid.
+    /// Delete the matching entry from the index opened by cursor P1, if there is one.
+    IdxDelete {
+        cursor_id: CursorID,
+        start_reg: usize,
+        num_regs: usize,
+    },
+
+    /// Write the rowid of the index entry that cursor P1 points to into register P2.
+    IdxRowId {
+        cursor_id: CursorID,
+        dest: usize,
+    },
+
+    /// The P4 registers starting at P3 form an unpacked key prefix. Jump to P2 if any of
+    /// them is NULL or if no entry of the index opened by cursor P1 starts with that prefix.
+    /// Otherwise fall through, leaving the cursor on the conflicting entry.
+    NoConflict {
+        cursor_id: CursorID,
+        target_pc: BranchOffset,
+        record_reg: usize,
+        num_regs: usize,
     },
 
     NewRowid {
@@ -597,7 +766,7 @@ pub enum Insn {
 
     OpenWriteAsync {
         cursor_id: CursorID,
-        root_page: PageIdx,
+        root_page: RegisterOrLiteral<PageIdx>,
     },
 
     OpenWriteAwait {},
@@ -636,6 +805,100 @@ pub enum Insn {
         where_clause: String,
     },
 
+    /// Attach the database file whose path is in path_reg under the schema name in name_reg.
+    Attach {
+        path_reg: usize,
+        name_reg: usize,
+    },
+
+    /// Detach the database whose schema name is in name_reg.
+    Detach {
+        name_reg: usize,
+    },
+
+    /// Rebuild the database `db` without its free pages or, if into_reg is set, write a compacted
+    /// copy of it to the file whose path is in into_reg.
+    Vacuum {
+        db: usize,
+        into_reg: Option<usize>,
+    },
+
+    /// Gather the statistics of the table `table_name` of the database `db`, or of all of its tables,
+    /// into its sqlite_stat1 table.
+    Analyze {
+        db: usize,
+        table_name: Option<String>,
+    },
+
+    /// Free all pages of a b-tree, including its root page and the overflow pages of its cells.
+    Destroy {
+        /// Root page of the b-tree (P1).
+        root: usize,
+        /// Register that receives the page number of the root page moved into the freed root page (P2).
+        /// Always set to zero, as auto-vacuum is not supported.
+        former_root_reg: usize,
+        /// Index of the database the b-tree belongs to (P3).
+        db: usize,
+    },
+
+    /// Remove the index from the in-memory schema of database P1. The b-tree itself is freed by Destroy.
+    DropIndex {
+        index: Rc<Index>,
+        db: usize,
+    },
+
+    /// Remove the table and its indexes from the in-memory schema of database P1.
+    /// The b-trees themselves are freed by Destroy.
+    DropTable {
+        db: usize,
+        table_name: String,
+    },
+
+    /// Remove the view and its triggers from the in-memory schema of database P1.
+    DropView {
+        db: usize,
+        view_name: String,
+    },
+
+    /// Remove the trigger from the in-memory schema of database P1.
+    DropTrigger {
+        db: usize,
+        trigger_name: String,
+    },
+
+    /// Run a trigger program to completion. The program reads the OLD and NEW rows of the change
+    /// that fired the trigger from the registers starting at params_start_reg, with the Param instruction.
+    /// If the program is halted by RAISE(IGNORE), jump to ignore_jump.
+    Program {
+        params_start_reg: usize,
+        num_params: usize,
+        /// Index of the program in the sub-programs of this program, or None to run this program
+        /// again, as a FOREIGN KEY action on a table that references itself does.
+        sub_program: Option<usize>,
+        ignore_jump: BranchOffset,
+    },
+
+    /// Add increment to the count of FOREIGN KEY constraint violations: the one of the statement for
+    /// an immediate constraint, or the one of the transaction, checked at COMMIT, for a deferred
+    /// constraint or when PRAGMA defer_foreign_keys is on.
+    FkCounter {
+        deferred: bool,
+        increment: i64,
+    },
+
+    /// Jump to target_pc if there are no FOREIGN KEY constraint violations to undo: in the
+    /// transaction if deferred is set, otherwise in the statement.
+    FkIfZero {
+        deferred: bool,
+        target_pc: BranchOffset,
+    },
+
+    /// In a trigger program, copy the parameter at this offset into the dest register.
+    Param {
+        offset: usize,
+        dest: usize,
+    },
+
     // Place the result of lhs >> rhs in dest register.
     ShiftRight {
         lhs: usize,
@@ -699,6 +962,38 @@ pub enum Insn {
     },
 }
 
+/// How a Halt with an error undoes changes, after the conflict resolution of the constraint that
+/// failed or the RAISE() function that raised the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Roll back the transaction.
+    Rollback = 1,
+    /// Undo the changes of the statement, keeping the transaction open.
+    Abort = 2,
+    /// Keep the changes the statement made so far.
+    Fail = 3,
+}
+
+impl OnError {
+    pub fn from_resolve_type(resolve_type: ResolveType) -> Self {
+        match resolve_type {
+            ResolveType::Rollback => Self::Rollback,
+            ResolveType::Fail => Self::Fail,
+            ResolveType::Abort | ResolveType::Ignore | ResolveType::Replace => Self::Abort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SavepointOp {
+    /// Open a new savepoint, starting a transaction if none is active.
+    Begin = 0,
+    /// Release a savepoint and the ones opened after it, committing if it started the transaction.
+    Release = 1,
+    /// Undo the changes made since a savepoint was opened, which stays open.
+    Rollback = 2,
+}
+
 // TODO: Add remaining cookies.
 #[derive(Description, Debug, Clone, Copy)]
 pub enum Cookie {
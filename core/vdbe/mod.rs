@@ -18,12 +18,17 @@
 //! https://www.sqlite.org/opcode.html
 
 pub mod builder;
+pub mod ephemeral;
 pub mod explain;
 pub mod insn;
 pub mod likeop;
 pub mod sorter;
 
-use crate::error::{LimboError, SQLITE_CONSTRAINT_PRIMARYKEY};
+use crate::error::{
+    LimboError, SQLITE_CONSTRAINT_FOREIGNKEY, SQLITE_CONSTRAINT_NOTNULL,
+    SQLITE_CONSTRAINT_PRIMARYKEY, SQLITE_CONSTRAINT_TRIGGER, SQLITE_CONSTRAINT_UNIQUE,
+    SQLITE_IGNORE,
+};
 use crate::ext::ExtValue;
 use crate::function::{AggFunc, ExtFunc, FuncCtx, MathFunc, MathFuncArity, ScalarFunc, VectorFunc};
 use crate::functions::datetime::{
@@ -33,13 +38,16 @@ use crate::functions::printf::exec_printf;
 use crate::info;
 use crate::pseudo::PseudoCursor;
 use crate::result::LimboResult;
-use crate::schema::{affinity, Affinity};
+use crate::schema::{affinity, Affinity, MAIN_DB, TEMP_DB};
 use crate::storage::sqlite3_ondisk::DatabaseHeader;
 use crate::storage::wal::CheckpointResult;
 use crate::storage::{btree::BTreeCursor, pager::Pager};
+use crate::translate::collate::CollationSeq;
+use crate::translate::expr::sanitize_string;
 use crate::translate::plan::{ResultSetColumn, TableReference};
 use crate::types::{
-    AggContext, Cursor, CursorResult, ExternalAggState, OwnedValue, Record, SeekKey, SeekOp,
+    compare_index_keys, AggContext, Cursor, CursorResult, ExternalAggState, OwnedValue, Record,
+    SeekKey, SeekOp,
 };
 use crate::util::{
     cast_real_to_integer, cast_text_to_integer, cast_text_to_numeric, cast_text_to_real,
@@ -56,12 +64,14 @@ use crate::{
     json::json_quote, json::json_remove, json::json_set, json::json_type,
 };
 use crate::{resolve_ext_path, Connection, Result, TransactionState, DATABASE_VERSION};
+use ephemeral::EphemeralCursor;
 use insn::{
     exec_add, exec_and, exec_bit_and, exec_bit_not, exec_bit_or, exec_boolean_not, exec_concat,
     exec_divide, exec_multiply, exec_or, exec_remainder, exec_shift_left, exec_shift_right,
-    exec_subtract, Cookie,
+    exec_subtract, Cookie, OnError, RegisterOrLiteral, SavepointOp,
 };
 use likeop::{construct_like_escape_arg, exec_glob, exec_like_with_escape};
+use limbo_sqlite3_parser::ast;
 use rand::distributions::{Distribution, Uniform};
 use rand::{thread_rng, Rng};
 use regex::{Regex, RegexBuilder};
@@ -232,6 +242,21 @@ fn get_cursor_as_table_mut<'long, 'short>(
     cursor
 }
 
+/// Compares two values under the collation of a comparison instruction. Returns None when the
+/// collation does not apply, i.e. when there is none or the values are not both text.
+fn collated_cmp(
+    lhs: &OwnedValue,
+    rhs: &OwnedValue,
+    collation: Option<&CollationSeq>,
+) -> Option<std::cmp::Ordering> {
+    match (lhs, rhs, collation) {
+        (OwnedValue::Text(_), OwnedValue::Text(_), Some(collation)) => {
+            Some(collation.compare(lhs, rhs))
+        }
+        _ => None,
+    }
+}
+
 fn get_cursor_as_index_mut<'long, 'short>(
     cursors: &'short mut RefMut<'long, Vec<Option<Cursor>>>,
     cursor_id: CursorID,
@@ -284,6 +309,19 @@ fn get_cursor_as_virtual_mut<'long, 'short>(
     cursor
 }
 
+fn get_cursor_as_ephemeral_mut<'short>(
+    cursors: &'short mut RefMut<'_, Vec<Option<Cursor>>>,
+    cursor_id: CursorID,
+) -> &'short mut EphemeralCursor {
+    let cursor = cursors
+        .get_mut(cursor_id)
+        .expect("cursor id out of bounds")
+        .as_mut()
+        .expect("cursor not allocated")
+        .as_ephemeral_mut();
+    cursor
+}
+
 struct Bitfield<const N: usize>([u64; N]);
 
 impl<const N: usize> Bitfield<N> {
@@ -336,6 +374,19 @@ pub struct ProgramState {
     regex_cache: RegexCache,
     interrupted: bool,
     parameters: HashMap<NonZero<usize>, OwnedValue>,
+    /// Cursor of the b-tree being freed by an in-flight Destroy instruction.
+    destroy_cursor: Option<BTreeCursor>,
+    /// State of the trigger program being run by a Program instruction.
+    sub_program_state: Option<Box<ProgramState>>,
+    /// In a trigger program, the OLD and NEW rows of the change that fired the trigger.
+    params: Option<Vec<OwnedValue>>,
+    /// The violations of immediate FOREIGN KEY constraints by the statement, shared with the
+    /// programs it runs.
+    fk_violations: i64,
+    /// How many Program instructions are running this program.
+    depth: usize,
+    /// Whether the statement opened a savepoint of the transaction, to roll back to if it fails.
+    statement_savepoint: bool,
 }
 
 impl ProgramState {
@@ -354,6 +405,12 @@ impl ProgramState {
             regex_cache: RegexCache::new(),
             interrupted: false,
             parameters: HashMap::new(),
+            destroy_cursor: None,
+            sub_program_state: None,
+            params: None,
+            fk_violations: 0,
+            depth: 0,
+            statement_savepoint: false,
         }
     }
 
@@ -393,6 +450,10 @@ impl ProgramState {
         self.regex_cache.like.clear();
         self.interrupted = false;
         self.parameters.clear();
+        self.destroy_cursor = None;
+        self.sub_program_state = None;
+        self.fk_violations = 0;
+        self.statement_savepoint = false;
     }
 }
 
@@ -405,6 +466,7 @@ macro_rules! must_be_btree_cursor {
             CursorType::Pseudo(_) => panic!("{} on pseudo cursor", $insn_name),
             CursorType::Sorter => panic!("{} on sorter cursor", $insn_name),
             CursorType::VirtualTable(_) => panic!("{} on virtual table cursor", $insn_name),
+            CursorType::Ephemeral => panic!("{} on ephemeral cursor", $insn_name),
         };
         cursor
     }};
@@ -423,6 +485,8 @@ pub struct Program {
     pub change_cnt_on: bool,
     pub result_columns: Vec<ResultSetColumn>,
     pub table_references: Vec<TableReference>,
+    /// The trigger programs run by the Program instructions of this program.
+    pub sub_programs: Vec<Program>,
 }
 
 impl Program {
@@ -445,6 +509,16 @@ impl Program {
     }
 
     pub fn step(&self, state: &mut ProgramState, pager: Rc<Pager>) -> Result<StepResult> {
+        let result = self.execute(state, pager);
+        if result.is_err() && state.statement_savepoint {
+            // The statement failed inside a transaction, which stays open without its changes.
+            state.statement_savepoint = false;
+            self.connection.upgrade().unwrap().rollback_statement();
+        }
+        result
+    }
+
+    fn execute(&self, state: &mut ProgramState, pager: Rc<Pager>) -> Result<StepResult> {
         loop {
             if state.is_interrupted() {
                 return Ok(StepResult::Interrupt);
@@ -535,12 +609,20 @@ impl Program {
                     let cursor =
                         must_be_btree_cursor!(*cursor_id, self.cursor_ref, cursors, "NullRow");
                     cursor.set_null_flag(true);
+                    // The cursor must not be moved to the row of an index by a pending deferred seek
+                    if state
+                        .deferred_seek
+                        .is_some_and(|(_, table_cursor_id)| table_cursor_id == *cursor_id)
+                    {
+                        state.deferred_seek = None;
+                    }
                     state.pc += 1;
                 }
                 Insn::Compare {
                     start_reg_a,
                     start_reg_b,
                     count,
+                    collations,
                 } => {
                     let start_reg_a = *start_reg_a;
                     let start_reg_b = *start_reg_b;
@@ -556,7 +638,10 @@ impl Program {
                     for i in 0..count {
                         let a = &state.registers[start_reg_a + i];
                         let b = &state.registers[start_reg_b + i];
-                        cmp = Some(a.cmp(b));
+                        cmp = Some(match collations.get(i) {
+                            Some(Some(collation)) => collation.compare(a, b),
+                            _ => a.cmp(b),
+                        });
                         if cmp != Some(std::cmp::Ordering::Equal) {
                             break;
                         }
@@ -643,6 +728,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -660,7 +746,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] == state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l == r, |cmp| cmp.is_eq())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -673,6 +762,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -690,7 +780,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] != state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l != r, |cmp| cmp.is_ne())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -703,6 +796,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -718,7 +812,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] < state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l < r, |cmp| cmp.is_lt())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -731,6 +828,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -746,7 +844,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] <= state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l <= r, |cmp| cmp.is_le())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -759,6 +860,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -774,7 +876,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] > state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l > r, |cmp| cmp.is_gt())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -787,6 +892,7 @@ impl Program {
                     rhs,
                     target_pc,
                     flags,
+                    collation,
                 } => {
                     assert!(target_pc.is_offset());
                     let lhs = *lhs;
@@ -802,7 +908,10 @@ impl Program {
                             }
                         }
                         _ => {
-                            if state.registers[lhs] >= state.registers[rhs] {
+                            let (l, r) = (&state.registers[lhs], &state.registers[rhs]);
+                            if collated_cmp(l, r, collation.as_ref())
+                                .map_or(l >= r, |cmp| cmp.is_ge())
+                            {
                                 state.pc = target_pc.to_offset_int();
                             } else {
                                 state.pc += 1;
@@ -839,16 +948,18 @@ impl Program {
                     root_page,
                 } => {
                     let (_, cursor_type) = self.cursor_ref.get(*cursor_id).unwrap();
-                    let cursor = BTreeCursor::new(pager.clone(), *root_page);
+                    let pager = self.db_pager(cursor_type.db(), &pager)?;
                     let mut cursors = state.cursors.borrow_mut();
                     match cursor_type {
                         CursorType::BTreeTable(_) => {
+                            let cursor = BTreeCursor::new(pager.clone(), *root_page);
                             cursors
                                 .get_mut(*cursor_id)
                                 .unwrap()
                                 .replace(Cursor::new_table(cursor));
                         }
-                        CursorType::BTreeIndex(_) => {
+                        CursorType::BTreeIndex(index) => {
+                            let cursor = BTreeCursor::new_index(pager.clone(), *root_page, index);
                             cursors
                                 .get_mut(*cursor_id)
                                 .unwrap()
@@ -860,6 +971,9 @@ impl Program {
                         CursorType::Sorter => {
                             panic!("OpenReadAsync on sorter cursor");
                         }
+                        CursorType::Ephemeral => {
+                            panic!("OpenReadAsync on ephemeral cursor, use Insn::OpenEphemeral instead");
+                        }
                         CursorType::VirtualTable(_) => {
                             panic!("OpenReadAsync on virtual table cursor, use Insn::VOpenAsync instead");
                         }
@@ -1050,6 +1164,12 @@ impl Program {
                     let cursor =
                         must_be_btree_cursor!(*cursor_id, self.cursor_ref, cursors, "RewindAsync");
                     return_if_io!(cursor.rewind());
+                    if state
+                        .deferred_seek
+                        .is_some_and(|(_, table_cursor_id)| table_cursor_id == *cursor_id)
+                    {
+                        state.deferred_seek = None;
+                    }
                     state.pc += 1;
                 }
                 Insn::LastAsync { cursor_id } => {
@@ -1120,8 +1240,12 @@ impl Program {
                             if let Some(record) = record.as_ref() {
                                 state.registers[*dest] = if cursor.get_null_flag() {
                                     OwnedValue::Null
-                                } else {
+                                } else if *column < record.len() {
                                     record.get_value(*column).clone()
+                                } else {
+                                    // Rows written before ALTER TABLE ADD COLUMN lack the
+                                    // trailing columns that were added since.
+                                    column_default_value(cursor_type, *column)
                                 };
                             } else {
                                 state.registers[*dest] = OwnedValue::Null;
@@ -1148,6 +1272,11 @@ impl Program {
                                 "Insn::Column on virtual table cursor, use Insn::VColumn instead"
                             );
                         }
+                        CursorType::Ephemeral => {
+                            let cursor = get_cursor_as_ephemeral_mut(&mut cursors, *cursor_id);
+                            state.registers[*dest] =
+                                cursor.column(*column).unwrap_or(OwnedValue::Null);
+                        }
                     }
 
                     state.pc += 1;
@@ -1216,22 +1345,58 @@ impl Program {
                 Insn::Halt {
                     err_code,
                     description,
+                    on_error,
                 } => {
                     match *err_code {
                         0 => {}
-                        SQLITE_CONSTRAINT_PRIMARYKEY => {
-                            return Err(LimboError::Constraint(format!(
-                                "UNIQUE constraint failed: {} (19)",
-                                description
-                            )));
+                        SQLITE_CONSTRAINT_PRIMARYKEY
+                        | SQLITE_CONSTRAINT_UNIQUE
+                        | SQLITE_CONSTRAINT_NOTNULL => {
+                            let constraint = if *err_code == SQLITE_CONSTRAINT_NOTNULL {
+                                "NOT NULL"
+                            } else {
+                                "UNIQUE"
+                            };
+                            let err = LimboError::Constraint(format!(
+                                "{} constraint failed: {} (19)",
+                                constraint, description
+                            ));
+                            return self.halt_with_error(pager, *on_error, err);
+                        }
+                        // RAISE(ABORT|FAIL|ROLLBACK, message) in a trigger program
+                        SQLITE_CONSTRAINT_TRIGGER => {
+                            let err = LimboError::Constraint(format!("{} (19)", description));
+                            return self.halt_with_error(pager, *on_error, err);
+                        }
+                        // RAISE(IGNORE) in a trigger program, see the Program instruction
+                        SQLITE_IGNORE => return Ok(StepResult::Done),
+                        // A FOREIGN KEY constraint with a RESTRICT action
+                        SQLITE_CONSTRAINT_FOREIGNKEY => {
+                            self.abort(pager)?;
+                            return Err(foreign_key_constraint_failed());
                         }
                         _ => {
+                            self.abort(pager)?;
                             return Err(LimboError::Constraint(format!(
                                 "undocumented halt error code {}",
                                 description
                             )));
                         }
                     }
+                    // A trigger program runs inside the statement that fired the trigger,
+                    // which ends the transaction when it halts.
+                    if state.params.is_some() {
+                        return Ok(StepResult::Done);
+                    }
+                    let conn = self.connection.upgrade().unwrap();
+                    if state.fk_violations > 0
+                        || (*conn.auto_commit.borrow() && conn.deferred_fk_violations() > 0)
+                    {
+                        self.abort(pager)?;
+                        return Err(foreign_key_constraint_failed());
+                    }
+                    conn.release_statement_savepoint();
+                    state.statement_savepoint = false;
                     return self.halt(pager);
                 }
                 Insn::Transaction { write } => {
@@ -1264,6 +1429,17 @@ impl Program {
                             .transaction_state
                             .replace(new_transaction_state.clone());
                     }
+                    for database in connection.aux_databases() {
+                        if let LimboResult::Busy = database.begin(*write)? {
+                            tracing::trace!("begin_tx busy on database {}", database.name);
+                            return Ok(StepResult::Busy);
+                        }
+                    }
+                    // Inside a transaction, a statement that fails undoes its own changes only.
+                    if *write && !*connection.auto_commit.borrow() {
+                        connection.open_statement_savepoint();
+                        state.statement_savepoint = true;
+                    }
                     state.pc += 1;
                 }
                 Insn::AutoCommit {
@@ -1273,10 +1449,14 @@ impl Program {
                     let conn = self.connection.upgrade().unwrap();
                     if *auto_commit != *conn.auto_commit.borrow() {
                         if *rollback {
-                            todo!("Rollback is not implemented");
-                        } else {
-                            conn.auto_commit.replace(*auto_commit);
+                            self.rollback(pager)?;
+                            return Ok(StepResult::Done);
                         }
+                        // The transaction stays open, so that the violations can be fixed.
+                        if *auto_commit && conn.deferred_fk_violations() > 0 {
+                            return Err(foreign_key_constraint_failed());
+                        }
+                        conn.auto_commit.replace(*auto_commit);
                     } else if !*auto_commit {
                         return Err(LimboError::TxError(
                             "cannot start a transaction within a transaction".to_string(),
@@ -1290,7 +1470,48 @@ impl Program {
                             "cannot commit - no transaction is active".to_string(),
                         ));
                     }
-                    return self.halt(pager);
+                    // The Halt that follows commits the transaction, which can take several steps
+                    // when it waits for IO, so this instruction must not run again.
+                    state.pc += 1;
+                }
+                Insn::Savepoint { op, name } => {
+                    let conn = self.connection.upgrade().unwrap();
+                    match op {
+                        SavepointOp::Begin => {
+                            // The savepoint takes the place of the one of the statement.
+                            conn.release_statement_savepoint();
+                            let starts_transaction = *conn.auto_commit.borrow();
+                            conn.auto_commit.replace(false);
+                            conn.open_savepoint(Some(name.clone()), starts_transaction);
+                        }
+                        SavepointOp::Release | SavepointOp::Rollback => {
+                            let savepoints = conn.savepoints.borrow();
+                            let Some(index) = savepoints
+                                .iter()
+                                .rposition(|s| s.name.as_ref() == Some(name))
+                            else {
+                                return Err(LimboError::TxError(format!(
+                                    "no such savepoint: {}",
+                                    name
+                                )));
+                            };
+                            let commits = index == 0 && savepoints[0].starts_transaction;
+                            drop(savepoints);
+                            if let SavepointOp::Rollback = op {
+                                conn.rollback_to_savepoint(index);
+                            } else if commits {
+                                if conn.deferred_fk_violations() > 0 {
+                                    return Err(foreign_key_constraint_failed());
+                                }
+                                // The Halt that follows commits the transaction.
+                                conn.savepoints.borrow_mut().clear();
+                                conn.auto_commit.replace(true);
+                            } else {
+                                conn.release_savepoint(index);
+                            }
+                        }
+                    }
+                    state.pc += 1;
                 }
                 Insn::Goto { target_pc } => {
                     assert!(target_pc.is_offset());
@@ -1355,7 +1576,9 @@ impl Program {
 
                     if let Some(Cursor::Table(btree_cursor)) = cursors.get_mut(*cursor_id).unwrap()
                     {
-                        if let Some(ref rowid) = btree_cursor.rowid()? {
+                        if btree_cursor.get_null_flag() {
+                            state.registers[*dest] = OwnedValue::Null;
+                        } else if let Some(ref rowid) = btree_cursor.rowid()? {
                             state.registers[*dest] = OwnedValue::Integer(*rowid as i64);
                         } else {
                             state.registers[*dest] = OwnedValue::Null;
@@ -1388,18 +1611,26 @@ impl Program {
                     assert!(target_pc.is_offset());
                     let mut cursors = state.cursors.borrow_mut();
                     let cursor = get_cursor_as_table_mut(&mut cursors, *cursor_id);
+                    // Like SQLite, a value that cannot be losslessly converted to an integer
+                    // cannot be equal to any rowid, so it is treated as not found.
                     let rowid = match &state.registers[*src_reg] {
-                        OwnedValue::Integer(rowid) => *rowid as u64,
-                        OwnedValue::Null => {
-                            state.pc = target_pc.to_offset_int();
-                            continue;
-                        }
-                        other => {
-                            return Err(LimboError::InternalError(
-                                format!("SeekRowid: the value in the register is not an integer or NULL: {}", other)
-                            ));
+                        OwnedValue::Integer(rowid) => Some(*rowid),
+                        OwnedValue::Float(f) => cast_real_to_integer(*f).ok(),
+                        OwnedValue::Text(text) => {
+                            let text = text.as_str().trim();
+                            text.parse::<i64>().ok().or_else(|| {
+                                text.parse::<f64>()
+                                    .ok()
+                                    .and_then(|f| cast_real_to_integer(f).ok())
+                            })
                         }
+                        _ => None,
+                    };
+                    let Some(rowid) = rowid else {
+                        state.pc = target_pc.to_offset_int();
+                        continue;
                     };
+                    let rowid = rowid as u64;
                     let found = return_if_io!(cursor.seek(SeekKey::TableRowId(rowid), SeekOp::EQ));
                     if !found {
                         state.pc = target_pc.to_offset_int();
@@ -1519,8 +1750,12 @@ impl Program {
                         make_owned_record(&state.registers, start_reg, num_regs);
                     if let Some(ref idx_record) = *cursor.record()? {
                         // Compare against the same number of values
-                        if idx_record.get_values()[..record_from_regs.len()]
-                            >= record_from_regs.get_values()[..]
+                        if compare_index_keys(
+                            &idx_record.get_values()[..record_from_regs.len()],
+                            record_from_regs.get_values(),
+                            cursor.index_key_info(),
+                        )
+                        .is_ge()
                         {
                             state.pc = target_pc.to_offset_int();
                         } else {
@@ -1543,8 +1778,12 @@ impl Program {
                         make_owned_record(&state.registers, start_reg, num_regs);
                     if let Some(ref idx_record) = *cursor.record()? {
                         // Compare against the same number of values
-                        if idx_record.get_values()[..record_from_regs.len()]
-                            <= record_from_regs.get_values()[..]
+                        if compare_index_keys(
+                            &idx_record.get_values()[..record_from_regs.len()],
+                            record_from_regs.get_values(),
+                            cursor.index_key_info(),
+                        )
+                        .is_le()
                         {
                             state.pc = target_pc.to_offset_int();
                         } else {
@@ -1567,8 +1806,12 @@ impl Program {
                         make_owned_record(&state.registers, start_reg, num_regs);
                     if let Some(ref idx_record) = *cursor.record()? {
                         // Compare against the same number of values
-                        if idx_record.get_values()[..record_from_regs.len()]
-                            > record_from_regs.get_values()[..]
+                        if compare_index_keys(
+                            &idx_record.get_values()[..record_from_regs.len()],
+                            record_from_regs.get_values(),
+                            cursor.index_key_info(),
+                        )
+                        .is_gt()
                         {
                             state.pc = target_pc.to_offset_int();
                         } else {
@@ -1591,8 +1834,12 @@ impl Program {
                         make_owned_record(&state.registers, start_reg, num_regs);
                     if let Some(ref idx_record) = *cursor.record()? {
                         // Compare against the same number of values
-                        if idx_record.get_values()[..record_from_regs.len()]
-                            < record_from_regs.get_values()[..]
+                        if compare_index_keys(
+                            &idx_record.get_values()[..record_from_regs.len()],
+                            record_from_regs.get_values(),
+                            cursor.index_key_info(),
+                        )
+                        .is_lt()
                         {
                             state.pc = target_pc.to_offset_int();
                         } else {
@@ -1622,6 +1869,7 @@ impl Program {
                     col,
                     delimiter,
                     func,
+                    collation,
                 } => {
                     if let OwnedValue::Null = &state.registers[*acc_reg] {
                         state.registers[*acc_reg] = match func {
@@ -1641,40 +1889,8 @@ impl Program {
                             AggFunc::Count | AggFunc::Count0 => {
                                 OwnedValue::Agg(Box::new(AggContext::Count(OwnedValue::Integer(0))))
                             }
-                            AggFunc::Max => {
-                                let col = state.registers[*col].clone();
-                                match col {
-                                    OwnedValue::Integer(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Max(None)))
-                                    }
-                                    OwnedValue::Float(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Max(None)))
-                                    }
-                                    OwnedValue::Text(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Max(None)))
-                                    }
-                                    _ => {
-                                        unreachable!();
-                                    }
-                                }
-                            }
-                            AggFunc::Min => {
-                                let col = state.registers[*col].clone();
-                                match col {
-                                    OwnedValue::Integer(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Min(None)))
-                                    }
-                                    OwnedValue::Float(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Min(None)))
-                                    }
-                                    OwnedValue::Text(_) => {
-                                        OwnedValue::Agg(Box::new(AggContext::Min(None)))
-                                    }
-                                    _ => {
-                                        unreachable!();
-                                    }
-                                }
-                            }
+                            AggFunc::Max => OwnedValue::Agg(Box::new(AggContext::Max(None))),
+                            AggFunc::Min => OwnedValue::Agg(Box::new(AggContext::Min(None))),
                             AggFunc::GroupConcat | AggFunc::StringAgg => OwnedValue::Agg(Box::new(
                                 AggContext::GroupConcat(OwnedValue::build_text("")),
                             )),
@@ -1722,6 +1938,9 @@ impl Program {
                             *acc += col;
                         }
                         AggFunc::Count | AggFunc::Count0 => {
+                            // count(x) only counts the rows where x is not NULL
+                            let counted = matches!(func, AggFunc::Count0)
+                                || !matches!(state.registers[*col], OwnedValue::Null);
                             if matches!(&state.registers[*acc_reg], OwnedValue::Null) {
                                 state.registers[*acc_reg] = OwnedValue::Agg(Box::new(
                                     AggContext::Count(OwnedValue::Integer(0)),
@@ -1734,7 +1953,9 @@ impl Program {
                             let AggContext::Count(count) = agg.borrow_mut() else {
                                 unreachable!();
                             };
-                            *count += 1;
+                            if counted {
+                                *count += 1;
+                            }
                         }
                         AggFunc::Max => {
                             let col = state.registers[*col].clone();
@@ -1747,6 +1968,8 @@ impl Program {
                             };
 
                             match (acc.as_mut(), col) {
+                                // NULLs are ignored
+                                (_, OwnedValue::Null) => {}
                                 (None, value) => {
                                     *acc = Some(value);
                                 }
@@ -1770,7 +1993,12 @@ impl Program {
                                     Some(OwnedValue::Text(ref mut current_max)),
                                     OwnedValue::Text(value),
                                 ) => {
-                                    if value.value > current_max.value {
+                                    let collation =
+                                        collation.as_ref().unwrap_or(&CollationSeq::Binary);
+                                    if collation
+                                        .compare_strings(value.as_str(), current_max.as_str())
+                                        == std::cmp::Ordering::Greater
+                                    {
                                         *current_max = value;
                                     }
                                 }
@@ -1790,6 +2018,8 @@ impl Program {
                             };
 
                             match (acc.as_mut(), col) {
+                                // NULLs are ignored
+                                (_, OwnedValue::Null) => {}
                                 (None, value) => {
                                     *acc.borrow_mut() = Some(value);
                                 }
@@ -1813,7 +2043,12 @@ impl Program {
                                     Some(OwnedValue::Text(ref mut current_min)),
                                     OwnedValue::Text(text),
                                 ) => {
-                                    if text.value < current_min.value {
+                                    let collation =
+                                        collation.as_ref().unwrap_or(&CollationSeq::Binary);
+                                    if collation
+                                        .compare_strings(text.as_str(), current_min.as_str())
+                                        == std::cmp::Ordering::Less
+                                    {
                                         *current_min = text;
                                     }
                                 }
@@ -1901,12 +2136,19 @@ impl Program {
                             unreachable!();
                         }
                     };
+                    // Replace the accumulator with the result, so that the register can be compared and
+                    // copied like any other value.
+                    if let OwnedValue::Agg(_) = &state.registers[*register] {
+                        state.registers[*register] =
+                            OwnedValue::from(state.registers[*register].to_value());
+                    }
                     state.pc += 1;
                 }
                 Insn::SorterOpen {
                     cursor_id,
                     columns: _,
                     order,
+                    collations,
                 } => {
                     let order = order
                         .get_values()
@@ -1916,7 +2158,7 @@ impl Program {
                             _ => unreachable!(),
                         })
                         .collect();
-                    let cursor = Sorter::new(order);
+                    let cursor = Sorter::new(order, collations.clone());
                     let mut cursors = state.cursors.borrow_mut();
                     cursors
                         .get_mut(*cursor_id)
@@ -1983,6 +2225,109 @@ impl Program {
                         state.pc += 1;
                     }
                 }
+                Insn::SorterSeek {
+                    cursor_id,
+                    row_reg,
+                    pc_if_out_of_range,
+                } => {
+                    assert!(pc_if_out_of_range.is_offset());
+                    let row = match &state.registers[*row_reg] {
+                        OwnedValue::Integer(row) => usize::try_from(*row).ok(),
+                        _ => None,
+                    };
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_sorter_mut(&mut cursors, *cursor_id);
+                    if row.is_some_and(|row| cursor.seek(row)) {
+                        state.pc += 1;
+                    } else {
+                        state.pc = pc_if_out_of_range.to_offset_int();
+                    }
+                }
+                Insn::OpenEphemeral {
+                    cursor_id,
+                    distinct,
+                    collations,
+                } => {
+                    let cursor = EphemeralCursor::new(*distinct, collations.clone());
+                    let mut cursors = state.cursors.borrow_mut();
+                    cursors
+                        .get_mut(*cursor_id)
+                        .unwrap()
+                        .replace(Cursor::new_ephemeral(cursor));
+                    state.pc += 1;
+                }
+                Insn::OpenDup {
+                    new_cursor_id,
+                    original_cursor_id,
+                } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor =
+                        get_cursor_as_ephemeral_mut(&mut cursors, *original_cursor_id).duplicate();
+                    cursors
+                        .get_mut(*new_cursor_id)
+                        .unwrap()
+                        .replace(Cursor::new_ephemeral(cursor));
+                    state.pc += 1;
+                }
+                Insn::EphemeralInsert {
+                    cursor_id,
+                    record_reg,
+                } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_ephemeral_mut(&mut cursors, *cursor_id);
+                    let record = match &state.registers[*record_reg] {
+                        OwnedValue::Record(record) => record,
+                        _ => unreachable!("EphemeralInsert on non-record register"),
+                    };
+                    cursor.insert(record);
+                    state.pc += 1;
+                }
+                Insn::EphemeralFound {
+                    cursor_id,
+                    record_reg,
+                    target_pc,
+                } => {
+                    assert!(target_pc.is_offset());
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_ephemeral_mut(&mut cursors, *cursor_id);
+                    let record = match &state.registers[*record_reg] {
+                        OwnedValue::Record(record) => record,
+                        _ => unreachable!("EphemeralFound on non-record register"),
+                    };
+                    if cursor.contains(record) {
+                        state.pc = target_pc.to_offset_int();
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::EphemeralRewind {
+                    cursor_id,
+                    pc_if_empty,
+                } => {
+                    assert!(pc_if_empty.is_offset());
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_ephemeral_mut(&mut cursors, *cursor_id);
+                    cursor.rewind();
+                    if cursor.has_more() {
+                        state.pc += 1;
+                    } else {
+                        state.pc = pc_if_empty.to_offset_int();
+                    }
+                }
+                Insn::EphemeralNext {
+                    cursor_id,
+                    pc_if_next,
+                } => {
+                    assert!(pc_if_next.is_offset());
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_ephemeral_mut(&mut cursors, *cursor_id);
+                    cursor.next();
+                    if cursor.has_more() {
+                        state.pc = pc_if_next.to_offset_int();
+                    } else {
+                        state.pc += 1;
+                    }
+                }
                 Insn::Function {
                     constant_mask,
                     func,
@@ -2648,7 +2993,7 @@ impl Program {
                     cursor,
                     key_reg,
                     record_reg,
-                    flag: _,
+                    flag,
                 } => {
                     let mut cursors = state.cursors.borrow_mut();
                     let cursor = get_cursor_as_table_mut(&mut cursors, *cursor);
@@ -2658,38 +3003,132 @@ impl Program {
                     };
                     let key = &state.registers[*key_reg];
                     return_if_io!(cursor.insert(key, record, true));
+                    // Only update last_insert_rowid and the change counter for regular table
+                    // inserts, not schema modifications
+                    if cursor.root_page() != 1 {
+                        if let Some(rowid) = cursor.rowid()? {
+                            if !flag.is_update() {
+                                if let Some(conn) = self.connection.upgrade() {
+                                    conn.update_last_rowid(rowid);
+                                }
+                            }
+                            if !flag.has_skip_nchange() {
+                                let prev_changes = self.n_change.get();
+                                self.n_change.set(prev_changes + 1);
+                            }
+                        }
+                    }
                     state.pc += 1;
                 }
                 Insn::InsertAwait { cursor_id } => {
                     let mut cursors = state.cursors.borrow_mut();
                     let cursor = get_cursor_as_table_mut(&mut cursors, *cursor_id);
                     cursor.wait_for_completion()?;
-                    // Only update last_insert_rowid for regular table inserts, not schema modifications
-                    if cursor.root_page() != 1 {
-                        if let Some(rowid) = cursor.rowid()? {
-                            if let Some(conn) = self.connection.upgrade() {
-                                conn.update_last_rowid(rowid);
-                            }
-                            let prev_changes = self.n_change.get();
-                            self.n_change.set(prev_changes + 1);
-                        }
-                    }
                     state.pc += 1;
                 }
                 Insn::DeleteAsync { cursor_id } => {
                     let mut cursors = state.cursors.borrow_mut();
-                    let cursor = get_cursor_as_table_mut(&mut cursors, *cursor_id);
+                    let cursor =
+                        must_be_btree_cursor!(*cursor_id, self.cursor_ref, cursors, "DeleteAsync");
                     return_if_io!(cursor.delete());
                     state.pc += 1;
                 }
-                Insn::DeleteAwait { cursor_id } => {
+                Insn::DeleteAwait {
+                    cursor_id,
+                    skip_nchange,
+                } => {
                     let mut cursors = state.cursors.borrow_mut();
-                    let cursor = get_cursor_as_table_mut(&mut cursors, *cursor_id);
+                    let cursor =
+                        must_be_btree_cursor!(*cursor_id, self.cursor_ref, cursors, "DeleteAwait");
                     cursor.wait_for_completion()?;
-                    let prev_changes = self.n_change.get();
-                    self.n_change.set(prev_changes + 1);
+                    if !*skip_nchange {
+                        let prev_changes = self.n_change.get();
+                        self.n_change.set(prev_changes + 1);
+                    }
                     state.pc += 1;
                 }
+                Insn::IdxInsertAsync {
+                    cursor_id,
+                    record_reg,
+                    nchange,
+                } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_index_mut(&mut cursors, *cursor_id);
+                    let record = match &state.registers[*record_reg] {
+                        OwnedValue::Record(r) => r,
+                        _ => unreachable!("Not a record! Cannot insert a non record value."),
+                    };
+                    return_if_io!(cursor.insert_index_key(record));
+                    if *nchange {
+                        self.n_change.set(self.n_change.get() + 1);
+                    }
+                    state.pc += 1;
+                }
+                Insn::IdxInsertAwait { cursor_id } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_index_mut(&mut cursors, *cursor_id);
+                    cursor.wait_for_completion()?;
+                    state.pc += 1;
+                }
+                Insn::IdxDelete {
+                    cursor_id,
+                    start_reg,
+                    num_regs,
+                } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_index_mut(&mut cursors, *cursor_id);
+                    let key = make_owned_record(&state.registers, start_reg, num_regs);
+                    let found = cursor.is_delete_pending()
+                        || return_if_io!(cursor.seek(SeekKey::IndexKey(&key), SeekOp::EQ));
+                    if found {
+                        return_if_io!(cursor.delete());
+                    }
+                    state.pc += 1;
+                }
+                Insn::IdxRowId { cursor_id, dest } => {
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_index_mut(&mut cursors, *cursor_id);
+                    state.registers[*dest] = match cursor.rowid()? {
+                        Some(rowid) => OwnedValue::Integer(rowid as i64),
+                        None => OwnedValue::Null,
+                    };
+                    state.pc += 1;
+                }
+                Insn::NoConflict {
+                    cursor_id,
+                    target_pc,
+                    record_reg,
+                    num_regs,
+                } => {
+                    assert!(target_pc.is_offset());
+                    let key = make_owned_record(&state.registers, record_reg, num_regs);
+                    // NULLs never conflict with each other.
+                    if key
+                        .get_values()
+                        .iter()
+                        .any(|v| matches!(v, OwnedValue::Null))
+                    {
+                        state.pc = target_pc.to_offset_int();
+                        continue;
+                    }
+                    let mut cursors = state.cursors.borrow_mut();
+                    let cursor = get_cursor_as_index_mut(&mut cursors, *cursor_id);
+                    let found = return_if_io!(cursor.seek(SeekKey::IndexKey(&key), SeekOp::GE));
+                    let conflict = found
+                        && cursor.record()?.as_ref().is_some_and(|record| {
+                            compare_index_keys(
+                                record.get_values(),
+                                key.get_values(),
+                                cursor.index_key_info(),
+                            )
+                            .is_eq()
+                        });
+                    if conflict {
+                        state.pc += 1;
+                    } else {
+                        state.pc = target_pc.to_offset_int();
+                    }
+                }
                 Insn::NewRowid {
                     cursor, rowid_reg, ..
                 } => {
@@ -2788,15 +3227,28 @@ impl Program {
                     root_page,
                 } => {
                     let (_, cursor_type) = self.cursor_ref.get(*cursor_id).unwrap();
+                    let pager = self.db_pager(cursor_type.db(), &pager)?;
                     let mut cursors = state.cursors.borrow_mut();
-                    let is_index = cursor_type.is_index();
-                    let cursor = BTreeCursor::new(pager.clone(), *root_page);
-                    if is_index {
+                    let root_page = match root_page {
+                        RegisterOrLiteral::Literal(page) => *page,
+                        RegisterOrLiteral::Register(reg) => match &state.registers[*reg] {
+                            OwnedValue::Integer(page) => *page as PageIdx,
+                            _ => {
+                                return Err(LimboError::InternalError(
+                                    "OpenWriteAsync: the root page register is not an integer"
+                                        .into(),
+                                ));
+                            }
+                        },
+                    };
+                    if let CursorType::BTreeIndex(index) = cursor_type {
+                        let cursor = BTreeCursor::new_index(pager.clone(), root_page, index);
                         cursors
                             .get_mut(*cursor_id)
                             .unwrap()
                             .replace(Cursor::new_index(cursor));
                     } else {
+                        let cursor = BTreeCursor::new(pager.clone(), root_page);
                         cursors
                             .get_mut(*cursor_id)
                             .unwrap()
@@ -2818,10 +3270,7 @@ impl Program {
                     state.pc += 1;
                 }
                 Insn::CreateBtree { db, root, flags } => {
-                    if *db > 0 {
-                        // TODO: implement temp databases
-                        todo!("temp databases not implemented yet");
-                    }
+                    let pager = self.db_pager(*db, &pager)?;
                     let mut cursor = Box::new(BTreeCursor::new(pager.clone(), 0));
 
                     let root_page = cursor.btree_create(*flags);
@@ -2841,10 +3290,7 @@ impl Program {
                     }
                 }
                 Insn::PageCount { db, dest } => {
-                    if *db > 0 {
-                        // TODO: implement temp databases
-                        todo!("temp databases not implemented yet");
-                    }
+                    let pager = self.db_pager(*db, &pager)?;
                     // SQLite returns "0" on an empty database, and 2 on the first insertion,
                     // so we'll mimic that behavior.
                     let mut pages = pager.db_header.borrow().database_size.into();
@@ -2854,16 +3300,35 @@ impl Program {
                     state.registers[*dest] = OwnedValue::Integer(pages);
                     state.pc += 1;
                 }
-                Insn::ParseSchema {
-                    db: _,
-                    where_clause,
-                } => {
+                Insn::ParseSchema { db, where_clause } => {
                     let conn = self.connection.upgrade();
                     let conn = conn.as_ref().unwrap();
+                    if *db != MAIN_DB {
+                        let database = conn.aux_database(*db)?;
+                        let schema_table = if *db == TEMP_DB {
+                            "sqlite_temp_schema"
+                        } else {
+                            "sqlite_schema"
+                        };
+                        let stmt = conn.prepare(format!(
+                            "SELECT * FROM {}.{} WHERE {}",
+                            database.name, schema_table, where_clause
+                        ))?;
+                        database.save_committed_schema();
+                        parse_schema_rows(
+                            Some(stmt),
+                            &mut database.schema.borrow_mut(),
+                            conn.pager.io.clone(),
+                            &conn.db.syms.borrow(),
+                        )?;
+                        state.pc += 1;
+                        continue;
+                    }
                     let stmt = conn.prepare(format!(
                         "SELECT * FROM  sqlite_schema WHERE {}",
                         where_clause
                     ))?;
+                    conn.save_committed_schema();
                     let mut schema = RefCell::borrow_mut(&conn.schema);
                     // TODO: This function below is synchronous, make it async
                     parse_schema_rows(
@@ -2874,11 +3339,190 @@ impl Program {
                     )?;
                     state.pc += 1;
                 }
-                Insn::ReadCookie { db, dest, cookie } => {
-                    if *db > 0 {
-                        // TODO: implement temp databases
-                        todo!("temp databases not implemented yet");
+                Insn::Destroy {
+                    root,
+                    former_root_reg,
+                    db,
+                } => {
+                    let pager = self.db_pager(*db, &pager)?;
+                    let cursor = state
+                        .destroy_cursor
+                        .get_or_insert_with(|| BTreeCursor::new(pager.clone(), *root));
+                    return_if_io!(cursor.btree_destroy());
+                    state.destroy_cursor = None;
+                    state.registers[*former_root_reg] = OwnedValue::Integer(0);
+                    state.pc += 1;
+                }
+                Insn::Attach { path_reg, name_reg } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    let path = state.registers[*path_reg].to_string();
+                    let name = state.registers[*name_reg].to_string();
+                    conn.attach(&path, &name)?;
+                    state.pc += 1;
+                }
+                Insn::Detach { name_reg } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    let name = state.registers[*name_reg].to_string();
+                    conn.detach(&name)?;
+                    state.pc += 1;
+                }
+                Insn::Vacuum { db, into_reg } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    let into = into_reg.map(|reg| state.registers[reg].to_string());
+                    conn.vacuum(*db, into.as_deref())?;
+                    state.pc += 1;
+                }
+                Insn::Analyze { db, table_name } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    conn.analyze(*db, table_name.as_deref())?;
+                    state.pc += 1;
+                }
+                Insn::DropTable { db, table_name } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    if *db != MAIN_DB {
+                        let database = conn.aux_database(*db)?;
+                        database.save_committed_schema();
+                        database.schema.borrow_mut().remove_table(table_name);
+                        state.pc += 1;
+                        continue;
+                    }
+                    conn.save_committed_schema();
+                    RefCell::borrow_mut(&conn.schema).remove_table(table_name);
+                    state.pc += 1;
+                }
+                Insn::DropView { db: _, view_name } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    conn.save_committed_schema();
+                    RefCell::borrow_mut(&conn.schema).remove_view(view_name);
+                    state.pc += 1;
+                }
+                Insn::DropTrigger {
+                    db: _,
+                    trigger_name,
+                } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    conn.save_committed_schema();
+                    let mut schema = RefCell::borrow_mut(&conn.schema);
+                    if let Some(trigger) = schema.get_trigger(trigger_name) {
+                        schema.remove_trigger(&trigger);
+                    }
+                    state.pc += 1;
+                }
+                Insn::Program {
+                    params_start_reg,
+                    num_params,
+                    sub_program,
+                    ignore_jump,
+                } => {
+                    let sub_program = match sub_program {
+                        Some(sub_program) => &self.sub_programs[*sub_program],
+                        None => self,
+                    };
+                    if state.sub_program_state.is_none() && state.depth >= MAX_PROGRAM_DEPTH {
+                        self.abort(pager)?;
+                        return Err(LimboError::Constraint(
+                            "too many levels of trigger recursion".to_string(),
+                        ));
+                    }
+                    let registers = &state.registers;
+                    let (fk_violations, depth) = (state.fk_violations, state.depth);
+                    let sub_state = state.sub_program_state.get_or_insert_with(|| {
+                        let mut sub_state = ProgramState::new(
+                            sub_program.max_registers,
+                            sub_program.cursor_ref.len(),
+                        );
+                        sub_state.params = Some(
+                            registers[*params_start_reg..*params_start_reg + *num_params].to_vec(),
+                        );
+                        sub_state.fk_violations = fk_violations;
+                        sub_state.depth = depth + 1;
+                        Box::new(sub_state)
+                    });
+                    match sub_program.step(sub_state, pager.clone())? {
+                        // The rows of a SELECT in a trigger program are discarded
+                        StepResult::Row => {}
+                        StepResult::Done => {
+                            let ignored = matches!(
+                                sub_program.insns[sub_state.pc as usize],
+                                Insn::Halt {
+                                    err_code: SQLITE_IGNORE,
+                                    ..
+                                }
+                            );
+                            state.fk_violations = sub_state.fk_violations;
+                            state.sub_program_state = None;
+                            if ignored {
+                                state.pc = ignore_jump.to_offset_int();
+                            } else {
+                                state.pc += 1;
+                            }
+                        }
+                        step_result => return Ok(step_result),
+                    }
+                }
+                Insn::FkCounter {
+                    deferred,
+                    increment,
+                } => {
+                    let conn = self.connection.upgrade().unwrap();
+                    if conn.defer_foreign_keys.get() {
+                        let count = conn.deferred_imm_fk_violations.get();
+                        conn.deferred_imm_fk_violations.set(count + increment);
+                    } else if *deferred {
+                        let count = conn.deferred_fk_violations.get();
+                        conn.deferred_fk_violations.set(count + increment);
+                    } else {
+                        state.fk_violations += increment;
+                    }
+                    state.pc += 1;
+                }
+                Insn::FkIfZero {
+                    deferred,
+                    target_pc,
+                } => {
+                    let conn = self.connection.upgrade().unwrap();
+                    let count = if *deferred {
+                        conn.deferred_fk_violations.get()
+                    } else {
+                        state.fk_violations
+                    };
+                    if count == 0 && conn.deferred_imm_fk_violations.get() == 0 {
+                        state.pc = target_pc.to_offset_int();
+                    } else {
+                        state.pc += 1;
+                    }
+                }
+                Insn::Param { offset, dest } => {
+                    let params = state
+                        .params
+                        .as_ref()
+                        .expect("Param outside of a trigger program");
+                    state.registers[*dest] = params[*offset].clone();
+                    state.pc += 1;
+                }
+                Insn::DropIndex { index, db } => {
+                    let conn = self.connection.upgrade();
+                    let conn = conn.as_ref().unwrap();
+                    if *db != MAIN_DB {
+                        let database = conn.aux_database(*db)?;
+                        database.save_committed_schema();
+                        database.schema.borrow_mut().remove_index(index);
+                        state.pc += 1;
+                        continue;
                     }
+                    conn.save_committed_schema();
+                    RefCell::borrow_mut(&conn.schema).remove_index(index);
+                    state.pc += 1;
+                }
+                Insn::ReadCookie { db, dest, cookie } => {
+                    let pager = self.db_pager(*db, &pager)?;
                     let cookie_value = match cookie {
                         Cookie::UserVersion => pager.db_header.borrow().user_version.into(),
                         cookie => todo!("{cookie:?} is not yet implement for ReadCookie"),
@@ -2949,6 +3593,9 @@ impl Program {
         let auto_commit = *connection.auto_commit.borrow();
         tracing::trace!("Halt auto_commit {}", auto_commit);
         if auto_commit {
+            for database in connection.aux_databases() {
+                database.commit()?;
+            }
             let current_state = connection.transaction_state.borrow().clone();
             if current_state == TransactionState::Read {
                 pager.end_read_tx()?;
@@ -2957,6 +3604,9 @@ impl Program {
             match pager.end_tx() {
                 Ok(crate::storage::wal::CheckpointStatus::IO) => Ok(StepResult::IO),
                 Ok(crate::storage::wal::CheckpointStatus::Done(_)) => {
+                    connection.committed_schema.take();
+                    connection.savepoints.borrow_mut().clear();
+                    connection.reset_deferred_fk_violations();
                     if self.change_cnt_on {
                         if let Some(conn) = self.connection.upgrade() {
                             conn.set_changes(self.n_change.get());
@@ -2975,6 +3625,119 @@ impl Program {
             Ok(StepResult::Done)
         }
     }
+
+    /// The pager of the database `db` of the connection, `pager` being the one of the main
+    /// database.
+    fn db_pager(&self, db: usize, pager: &Rc<Pager>) -> Result<Rc<Pager>> {
+        if db != MAIN_DB {
+            let connection = self.connection.upgrade().unwrap();
+            return Ok(connection.aux_database(db)?.pager.clone());
+        }
+        Ok(pager.clone())
+    }
+
+    /// End the statement with the error of a Halt, undoing changes as `on_error` says.
+    fn halt_with_error(
+        &self,
+        pager: Rc<Pager>,
+        on_error: OnError,
+        err: LimboError,
+    ) -> Result<StepResult> {
+        match on_error {
+            OnError::Rollback => self.rollback(pager)?,
+            OnError::Abort => self.abort(pager)?,
+            OnError::Fail => {
+                // The rows the statement already wrote are committed like the statement had
+                // succeeded, unless a transaction is open.
+                let connection = self.connection.upgrade().unwrap();
+                connection.release_statement_savepoint();
+                match self.halt(pager)? {
+                    StepResult::Done => {}
+                    step_result => return Ok(step_result),
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// Undo the changes of the statement, which failed: roll back to its savepoint inside a
+    /// transaction, or roll back the transaction the statement runs in otherwise.
+    fn abort(&self, pager: Rc<Pager>) -> Result<()> {
+        let connection = self
+            .connection
+            .upgrade()
+            .expect("only weak ref to connection?");
+        if *connection.auto_commit.borrow() {
+            return self.rollback(pager);
+        }
+        connection.rollback_statement();
+        Ok(())
+    }
+
+    /// Roll back the transaction of the connection: discard the changes it made to the database
+    /// and to the schema, and return to autocommit mode.
+    fn rollback(&self, pager: Rc<Pager>) -> Result<()> {
+        let connection = self
+            .connection
+            .upgrade()
+            .expect("only weak ref to connection?");
+        tracing::trace!("Rollback");
+        pager.rollback()?;
+        if let Some(schema) = connection.committed_schema.take() {
+            connection.schema.replace(schema);
+        }
+        for database in connection.aux_databases() {
+            database.rollback()?;
+        }
+        connection.savepoints.borrow_mut().clear();
+        connection.reset_deferred_fk_violations();
+        connection.auto_commit.replace(true);
+        Ok(())
+    }
+}
+
+/// How deep Program instructions can nest, as triggers and FOREIGN KEY actions fire each other.
+/// Each level is a nested call of Program::step, so the limit keeps them within the native stack.
+const MAX_PROGRAM_DEPTH: usize = 50;
+
+fn foreign_key_constraint_failed() -> LimboError {
+    LimboError::Constraint("FOREIGN KEY constraint failed (19)".to_string())
+}
+
+/// The default value of a column of the table the cursor reads, or NULL if it has none.
+fn column_default_value(cursor_type: &CursorType, column: usize) -> OwnedValue {
+    let CursorType::BTreeTable(table) = cursor_type else {
+        return OwnedValue::Null;
+    };
+    match table.columns.get(column).and_then(|c| c.default.as_ref()) {
+        Some(expr) => constant_value(expr),
+        None => OwnedValue::Null,
+    }
+}
+
+/// Evaluate a constant expression, such as the default value of a column added by ALTER TABLE.
+fn constant_value(expr: &ast::Expr) -> OwnedValue {
+    match expr {
+        ast::Expr::Literal(ast::Literal::Numeric(val)) => match val.parse::<i64>() {
+            Ok(int_value) => OwnedValue::Integer(int_value),
+            Err(_) => OwnedValue::Float(val.parse().unwrap_or(0.0)),
+        },
+        ast::Expr::Literal(ast::Literal::String(s)) => OwnedValue::build_text(&sanitize_string(s)),
+        ast::Expr::Literal(ast::Literal::Blob(s)) => OwnedValue::from_blob(
+            s.as_bytes()
+                .chunks_exact(2)
+                .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+                .collect(),
+        ),
+        ast::Expr::Unary(ast::UnaryOperator::Negative, expr) => match constant_value(expr) {
+            OwnedValue::Integer(i) => OwnedValue::Integer(-i),
+            OwnedValue::Float(f) => OwnedValue::Float(-f),
+            value => value,
+        },
+        ast::Expr::Unary(ast::UnaryOperator::Positive, expr) => constant_value(expr),
+        ast::Expr::Parenthesized(exprs) if exprs.len() == 1 => constant_value(&exprs[0]),
+        _ => OwnedValue::Null,
+    }
 }
 
 fn get_new_rowid<R: Rng>(cursor: &mut BTreeCursor, mut rng: R) -> Result<CursorResult<i64>> {
@@ -3057,6 +3820,7 @@ fn get_indent_count(indent_count: usize, curr_insn: &Insn, prev_insn: Option<&In
             Insn::RewindAwait { .. }
             | Insn::LastAwait { .. }
             | Insn::SorterSort { .. }
+            | Insn::EphemeralRewind { .. }
             | Insn::SeekGE { .. }
             | Insn::SeekGT { .. } => indent_count + 1,
             _ => indent_count,
@@ -3066,9 +3830,10 @@ fn get_indent_count(indent_count: usize, curr_insn: &Insn, prev_insn: Option<&In
     };
 
     match curr_insn {
-        Insn::NextAsync { .. } | Insn::SorterNext { .. } | Insn::PrevAsync { .. } => {
-            indent_count - 1
-        }
+        Insn::NextAsync { .. }
+        | Insn::SorterNext { .. }
+        | Insn::EphemeralNext { .. }
+        | Insn::PrevAsync { .. } => indent_count - 1,
         _ => indent_count,
     }
 }
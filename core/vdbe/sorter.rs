@@ -1,18 +1,23 @@
+use crate::translate::collate::CollationSeq;
 use crate::types::Record;
 use std::cmp::Ordering;
 
 pub struct Sorter {
     records: Vec<Record>,
-    current: Option<Record>,
+    /// index of the current row in the sorted records
+    current: usize,
     order: Vec<bool>,
+    /// The collation of each sort key, BINARY if there is none.
+    collations: Vec<Option<CollationSeq>>,
 }
 
 impl Sorter {
-    pub fn new(order: Vec<bool>) -> Self {
+    pub fn new(order: Vec<bool>, collations: Vec<Option<CollationSeq>>) -> Self {
         Self {
             records: Vec::new(),
-            current: None,
+            current: 0,
             order,
+            collations,
         }
     }
     pub fn is_empty(&self) -> bool {
@@ -20,7 +25,7 @@ impl Sorter {
     }
 
     pub fn has_more(&self) -> bool {
-        self.current.is_some()
+        self.current < self.records.len()
     }
 
     // We do the sorting here since this is what is called by the SorterSort instruction
@@ -29,10 +34,14 @@ impl Sorter {
             let cmp_by_idx = |idx: usize, ascending: bool| {
                 let a = &a.get_value(idx);
                 let b = &b.get_value(idx);
+                let cmp = match self.collations.get(idx) {
+                    Some(Some(collation)) => collation.compare(a, b),
+                    _ => a.cmp(b),
+                };
                 if ascending {
-                    a.cmp(b)
+                    cmp
                 } else {
-                    b.cmp(a)
+                    cmp.reverse()
                 }
             };
 
@@ -45,14 +54,18 @@ impl Sorter {
             }
             cmp_ret
         });
-        self.records.reverse();
-        self.next()
+        self.current = 0;
     }
     pub fn next(&mut self) {
-        self.current = self.records.pop();
+        self.current += 1;
+    }
+    /// Moves to the row at the given index of the sorted rows. Returns false if there is no such row.
+    pub fn seek(&mut self, index: usize) -> bool {
+        self.current = index;
+        self.has_more()
     }
     pub fn record(&self) -> Option<&Record> {
-        self.current.as_ref()
+        self.records.get(self.current)
     }
 
     pub fn insert(&mut self, record: &Record) {
@@ -14,6 +14,7 @@ pub struct ExtensionApi {
     pub register_scalar_function: RegisterScalarFn,
     pub register_aggregate_function: RegisterAggFn,
     pub register_module: RegisterModuleFn,
+    pub register_collation: RegisterCollationFn,
 }
 
 pub type ExtensionEntryPoint = unsafe extern "C" fn(api: *const ExtensionApi) -> ResultCode;
@@ -39,6 +40,22 @@ pub type RegisterModuleFn = unsafe extern "C" fn(
     kind: VTabKind,
 ) -> ResultCode;
 
+/// Compares the UTF-8 strings `lhs` and `rhs` of `lhs_len` and `rhs_len` bytes, which are not
+/// nul-terminated. Returns a negative number, zero or a positive number if `lhs` sorts before,
+/// the same as or after `rhs`.
+pub type CollationFunction = unsafe extern "C" fn(
+    lhs: *const c_char,
+    lhs_len: usize,
+    rhs: *const c_char,
+    rhs_len: usize,
+) -> i32;
+
+pub type RegisterCollationFn = unsafe extern "C" fn(
+    ctx: *mut c_void,
+    name: *const c_char,
+    func: CollationFunction,
+) -> ResultCode;
+
 pub type InitAggFunction = unsafe extern "C" fn() -> *mut AggCtx;
 pub type StepFunction = unsafe extern "C" fn(ctx: *mut AggCtx, argc: i32, argv: *const Value);
 pub type FinalizeFunction = unsafe extern "C" fn(ctx: *mut AggCtx) -> Value;
@@ -109,6 +109,10 @@ impl File for SimulatorFile {
     fn size(&self) -> Result<u64> {
         self.inner.size()
     }
+
+    fn truncate(&self, len: usize) -> Result<()> {
+        self.inner.truncate(len)
+    }
 }
 
 impl Drop for SimulatorFile {
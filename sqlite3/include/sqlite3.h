@@ -31,6 +31,10 @@
 
 #define SQLITE_STATE_BUSY 109
 
+#define SQLITE_UTF8 1
+
+#define SQLITE_UTF16_ALIGNED 8
+
 typedef struct sqlite3 sqlite3;
 
 typedef struct sqlite3_stmt sqlite3_stmt;
@@ -204,12 +208,12 @@ int sqlite3_blob_close(void *_blob);
 
 int sqlite3_stricmp(const char *_a, const char *_b);
 
-int sqlite3_create_collation_v2(sqlite3 *_db,
-                                const char *_name,
-                                int _enc,
-                                void *_context,
-                                int (*_cmp)(void),
-                                void (*_destroy)(void));
+int sqlite3_create_collation_v2(sqlite3 *db,
+                                const char *name,
+                                int enc,
+                                void *context,
+                                int (*cmp)(void *, int, const void *, int, const void *),
+                                void (*destroy)(void *));
 
 int sqlite3_create_function_v2(sqlite3 *_db,
                                const char *_name,
@@ -29,6 +29,9 @@ pub const SQLITE_STATE_OPEN: u8 = 0x76;
 pub const SQLITE_STATE_SICK: u8 = 0xba;
 pub const SQLITE_STATE_BUSY: u8 = 0x6d;
 
+pub const SQLITE_UTF8: ffi::c_int = 1;
+pub const SQLITE_UTF16_ALIGNED: ffi::c_int = 8;
+
 pub const SQLITE_CHECKPOINT_PASSIVE: ffi::c_int = 0;
 pub const SQLITE_CHECKPOINT_FULL: ffi::c_int = 1;
 pub const SQLITE_CHECKPOINT_RESTART: ffi::c_int = 2;
@@ -885,16 +888,83 @@ pub unsafe extern "C" fn sqlite3_stricmp(
     stub!();
 }
 
+type collation_cmp_callback = Option<
+    unsafe extern "C" fn(
+        context: *mut ffi::c_void,
+        lhs_len: ffi::c_int,
+        lhs: *const ffi::c_void,
+        rhs_len: ffi::c_int,
+        rhs: *const ffi::c_void,
+    ) -> ffi::c_int,
+>;
+
+type collation_destroy_callback = Option<unsafe extern "C" fn(context: *mut ffi::c_void)>;
+
+/// The application data of a collation, which is passed to its destructor when the collation
+/// is replaced.
+struct CollationContext {
+    context: *mut ffi::c_void,
+    destroy: collation_destroy_callback,
+}
+
+impl Drop for CollationContext {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.context) };
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn sqlite3_create_collation_v2(
-    _db: *mut sqlite3,
-    _name: *const ffi::c_char,
-    _enc: ffi::c_int,
-    _context: *mut ffi::c_void,
-    _cmp: Option<unsafe extern "C" fn() -> ffi::c_int>,
-    _destroy: Option<unsafe extern "C" fn()>,
+    db: *mut sqlite3,
+    name: *const ffi::c_char,
+    enc: ffi::c_int,
+    context: *mut ffi::c_void,
+    cmp: collation_cmp_callback,
+    destroy: collation_destroy_callback,
 ) -> ffi::c_int {
-    stub!();
+    if db.is_null() || name.is_null() {
+        return SQLITE_MISUSE;
+    }
+    // Only UTF-8 strings are compared, and collations can't be deleted
+    let Some(cmp) = cmp else {
+        return SQLITE_MISUSE;
+    };
+    if enc & !SQLITE_UTF16_ALIGNED != SQLITE_UTF8 {
+        return SQLITE_MISUSE;
+    }
+    let db: &mut sqlite3 = &mut *db;
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(s) => s,
+        Err(_) => return SQLITE_MISUSE,
+    };
+    let collation_context = Rc::new(CollationContext { context, destroy });
+    let compare = {
+        let collation_context = collation_context.clone();
+        move |lhs: &str, rhs: &str| {
+            let result = unsafe {
+                cmp(
+                    collation_context.context,
+                    lhs.len() as ffi::c_int,
+                    lhs.as_ptr() as *const ffi::c_void,
+                    rhs.len() as ffi::c_int,
+                    rhs.as_ptr() as *const ffi::c_void,
+                )
+            };
+            result.cmp(&0)
+        }
+    };
+    match db._db.create_collation(name, compare) {
+        Ok(()) => SQLITE_OK,
+        Err(_) => {
+            // The destructor is not called when the collation could not be created
+            if let Some(mut collation_context) = Rc::into_inner(collation_context) {
+                collation_context.destroy = None;
+            }
+            SQLITE_ERROR
+        }
+    }
 }
 
 #[no_mangle]
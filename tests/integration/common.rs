@@ -123,6 +123,20 @@ mod tests {
         let columns = stmt.num_columns();
         assert_eq!(columns, 0);
 
+        let stmt = conn.prepare("insert into test (foo, bar) values (1, 2) returning *;")?;
+        let columns = stmt.num_columns();
+        assert_eq!(columns, 3);
+        assert_eq!(stmt.get_column_name(0), "foo".into());
+        assert_eq!(stmt.get_column_name(1), "bar".into());
+        assert_eq!(stmt.get_column_name(2), "baz".into());
+
+        let stmt = conn.prepare("delete from test returning bar, foo as id, baz + 1")?;
+        let columns = stmt.num_columns();
+        assert_eq!(columns, 3);
+        assert_eq!(stmt.get_column_name(0), "bar".into());
+        assert_eq!(stmt.get_column_name(1), "id".into());
+        assert_eq!(stmt.get_column_name(2), "baz + 1".into());
+
         Ok(())
     }
 }
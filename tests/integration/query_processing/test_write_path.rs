@@ -1,6 +1,6 @@
 use crate::common;
 use crate::common::{compare_string, do_flush, TempDatabase};
-use limbo_core::{Connection, StepResult, Value};
+use limbo_core::{Connection, Database, StepResult, Value};
 use log::debug;
 use std::rc::Rc;
 
@@ -467,3 +467,1059 @@ fn test_wal_restart() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_update_overflow_page() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db =
+        TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    for i in 1..=20 {
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, 'small')", i),
+        )?;
+    }
+
+    let mut huge_text = String::new();
+    for i in 0..8192 {
+        huge_text.push((b'A' + (i % 24) as u8) as char);
+    }
+    run_query(
+        &tmp_db,
+        &conn,
+        &format!("UPDATE test SET t = '{}' WHERE x % 2 = 0", huge_text),
+    )?;
+    run_query(
+        &tmp_db,
+        &conn,
+        "UPDATE test SET t = 'shrunk' WHERE x % 4 = 0",
+    )?;
+
+    let mut rows = Vec::new();
+    let mut stmt = conn.prepare("SELECT x, t FROM test")?;
+    loop {
+        match stmt.step()? {
+            StepResult::Row => {
+                let row = stmt.row().unwrap();
+                let x = match row.get_value(0).to_value() {
+                    Value::Integer(i) => i,
+                    _ => unreachable!(),
+                };
+                let t = match row.get_value(1).to_value() {
+                    Value::Text(t) => t.to_string(),
+                    _ => unreachable!(),
+                };
+                rows.push((x, t));
+            }
+            StepResult::IO => {
+                tmp_db.io.run_once()?;
+            }
+            StepResult::Done => break,
+            _ => unreachable!(),
+        }
+    }
+
+    assert_eq!(rows.len(), 20);
+    for (x, t) in rows {
+        let expected = match x {
+            x if x % 4 == 0 => "shrunk",
+            x if x % 2 == 0 => huge_text.as_str(),
+            _ => "small",
+        };
+        compare_string(expected, &t);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_update_rowid_conflict() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (1), (2)")?;
+    let err = run_query(&tmp_db, &conn, "UPDATE test SET x = 2 WHERE x = 1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Runtime error: UNIQUE constraint failed: test.x (19)"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_create_index_errors() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER, y INTEGER);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "CREATE INDEX test_x ON test (x)")?;
+    run_query(
+        &tmp_db,
+        &conn,
+        "CREATE INDEX IF NOT EXISTS test_x ON test (x)",
+    )?;
+    for (query, expected) in [
+        (
+            "CREATE INDEX test_x ON test (y)",
+            "Parse error: index test_x already exists",
+        ),
+        (
+            "CREATE INDEX test ON test (y)",
+            "Parse error: there is already a table named test",
+        ),
+        (
+            "CREATE INDEX sqlite_idx ON test (y)",
+            "Parse error: object name reserved for internal use: sqlite_idx",
+        ),
+        (
+            "CREATE INDEX missing_x ON missing (x)",
+            "Parse error: no such table: missing",
+        ),
+        (
+            "CREATE INDEX test_z ON test (z)",
+            "Parse error: no such column: z",
+        ),
+    ] {
+        let err = run_query(&tmp_db, &conn, query).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_drop_index_errors() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x TEXT PRIMARY KEY);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "DROP INDEX IF EXISTS test_x")?;
+    let err = run_query(&tmp_db, &conn, "DROP INDEX test_x").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: no such index: test_x");
+    let err = run_query(&tmp_db, &conn, "DROP INDEX sqlite_autoindex_test_1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Parse error: index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_create_index_integrity() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER, y TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    for i in 0..1000 {
+        let insert_query = format!("INSERT INTO test VALUES ({}, 'value-{}')", i % 97, i);
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(&tmp_db, &conn, "CREATE INDEX test_xy ON test (x, y DESC)")?;
+    run_query(&tmp_db, &conn, "CREATE UNIQUE INDEX test_y ON test (y)")?;
+    for i in 1000..2000 {
+        let insert_query = format!("INSERT INTO test VALUES ({}, 'value-{}')", i % 97, i);
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(&tmp_db, &conn, "UPDATE test SET x = x + 1 WHERE x < 10")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM test", [], |row| row.get(0))?;
+    assert_eq!(count, 2000);
+    Ok(())
+}
+
+#[test]
+fn test_delete_integrity() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db =
+        TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, y TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "CREATE INDEX test_y ON test (y)")?;
+    for i in 1..3000 {
+        let insert_query = format!(
+            "INSERT INTO test VALUES ({}, 'value-{:06}')",
+            i,
+            i * 7919 % 3001
+        );
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    // Deleting most rows empties whole pages of both the table and the index.
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x > 100")?;
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x % 3 = 0")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM test", [], |row| row.get(0))?;
+    assert_eq!(count, 67);
+    Ok(())
+}
+
+#[test]
+fn test_delete_integrity_existing_file() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x TEXT, y BLOB);");
+    rusqlite::Connection::open(&tmp_db.path)?.execute_batch(
+        "CREATE INDEX test_x ON test (x);
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 5000)
+         INSERT INTO test SELECT 'k' || i, zeroblob(50) FROM n;",
+    )?;
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x < 'k5'")?;
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 556);
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row(
+        "SELECT count(*) FROM test INDEXED BY test_x WHERE x > ''",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(count, 556);
+    Ok(())
+}
+
+#[test]
+fn test_without_rowid_delete_integrity() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite(
+        "CREATE TABLE test (k TEXT PRIMARY KEY, v BLOB) WITHOUT ROWID;",
+    );
+    rusqlite::Connection::open(&tmp_db.path)?.execute_batch(
+        "CREATE TABLE pairs (a INTEGER, b INTEGER, c TEXT, PRIMARY KEY (b, a)) WITHOUT ROWID;
+         WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 999)
+         INSERT INTO pairs SELECT i, i % 100, printf('%0100d', i) FROM n;",
+    )?;
+    let conn = tmp_db.connect_limbo();
+
+    // Enough rows for the b-tree to have interior pages, whose cells hold rows as well.
+    for i in 1..1500 {
+        let insert_query = format!("INSERT INTO test VALUES ('key{:05}', zeroblob(100))", i);
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE k < 'key00600'")?;
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 900);
+    run_query(&tmp_db, &conn, "DELETE FROM test")?;
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 0);
+    run_query(&tmp_db, &conn, "DELETE FROM pairs WHERE b < 50")?;
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT count(*) FROM pairs")?,
+        500
+    );
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT count(*) FROM pairs WHERE b < 50")?,
+        0
+    );
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    Ok(())
+}
+
+#[test]
+fn test_unique_constraints_existing_file() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite(
+        "CREATE TABLE kv (k TEXT UNIQUE, v INTEGER, w TEXT, UNIQUE (v, w));",
+    );
+    rusqlite::Connection::open(&tmp_db.path)?.execute_batch(
+        "CREATE TABLE pairs (a, b UNIQUE, PRIMARY KEY (a, b)) WITHOUT ROWID;
+         INSERT INTO kv VALUES ('a', 1, 'x');
+         INSERT INTO pairs VALUES (1, 1);",
+    )?;
+    let conn = tmp_db.connect_limbo();
+
+    for i in 0..200 {
+        let insert_query = format!("INSERT INTO kv VALUES ('k{}', {}, 'y')", i, i);
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(
+        &tmp_db,
+        &conn,
+        "INSERT INTO kv VALUES ('a', 2, 'z') ON CONFLICT (k) DO UPDATE SET v = v + excluded.v",
+    )?;
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT v FROM kv WHERE k = 'a'")?,
+        3
+    );
+    let err = run_query(&tmp_db, &conn, "INSERT INTO kv VALUES ('b', 5, 'y')").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Runtime error: UNIQUE constraint failed: kv.v, kv.w (19)"
+    );
+    let err = run_query(&tmp_db, &conn, "INSERT INTO pairs VALUES (2, 1)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Runtime error: UNIQUE constraint failed: pairs.b (19)"
+    );
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row(
+        "SELECT count(*) FROM kv INDEXED BY sqlite_autoindex_kv_2 WHERE v >= 0",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(count, 201);
+    Ok(())
+}
+
+#[test]
+fn test_drop_table_errors() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "DROP TABLE IF EXISTS missing")?;
+    let err = run_query(&tmp_db, &conn, "DROP TABLE missing").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: no such table: missing");
+    let err = run_query(&tmp_db, &conn, "DROP TABLE sqlite_schema").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Parse error: table sqlite_schema may not be dropped"
+    );
+    run_query(&tmp_db, &conn, "DROP TABLE test")?;
+    let err = run_query(&tmp_db, &conn, "SELECT * FROM test").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: Table test not found");
+    Ok(())
+}
+
+#[test]
+fn test_drop_table_frees_pages() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE keep (x INTEGER);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(
+        &tmp_db,
+        &conn,
+        "CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT)",
+    )?;
+    run_query(&tmp_db, &conn, "CREATE INDEX test_t ON test (t)")?;
+    for i in 0..300 {
+        // Every third row spills onto overflow pages, both in the table and in the index.
+        let len = if i % 3 == 0 { 6000 } else { 50 };
+        let insert_query = format!(
+            "INSERT INTO test VALUES ({}, '{}{}')",
+            i,
+            "x".repeat(len),
+            i
+        );
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(&tmp_db, &conn, "INSERT INTO keep VALUES (1)")?;
+    run_query(&tmp_db, &conn, "DROP TABLE test")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let page_count: i64 = rusqlite_conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    let freelist_count: i64 =
+        rusqlite_conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+    // Only the schema page and the page of `keep` remain in use.
+    assert_eq!(freelist_count, page_count - 2);
+    let names: Vec<String> = rusqlite_conn
+        .prepare("SELECT name FROM sqlite_schema")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(names, vec!["keep".to_string()]);
+    Ok(())
+}
+
+#[test]
+fn test_rollback() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db =
+        TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    for i in 0..20 {
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, '{}')", i, "x".repeat(100)),
+        )?;
+    }
+    run_query(&tmp_db, &conn, "BEGIN IMMEDIATE")?;
+    for i in 20..100 {
+        // Large rows split pages and allocate overflow pages.
+        let len = if i % 10 == 0 { 6000 } else { 100 };
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, '{}')", i, "y".repeat(len)),
+        )?;
+        if i == 50 {
+            // Frames written to the WAL during the transaction are discarded too.
+            do_flush(&conn, &tmp_db)?;
+        }
+    }
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x < 10")?;
+    run_query(&tmp_db, &conn, "CREATE TABLE other (y)")?;
+    run_query(&tmp_db, &conn, "ROLLBACK")?;
+
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 20);
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT count(*) FROM sqlite_schema")?,
+        1
+    );
+    let err = run_query(&tmp_db, &conn, "SELECT * FROM other").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: Table other not found");
+    let err = run_query(&tmp_db, &conn, "ROLLBACK").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Transaction error: cannot rollback - no transaction is active"
+    );
+
+    // The connection keeps working after the rollback.
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (20, 'z')")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM test", [], |row| row.get(0))?;
+    assert_eq!(count, 21);
+    Ok(())
+}
+
+#[test]
+fn test_rollback_on_constraint_error() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (1)")?;
+    // The rows inserted before the conflicting one are rolled back.
+    let err = run_query(&tmp_db, &conn, "INSERT INTO test VALUES (2), (3), (1), (4)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Runtime error: UNIQUE constraint failed: test.x (19)"
+    );
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 1);
+
+    // Inside a transaction, only the failing statement is rolled back and the transaction stays
+    // open.
+    run_query(&tmp_db, &conn, "BEGIN IMMEDIATE")?;
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (5)")?;
+    assert!(run_query(&tmp_db, &conn, "INSERT INTO test VALUES (6), (7), (1)").is_err());
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 2);
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (8)")?;
+    run_query(&tmp_db, &conn, "COMMIT")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let rows: Vec<i64> = rusqlite_conn
+        .prepare("SELECT x FROM test ORDER BY x")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(rows, vec![1, 5, 8]);
+    Ok(())
+}
+
+#[test]
+fn test_rollback_keeps_last_commit() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, y);");
+    let conn = tmp_db.connect_limbo();
+    run_query(&tmp_db, &conn, "CREATE INDEX test_y ON test (y)")?;
+    run_query(&tmp_db, &conn, "CREATE TABLE other (z)")?;
+
+    // Like the shell, start a cache flush after every statement without waiting for it.
+    let run = |query: &str| -> anyhow::Result<()> {
+        let result = run_query(&tmp_db, &conn, query);
+        conn.cacheflush()?;
+        result
+    };
+    // The rollbacks must not discard the last committed transaction, which the writes to the
+    // other table after them wouldn't write again.
+    run("INSERT INTO test VALUES (1, 1)")?;
+    run("INSERT INTO test VALUES (2, 2)")?;
+    assert!(run("INSERT INTO test VALUES (1, 3)").is_err());
+    run("INSERT INTO other VALUES (1)")?;
+    run("INSERT INTO test VALUES (3, 3)")?;
+    run("BEGIN IMMEDIATE")?;
+    run("ROLLBACK")?;
+    run("INSERT INTO other VALUES (2)")?;
+    run("INSERT INTO test VALUES (4, 4)")?;
+    run("BEGIN IMMEDIATE")?;
+    run("INSERT INTO test VALUES (5, 5)")?;
+    run("ROLLBACK")?;
+    run("INSERT INTO other VALUES (3)")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let rows: Vec<i64> = rusqlite_conn
+        .prepare("SELECT x FROM test ORDER BY x")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(rows, vec![1, 2, 3, 4]);
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM other", [], |row| row.get(0))?;
+    assert_eq!(count, 3);
+    Ok(())
+}
+
+fn run_query(tmp_db: &TempDatabase, conn: &Rc<Connection>, query: &str) -> anyhow::Result<()> {
+    if let Some(ref mut rows) = conn.query(query)? {
+        loop {
+            match rows.step()? {
+                StepResult::IO => {
+                    tmp_db.io.run_once()?;
+                }
+                StepResult::Done => break,
+                _ => unreachable!(),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_savepoint() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db =
+        TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "BEGIN IMMEDIATE")?;
+    for i in 0..20 {
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, '{}')", i, "x".repeat(100)),
+        )?;
+    }
+    run_query(&tmp_db, &conn, "SAVEPOINT outer_sp")?;
+    for i in 20..50 {
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, '{}')", i, "y".repeat(100)),
+        )?;
+    }
+    run_query(&tmp_db, &conn, "SAVEPOINT inner_sp")?;
+    for i in 50..100 {
+        // Large rows split pages and allocate overflow pages.
+        let len = if i % 10 == 0 { 6000 } else { 100 };
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO test VALUES ({}, '{}')", i, "z".repeat(len)),
+        )?;
+    }
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x < 10")?;
+    run_query(&tmp_db, &conn, "ROLLBACK TO inner_sp")?;
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 50);
+
+    run_query(&tmp_db, &conn, "RELEASE outer_sp")?;
+    let err = run_query(&tmp_db, &conn, "ROLLBACK TO inner_sp").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Transaction error: no such savepoint: inner_sp"
+    );
+    run_query(&tmp_db, &conn, "COMMIT")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM test", [], |row| row.get(0))?;
+    assert_eq!(count, 50);
+    Ok(())
+}
+
+#[test]
+fn test_alter_table() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite(
+        "CREATE TABLE test (x INTEGER PRIMARY KEY, big TEXT, y INTEGER);",
+    );
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "CREATE INDEX test_y ON test (y)")?;
+    for i in 0..200 {
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!(
+                "INSERT INTO test VALUES ({}, '{}', {})",
+                i,
+                "x".repeat(500),
+                i
+            ),
+        )?;
+    }
+    run_query(
+        &tmp_db,
+        &conn,
+        "ALTER TABLE test ADD COLUMN z TEXT DEFAULT 'zzz'",
+    )?;
+    // Dropping the large column rewrites every row with a much smaller record.
+    run_query(&tmp_db, &conn, "ALTER TABLE test DROP COLUMN big")?;
+    run_query(&tmp_db, &conn, "ALTER TABLE test RENAME COLUMN y TO w")?;
+    run_query(&tmp_db, &conn, "ALTER TABLE test RENAME TO renamed")?;
+    assert_eq!(
+        query_i64(
+            &tmp_db,
+            &conn,
+            "SELECT count(*) FROM renamed WHERE z = 'zzz'"
+        )?,
+        200
+    );
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT x FROM renamed WHERE w = 150")?,
+        150
+    );
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let (count, sum): (i64, i64) = rusqlite_conn.query_row(
+        "SELECT count(*), sum(w) FROM renamed WHERE z = 'zzz'",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    assert_eq!((count, sum), (200, 199 * 200 / 2));
+    let index_sql: String = rusqlite_conn.query_row(
+        "SELECT sql FROM sqlite_schema WHERE name = 'test_y'",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(index_sql, "CREATE INDEX test_y ON renamed (w)");
+    Ok(())
+}
+
+#[test]
+fn test_temp_table() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "INSERT INTO test VALUES (1)")?;
+    run_query(&tmp_db, &conn, "CREATE TEMP TABLE test (x INTEGER, t TEXT)")?;
+    run_query(&tmp_db, &conn, "CREATE INDEX temp_t ON test (t)")?;
+    for i in 0..300 {
+        // Large rows make the temp table and its index span many pages.
+        let insert_query = format!(
+            "INSERT INTO test VALUES ({}, '{}{}')",
+            i,
+            "x".repeat(500),
+            i
+        );
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    assert_eq!(query_i64(&tmp_db, &conn, "SELECT count(*) FROM test")?, 300);
+    assert_eq!(
+        query_i64(&tmp_db, &conn, "SELECT count(*) FROM main.test")?,
+        1
+    );
+    let other_conn = tmp_db.connect_limbo();
+    let err = run_query(&tmp_db, &other_conn, "SELECT * FROM temp.test").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: Table test not found");
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    // Nothing of the temp database reaches the database file.
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let names: Vec<String> = rusqlite_conn
+        .prepare("SELECT name FROM sqlite_schema")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(names, vec!["test".to_string()]);
+    Ok(())
+}
+
+#[test]
+fn test_attach_database() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE data (k INTEGER, x INTEGER);");
+    let cfg_db = TempDatabase::new_with_rusqlite("CREATE TABLE cfg (k INTEGER, v TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    let attach_query = format!("ATTACH '{}' AS cfg", cfg_db.path.to_str().unwrap());
+    run_query(&tmp_db, &conn, &attach_query)?;
+    run_query(&tmp_db, &conn, "CREATE INDEX cfg.cfg_k ON cfg (k)")?;
+    for i in 0..300 {
+        // Large rows make the table of the attached database span many pages.
+        let insert_query = format!("INSERT INTO cfg.cfg VALUES ({}, '{}')", i, "x".repeat(500));
+        run_query(&tmp_db, &conn, &insert_query)?;
+        if i % 3 == 0 {
+            run_query(
+                &tmp_db,
+                &conn,
+                &format!("INSERT INTO data VALUES ({}, 1)", i),
+            )?;
+        }
+    }
+    assert_eq!(
+        query_i64(
+            &tmp_db,
+            &conn,
+            "SELECT count(*) FROM data JOIN cfg.cfg ON cfg.cfg.k = main.data.k"
+        )?,
+        100
+    );
+    let err = run_query(&tmp_db, &conn, "DETACH main").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid argument supplied: cannot detach database main"
+    );
+    run_query(&tmp_db, &conn, "DETACH cfg")?;
+    let err = run_query(&tmp_db, &conn, "SELECT * FROM cfg.cfg").unwrap_err();
+    assert_eq!(err.to_string(), "Parse error: unknown database cfg");
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&cfg_db.path)?;
+    let check: String = rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    assert_eq!(check, "ok");
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM cfg", [], |row| row.get(0))?;
+    assert_eq!(count, 300);
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let count: i64 = rusqlite_conn.query_row("SELECT count(*) FROM data", [], |row| row.get(0))?;
+    assert_eq!(count, 100);
+    Ok(())
+}
+
+#[test]
+fn test_vacuum() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db =
+        TempDatabase::new_with_rusqlite("CREATE TABLE test (x INTEGER PRIMARY KEY, t TEXT);");
+    let conn = tmp_db.connect_limbo();
+
+    run_query(&tmp_db, &conn, "CREATE INDEX test_t ON test (t)")?;
+    for i in 0..300 {
+        let insert_query = format!(
+            "INSERT INTO test VALUES ({}, '{}{}')",
+            i,
+            "x".repeat(500),
+            i
+        );
+        run_query(&tmp_db, &conn, &insert_query)?;
+    }
+    run_query(&tmp_db, &conn, "DELETE FROM test WHERE x % 10 != 0")?;
+    do_flush(&conn, &tmp_db)?;
+    let size_before = std::fs::metadata(&tmp_db.path)?.len();
+    let backup_path = tmp_db.path.with_file_name("backup.db");
+    let vacuum_into_query = format!("VACUUM INTO '{}'", backup_path.to_str().unwrap());
+    run_query(&tmp_db, &conn, &vacuum_into_query)?;
+    let err = run_query(&tmp_db, &conn, &vacuum_into_query).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid argument supplied: output file already exists"
+    );
+    assert_eq!(std::fs::metadata(&tmp_db.path)?.len(), size_before);
+    run_query(&tmp_db, &conn, "VACUUM")?;
+    assert!(std::fs::metadata(&tmp_db.path)?.len() < size_before);
+    let query = format!(
+        "SELECT count(*) FROM test WHERE t = '{}{}'",
+        "x".repeat(500),
+        120
+    );
+    assert_eq!(query_i64(&tmp_db, &conn, &query)?, 1);
+    conn.close()?;
+
+    for path in [&tmp_db.path, &backup_path] {
+        let rusqlite_conn = rusqlite::Connection::open(path)?;
+        let check: String =
+            rusqlite_conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+        assert_eq!(check, "ok");
+        let freelist_count: i64 =
+            rusqlite_conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+        assert_eq!(freelist_count, 0);
+        let count: i64 =
+            rusqlite_conn.query_row("SELECT count(*) FROM test", [], |row| row.get(0))?;
+        assert_eq!(count, 30);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_analyze() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_with_rusqlite("CREATE TABLE p (id INTEGER PRIMARY KEY, k);");
+    {
+        let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+        rusqlite_conn.execute_batch(
+            "CREATE INDEX pk ON p (k);
+             CREATE TABLE q (id INTEGER PRIMARY KEY, k);
+             CREATE INDEX qk ON q (k);",
+        )?;
+    }
+    let conn = tmp_db.connect_limbo();
+    for i in 1..=12 {
+        let k = if i <= 5 { 6 - i } else { i };
+        run_query(
+            &tmp_db,
+            &conn,
+            &format!("INSERT INTO p VALUES ({}, {})", i, k),
+        )?;
+    }
+    run_query(
+        &tmp_db,
+        &conn,
+        "INSERT INTO q VALUES (1, 3), (2, 1), (3, 2)",
+    )?;
+    let join_query = "SELECT p.id, q.id FROM p, q WHERE p.k = q.k";
+    // Without statistics the tables are joined in the order of the FROM clause
+    assert_eq!(
+        query_i64_pairs(&tmp_db, &conn, join_query)?,
+        vec![(3, 1), (4, 3), (5, 2)]
+    );
+    run_query(&tmp_db, &conn, "ANALYZE")?;
+    // The smaller table is looped over first
+    assert_eq!(
+        query_i64_pairs(&tmp_db, &conn, join_query)?,
+        vec![(3, 1), (5, 2), (4, 3)]
+    );
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    let rusqlite_conn = rusqlite::Connection::open(&tmp_db.path)?;
+    let mut stmt =
+        rusqlite_conn.prepare("SELECT tbl, idx, stat FROM sqlite_stat1 ORDER BY tbl, idx")?;
+    let stats = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, String, String)>, _>>()?;
+    assert_eq!(
+        stats,
+        vec![
+            ("p".to_string(), "pk".to_string(), "12 1".to_string()),
+            ("q".to_string(), "qk".to_string(), "3 1".to_string()),
+        ]
+    );
+
+    // The statistics are read when the database is opened
+    let conn = tmp_db.connect_limbo();
+    assert_eq!(
+        query_i64_pairs(&tmp_db, &conn, join_query)?,
+        vec![(3, 1), (5, 2), (4, 3)]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_create_collation() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_empty();
+    let db = Database::open_file(tmp_db.io.clone(), tmp_db.path.to_str().unwrap())?;
+    db.create_collation("by_length", |lhs, rhs| lhs.len().cmp(&rhs.len()))?;
+    let conn = db.connect();
+
+    run_query(&tmp_db, &conn, "CREATE TABLE t (a TEXT COLLATE BY_LENGTH)")?;
+    run_query(
+        &tmp_db,
+        &conn,
+        "INSERT INTO t VALUES ('zz'), ('a'), ('yyy')",
+    )?;
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t ORDER BY a")?,
+        ["a", "zz", "yyy"]
+    );
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t WHERE a = 'xy'")?,
+        ["zz"]
+    );
+    run_query(&tmp_db, &conn, "CREATE INDEX t_a ON t (a)")?;
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t WHERE a > 'xy'")?,
+        ["yyy"]
+    );
+    assert_eq!(
+        query_rows(
+            &tmp_db,
+            &conn,
+            "SELECT a FROM t ORDER BY a COLLATE BINARY DESC"
+        )?,
+        ["zz", "yyy", "a"]
+    );
+    assert!(query_rows(&tmp_db, &conn, "PRAGMA collation_list")?
+        .iter()
+        .any(|row| row.ends_with("|by_length")));
+
+    // The keys of t_a are ordered by the collation, so its function can't change.
+    let err = db
+        .create_collation("BY_LENGTH", |lhs, rhs| rhs.len().cmp(&lhs.len()))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid argument supplied: cannot replace the collation BY_LENGTH that index t_a is ordered by"
+    );
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t WHERE a > 'xy'")?,
+        ["yyy"]
+    );
+
+    // Once no index uses the collation, registering it again replaces its function.
+    run_query(&tmp_db, &conn, "DROP INDEX t_a")?;
+    db.create_collation("BY_LENGTH", |lhs, rhs| rhs.len().cmp(&lhs.len()))?;
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t ORDER BY a")?,
+        ["yyy", "zz", "a"]
+    );
+
+    let err = db
+        .create_collation("nocase", |lhs, rhs| lhs.cmp(rhs))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid argument supplied: cannot replace the built-in collation nocase"
+    );
+    let err = run_query(&tmp_db, &conn, "CREATE TABLE u (a TEXT COLLATE missing)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Parse error: no such collation sequence: missing"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_unregistered_collation() -> anyhow::Result<()> {
+    let _ = env_logger::try_init();
+    let tmp_db = TempDatabase::new_empty();
+    let db = Database::open_file(tmp_db.io.clone(), tmp_db.path.to_str().unwrap())?;
+    db.create_collation("by_length", |lhs, rhs| lhs.len().cmp(&rhs.len()))?;
+    let conn = db.connect();
+    run_query(
+        &tmp_db,
+        &conn,
+        "CREATE TABLE t (a TEXT COLLATE by_length, b)",
+    )?;
+    run_query(
+        &tmp_db,
+        &conn,
+        "INSERT INTO t VALUES ('zz', 1), ('a', 2), ('yyy', 3)",
+    )?;
+    run_query(&tmp_db, &conn, "CREATE INDEX t_b ON t (b)")?;
+    do_flush(&conn, &tmp_db)?;
+    conn.close()?;
+
+    // The collation is registered on the database that created the table, not on this one, so
+    // the statements that compare with it can't be prepared.
+    let conn = tmp_db.connect_limbo();
+    for query in [
+        "SELECT a FROM t ORDER BY a",
+        "SELECT a FROM t WHERE a = 'xy'",
+        "SELECT b FROM t WHERE b = 1 AND a > 'x'",
+        "CREATE INDEX t_a ON t (a)",
+    ] {
+        let err = run_query(&tmp_db, &conn, query).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error: no such collation sequence: by_length",
+            "{}",
+            query
+        );
+    }
+    assert_eq!(
+        query_rows(&tmp_db, &conn, "SELECT a FROM t ORDER BY b")?,
+        ["zz", "a", "yyy"]
+    );
+    Ok(())
+}
+
+fn query_rows(
+    tmp_db: &TempDatabase,
+    conn: &Rc<Connection>,
+    query: &str,
+) -> anyhow::Result<Vec<String>> {
+    let mut rows = conn.query(query)?.unwrap();
+    let mut result = vec![];
+    loop {
+        match rows.step()? {
+            StepResult::Row => {
+                let row = rows.row().unwrap();
+                let values: Vec<String> = row.get_values().iter().map(|v| v.to_string()).collect();
+                result.push(values.join("|"));
+            }
+            StepResult::IO => {
+                tmp_db.io.run_once()?;
+            }
+            StepResult::Done => break,
+            _ => unreachable!(),
+        }
+    }
+    Ok(result)
+}
+
+fn query_i64_pairs(
+    tmp_db: &TempDatabase,
+    conn: &Rc<Connection>,
+    query: &str,
+) -> anyhow::Result<Vec<(i64, i64)>> {
+    let mut rows = conn.query(query)?.unwrap();
+    let mut result = vec![];
+    loop {
+        match rows.step()? {
+            StepResult::Row => {
+                let row = rows.row().unwrap();
+                match (row.get_value(0).to_value(), row.get_value(1).to_value()) {
+                    (Value::Integer(a), Value::Integer(b)) => result.push((a, b)),
+                    values => panic!("expected two integers, got {:?}", values),
+                }
+            }
+            StepResult::IO => {
+                tmp_db.io.run_once()?;
+            }
+            StepResult::Done => break,
+            _ => unreachable!(),
+        }
+    }
+    Ok(result)
+}
+
+fn query_i64(tmp_db: &TempDatabase, conn: &Rc<Connection>, query: &str) -> anyhow::Result<i64> {
+    let mut rows = conn.query(query)?.unwrap();
+    let mut result = None;
+    loop {
+        match rows.step()? {
+            StepResult::Row => {
+                let row = rows.row().unwrap();
+                match row.get_value(0).to_value() {
+                    Value::Integer(i) => result = Some(i),
+                    value => panic!("expected an integer, got {:?}", value),
+                }
+            }
+            StepResult::IO => {
+                tmp_db.io.run_once()?;
+            }
+            StepResult::Done => break,
+            _ => unreachable!(),
+        }
+    }
+    Ok(result.expect("query returned no rows"))
+}
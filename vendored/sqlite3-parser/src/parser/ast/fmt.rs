@@ -735,6 +735,10 @@ impl ToTokens for Expr {
                 s.append(TK_RP, None)
             }
             Self::RowId { .. } => Ok(()),
+            Self::SubqueryResult { .. } => Ok(()),
+            Self::OuterRef(_) => Ok(()),
+            Self::Param(_) => Ok(()),
+            Self::Register(_) => Ok(()),
             Self::Subquery(query) => {
                 s.append(TK_LP, None)?;
                 query.to_tokens(s)?;
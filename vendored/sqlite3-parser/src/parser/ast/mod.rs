@@ -367,6 +367,26 @@ pub enum Expr {
         /// the y in `x.y.z`. index of the table in catalog.
         table: usize,
     },
+    /// Planned subquery expression: scalar subquery, `EXISTS` or `IN` subselect
+    SubqueryResult {
+        /// index of the subquery in the query plan
+        subquery_id: usize,
+        /// left-hand side of `IN`
+        lhs: Option<Box<Expr>>,
+        /// `NOT IN`
+        not_in: bool,
+        /// expressions of the enclosing query that the subquery refers to
+        outer_refs: Vec<Expr>,
+    },
+    /// In a correlated subquery, the value of the expression at this index
+    /// in the `outer_refs` of its `SubqueryResult`
+    OuterRef(usize),
+    /// In a trigger program, the value at this index in the OLD and NEW rows
+    /// the program was invoked with
+    Param(usize),
+    /// The value in this register, which the statement computed itself,
+    /// e.g. a column of the `excluded` row of an upsert
+    Register(usize),
     /// `IN`
     InList {
         /// expression
@@ -824,6 +844,15 @@ pub struct FromClause {
     op: Option<JoinOperator>, // FIXME transient
 }
 impl FromClause {
+    /// Constructor of a FROM clause with a single table
+    pub fn single(table: SelectTable) -> Self {
+        Self {
+            select: Some(Box::new(table)),
+            joins: None,
+            op: None,
+        }
+    }
+
     pub(crate) fn empty() -> Self {
         Self {
             select: None,
@@ -1616,6 +1645,18 @@ pub type PragmaValue = Expr; // TODO
 pub enum PragmaName {
     /// `cache_size` pragma
     CacheSize,
+    /// Returns the collating sequences of the connection.
+    CollationList,
+    /// Returns the databases of the connection.
+    DatabaseList,
+    /// Defers the checks of immediate foreign key constraints to the end of the transaction.
+    DeferForeignKeys,
+    /// Returns the rows that violate foreign key constraints.
+    ForeignKeyCheck,
+    /// Returns the foreign keys of a table.
+    ForeignKeyList,
+    /// Enables or disables the enforcement of foreign key constraints.
+    ForeignKeys,
     /// `journal_mode` pragma
     JournalMode,
     /// Noop as per SQLite docs